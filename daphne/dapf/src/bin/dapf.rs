@@ -7,11 +7,13 @@ use daphne::{
     constants::DapMediaType,
     error::aborts::ProblemDetails,
     hpke::{HpkeConfig, HpkeKemId, HpkeReceiverConfig},
-    messages::{Base64Encode, BatchSelector, Collection, CollectionReq, Query, TaskId},
+    messages::{
+        Base64Encode, BatchSelector, Collection, CollectionFormat, CollectionReq, Query, TaskId,
+    },
     vdaf::VdafConfig,
     DapAggregationParam, DapMeasurement, DapVersion,
 };
-use prio::codec::{Decode, ParameterizedDecode, ParameterizedEncode};
+use prio::codec::{Decode, ParameterizedEncode};
 use rand::prelude::*;
 use reqwest::{Client, ClientBuilder};
 use std::{
@@ -253,7 +255,14 @@ async fn main() -> Result<()> {
             let batch_selector: BatchSelector =
                 serde_json::from_str(&buf).with_context(|| "failed to parse JSON from stdin")?;
 
-            let resp = http_client.get(uri).send().await?;
+            let resp = http_client
+                .get(uri)
+                .header(
+                    "dap-collection-format",
+                    u8::from(CollectionFormat::LATEST).to_string(),
+                )
+                .send()
+                .await?;
             if resp.status() == 202 {
                 return Err(anyhow!("aggregate result not ready"));
             } else if resp.status() != 200 {
@@ -262,7 +271,17 @@ async fn main() -> Result<()> {
             let receiver = cli.hpke_receiver.as_ref().ok_or_else(|| {
                 anyhow!("received response, but cannot decrypt without HPKE receiver config")
             })?;
-            let collect_resp = Collection::get_decoded_with_param(&version, &resp.bytes().await?)?;
+            // A Leader that doesn't support format negotiation responds in the original format.
+            let format = match resp.headers().get("dap-collection-format") {
+                Some(format) => format
+                    .to_str()?
+                    .parse::<u8>()?
+                    .try_into()
+                    .with_context(|| "Leader responded with an unknown collection format")?,
+                None => CollectionFormat::V1,
+            };
+            let collect_resp =
+                Collection::get_decoded_with_format(version, format, &resp.bytes().await?)?;
             let agg_res = vdaf
                 .consume_encrypted_agg_shares(
                     receiver,
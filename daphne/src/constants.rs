@@ -16,11 +16,14 @@ const DRAFT02_MEDIA_TYPE_HPKE_CONFIG: &str = "application/dap-hpke-config";
 const MEDIA_TYPE_AGG_JOB_CONT_REQ: &str = "application/dap-aggregation-job-continue-req";
 const MEDIA_TYPE_AGG_JOB_INIT_REQ: &str = "application/dap-aggregation-job-init-req";
 const MEDIA_TYPE_AGG_JOB_RESP: &str = "application/dap-aggregation-job-resp";
+const MEDIA_TYPE_AGG_JOB_RESP_CHUNK: &str = "application/dap-aggregation-job-resp-chunk";
+const MEDIA_TYPE_AGG_JOB_RESP_CHUNK_REQ: &str = "application/dap-aggregation-job-resp-chunk-req";
 const MEDIA_TYPE_AGG_SHARE_REQ: &str = "application/dap-aggregate-share-req";
 const MEDIA_TYPE_AGG_SHARE: &str = "application/dap-aggregate-share";
 const MEDIA_TYPE_COLLECTION: &str = "application/dap-collection";
 const MEDIA_TYPE_COLLECT_REQ: &str = "application/dap-collect-req";
 const MEDIA_TYPE_HPKE_CONFIG_LIST: &str = "application/dap-hpke-config-list";
+const MEDIA_TYPE_PROBLEM: &str = "application/problem+json";
 const MEDIA_TYPE_REPORT: &str = "application/dap-report";
 
 /// Media type for each DAP request. This is included in the "content-type" HTTP header.
@@ -32,12 +35,19 @@ pub enum DapMediaType {
     /// draft02 compatibility: the latest draft doesn't define a separate media type for initialize
     /// and continue responses, but draft02 does.
     Draft02AggregateContinueResp,
+    /// A chunk of an aggregation job response that was too large to send at once, and the
+    /// request for the next chunk. These are not part of DAP and are only used with the latest
+    /// draft.
+    AggregationJobRespChunk,
+    AggregationJobRespChunkReq,
     AggregateShareReq,
     AggregateShare,
     CollectReq,
     Collection,
     HpkeConfigList,
     Report,
+    /// Problem details document (RFC 7807) sent in response to a request that was aborted.
+    Problem,
     /// No content-type header found.
     #[default]
     Missing,
@@ -50,15 +60,17 @@ impl DapMediaType {
         match self {
             Self::AggregationJobInitReq
             | Self::AggregationJobContinueReq
+            | Self::AggregationJobRespChunkReq
             | Self::AggregateShareReq
             | Self::Collection
             | Self::HpkeConfigList => Some(DapSender::Leader),
             Self::AggregationJobResp
             | Self::Draft02AggregateContinueResp
+            | Self::AggregationJobRespChunk
             | Self::AggregateShare => Some(DapSender::Helper),
             Self::Report => Some(DapSender::Client),
             Self::CollectReq => Some(DapSender::Collector),
-            Self::Missing => None,
+            Self::Problem | Self::Missing => None,
         }
     }
 
@@ -78,6 +90,12 @@ impl DapMediaType {
             }
             (DapVersion::Draft02, Some(DRAFT02_MEDIA_TYPE_AGG_INIT_RESP))
             | (DapVersion::DraftLatest, Some(MEDIA_TYPE_AGG_JOB_RESP)) => Self::AggregationJobResp,
+            (DapVersion::DraftLatest, Some(MEDIA_TYPE_AGG_JOB_RESP_CHUNK)) => {
+                Self::AggregationJobRespChunk
+            }
+            (DapVersion::DraftLatest, Some(MEDIA_TYPE_AGG_JOB_RESP_CHUNK_REQ)) => {
+                Self::AggregationJobRespChunkReq
+            }
             (DapVersion::Draft02, Some(DRAFT02_MEDIA_TYPE_AGG_SHARE_RESP))
             | (DapVersion::DraftLatest, Some(MEDIA_TYPE_AGG_SHARE)) => Self::AggregateShare,
             (DapVersion::Draft02, Some(DRAFT02_MEDIA_TYPE_COLLECT_RESP))
@@ -93,6 +111,9 @@ impl DapMediaType {
             (DapVersion::Draft02 | DapVersion::DraftLatest, Some(MEDIA_TYPE_REPORT)) => {
                 Self::Report
             }
+            (DapVersion::Draft02 | DapVersion::DraftLatest, Some(MEDIA_TYPE_PROBLEM)) => {
+                Self::Problem
+            }
             (_, Some(_)) => return None,
             (_, None) => Self::Missing,
         };
@@ -102,12 +123,7 @@ impl DapMediaType {
     /// Get the content-type representation of the media type.
     pub fn as_str_for_version(&self, version: DapVersion) -> Option<&str> {
         match (version, self) {
-            (DapVersion::Draft02, Self::AggregationJobInitReq) => {
-                Some(DRAFT02_MEDIA_TYPE_AGG_INIT_REQ)
-            }
-            (DapVersion::DraftLatest, Self::AggregationJobInitReq) => {
-                Some(MEDIA_TYPE_AGG_JOB_INIT_REQ)
-            }
+            (_, Self::AggregationJobInitReq) => Some(Self::for_aggregation_job_init(version)),
             (DapVersion::Draft02, Self::AggregationJobResp) => {
                 Some(DRAFT02_MEDIA_TYPE_AGG_INIT_RESP)
             }
@@ -121,6 +137,12 @@ impl DapMediaType {
             (DapVersion::Draft02, Self::Draft02AggregateContinueResp) => {
                 Some(DRAFT02_MEDIA_TYPE_AGG_CONT_RESP)
             }
+            (DapVersion::DraftLatest, Self::AggregationJobRespChunk) => {
+                Some(MEDIA_TYPE_AGG_JOB_RESP_CHUNK)
+            }
+            (DapVersion::DraftLatest, Self::AggregationJobRespChunkReq) => {
+                Some(MEDIA_TYPE_AGG_JOB_RESP_CHUNK_REQ)
+            }
             (DapVersion::Draft02 | DapVersion::DraftLatest, Self::AggregateShareReq) => {
                 Some(MEDIA_TYPE_AGG_SHARE_REQ)
             }
@@ -136,7 +158,25 @@ impl DapMediaType {
             (DapVersion::Draft02 | DapVersion::DraftLatest, Self::Report) => {
                 Some(MEDIA_TYPE_REPORT)
             }
-            (_, Self::Draft02AggregateContinueResp | Self::Missing) => None,
+            (DapVersion::Draft02 | DapVersion::DraftLatest, Self::Problem) => {
+                Some(MEDIA_TYPE_PROBLEM)
+            }
+            (
+                _,
+                Self::Draft02AggregateContinueResp
+                | Self::AggregationJobRespChunk
+                | Self::AggregationJobRespChunkReq
+                | Self::Missing,
+            ) => None,
+        }
+    }
+
+    /// Get the content-type of an aggregation job initialization request for the given version.
+    /// draft02 calls this an `AggregateInitializeReq`.
+    pub fn for_aggregation_job_init(version: DapVersion) -> &'static str {
+        match version {
+            DapVersion::Draft02 => DRAFT02_MEDIA_TYPE_AGG_INIT_REQ,
+            DapVersion::DraftLatest => MEDIA_TYPE_AGG_JOB_INIT_REQ,
         }
     }
 
@@ -278,6 +318,20 @@ mod test {
             ),
             Some(DapMediaType::Collection),
         );
+        assert_eq!(
+            DapMediaType::from_str_for_version(
+                DapVersion::DraftLatest,
+                Some("application/dap-aggregation-job-resp-chunk")
+            ),
+            Some(DapMediaType::AggregationJobRespChunk),
+        );
+        assert_eq!(
+            DapMediaType::from_str_for_version(
+                DapVersion::Draft02,
+                Some("application/dap-aggregation-job-resp-chunk")
+            ),
+            None,
+        );
 
         // Invalid media type
         assert_eq!(
@@ -320,6 +374,8 @@ mod test {
             (DapVersion::DraftLatest, DapMediaType::HpkeConfigList),
             (DapVersion::Draft02, DapMediaType::Report),
             (DapVersion::DraftLatest, DapMediaType::Report),
+            (DapVersion::Draft02, DapMediaType::Problem),
+            (DapVersion::DraftLatest, DapMediaType::Problem),
         ] {
             assert_eq!(
                 DapMediaType::from_str_for_version(version, media_type.as_str_for_version(version)),
@@ -343,4 +399,25 @@ mod test {
             DapMediaType::agg_job_cont_resp_for_version(DapVersion::DraftLatest)
         );
     }
+
+    #[test]
+    fn media_type_for_agg_job_init() {
+        assert_eq!(
+            DapMediaType::for_aggregation_job_init(DapVersion::Draft02),
+            "application/dap-aggregate-initialize-req"
+        );
+        assert_eq!(
+            DapMediaType::for_aggregation_job_init(DapVersion::DraftLatest),
+            "application/dap-aggregation-job-init-req"
+        );
+
+        // The media type for one draft isn't recognized by the other.
+        assert_eq!(
+            DapMediaType::from_str_for_version(
+                DapVersion::DraftLatest,
+                Some(DapMediaType::for_aggregation_job_init(DapVersion::Draft02))
+            ),
+            None
+        );
+    }
 }
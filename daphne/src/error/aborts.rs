@@ -5,8 +5,8 @@
 
 use crate::{
     fatal_error,
-    messages::{Base64Encode, TaskId, TransitionFailure},
-    DapError, DapMediaType, DapRequest, DapVersion,
+    messages::{Base64Encode, Duration, TaskId, TransitionFailure},
+    DapBatchBucket, DapError, DapMediaType, DapRequest, DapResponse, DapVersion,
 };
 use hex::FromHexError;
 use prio::codec::CodecError;
@@ -24,6 +24,17 @@ pub enum DapAbort {
     #[error("bad request")]
     BadRequest(String),
 
+    /// Conflict. Sent in response to a request that reuses the ID of an existing resource, e.g., a
+    /// collection job, but with different parameters.
+    #[error("conflict")]
+    Conflict(String),
+
+    /// Service unavailable. Sent in response to a request that the Aggregator is temporarily
+    /// unable to handle, e.g., because it is shedding load. The request may be retried after
+    /// `retry_after` seconds.
+    #[error("service unavailable")]
+    ServiceUnavailable { retry_after: Duration },
+
     /// Invalid batch. Sent in response to a CollectReq or AggregateShareReq.
     #[error("batchInvalid")]
     BatchInvalid { detail: String, task_id: TaskId },
@@ -50,6 +61,12 @@ pub enum DapAbort {
     #[error("missingTaskID")]
     MissingTaskId,
 
+    /// Outdated configuration. Sent in response to an upload request containing a Report that was
+    /// encrypted under an HPKE config the Leader no longer recognizes. The Client is expected to
+    /// fetch the current config and try again.
+    #[error("outdatedConfig")]
+    OutdatedConfig { detail: String, task_id: TaskId },
+
     /// Query mismatch. Sent in response to a CollectReq or AggregateShareReq.
     #[error("queryMismatch")]
     QueryMismatch { detail: String, task_id: TaskId },
@@ -59,6 +76,11 @@ pub enum DapAbort {
     #[error("reportRejected")]
     ReportRejected { detail: String },
 
+    /// Report too early. Sent in response to an upload request containing a Report whose
+    /// timestamp is further in the future than the Aggregator is willing to accept.
+    #[error("reportTooEarly")]
+    ReportTooEarly,
+
     /// Report too late. Sent in response to an upload request for a task that is known to have
     /// expired.
     #[error("reportTooLate")]
@@ -93,6 +115,21 @@ pub enum DapAbort {
         agg_job_id_base64url: String,
     },
 
+    /// VDAF version mismatch. Sent in response to an aggregation job initialization request from a
+    /// Leader whose VDAF implementation is incompatible with the Helper's.
+    #[error("vdafVersionMismatch")]
+    VdafVersionMismatch { detail: String, task_id: TaskId },
+
+    /// Aggregation parameter mismatch. Sent in response to an aggregation job whose aggregation
+    /// parameter differs from the one used by earlier aggregation jobs for the same batch.
+    #[error("aggregationParameterMismatch")]
+    AggregationParameterMismatch { detail: String, task_id: TaskId },
+
+    /// taskprov: Task config mismatch. Sent when a taskprov task is re-advertised with parameters
+    /// that are incompatible with the task as it was first configured.
+    #[error("taskprovMismatch")]
+    TaskprovMismatch { detail: String, task_id: TaskId },
+
     /// Invalid message. Sent in response to a malformed or unexpected message.
     #[error("invalidMessage")]
     InvalidMessage {
@@ -116,14 +153,18 @@ impl DapAbort {
             | Self::BatchMismatch { detail, task_id }
             | Self::BatchOverlap { detail, task_id }
             | Self::InvalidBatchSize { detail, task_id }
+            | Self::OutdatedConfig { detail, task_id }
             | Self::QueryMismatch { detail, task_id }
-            | Self::UnauthorizedRequest { detail, task_id } => (Some(task_id), Some(detail), None),
+            | Self::UnauthorizedRequest { detail, task_id }
+            | Self::VdafVersionMismatch { detail, task_id }
+            | Self::AggregationParameterMismatch { detail, task_id }
+            | Self::TaskprovMismatch { detail, task_id } => (Some(task_id), Some(detail), None),
             Self::MissingTaskId => (
                 None,
                 Some("A task ID must be specified in the query parameter of the request.".into()),
                 None,
             ),
-            Self::BadRequest(detail) | Self::ReportRejected { detail } => {
+            Self::BadRequest(detail) | Self::Conflict(detail) | Self::ReportRejected { detail } => {
                 (None, Some(detail), None)
             }
             Self::RoundMismatch {
@@ -140,7 +181,10 @@ impl DapAbort {
                 Some(agg_job_id_base64url),
             ),
             Self::InvalidMessage { detail, task_id } => (task_id, Some(detail), None),
-            Self::ReportTooLate | Self::UnrecognizedTask => (None, None, None),
+            Self::ReportTooEarly
+            | Self::ReportTooLate
+            | Self::ServiceUnavailable { .. }
+            | Self::UnrecognizedTask => (None, None, None),
         };
 
         ProblemDetails {
@@ -153,6 +197,57 @@ impl DapAbort {
         }
     }
 
+    /// Construct the response for this abort: a problem details document, sent with the HTTP
+    /// status code for the abort.
+    pub fn into_problem_response(self, version: DapVersion) -> DapResponse {
+        let status = self.status_code();
+        self.into_problem_details().into_response(version, status)
+    }
+
+    /// The HTTP status code to respond with for this abort.
+    pub fn status_code(&self) -> u16 {
+        // The DAP spec requires aborts to be sent with status 400 unless specified otherwise. Each
+        // variant is listed so that new aborts get an explicit status.
+        match self {
+            Self::BadRequest(..)
+            | Self::BatchInvalid { .. }
+            | Self::BatchMismatch { .. }
+            | Self::BatchOverlap { .. }
+            | Self::InvalidBatchSize { .. }
+            | Self::InvalidTask { .. }
+            | Self::MissingTaskId
+            | Self::OutdatedConfig { .. }
+            | Self::QueryMismatch { .. }
+            | Self::ReportRejected { .. }
+            | Self::ReportTooEarly
+            | Self::ReportTooLate
+            | Self::RoundMismatch { .. }
+            | Self::UnauthorizedRequest { .. }
+            | Self::UnrecognizedAggregationJob { .. }
+            | Self::VdafVersionMismatch { .. }
+            | Self::AggregationParameterMismatch { .. }
+            | Self::TaskprovMismatch { .. }
+            | Self::InvalidMessage { .. }
+            | Self::UnrecognizedTask => 400,
+            Self::Conflict(..) => 409,
+            Self::ServiceUnavailable { .. } => 503,
+        }
+    }
+
+    /// The number of seconds after which the request may be retried, if the abort is temporary.
+    /// This is sent in the retry-after header of the response.
+    pub fn retry_after(&self) -> Option<Duration> {
+        match self {
+            Self::ServiceUnavailable { retry_after } => Some(*retry_after),
+            _ => None,
+        }
+    }
+
+    /// The title of the problem document for this abort.
+    pub fn title(&self) -> &'static str {
+        self.title_and_type().0
+    }
+
     /// Abort due to unexpected value for HTTP content-type header.
     pub fn content_type<S>(req: &DapRequest<S>, expected: DapMediaType) -> Self {
         let want_str = expected
@@ -181,9 +276,38 @@ impl DapAbort {
     }
 
     #[inline]
-    pub(crate) fn batch_overlap(task_id: &TaskId, batch_sel: impl std::fmt::Display) -> Self {
+    pub(crate) fn vdaf_version_mismatch(task_id: &TaskId, indicated: &str, expected: &str) -> Self {
+        Self::VdafVersionMismatch {
+            detail: format!("VDAF version of request does not match the Helper: got {indicated}; want {expected}"),
+            task_id: *task_id,
+        }
+    }
+
+    #[inline]
+    pub(crate) fn batch_overlap(
+        task_id: &TaskId,
+        batch_sel: impl std::fmt::Display,
+        overlapping: &[DapBatchBucket],
+    ) -> Self {
+        // A long-running batch may overlap with many buckets, so only the first few are listed.
+        const MAX_LISTED_BUCKETS: usize = 8;
+
+        let mut sorted = overlapping.iter().collect::<Vec<_>>();
+        sorted.sort();
+        let listed = sorted
+            .iter()
+            .take(MAX_LISTED_BUCKETS)
+            .map(ToString::to_string)
+            .collect::<Vec<_>>();
+        let unlisted = match overlapping.len() - listed.len() {
+            0 => String::new(),
+            n => format!(" and {n} more"),
+        };
         Self::BatchOverlap {
-            detail: format!("The batch indicated by the request: {batch_sel}"),
+            detail: format!(
+                "The batch indicated by the request: {batch_sel}. Previously collected: {}{unlisted}",
+                listed.join(", ")
+            ),
             task_id: *task_id,
         }
     }
@@ -238,6 +362,10 @@ impl DapAbort {
             ),
             Self::InvalidBatchSize { .. } => ("Batch size is invalid", Some(self.to_string())),
             Self::InvalidTask { .. } => ("Opted out of Taskprov task", Some(self.to_string())),
+            Self::OutdatedConfig { .. } => (
+                "Report was encrypted under an outdated HPKE configuration",
+                Some(self.to_string()),
+            ),
             Self::QueryMismatch { .. } => {
                 ("Query type does not match the task", Some(self.to_string()))
             }
@@ -250,6 +378,10 @@ impl DapAbort {
                 Some(self.to_string()),
             ),
             Self::ReportRejected { .. } => ("Report rejected", Some(self.to_string())),
+            Self::ReportTooEarly => (
+                "Report timestamp is too far in the future",
+                Some(self.to_string()),
+            ),
             Self::ReportTooLate => (
                 "The requested task expires after report timestamp",
                 Some(self.to_string()),
@@ -265,7 +397,22 @@ impl DapAbort {
                 "Task indicated by request is not recognized",
                 Some(self.to_string()),
             ),
+            // Not defined by the DAP spec, so there is no abort type.
+            Self::VdafVersionMismatch { .. } => (
+                "VDAF implementation of the Leader does not match the Helper",
+                None,
+            ),
+            Self::AggregationParameterMismatch { .. } => (
+                "Aggregation parameter does not match earlier aggregation jobs for the batch",
+                None,
+            ),
+            Self::TaskprovMismatch { .. } => (
+                "Taskprov task configuration is incompatible with the existing task",
+                None,
+            ),
             Self::BadRequest(..) => ("Bad request", None),
+            Self::Conflict(..) => ("Conflict", None),
+            Self::ServiceUnavailable { .. } => ("Service unavailable", None),
         };
 
         (
@@ -289,6 +436,86 @@ impl DapAbort {
             task_id: Some(task_id),
         }
     }
+
+    /// Interpret a problem details document received from a peer, e.g., so that the Leader can
+    /// decide whether to retry a request the Helper rejected. Returns `None` if the document's
+    /// type is not a DAP abort type or if it lacks a field the abort requires, such as the task ID.
+    pub fn from_problem_details(problem: &ProblemDetails) -> Option<Self> {
+        let abort_type = problem
+            .typ
+            .as_deref()?
+            .strip_prefix("urn:ietf:params:ppm:dap:error:")?;
+        let task_id = problem
+            .task_id
+            .as_ref()
+            .map(|task_id| TaskId::try_from_base64url(task_id).ok_or(()))
+            .transpose()
+            .ok()?;
+        let detail = problem.detail.clone().unwrap_or_default();
+        let abort = match abort_type {
+            "batchInvalid" => Self::BatchInvalid {
+                detail,
+                task_id: task_id?,
+            },
+            "batchMismatch" => Self::BatchMismatch {
+                detail,
+                task_id: task_id?,
+            },
+            "batchOverlap" => Self::BatchOverlap {
+                detail,
+                task_id: task_id?,
+            },
+            "invalidBatchSize" => Self::InvalidBatchSize {
+                detail,
+                task_id: task_id?,
+            },
+            "invalidTask" => Self::InvalidTask {
+                detail,
+                task_id: task_id?,
+            },
+            "missingTaskID" => Self::MissingTaskId,
+            "outdatedConfig" => Self::OutdatedConfig {
+                detail,
+                task_id: task_id?,
+            },
+            "queryMismatch" => Self::QueryMismatch {
+                detail,
+                task_id: task_id?,
+            },
+            "reportRejected" => Self::ReportRejected { detail },
+            "reportTooEarly" => Self::ReportTooEarly,
+            "reportTooLate" => Self::ReportTooLate,
+            "roundMismatch" => Self::RoundMismatch {
+                detail,
+                task_id: task_id?,
+                agg_job_id_base64url: problem.agg_job_id.clone()?,
+            },
+            "unauthorizedRequest" => Self::UnauthorizedRequest {
+                detail,
+                task_id: task_id?,
+            },
+            "unrecognizedAggregationJob" => Self::UnrecognizedAggregationJob {
+                task_id: task_id?,
+                agg_job_id_base64url: problem.agg_job_id.clone()?,
+            },
+            "invalidMessage" => Self::InvalidMessage { detail, task_id },
+            "unrecognizedTask" => Self::UnrecognizedTask,
+            _ => return None,
+        };
+        Some(abort)
+    }
+}
+
+impl ProblemDetails {
+    pub(crate) fn into_response(self, version: DapVersion, status: u16) -> DapResponse {
+        DapResponse {
+            version,
+            status,
+            media_type: DapMediaType::Problem,
+            payload: serde_json::to_vec(&self)
+                .expect("serializing a problem details document should never fail"),
+        }
+    }
 }
 
 /// A problem details document compatible with RFC 7807.
@@ -314,3 +541,209 @@ pub struct ProblemDetails {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub detail: Option<String>,
 }
+
+#[cfg(test)]
+mod test {
+    use super::{DapAbort, ProblemDetails};
+    use crate::{
+        fatal_error,
+        messages::{Base64Encode, TaskId},
+        DapBatchBucket, DapError, DapMediaType, DapResponse, DapVersion,
+    };
+    use assert_matches::assert_matches;
+
+    fn decode(resp: &DapResponse) -> ProblemDetails {
+        assert_eq!(resp.media_type, DapMediaType::Problem);
+        serde_json::from_slice(&resp.payload).unwrap()
+    }
+
+    #[test]
+    fn problem_response() {
+        let task_id = TaskId([1; 32]);
+
+        let resp = DapAbort::UnrecognizedTask.into_problem_response(DapVersion::DraftLatest);
+        assert_eq!(resp.status, 400);
+        assert_eq!(resp.version, DapVersion::DraftLatest);
+        let problem = decode(&resp);
+        assert_eq!(
+            problem.typ.as_deref(),
+            Some("urn:ietf:params:ppm:dap:error:unrecognizedTask")
+        );
+        assert_eq!(problem.title, "Task indicated by request is not recognized");
+
+        let resp = DapAbort::ReportTooLate.into_problem_response(DapVersion::Draft02);
+        assert_eq!(resp.status, 400);
+        assert_eq!(resp.version, DapVersion::Draft02);
+        assert_eq!(
+            decode(&resp).typ.as_deref(),
+            Some("urn:ietf:params:ppm:dap:error:reportTooLate")
+        );
+
+        let resp = DapAbort::batch_overlap(&task_id, "batch", &[])
+            .into_problem_response(DapVersion::DraftLatest);
+        assert_eq!(resp.status, 400);
+        let problem = decode(&resp);
+        assert_eq!(
+            problem.typ.as_deref(),
+            Some("urn:ietf:params:ppm:dap:error:batchOverlap")
+        );
+        assert_eq!(problem.task_id, Some(task_id.to_base64url()));
+
+        // Only the first few overlapping buckets are listed.
+        let overlapping = (0..20)
+            .map(|batch_window| DapBatchBucket::TimeInterval { batch_window })
+            .collect::<Vec<_>>();
+        let resp = DapAbort::batch_overlap(&task_id, "batch", &overlapping)
+            .into_problem_response(DapVersion::DraftLatest);
+        let detail = decode(&resp).detail.unwrap();
+        assert!(detail.contains("batch_window(0)"), "{detail}");
+        assert!(detail.contains("batch_window(7)"), "{detail}");
+        assert!(!detail.contains("batch_window(8)"), "{detail}");
+        assert!(detail.ends_with(" and 12 more"), "{detail}");
+
+        let resp = DapAbort::UnauthorizedRequest {
+            detail: "bad token".into(),
+            task_id,
+        }
+        .into_problem_response(DapVersion::DraftLatest);
+        assert_eq!(resp.status, 400);
+        let problem = decode(&resp);
+        assert_eq!(
+            problem.typ.as_deref(),
+            Some("urn:ietf:params:ppm:dap:error:unauthorizedRequest")
+        );
+        assert_eq!(problem.task_id, Some(task_id.to_base64url()));
+        assert_eq!(problem.detail.as_deref(), Some("bad token"));
+
+        // Bad requests are not a DAP abort type.
+        let resp =
+            DapAbort::BadRequest("oops".into()).into_problem_response(DapVersion::DraftLatest);
+        assert_eq!(resp.status, 400);
+        assert_eq!(decode(&resp).typ, None);
+
+        // Neither are conflicts, which have their own status.
+        let resp = DapAbort::Conflict("already exists".into())
+            .into_problem_response(DapVersion::DraftLatest);
+        assert_eq!(resp.status, 409);
+        let problem = decode(&resp);
+        assert_eq!(problem.typ, None);
+        assert_eq!(problem.detail.as_deref(), Some("already exists"));
+
+        let abort = DapAbort::ServiceUnavailable { retry_after: 30 };
+        assert_eq!(abort.retry_after(), Some(30));
+        let resp = abort.into_problem_response(DapVersion::DraftLatest);
+        assert_eq!(resp.status, 503);
+        assert_eq!(decode(&resp).typ, None);
+    }
+
+    #[test]
+    fn abort_from_problem_details() {
+        let task_id = TaskId([1; 32]);
+
+        let problem: ProblemDetails = serde_json::from_value(serde_json::json!({
+            "type": "urn:ietf:params:ppm:dap:error:unrecognizedTask",
+            "title": "Task indicated by request is not recognized",
+        }))
+        .unwrap();
+        assert_matches!(
+            DapAbort::from_problem_details(&problem),
+            Some(DapAbort::UnrecognizedTask)
+        );
+
+        let problem: ProblemDetails = serde_json::from_value(serde_json::json!({
+            "type": "urn:ietf:params:ppm:dap:error:batchOverlap",
+            "title": "The selected batch overlaps with a previous batch",
+            "taskid": task_id.to_base64url(),
+            "detail": "already collected",
+        }))
+        .unwrap();
+        assert_matches!(
+            DapAbort::from_problem_details(&problem),
+            Some(DapAbort::BatchOverlap { detail, task_id: got }) => {
+                assert_eq!(detail, "already collected");
+                assert_eq!(got, task_id);
+            }
+        );
+
+        // An abort that carries a task ID can't be interpreted without one.
+        let problem: ProblemDetails = serde_json::from_value(serde_json::json!({
+            "type": "urn:ietf:params:ppm:dap:error:batchOverlap",
+            "title": "The selected batch overlaps with a previous batch",
+        }))
+        .unwrap();
+        assert_matches!(DapAbort::from_problem_details(&problem), None);
+
+        // Problem types that aren't DAP aborts are not interpreted.
+        for typ in [
+            Some("urn:ietf:params:ppm:dap:error:batchCollected"),
+            Some("about:blank"),
+            None,
+        ] {
+            let problem = ProblemDetails {
+                title: "Some problem".into(),
+                typ: typ.map(ToString::to_string),
+                task_id: None,
+                agg_job_id: None,
+                instance: None,
+                detail: None,
+            };
+            assert_matches!(DapAbort::from_problem_details(&problem), None);
+        }
+    }
+
+    #[test]
+    fn abort_from_problem_details_round_trip() {
+        let task_id = TaskId([1; 32]);
+        for abort in [
+            DapAbort::BatchInvalid {
+                detail: "bad".into(),
+                task_id,
+            },
+            DapAbort::InvalidBatchSize {
+                detail: "too small".into(),
+                task_id,
+            },
+            DapAbort::MissingTaskId,
+            DapAbort::ReportRejected {
+                detail: "replayed".into(),
+            },
+            DapAbort::ReportTooEarly,
+            DapAbort::RoundMismatch {
+                detail: "round 2".into(),
+                task_id,
+                agg_job_id_base64url: "AAAA".into(),
+            },
+            DapAbort::UnrecognizedAggregationJob {
+                task_id,
+                agg_job_id_base64url: "AAAA".into(),
+            },
+            DapAbort::InvalidMessage {
+                detail: "codec error".into(),
+                task_id: None,
+            },
+        ] {
+            let problem = abort.into_problem_details();
+            let round_tripped = DapAbort::from_problem_details(&problem)
+                .unwrap()
+                .into_problem_details();
+            assert_eq!(
+                serde_json::to_value(round_tripped).unwrap(),
+                serde_json::to_value(problem).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn problem_response_for_fatal_error() {
+        let resp = fatal_error!(err = "something went wrong")
+            .into_problem_response(DapVersion::DraftLatest);
+        assert_eq!(resp.status, 500);
+        let problem = decode(&resp);
+        assert_eq!(problem.typ, None);
+        assert_eq!(problem.title, "Internal server error");
+
+        let resp = DapError::Abort(DapAbort::UnrecognizedTask)
+            .into_problem_response(DapVersion::DraftLatest);
+        assert_eq!(resp.status, 400);
+    }
+}
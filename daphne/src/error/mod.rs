@@ -5,7 +5,7 @@ pub mod aborts;
 
 use std::fmt::{Debug, Display};
 
-use crate::{messages::TransitionFailure, vdaf::VdafError};
+use crate::{messages::TransitionFailure, vdaf::VdafError, DapResponse, DapSender, DapVersion};
 pub use aborts::DapAbort;
 use prio::codec::CodecError;
 
@@ -29,6 +29,90 @@ pub enum DapError {
     /// certain conditions, trigger an abort.
     #[error("transition error: {0}")]
     Transition(#[from] TransitionFailure),
+
+    /// Collector: An encrypted aggregate share could not be consumed.
+    #[error("collect error: {0}")]
+    Collect(#[from] CollectError),
+
+    /// The Aggregator's configuration is invalid.
+    #[error("config error: {0}")]
+    Config(#[from] ConfigError),
+
+    /// Client: The measurement can't be sharded under the task's VDAF.
+    #[error("measurement error: {0}")]
+    Measurement(#[from] MeasurementError),
+
+    /// Leader: A request to the peer failed before the peer could handle it.
+    #[error("peer error: {0}")]
+    Peer(#[from] PeerError),
+}
+
+/// Reasons the Collector may fail to decrypt an aggregate share or to combine aggregate results.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, thiserror::Error)]
+pub enum CollectError {
+    /// The aggregate share is encrypted under an HPKE config the Collector doesn't have.
+    #[error("aggregate share encrypted under unexpected HPKE config {config_id}")]
+    UnexpectedHpkeConfigId { config_id: u8 },
+
+    /// The aggregate share is encrypted under the Collector's HPKE config, but decryption failed,
+    /// e.g., because the ciphertext was corrupted.
+    #[error("failed to decrypt aggregate share")]
+    HpkeDecryptError,
+
+    /// More than one aggregate share was sent by the same Aggregator.
+    #[error("received more than one aggregate share from the {sender:?}")]
+    DuplicateAggShare { sender: DapSender },
+
+    /// The aggregate share of one of the Aggregators is missing.
+    #[error("missing the aggregate share from the {sender:?}")]
+    MissingAggShare { sender: DapSender },
+
+    /// There were no aggregate results to combine.
+    #[error("no aggregate results to combine")]
+    NoAggResults,
+
+    /// The aggregate results to combine are not all of the same type and length.
+    #[error("tried to combine aggregate results of different types or lengths")]
+    AggResultMismatch,
+
+    /// The combined aggregate result doesn't fit in its type.
+    #[error("overflow while combining aggregate results")]
+    AggResultOverflow,
+}
+
+/// Reasons the configuration of an Aggregator may be rejected.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, thiserror::Error)]
+pub enum ConfigError {
+    /// More than one HPKE receiver config has the same ID, so it would be ambiguous which one to
+    /// decrypt a ciphertext with.
+    #[error("more than one HPKE receiver config has ID {config_id}")]
+    DuplicateHpkeConfigId { config_id: u8 },
+}
+
+/// Reasons a Client's measurement may be rejected before it is sharded.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, thiserror::Error)]
+pub enum MeasurementError {
+    /// The measurement is not of the type the VDAF expects.
+    #[error("unexpected measurement type for the VDAF")]
+    UnexpectedType,
+
+    /// The measurement has the expected type, but is out of range, e.g., a count that isn't 0 or
+    /// 1, or a vector of the wrong length.
+    #[error("measurement is out of range for the VDAF")]
+    OutOfRange,
+}
+
+/// Reasons a request to the peer may fail before the peer could handle it. These failures are
+/// transient, so sending the request again may succeed.
+#[derive(Clone, Debug, PartialEq, Eq, thiserror::Error)]
+pub enum PeerError {
+    /// The peer could not be reached, e.g., because the connection failed or was interrupted.
+    #[error("failed to reach the peer: {0}")]
+    Unreachable(String),
+
+    /// The peer responded with a server error.
+    #[error("peer responded with status {status}")]
+    ServerError { status: u16 },
 }
 
 impl DapError {
@@ -39,7 +123,7 @@ impl DapError {
 
         ProblemDetails {
             typ: None,
-            title: "Internal server error".into(),
+            title: self.title().into(),
             agg_job_id: None,
             task_id: None,
             instance: None,
@@ -47,6 +131,39 @@ impl DapError {
         }
     }
 
+    /// Construct the response to send to the peer for this error. Aborts are mapped to their
+    /// problem document and status code; any other error is an internal server error.
+    pub fn into_problem_response(self, version: DapVersion) -> DapResponse {
+        if let Self::Abort(a) = self {
+            return a.into_problem_response(version);
+        }
+
+        self.into_problem_details().into_response(version, 500)
+    }
+
+    /// The title of the problem document for this error.
+    pub fn title(&self) -> &'static str {
+        match self {
+            Self::Abort(a) => a.title(),
+            Self::Fatal(..)
+            | Self::Transition(..)
+            | Self::Collect(..)
+            | Self::Config(..)
+            | Self::Measurement(..)
+            | Self::Peer(..) => "Internal server error",
+        }
+    }
+
+    /// Whether the operation that failed may succeed if it is tried again. This is the case for
+    /// failures to reach the peer, server errors returned by the peer, and temporary aborts. Any
+    /// other error is deterministic or a bug, and would recur.
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            self,
+            Self::Peer(..) | Self::Abort(DapAbort::ServiceUnavailable { .. })
+        )
+    }
+
     /// Construct a fatal encoding error.
     pub fn encoding(e: CodecError) -> DapError {
         DapError::Fatal(FatalDapError(format!(
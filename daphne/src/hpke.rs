@@ -12,6 +12,7 @@ use hpke_rs_crypto::{
 use hpke_rs_rust_crypto::HpkeRustCrypto as ImplHpkeCrypto;
 
 use crate::{
+    error::{CollectError, ConfigError},
     fatal_error,
     messages::{decode_u16_bytes, encode_u16_bytes, HpkeCiphertext, TaskId, TransitionFailure},
     DapError, DapVersion,
@@ -19,7 +20,7 @@ use crate::{
 use async_trait::async_trait;
 use prio::codec::{CodecError, Decode, Encode};
 use serde::{Deserialize, Serialize};
-use std::io::Cursor;
+use std::{collections::HashSet, io::Cursor};
 
 // Various algorithm constants
 const KEM_ID_X25519_HKDF_SHA256: u16 = 0x0020;
@@ -65,8 +66,13 @@ fn check_suite<T: HpkeCrypto>(
 }
 
 /// Codepoint for KEM schemes compatible with HPKE.
+///
+/// `NotImplemented` only ever holds a codepoint that has no named variant. IDs decoded from the
+/// wire or deserialized are normalized accordingly, and IDs constructed from a codepoint should be
+/// constructed with `From<u16>`, so that encoding and then decoding any ID results in the same ID.
+/// The same holds for [`HpkeKdfId`] and [`HpkeAeadId`].
 #[derive(Clone, Copy, Deserialize, Serialize, Debug, PartialEq, Eq)]
-#[serde(rename_all = "snake_case")]
+#[serde(rename_all = "snake_case", from = "HpkeKemIdSerde")]
 #[cfg_attr(any(test, feature = "test-utils"), derive(deepsize::DeepSizeOf))]
 pub enum HpkeKemId {
     P256HkdfSha256,
@@ -74,6 +80,25 @@ pub enum HpkeKemId {
     NotImplemented(u16),
 }
 
+/// The serialized form of [`HpkeKemId`], which may not be normalized.
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum HpkeKemIdSerde {
+    P256HkdfSha256,
+    X25519HkdfSha256,
+    NotImplemented(u16),
+}
+
+impl From<HpkeKemIdSerde> for HpkeKemId {
+    fn from(kem_id: HpkeKemIdSerde) -> Self {
+        match kem_id {
+            HpkeKemIdSerde::P256HkdfSha256 => Self::P256HkdfSha256,
+            HpkeKemIdSerde::X25519HkdfSha256 => Self::X25519HkdfSha256,
+            HpkeKemIdSerde::NotImplemented(x) => Self::from(x),
+        }
+    }
+}
+
 impl From<HpkeKemId> for u16 {
     fn from(kem_id: HpkeKemId) -> Self {
         match kem_id {
@@ -96,13 +121,30 @@ impl From<u16> for HpkeKemId {
 
 /// Codepoint for KDF schemes compatible with HPKE.
 #[derive(Clone, Copy, Deserialize, Serialize, Debug, PartialEq, Eq)]
-#[serde(rename_all = "snake_case")]
+#[serde(rename_all = "snake_case", from = "HpkeKdfIdSerde")]
 #[cfg_attr(any(test, feature = "test-utils"), derive(deepsize::DeepSizeOf))]
 pub enum HpkeKdfId {
     HkdfSha256,
     NotImplemented(u16),
 }
 
+/// The serialized form of [`HpkeKdfId`], which may not be normalized.
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum HpkeKdfIdSerde {
+    HkdfSha256,
+    NotImplemented(u16),
+}
+
+impl From<HpkeKdfIdSerde> for HpkeKdfId {
+    fn from(kdf_id: HpkeKdfIdSerde) -> Self {
+        match kdf_id {
+            HpkeKdfIdSerde::HkdfSha256 => Self::HkdfSha256,
+            HpkeKdfIdSerde::NotImplemented(x) => Self::from(x),
+        }
+    }
+}
+
 impl From<HpkeKdfId> for u16 {
     fn from(kdf_id: HpkeKdfId) -> Self {
         match kdf_id {
@@ -123,13 +165,30 @@ impl From<u16> for HpkeKdfId {
 
 /// Codepoint for AEAD schemes compatible with HPKE.
 #[derive(Clone, Copy, Deserialize, Serialize, Debug, PartialEq, Eq)]
-#[serde(rename_all = "snake_case")]
+#[serde(rename_all = "snake_case", from = "HpkeAeadIdSerde")]
 #[cfg_attr(any(test, feature = "test-utils"), derive(deepsize::DeepSizeOf))]
 pub enum HpkeAeadId {
     Aes128Gcm,
     NotImplemented(u16),
 }
 
+/// The serialized form of [`HpkeAeadId`], which may not be normalized.
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum HpkeAeadIdSerde {
+    Aes128Gcm,
+    NotImplemented(u16),
+}
+
+impl From<HpkeAeadIdSerde> for HpkeAeadId {
+    fn from(aead_id: HpkeAeadIdSerde) -> Self {
+        match aead_id {
+            HpkeAeadIdSerde::Aes128Gcm => Self::Aes128Gcm,
+            HpkeAeadIdSerde::NotImplemented(x) => Self::from(x),
+        }
+    }
+}
+
 impl From<HpkeAeadId> for u16 {
     fn from(aead_id: HpkeAeadId) -> Self {
         match aead_id {
@@ -231,6 +290,32 @@ pub trait HpkeDecrypter {
         aad: &[u8],
         ciphertext: &HpkeCiphertext,
     ) -> Result<Vec<u8>, DapError>;
+
+    /// Collector: Decrypt an encrypted aggregate share. Unlike [`Self::hpke_decrypt`], the
+    /// config ID of the ciphertext is checked before decrypting, so that an aggregate share
+    /// encrypted under the wrong HPKE config can be told apart from one that fails to decrypt.
+    async fn hpke_decrypt_agg_share(
+        &self,
+        task_id: &TaskId,
+        info: &[u8],
+        aad: &[u8],
+        ciphertext: &HpkeCiphertext,
+    ) -> Result<Vec<u8>, DapError> {
+        if !self.can_hpke_decrypt(task_id, ciphertext.config_id).await? {
+            return Err(CollectError::UnexpectedHpkeConfigId {
+                config_id: ciphertext.config_id,
+            }
+            .into());
+        }
+        self.hpke_decrypt(task_id, info, aad, ciphertext)
+            .await
+            .map_err(|e| match e {
+                DapError::Transition(TransitionFailure::HpkeDecryptError) => {
+                    CollectError::HpkeDecryptError.into()
+                }
+                e => e,
+            })
+    }
 }
 
 /// Struct that combines `HpkeConfig` and `HpkeSecretKey`
@@ -307,6 +392,22 @@ impl HpkeReceiverConfig {
     }
 }
 
+/// Check that no two HPKE receiver configs in `hpke_receiver_config_list` have the same ID.
+pub fn validate_hpke_receiver_config_list<'a>(
+    hpke_receiver_config_list: impl IntoIterator<Item = &'a HpkeReceiverConfig>,
+) -> Result<(), DapError> {
+    let mut config_ids = HashSet::new();
+    for receiver in hpke_receiver_config_list {
+        if !config_ids.insert(receiver.config.id) {
+            return Err(ConfigError::DuplicateHpkeConfigId {
+                config_id: receiver.config.id,
+            }
+            .into());
+        }
+    }
+    Ok(())
+}
+
 impl TryFrom<(HpkeConfig, HpkePrivateKey)> for HpkeReceiverConfig {
     type Error = DapError;
     /// Create a new HPKE receiver context given an `HpkeConfig` and a corresponding private key.
@@ -424,6 +525,7 @@ mod test {
     use hpke_rs::{Hpke, HpkePrivateKey, HpkePublicKey, Mode};
     use hpke_rs_crypto::types::{AeadAlgorithm, KdfAlgorithm, KemAlgorithm};
     use hpke_rs_rust_crypto::HpkeRustCrypto as ImplHpkeCrypto;
+    use proptest::prelude::*;
 
     #[test]
     fn encrypt_roundtrip_x25519_hkdf_sha256() {
@@ -483,4 +585,52 @@ mod test {
         let bad_private_key = HpkePrivateKey::from(vec![0; 20]);
         assert!(HpkeReceiverConfig::try_from((config, bad_private_key)).is_err());
     }
+
+    #[test]
+    fn deserialize_not_implemented_known_id() {
+        assert_eq!(
+            serde_json::from_str::<HpkeKemId>(r#"{"not_implemented":32}"#).unwrap(),
+            HpkeKemId::X25519HkdfSha256
+        );
+        assert_eq!(
+            serde_json::from_str::<HpkeKdfId>(r#"{"not_implemented":1}"#).unwrap(),
+            HpkeKdfId::HkdfSha256
+        );
+        assert_eq!(
+            serde_json::from_str::<HpkeAeadId>(r#"{"not_implemented":1}"#).unwrap(),
+            HpkeAeadId::Aes128Gcm
+        );
+    }
+
+    proptest! {
+        #[test]
+        fn kem_id_roundtrip(x in any::<u16>()) {
+            let kem_id = HpkeKemId::from(x);
+            prop_assert_eq!(u16::from(kem_id), x);
+            let json = serde_json::to_string(&kem_id).unwrap();
+            prop_assert_eq!(serde_json::from_str::<HpkeKemId>(&json).unwrap(), kem_id);
+            let json = serde_json::to_string(&HpkeKemId::NotImplemented(x)).unwrap();
+            prop_assert_eq!(serde_json::from_str::<HpkeKemId>(&json).unwrap(), kem_id);
+        }
+
+        #[test]
+        fn kdf_id_roundtrip(x in any::<u16>()) {
+            let kdf_id = HpkeKdfId::from(x);
+            prop_assert_eq!(u16::from(kdf_id), x);
+            let json = serde_json::to_string(&kdf_id).unwrap();
+            prop_assert_eq!(serde_json::from_str::<HpkeKdfId>(&json).unwrap(), kdf_id);
+            let json = serde_json::to_string(&HpkeKdfId::NotImplemented(x)).unwrap();
+            prop_assert_eq!(serde_json::from_str::<HpkeKdfId>(&json).unwrap(), kdf_id);
+        }
+
+        #[test]
+        fn aead_id_roundtrip(x in any::<u16>()) {
+            let aead_id = HpkeAeadId::from(x);
+            prop_assert_eq!(u16::from(aead_id), x);
+            let json = serde_json::to_string(&aead_id).unwrap();
+            prop_assert_eq!(serde_json::from_str::<HpkeAeadId>(&json).unwrap(), aead_id);
+            let json = serde_json::to_string(&HpkeAeadId::NotImplemented(x)).unwrap();
+            prop_assert_eq!(serde_json::from_str::<HpkeAeadId>(&json).unwrap(), aead_id);
+        }
+    }
 }
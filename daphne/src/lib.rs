@@ -55,11 +55,12 @@ pub mod testing;
 pub mod vdaf;
 
 use crate::{
-    error::DapAbort,
+    error::{CollectError, DapAbort},
     hpke::HpkeReceiverConfig,
     messages::{
-        AggregationJobId, BatchId, BatchSelector, Collection, CollectionJobId,
-        Draft02AggregationJobId, Duration, Interval, PartialBatchSelector, ReportId, TaskId, Time,
+        AggregationJobId, BatchId, BatchSelector, Collection, CollectionFormat, CollectionJobId,
+        Draft02AggregationJobId, Duration, HpkeCiphertext, Interval, PartialBatchSelector, Report,
+        ReportId, TaskId, Time,
     },
     vdaf::{
         Prio3Config, VdafAggregateShare, VdafConfig, VdafPrepMessage, VdafPrepState, VdafVerifyKey,
@@ -159,9 +160,97 @@ pub struct DapGlobalConfig {
     /// draft-wang-ppm-dap-taskprov: Indicates if the taskprov extension is enabled.
     #[serde(default)]
     pub allow_taskprov: bool,
+
+    /// How long the Helper retains the aggregate share produced for an aggregate share request
+    /// that carries an idempotency token. A retry of the request with the same token within this
+    /// window is answered with the retained aggregate share.
+    #[serde(default)]
+    pub agg_share_idempotency_window: Duration,
+
+    /// IDs of HPKE configs that have been revoked, e.g., because the private key was compromised.
+    /// A revoked config is neither advertised nor used for decryption.
+    #[serde(default)]
+    pub revoked_hpke_config_ids: Vec<u8>,
+
+    /// Leader: Number of recently uploaded reports to remember in order to skip redundant storage
+    /// writes when a Client retries an upload. Set to `0` to disable.
+    #[serde(default)]
+    pub upload_dedup_cache_capacity: usize,
+
+    /// Leader: Maximum number of aggregation jobs per task that may be in flight at once. Jobs
+    /// beyond the limit are left in the work queue until a running job completes. This keeps a
+    /// single busy task from overloading the Helper. Set to `0` to disable.
+    #[serde(default)]
+    pub max_concurrent_agg_jobs_per_task: usize,
+
+    /// Leader: Start aggregating the reports in a time-interval bucket as soon as this many have
+    /// been uploaded, rather than waiting for a collection job that covers the bucket. `Some(0)`
+    /// means the task's effective minimum batch size is used as the threshold. If not set, then
+    /// aggregation only begins once a collection job is initialized. Eager aggregation is skipped
+    /// for VDAFs that take an aggregation parameter, as the parameter isn't known until
+    /// collection.
+    #[serde(default)]
+    pub eager_agg_threshold: Option<u64>,
+
+    /// Maximum number of report shares in a single aggregation job. The Leader splits larger sets
+    /// of reports into multiple aggregation jobs; the Helper rejects requests with more report
+    /// shares before the excess ones are decoded. If not set, then the number of report shares is
+    /// not limited.
+    #[serde(default)]
+    pub max_report_shares_per_agg_job: Option<usize>,
+
+    /// Helper: Maximum number of transitions to send in a single aggregation job response. Larger
+    /// responses are split into chunks that the Leader fetches one at a time. If not set, then
+    /// responses are never chunked. Only applies to the latest draft.
+    #[serde(default)]
+    pub agg_job_resp_chunk_size: Option<usize>,
+
+    /// Leader: How long a dequeued work item is hidden from the work queue. If the item isn't
+    /// acknowledged within this time, e.g., because the process handling it crashed, then it is
+    /// put back in the queue to be processed again. If not set, then a default of ten minutes is
+    /// used (see [`Self::work_item_visibility_timeout`]).
+    #[serde(default)]
+    pub work_item_visibility_timeout: Option<Duration>,
+
+    /// Leader: Number of times an aggregation job is retried if it fails with a retryable error,
+    /// e.g., because the Helper could not be reached. Once the budget is exhausted, the job fails.
+    /// Set to `0` to disable retries.
+    #[serde(default)]
+    pub agg_job_retry_budget: u32,
+
+    /// Leader: How long to wait before retrying a failed aggregation job. The delay doubles with
+    /// each subsequent retry of the same job.
+    #[serde(default)]
+    pub agg_job_retry_backoff: Duration,
+
+    /// Helper: Fraction of report shares, between `0.0` and `1.0`, whose metadata (report ID,
+    /// timestamp and HPKE config ID) is logged when an aggregation job is initialized. Reports are
+    /// sampled deterministically by report ID. Input shares are never logged. Set to `0.0` to
+    /// disable.
+    #[serde(default)]
+    pub report_debug_sample_rate: f64,
+
+    /// draft-wang-ppm-dap-taskprov: Run the VDAF self-test (see [`VdafConfig::self_test`]) on
+    /// each task configuration the Aggregator opts into, and reject the task if it fails.
+    #[serde(default)]
+    pub taskprov_vdaf_self_test: bool,
+
+    /// Accept tasks whose Leader or Helper endpoint uses plain http rather than https. This is
+    /// meant for test deployments, which run without TLS.
+    #[serde(default)]
+    pub allow_http_urls: bool,
 }
 
 impl DapGlobalConfig {
+    /// Return how long a dequeued work item is hidden from the work queue, falling back to a
+    /// default if none is configured. Leases always expire so that work held by a process that
+    /// crashed is eventually handed out again.
+    pub fn work_item_visibility_timeout(&self) -> Duration {
+        const DEFAULT_WORK_ITEM_VISIBILITY_TIMEOUT: Duration = 600;
+        self.work_item_visibility_timeout
+            .unwrap_or(DEFAULT_WORK_ITEM_VISIBILITY_TIMEOUT)
+    }
+
     /// Generate a list of HPKE receiver configurations, one for each element of supported KEM
     /// algorithm. `first_config_id` is used as the first config ID; subsequent IDs are chosen by
     /// incrementing `first_config_id`.
@@ -188,6 +277,11 @@ impl DapGlobalConfig {
             })
             .collect()
     }
+
+    /// Check whether the HPKE config with the given ID has been revoked.
+    pub fn is_hpke_config_revoked(&self, config_id: u8) -> bool {
+        self.revoked_hpke_config_ids.contains(&config_id)
+    }
 }
 
 /// DAP Query configuration.
@@ -251,13 +345,25 @@ impl std::fmt::Display for DapQueryConfig {
 /// queries, the bucket to which a report is assigned is determined by truncating its timestamp by
 /// the task's `time_precision` parameter; for fixed-size queries, the span consists of a single
 /// bucket, which is the batch determined by the batch ID (i.e., the partial batch selector).
-#[derive(Debug, Clone, Eq, Hash, PartialEq)]
+///
+/// Buckets are ordered so that aggregate shares for a set of buckets can be merged in a
+/// reproducible order.
+#[derive(Debug, Clone, Eq, Hash, PartialEq, PartialOrd, Ord)]
 #[cfg_attr(any(test, feature = "test-utils"), derive(deepsize::DeepSizeOf))]
 pub enum DapBatchBucket {
     FixedSize { batch_id: BatchId },
     TimeInterval { batch_window: Time },
 }
 
+impl std::fmt::Display for DapBatchBucket {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::FixedSize { batch_id } => write!(f, "batch_id({})", batch_id.to_base64url()),
+            Self::TimeInterval { batch_window } => write!(f, "batch_window({batch_window})"),
+        }
+    }
+}
+
 /// A set of values related to reports in the same bucket.
 #[derive(Debug)]
 pub struct DapAggregateSpan<T> {
@@ -320,8 +426,11 @@ impl DapAggregateSpan<DapAggregateShare> {
     /// Panics if two aggregates shares in the span have incompatible types.
     #[cfg(any(test, feature = "test-utils"))]
     pub fn collapsed(self) -> DapAggregateShare {
-        self.span
-            .into_iter()
+        // Merge the buckets in order so that the result doesn't depend on the iteration order of
+        // the span.
+        let mut span = self.span.into_iter().collect::<Vec<_>>();
+        span.sort_by(|(bucket, _), (other_bucket, _)| bucket.cmp(other_bucket));
+        span.into_iter()
             .map(|(_bucket, (agg_share_for_bucket, _reports))| agg_share_for_bucket)
             .reduce(|mut agg_share, agg_share_for_bucket| {
                 agg_share.merge(agg_share_for_bucket).unwrap();
@@ -537,6 +646,26 @@ pub struct DapTaskConfig {
     pub query: DapQueryConfig,
     pub vdaf: VdafConfig,
 
+    /// Offset of the batch windows from the UNIX epoch. Report times are quantized to multiples of
+    /// `time_precision` starting from this offset, e.g., to align batch windows to midnight in a
+    /// local time zone rather than UTC. Defaults to `0`.
+    ///
+    /// The offset is local to this Aggregator: it isn't part of the task configuration exchanged
+    /// with the peer, and a taskprov task always has an offset of `0`. As the Helper checks that
+    /// batch intervals are aligned to its own windows, a non-zero offset only works if the same
+    /// offset is configured for the task on both Aggregators.
+    #[serde(default)]
+    pub time_quantization_offset: Duration,
+
+    /// Whether to align the batch interval of a collection to the batch windows (see
+    /// [`Interval::align_to`](crate::messages::Interval::align_to)) rather than reject it if it is
+    /// misaligned. The aligned interval is only used to select the reports to aggregate; the
+    /// aggregate shares remain bound to the interval the Collector requested. Both Aggregators
+    /// must enable this, as otherwise the Helper rejects the misaligned interval. Defaults to
+    /// `false`.
+    #[serde(default)]
+    pub align_batch_intervals: bool,
+
     /// The time at which the task expires.
     pub expiration: Time,
 
@@ -546,6 +675,11 @@ pub struct DapTaskConfig {
     /// The Collector's HPKE configuration for this task.
     pub collector_hpke_config: HpkeConfig,
 
+    /// Additional HPKE configurations of the Collector that aggregate shares may be encrypted
+    /// under. This allows the Collector to rotate its key without the task being recreated.
+    #[serde(default)]
+    pub collector_hpke_config_allowlist: Vec<HpkeConfig>,
+
     /// Method by which the task was configured.
     #[serde(default)]
     pub method: DapTaskConfigMethod,
@@ -560,10 +694,16 @@ struct ShadowDapTaskConfig {
     min_batch_size: u64,
     query: DapQueryConfig,
     vdaf: VdafConfig,
+    #[serde(default)]
+    time_quantization_offset: Duration,
+    #[serde(default)]
+    align_batch_intervals: bool,
     expiration: Time,
     vdaf_verify_key: VdafVerifyKey,
     collector_hpke_config: HpkeConfig,
     #[serde(default)]
+    collector_hpke_config_allowlist: Vec<HpkeConfig>,
+    #[serde(default)]
     method: DapTaskConfigMethod,
 
     // Deprecated. Indicates that the task was configured via draft-wang-ppm-taskprov. This flag
@@ -582,9 +722,12 @@ impl From<ShadowDapTaskConfig> for DapTaskConfig {
             min_batch_size: shadow.min_batch_size,
             query: shadow.query,
             vdaf: shadow.vdaf,
+            time_quantization_offset: shadow.time_quantization_offset,
+            align_batch_intervals: shadow.align_batch_intervals,
             expiration: shadow.expiration,
             vdaf_verify_key: shadow.vdaf_verify_key,
             collector_hpke_config: shadow.collector_hpke_config,
+            collector_hpke_config_allowlist: shadow.collector_hpke_config_allowlist,
             method: match shadow.method {
                 // If the configuration method is unknown or unspecified, but the deprecated
                 // taskprov flag is set, then set the method to taskprov with unknown info.
@@ -604,12 +747,16 @@ impl deepsize::DeepSizeOf for DapTaskConfig {
             + std::mem::size_of_val(self.leader_url.as_str())
             + std::mem::size_of_val(self.helper_url.as_str())
             + self.time_precision.deep_size_of_children(context)
+            + self.time_quantization_offset.deep_size_of_children(context)
             + self.expiration.deep_size_of_children(context)
             + self.min_batch_size.deep_size_of_children(context)
             + self.query.deep_size_of_children(context)
             + self.vdaf.deep_size_of_children(context)
             + self.vdaf_verify_key.deep_size_of_children(context)
             + self.collector_hpke_config.deep_size_of_children(context)
+            + self
+                .collector_hpke_config_allowlist
+                .deep_size_of_children(context)
     }
 }
 
@@ -627,15 +774,117 @@ impl DapTaskConfig {
         }
     }
 
-    /// Return the greatest multiple of the `time_precision` which is less than or equal to the
-    /// specified time.
+    /// Check that `new` may replace this config for an already configured task, e.g., when a
+    /// taskprov task is re-advertised with updated parameters. The VDAF and query type must be
+    /// unchanged and the task must not expire any sooner than before.
+    pub fn check_compatible_update(&self, task_id: &TaskId, new: &Self) -> Result<(), DapAbort> {
+        let mismatch = |detail: String| {
+            Err(DapAbort::TaskprovMismatch {
+                detail,
+                task_id: *task_id,
+            })
+        };
+
+        if new.vdaf != self.vdaf {
+            return mismatch(format!("VDAF changed from {} to {}", self.vdaf, new.vdaf));
+        }
+
+        if !matches!(
+            (&self.query, &new.query),
+            (DapQueryConfig::TimeInterval, DapQueryConfig::TimeInterval)
+                | (
+                    DapQueryConfig::FixedSize { .. },
+                    DapQueryConfig::FixedSize { .. }
+                )
+        ) {
+            return mismatch("query type changed".into());
+        }
+
+        if new.expiration < self.expiration {
+            return mismatch(format!(
+                "expiration moved back from {} to {}",
+                self.expiration, new.expiration
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Check the invariants of the task configuration. This is meant to be run whenever a task is
+    /// added, whether it is configured statically or advertised via taskprov.
+    pub fn validate(
+        &self,
+        task_id: &TaskId,
+        global_config: &DapGlobalConfig,
+        now: Time,
+    ) -> Result<(), DapAbort> {
+        let invalid = |detail: String| {
+            Err(DapAbort::InvalidTask {
+                detail,
+                task_id: *task_id,
+            })
+        };
+
+        if self.expiration <= now {
+            return invalid(format!("task expired at {}", self.expiration));
+        }
+
+        if self.min_batch_size == 0 {
+            return invalid("minimum batch size must be positive".into());
+        }
+
+        // Batch intervals are made up of whole batch windows, so the maximum batch duration must
+        // be a multiple of the time precision. Otherwise the widest batch interval that can be
+        // collected doesn't line up with the batch windows.
+        if self.time_precision == 0 {
+            return invalid("time precision must be positive".into());
+        }
+        if global_config.max_batch_duration % self.time_precision != 0 {
+            return invalid(format!(
+                "time precision ({}) does not divide the maximum batch duration ({})",
+                self.time_precision, global_config.max_batch_duration
+            ));
+        }
+
+        if let DapQueryConfig::FixedSize {
+            max_batch_size: Some(max_batch_size),
+        } = self.query
+        {
+            if max_batch_size < self.min_batch_size {
+                return invalid(format!(
+                    "maximum batch size ({max_batch_size}) is less than the minimum batch size ({})",
+                    self.min_batch_size
+                ));
+            }
+        }
+
+        for url in [&self.leader_url, &self.helper_url] {
+            match url.scheme() {
+                "https" => {}
+                "http" if global_config.allow_http_urls => {}
+                _ => return invalid(format!("endpoint {url} does not use https")),
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Return the greatest multiple of the `time_precision`, offset by the
+    /// `time_quantization_offset`, which is less than or equal to the specified time. Times before
+    /// the first such window are clamped to zero.
     pub fn quantized_time_lower_bound(&self, time: Time) -> Time {
-        time - (time % self.time_precision)
+        let offset = self.time_quantization_offset % self.time_precision;
+        time.saturating_sub(
+            (time % self.time_precision + self.time_precision - offset) % self.time_precision,
+        )
     }
 
-    /// Return the least multiple of the `time_precision` which is greater than the specified time.
+    /// Return the least multiple of the `time_precision`, offset by the
+    /// `time_quantization_offset`, which is greater than the specified time. This is the
+    /// (exclusive) end of the batch window containing `time`.
     pub fn quantized_time_upper_bound(&self, time: Time) -> Time {
-        self.quantized_time_lower_bound(time) + self.time_precision
+        self.quantized_time_lower_bound(time)
+            .saturating_add(self.time_precision)
     }
 
     /// Return the batch span determined by the given batch selector. The span includes every
@@ -644,31 +893,58 @@ impl DapTaskConfig {
         &self,
         batch_sel: &BatchSelector,
     ) -> Result<HashSet<DapBatchBucket>, DapError> {
+        Ok(self.batch_span_iter_for_sel(batch_sel)?.collect())
+    }
+
+    /// Like [`Self::batch_span_for_sel`], except that the buckets are yielded one at a time rather
+    /// than being collected up front. Use this when the batch interval may span many batch
+    /// windows. Each bucket is yielded once; time-interval buckets are yielded in order.
+    pub fn batch_span_iter_for_sel<'a>(
+        &'a self,
+        batch_sel: &'a BatchSelector,
+    ) -> Result<impl Iterator<Item = DapBatchBucket> + 'a, DapError> {
         if !self.query.is_valid_batch_sel(batch_sel) {
             return Err(fatal_error!(
                 err = "batch selector not compatible with task"
             ));
         }
 
-        match batch_sel {
-            BatchSelector::TimeInterval {
-                batch_interval: Interval { start, duration },
-            } => {
-                let windows = duration / self.time_precision;
-                let mut span = HashSet::with_capacity(usize::try_from(windows).unwrap());
-                for i in 0..windows {
-                    span.insert(DapBatchBucket::TimeInterval {
-                        batch_window: start + i * self.time_precision,
-                    });
-                }
-                Ok(span)
+        let (time_interval_span, fixed_size_bucket) = match batch_sel {
+            BatchSelector::TimeInterval { batch_interval } => {
+                let batch_interval = if self.align_batch_intervals {
+                    batch_interval
+                        .align_to(self)
+                        .ok_or_else(|| fatal_error!(err = "batch interval overflows"))?
+                } else {
+                    batch_interval.clone()
+                };
+
+                // Walk the batch windows overlapping the interval. Since the interval is
+                // half-open, a window beginning at the end of the interval is not included.
+                let batch_windows = std::iter::successors(
+                    batch_interval
+                        .contains(batch_interval.start)
+                        .then(|| self.quantized_time_lower_bound(batch_interval.start)),
+                    move |batch_window| {
+                        let next = batch_window.checked_add(self.time_precision)?;
+                        batch_interval.contains(next).then_some(next)
+                    },
+                );
+                (Some(batch_windows), None)
             }
-            BatchSelector::FixedSizeByBatchId { batch_id } => {
-                Ok(HashSet::from([DapBatchBucket::FixedSize {
+            BatchSelector::FixedSizeByBatchId { batch_id } => (
+                None,
+                Some(DapBatchBucket::FixedSize {
                     batch_id: *batch_id,
-                }]))
-            }
-        }
+                }),
+            ),
+        };
+
+        Ok(time_interval_span
+            .into_iter()
+            .flatten()
+            .map(|batch_window| DapBatchBucket::TimeInterval { batch_window })
+            .chain(fixed_size_bucket))
     }
 
     /// Return the batch span of a set of reports.
@@ -707,6 +983,19 @@ impl DapTaskConfig {
         }
     }
 
+    /// Return the bucket to which a report would be assigned, without assigning it. For
+    /// time-interval queries, the bucket is the batch window containing the report's timestamp.
+    /// For fixed-size queries, the batch is chosen by the Leader when the report is assigned, so
+    /// this returns `None`.
+    pub fn bucket_for_report(&self, report: &Report) -> Option<DapBatchBucket> {
+        match self.query {
+            DapQueryConfig::TimeInterval => Some(DapBatchBucket::TimeInterval {
+                batch_window: self.quantized_time_lower_bound(report.report_metadata.time),
+            }),
+            DapQueryConfig::FixedSize { .. } => None,
+        }
+    }
+
     /// Check if the batch size is too small. Returns an error if the report count is too large.
     pub(crate) fn is_report_count_compatible(
         &self,
@@ -732,7 +1021,32 @@ impl DapTaskConfig {
             } => (),
         };
 
-        Ok(report_count >= self.min_batch_size)
+        Ok(report_count >= self.effective_min_batch_size())
+    }
+
+    /// The minimum number of reports in a batch eligible for collection. This is the task's
+    /// configured minimum batch size, unless the VDAF requires more.
+    pub fn effective_min_batch_size(&self) -> u64 {
+        max(self.min_batch_size, self.vdaf.min_batch_size())
+    }
+
+    /// Select the Collector's HPKE configuration with the given ID from among those accepted for
+    /// the task. If no ID is given, then the task's primary config is selected.
+    pub fn collector_hpke_config_for(
+        &self,
+        task_id: &TaskId,
+        config_id: Option<u8>,
+    ) -> Result<&HpkeConfig, DapAbort> {
+        let Some(config_id) = config_id else {
+            return Ok(&self.collector_hpke_config);
+        };
+        std::iter::once(&self.collector_hpke_config)
+            .chain(&self.collector_hpke_config_allowlist)
+            .find(|config| config.id == config_id)
+            .ok_or_else(|| DapAbort::OutdatedConfig {
+                detail: format!("collector HPKE config {config_id} is not accepted for this task"),
+                task_id: *task_id,
+            })
     }
 
     /// Leader: Resolve taskprov advertisement to send in a request to the Helper.
@@ -785,13 +1099,50 @@ pub enum DapMeasurement {
 }
 
 /// An aggregation parameter.
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
 pub enum DapAggregationParam {
     Empty,
     #[cfg(any(test, feature = "test-utils"))]
     Mastic(Poplar1AggregationParam),
 }
 
+// The aggregation parameter is serialized as its hex-encoded wire encoding so that it can be
+// persisted, e.g., as part of a queued work item.
+#[derive(Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+enum ShadowDapAggregationParam {
+    Empty,
+    #[cfg(any(test, feature = "test-utils"))]
+    Mastic(#[serde(with = "hex")] Vec<u8>),
+}
+
+impl Serialize for DapAggregationParam {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let shadow = match self {
+            Self::Empty => ShadowDapAggregationParam::Empty,
+            #[cfg(any(test, feature = "test-utils"))]
+            Self::Mastic(agg_param) => ShadowDapAggregationParam::Mastic(
+                agg_param.get_encoded().map_err(serde::ser::Error::custom)?,
+            ),
+        };
+        shadow.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for DapAggregationParam {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        match ShadowDapAggregationParam::deserialize(deserializer)? {
+            ShadowDapAggregationParam::Empty => Ok(Self::Empty),
+            #[cfg(any(test, feature = "test-utils"))]
+            ShadowDapAggregationParam::Mastic(agg_param) => {
+                Poplar1AggregationParam::get_decoded(&agg_param)
+                    .map(Self::Mastic)
+                    .map_err(serde::de::Error::custom)
+            }
+        }
+    }
+}
+
 #[cfg(any(test, feature = "test-utils"))]
 impl deepsize::DeepSizeOf for DapAggregationParam {
     fn deep_size_of(&self) -> usize {
@@ -814,6 +1165,41 @@ impl Encode for DapAggregationParam {
     }
 }
 
+impl DapAggregationParam {
+    /// Decode an aggregation parameter received for a task. Prio2 and Prio3 take no aggregation
+    /// parameter, so for tasks using them the parameter must be empty. An abort is returned if the
+    /// parameter is malformed.
+    pub(crate) fn decode_for_task(
+        task_id: &TaskId,
+        vdaf_config: &VdafConfig,
+        bytes: &[u8],
+    ) -> Result<Self, DapAbort> {
+        let mut cursor = std::io::Cursor::new(bytes);
+        let agg_param = Self::decode_with_param(vdaf_config, &mut cursor)
+            .map_err(|e| DapAbort::from_codec_error(e, *task_id))?;
+
+        let num_bytes_left_over = bytes.len() - usize::try_from(cursor.position()).unwrap();
+        if num_bytes_left_over == 0 {
+            return Ok(agg_param);
+        }
+
+        match agg_param {
+            Self::Empty => Err(DapAbort::InvalidMessage {
+                detail: format!(
+                    "expected an empty aggregation parameter for {vdaf_config}, got {} bytes",
+                    bytes.len()
+                ),
+                task_id: Some(*task_id),
+            }),
+            #[cfg(any(test, feature = "test-utils"))]
+            Self::Mastic(..) => Err(DapAbort::from_codec_error(
+                CodecError::BytesLeftOver(num_bytes_left_over),
+                *task_id,
+            )),
+        }
+    }
+}
+
 impl ParameterizedDecode<VdafConfig> for DapAggregationParam {
     fn decode_with_param(
         vdaf_config: &VdafConfig,
@@ -829,7 +1215,7 @@ impl ParameterizedDecode<VdafConfig> for DapAggregationParam {
 }
 
 /// The aggregate result computed by the Collector.
-#[derive(Debug, PartialEq, Eq, Serialize)]
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
 #[serde(rename_all = "snake_case")]
 pub enum DapAggregateResult {
     U32Vec(Vec<u32>),
@@ -839,6 +1225,122 @@ pub enum DapAggregateResult {
     U128Vec(Vec<u128>),
 }
 
+impl DapAggregateResult {
+    /// The result as a single integer, e.g., for `Prio3Count`. Returns `None` for any other type of
+    /// result.
+    pub fn as_u64(&self) -> Option<u64> {
+        match self {
+            Self::U64(res) => Some(*res),
+            _ => None,
+        }
+    }
+
+    /// The result as a single integer, e.g., for `Prio3Sum`. Returns `None` for any other type of
+    /// result.
+    pub fn as_u128(&self) -> Option<u128> {
+        match self {
+            Self::U128(res) => Some(*res),
+            _ => None,
+        }
+    }
+
+    /// The result as a vector, e.g., for Prio2. Returns `None` for any other type of result.
+    pub fn as_u32_vec(&self) -> Option<&[u32]> {
+        match self {
+            Self::U32Vec(res) => Some(res),
+            _ => None,
+        }
+    }
+
+    /// The result as a vector, e.g., for Mastic. Returns `None` for any other type of result.
+    pub fn as_u64_vec(&self) -> Option<&[u64]> {
+        match self {
+            Self::U64Vec(res) => Some(res),
+            _ => None,
+        }
+    }
+
+    /// The result as a vector, e.g., for `Prio3SumVec`. Returns `None` for any other type of
+    /// result.
+    pub fn as_u128_vec(&self) -> Option<&[u128]> {
+        match self {
+            Self::U128Vec(res) => Some(res),
+            _ => None,
+        }
+    }
+
+    /// The result as a histogram, i.e., a count for each bucket, e.g., for `Prio3Histogram`.
+    /// Results of any vector type are converted. Returns `None` if the result is a single integer.
+    pub fn as_histogram(&self) -> Option<Vec<u128>> {
+        match self {
+            Self::U32Vec(res) => Some(res.iter().copied().map(u128::from).collect()),
+            Self::U64Vec(res) => Some(res.iter().copied().map(u128::from).collect()),
+            Self::U128Vec(res) => Some(res.clone()),
+            Self::U64(..) | Self::U128(..) => None,
+        }
+    }
+
+    /// Combine the results of several collection jobs for the same task, e.g., over disjoint
+    /// batch intervals, into a single total. Scalars are added and vectors are added
+    /// element-wise. Returns an error if `results` is empty, if the results are not all of the same
+    /// type and length, or if the total overflows.
+    pub fn combine(results: &[DapAggregateResult]) -> Result<DapAggregateResult, CollectError> {
+        fn add_vec<T: Copy>(
+            left: &mut [T],
+            right: &[T],
+            checked_add: impl Fn(T, T) -> Option<T>,
+        ) -> Result<(), CollectError> {
+            if left.len() != right.len() {
+                return Err(CollectError::AggResultMismatch);
+            }
+            for (l, r) in left.iter_mut().zip(right) {
+                *l = checked_add(*l, *r).ok_or(CollectError::AggResultOverflow)?;
+            }
+            Ok(())
+        }
+
+        let (first, rest) = results.split_first().ok_or(CollectError::NoAggResults)?;
+        let mut combined = first.clone();
+        for res in rest {
+            match (&mut combined, res) {
+                (Self::U64(left), Self::U64(right)) => {
+                    *left = left
+                        .checked_add(*right)
+                        .ok_or(CollectError::AggResultOverflow)?;
+                }
+                (Self::U128(left), Self::U128(right)) => {
+                    *left = left
+                        .checked_add(*right)
+                        .ok_or(CollectError::AggResultOverflow)?;
+                }
+                (Self::U32Vec(left), Self::U32Vec(right)) => {
+                    add_vec(left, right, u32::checked_add)?;
+                }
+                (Self::U64Vec(left), Self::U64Vec(right)) => {
+                    add_vec(left, right, u64::checked_add)?;
+                }
+                (Self::U128Vec(left), Self::U128Vec(right)) => {
+                    add_vec(left, right, u128::checked_add)?;
+                }
+                _ => return Err(CollectError::AggResultMismatch),
+            }
+        }
+        Ok(combined)
+    }
+}
+
+impl std::fmt::Display for DapAggregateResult {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::U32Vec(res) => write!(f, "{res:?}"),
+            Self::U64(res) => write!(f, "{res}"),
+            Self::U64Vec(res) => write!(f, "{res:?}"),
+            Self::U128(res) => write!(f, "{res}"),
+            Self::U128Vec(res) => write!(f, "{res:?}"),
+        }
+    }
+}
+
 #[derive(Clone)]
 #[cfg_attr(any(test, feature = "test-utils"), derive(Debug, deepsize::DeepSizeOf))]
 pub(crate) struct AggregationJobReportState {
@@ -891,12 +1393,29 @@ impl Encode for DapAggregationJobState {
 impl DapAggregationJobState {
     /// Decode the Helper state from a byte string.
     pub fn get_decoded(vdaf_config: &VdafConfig, data: &[u8]) -> Result<Self, DapError> {
+        Self::get_decoded_for_role(vdaf_config, false, data)
+    }
+
+    /// Decode the Leader state from a byte string. The Leader may store its state while it waits
+    /// for the Helper's response to an aggregation job so that the job can be resumed if the
+    /// Leader restarts.
+    ///
+    /// draft02: The Leader's state includes its prep shares and can't be encoded.
+    pub fn get_decoded_for_leader(vdaf_config: &VdafConfig, data: &[u8]) -> Result<Self, DapError> {
+        Self::get_decoded_for_role(vdaf_config, true, data)
+    }
+
+    fn get_decoded_for_role(
+        vdaf_config: &VdafConfig,
+        is_leader: bool,
+        data: &[u8],
+    ) -> Result<Self, DapError> {
         let mut r = std::io::Cursor::new(data);
         let part_batch_sel = PartialBatchSelector::decode(&mut r)
             .map_err(|e| DapAbort::from_codec_error(e, None))?;
         let mut seq = vec![];
         while (usize::try_from(r.position()).unwrap()) < data.len() {
-            let prep_state = VdafPrepState::decode_with_param(&(vdaf_config, false), &mut r)
+            let prep_state = VdafPrepState::decode_with_param(&(vdaf_config, is_leader), &mut r)
                 .map_err(|e| DapAbort::from_codec_error(e, None))?;
             let time = Time::decode(&mut r).map_err(|e| DapAbort::from_codec_error(e, None))?;
             let report_id =
@@ -933,7 +1452,7 @@ pub struct DapAggregateShare {
     pub report_count: u64,
     pub min_time: Time,
     pub max_time: Time,
-    /// Batch checkusm.
+    /// Batch checksum: the XOR of the SHA-256 hashes of the IDs of the reports in the batch.
     pub checksum: [u8; 32],
     pub data: Option<VdafAggregateShare>,
 }
@@ -1019,6 +1538,21 @@ impl DapAggregateShare {
     }
 }
 
+/// An encrypted aggregate share retained by the Helper in order to answer retries of an aggregate
+/// share request.
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Serialize)]
+#[cfg_attr(any(test, feature = "test-utils"), derive(deepsize::DeepSizeOf))]
+pub struct DapRetainedAggShare {
+    /// The batch for which the aggregate share was produced.
+    pub batch_sel: BatchSelector,
+
+    /// The Helper's encrypted aggregate share.
+    pub encrypted_agg_share: HpkeCiphertext,
+
+    /// The time after which the aggregate share no longer needs to be retained.
+    pub expiration: Time,
+}
+
 /// Leader state transition during the aggregation flow.
 #[cfg_attr(any(test, feature = "test-utils"), derive(Debug))]
 pub enum DapLeaderAggregationJobTransition<M: Debug> {
@@ -1043,7 +1577,7 @@ pub enum DapHelperAggregationJobTransition<M: Debug> {
 }
 
 /// DAP sender role.
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum DapSender {
     Client,
     Collector,
@@ -1103,6 +1637,24 @@ pub struct DapRequest<S> {
 
     /// taskprov: The task advertisement, sent in the "dap-taskprov" header.
     pub taskprov: Option<String>,
+
+    /// Idempotency token for an aggregate share request, sent in the "dap-idempotency-token"
+    /// header.
+    pub idempotency_token: Option<String>,
+
+    /// Version of the Leader's VDAF implementation, sent in the "dap-vdaf-version" header of an
+    /// aggregation job initialization request. See [`VDAF_VERSION`](crate::vdaf::VDAF_VERSION).
+    pub vdaf_version: Option<String>,
+
+    /// Format in which to encode the collection, negotiated from the "dap-collection-format"
+    /// header of a request from the Collector.
+    pub collection_format: CollectionFormat,
+
+    /// ID of the Collector's HPKE configuration under which to encrypt the aggregate shares, sent
+    /// in the "dap-collector-hpke-config-id" header of a collection request or aggregate share
+    /// request. If not set, then the task's primary config is used. See
+    /// [`DapTaskConfig::collector_hpke_config_for`].
+    pub collector_hpke_config_id: Option<u8>,
 }
 
 #[cfg(test)]
@@ -1116,11 +1668,32 @@ impl<S> Default for DapRequest<S> {
             payload: Default::default(),
             sender_auth: Default::default(),
             taskprov: Default::default(),
+            idempotency_token: Default::default(),
+            vdaf_version: Default::default(),
+            collection_format: Default::default(),
+            collector_hpke_config_id: Default::default(),
         }
     }
 }
 
 impl<S> DapRequest<S> {
+    /// Start building a request for the given DAP version. See [`DapRequestBuilder`].
+    pub fn builder(version: DapVersion) -> DapRequestBuilder<S> {
+        DapRequestBuilder {
+            version,
+            media_type: Ok(DapMediaType::Missing),
+            task_id: None,
+            resource: DapResource::Undefined,
+            payload: Vec::new(),
+            sender_auth: None,
+            taskprov: None,
+            idempotency_token: None,
+            vdaf_version: None,
+            collection_format: CollectionFormat::default(),
+            collector_hpke_config_id: None,
+        }
+    }
+
     /// Return the task ID, handling a missing ID as a user error.
     pub fn task_id(&self) -> Result<&TaskId, DapAbort> {
         if let Some(ref id) = self.task_id {
@@ -1157,10 +1730,163 @@ impl<S> DapRequest<S> {
     }
 }
 
+/// Builder for a [`DapRequest`] that checks the request is well-formed before it is handled:
+///
+/// * the media type is recognized for the DAP version;
+/// * the task ID is present if the media type requires one; and
+/// * sender authorization is present if the media type is for an authenticated endpoint.
+pub struct DapRequestBuilder<S> {
+    version: DapVersion,
+    /// The media type, or the content type if it could not be parsed.
+    media_type: Result<DapMediaType, String>,
+    task_id: Option<TaskId>,
+    resource: DapResource,
+    payload: Vec<u8>,
+    sender_auth: Option<S>,
+    taskprov: Option<String>,
+    idempotency_token: Option<String>,
+    vdaf_version: Option<String>,
+    collection_format: CollectionFormat,
+    collector_hpke_config_id: Option<u8>,
+}
+
+impl<S> DapRequestBuilder<S> {
+    #[must_use]
+    pub fn media_type(mut self, media_type: DapMediaType) -> Self {
+        self.media_type = if media_type == DapMediaType::Missing
+            || media_type.as_str_for_version(self.version).is_some()
+        {
+            Ok(media_type)
+        } else {
+            Err(format!("{media_type:?}"))
+        };
+        self
+    }
+
+    /// Set the media type by parsing the "content-type" header of the HTTP request.
+    #[must_use]
+    pub fn content_type(mut self, content_type: Option<&str>) -> Self {
+        self.media_type = DapMediaType::from_str_for_version(self.version, content_type)
+            .ok_or_else(|| content_type.unwrap_or_default().to_string());
+        self
+    }
+
+    #[must_use]
+    pub fn task_id(mut self, task_id: Option<TaskId>) -> Self {
+        self.task_id = task_id;
+        self
+    }
+
+    #[must_use]
+    pub fn resource(mut self, resource: DapResource) -> Self {
+        self.resource = resource;
+        self
+    }
+
+    #[must_use]
+    pub fn payload(mut self, payload: Vec<u8>) -> Self {
+        self.payload = payload;
+        self
+    }
+
+    #[must_use]
+    pub fn sender_auth(mut self, sender_auth: Option<S>) -> Self {
+        self.sender_auth = sender_auth;
+        self
+    }
+
+    #[must_use]
+    pub fn taskprov(mut self, taskprov: Option<String>) -> Self {
+        self.taskprov = taskprov;
+        self
+    }
+
+    #[must_use]
+    pub fn idempotency_token(mut self, idempotency_token: Option<String>) -> Self {
+        self.idempotency_token = idempotency_token;
+        self
+    }
+
+    #[must_use]
+    pub fn vdaf_version(mut self, vdaf_version: Option<String>) -> Self {
+        self.vdaf_version = vdaf_version;
+        self
+    }
+
+    #[must_use]
+    pub fn collection_format(mut self, collection_format: CollectionFormat) -> Self {
+        self.collection_format = collection_format;
+        self
+    }
+
+    #[must_use]
+    pub fn collector_hpke_config_id(mut self, collector_hpke_config_id: Option<u8>) -> Self {
+        self.collector_hpke_config_id = collector_hpke_config_id;
+        self
+    }
+
+    /// Validate and build the request.
+    pub fn build(self) -> Result<DapRequest<S>, DapAbort> {
+        let media_type = self.media_type.map_err(|content_type| {
+            DapAbort::BadRequest(format!(
+                "unrecognized media type for {}: {content_type}",
+                self.version
+            ))
+        })?;
+
+        let req = DapRequest {
+            version: self.version,
+            media_type,
+            task_id: self.task_id,
+            resource: self.resource,
+            payload: self.payload,
+            sender_auth: self.sender_auth,
+            taskprov: self.taskprov,
+            idempotency_token: self.idempotency_token,
+            vdaf_version: self.vdaf_version,
+            collection_format: self.collection_format,
+            collector_hpke_config_id: self.collector_hpke_config_id,
+        };
+
+        // Requests sent by the Leader or Collector are authenticated. Every request that is
+        // specific to a task must identify it.
+        let (requires_task_id, requires_sender_auth) = match media_type {
+            DapMediaType::AggregationJobInitReq
+            | DapMediaType::AggregationJobContinueReq
+            | DapMediaType::AggregationJobRespChunkReq
+            | DapMediaType::AggregateShareReq
+            | DapMediaType::CollectReq => (true, true),
+            DapMediaType::Report => (true, false),
+            DapMediaType::AggregationJobResp
+            | DapMediaType::Draft02AggregateContinueResp
+            | DapMediaType::AggregationJobRespChunk
+            | DapMediaType::AggregateShare
+            | DapMediaType::Collection
+            | DapMediaType::HpkeConfigList
+            | DapMediaType::Problem
+            | DapMediaType::Missing => (false, false),
+        };
+
+        if requires_task_id {
+            let task_id = req.task_id()?;
+            if requires_sender_auth && req.sender_auth.is_none() {
+                return Err(DapAbort::UnauthorizedRequest {
+                    detail: "missing sender authorization".into(),
+                    task_id: *task_id,
+                });
+            }
+        }
+
+        Ok(req)
+    }
+}
+
 /// DAP response.
 #[derive(Debug)]
 pub struct DapResponse {
     pub version: DapVersion,
+    /// HTTP status code of the response.
+    pub status: u16,
     pub media_type: DapMediaType,
     pub payload: Vec<u8>,
 }
@@ -1175,6 +1901,38 @@ pub enum DapCollectionJob {
     Unknown,
 }
 
+/// Summary of a collection job, as listed for operators.
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Serialize)]
+pub struct DapCollectionJobInfo {
+    /// Status of the collection job.
+    pub status: DapCollectionJob,
+
+    /// For pending collection jobs, the number of buckets of reports still queued for
+    /// aggregation.
+    pub queued_buckets: Option<usize>,
+
+    /// For collection jobs of a fixed-size batch, the range of timestamps of the reports
+    /// assigned to the batch. See [`DapLeader::batch_time_range`](crate::roles::DapLeader::batch_time_range).
+    pub batch_time_range: Option<Interval>,
+}
+
+/// Status of an aggregation job enqueued by the Leader.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DapAggregationJobStatus {
+    /// The job is in the work queue.
+    Queued,
+
+    /// The job has been dequeued and is being run.
+    InFlight,
+
+    /// The job ran to completion.
+    Completed,
+
+    /// The job was aborted.
+    Failed,
+}
+
 /// Telemetry information for the leader's processing loop.
 //
 // TODO This is used for tests. Perhaps Prometheus metrics would be sufficient?
@@ -1222,7 +1980,7 @@ generate_from_id_impls_for_meta_agg_job_id!(DraftLatest => AggregationJobId);
 
 impl MetaAggregationJobId {
     /// Generate a random ID of the type required for the version.
-    pub(crate) fn gen_for_version(version: DapVersion) -> Self {
+    pub fn gen_for_version(version: DapVersion) -> Self {
         let mut rng = thread_rng();
         match version {
             DapVersion::Draft02 => Self::Draft02(Draft02AggregationJobId(rng.gen())),
@@ -1230,6 +1988,16 @@ impl MetaAggregationJobId {
         }
     }
 
+    /// The ID under which the Leader retries the aggregation job. The ID is kept, so that a Helper
+    /// that already finished the job answers the retry with the response it sent the first time.
+    /// A draft02 Helper rejects a repeated request for a job, so draft02 jobs get a new ID.
+    pub(crate) fn for_retry(&self) -> Self {
+        match self {
+            Self::Draft02(..) => Self::gen_for_version(DapVersion::Draft02),
+            Self::DraftLatest(..) => *self,
+        }
+    }
+
     /// Convert this aggregation job ID into to the type that would be included in the payload of
     /// the HTTP request request.
     pub(crate) fn for_request_payload(&self) -> Option<Draft02AggregationJobId> {
@@ -1265,3 +2033,268 @@ impl MetaAggregationJobId {
         }
     }
 }
+
+impl std::fmt::Display for MetaAggregationJobId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Draft02(agg_job_id) => write!(f, "{agg_job_id}"),
+            Self::DraftLatest(agg_job_id) => write!(f, "{agg_job_id}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::{
+        auth::BearerToken,
+        constants::DapMediaType,
+        error::{CollectError, DapAbort},
+        messages::{ReportId, TaskId},
+        test_versions, DapAggregateResult, DapAggregateShare, DapRequest, DapVersion,
+        VdafAggregateShare,
+    };
+    use assert_matches::assert_matches;
+    use prio::{
+        field::Field64,
+        vdaf::{AggregateShare, OutputShare},
+    };
+    use serde_json::json;
+
+    fn builder(version: DapVersion) -> crate::DapRequestBuilder<BearerToken> {
+        DapRequest::builder(version)
+            .task_id(Some(TaskId([1; 32])))
+            .sender_auth(Some(BearerToken::from("leader_token")))
+    }
+
+    fn build_ok(version: DapVersion) {
+        let req = builder(version)
+            .media_type(DapMediaType::AggregateShareReq)
+            .payload(b"payload".to_vec())
+            .build()
+            .unwrap();
+        assert_eq!(req.version, version);
+        assert_eq!(req.media_type, DapMediaType::AggregateShareReq);
+        assert_eq!(req.task_id, Some(TaskId([1; 32])));
+        assert_eq!(req.payload, b"payload");
+
+        // A request that doesn't pertain to a task doesn't need a task ID or authorization.
+        let req = DapRequest::<BearerToken>::builder(version)
+            .content_type(None)
+            .build()
+            .unwrap();
+        assert_eq!(req.media_type, DapMediaType::Missing);
+    }
+
+    test_versions! { build_ok }
+
+    fn build_missing_task_id(version: DapVersion) {
+        let err = builder(version)
+            .media_type(DapMediaType::Report)
+            .task_id(None)
+            .build()
+            .unwrap_err();
+        match version {
+            DapVersion::Draft02 => assert_matches!(err, DapAbort::InvalidMessage { .. }),
+            DapVersion::DraftLatest => assert_matches!(err, DapAbort::BadRequest(..)),
+        }
+    }
+
+    test_versions! { build_missing_task_id }
+
+    fn build_unknown_media_type(version: DapVersion) {
+        let err = builder(version)
+            .content_type(Some("application/dap-unknown"))
+            .build()
+            .unwrap_err();
+        assert_matches!(err, DapAbort::BadRequest(detail) => {
+            assert!(detail.contains("application/dap-unknown"));
+        });
+    }
+
+    test_versions! { build_unknown_media_type }
+
+    #[test]
+    fn build_media_type_for_other_version() {
+        // draft02 defines a separate media type for the aggregation continue response, but the
+        // latest draft does not.
+        assert_matches!(
+            builder(DapVersion::DraftLatest)
+                .media_type(DapMediaType::Draft02AggregateContinueResp)
+                .build(),
+            Err(DapAbort::BadRequest(..))
+        );
+        assert_matches!(
+            builder(DapVersion::DraftLatest)
+                .content_type(Some("application/dap-aggregate-continue-req"))
+                .build(),
+            Err(DapAbort::BadRequest(..))
+        );
+    }
+
+    fn build_missing_sender_auth(version: DapVersion) {
+        let err = builder(version)
+            .media_type(DapMediaType::AggregationJobInitReq)
+            .sender_auth(None)
+            .build()
+            .unwrap_err();
+        assert_matches!(err, DapAbort::UnauthorizedRequest { task_id, .. } => {
+            assert_eq!(task_id, TaskId([1; 32]));
+        });
+
+        // Clients don't authenticate their uploads.
+        builder(version)
+            .media_type(DapMediaType::Report)
+            .sender_auth(None)
+            .build()
+            .unwrap();
+    }
+
+    test_versions! { build_missing_sender_auth }
+
+    #[test]
+    fn agg_share_checksum() {
+        let report_ids = [ReportId([1; 16]), ReportId([2; 16]), ReportId([3; 16])];
+        let out_share = || {
+            VdafAggregateShare::Field64(AggregateShare::from(OutputShare::from(vec![
+                Field64::from(1),
+            ])))
+        };
+
+        let mut agg_share = DapAggregateShare::default();
+        for report_id in &report_ids {
+            agg_share
+                .add_out_share(report_id, 1_637_359_200, out_share())
+                .unwrap();
+        }
+        assert_eq!(agg_share.report_count, 3);
+        // XOR of SHA-256(0x01 * 16), SHA-256(0x02 * 16) and SHA-256(0x03 * 16).
+        assert_eq!(
+            hex::encode(agg_share.checksum),
+            "863fdf273a6e953da55c3af0145aadc0e61029db02fd4d8bed76111817319312"
+        );
+
+        // The checksum doesn't depend on the order in which reports are aggregated or on how they
+        // are split across aggregate shares.
+        let mut merged = DapAggregateShare::default();
+        for report_id in report_ids.iter().rev() {
+            let mut other = DapAggregateShare::default();
+            other
+                .add_out_share(report_id, 1_637_359_200, out_share())
+                .unwrap();
+            merged.merge(other).unwrap();
+        }
+        assert_eq!(merged.report_count, agg_share.report_count);
+        assert_eq!(merged.checksum, agg_share.checksum);
+    }
+
+    #[test]
+    fn agg_result_count() {
+        let res = DapAggregateResult::U64(3);
+        assert_eq!(res.as_u64(), Some(3));
+        assert_eq!(res.as_u128(), None);
+        assert_eq!(res.as_u64_vec(), None);
+        assert_eq!(res.as_histogram(), None);
+        assert_eq!(res.to_string(), "3");
+        assert_eq!(serde_json::to_value(&res).unwrap(), json!({ "u64": 3 }));
+    }
+
+    #[test]
+    fn agg_result_sum() {
+        let res = DapAggregateResult::U128(1342);
+        assert_eq!(res.as_u128(), Some(1342));
+        assert_eq!(res.as_u64(), None);
+        assert_eq!(res.as_u128_vec(), None);
+        assert_eq!(res.as_histogram(), None);
+        assert_eq!(res.to_string(), "1342");
+        assert_eq!(serde_json::to_value(&res).unwrap(), json!({ "u128": 1342 }));
+    }
+
+    #[test]
+    fn agg_result_histogram() {
+        let res = DapAggregateResult::U128Vec(vec![1, 1, 3]);
+        assert_eq!(res.as_u128_vec(), Some([1, 1, 3].as_slice()));
+        assert_eq!(res.as_histogram(), Some(vec![1, 1, 3]));
+        assert_eq!(res.as_u64(), None);
+        assert_eq!(res.as_u64_vec(), None);
+        assert_eq!(res.to_string(), "[1, 1, 3]");
+        assert_eq!(
+            serde_json::to_value(&res).unwrap(),
+            json!({ "u128_vec": [1, 1, 3] })
+        );
+    }
+
+    #[test]
+    fn agg_result_prio2() {
+        let res = DapAggregateResult::U32Vec(vec![3, 3, 1, 0, 5]);
+        assert_eq!(res.as_u32_vec(), Some([3, 3, 1, 0, 5].as_slice()));
+        assert_eq!(res.as_histogram(), Some(vec![3, 3, 1, 0, 5]));
+        assert_eq!(res.as_u128_vec(), None);
+        assert_eq!(res.as_u64(), None);
+        assert_eq!(res.to_string(), "[3, 3, 1, 0, 5]");
+    }
+
+    #[test]
+    fn agg_result_u64_vec() {
+        // E.g., Mastic or Prio3SumVecField64MultiproofHmacSha256Aes128.
+        let res = DapAggregateResult::U64Vec(vec![1338, 1338]);
+        assert_eq!(res.as_u64_vec(), Some([1338, 1338].as_slice()));
+        assert_eq!(res.as_histogram(), Some(vec![1338, 1338]));
+        assert_eq!(res.as_u128_vec(), None);
+        assert_eq!(res.as_u32_vec(), None);
+        assert_eq!(res.as_u128(), None);
+        assert_eq!(res.to_string(), "[1338, 1338]");
+    }
+
+    #[test]
+    fn agg_result_combine_histograms() {
+        let combined = DapAggregateResult::combine(&[
+            DapAggregateResult::U128Vec(vec![1, 1, 3]),
+            DapAggregateResult::U128Vec(vec![0, 2, 5]),
+        ])
+        .unwrap();
+        assert_eq!(combined, DapAggregateResult::U128Vec(vec![1, 3, 8]));
+
+        // Histograms with a different number of buckets can't be combined.
+        assert_eq!(
+            DapAggregateResult::combine(&[
+                DapAggregateResult::U128Vec(vec![1, 1, 3]),
+                DapAggregateResult::U128Vec(vec![1, 1]),
+            ]),
+            Err(CollectError::AggResultMismatch)
+        );
+    }
+
+    #[test]
+    fn agg_result_combine_sums() {
+        let combined = DapAggregateResult::combine(&[
+            DapAggregateResult::U128(1342),
+            DapAggregateResult::U128(1),
+            DapAggregateResult::U128(7),
+        ])
+        .unwrap();
+        assert_eq!(combined, DapAggregateResult::U128(1350));
+
+        assert_eq!(
+            DapAggregateResult::combine(&[
+                DapAggregateResult::U64(u64::MAX),
+                DapAggregateResult::U64(1),
+            ]),
+            Err(CollectError::AggResultOverflow)
+        );
+        assert_eq!(
+            DapAggregateResult::combine(&[]),
+            Err(CollectError::NoAggResults)
+        );
+    }
+
+    #[test]
+    fn agg_result_combine_type_mismatch() {
+        assert_eq!(
+            DapAggregateResult::combine(&[
+                DapAggregateResult::U128Vec(vec![1, 1, 3]),
+                DapAggregateResult::U128(1342),
+            ]),
+            Err(CollectError::AggResultMismatch)
+        );
+    }
+}
@@ -0,0 +1,260 @@
+// Copyright (c) 2024 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! Human-readable dumps of DAP messages, for inspecting traffic when debugging interop issues.
+//!
+//! The JSON emitted here is meant for logging only. It is not a wire format and may change at any
+//! time. Binary fields are hex-encoded and enums are rendered by name.
+
+use serde::Serialize;
+use serde_json::{json, Value};
+
+use super::{
+    AggregateShare, AggregateShareReq, AggregationJobContinueReq, AggregationJobInitReq,
+    AggregationJobResp, Collection, CollectionReq, Extension, HpkeCiphertext, HpkeConfigList,
+    PrepareInit, Report, ReportMetadata, ReportShare, Transition, TransitionVar,
+};
+
+/// Conversion of a message into a structured JSON value for debugging.
+pub trait ToDebugJson {
+    fn to_debug_json(&self) -> Value;
+}
+
+/// Messages whose serde representation is already human readable.
+fn serialized<T: Serialize>(t: &T) -> Value {
+    serde_json::to_value(t).expect("serializing a message to JSON should never fail")
+}
+
+fn list<T: ToDebugJson>(items: &[T]) -> Value {
+    items.iter().map(ToDebugJson::to_debug_json).collect()
+}
+
+impl ToDebugJson for Extension {
+    fn to_debug_json(&self) -> Value {
+        match self {
+            Self::Taskprov { draft02_payload } => json!({
+                "taskprov": {
+                    "draft02_payload": draft02_payload.as_ref().map(hex::encode),
+                },
+            }),
+            Self::NotImplemented { typ, payload } => json!({
+                "not_implemented": {
+                    "typ": typ,
+                    "payload": hex::encode(payload),
+                },
+            }),
+        }
+    }
+}
+
+impl ToDebugJson for ReportMetadata {
+    fn to_debug_json(&self) -> Value {
+        json!({
+            "id": self.id.to_hex(),
+            "time": self.time,
+            "draft02_extensions": self.draft02_extensions.as_deref().map(list),
+        })
+    }
+}
+
+impl ToDebugJson for HpkeCiphertext {
+    fn to_debug_json(&self) -> Value {
+        serialized(self)
+    }
+}
+
+impl ToDebugJson for Report {
+    fn to_debug_json(&self) -> Value {
+        json!({
+            "draft02_task_id": self.draft02_task_id.map(|id| id.to_hex()),
+            "report_metadata": self.report_metadata.to_debug_json(),
+            "public_share": hex::encode(&self.public_share),
+            "encrypted_input_shares": list(&self.encrypted_input_shares),
+        })
+    }
+}
+
+impl ToDebugJson for ReportShare {
+    fn to_debug_json(&self) -> Value {
+        json!({
+            "report_metadata": self.report_metadata.to_debug_json(),
+            "public_share": hex::encode(&self.public_share),
+            "encrypted_input_share": self.encrypted_input_share.to_debug_json(),
+        })
+    }
+}
+
+impl ToDebugJson for PrepareInit {
+    fn to_debug_json(&self) -> Value {
+        json!({
+            "report_share": self.report_share.to_debug_json(),
+            "draft_latest_payload": self.draft_latest_payload.as_ref().map(hex::encode),
+        })
+    }
+}
+
+impl ToDebugJson for AggregationJobInitReq {
+    fn to_debug_json(&self) -> Value {
+        json!({
+            "draft02_task_id": self.draft02_task_id.map(|id| id.to_hex()),
+            "draft02_agg_job_id": self.draft02_agg_job_id.map(|id| id.to_hex()),
+            "agg_param": hex::encode(&self.agg_param),
+            "part_batch_sel": serialized(&self.part_batch_sel),
+            "prep_inits": list(&self.prep_inits),
+        })
+    }
+}
+
+impl ToDebugJson for TransitionVar {
+    fn to_debug_json(&self) -> Value {
+        match self {
+            Self::Continued(message) => json!({ "continued": hex::encode(message) }),
+            Self::Finished => json!("finished"),
+            Self::Failed(failure) => json!({ "failed": serialized(failure) }),
+        }
+    }
+}
+
+impl ToDebugJson for Transition {
+    fn to_debug_json(&self) -> Value {
+        json!({
+            "report_id": self.report_id.to_hex(),
+            "var": self.var.to_debug_json(),
+        })
+    }
+}
+
+impl ToDebugJson for AggregationJobContinueReq {
+    fn to_debug_json(&self) -> Value {
+        json!({
+            "draft02_task_id": self.draft02_task_id.map(|id| id.to_hex()),
+            "draft02_agg_job_id": self.draft02_agg_job_id.map(|id| id.to_hex()),
+            "round": self.round,
+            "transitions": list(&self.transitions),
+        })
+    }
+}
+
+impl ToDebugJson for AggregationJobResp {
+    fn to_debug_json(&self) -> Value {
+        json!({
+            "transitions": list(&self.transitions),
+        })
+    }
+}
+
+impl ToDebugJson for CollectionReq {
+    fn to_debug_json(&self) -> Value {
+        json!({
+            "draft02_task_id": self.draft02_task_id.map(|id| id.to_hex()),
+            "query": serialized(&self.query),
+            "agg_param": hex::encode(&self.agg_param),
+        })
+    }
+}
+
+impl ToDebugJson for Collection {
+    fn to_debug_json(&self) -> Value {
+        json!({
+            "part_batch_sel": serialized(&self.part_batch_sel),
+            "report_count": self.report_count,
+            "draft_latest_interval": self.draft_latest_interval.as_ref().map(serialized),
+            "encrypted_agg_shares": list(&self.encrypted_agg_shares),
+        })
+    }
+}
+
+impl ToDebugJson for AggregateShareReq {
+    fn to_debug_json(&self) -> Value {
+        json!({
+            "draft02_task_id": self.draft02_task_id.map(|id| id.to_hex()),
+            "batch_sel": serialized(&self.batch_sel),
+            "agg_param": hex::encode(&self.agg_param),
+            "report_count": self.report_count,
+            "checksum": hex::encode(self.checksum),
+        })
+    }
+}
+
+impl ToDebugJson for AggregateShare {
+    fn to_debug_json(&self) -> Value {
+        json!({
+            "encrypted_agg_share": self.encrypted_agg_share.to_debug_json(),
+        })
+    }
+}
+
+impl ToDebugJson for HpkeConfigList {
+    fn to_debug_json(&self) -> Value {
+        json!({
+            "hpke_configs": self.hpke_configs.iter().map(serialized).collect::<Vec<_>>(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::ToDebugJson;
+    use crate::messages::{
+        AggregationJobResp, HpkeCiphertext, Report, ReportId, ReportMetadata, Transition,
+        TransitionFailure, TransitionVar,
+    };
+    use serde_json::json;
+
+    #[test]
+    fn report() {
+        let report = Report {
+            draft02_task_id: None,
+            report_metadata: ReportMetadata {
+                id: ReportId([0xab; 16]),
+                time: 1_637_361_337,
+                draft02_extensions: None,
+            },
+            public_share: b"public".to_vec(),
+            encrypted_input_shares: [
+                HpkeCiphertext {
+                    config_id: 23,
+                    enc: vec![0x01, 0x02],
+                    payload: vec![0xff],
+                },
+                HpkeCiphertext {
+                    config_id: 119,
+                    enc: vec![0x03],
+                    payload: vec![0x04, 0x05],
+                },
+            ],
+        };
+
+        assert_eq!(
+            report.to_debug_json(),
+            json!({
+                "draft02_task_id": null,
+                "report_metadata": {
+                    "id": "abababababababababababababababab",
+                    "time": 1_637_361_337,
+                    "draft02_extensions": null,
+                },
+                "public_share": "7075626c6963",
+                "encrypted_input_shares": [
+                    { "config_id": 23, "enc": "0102", "payload": "ff" },
+                    { "config_id": 119, "enc": "03", "payload": "0405" },
+                ],
+            })
+        );
+    }
+
+    #[test]
+    fn transition_failure_by_name() {
+        let agg_job_resp = AggregationJobResp {
+            transitions: vec![Transition {
+                report_id: ReportId([0; 16]),
+                var: TransitionVar::Failed(TransitionFailure::BatchCollected),
+            }],
+        };
+
+        assert_eq!(
+            agg_job_resp.to_debug_json()["transitions"][0]["var"],
+            json!({ "failed": "batch_collected" })
+        );
+    }
+}
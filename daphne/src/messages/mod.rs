@@ -3,11 +3,15 @@
 
 //! Messages in the DAP protocol.
 
+#[cfg(any(test, feature = "debug-tools"))]
+pub mod debug;
+#[cfg(any(test, feature = "test-utils"))]
+pub mod fuzz;
 pub mod taskprov;
 
 use crate::{
     hpke::{HpkeAeadId, HpkeConfig, HpkeKdfId, HpkeKemId},
-    DapVersion,
+    DapTaskConfig, DapVersion,
 };
 use base64::engine::{general_purpose::URL_SAFE_NO_PAD, Engine};
 use hpke_rs::HpkePublicKey;
@@ -178,6 +182,19 @@ impl TaskId {
     }
 }
 
+impl ReportId {
+    /// Map the report to one of `shard_count` storage shards. The shard is derived from a keyed
+    /// hash (HMAC-SHA256) of the report ID, so that reports are spread evenly across shards and
+    /// Clients can't target a particular shard. A `shard_count` of `0` is treated as `1`.
+    pub fn shard(&self, shard_key: &[u8; 32], shard_count: u64) -> u32 {
+        let key = ring::hmac::Key::new(ring::hmac::HMAC_SHA256, shard_key);
+        let tag = ring::hmac::sign(&key, &self.0);
+        let n = u64::from_be_bytes(tag.as_ref()[..8].try_into().unwrap());
+        let shard_count = shard_count.clamp(1, u32::MAX.into());
+        u32::try_from(n % shard_count).unwrap()
+    }
+}
+
 /// A duration in seconds.
 pub type Duration = u64;
 
@@ -317,6 +334,7 @@ impl ParameterizedDecode<DapVersion> for ReportMetadata {
 pub struct Report {
     pub draft02_task_id: Option<TaskId>, // Set in draft02
     pub report_metadata: ReportMetadata,
+    #[serde(with = "hex")]
     pub public_share: Vec<u8>,
     pub encrypted_input_shares: [HpkeCiphertext; 2],
 }
@@ -362,9 +380,18 @@ impl ParameterizedDecode<DapVersion> for Report {
             report_metadata: ReportMetadata::decode_with_param(version, bytes)?,
             public_share: decode_u32_bytes(bytes)?,
             encrypted_input_shares: match version {
-                DapVersion::Draft02 => decode_u32_items(&(), bytes)?
-                    .try_into()
-                    .map_err(|_| CodecError::UnexpectedValue)?,
+                DapVersion::Draft02 => {
+                    let encrypted_input_shares: Vec<HpkeCiphertext> = decode_u32_items(&(), bytes)?;
+                    let num_shares = encrypted_input_shares.len();
+                    encrypted_input_shares.try_into().map_err(|_| {
+                        CodecError::Other(
+                            format!(
+                                "expected exactly two encrypted input shares; got {num_shares}"
+                            )
+                            .into(),
+                        )
+                    })?
+                }
                 DapVersion::DraftLatest => [
                     HpkeCiphertext::decode(bytes)?,
                     HpkeCiphertext::decode(bytes)?,
@@ -600,9 +627,39 @@ impl ParameterizedEncode<DapVersion> for AggregationJobInitReq {
     }
 }
 
-impl ParameterizedDecode<DapVersion> for AggregationJobInitReq {
-    fn decode_with_param(
-        version: &DapVersion,
+impl AggregationJobInitReq {
+    /// Decode an aggregation job initialization request, failing if it carries more than
+    /// `max_prep_inits` reports. Use this instead of
+    /// [`get_decoded_with_param`](ParameterizedDecode::get_decoded_with_param) when the request
+    /// comes from a peer, so that the number of reports decoded is bounded.
+    pub fn get_decoded_bounded(
+        version: DapVersion,
+        max_prep_inits: usize,
+        bytes: &[u8],
+    ) -> Result<Self, CodecError> {
+        Self::get_decoded_for_version(version, Some(max_prep_inits), bytes)
+    }
+
+    /// Decode an aggregation job initialization request using the structure defined by `version`,
+    /// failing if `max_prep_inits` is set and the request carries more reports than that. This
+    /// allows a single handler to serve requests for each draft.
+    pub fn get_decoded_for_version(
+        version: DapVersion,
+        max_prep_inits: Option<usize>,
+        bytes: &[u8],
+    ) -> Result<Self, CodecError> {
+        let mut cursor = Cursor::new(bytes);
+        let decoded = Self::decode_bounded(version, max_prep_inits, &mut cursor)?;
+        let num_bytes_left_over = bytes.len() - usize::try_from(cursor.position()).unwrap();
+        if num_bytes_left_over > 0 {
+            return Err(CodecError::BytesLeftOver(num_bytes_left_over));
+        }
+        Ok(decoded)
+    }
+
+    fn decode_bounded(
+        version: DapVersion,
+        max_prep_inits: Option<usize>,
         bytes: &mut Cursor<&[u8]>,
     ) -> Result<Self, CodecError> {
         let (draft02_task_id, draft02_agg_job_id, agg_param) = match version {
@@ -619,11 +676,23 @@ impl ParameterizedDecode<DapVersion> for AggregationJobInitReq {
             draft02_agg_job_id,
             agg_param,
             part_batch_sel: PartialBatchSelector::decode(bytes)?,
-            prep_inits: decode_u32_items(version, bytes)?,
+            prep_inits: match max_prep_inits {
+                Some(max_items) => decode_u32_items_bounded(max_items, &version, bytes)?,
+                None => decode_u32_items(&version, bytes)?,
+            },
         })
     }
 }
 
+impl ParameterizedDecode<DapVersion> for AggregationJobInitReq {
+    fn decode_with_param(
+        version: &DapVersion,
+        bytes: &mut Cursor<&[u8]>,
+    ) -> Result<Self, CodecError> {
+        Self::decode_bounded(*version, None, bytes)
+    }
+}
+
 /// Aggregate continuation request.
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub struct AggregationJobContinueReq {
@@ -816,7 +885,7 @@ impl std::fmt::Display for TransitionFailure {
 }
 
 /// An aggregate response sent from the Helper to the Leader.
-#[derive(Debug, PartialEq, Eq, Default)]
+#[derive(Clone, Debug, PartialEq, Eq, Default)]
 #[allow(missing_docs)]
 pub struct AggregationJobResp {
     pub transitions: Vec<Transition>,
@@ -836,6 +905,67 @@ impl Decode for AggregationJobResp {
     }
 }
 
+/// A chunk of an [`AggregationJobResp`] that is too large for the Helper to send at once. If a
+/// continuation token is set, then the Leader fetches the next chunk by sending it back in an
+/// [`AggregationJobRespChunkReq`]. (This is not part of DAP.)
+#[derive(Debug, PartialEq, Eq, Default)]
+#[allow(missing_docs)]
+pub struct AggregationJobRespChunk {
+    pub transitions: Vec<Transition>,
+    pub continuation_token: Option<u32>,
+}
+
+impl Encode for AggregationJobRespChunk {
+    fn encode(&self, bytes: &mut Vec<u8>) -> Result<(), CodecError> {
+        encode_u32_items(bytes, &(), &self.transitions)?;
+        match self.continuation_token {
+            None => 0_u8.encode(bytes)?,
+            Some(continuation_token) => {
+                1_u8.encode(bytes)?;
+                continuation_token.encode(bytes)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Decode for AggregationJobRespChunk {
+    fn decode(bytes: &mut Cursor<&[u8]>) -> Result<Self, CodecError> {
+        let transitions = decode_u32_items(&(), bytes)?;
+        let continuation_token = match u8::decode(bytes)? {
+            0 => None,
+            1 => Some(u32::decode(bytes)?),
+            _ => return Err(CodecError::UnexpectedValue),
+        };
+        Ok(Self {
+            transitions,
+            continuation_token,
+        })
+    }
+}
+
+/// A request from the Leader for the next chunk of an aggregation job response. (This is not part
+/// of DAP.)
+#[derive(Debug, PartialEq, Eq)]
+#[allow(missing_docs)]
+pub struct AggregationJobRespChunkReq {
+    pub continuation_token: u32,
+}
+
+impl Encode for AggregationJobRespChunkReq {
+    fn encode(&self, bytes: &mut Vec<u8>) -> Result<(), CodecError> {
+        self.continuation_token.encode(bytes)
+    }
+}
+
+impl Decode for AggregationJobRespChunkReq {
+    fn decode(bytes: &mut Cursor<&[u8]>) -> Result<Self, CodecError> {
+        Ok(Self {
+            continuation_token: u32::decode(bytes)?,
+        })
+    }
+}
+
 /// A batch interval.
 #[derive(Clone, Debug, Default, Deserialize, Eq, Hash, PartialEq, Serialize)]
 #[cfg_attr(any(test, feature = "test-utils"), derive(deepsize::DeepSizeOf))]
@@ -849,6 +979,33 @@ impl Interval {
     pub fn end(&self) -> Time {
         self.start + self.duration
     }
+
+    /// Check if the interval contains `time`. Intervals are half-open, i.e., the interval contains
+    /// its start but not its end.
+    pub fn contains(&self, time: Time) -> bool {
+        self.start <= time && time < self.end()
+    }
+
+    /// Return the smallest interval that contains this one and whose boundaries are aligned to
+    /// the task's batch windows: the start is snapped down and the end up to the nearest multiple
+    /// of the time precision. The result spans at least one batch window. Returns `None` if the
+    /// end of the aligned interval is not representable.
+    pub fn align_to(&self, task_config: &DapTaskConfig) -> Option<Self> {
+        let start = task_config.quantized_time_lower_bound(self.start);
+        let end = self.start.checked_add(self.duration)?;
+        let end = if task_config.quantized_time_lower_bound(end) == end {
+            end
+        } else {
+            task_config
+                .quantized_time_lower_bound(end)
+                .checked_add(task_config.time_precision)?
+        };
+        let end = end.max(start.checked_add(task_config.time_precision)?);
+        Some(Self {
+            start,
+            duration: end - start,
+        })
+    }
 }
 
 impl Encode for Interval {
@@ -1102,6 +1259,93 @@ impl ParameterizedDecode<DapVersion> for Collection {
     }
 }
 
+/// Serialization format of a [`Collection`]. The format is versioned separately from the DAP
+/// version so that the Leader can evolve it without breaking older Collectors.
+///
+/// The Collector indicates the latest format it supports in the "dap-collection-format" header
+/// and the Leader responds with the format it chose in the same header. A Collector that doesn't
+/// send the header gets [`Self::V1`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum CollectionFormat {
+    /// The format specified by DAP.
+    #[default]
+    V1,
+
+    /// The [`Self::V1`] encoding prefixed by the format version.
+    V2,
+}
+
+impl CollectionFormat {
+    /// The latest format supported by this crate.
+    pub const LATEST: Self = Self::V2;
+
+    /// Choose the latest format that is no newer than the latest format supported by the
+    /// Collector, if it indicated one.
+    pub fn negotiate(supported_by_collector: Option<u8>) -> Self {
+        match supported_by_collector {
+            Some(v) if v >= u8::from(Self::V2) => Self::V2,
+            _ => Self::V1,
+        }
+    }
+}
+
+impl From<CollectionFormat> for u8 {
+    fn from(format: CollectionFormat) -> Self {
+        match format {
+            CollectionFormat::V1 => 1,
+            CollectionFormat::V2 => 2,
+        }
+    }
+}
+
+impl TryFrom<u8> for CollectionFormat {
+    type Error = CodecError;
+
+    fn try_from(v: u8) -> Result<Self, Self::Error> {
+        match v {
+            1 => Ok(Self::V1),
+            2 => Ok(Self::V2),
+            _ => Err(CodecError::UnexpectedValue),
+        }
+    }
+}
+
+impl Collection {
+    /// Encode the collection in the given format.
+    pub fn get_encoded_with_format(
+        &self,
+        version: DapVersion,
+        format: CollectionFormat,
+    ) -> Result<Vec<u8>, CodecError> {
+        let mut bytes = Vec::new();
+        if format != CollectionFormat::V1 {
+            u8::from(format).encode(&mut bytes)?;
+        }
+        self.encode_with_param(&version, &mut bytes)?;
+        Ok(bytes)
+    }
+
+    /// Decode a collection encoded in the given format.
+    pub fn get_decoded_with_format(
+        version: DapVersion,
+        format: CollectionFormat,
+        bytes: &[u8],
+    ) -> Result<Self, CodecError> {
+        let mut r = Cursor::new(bytes);
+        if format != CollectionFormat::V1
+            && CollectionFormat::try_from(u8::decode(&mut r)?)? != format
+        {
+            return Err(CodecError::UnexpectedValue);
+        }
+        let collection = Self::decode_with_param(&version, &mut r)?;
+        let remaining = bytes.len() - usize::try_from(r.position()).unwrap();
+        if remaining > 0 {
+            return Err(CodecError::BytesLeftOver(remaining));
+        }
+        Ok(collection)
+    }
+}
+
 /// An aggregate-share request.
 //
 // TODO Add serialization tests.
@@ -1377,6 +1621,47 @@ pub(crate) fn decode_u32_bytes(bytes: &mut Cursor<&[u8]>) -> Result<Vec<u8>, Cod
     Ok(out)
 }
 
+/// The error returned (wrapped in [`CodecError::Other`]) when a bounded list has more items than
+/// allowed.
+#[derive(Debug, thiserror::Error)]
+#[error("list has more than the maximum of {max_items} items")]
+pub struct TooManyItems {
+    pub max_items: usize,
+}
+
+/// Like `decode_u32_items()` from libprio, except that decoding fails once more than `max_items`
+/// items have been read. The length prefix counts bytes rather than items, so the bound is
+/// enforced as the items are decoded; this caps the number of items allocated for a list whose
+/// length is controlled by the peer.
+pub(crate) fn decode_u32_items_bounded<P, D: ParameterizedDecode<P>>(
+    max_items: usize,
+    param: &P,
+    bytes: &mut Cursor<&[u8]>,
+) -> Result<Vec<D>, CodecError> {
+    let len = u32::decode(bytes)? as usize;
+    let item_start = usize::try_from(bytes.position()).unwrap();
+    let item_end = item_start
+        .checked_add(len)
+        .ok_or(CodecError::LengthPrefixTooBig(len))?;
+    let mut inner = Cursor::new(
+        bytes
+            .get_ref()
+            .get(item_start..item_end)
+            .ok_or(CodecError::LengthPrefixTooBig(len))?,
+    );
+
+    let mut items = Vec::new();
+    while usize::try_from(inner.position()).unwrap() < len {
+        if items.len() == max_items {
+            return Err(CodecError::Other(Box::new(TooManyItems { max_items })));
+        }
+        items.push(D::decode_with_param(param, &mut inner)?);
+    }
+
+    bytes.set_position(item_end.try_into().unwrap());
+    Ok(items)
+}
+
 /// Encode the input bytes as a URL-safe, base64 string.
 pub fn encode_base64url<T: AsRef<[u8]>>(input: T) -> String {
     URL_SAFE_NO_PAD.encode(input)
@@ -1681,6 +1966,88 @@ mod test {
         assert_eq!(got, want);
     }
 
+    fn decode_agg_job_init_req_bounded(version: DapVersion) {
+        let prep_init = PrepareInit {
+            report_share: ReportShare {
+                report_metadata: ReportMetadata {
+                    id: ReportId([99; 16]),
+                    time: 1_637_361_337,
+                    draft02_extensions: (version == DapVersion::Draft02).then(Vec::new),
+                },
+                public_share: b"public share".to_vec(),
+                encrypted_input_share: HpkeCiphertext {
+                    config_id: 23,
+                    enc: b"encapsulated key".to_vec(),
+                    payload: b"ciphertext".to_vec(),
+                },
+            },
+            draft_latest_payload: (version == DapVersion::DraftLatest)
+                .then(|| b"prep share".to_vec()),
+        };
+        let want = AggregationJobInitReq {
+            draft02_task_id: task_id_for_version(version),
+            draft02_agg_job_id: (version == DapVersion::Draft02)
+                .then_some(Draft02AggregationJobId([1; 32])),
+            agg_param: Vec::new(),
+            part_batch_sel: PartialBatchSelector::TimeInterval,
+            prep_inits: vec![prep_init; 3],
+        };
+        let mut bytes = want.get_encoded_with_param(&version).unwrap();
+
+        let got = AggregationJobInitReq::get_decoded_bounded(version, 3, &bytes).unwrap();
+        assert_eq!(got, want);
+
+        assert!(matches!(
+            AggregationJobInitReq::get_decoded_bounded(version, 2, &bytes),
+            Err(CodecError::Other(_))
+        ));
+
+        // Declare a list length that runs past the end of the message.
+        let prep_inits_offset = AggregationJobInitReq {
+            prep_inits: Vec::new(),
+            ..want
+        }
+        .get_encoded_with_param(&version)
+        .unwrap()
+        .len()
+            - 4;
+        bytes[prep_inits_offset..prep_inits_offset + 4].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert!(matches!(
+            AggregationJobInitReq::get_decoded_bounded(version, 3, &bytes),
+            Err(CodecError::LengthPrefixTooBig(_))
+        ));
+    }
+
+    test_versions! { decode_agg_job_init_req_bounded }
+
+    fn decode_agg_job_init_req_for_version(version: DapVersion) {
+        let want = AggregationJobInitReq {
+            draft02_task_id: task_id_for_version(version),
+            draft02_agg_job_id: (version == DapVersion::Draft02)
+                .then_some(Draft02AggregationJobId([1; 32])),
+            agg_param: b"agg param".to_vec(),
+            part_batch_sel: PartialBatchSelector::FixedSizeByBatchId {
+                batch_id: BatchId([2; 32]),
+            },
+            prep_inits: Vec::new(),
+        };
+        let bytes = want.get_encoded_with_param(&version).unwrap();
+
+        let got = AggregationJobInitReq::get_decoded_for_version(version, None, &bytes).unwrap();
+        assert_eq!(got, want);
+
+        // The request can't be decoded using the other draft's structure.
+        let other_version = match version {
+            DapVersion::Draft02 => DapVersion::DraftLatest,
+            DapVersion::DraftLatest => DapVersion::Draft02,
+        };
+        assert!(
+            AggregationJobInitReq::get_decoded_for_version(other_version, None, &bytes).is_err()
+        );
+    }
+
+    test_versions! { decode_agg_job_init_req_for_version }
+
     #[test]
     fn roundtrip_agg_job_cont_req() {
         let want = AggregationJobContinueReq {
@@ -1844,6 +2211,26 @@ mod test {
         assert_eq!(got, want);
     }
 
+    #[test]
+    fn roundtrip_agg_job_resp_chunk() {
+        for continuation_token in [None, Some(1337)] {
+            let want = AggregationJobRespChunk {
+                transitions: vec![Transition {
+                    report_id: ReportId([22; 16]),
+                    var: TransitionVar::Failed(TransitionFailure::ReportReplayed),
+                }],
+                continuation_token,
+            };
+            let got = AggregationJobRespChunk::get_decoded(&want.get_encoded().unwrap()).unwrap();
+            assert_eq!(got, want);
+        }
+
+        // Unrecognized continuation token flag.
+        let mut bytes = AggregationJobRespChunk::default().get_encoded().unwrap();
+        *bytes.last_mut().unwrap() = 2;
+        assert!(AggregationJobRespChunk::get_decoded(&bytes).is_err());
+    }
+
     #[test]
     fn read_hpke_config() {
         let data = [
@@ -1884,6 +2271,39 @@ mod test {
         );
     }
 
+    #[test]
+    fn roundtrip_hpke_config_list() {
+        let hpke_config_list = HpkeConfigList {
+            hpke_configs: vec![
+                HpkeConfig {
+                    id: 23,
+                    kem_id: HpkeKemId::X25519HkdfSha256,
+                    kdf_id: HpkeKdfId::HkdfSha256,
+                    aead_id: HpkeAeadId::Aes128Gcm,
+                    public_key: HpkePublicKey::from(b"this is a public key".to_vec()),
+                },
+                HpkeConfig {
+                    id: 42,
+                    kem_id: HpkeKemId::P256HkdfSha256,
+                    kdf_id: HpkeKdfId::HkdfSha256,
+                    aead_id: HpkeAeadId::Aes128Gcm,
+                    public_key: HpkePublicKey::from(b"this is another public key".to_vec()),
+                },
+            ],
+        };
+
+        let bytes = hpke_config_list.get_encoded().unwrap();
+        // The configs are prefixed by their total length in bytes, encoded as a `u16`.
+        assert_eq!(
+            usize::from(u16::from_be_bytes([bytes[0], bytes[1]])),
+            bytes.len() - 2
+        );
+        assert_eq!(
+            HpkeConfigList::get_decoded(&bytes).unwrap(),
+            hpke_config_list
+        );
+    }
+
     #[test]
     fn test_base64url() {
         let mut rng = thread_rng();
@@ -1921,4 +2341,116 @@ mod test {
         let id = TaskId([7; 32]);
         assert_eq!(TaskId::try_from_base64url(id.to_base64url()).unwrap(), id);
     }
+
+    fn collection_format(version: DapVersion) {
+        let collection = Collection {
+            part_batch_sel: PartialBatchSelector::TimeInterval,
+            report_count: 23,
+            draft_latest_interval: match version {
+                DapVersion::Draft02 => None,
+                DapVersion::DraftLatest => Some(Interval {
+                    start: 1_637_359_200,
+                    duration: 7200,
+                }),
+            },
+            encrypted_agg_shares: [
+                HpkeCiphertext {
+                    config_id: 23,
+                    enc: b"leader encapsulated key".to_vec(),
+                    payload: b"leader ciphertext".to_vec(),
+                },
+                HpkeCiphertext {
+                    config_id: 119,
+                    enc: b"helper encapsulated key".to_vec(),
+                    payload: b"helper ciphertext".to_vec(),
+                },
+            ],
+        };
+
+        // The V1 format is the DAP encoding.
+        let v1 = collection
+            .get_encoded_with_format(version, CollectionFormat::V1)
+            .unwrap();
+        assert_eq!(v1, collection.get_encoded_with_param(&version).unwrap());
+        assert_eq!(
+            Collection::get_decoded_with_format(version, CollectionFormat::V1, &v1).unwrap(),
+            collection
+        );
+
+        // The V2 format carries the format version.
+        let v2 = collection
+            .get_encoded_with_format(version, CollectionFormat::V2)
+            .unwrap();
+        assert_eq!(v2[0], 2);
+        assert_eq!(
+            Collection::get_decoded_with_format(version, CollectionFormat::V2, &v2).unwrap(),
+            collection
+        );
+
+        // Decoding with the wrong decoder fails.
+        assert!(Collection::get_decoded_with_format(version, CollectionFormat::V1, &v2).is_err());
+        assert!(Collection::get_decoded_with_format(version, CollectionFormat::V2, &v1).is_err());
+    }
+
+    test_versions! { collection_format }
+
+    #[test]
+    fn collection_format_negotiation() {
+        // A Collector that doesn't indicate a format gets the original one.
+        assert_eq!(CollectionFormat::negotiate(None), CollectionFormat::V1);
+        assert_eq!(CollectionFormat::negotiate(Some(1)), CollectionFormat::V1);
+        assert_eq!(CollectionFormat::negotiate(Some(2)), CollectionFormat::V2);
+
+        // A Collector newer than the Leader gets the latest format the Leader supports.
+        assert_eq!(
+            CollectionFormat::negotiate(Some(9)),
+            CollectionFormat::LATEST
+        );
+    }
+
+    #[test]
+    fn interval_contains() {
+        let interval = Interval {
+            start: 1_000,
+            duration: 100,
+        };
+        assert!(interval.contains(1_000));
+        assert!(interval.contains(1_099));
+        assert!(!interval.contains(1_100));
+        assert!(!interval.contains(999));
+    }
+
+    #[test]
+    fn report_id_shard() {
+        const SHARD_COUNT: usize = 8;
+        const NUM_REPORTS: usize = 8_000;
+        let mut rng = thread_rng();
+        let shard_key = rng.gen();
+        let other_shard_key = rng.gen();
+
+        let mut counts = [0; SHARD_COUNT];
+        let mut num_moved = 0;
+        for _ in 0..NUM_REPORTS {
+            let report_id = ReportId(rng.gen());
+            let shard = report_id.shard(&shard_key, SHARD_COUNT as u64);
+            assert_eq!(report_id.shard(&shard_key, SHARD_COUNT as u64), shard);
+            assert_eq!(report_id.shard(&shard_key, 1), 0);
+            if report_id.shard(&other_shard_key, SHARD_COUNT as u64) != shard {
+                num_moved += 1;
+            }
+            counts[usize::try_from(shard).unwrap()] += 1;
+        }
+
+        // Each shard should get about 1/8 of the reports. The bounds are loose enough that the
+        // test is not flaky in practice.
+        for count in counts {
+            assert!(
+                (800..1_200).contains(&count),
+                "unbalanced shards: {counts:?}"
+            );
+        }
+
+        // The assignment depends on the key.
+        assert!(num_moved > NUM_REPORTS / 2);
+    }
 }
@@ -3,13 +3,17 @@
 
 //! Daphne metrics.
 
+use crate::messages::TransitionFailure;
+
 pub trait DaphneMetrics: Send + Sync {
     fn inbound_req_inc(&self, request_type: DaphneRequestType);
     fn report_inc_by(&self, status: &str, val: u64);
+    fn report_rejection_inc_by(&self, failure: TransitionFailure, val: u64);
     fn agg_job_observe_batch_size(&self, val: usize);
     fn agg_job_started_inc(&self);
     fn agg_job_completed_inc(&self);
     fn agg_job_put_span_retry_inc(&self);
+    fn hpke_decrypt_failure_inc(&self, config_id: &str);
 }
 
 #[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
@@ -27,7 +31,7 @@ pub enum DaphneRequestType {
 #[cfg(any(feature = "prometheus", feature = "test-utils", test))]
 pub mod prometheus {
     use super::{DaphneMetrics, DaphneRequestType};
-    use crate::{fatal_error, DapError};
+    use crate::{fatal_error, messages::TransitionFailure, DapError};
     use ::prometheus::{
         exponential_buckets, register_histogram_with_registry,
         register_int_counter_vec_with_registry, register_int_counter_with_registry, Histogram,
@@ -43,6 +47,9 @@ pub mod prometheus {
         /// a report is rejected, the failure type is recorded.
         report_counter: IntCounterVec,
 
+        /// Number of reports rejected, broken down by the reason for rejection.
+        report_rejection_total: IntCounterVec,
+
         /// Helper: Total number of aggregation jobs started and completed.
         aggregation_job_counter: IntCounterVec,
 
@@ -51,6 +58,10 @@ pub mod prometheus {
 
         /// Helper: Number of times replays caused the aggregation to be retried.
         aggregation_job_put_span_retry_counter: IntCounter,
+
+        /// Number of input shares that could not be decrypted, broken down by the HPKE config
+        /// ID they were encrypted under.
+        hpke_decrypt_failure_counter: IntCounterVec,
     }
 
     impl DaphnePromMetrics {
@@ -75,6 +86,15 @@ pub mod prometheus {
             )
             .map_err(|e| fatal_error!(err = ?e, "failed to register report_counter"))?;
 
+            #[allow(clippy::ignored_unit_patterns)]
+            let report_rejection_total = register_int_counter_vec_with_registry!(
+                "report_rejection_total",
+                "Total number of reports rejected, by reason.",
+                &["reason"],
+                registry
+            )
+            .map_err(|e| fatal_error!(err = ?e, "failed to register report_rejection_total"))?;
+
             #[allow(clippy::ignored_unit_patterns)]
             let aggregation_job_batch_size_histogram = register_histogram_with_registry!(
                 "aggregation_job_batch_size",
@@ -104,12 +124,25 @@ pub mod prometheus {
                 )
                 .map_err(|e| fatal_error!(err = ?e, "failed to register aggregation_job_put_span_retry_counter"))?;
 
+            #[allow(clippy::ignored_unit_patterns)]
+            let hpke_decrypt_failure_counter = register_int_counter_vec_with_registry!(
+                "hpke_decrypt_failure_counter",
+                "Total number of input shares that could not be decrypted.",
+                &["config_id"],
+                registry
+            )
+            .map_err(
+                |e| fatal_error!(err = ?e, "failed to register hpke_decrypt_failure_counter"),
+            )?;
+
             Ok(Self {
                 inbound_request_counter,
                 report_counter,
+                report_rejection_total,
                 aggregation_job_counter,
                 aggregation_job_batch_size_histogram,
                 aggregation_job_put_span_retry_counter,
+                hpke_decrypt_failure_counter,
             })
         }
     }
@@ -132,6 +165,12 @@ pub mod prometheus {
             self.report_counter.with_label_values(&[status]).inc_by(val);
         }
 
+        fn report_rejection_inc_by(&self, failure: TransitionFailure, val: u64) {
+            self.report_rejection_total
+                .with_label_values(&[&failure.to_string()])
+                .inc_by(val);
+        }
+
         fn agg_job_observe_batch_size(&self, val: usize) {
             self.aggregation_job_batch_size_histogram
                 .observe(val as f64);
@@ -152,5 +191,11 @@ pub mod prometheus {
         fn agg_job_put_span_retry_inc(&self) {
             self.aggregation_job_put_span_retry_counter.inc();
         }
+
+        fn hpke_decrypt_failure_inc(&self, config_id: &str) {
+            self.hpke_decrypt_failure_counter
+                .with_label_values(&[config_id])
+                .inc();
+        }
     }
 }
@@ -8,22 +8,23 @@ use crate::{
     fatal_error,
     hpke::{HpkeConfig, HpkeDecrypter},
     messages::{
-        encode_u32_bytes, encode_u32_prefixed, AggregationJobContinueReq, AggregationJobInitReq,
-        AggregationJobResp, Base64Encode, BatchSelector, Extension, HpkeCiphertext,
-        PartialBatchSelector, PlaintextInputShare, PrepareInit, Report, ReportId, ReportMetadata,
-        ReportShare, TaskId, Transition, TransitionFailure, TransitionVar,
+        encode_u32_bytes, AggregationJobContinueReq, AggregationJobInitReq, AggregationJobResp,
+        Base64Encode, BatchSelector, Extension, HpkeCiphertext, PartialBatchSelector,
+        PlaintextInputShare, PrepareInit, Report, ReportId, ReportMetadata, ReportShare, TaskId,
+        Transition, TransitionFailure, TransitionVar,
     },
     metrics::DaphneMetrics,
     roles::DapReportInitializer,
+    taskprov::compute_task_id,
     vdaf::{
         prio2::{prio2_prep_finish, prio2_prep_finish_from_shares, prio2_prep_init},
         prio3::{prio3_prep_finish, prio3_prep_finish_from_shares, prio3_prep_init},
         VdafError, VdafPrepMessage, VdafPrepState, VdafVerifyKey,
     },
     AggregationJobReportState, DapAggregateShare, DapAggregateSpan, DapAggregationJobState,
-    DapAggregationJobUncommitted, DapAggregationParam, DapError, DapHelperAggregationJobTransition,
-    DapLeaderAggregationJobTransition, DapOutputShare, DapTaskConfig, DapVersion,
-    MetaAggregationJobId, VdafConfig,
+    DapAggregationJobUncommitted, DapAggregationParam, DapError, DapGlobalConfig,
+    DapHelperAggregationJobTransition, DapLeaderAggregationJobTransition, DapOutputShare,
+    DapTaskConfig, DapVersion, MetaAggregationJobId, VdafConfig,
 };
 use prio::codec::{
     encode_u32_items, CodecError, Decode, Encode, ParameterizedDecode, ParameterizedEncode,
@@ -35,11 +36,25 @@ use std::{
 };
 
 use super::{
-    CTX_AGG_SHARE_DRAFT02, CTX_AGG_SHARE_DRAFT_LATEST, CTX_INPUT_SHARE_DRAFT02,
+    agg_share_aad, CTX_AGG_SHARE_DRAFT02, CTX_AGG_SHARE_DRAFT_LATEST, CTX_INPUT_SHARE_DRAFT02,
     CTX_INPUT_SHARE_DRAFT_LATEST, CTX_ROLE_CLIENT, CTX_ROLE_COLLECTOR, CTX_ROLE_HELPER,
     CTX_ROLE_LEADER,
 };
 
+/// Decide whether to log the metadata of the report with the given ID, given the fraction of
+/// reports to sample. The decision is a deterministic function of the report ID, so a report is
+/// either always or never sampled.
+#[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+fn sample_report_for_debug(report_id: &ReportId, rate: f64) -> bool {
+    if rate.is_nan() || rate <= 0.0 {
+        return false;
+    }
+    let modulus = (1.0 / rate).round().max(1.0) as u64;
+    let digest = ring::digest::digest(&ring::digest::SHA256, report_id.as_ref());
+    let hash = u64::from_be_bytes(digest.as_ref()[..8].try_into().unwrap());
+    hash % modulus == 0
+}
+
 // Ping-pong message framing as defined in draft-irtf-cfrg-vdaf-08, Section 5.8. We do not
 // implement the "continue" message type because we only support 1-round VDAFs.
 enum PingPongMessageType {
@@ -197,7 +212,20 @@ impl EarlyReportStateConsumed {
                 }
 
                 match (task_config.version, extension) {
-                    (.., Extension::Taskprov { .. }) if task_config.method_is_taskprov() => {
+                    (.., Extension::Taskprov { draft02_payload })
+                        if task_config.method_is_taskprov() =>
+                    {
+                        // draft02: The payload is the task configuration advertised by the Client,
+                        // from which the task ID is derived. Drop the report if it advertises a
+                        // different task.
+                        if draft02_payload.as_ref().is_some_and(|payload| {
+                            compute_task_id(task_config.version, payload) != *task_id
+                        }) {
+                            return Ok(Self::Rejected {
+                                metadata: state.metadata,
+                                failure: TransitionFailure::ReportDropped,
+                            });
+                        }
                         taskprov_indicated = true;
                     }
 
@@ -318,6 +346,7 @@ impl EarlyReportStateInitialized {
             VdafConfig::Mastic {
                 input_size,
                 weight_config,
+                ..
             } => mastic_prep_init(
                 *input_size,
                 *weight_config,
@@ -494,6 +523,7 @@ impl DapTaskConfig {
                 EarlyReportStateInitialized::Rejected { failure, .. } => {
                     // Skip report that can't be processed any further.
                     metrics.report_inc_by(&format!("rejected_{failure}"), 1);
+                    metrics.report_rejection_inc_by(failure, 1);
                     continue;
                 }
             }
@@ -520,12 +550,25 @@ impl DapTaskConfig {
         ))
     }
 
+    /// Decrypt and initialize the report shares in an aggregation job. This method is run by the
+    /// Helper.
+    ///
+    /// `decryption_progress` is called with the number of report shares decrypted so far and the
+    /// total number of report shares each time a report share is decrypted. This happens before
+    /// the report shares are initialized, which is done for all of them at once.
+    ///
+    /// The metadata of a fraction `report_debug_sample_rate` (see [`DapGlobalConfig`]) of the report
+    /// shares is logged for debugging.
+    #[allow(clippy::too_many_arguments)]
     pub(crate) async fn helper_initialize_reports(
         &self,
         decrypter: &impl HpkeDecrypter,
         initializer: &impl DapReportInitializer,
         task_id: &TaskId,
         agg_job_init_req: AggregationJobInitReq,
+        metrics: &dyn DaphneMetrics,
+        global_config: &DapGlobalConfig,
+        mut decryption_progress: impl FnMut(usize, usize) + Send,
     ) -> Result<Vec<EarlyReportStateInitialized>, DapError> {
         let num_reports = agg_job_init_req.prep_inits.len();
         let mut processed = HashSet::with_capacity(num_reports);
@@ -543,6 +586,16 @@ impl DapTaskConfig {
             }
             processed.insert(prep_init.report_share.report_metadata.id);
 
+            let metadata = &prep_init.report_share.report_metadata;
+            if sample_report_for_debug(&metadata.id, global_config.report_debug_sample_rate) {
+                tracing::info!(
+                    report_id = %metadata.id,
+                    time = metadata.time,
+                    hpke_config_id = prep_init.report_share.encrypted_input_share.config_id,
+                    "sampled report share"
+                );
+            }
+
             consumed_reports.push(
                 EarlyReportStateConsumed::consume(
                     decrypter,
@@ -558,11 +611,11 @@ impl DapTaskConfig {
                 )
                 .await?,
             );
+            decryption_progress(consumed_reports.len(), num_reports);
         }
 
         let agg_param =
-            DapAggregationParam::get_decoded_with_param(&self.vdaf, &agg_job_init_req.agg_param)
-                .map_err(|e| DapAbort::from_codec_error(e, *task_id))?;
+            DapAggregationParam::decode_for_task(task_id, &self.vdaf, &agg_job_init_req.agg_param)?;
 
         let initialized_reports = initializer
             .initialize_reports(
@@ -575,6 +628,12 @@ impl DapTaskConfig {
             )
             .await?;
 
+        for initialized_report in &initialized_reports {
+            if let EarlyReportStateInitialized::Rejected { failure, .. } = initialized_report {
+                metrics.report_rejection_inc_by(*failure, 1);
+            }
+        }
+
         Ok(initialized_reports)
     }
 
@@ -715,15 +774,14 @@ impl DapTaskConfig {
                                     leader_prep_share,
                                 ),
                                 #[cfg(any(test, feature = "test-utils"))]
-                                VdafConfig::Mastic {
-                                    input_size: _,
-                                    weight_config,
-                                } => mastic_prep_finish_from_shares(
-                                    *weight_config,
-                                    helper_prep_state.clone(),
-                                    helper_prep_share.clone(),
-                                    leader_prep_share,
-                                ),
+                                VdafConfig::Mastic { weight_config, .. } => {
+                                    mastic_prep_finish_from_shares(
+                                        *weight_config,
+                                        helper_prep_state.clone(),
+                                        helper_prep_share.clone(),
+                                        leader_prep_share,
+                                    )
+                                }
                             });
 
                         match res {
@@ -1235,9 +1293,12 @@ impl DapTaskConfig {
 
     /// Encrypt an aggregate share under the Collector's public key. This method is run by the
     /// Leader in reponse to a collect request.
+    ///
+    /// The Collector's HPKE config is selected by `collector_hpke_config_id` from among those
+    /// accepted for the task (see [`DapTaskConfig::collector_hpke_config_for`]).
     pub fn produce_leader_encrypted_agg_share(
         &self,
-        hpke_config: &HpkeConfig,
+        collector_hpke_config_id: Option<u8>,
         task_id: &TaskId,
         batch_sel: &BatchSelector,
         agg_param: &DapAggregationParam,
@@ -1246,7 +1307,7 @@ impl DapTaskConfig {
     ) -> Result<HpkeCiphertext, DapError> {
         produce_encrypted_agg_share(
             true,
-            hpke_config,
+            self.collector_hpke_config_for(task_id, collector_hpke_config_id)?,
             task_id,
             batch_sel,
             agg_param,
@@ -1259,7 +1320,7 @@ impl DapTaskConfig {
     /// aggregate-share request.
     pub fn produce_helper_encrypted_agg_share(
         &self,
-        hpke_config: &HpkeConfig,
+        collector_hpke_config_id: Option<u8>,
         task_id: &TaskId,
         batch_sel: &BatchSelector,
         agg_param: &DapAggregationParam,
@@ -1268,7 +1329,7 @@ impl DapTaskConfig {
     ) -> Result<HpkeCiphertext, DapError> {
         produce_encrypted_agg_share(
             false,
-            hpke_config,
+            self.collector_hpke_config_for(task_id, collector_hpke_config_id)?,
             task_id,
             batch_sel,
             agg_param,
@@ -1287,10 +1348,14 @@ fn produce_encrypted_agg_share(
     agg_share: &DapAggregateShare,
     version: DapVersion,
 ) -> Result<HpkeCiphertext, DapError> {
+    // An aggregate share with no reports can't be collected.
     let agg_share_data = agg_share
         .data
         .as_ref()
-        .ok_or_else(|| fatal_error!(err = "empty aggregate share"))?
+        .ok_or_else(|| DapAbort::InvalidBatchSize {
+            detail: "aggregate share is empty".into(),
+            task_id: *task_id,
+        })?
         .get_encoded()
         .map_err(DapError::encoding)?;
 
@@ -1308,13 +1373,7 @@ fn produce_encrypted_agg_share(
     }); // Sender role
     info.push(CTX_ROLE_COLLECTOR); // Receiver role
 
-    let mut aad = Vec::with_capacity(40);
-    task_id.encode(&mut aad).map_err(DapError::encoding)?;
-    if version != DapVersion::Draft02 {
-        encode_u32_prefixed(version, &mut aad, |_version, bytes| agg_param.encode(bytes))
-            .map_err(DapError::encoding)?;
-    }
-    batch_sel.encode(&mut aad).map_err(DapError::encoding)?;
+    let aad = agg_share_aad(version, task_id, batch_sel, agg_param)?;
 
     let (enc, payload) = hpke_config.encrypt(&info, &aad, &agg_share_data)?;
     Ok(HpkeCiphertext {
@@ -51,7 +51,30 @@ impl VdafConfig {
         version: DapVersion,
     ) -> Result<Report, DapError> {
         let mut rng = thread_rng();
-        let report_id = ReportId(rng.gen());
+        self.produce_report_with_id(
+            hpke_config_list,
+            time,
+            task_id,
+            &ReportId(rng.gen()),
+            measurement,
+            extensions,
+            version,
+        )
+    }
+
+    /// Like [`Self::produce_report_with_extensions`], except the report ID is chosen by the caller
+    /// rather than sampled at random.
+    #[allow(clippy::too_many_arguments)]
+    pub(crate) fn produce_report_with_id(
+        &self,
+        hpke_config_list: &[HpkeConfig],
+        time: Time,
+        task_id: &TaskId,
+        report_id: &ReportId,
+        measurement: DapMeasurement,
+        extensions: Vec<Extension>,
+        version: DapVersion,
+    ) -> Result<Report, DapError> {
         let (public_share, input_shares) = self.produce_input_shares(measurement, &report_id.0)?;
         Self::produce_report_with_extensions_for_shares(
             public_share,
@@ -59,7 +82,7 @@ impl VdafConfig {
             hpke_config_list,
             time,
             task_id,
-            &report_id,
+            report_id,
             extensions,
             version,
         )
@@ -167,6 +190,7 @@ impl VdafConfig {
         measurement: DapMeasurement,
         nonce: &[u8; 16],
     ) -> Result<(Vec<u8>, Vec<Vec<u8>>), DapError> {
+        self.validate_measurement(&measurement)?;
         match self {
             Self::Prio3(prio3_config) => Ok(prio3_shard(prio3_config, measurement, nonce)?),
             Self::Prio2 { dimension } => Ok(prio2_shard(*dimension, measurement, nonce)?),
@@ -174,6 +198,7 @@ impl VdafConfig {
             VdafConfig::Mastic {
                 input_size,
                 weight_config,
+                ..
             } => Ok(mastic_shard(*input_size, *weight_config, measurement)?),
         }
     }
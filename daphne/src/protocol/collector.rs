@@ -2,19 +2,23 @@
 // SPDX-License-Identifier: BSD-3-Clause
 
 #[cfg(any(test, feature = "test-utils"))]
-use crate::vdaf::mastic::mastic_unshard;
+use crate::vdaf::mastic::{mastic_merge_agg_shares, mastic_unshard};
 use crate::{
+    error::CollectError,
     fatal_error,
     hpke::HpkeDecrypter,
-    messages::{encode_u32_prefixed, BatchSelector, HpkeCiphertext, TaskId},
-    vdaf::{prio2::prio2_unshard, prio3::prio3_unshard},
-    DapAggregateResult, DapAggregationParam, DapError, DapVersion, VdafConfig,
+    messages::{BatchSelector, HpkeCiphertext, TaskId},
+    vdaf::{
+        prio2::{prio2_merge_agg_shares, prio2_unshard},
+        prio3::{prio3_merge_agg_shares, prio3_unshard},
+    },
+    DapAggregateResult, DapAggregationParam, DapError, DapSender, DapVersion, VdafAggregateShare,
+    VdafConfig,
 };
-use prio::codec::Encode;
 
 use super::{
-    CTX_AGG_SHARE_DRAFT02, CTX_AGG_SHARE_DRAFT_LATEST, CTX_ROLE_COLLECTOR, CTX_ROLE_HELPER,
-    CTX_ROLE_LEADER,
+    agg_share_aad, CTX_AGG_SHARE_DRAFT02, CTX_AGG_SHARE_DRAFT_LATEST, CTX_ROLE_COLLECTOR,
+    CTX_ROLE_HELPER, CTX_ROLE_LEADER,
 };
 
 impl VdafConfig {
@@ -30,13 +34,16 @@ impl VdafConfig {
     /// * `batch_interval` is the batch interval for the aggregate share.
     ///
     /// * `encrypted_agg_shares` is the set of encrypted aggregate shares produced by the
-    /// Aggregators. The first encrypted aggregate shares must be the Leader's.
+    /// Aggregators, in any order. Both shares are encrypted under the Collector's HPKE config,
+    ///   so the config ID doesn't say which Aggregator sent which share; instead each share is
+    ///   matched to the sender role bound into its HPKE info string. Exactly one share is expected
+    ///   from each Aggregator.
     ///
     /// * `version` is the `DapVersion` to use.
     #[allow(clippy::too_many_arguments)]
     pub async fn consume_encrypted_agg_shares(
         &self,
-        decrypter: &impl HpkeDecrypter,
+        decrypter: &(impl HpkeDecrypter + Sync),
         task_id: &TaskId,
         batch_sel: &BatchSelector,
         report_count: u64,
@@ -44,49 +51,15 @@ impl VdafConfig {
         encrypted_agg_shares: Vec<HpkeCiphertext>,
         version: DapVersion,
     ) -> Result<DapAggregateResult, DapError> {
-        if encrypted_agg_shares.len() != 2 {
-            return Err(fatal_error!(
-                err = "unexpected number of encrypted aggregate shares"
-            ));
-        }
-
-        let agg_share_text = match version {
-            DapVersion::Draft02 => CTX_AGG_SHARE_DRAFT02,
-            DapVersion::DraftLatest => CTX_AGG_SHARE_DRAFT_LATEST,
-        };
-        let n: usize = agg_share_text.len();
-        let mut info = Vec::with_capacity(n + 2);
-        info.extend_from_slice(agg_share_text);
-        info.push(CTX_ROLE_LEADER); // Sender role placeholder
-        info.push(CTX_ROLE_COLLECTOR); // Receiver role
-
-        let mut aad = Vec::with_capacity(40);
-        task_id.encode(&mut aad).map_err(DapError::encoding)?;
-        if version != DapVersion::Draft02 {
-            encode_u32_prefixed(version, &mut aad, |_version, bytes| agg_param.encode(bytes))
-                .map_err(DapError::encoding)?;
-        }
-        batch_sel.encode(&mut aad).map_err(DapError::encoding)?;
-
-        let mut agg_shares = Vec::with_capacity(encrypted_agg_shares.len());
-        for (i, agg_share_ciphertext) in encrypted_agg_shares.iter().enumerate() {
-            info[n] = if i == 0 {
-                CTX_ROLE_LEADER
-            } else {
-                CTX_ROLE_HELPER
-            };
-
-            let agg_share_data = decrypter
-                .hpke_decrypt(task_id, &info, &aad, agg_share_ciphertext)
-                .await?;
-            agg_shares.push(agg_share_data);
-        }
-
-        if agg_shares.len() != encrypted_agg_shares.len() {
-            return Err(fatal_error!(
-                err = "one or more HPKE ciphertexts with unrecognized config ID",
-            ));
-        }
+        let agg_shares = decrypt_agg_shares(
+            decrypter,
+            task_id,
+            batch_sel,
+            agg_param,
+            &encrypted_agg_shares,
+            version,
+        )
+        .await?;
 
         let num_measurements = usize::try_from(report_count).unwrap();
         match self {
@@ -97,10 +70,153 @@ impl VdafConfig {
                 Ok(prio2_unshard(*dimension, num_measurements, agg_shares)?)
             }
             #[cfg(any(test, feature = "test-utils"))]
-            Self::Mastic {
-                input_size: _,
-                weight_config,
-            } => Ok(mastic_unshard(*weight_config, agg_param, agg_shares)?),
+            Self::Mastic { weight_config, .. } => {
+                Ok(mastic_unshard(*weight_config, agg_param, agg_shares)?)
+            }
         }
     }
+
+    /// Like [`consume_encrypted_agg_shares`](Self::consume_encrypted_agg_shares), except that the
+    /// aggregate result is passed to `sink` one element at a time instead of being returned. The
+    /// sink is called with the index and value of each element, in order; a scalar result, e.g.,
+    /// for `Prio3Count`, is passed as the element at index 0. If the sink returns an error, no
+    /// further elements are passed to it and the error is returned.
+    ///
+    /// The aggregate shares are merged in place and each element of the result is converted to an
+    /// integer as it is emitted, so the aggregate result, which may be large for vector VDAFs like
+    /// `Prio3SumVec` and `Prio3Histogram`, is never materialized. This relies on the unsharding
+    /// algorithm of each of these VDAFs being the sum of the aggregate shares, interpreted as
+    /// integers.
+    #[allow(clippy::too_many_arguments)]
+    pub async fn consume_encrypted_agg_shares_into<E: From<DapError>>(
+        &self,
+        decrypter: &(impl HpkeDecrypter + Sync),
+        task_id: &TaskId,
+        batch_sel: &BatchSelector,
+        agg_param: &DapAggregationParam,
+        encrypted_agg_shares: Vec<HpkeCiphertext>,
+        version: DapVersion,
+        sink: impl FnMut(usize, u128) -> Result<(), E>,
+    ) -> Result<(), E> {
+        let agg_shares = decrypt_agg_shares(
+            decrypter,
+            task_id,
+            batch_sel,
+            agg_param,
+            &encrypted_agg_shares,
+            version,
+        )
+        .await?;
+
+        let agg_share = match self {
+            Self::Prio3(prio3_config) => prio3_merge_agg_shares(prio3_config, agg_shares),
+            Self::Prio2 { dimension } => prio2_merge_agg_shares(*dimension, agg_shares),
+            #[cfg(any(test, feature = "test-utils"))]
+            Self::Mastic { weight_config, .. } => {
+                mastic_merge_agg_shares(*weight_config, agg_param, agg_shares)
+            }
+        }
+        .map_err(DapError::from)?;
+
+        match agg_share {
+            VdafAggregateShare::Field64(agg) => {
+                emit(agg.as_ref().iter().map(|x| u64::from(*x).into()), sink)
+            }
+            VdafAggregateShare::Field128(agg) => {
+                emit(agg.as_ref().iter().map(|x| u128::from(*x)), sink)
+            }
+            VdafAggregateShare::FieldPrio2(agg) => {
+                emit(agg.as_ref().iter().map(|x| u32::from(*x).into()), sink)
+            }
+        }
+    }
+}
+
+/// Pass each element of the aggregate result to `sink`, stopping at the first error.
+fn emit<E>(
+    agg_res: impl Iterator<Item = u128>,
+    mut sink: impl FnMut(usize, u128) -> Result<(), E>,
+) -> Result<(), E> {
+    for (i, x) in agg_res.enumerate() {
+        sink(i, x)?;
+    }
+    Ok(())
+}
+
+/// Decrypt the aggregate shares and order them by sender, Leader first.
+async fn decrypt_agg_shares(
+    decrypter: &(impl HpkeDecrypter + Sync),
+    task_id: &TaskId,
+    batch_sel: &BatchSelector,
+    agg_param: &DapAggregationParam,
+    encrypted_agg_shares: &[HpkeCiphertext],
+    version: DapVersion,
+) -> Result<[Vec<u8>; 2], DapError> {
+    if encrypted_agg_shares.len() > 2 {
+        return Err(fatal_error!(
+            err = "unexpected number of encrypted aggregate shares"
+        ));
+    }
+
+    let agg_share_text = match version {
+        DapVersion::Draft02 => CTX_AGG_SHARE_DRAFT02,
+        DapVersion::DraftLatest => CTX_AGG_SHARE_DRAFT_LATEST,
+    };
+    let n: usize = agg_share_text.len();
+    let mut info = Vec::with_capacity(n + 2);
+    info.extend_from_slice(agg_share_text);
+    info.push(CTX_ROLE_LEADER); // Sender role placeholder
+    info.push(CTX_ROLE_COLLECTOR); // Receiver role
+
+    let aad = agg_share_aad(version, task_id, batch_sel, agg_param)?;
+
+    let mut leader_agg_share = None;
+    let mut helper_agg_share = None;
+    for (i, agg_share_ciphertext) in encrypted_agg_shares.iter().enumerate() {
+        // Shares are usually delivered Leader first, so try that role first.
+        let roles = if i == 0 {
+            [DapSender::Leader, DapSender::Helper]
+        } else {
+            [DapSender::Helper, DapSender::Leader]
+        };
+
+        let mut decrypted = None;
+        for sender in roles {
+            info[n] = match sender {
+                DapSender::Leader => CTX_ROLE_LEADER,
+                _ => CTX_ROLE_HELPER,
+            };
+            match decrypter
+                .hpke_decrypt_agg_share(task_id, &info, &aad, agg_share_ciphertext)
+                .await
+            {
+                Ok(agg_share_data) => {
+                    decrypted = Some((sender, agg_share_data));
+                    break;
+                }
+                Err(DapError::Collect(CollectError::HpkeDecryptError)) => (),
+                Err(e) => return Err(e),
+            }
+        }
+        let Some((sender, agg_share_data)) = decrypted else {
+            return Err(CollectError::HpkeDecryptError.into());
+        };
+
+        let slot = match sender {
+            DapSender::Leader => &mut leader_agg_share,
+            _ => &mut helper_agg_share,
+        };
+        if slot.replace(agg_share_data).is_some() {
+            return Err(CollectError::DuplicateAggShare { sender }.into());
+        }
+    }
+
+    Ok([
+        leader_agg_share.ok_or(CollectError::MissingAggShare {
+            sender: DapSender::Leader,
+        })?,
+        helper_agg_share.ok_or(CollectError::MissingAggShare {
+            sender: DapSender::Helper,
+        })?,
+    ])
 }
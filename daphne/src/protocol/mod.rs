@@ -5,6 +5,12 @@ pub(crate) mod aggregator;
 mod client;
 mod collector;
 
+use crate::{
+    messages::{encode_u32_prefixed, BatchSelector, TaskId},
+    DapAggregationParam, DapError, DapVersion,
+};
+use prio::codec::Encode;
+
 const CTX_INPUT_SHARE_DRAFT02: &[u8] = b"dap-02 input share";
 const CTX_INPUT_SHARE_DRAFT_LATEST: &[u8] = b"dap-09 input share";
 const CTX_AGG_SHARE_DRAFT02: &[u8] = b"dap-02 aggregate share";
@@ -14,12 +20,31 @@ const CTX_ROLE_CLIENT: u8 = 1;
 const CTX_ROLE_LEADER: u8 = 2;
 const CTX_ROLE_HELPER: u8 = 3;
 
+/// Associated data for encrypting an aggregate share. Binding the task ID, aggregation parameter
+/// (not in draft02) and batch selector ensures a share produced for one batch can't be decrypted
+/// as the share for another.
+fn agg_share_aad(
+    version: DapVersion,
+    task_id: &TaskId,
+    batch_sel: &BatchSelector,
+    agg_param: &DapAggregationParam,
+) -> Result<Vec<u8>, DapError> {
+    let mut aad = Vec::with_capacity(40);
+    task_id.encode(&mut aad).map_err(DapError::encoding)?;
+    if version != DapVersion::Draft02 {
+        encode_u32_prefixed(version, &mut aad, |_version, bytes| agg_param.encode(bytes))
+            .map_err(DapError::encoding)?;
+    }
+    batch_sel.encode(&mut aad).map_err(DapError::encoding)?;
+    Ok(aad)
+}
+
 #[cfg(test)]
 mod test {
     use crate::{
-        assert_metrics_include, async_test_versions,
-        error::DapAbort,
-        hpke::{HpkeAeadId, HpkeConfig, HpkeKdfId, HpkeKemId},
+        assert_metrics_include, async_test_version, async_test_versions,
+        error::{CollectError, DapAbort},
+        hpke::{HpkeAeadId, HpkeConfig, HpkeKdfId, HpkeKemId, HpkeReceiverConfig},
         messages::{
             AggregationJobInitReq, BatchSelector, Extension, Interval, PartialBatchSelector,
             PrepareInit, Report, ReportId, ReportShare, Transition, TransitionFailure,
@@ -28,19 +53,21 @@ mod test {
         protocol::aggregator::{
             EarlyReportState, EarlyReportStateConsumed, EarlyReportStateInitialized,
         },
+        taskprov::compute_task_id,
         test_versions,
         testing::AggregationJobTest,
         vdaf::{Prio3Config, VdafConfig},
         DapAggregateResult, DapAggregateShare, DapAggregateSpan, DapAggregationJobState,
         DapAggregationJobUncommitted, DapAggregationParam, DapError,
         DapHelperAggregationJobTransition, DapLeaderAggregationJobTransition, DapMeasurement,
-        DapVersion, VdafAggregateShare, VdafPrepMessage, VdafPrepState,
+        DapSender, DapTaskConfigMethod, DapVersion, VdafAggregateShare, VdafPrepMessage,
+        VdafPrepState,
     };
     use assert_matches::assert_matches;
     use hpke_rs::HpkePublicKey;
     use prio::{
         codec::Encode,
-        field::Field64,
+        field::{Field128, Field64, FieldPrio2},
         vdaf::{
             prio3::Prio3, AggregateShare, Aggregator as VdafAggregator, Collector as VdafCollector,
             OutputShare, PrepareTransition,
@@ -213,6 +240,112 @@ mod test {
 
     async_test_versions! { roundtrip_report }
 
+    /// Shard `measurement` with `client_vdaf`, then initialize the Leader's share with the task's
+    /// VDAF config.
+    async fn initialize_with_client_vdaf(
+        t: &AggregationJobTest,
+        client_vdaf: &VdafConfig,
+        measurement: DapMeasurement,
+    ) -> EarlyReportStateInitialized {
+        let report = client_vdaf
+            .produce_report(
+                &t.client_hpke_config_list,
+                t.now,
+                &t.task_id,
+                measurement,
+                t.task_config.version,
+            )
+            .unwrap();
+        let [leader_share, _] = report.encrypted_input_shares;
+
+        let early_report_state_consumed = EarlyReportStateConsumed::consume(
+            &t.leader_hpke_receiver_config,
+            true, // is_leader
+            &t.task_id,
+            &t.task_config,
+            ReportState {
+                metadata: report.report_metadata,
+                public_share: report.public_share,
+                draft_latest_prep_init_payload: None,
+            },
+            leader_share,
+        )
+        .await
+        .unwrap();
+        EarlyReportStateInitialized::initialize(
+            true,
+            &t.task_config.vdaf_verify_key,
+            &t.task_config.vdaf,
+            &DapAggregationParam::Empty,
+            early_report_state_consumed,
+        )
+        .unwrap()
+    }
+
+    async fn initialize_rejects_malformed_sum_vec(version: DapVersion) {
+        let t = AggregationJobTest::new(
+            &VdafConfig::Prio3(Prio3Config::SumVec {
+                bits: 1,
+                length: 4,
+                chunk_length: 2,
+            }),
+            HpkeKemId::X25519HkdfSha256,
+            version,
+        );
+
+        // The Client's measurement has one more element than the task expects.
+        let initialized = initialize_with_client_vdaf(
+            &t,
+            &VdafConfig::Prio3(Prio3Config::SumVec {
+                bits: 1,
+                length: 5,
+                chunk_length: 2,
+            }),
+            DapMeasurement::U128Vec(vec![1; 5]),
+        )
+        .await;
+        assert!(matches!(
+            initialized,
+            EarlyReportStateInitialized::Rejected {
+                failure: TransitionFailure::VdafPrepError,
+                ..
+            }
+        ));
+    }
+
+    async_test_versions! { initialize_rejects_malformed_sum_vec }
+
+    async fn initialize_rejects_malformed_histogram(version: DapVersion) {
+        let t = AggregationJobTest::new(
+            &VdafConfig::Prio3(Prio3Config::Histogram {
+                length: 4,
+                chunk_length: 2,
+            }),
+            HpkeKemId::X25519HkdfSha256,
+            version,
+        );
+
+        // The Client's histogram has fewer buckets than the task expects.
+        let initialized = initialize_with_client_vdaf(
+            &t,
+            &VdafConfig::Prio3(Prio3Config::Histogram {
+                length: 3,
+                chunk_length: 2,
+            }),
+            DapMeasurement::U64(2),
+        )
+        .await;
+        assert!(matches!(
+            initialized,
+            EarlyReportStateInitialized::Rejected {
+                failure: TransitionFailure::VdafPrepError,
+                ..
+            }
+        ));
+    }
+
+    async_test_versions! { initialize_rejects_malformed_histogram }
+
     fn roundtrip_report_unsupported_hpke_suite(version: DapVersion) {
         let t = AggregationJobTest::new(TEST_VDAF, HpkeKemId::X25519HkdfSha256, version);
 
@@ -349,6 +482,34 @@ mod test {
 
     async_test_versions! { produce_agg_job_init_req_skip_vdaf_prep_error }
 
+    async fn produce_agg_job_init_req_skip_replayed_report(version: DapVersion) {
+        let t = AggregationJobTest::new(TEST_VDAF, HpkeKemId::X25519HkdfSha256, version);
+        let report_id = ReportId(thread_rng().gen());
+        let reports = t.produce_reports_with_ids(vec![
+            (report_id, DapMeasurement::U64(1)),
+            (report_id, DapMeasurement::U64(0)),
+        ]);
+        let [first, second] = <[Report; 2]>::try_from(reports).unwrap();
+
+        t.produce_agg_job_init_req(&DapAggregationParam::Empty, vec![first])
+            .await
+            .unwrap_continued();
+
+        // The second report reuses the first report's ID, so it is rejected as a replay.
+        assert_eq!(
+            t.produce_agg_job_init_req(&DapAggregationParam::Empty, vec![second])
+                .await
+                .unwrap_finished()
+                .report_count(),
+            0
+        );
+        assert_metrics_include!(t.leader_registry, {
+            r#"report_counter{env="test_leader",host="leader.com",status="rejected_report_replayed"}"#: 1,
+        });
+    }
+
+    async_test_versions! { produce_agg_job_init_req_skip_replayed_report }
+
     async fn handle_agg_job_init_req_hpke_decrypt_err(version: DapVersion) {
         let t = AggregationJobTest::new(TEST_VDAF, HpkeKemId::X25519HkdfSha256, version);
         let mut reports = t.produce_reports(vec![DapMeasurement::U64(1)]);
@@ -839,6 +1000,464 @@ mod test {
 
     async_test_versions! { encrypted_agg_share }
 
+    /// The error type of a sink that writes the aggregate result to disk.
+    #[derive(Debug)]
+    enum SinkError {
+        Io(std::io::Error),
+        #[allow(dead_code)]
+        Dap(DapError),
+    }
+
+    impl From<DapError> for SinkError {
+        fn from(e: DapError) -> Self {
+            Self::Dap(e)
+        }
+    }
+
+    async fn check_encrypted_agg_share_into_sink(
+        version: DapVersion,
+        vdaf: VdafConfig,
+        leader_data: VdafAggregateShare,
+        helper_data: VdafAggregateShare,
+    ) {
+        let t = AggregationJobTest::new(&vdaf, HpkeKemId::X25519HkdfSha256, version);
+        let agg_share = |data| DapAggregateShare {
+            report_count: 50,
+            min_time: 1_637_359_200,
+            max_time: 1_637_359_200,
+            checksum: [0; 32],
+            data: Some(data),
+        };
+        let batch_selector = BatchSelector::TimeInterval {
+            batch_interval: Interval {
+                start: 1_637_359_200,
+                duration: 7200,
+            },
+        };
+        let encrypted_agg_shares = vec![
+            t.produce_leader_encrypted_agg_share(
+                &batch_selector,
+                &DapAggregationParam::Empty,
+                &agg_share(leader_data),
+            ),
+            t.produce_helper_encrypted_agg_share(
+                &batch_selector,
+                &DapAggregationParam::Empty,
+                &agg_share(helper_data),
+            ),
+        ];
+
+        let materialized: Vec<u128> = match t
+            .consume_encrypted_agg_shares(
+                &batch_selector,
+                50,
+                &DapAggregationParam::Empty,
+                encrypted_agg_shares.clone(),
+            )
+            .await
+        {
+            DapAggregateResult::U64(x) => vec![x.into()],
+            DapAggregateResult::U128(x) => vec![x],
+            DapAggregateResult::U32Vec(v) => v.into_iter().map(u128::from).collect(),
+            DapAggregateResult::U64Vec(v) => v.into_iter().map(u128::from).collect(),
+            DapAggregateResult::U128Vec(v) => v,
+        };
+
+        let mut streamed = Vec::new();
+        vdaf.consume_encrypted_agg_shares_into(
+            &t.collector_hpke_receiver_config,
+            &t.task_id,
+            &batch_selector,
+            &DapAggregationParam::Empty,
+            encrypted_agg_shares.clone(),
+            version,
+            |i, x| {
+                streamed.push((i, x));
+                Ok::<_, DapError>(())
+            },
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(
+            streamed,
+            materialized.into_iter().enumerate().collect::<Vec<_>>()
+        );
+
+        // An error from the sink stops the stream and is returned to the caller.
+        let mut calls = 0;
+        let res = vdaf
+            .consume_encrypted_agg_shares_into(
+                &t.collector_hpke_receiver_config,
+                &t.task_id,
+                &batch_selector,
+                &DapAggregationParam::Empty,
+                encrypted_agg_shares,
+                version,
+                |_, _| {
+                    calls += 1;
+                    Err(SinkError::Io(std::io::Error::other("disk full")))
+                },
+            )
+            .await;
+        assert_matches!(res, Err(SinkError::Io(e)) if e.to_string() == "disk full");
+        assert_eq!(calls, 1);
+    }
+
+    async fn encrypted_agg_share_into_sink(version: DapVersion) {
+        let field64 = |data: Vec<u64>| {
+            VdafAggregateShare::Field64(AggregateShare::from(OutputShare::from(
+                data.into_iter().map(Field64::from).collect::<Vec<_>>(),
+            )))
+        };
+        let field128 = |data: Vec<u128>| {
+            VdafAggregateShare::Field128(AggregateShare::from(OutputShare::from(
+                data.into_iter().map(Field128::from).collect::<Vec<_>>(),
+            )))
+        };
+        let field_prio2 = |data: Vec<u32>| {
+            VdafAggregateShare::FieldPrio2(AggregateShare::from(OutputShare::from(
+                data.into_iter().map(FieldPrio2::from).collect::<Vec<_>>(),
+            )))
+        };
+
+        check_encrypted_agg_share_into_sink(
+            version,
+            VdafConfig::Prio3(Prio3Config::Count),
+            field64(vec![23]),
+            field64(vec![9]),
+        )
+        .await;
+
+        check_encrypted_agg_share_into_sink(
+            version,
+            VdafConfig::Prio3(Prio3Config::Sum { bits: 8 }),
+            field128(vec![1337]),
+            field128(vec![42]),
+        )
+        .await;
+
+        check_encrypted_agg_share_into_sink(
+            version,
+            VdafConfig::Prio3(Prio3Config::Histogram {
+                length: 4,
+                chunk_length: 2,
+            }),
+            field128(vec![1, 2, 3, 4]),
+            field128(vec![10, 0, 5, 1]),
+        )
+        .await;
+
+        check_encrypted_agg_share_into_sink(
+            version,
+            VdafConfig::Prio3(Prio3Config::SumVec {
+                bits: 8,
+                length: 100,
+                chunk_length: 10,
+            }),
+            field128((0..100).collect()),
+            field128((0..100).map(|x| 3 * x).collect()),
+        )
+        .await;
+
+        check_encrypted_agg_share_into_sink(
+            version,
+            VdafConfig::Prio3(Prio3Config::SumVecField64MultiproofHmacSha256Aes128 {
+                bits: 8,
+                length: 100,
+                chunk_length: 10,
+                num_proofs: 2,
+            }),
+            field64((0..100).collect()),
+            field64((0..100).map(|x| 3 * x).collect()),
+        )
+        .await;
+
+        check_encrypted_agg_share_into_sink(
+            version,
+            VdafConfig::Prio2 { dimension: 100 },
+            field_prio2((0..100).collect()),
+            field_prio2((0..100).map(|x| 3 * x).collect()),
+        )
+        .await;
+    }
+
+    async_test_versions! { encrypted_agg_share_into_sink }
+
+    async fn encrypted_agg_share_tampered(version: DapVersion) {
+        let t = AggregationJobTest::new(TEST_VDAF, HpkeKemId::X25519HkdfSha256, version);
+        let agg_share = DapAggregateShare {
+            report_count: 50,
+            min_time: 1_637_359_200,
+            max_time: 1_637_359_200,
+            checksum: [0; 32],
+            data: Some(VdafAggregateShare::Field64(AggregateShare::from(
+                OutputShare::from(vec![Field64::from(23)]),
+            ))),
+        };
+        let batch_selector = BatchSelector::TimeInterval {
+            batch_interval: Interval {
+                start: 1_637_359_200,
+                duration: 7200,
+            },
+        };
+        let encrypted_agg_shares = vec![
+            t.produce_leader_encrypted_agg_share(
+                &batch_selector,
+                &DapAggregationParam::Empty,
+                &agg_share,
+            ),
+            t.produce_helper_encrypted_agg_share(
+                &batch_selector,
+                &DapAggregationParam::Empty,
+                &agg_share,
+            ),
+        ];
+        let consume = |encrypted_agg_shares| {
+            t.task_config.vdaf.consume_encrypted_agg_shares(
+                &t.collector_hpke_receiver_config,
+                &t.task_id,
+                &batch_selector,
+                50,
+                &DapAggregationParam::Empty,
+                encrypted_agg_shares,
+                version,
+            )
+        };
+
+        // Aggregate share encrypted under the wrong HPKE config.
+        let mut tampered = encrypted_agg_shares.clone();
+        tampered[1].config_id = tampered[1].config_id.wrapping_add(1);
+        assert_matches!(
+            consume(tampered).await.unwrap_err(),
+            DapError::Collect(CollectError::UnexpectedHpkeConfigId { config_id })
+                if config_id == t.collector_hpke_receiver_config.config.id.wrapping_add(1)
+        );
+
+        // Aggregate share with a corrupted payload.
+        let mut tampered = encrypted_agg_shares;
+        tampered[0].payload[0] ^= 1;
+        assert_matches!(
+            consume(tampered).await.unwrap_err(),
+            DapError::Collect(CollectError::HpkeDecryptError)
+        );
+    }
+
+    async_test_versions! { encrypted_agg_share_tampered }
+
+    async fn encrypted_agg_share_wrong_batch_selector(version: DapVersion) {
+        let t = AggregationJobTest::new(TEST_VDAF, HpkeKemId::X25519HkdfSha256, version);
+        let agg_share = DapAggregateShare {
+            report_count: 50,
+            min_time: 1_637_359_200,
+            max_time: 1_637_359_200,
+            checksum: [0; 32],
+            data: Some(VdafAggregateShare::Field64(AggregateShare::from(
+                OutputShare::from(vec![Field64::from(23)]),
+            ))),
+        };
+        let batch_selector = BatchSelector::TimeInterval {
+            batch_interval: Interval {
+                start: 1_637_359_200,
+                duration: 7200,
+            },
+        };
+        let encrypted_agg_shares = vec![
+            t.produce_leader_encrypted_agg_share(
+                &batch_selector,
+                &DapAggregationParam::Empty,
+                &agg_share,
+            ),
+            t.produce_helper_encrypted_agg_share(
+                &batch_selector,
+                &DapAggregationParam::Empty,
+                &agg_share,
+            ),
+        ];
+
+        // The shares are bound to the batch they were produced for.
+        let other_batch_selector = BatchSelector::TimeInterval {
+            batch_interval: Interval {
+                start: 1_637_359_200 + 7200,
+                duration: 7200,
+            },
+        };
+        assert_matches!(
+            t.task_config
+                .vdaf
+                .consume_encrypted_agg_shares(
+                    &t.collector_hpke_receiver_config,
+                    &t.task_id,
+                    &other_batch_selector,
+                    50,
+                    &DapAggregationParam::Empty,
+                    encrypted_agg_shares,
+                    version,
+                )
+                .await
+                .unwrap_err(),
+            DapError::Collect(CollectError::HpkeDecryptError)
+        );
+    }
+
+    async_test_versions! { encrypted_agg_share_wrong_batch_selector }
+
+    async fn encrypted_agg_share_out_of_order(version: DapVersion) {
+        let t = AggregationJobTest::new(TEST_VDAF, HpkeKemId::X25519HkdfSha256, version);
+        let agg_share = |value| DapAggregateShare {
+            report_count: 50,
+            min_time: 1_637_359_200,
+            max_time: 1_637_359_200,
+            checksum: [0; 32],
+            data: Some(VdafAggregateShare::Field64(AggregateShare::from(
+                OutputShare::from(vec![Field64::from(value)]),
+            ))),
+        };
+        let batch_selector = BatchSelector::TimeInterval {
+            batch_interval: Interval {
+                start: 1_637_359_200,
+                duration: 7200,
+            },
+        };
+        let leader_encrypted_agg_share = t.produce_leader_encrypted_agg_share(
+            &batch_selector,
+            &DapAggregationParam::Empty,
+            &agg_share(23),
+        );
+        let helper_encrypted_agg_share = t.produce_helper_encrypted_agg_share(
+            &batch_selector,
+            &DapAggregationParam::Empty,
+            &agg_share(9),
+        );
+        let consume = |encrypted_agg_shares| {
+            t.task_config.vdaf.consume_encrypted_agg_shares(
+                &t.collector_hpke_receiver_config,
+                &t.task_id,
+                &batch_selector,
+                50,
+                &DapAggregationParam::Empty,
+                encrypted_agg_shares,
+                version,
+            )
+        };
+
+        // The Helper's share arrives first.
+        assert_eq!(
+            consume(vec![
+                helper_encrypted_agg_share.clone(),
+                leader_encrypted_agg_share.clone(),
+            ])
+            .await
+            .unwrap(),
+            DapAggregateResult::U64(32)
+        );
+
+        // Both shares are from the same Aggregator.
+        assert_matches!(
+            consume(vec![
+                helper_encrypted_agg_share.clone(),
+                helper_encrypted_agg_share.clone(),
+            ])
+            .await
+            .unwrap_err(),
+            DapError::Collect(CollectError::DuplicateAggShare {
+                sender: DapSender::Helper
+            })
+        );
+
+        // The Leader's share is missing.
+        assert_matches!(
+            consume(vec![helper_encrypted_agg_share]).await.unwrap_err(),
+            DapError::Collect(CollectError::MissingAggShare {
+                sender: DapSender::Leader
+            })
+        );
+    }
+
+    async_test_versions! { encrypted_agg_share_out_of_order }
+
+    async fn encrypted_agg_share_rotated_collector_config(version: DapVersion) {
+        let mut t = AggregationJobTest::new(TEST_VDAF, HpkeKemId::X25519HkdfSha256, version);
+        let rotated_config_id = t.collector_hpke_receiver_config.config.id.wrapping_add(1);
+        let rotated_collector_hpke_receiver_config =
+            HpkeReceiverConfig::gen(rotated_config_id, HpkeKemId::X25519HkdfSha256).unwrap();
+        t.task_config
+            .collector_hpke_config_allowlist
+            .push(rotated_collector_hpke_receiver_config.config.clone());
+
+        let agg_share = |value| DapAggregateShare {
+            report_count: 50,
+            min_time: 1_637_359_200,
+            max_time: 1_637_359_200,
+            checksum: [0; 32],
+            data: Some(VdafAggregateShare::Field64(AggregateShare::from(
+                OutputShare::from(vec![Field64::from(value)]),
+            ))),
+        };
+        let batch_selector = BatchSelector::TimeInterval {
+            batch_interval: Interval {
+                start: 1_637_359_200,
+                duration: 7200,
+            },
+        };
+        let leader_encrypted_agg_share = t
+            .task_config
+            .produce_leader_encrypted_agg_share(
+                Some(rotated_config_id),
+                &t.task_id,
+                &batch_selector,
+                &DapAggregationParam::Empty,
+                &agg_share(23),
+                version,
+            )
+            .unwrap();
+        let helper_encrypted_agg_share = t
+            .task_config
+            .produce_helper_encrypted_agg_share(
+                Some(rotated_config_id),
+                &t.task_id,
+                &batch_selector,
+                &DapAggregationParam::Empty,
+                &agg_share(9),
+                version,
+            )
+            .unwrap();
+        assert_eq!(leader_encrypted_agg_share.config_id, rotated_config_id);
+
+        let agg_res = t
+            .task_config
+            .vdaf
+            .consume_encrypted_agg_shares(
+                &rotated_collector_hpke_receiver_config,
+                &t.task_id,
+                &batch_selector,
+                50,
+                &DapAggregationParam::Empty,
+                vec![leader_encrypted_agg_share, helper_encrypted_agg_share],
+                version,
+            )
+            .await
+            .unwrap();
+        assert_eq!(agg_res, DapAggregateResult::U64(32));
+
+        // A config that isn't accepted for the task can't be selected.
+        assert_matches!(
+            t.task_config
+                .produce_leader_encrypted_agg_share(
+                    Some(rotated_config_id.wrapping_add(1)),
+                    &t.task_id,
+                    &batch_selector,
+                    &DapAggregationParam::Empty,
+                    &agg_share(23),
+                    version,
+                )
+                .unwrap_err(),
+            DapError::Abort(DapAbort::OutdatedConfig { .. })
+        );
+    }
+
+    async_test_versions! { encrypted_agg_share_rotated_collector_config }
+
     #[tokio::test]
     async fn helper_state_serialization_draft02() {
         let t =
@@ -866,6 +1485,54 @@ mod test {
         assert!(DapAggregationJobState::get_decoded(TEST_VDAF, b"invalid helper state").is_err());
     }
 
+    async fn leader_state_resume_after_restart(version: DapVersion) {
+        let t = AggregationJobTest::new(TEST_VDAF, HpkeKemId::X25519HkdfSha256, version);
+        let reports = t.produce_reports(vec![
+            DapMeasurement::U64(1),
+            DapMeasurement::U64(0),
+            DapMeasurement::U64(1),
+        ]);
+        let (leader_state, agg_job_init_req) = t
+            .produce_agg_job_init_req(&DapAggregationParam::Empty, reports)
+            .await
+            .unwrap_continued();
+
+        if version == DapVersion::Draft02 {
+            // The Leader's state includes its prep shares, which are never encoded.
+            assert!(leader_state.get_encoded().is_err());
+            return;
+        }
+
+        // The Leader stores its state and restarts before handling the Helper's response.
+        let encoded_leader_state = leader_state.get_encoded().unwrap();
+        let agg_job_resp = t
+            .handle_agg_job_init_req(agg_job_init_req)
+            .await
+            .into_message();
+        let want = t
+            .handle_agg_job_resp(leader_state, agg_job_resp.clone())
+            .unwrap_finished()
+            .collapsed();
+
+        let resumed_leader_state =
+            DapAggregationJobState::get_decoded_for_leader(TEST_VDAF, &encoded_leader_state)
+                .unwrap();
+        let got = t
+            .handle_agg_job_resp(resumed_leader_state, agg_job_resp)
+            .unwrap_finished()
+            .collapsed();
+
+        assert_eq!(got.report_count, 3);
+        assert_eq!(got.report_count, want.report_count);
+        assert_eq!(got.checksum, want.checksum);
+        assert_eq!(
+            got.data.unwrap().get_encoded().unwrap(),
+            want.data.unwrap().get_encoded().unwrap()
+        );
+    }
+
+    async_test_versions! { leader_state_resume_after_restart }
+
     async fn handle_unrecognized_report_extensions(version: DapVersion) {
         let t = AggregationJobTest::new(TEST_VDAF, HpkeKemId::X25519HkdfSha256, version);
         let report = t
@@ -962,6 +1629,54 @@ mod test {
 
     async_test_versions! { handle_repeated_report_extensions }
 
+    async fn handle_mismatched_taskprov_report_extension(version: DapVersion) {
+        let mut t = AggregationJobTest::new(TEST_VDAF, HpkeKemId::X25519HkdfSha256, version);
+        let advertised_task_config = b"advertised task config".to_vec();
+        t.task_id = compute_task_id(version, &advertised_task_config);
+        t.task_config.method = DapTaskConfigMethod::Taskprov { info: None };
+
+        let consume = |draft02_payload: Vec<u8>| {
+            let report = t
+                .task_config
+                .vdaf
+                .produce_report_with_extensions(
+                    &t.client_hpke_config_list,
+                    t.now,
+                    &t.task_id,
+                    DapMeasurement::U64(1),
+                    vec![Extension::Taskprov {
+                        draft02_payload: Some(draft02_payload),
+                    }],
+                    version,
+                )
+                .unwrap();
+            let [leader_share, _] = report.encrypted_input_shares;
+            EarlyReportStateConsumed::consume(
+                &t.leader_hpke_receiver_config,
+                true,
+                &t.task_id,
+                &t.task_config,
+                ReportState {
+                    metadata: report.report_metadata,
+                    public_share: report.public_share,
+                    draft_latest_prep_init_payload: None,
+                },
+                leader_share,
+            )
+        };
+
+        assert!(consume(advertised_task_config).await.unwrap().is_ready());
+        let EarlyReportStateConsumed::Rejected { failure, .. } =
+            consume(b"some other task config".to_vec()).await.unwrap()
+        else {
+            panic!("expected report to be rejected");
+        };
+        assert_eq!(failure, TransitionFailure::ReportDropped);
+    }
+
+    // The taskprov extension only carries the task configuration in draft02.
+    async_test_version! { handle_mismatched_taskprov_report_extension, Draft02 }
+
     impl AggregationJobTest {
         // Tweak the Helper's share so that decoding succeeds but preparation fails.
         fn produce_invalid_report_vdaf_prep_failure(
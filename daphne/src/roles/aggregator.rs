@@ -1,7 +1,7 @@
 // Copyright (c) 2023 Cloudflare, Inc. All rights reserved.
 // SPDX-License-Identifier: BSD-3-Clause
 
-use std::collections::HashSet;
+use std::{collections::HashSet, ops::RangeInclusive};
 
 use async_trait::async_trait;
 use prio::codec::Encode;
@@ -16,8 +16,8 @@ use crate::{
     },
     metrics::{DaphneMetrics, DaphneRequestType},
     protocol::aggregator::{EarlyReportStateConsumed, EarlyReportStateInitialized},
-    DapAggregateShare, DapAggregateSpan, DapAggregationParam, DapError, DapGlobalConfig,
-    DapRequest, DapResponse, DapTaskConfig, DapVersion,
+    DapAggregateShare, DapAggregateSpan, DapAggregationParam, DapBatchBucket, DapError,
+    DapGlobalConfig, DapRequest, DapResponse, DapTaskConfig, DapVersion,
 };
 
 /// Report initializer. Used by a DAP Aggregator [`DapAggregator`] when initializing an aggregation
@@ -44,6 +44,21 @@ pub enum MergeAggShareError {
     Other(DapError),
 }
 
+/// Summary of the buckets a collection of a batch would touch. See
+/// [`DapAggregator::preview_collection`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CollectionPreview {
+    /// Number of buckets spanned by the batch.
+    pub bucket_count: usize,
+
+    /// Number of reports aggregated into the buckets so far. Reports that are pending
+    /// aggregation are not counted.
+    pub report_count: u64,
+
+    /// Whether any of the buckets has already been collected.
+    pub any_collected: bool,
+}
+
 /// DAP Aggregator functionality.
 #[async_trait]
 pub trait DapAggregator<S: Sync>: HpkeDecrypter + DapReportInitializer + Sized {
@@ -93,6 +108,18 @@ pub trait DapAggregator<S: Sync>: HpkeDecrypter + DapReportInitializer + Sized {
         task_config: DapTaskConfig,
     ) -> Result<(), DapError>;
 
+    /// taskprov: Replace the configuration of a task that was re-advertised with updated
+    /// parameters. The swap is atomic, i.e., a concurrent request sees either the old or the new
+    /// config, and the new config is checked against the config it replaces. If the new config is not compatible with the current one (see
+    /// [`DapTaskConfig::check_compatible_update`]), then the current config is kept and
+    /// [`DapAbort::TaskprovMismatch`] is returned. If the task is not configured yet, then the new
+    /// config is stored as is.
+    async fn replace_task_config_if_compatible(
+        &self,
+        task_id: &TaskId,
+        task_config: DapTaskConfig,
+    ) -> Result<(), DapError>;
+
     /// Look up the DAP task configuration for the given task ID.
     async fn get_task_config_for<'req>(
         &'req self,
@@ -102,19 +129,51 @@ pub trait DapAggregator<S: Sync>: HpkeDecrypter + DapReportInitializer + Sized {
     /// Get the current time (number of seconds since the beginning of UNIX time).
     fn get_current_time(&self) -> Time;
 
-    /// Check whether the batch determined by the collect request would overlap with a previously
-    /// collected batch.
-    async fn is_batch_overlapping(
+    /// The range of report timestamps that are currently accepted, e.g., as determined by how long
+    /// reports are stored and how far the Client's clock may be ahead. Reports with a timestamp
+    /// before this range are too late and reports with a timestamp after it are too early. By
+    /// default, reports are accepted regardless of their timestamp.
+    fn valid_report_time_range(&self) -> RangeInclusive<Time> {
+        Time::MIN..=Time::MAX
+    }
+
+    /// Get the buckets spanned by the batch determined by the collect request that were
+    /// previously collected under the same aggregation parameter. The same batch may be collected
+    /// more than once under distinct aggregation parameters, e.g., for Poplar1.
+    async fn get_overlapping_buckets(
         &self,
         task_id: &TaskId,
         batch_sel: &BatchSelector,
-    ) -> Result<bool, DapError>;
+        agg_param: &DapAggregationParam,
+    ) -> Result<Vec<DapBatchBucket>, DapError>;
 
     /// Check whether the given batch ID has been observed before. This is called by the Leader
     /// (resp. Helper) in response to a CollectReq (resp. AggregateShareReq) for fixed-size tasks.
     async fn batch_exists(&self, task_id: &TaskId, batch_id: &BatchId) -> Result<bool, DapError>;
 
-    /// Store a set of output shares and mark the corresponding reports as aggregated.
+    /// Check whether the given batch is ready to be collected under the given aggregation
+    /// parameter, i.e., whether none of its reports are still waiting to be aggregated. This says
+    /// nothing about the size of the batch: a batch into which no reports were aggregated is ready,
+    /// too. Unlike [`Self::get_overlapping_buckets`], this does not depend on whether the batch was
+    /// collected.
+    async fn batch_ready_for_collection(
+        &self,
+        task_id: &TaskId,
+        batch_sel: &BatchSelector,
+        agg_param: &DapAggregationParam,
+    ) -> Result<bool, DapError>;
+
+    /// Report what collecting the given batch would touch without collecting it. This lets
+    /// operators catch collections that are overly broad or empty before they are committed to.
+    async fn preview_collection(
+        &self,
+        task_id: &TaskId,
+        batch_sel: &BatchSelector,
+    ) -> Result<CollectionPreview, DapError>;
+
+    /// Store a set of output shares that were aggregated under the given aggregation parameter and
+    /// mark the corresponding reports as aggregated under it. Aggregate shares are kept separately
+    /// for each aggregation parameter.
     ///
     /// If any report within a bucket has already been aggregated (is a replay) then that entire
     /// bucket must be skipped without changing any state, such that this operation is idempotent.
@@ -133,23 +192,40 @@ pub trait DapAggregator<S: Sync>: HpkeDecrypter + DapReportInitializer + Sized {
         &self,
         task_id: &TaskId,
         task_config: &DapTaskConfig,
+        agg_param: &DapAggregationParam,
         agg_share_span: DapAggregateSpan<DapAggregateShare>,
     ) -> DapAggregateSpan<Result<(), MergeAggShareError>>;
 
-    /// Fetch the aggregate share for the given batch.
+    /// Fetch the aggregate share for the given batch to be collected under the given aggregation
+    /// parameter. Implementations that can tell that no report in the batch has been aggregated
+    /// yet should return [`DapAbort::InvalidBatchSize`] rather than an empty aggregate share.
     async fn get_agg_share(
         &self,
         task_id: &TaskId,
         batch_sel: &BatchSelector,
+        agg_param: &DapAggregationParam,
     ) -> Result<DapAggregateShare, DapError>;
 
-    /// Mark a batch as collected.
+    /// Mark a batch as collected under the given aggregation parameter.
     async fn mark_collected(
         &self,
         task_id: &TaskId,
         batch_sel: &BatchSelector,
+        agg_param: &DapAggregationParam,
     ) -> Result<(), DapError>;
 
+    /// Get the IDs of the reports that were aggregated into the given batch, e.g., so that an
+    /// auditor can check which reports contributed to a published aggregate.
+    ///
+    /// Returns an abort if any bucket of the batch has reports aggregated into it but has not yet
+    /// been collected: revealing which reports are in a batch that is still being filled leaks
+    /// more than the aggregate ever would.
+    async fn batch_report_ids(
+        &self,
+        task_id: &TaskId,
+        batch_sel: &BatchSelector,
+    ) -> Result<Vec<ReportId>, DapError>;
+
     /// Access the Prometheus metrics.
     fn metrics(&self) -> &dyn DaphneMetrics;
 
@@ -207,6 +283,7 @@ where
     metrics.inbound_req_inc(DaphneRequestType::HpkeConfig);
     Ok(DapResponse {
         version: req.version,
+        status: 200,
         media_type: DapMediaType::HpkeConfigList,
         payload,
     })
@@ -1,11 +1,14 @@
 // Copyright (c) 2022 Cloudflare, Inc. All rights reserved.
 // SPDX-License-Identifier: BSD-3-Clause
 
-use std::{collections::HashMap, sync::Once};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Once,
+};
 
 use async_trait::async_trait;
-use prio::codec::{Encode, ParameterizedDecode};
-use tracing::error;
+use prio::codec::{CodecError, Decode, Encode, ParameterizedDecode};
+use tracing::{debug, error, field, Span};
 
 use super::{check_batch, check_request_content_type, resolve_taskprov, DapAggregator};
 use crate::{
@@ -15,15 +18,18 @@ use crate::{
     fatal_error,
     messages::{
         constant_time_eq, AggregateShare, AggregateShareReq, AggregationJobContinueReq,
-        AggregationJobInitReq, AggregationJobResp, Draft02AggregationJobId, PartialBatchSelector,
-        ReportId, TaskId, TransitionFailure, TransitionVar,
+        AggregationJobInitReq, AggregationJobResp, AggregationJobRespChunk,
+        AggregationJobRespChunkReq, BatchSelector, Draft02AggregationJobId, PartialBatchSelector,
+        ReportId, TaskId, Time, TooManyItems, TransitionFailure, TransitionVar,
     },
     metrics::{DaphneMetrics, DaphneRequestType},
     protocol::aggregator::ReportProcessedStatus,
     roles::aggregator::MergeAggShareError,
-    DapAggregateShare, DapAggregateSpan, DapAggregationJobState, DapAggregationParam, DapError,
-    DapHelperAggregationJobTransition, DapRequest, DapResource, DapResponse, DapTaskConfig,
-    DapVersion, MetaAggregationJobId,
+    vdaf::VDAF_VERSION,
+    DapAggregateShare, DapAggregateSpan, DapAggregationJobState, DapAggregationParam,
+    DapBatchBucket, DapError, DapHelperAggregationJobTransition, DapRequest, DapResource,
+    DapResponse, DapRetainedAggShare, DapTaskConfig, DapVersion, EarlyReportState,
+    MetaAggregationJobId,
 };
 
 /// DAP Helper functionality.
@@ -49,17 +55,107 @@ pub trait DapHelper<S: Sync>: DapAggregator<S> {
     ) -> Result<Option<DapAggregationJobState>, DapError>
     where
         Id: Into<MetaAggregationJobId> + Send;
+
+    /// Delete the Helper's aggregation-flow state for the given task that is no longer needed,
+    /// i.e., all of it if the task has expired and otherwise any state stored before
+    /// `older_than`.
+    async fn purge_expired_helper_state(
+        &self,
+        task_id: &TaskId,
+        older_than: Time,
+    ) -> Result<(), DapError>;
+
+    /// Associate each of the given buckets with the aggregation parameter, unless it is already
+    /// associated with one. Returns the buckets that were already associated with a different
+    /// aggregation parameter.
+    async fn check_and_set_bucket_agg_param(
+        &self,
+        task_id: &TaskId,
+        buckets: &HashSet<DapBatchBucket>,
+        agg_param: &DapAggregationParam,
+    ) -> Result<Vec<DapBatchBucket>, DapError>;
+
+    /// Store the response to an aggregation job initialization request, so that a retried request
+    /// for the same job gets the same response, and so that the Leader can fetch a response that is
+    /// too large to send at once in chunks (see [`DapGlobalConfig::agg_job_resp_chunk_size`]). Not
+    /// used in draft02.
+    ///
+    /// [`DapGlobalConfig::agg_job_resp_chunk_size`]: crate::DapGlobalConfig::agg_job_resp_chunk_size
+    async fn put_agg_job_resp(
+        &self,
+        task_id: &TaskId,
+        agg_job_id: &MetaAggregationJobId,
+        agg_job_resp: &AggregationJobResp,
+    ) -> Result<(), DapError>;
+
+    /// Fetch the aggregation job response stored for the given task and aggregation job. `None` is
+    /// returned if the job is unknown or its response has been deleted.
+    async fn get_agg_job_resp(
+        &self,
+        task_id: &TaskId,
+        agg_job_id: &MetaAggregationJobId,
+    ) -> Result<Option<AggregationJobResp>, DapError>;
+
+    /// Delete the aggregation job response stored for the given task and aggregation job. This is
+    /// called once the last chunk of a chunked response has been served.
+    async fn delete_agg_job_resp(
+        &self,
+        task_id: &TaskId,
+        agg_job_id: &MetaAggregationJobId,
+    ) -> Result<(), DapError>;
+
+    /// Retain the aggregate share produced for an aggregate share request with the given
+    /// idempotency token and aggregation parameter. The aggregate share may be deleted once it
+    /// expires.
+    async fn put_retained_agg_share(
+        &self,
+        task_id: &TaskId,
+        idempotency_token: &str,
+        agg_param: &DapAggregationParam,
+        retained_agg_share: &DapRetainedAggShare,
+    ) -> Result<(), DapError>;
+
+    /// Fetch the aggregate share retained for the given idempotency token and aggregation
+    /// parameter. `None` is returned if no aggregate share was retained. The caller is responsible
+    /// for checking whether the retained aggregate share has expired.
+    async fn get_retained_agg_share(
+        &self,
+        task_id: &TaskId,
+        idempotency_token: &str,
+        agg_param: &DapAggregationParam,
+    ) -> Result<Option<DapRetainedAggShare>, DapError>;
 }
 
 pub async fn handle_agg_job_init_req<'req, S: Sync, A: DapHelper<S>>(
     aggregator: &A,
     req: &'req DapRequest<S>,
+) -> Result<DapResponse, DapError> {
+    handle_agg_job_init_req_with_decryption_progress(aggregator, req, |_processed, _total| ()).await
+}
+
+/// Like [`handle_agg_job_init_req`], except that `decryption_progress` is called with the number
+/// of report shares decrypted so far and the total number of report shares in the job as each
+/// report share is decrypted. The report shares are prepared only once all of them have been
+/// decrypted, so the callback reaching the total does not mean the job is nearly done. The
+/// callback has no effect on the result.
+pub async fn handle_agg_job_init_req_with_decryption_progress<S: Sync, A: DapHelper<S>>(
+    aggregator: &A,
+    req: &DapRequest<S>,
+    decryption_progress: impl FnMut(usize, usize) + Send,
 ) -> Result<DapResponse, DapError> {
     let task_id = req.task_id()?;
     let metrics = aggregator.metrics();
-    let agg_job_init_req =
-        AggregationJobInitReq::get_decoded_with_param(&req.version, &req.payload)
-            .map_err(|e| DapAbort::from_codec_error(e, *task_id))?;
+    let agg_job_init_req = AggregationJobInitReq::get_decoded_for_version(
+        req.version,
+        aggregator.get_global_config().max_report_shares_per_agg_job,
+        &req.payload,
+    )
+    .map_err(|e| match e {
+        CodecError::Other(e) if e.is::<TooManyItems>() => DapAbort::BadRequest(format!(
+            "AggregationJobInitReq contained too many report shares: {e}"
+        )),
+        e => DapAbort::from_codec_error(e, *task_id),
+    })?;
 
     metrics.agg_job_observe_batch_size(agg_job_init_req.prep_inits.len());
 
@@ -122,12 +218,24 @@ pub async fn handle_agg_job_init_req<'req, S: Sync, A: DapHelper<S>>(
     }
 
     let agg_job_id = resolve_agg_job_id(req, agg_job_init_req.draft02_agg_job_id.as_ref())?;
+    Span::current().record("agg_job_id", field::display(agg_job_id));
 
     // Check whether the DAP version in the request matches the task config.
     if task_config.version != req.version {
         return Err(DapAbort::version_mismatch(req.version, task_config.version).into());
     }
 
+    // Check that the Leader's VDAF implementation is compatible with ours. Leaders that don't
+    // indicate their version, e.g., those of other DAP implementations, are given the benefit of
+    // the doubt.
+    if let Some(vdaf_version) = req.vdaf_version.as_deref() {
+        if vdaf_version != VDAF_VERSION {
+            return Err(
+                DapAbort::vdaf_version_mismatch(task_id, vdaf_version, VDAF_VERSION).into(),
+            );
+        }
+    }
+
     // Ensure we know which batch the request pertains to.
     check_part_batch(
         task_id,
@@ -136,12 +244,84 @@ pub async fn handle_agg_job_init_req<'req, S: Sync, A: DapHelper<S>>(
         &agg_job_init_req.agg_param,
     )?;
 
+    // The Leader reuses the job's ID when it retries a job, e.g., after a transport error. If we
+    // already finished the job, then respond as we did the first time: handling the reports again
+    // would only reject them as replays. (draft02 jobs are retried under a new ID.)
+    if req.version != DapVersion::Draft02 {
+        if let Some(agg_job_resp) = aggregator.get_agg_job_resp(task_id, &agg_job_id).await? {
+            debug!("responding to repeated aggregation job initialization request");
+            return produce_agg_job_resp(aggregator, req.version, &agg_job_resp);
+        }
+    }
+
     let prep_init_count = agg_job_init_req.prep_inits.len();
+    debug!("initializing aggregation job with {prep_init_count} reports");
     let part_batch_sel = agg_job_init_req.part_batch_sel.clone();
-    let initialized_reports = task_config
-        .helper_initialize_reports(aggregator, aggregator, task_id, agg_job_init_req)
+    let agg_param = DapAggregationParam::decode_for_task(
+        task_id,
+        &task_config.vdaf,
+        &agg_job_init_req.agg_param,
+    )?;
+    let mut initialized_reports = task_config
+        .helper_initialize_reports(
+            aggregator,
+            aggregator,
+            task_id,
+            agg_job_init_req,
+            metrics,
+            aggregator.get_global_config(),
+            decryption_progress,
+        )
         .await?;
 
+    // If the VDAF aggregates each batch under a single aggregation parameter, then the parameter
+    // is fixed once the first aggregation job for the batch arrives. Reject the job if it uses a
+    // different one for any of the batch's buckets.
+    if task_config.vdaf.is_agg_param_fixed_per_batch() {
+        let buckets = initialized_reports
+            .iter()
+            .filter(|report| report.is_ready())
+            .map(|report| task_config.bucket_for(&part_batch_sel, report))
+            .collect::<HashSet<_>>();
+        let mut mismatched = aggregator
+            .check_and_set_bucket_agg_param(task_id, &buckets, &agg_param)
+            .await?;
+        if !mismatched.is_empty() {
+            mismatched.sort_by_key(ToString::to_string);
+            return Err(DapAbort::AggregationParameterMismatch {
+                detail: format!(
+                    "The aggregation parameter differs from earlier aggregation jobs for {}.",
+                    mismatched
+                        .iter()
+                        .map(ToString::to_string)
+                        .collect::<Vec<_>>()
+                        .join(", ")
+                ),
+                task_id: *task_id,
+            }
+            .into());
+        }
+    }
+
+    // Reject the reports if they pertain to a batch that has already been collected.
+    if let PartialBatchSelector::FixedSizeByBatchId { batch_id } = &part_batch_sel {
+        let batch_sel = BatchSelector::FixedSizeByBatchId {
+            batch_id: *batch_id,
+        };
+        if !aggregator
+            .get_overlapping_buckets(task_id, &batch_sel, &agg_param)
+            .await?
+            .is_empty()
+        {
+            for report in &mut initialized_reports {
+                if report.is_ready() {
+                    metrics.report_rejection_inc_by(TransitionFailure::BatchCollected, 1);
+                }
+                report.reject_due_to(TransitionFailure::BatchCollected);
+            }
+        }
+    }
+
     let agg_job_resp = match task_config.version {
         DapVersion::Draft02 => {
             let DapHelperAggregationJobTransition::Continued(state, agg_job_resp) = task_config
@@ -175,6 +355,7 @@ pub async fn handle_agg_job_init_req<'req, S: Sync, A: DapHelper<S>>(
                 aggregator,
                 task_id,
                 task_config,
+                &agg_param,
                 metrics,
                 |report_status| {
                     let DapHelperAggregationJobTransition::Finished(agg_span, agg_job_resp) =
@@ -193,6 +374,10 @@ pub async fn handle_agg_job_init_req<'req, S: Sync, A: DapHelper<S>>(
             )
             .await?;
 
+            aggregator
+                .put_agg_job_resp(task_id, &agg_job_id, &agg_job_resp)
+                .await?;
+
             metrics.agg_job_started_inc();
             metrics.agg_job_completed_inc();
             agg_job_resp
@@ -208,10 +393,134 @@ pub async fn handle_agg_job_init_req<'req, S: Sync, A: DapHelper<S>>(
     );
 
     metrics.inbound_req_inc(DaphneRequestType::Aggregate);
+
+    produce_agg_job_resp(aggregator, req.version, &agg_job_resp)
+}
+
+/// Respond with the aggregation job response. If the response is too large, then send the first
+/// chunk; the Leader fetches the rest from the stored response.
+fn produce_agg_job_resp<S: Sync>(
+    aggregator: &impl DapHelper<S>,
+    version: DapVersion,
+    agg_job_resp: &AggregationJobResp,
+) -> Result<DapResponse, DapError> {
+    match aggregator.get_global_config().agg_job_resp_chunk_size {
+        Some(chunk_size)
+            if chunk_size > 0
+                && version == DapVersion::DraftLatest
+                && agg_job_resp.transitions.len() > chunk_size =>
+        {
+            produce_agg_job_resp_chunk(version, agg_job_resp, 0, chunk_size)
+        }
+        _ => Ok(DapResponse {
+            version,
+            status: 200,
+            media_type: DapMediaType::AggregationJobResp,
+            payload: agg_job_resp.get_encoded().map_err(DapError::encoding)?,
+        }),
+    }
+}
+
+/// Handle a request for the next chunk of an aggregation job response that was too large to send
+/// at once. This is not part of DAP.
+pub async fn handle_agg_job_resp_chunk_req<S: Sync, A: DapHelper<S>>(
+    aggregator: &A,
+    req: &DapRequest<S>,
+) -> Result<DapResponse, DapError> {
+    let task_id = req.task_id()?;
+
+    check_request_content_type(req, DapMediaType::AggregationJobRespChunkReq)?;
+
+    if aggregator.get_global_config().allow_taskprov {
+        resolve_taskprov(aggregator, task_id, req, None).await?;
+    }
+    let wrapped_task_config = aggregator
+        .get_task_config_for(task_id)
+        .await?
+        .ok_or(DapAbort::UnrecognizedTask)?;
+    let task_config = wrapped_task_config.as_ref();
+
+    if let Some(reason) = aggregator.unauthorized_reason(task_config, req).await? {
+        error!("aborted unauthorized aggregation job response chunk request: {reason}");
+        return Err(DapAbort::UnauthorizedRequest {
+            detail: reason,
+            task_id: *task_id,
+        }
+        .into());
+    }
+
+    // Check whether the DAP version in the request matches the task config.
+    if task_config.version != req.version {
+        return Err(DapAbort::version_mismatch(req.version, task_config.version).into());
+    }
+
+    if req.version == DapVersion::Draft02 {
+        return Err(DapAbort::BadRequest(
+            "chunked aggregation job responses are not supported in draft02".into(),
+        )
+        .into());
+    }
+
+    let agg_job_id = resolve_agg_job_id(req, None)?;
+    Span::current().record("agg_job_id", field::display(agg_job_id));
+    let chunk_req = AggregationJobRespChunkReq::get_decoded(&req.payload)
+        .map_err(|e| DapAbort::from_codec_error(e, *task_id))?;
+
+    let agg_job_resp = aggregator
+        .get_agg_job_resp(task_id, &agg_job_id)
+        .await?
+        .ok_or_else(|| DapAbort::UnrecognizedAggregationJob {
+            task_id: *task_id,
+            agg_job_id_base64url: agg_job_id.to_base64url(),
+        })?;
+
+    let start = usize::try_from(chunk_req.continuation_token)
+        .ok()
+        .filter(|start| *start < agg_job_resp.transitions.len())
+        .ok_or_else(|| DapAbort::BadRequest("continuation token is out of range".into()))?;
+
+    // If chunking has since been disabled, then send the rest of the response at once.
+    let chunk_size = aggregator
+        .get_global_config()
+        .agg_job_resp_chunk_size
+        .filter(|chunk_size| *chunk_size > 0)
+        .unwrap_or(usize::MAX);
+
+    let resp = produce_agg_job_resp_chunk(req.version, &agg_job_resp, start, chunk_size)?;
+
+    // The response is no longer needed once its last chunk has been served.
+    if start.saturating_add(chunk_size) >= agg_job_resp.transitions.len() {
+        aggregator.delete_agg_job_resp(task_id, &agg_job_id).await?;
+    }
+    Ok(resp)
+}
+
+/// Respond with the chunk of `agg_job_resp` that begins with the transition at index `start`.
+fn produce_agg_job_resp_chunk(
+    version: DapVersion,
+    agg_job_resp: &AggregationJobResp,
+    start: usize,
+    chunk_size: usize,
+) -> Result<DapResponse, DapError> {
+    let end = start
+        .saturating_add(chunk_size)
+        .min(agg_job_resp.transitions.len());
+    let continuation_token = if end < agg_job_resp.transitions.len() {
+        Some(u32::try_from(end).map_err(|e| fatal_error!(err = ?e))?)
+    } else {
+        None
+    };
+
     Ok(DapResponse {
-        version: req.version,
-        media_type: DapMediaType::AggregationJobResp,
-        payload: agg_job_resp.get_encoded().map_err(DapError::encoding)?,
+        version,
+        status: 200,
+        media_type: DapMediaType::AggregationJobRespChunk,
+        payload: AggregationJobRespChunk {
+            transitions: agg_job_resp.transitions[start..end].to_vec(),
+            continuation_token,
+        }
+        .get_encoded()
+        .map_err(DapError::encoding)?,
     })
 }
 
@@ -250,6 +559,7 @@ pub async fn handle_agg_job_cont_req<'req, S: Sync, A: DapHelper<S>>(
             .map_err(|e| DapAbort::from_codec_error(e, *task_id))?;
 
     let agg_job_id = resolve_agg_job_id(req, agg_job_cont_req.draft02_agg_job_id.as_ref())?;
+    Span::current().record("agg_job_id", field::display(agg_job_id));
 
     let state = aggregator
         .get_helper_state(task_id, agg_job_id)
@@ -259,8 +569,15 @@ pub async fn handle_agg_job_cont_req<'req, S: Sync, A: DapHelper<S>>(
             agg_job_id_base64url: agg_job_id.to_base64url(),
         })?;
 
-    let agg_job_resp =
-        finish_agg_job_and_aggregate(aggregator, task_id, task_config, metrics, |report_status| {
+    // Only draft02 aggregation jobs are continued, and draft02 doesn't support VDAFs with an
+    // aggregation parameter.
+    let agg_job_resp = finish_agg_job_and_aggregate(
+        aggregator,
+        task_id,
+        task_config,
+        &DapAggregationParam::Empty,
+        metrics,
+        |report_status| {
             task_config.handle_agg_job_cont_req(
                 task_id,
                 &state,
@@ -268,8 +585,9 @@ pub async fn handle_agg_job_cont_req<'req, S: Sync, A: DapHelper<S>>(
                 &agg_job_id,
                 &agg_job_cont_req,
             )
-        })
-        .await?;
+        },
+    )
+    .await?;
 
     let out_shares_count = agg_job_resp
         .transitions
@@ -290,12 +608,20 @@ pub async fn handle_agg_job_cont_req<'req, S: Sync, A: DapHelper<S>>(
     metrics.inbound_req_inc(DaphneRequestType::Aggregate);
     Ok(DapResponse {
         version: req.version,
+        status: 200,
         media_type: DapMediaType::agg_job_cont_resp_for_version(task_config.version),
         payload: agg_job_resp.get_encoded().map_err(DapError::encoding)?,
     })
 }
 
 /// Handle a request pertaining to an aggregation job.
+#[tracing::instrument(
+    skip_all,
+    fields(
+        task_id = req.task_id().ok().map(field::display),
+        agg_job_id = field::Empty,
+    )
+)]
 pub async fn handle_agg_job_req<'req, S: Sync, A: DapHelper<S>>(
     aggregator: &A,
     req: &DapRequest<S>,
@@ -303,13 +629,24 @@ pub async fn handle_agg_job_req<'req, S: Sync, A: DapHelper<S>>(
     match req.media_type {
         DapMediaType::AggregationJobInitReq => handle_agg_job_init_req(aggregator, req).await,
         DapMediaType::AggregationJobContinueReq => handle_agg_job_cont_req(aggregator, req).await,
+        DapMediaType::AggregationJobRespChunkReq => {
+            handle_agg_job_resp_chunk_req(aggregator, req).await
+        }
         //TODO spec: Specify this behavior.
-        _ => Err(DapAbort::BadRequest("unexpected media type".into()).into()),
+        m => Err(DapAbort::BadRequest(format!("unexpected media type: {m:?}")).into()),
     }
 }
 
 /// Handle a request for an aggregate share. This is called by the Leader to complete a
 /// collection job.
+#[tracing::instrument(
+    skip_all,
+    fields(
+        task_id = req.task_id().ok().map(field::display),
+        // The Leader uses the collection job ID as the idempotency token.
+        coll_job_id = req.idempotency_token.as_deref(),
+    )
+)]
 pub async fn handle_agg_share_req<'req, S: Sync, A: DapHelper<S>>(
     aggregator: &A,
     req: &DapRequest<S>,
@@ -348,8 +685,36 @@ pub async fn handle_agg_share_req<'req, S: Sync, A: DapHelper<S>>(
         .map_err(|e| DapAbort::from_codec_error(e, *task_id))?;
 
     let agg_param =
-        DapAggregationParam::get_decoded_with_param(&task_config.vdaf, &agg_share_req.agg_param)
-            .map_err(|e| DapAbort::from_codec_error(e, *task_id))?;
+        DapAggregationParam::decode_for_task(task_id, &task_config.vdaf, &agg_share_req.agg_param)?;
+
+    // If we already produced an aggregate share for this idempotency token, then the request is a
+    // retry. Respond with the same aggregate share rather than collecting the batch again.
+    if let Some(idempotency_token) = req.idempotency_token.as_deref() {
+        if let Some(retained) = aggregator
+            .get_retained_agg_share(task_id, idempotency_token, &agg_param)
+            .await?
+            .filter(|retained| retained.expiration > now)
+        {
+            if retained.batch_sel != agg_share_req.batch_sel {
+                return Err(DapAbort::BadRequest(
+                    "idempotency token was used for a different batch".into(),
+                )
+                .into());
+            }
+
+            let agg_share_resp = AggregateShare {
+                encrypted_agg_share: retained.encrypted_agg_share,
+            };
+
+            metrics.inbound_req_inc(DaphneRequestType::Collect);
+            return Ok(DapResponse {
+                version: req.version,
+                status: 200,
+                media_type: DapMediaType::AggregateShare,
+                payload: agg_share_resp.get_encoded().map_err(DapError::encoding)?,
+            });
+        }
+    }
 
     // Ensure the batch boundaries are valid and that the batch doesn't overlap with previosuly
     // collected batches.
@@ -364,7 +729,7 @@ pub async fn handle_agg_share_req<'req, S: Sync, A: DapHelper<S>>(
     .await?;
 
     let agg_share = aggregator
-        .get_agg_share(task_id, &agg_share_req.batch_sel)
+        .get_agg_share(task_id, &agg_share_req.batch_sel, &agg_param)
         .await?;
 
     // Check that we have aggreagted the same set of reports as the Leader.
@@ -389,20 +754,18 @@ pub async fn handle_agg_share_req<'req, S: Sync, A: DapHelper<S>>(
         return Err(DapAbort::InvalidBatchSize {
             detail: format!(
                 "Report count ({}) is less than minimum ({})",
-                agg_share.report_count, task_config.min_batch_size
+                agg_share.report_count,
+                task_config.effective_min_batch_size()
             ),
             task_id: *task_id,
         }
         .into());
     }
 
-    // Mark each aggregated report as collected.
-    aggregator
-        .mark_collected(task_id, &agg_share_req.batch_sel)
-        .await?;
-
+    // Encrypt the aggregate share before marking the reports as collected, so that the batch
+    // isn't consumed if the Collector's HPKE config is not accepted for the task.
     let encrypted_agg_share = task_config.produce_helper_encrypted_agg_share(
-        &task_config.collector_hpke_config,
+        req.collector_hpke_config_id,
         task_id,
         &agg_share_req.batch_sel,
         &agg_param,
@@ -410,6 +773,29 @@ pub async fn handle_agg_share_req<'req, S: Sync, A: DapHelper<S>>(
         task_config.version,
     )?;
 
+    // Mark each aggregated report as collected.
+    aggregator
+        .mark_collected(task_id, &agg_share_req.batch_sel, &agg_param)
+        .await?;
+
+    let agg_share_idempotency_window = aggregator.get_global_config().agg_share_idempotency_window;
+    if let Some(idempotency_token) = req.idempotency_token.as_deref() {
+        if agg_share_idempotency_window > 0 {
+            aggregator
+                .put_retained_agg_share(
+                    task_id,
+                    idempotency_token,
+                    &agg_param,
+                    &DapRetainedAggShare {
+                        batch_sel: agg_share_req.batch_sel.clone(),
+                        encrypted_agg_share: encrypted_agg_share.clone(),
+                        expiration: now.saturating_add(agg_share_idempotency_window),
+                    },
+                )
+                .await?;
+        }
+    }
+
     let agg_share_resp = AggregateShare {
         encrypted_agg_share,
     };
@@ -418,6 +804,7 @@ pub async fn handle_agg_share_req<'req, S: Sync, A: DapHelper<S>>(
     metrics.inbound_req_inc(DaphneRequestType::Collect);
     Ok(DapResponse {
         version: req.version,
+        status: 200,
         media_type: DapMediaType::AggregateShare,
         payload: agg_share_resp.get_encoded().map_err(DapError::encoding)?,
     })
@@ -474,6 +861,7 @@ async fn finish_agg_job_and_aggregate<S: Sync>(
     helper: &impl DapHelper<S>,
     task_id: &TaskId,
     task_config: &DapTaskConfig,
+    agg_param: &DapAggregationParam,
     metrics: &dyn DaphneMetrics,
     finish_agg_job: impl Fn(
         &HashMap<ReportId, ReportProcessedStatus>,
@@ -498,7 +886,7 @@ async fn finish_agg_job_and_aggregate<S: Sync>(
         let (agg_span, agg_job_resp) = finish_agg_job(&report_status)?;
 
         let put_shares_result = helper
-            .try_put_agg_share_span(task_id, task_config, agg_span)
+            .try_put_agg_share_span(task_id, task_config, agg_param, agg_span)
             .await;
 
         let inc_restart_metric = Once::new();
@@ -516,6 +904,10 @@ async fn finish_agg_job_and_aggregate<S: Sync>(
                 // This bucket had replays.
                 (Err(MergeAggShareError::ReplaysDetected(replays)), _reports) => {
                     // At least one report was replayed (no change to aggregate storage).
+                    metrics.report_rejection_inc_by(
+                        TransitionFailure::ReportReplayed,
+                        replays.len().try_into().expect("usize to fit in u64"),
+                    );
                     report_status.extend(replays.into_iter().map(|report_id| {
                         (
                             report_id,
@@ -526,6 +918,10 @@ async fn finish_agg_job_and_aggregate<S: Sync>(
                 }
                 // This bucket is contained by an aggregate share span that has been collected.
                 (Err(MergeAggShareError::AlreadyCollected), reports) => {
+                    metrics.report_rejection_inc_by(
+                        TransitionFailure::BatchCollected,
+                        reports.len().try_into().expect("usize to fit in u64"),
+                    );
                     report_status.extend(reports.into_iter().map(|(report_id, _)| {
                         (
                             report_id,
@@ -683,4 +1079,44 @@ mod tests {
             r#"report_counter{env="test_helper",host="helper.org",status="rejected_report_replayed"}"#: 1,
         });
     }
+
+    #[tokio::test]
+    async fn agg_job_init_decryption_progress_called_once_per_report_share() {
+        let version = DapVersion::DraftLatest;
+        let mut data = TestData::new(version);
+        let task_id = data.insert_task(version, VdafConfig::Prio3(Prio3Config::Count));
+        let helper = data.new_helper();
+        let test = data.with_leader(Arc::clone(&helper));
+
+        let reports = futures::stream::iter(0..5)
+            .then(|_| async { test.gen_test_report(&task_id).await })
+            .collect::<Vec<_>>()
+            .await;
+        let (_, req) = test
+            .gen_test_agg_job_init_req(&task_id, version, DapAggregationParam::Empty, reports)
+            .await;
+
+        let mut calls = Vec::new();
+        let resp = super::handle_agg_job_init_req_with_decryption_progress(
+            &*helper,
+            &req,
+            |processed, total| {
+                calls.push((processed, total));
+            },
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            calls,
+            (1..=5).map(|processed| (processed, 5)).collect::<Vec<_>>()
+        );
+
+        let agg_job_resp =
+            AggregationJobResp::get_decoded_with_param(&version, &resp.payload).unwrap();
+        assert_eq!(agg_job_resp.transitions.len(), 5);
+        assert!(agg_job_resp
+            .transitions
+            .iter()
+            .all(|t| !matches!(t.var, TransitionVar::Failed(..))));
+    }
 }
@@ -1,12 +1,16 @@
 // Copyright (c) 2023 Cloudflare, Inc. All rights reserved.
 // SPDX-License-Identifier: BSD-3-Clause
 
-use std::collections::HashMap;
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::Mutex,
+};
 
 use async_trait::async_trait;
-use futures::future::try_join_all;
+use futures::future::join_all;
 use prio::codec::{Decode, Encode, ParameterizedDecode, ParameterizedEncode};
-use tracing::{debug, error};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, error, field, Span};
 use url::Url;
 
 use super::{
@@ -18,14 +22,17 @@ use crate::{
     error::DapAbort,
     fatal_error,
     messages::{
-        AggregateShare, AggregateShareReq, AggregationJobResp, Base64Encode, BatchId,
-        BatchSelector, Collection, CollectionJobId, CollectionReq, Interval, PartialBatchSelector,
-        Query, Report, TaskId,
+        AggregateShare, AggregateShareReq, AggregationJobResp, AggregationJobRespChunk,
+        AggregationJobRespChunkReq, Base64Encode, BatchId, BatchSelector, Collection,
+        CollectionJobId, CollectionReq, Duration, Interval, PartialBatchSelector, Query, Report,
+        ReportId, TaskId, Time, TransitionFailure,
     },
     metrics::DaphneRequestType,
-    DapAggregationParam, DapCollectionJob, DapError, DapLeaderAggregationJobTransition,
-    DapLeaderProcessTelemetry, DapRequest, DapResource, DapResponse, DapTaskConfig, DapVersion,
-    MetaAggregationJobId,
+    vdaf::VDAF_VERSION,
+    DapAggregateShare, DapAggregateSpan, DapAggregationJobState, DapAggregationJobStatus,
+    DapAggregationParam, DapCollectionJob, DapCollectionJobInfo, DapError,
+    DapLeaderAggregationJobTransition, DapLeaderProcessTelemetry, DapRequest, DapResource,
+    DapResponse, DapTaskConfig, DapVersion, MetaAggregationJobId,
 };
 
 struct LeaderHttpRequestOptions<'p> {
@@ -36,6 +43,9 @@ struct LeaderHttpRequestOptions<'p> {
     req_data: Vec<u8>,
     method: LeaderHttpRequestMethod,
     taskprov: Option<String>,
+    idempotency_token: Option<String>,
+    vdaf_version: Option<String>,
+    collector_hpke_config_id: Option<u8>,
 }
 
 enum LeaderHttpRequestMethod {
@@ -57,6 +67,9 @@ async fn leader_send_http_request<S: Sync>(
         req_data,
         method,
         taskprov,
+        idempotency_token,
+        vdaf_version,
+        collector_hpke_config_id,
     } = opts;
 
     let url = task_config
@@ -75,6 +88,10 @@ async fn leader_send_http_request<S: Sync>(
         ),
         payload: req_data,
         taskprov,
+        idempotency_token,
+        vdaf_version,
+        collection_format: Default::default(),
+        collector_hpke_config_id,
     };
 
     let resp = match method {
@@ -82,7 +99,12 @@ async fn leader_send_http_request<S: Sync>(
         LeaderHttpRequestMethod::Post => role.send_http_post(req, url).await?,
     };
 
-    check_response_content_type(&resp, resp_media_type)?;
+    // The Helper may split a large aggregation job response into chunks.
+    if !(resp_media_type == DapMediaType::AggregationJobResp
+        && resp.media_type == DapMediaType::AggregationJobRespChunk)
+    {
+        check_response_content_type(&resp, resp_media_type)?;
+    }
     Ok(resp)
 }
 
@@ -99,21 +121,40 @@ pub trait DapAuthorizedSender<S> {
     ) -> Result<S, DapError>;
 }
 
-/// A work item, either an aggregation job or collection job.
-#[derive(Debug)]
+/// A work item, either an aggregation job or collection job. Work items can be serialized so that
+/// the work queue can be persisted.
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
 #[cfg_attr(any(test, feature = "test-utils"), derive(deepsize::DeepSizeOf))]
 pub enum WorkItem {
     AggregationJob {
         task_id: TaskId,
+        agg_job_id: MetaAggregationJobId,
         part_batch_sel: PartialBatchSelector,
         agg_param: DapAggregationParam,
         reports: Vec<Report>,
+        /// The number of times the job may still be retried if it fails with a retryable error
+        /// (see [`DapGlobalConfig::agg_job_retry_budget`](crate::DapGlobalConfig)).
+        #[serde(default)]
+        retries_remaining: u32,
+        /// If set, the job is not dequeued before this time. This is used to back off before
+        /// retrying a failed job.
+        #[serde(default)]
+        not_before: Option<Time>,
     },
     CollectionJob {
         task_id: TaskId,
         coll_job_id: CollectionJobId,
         batch_sel: BatchSelector,
         agg_param: DapAggregationParam,
+        /// The Collector's HPKE config under which to encrypt the aggregate shares, if it asked
+        /// for one other than the task's primary config.
+        #[serde(default)]
+        collector_hpke_config_id: Option<u8>,
+        /// The number of times the job has been put back in the queue (see
+        /// [`MAX_COLL_JOB_REQUEUES`]).
+        #[serde(default)]
+        requeues: u32,
     },
 }
 
@@ -126,22 +167,210 @@ impl WorkItem {
     }
 }
 
+/// An aggregation job that the Helper has responded to, but that the Leader hasn't committed yet.
+/// [`process`] stores it while it commits the job, so that the job can be finished by
+/// [`resume_agg_jobs`] if the Leader restarts in the meantime.
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Serialize)]
+pub struct AggregationJobCheckpoint {
+    pub task_id: TaskId,
+    pub agg_job_id: MetaAggregationJobId,
+    /// The encoded aggregation parameter the job was run with.
+    #[serde(with = "hex")]
+    pub agg_param: Vec<u8>,
+    /// The Leader's state, as encoded by [`DapAggregationJobState`]'s [`Encode`] implementation.
+    #[serde(with = "hex")]
+    pub state: Vec<u8>,
+    /// The Helper's encoded [`AggregationJobResp`].
+    #[serde(with = "hex")]
+    pub agg_job_resp: Vec<u8>,
+}
+
+/// Identifies a work item leased by [`DapLeader::dequeue_work`]. It is passed back to
+/// [`DapLeader::ack_work`] once the item has been processed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct WorkItemId(pub u64);
+
+/// A summary of the Leader's work queue, as returned by [`DapLeader::work_queue_stats`]. Items
+/// that have been dequeued but not yet acknowledged are not counted.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct WorkQueueStats {
+    /// Total number of items in the queue.
+    pub len: usize,
+    /// Number of aggregation jobs in the queue.
+    pub agg_jobs: usize,
+    /// Number of collection jobs in the queue.
+    pub coll_jobs: usize,
+    /// The number of items in the queue for each task.
+    pub per_task: HashMap<TaskId, WorkQueueTaskStats>,
+}
+
+/// The number of items in the Leader's work queue for a single task. See [`WorkQueueStats`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize)]
+pub struct WorkQueueTaskStats {
+    pub agg_jobs: usize,
+    pub coll_jobs: usize,
+}
+
+impl WorkQueueStats {
+    /// Count a work item.
+    pub fn add(&mut self, work_item: &WorkItem) {
+        let per_task = self.per_task.entry(*work_item.task_id()).or_default();
+        match work_item {
+            WorkItem::AggregationJob { .. } => {
+                self.agg_jobs += 1;
+                per_task.agg_jobs += 1;
+            }
+            WorkItem::CollectionJob { .. } => {
+                self.coll_jobs += 1;
+                per_task.coll_jobs += 1;
+            }
+        }
+        self.len += 1;
+    }
+}
+
+/// Thresholds on the length of the Leader's work queue used to shed load when it backs up. Once
+/// the queue is longer than `high`, uploads are refused until it drains below `low`. Refused
+/// uploads may be retried after `retry_after` seconds.
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+pub struct WorkQueueWatermarks {
+    pub high: usize,
+    pub low: usize,
+    pub retry_after: Duration,
+}
+
+/// A bounded cache of recently uploaded reports. The Leader consults it to skip redundant storage
+/// writes when a Client retries an upload. This is only an optimization: replay protection is still
+/// enforced when the report is aggregated.
+///
+/// When the cache is full, the least recently used report is evicted.
+pub struct UploadDedupCache {
+    capacity: usize,
+    inner: Mutex<UploadDedupCacheInner>,
+}
+
+#[derive(Default)]
+struct UploadDedupCacheInner {
+    /// The last use of each cached report.
+    last_used: HashMap<(TaskId, ReportId), u64>,
+
+    /// Uses of cached reports, oldest first. A use is stale if the report has been used since.
+    uses: VecDeque<((TaskId, ReportId), u64)>,
+
+    /// Counter for uses.
+    tick: u64,
+}
+
+impl UploadDedupCacheInner {
+    fn touch(&mut self, key: (TaskId, ReportId)) {
+        self.tick += 1;
+        self.last_used.insert(key, self.tick);
+        self.uses.push_back((key, self.tick));
+    }
+}
+
+impl UploadDedupCache {
+    /// Create a cache that holds at most `capacity` reports. A capacity of `0` disables the cache.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            inner: Default::default(),
+        }
+    }
+
+    /// Check if a report was recently uploaded for the task.
+    pub fn contains(&self, task_id: &TaskId, report_id: &ReportId) -> bool {
+        let Ok(mut inner) = self.inner.lock() else {
+            return false;
+        };
+        let key = (*task_id, *report_id);
+        if inner.last_used.contains_key(&key) {
+            inner.touch(key);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Record that a report was uploaded for the task.
+    pub fn insert(&self, task_id: &TaskId, report_id: &ReportId) {
+        if self.capacity == 0 {
+            return;
+        }
+        let Ok(mut inner) = self.inner.lock() else {
+            return;
+        };
+        inner.touch((*task_id, *report_id));
+
+        // Evict the least recently used reports, skipping stale uses.
+        while inner.last_used.len() > self.capacity {
+            let Some((key, tick)) = inner.uses.pop_front() else {
+                break;
+            };
+            if inner.last_used.get(&key) == Some(&tick) {
+                inner.last_used.remove(&key);
+            }
+        }
+
+        // Drop stale uses so that repeated hits don't grow the queue without bound.
+        if inner.uses.len() > 2 * self.capacity {
+            let UploadDedupCacheInner {
+                last_used, uses, ..
+            } = &mut *inner;
+            uses.retain(|(key, tick)| last_used.get(key) == Some(tick));
+        }
+    }
+}
+
 /// DAP Leader functionality.
 #[async_trait]
 pub trait DapLeader<S: Sync>: DapAuthorizedSender<S> + DapAggregator<S> {
     /// Store a report for use later on.
     async fn put_report(&self, report: &Report, task_id: &TaskId) -> Result<(), DapError>;
 
-    /// Fixed-size tasks: Return the ID of the batch currently being filled.
+    /// Return the cache of recently uploaded reports, used to skip redundant writes when a Client
+    /// retries an upload. By default, uploads are not deduplicated.
+    fn upload_dedup_cache(&self) -> Option<&UploadDedupCache> {
+        None
+    }
+
+    /// Fixed-size tasks: Return the ID of the oldest batch that has not been collected. The batch
+    /// may still be filling.
     async fn current_batch(&self, task_id: &TaskId) -> Result<BatchId, DapError>;
 
-    /// Initialize a collection job.
+    /// Fixed-size tasks: Like [`Self::current_batch`], but also remove the batch from the queue of
+    /// batches being filled. This is done atomically, so that concurrent collection jobs for the
+    /// current batch are never handed the same batch, and no more reports are assigned to it.
+    ///
+    /// This selects the batch for a [`Query::FixedSizeCurrentBatch`] query, so implementations
+    /// should abort with [`DapAbort::InvalidBatchSize`] if the batch has not yet reached the
+    /// task's minimum batch size.
+    async fn reserve_current_batch(&self, task_id: &TaskId) -> Result<BatchId, DapError>;
+
+    /// Fixed-size tasks: Return the smallest interval containing the timestamps of all reports
+    /// assigned to the batch, or `None` if the batch is unknown or has been collected. Unlike the
+    /// interval in the [`Collection`], the interval is not rounded to the task's time precision.
+    async fn batch_time_range(
+        &self,
+        task_id: &TaskId,
+        batch_id: &BatchId,
+    ) -> Result<Option<Interval>, DapError>;
+
+    /// Initialize a collection job for the batch selected by `query`. The aggregate shares are
+    /// encrypted under the Collector's HPKE config with ID `collector_hpke_config_id`, or the
+    /// task's primary config if not set.
+    ///
+    /// For a [`Query::FixedSizeCurrentBatch`] query, the current batch is reserved as by
+    /// [`Self::reserve_current_batch`] in the same step that creates the job, so that the batch
+    /// is not taken from the queue if the job can't be created. A repeated request for an
+    /// existing job with the same parameters returns the job's URI.
     async fn init_collect_job(
         &self,
         task_id: &TaskId,
         collect_job_id: &Option<CollectionJobId>,
-        batch_sel: BatchSelector,
+        query: Query,
         agg_param: DapAggregationParam,
+        collector_hpke_config_id: Option<u8>,
     ) -> Result<Url, DapError>;
 
     /// Check the status of a collect job.
@@ -151,12 +380,90 @@ pub trait DapLeader<S: Sync>: DapAuthorizedSender<S> + DapAggregator<S> {
         coll_job_id: &CollectionJobId,
     ) -> Result<DapCollectionJob, DapError>;
 
-    /// Drain at most `num_items` items from the work queue.
-    async fn dequeue_work(&self, num_items: usize) -> Result<Vec<WorkItem>, DapError>;
+    /// List the collection jobs for a task. This is intended for operator tooling, e.g., for
+    /// diagnosing collection jobs that are stuck.
+    async fn list_collection_jobs(
+        &self,
+        task_id: &TaskId,
+    ) -> Result<Vec<(CollectionJobId, DapCollectionJobInfo)>, DapError>;
+
+    /// Re-drive a pending collection job whose processing failed partway, e.g., because the Helper
+    /// was briefly unavailable. The collection job is queued again, along with any of its
+    /// aggregation jobs that did not complete. Work that is already queued or completed is not
+    /// duplicated, and retrying a collection job that is done is a no-op.
+    async fn retry_collect_job(
+        &self,
+        task_id: &TaskId,
+        coll_job_id: &CollectionJobId,
+    ) -> Result<(), DapError>;
+
+    /// Get the status of an aggregation job in the work queue. Return `None` if the job is unknown.
+    /// The status of a finished job need only be kept for a bounded time; once it is evicted, the
+    /// job is unknown.
+    async fn agg_job_status(
+        &self,
+        task_id: &TaskId,
+        agg_job_id: &MetaAggregationJobId,
+    ) -> Result<Option<DapAggregationJobStatus>, DapError>;
+
+    /// Update the status of an aggregation job. This is called by [`process`] as it runs the job.
+    async fn set_agg_job_status(
+        &self,
+        task_id: &TaskId,
+        agg_job_id: &MetaAggregationJobId,
+        status: DapAggregationJobStatus,
+    ) -> Result<(), DapError>;
+
+    /// Mark an aggregation job as failed because it is retried under the new ID
+    /// `retry_agg_job_id` (see [`MetaAggregationJobId::for_retry`]). Collection jobs that depend on
+    /// the job depend on the retry from then on. This is called by [`process`] before it queues the
+    /// retry.
+    async fn replace_agg_job_id(
+        &self,
+        task_id: &TaskId,
+        agg_job_id: &MetaAggregationJobId,
+        retry_agg_job_id: &MetaAggregationJobId,
+    ) -> Result<(), DapError>;
+
+    /// Store the checkpoint of an aggregation job, replacing any previous checkpoint for the job.
+    /// This is called by [`process`] once the Helper has responded to the job.
+    async fn put_agg_job_checkpoint(
+        &self,
+        checkpoint: AggregationJobCheckpoint,
+    ) -> Result<(), DapError>;
+
+    /// Delete the checkpoint of an aggregation job once the job has been committed.
+    async fn delete_agg_job_checkpoint(
+        &self,
+        task_id: &TaskId,
+        agg_job_id: &MetaAggregationJobId,
+    ) -> Result<(), DapError>;
+
+    /// List the stored aggregation job checkpoints. See [`resume_agg_jobs`].
+    async fn list_agg_job_checkpoints(&self) -> Result<Vec<AggregationJobCheckpoint>, DapError>;
+
+    /// Lease at most `num_items` items from the work queue. Leased items are not handed out again
+    /// unless their lease expires (see [`DapGlobalConfig::work_item_visibility_timeout`]) before
+    /// they are acknowledged with [`Self::ack_work`].
+    ///
+    /// [`DapGlobalConfig::work_item_visibility_timeout`]: crate::DapGlobalConfig::work_item_visibility_timeout
+    async fn dequeue_work(&self, num_items: usize)
+        -> Result<Vec<(WorkItemId, WorkItem)>, DapError>;
+
+    /// Delete leased work items that have been processed. This is called by [`process`] as soon
+    /// as it is done with each item it dequeued, whether or not processing succeeded. Acknowledging
+    /// an aggregation job frees up a slot for the next job for the task (see
+    /// [`DapGlobalConfig::max_concurrent_agg_jobs_per_task`]).
+    ///
+    /// [`DapGlobalConfig::max_concurrent_agg_jobs_per_task`]: crate::DapGlobalConfig::max_concurrent_agg_jobs_per_task
+    async fn ack_work(&self, item_ids: Vec<WorkItemId>) -> Result<(), DapError>;
 
     /// Append `items` to the work queue.
     async fn enqueue_work(&self, items: Vec<WorkItem>) -> Result<(), DapError>;
 
+    /// Summarize the contents of the work queue without dequeuing anything.
+    async fn work_queue_stats(&self) -> Result<WorkQueueStats, DapError>;
+
     /// Complete a collect job by assigning it the completed
     /// [`Collection`](crate::messages::Collection).
     async fn finish_collect_job(
@@ -174,6 +481,13 @@ pub trait DapLeader<S: Sync>: DapAuthorizedSender<S> + DapAggregator<S> {
 }
 
 /// Handle a report from a Client.
+#[tracing::instrument(
+    skip_all,
+    fields(
+        task_id = req.task_id().ok().map(field::display),
+        report_id = field::Empty,
+    )
+)]
 pub async fn handle_upload_req<S: Sync, A: DapLeader<S>>(
     aggregator: &A,
     req: &DapRequest<S>,
@@ -187,6 +501,16 @@ pub async fn handle_upload_req<S: Sync, A: DapLeader<S>>(
     let report = Report::get_decoded_with_param(&req.version, req.payload.as_ref())
         .map_err(|e| DapAbort::from_codec_error(e, *task_id))?;
     debug!("report id is {}", report.report_metadata.id);
+    Span::current().record("report_id", field::display(report.report_metadata.id));
+
+    // draft02: The task ID is also encoded in the report. Reject the report if it was uploaded
+    // for a different task.
+    if report
+        .draft02_task_id
+        .is_some_and(|draft02_task_id| draft02_task_id != *task_id)
+    {
+        return Err(DapAbort::UnrecognizedTask.into());
+    }
 
     if aggregator.get_global_config().allow_taskprov {
         resolve_taskprov(aggregator, task_id, req, Some(&report.report_metadata)).await?;
@@ -201,48 +525,85 @@ pub async fn handle_upload_req<S: Sync, A: DapLeader<S>>(
         return Err(DapAbort::version_mismatch(req.version, task_config.as_ref().version).into());
     }
 
-    if report.encrypted_input_shares.len() != 2 {
-        return Err(DapAbort::InvalidMessage {
-            detail: format!(
-                "expected exactly two encrypted input shares; got {}",
-                report.encrypted_input_shares.len()
-            ),
-            task_id: Some(*task_id),
-        }
-        .into());
-    }
-
-    // Check that the indicated HpkeConfig is present.
+    // Check that the indicated HpkeConfig is present. If not, the Client is likely using a config
+    // that has since been retired and needs to fetch the current one.
     if !aggregator
         .can_hpke_decrypt(req.task_id()?, report.encrypted_input_shares[0].config_id)
         .await?
     {
-        return Err(DapAbort::ReportRejected {
+        return Err(DapAbort::OutdatedConfig {
             detail: "No current HPKE configuration matches the indicated ID.".into(),
+            task_id: *task_id,
         }
         .into());
     }
 
     // Check that the task has not expired.
-    if report.report_metadata.time >= task_config.as_ref().expiration {
+    if report.report_metadata.time >= task_config.as_ref().expiration
+        || aggregator.get_current_time() >= task_config.as_ref().expiration
+    {
         return Err(DapAbort::ReportTooLate.into());
     }
 
+    // Check that the report timestamp is within the range of times the Aggregators are willing
+    // to store reports for.
+    let valid_report_time_range = aggregator.valid_report_time_range();
+    if report.report_metadata.time < *valid_report_time_range.start() {
+        return Err(DapAbort::ReportRejected {
+            detail: "The report timestamp is too far in the past.".into(),
+        }
+        .into());
+    }
+    if report.report_metadata.time > *valid_report_time_range.end() {
+        return Err(DapAbort::ReportTooEarly.into());
+    }
+
+    // Skip the write if the Client is retrying an upload we've already stored.
+    let dedup_cache = aggregator.upload_dedup_cache();
+    if dedup_cache.is_some_and(|cache| cache.contains(task_id, &report.report_metadata.id)) {
+        debug!(
+            "report {} was uploaded recently; skipping write",
+            report.report_metadata.id
+        );
+        metrics.inbound_req_inc(DaphneRequestType::Upload);
+        return Ok(());
+    }
+
     // Store the report for future processing. At this point, the report may be rejected if
     // the Leader detects that the report was replayed or pertains to a batch that has already
     // been collected.
     aggregator.put_report(&report, req.task_id()?).await?;
+    if let Some(cache) = dedup_cache {
+        cache.insert(task_id, &report.report_metadata.id);
+    }
 
     metrics.inbound_req_inc(DaphneRequestType::Upload);
     Ok(())
 }
 
-/// Handle a collect job from the Collector. The response is the URI that the Collector will
-/// poll later on to get the collection.
+/// The Leader's response to a collection job request.
+#[derive(Debug)]
+pub struct CollectionJobCreated {
+    /// The URI that the Collector will poll later on to get the collection.
+    pub uri: Url,
+
+    /// The batch interval that will be collected, if it was aligned to the task's batch windows
+    /// and thus differs from the requested one. See [`DapTaskConfig::align_batch_intervals`].
+    pub aligned_batch_interval: Option<Interval>,
+}
+
+/// Handle a collect job from the Collector.
+#[tracing::instrument(
+    skip_all,
+    fields(
+        task_id = req.task_id().ok().map(field::display),
+        coll_job_id = field::Empty,
+    )
+)]
 pub async fn handle_coll_job_req<S: Sync, A: DapLeader<S>>(
     aggregator: &A,
     req: &DapRequest<S>,
-) -> Result<Url, DapError> {
+) -> Result<CollectionJobCreated, DapError> {
     let now = aggregator.get_current_time();
     let metrics = aggregator.metrics();
     let task_id = req.task_id()?;
@@ -272,26 +633,31 @@ pub async fn handle_coll_job_req<S: Sync, A: DapLeader<S>>(
     let coll_job_req = CollectionReq::get_decoded_with_param(&req.version, req.payload.as_ref())
         .map_err(|e| DapAbort::from_codec_error(e, *task_id))?;
 
+    // The collection job keeps the batch interval as requested, since that is what the aggregate
+    // shares are bound to. Only the reports that are aggregated are selected by the aligned one.
+    let aligned_batch_interval = match &coll_job_req.query {
+        Query::TimeInterval { batch_interval } if task_config.align_batch_intervals => {
+            batch_interval
+                .align_to(task_config)
+                .filter(|aligned| aligned != batch_interval)
+                .inspect(|aligned| {
+                    debug!("aligned batch interval {batch_interval:?} to {aligned:?}");
+                })
+        }
+        _ => None,
+    };
+
     let agg_param =
-        DapAggregationParam::get_decoded_with_param(&task_config.vdaf, &coll_job_req.agg_param)
-            .map_err(|e| DapAbort::from_codec_error(e, *task_id))?;
+        DapAggregationParam::decode_for_task(task_id, &task_config.vdaf, &coll_job_req.agg_param)?;
 
     // Check whether the DAP version in the request matches the task config.
     if task_config.version != req.version {
         return Err(DapAbort::version_mismatch(req.version, task_config.version).into());
     }
 
-    // Ensure the batch boundaries are valid and that the batch doesn't overlap with previosuly
-    // collected batches.
-    check_batch(
-        aggregator,
-        task_config,
-        task_id,
-        &coll_job_req.query,
-        &coll_job_req.agg_param,
-        now,
-    )
-    .await?;
+    // Make sure the aggregate shares can be encrypted under the Collector's HPKE config before
+    // any work is done for the collection job.
+    task_config.collector_hpke_config_for(task_id, req.collector_hpke_config_id)?;
 
     // draft02 compatibility: In draft02, the collection job ID is generated as a result of the
     // initial collection request, whereas in the latest draft, the collection job ID is parsed
@@ -308,29 +674,60 @@ pub async fn handle_coll_job_req<S: Sync, A: DapLeader<S>>(
             return Err(DapAbort::BadRequest(format!("unexpected resource {resource:?}")).into())
         }
     };
+    if let Some(collect_job_id) = collect_job_id {
+        Span::current().record("coll_job_id", field::display(collect_job_id));
+    }
 
-    let batch_sel = match coll_job_req.query {
-        Query::TimeInterval { batch_interval } => BatchSelector::TimeInterval { batch_interval },
-        Query::FixedSizeByBatchId { batch_id } => BatchSelector::FixedSizeByBatchId { batch_id },
-        Query::FixedSizeCurrentBatch => BatchSelector::FixedSizeByBatchId {
-            batch_id: aggregator.current_batch(task_id).await?,
-        },
+    // A repeated request for an existing collection job is a retry. The batch was checked when
+    // the job was created and may since have been collected by it, so whether the request
+    // matches the existing job is left to `init_collect_job`.
+    let is_retry = match collect_job_id {
+        Some(ref collect_job_id) => !matches!(
+            aggregator.poll_collect_job(task_id, collect_job_id).await?,
+            DapCollectionJob::Unknown
+        ),
+        None => false,
     };
 
+    // Ensure the batch boundaries are valid and that the batch doesn't overlap with previosuly
+    // collected batches.
+    if !is_retry {
+        check_batch(
+            aggregator,
+            task_config,
+            task_id,
+            &coll_job_req.query,
+            &coll_job_req.agg_param,
+            now,
+        )
+        .await?;
+    }
+
     let collect_job_uri = aggregator
-        .init_collect_job(task_id, &collect_job_id, batch_sel, agg_param)
+        .init_collect_job(
+            task_id,
+            &collect_job_id,
+            coll_job_req.query,
+            agg_param,
+            req.collector_hpke_config_id,
+        )
         .await?;
 
     metrics.inbound_req_inc(DaphneRequestType::Collect);
-    Ok(collect_job_uri)
+    Ok(CollectionJobCreated {
+        uri: collect_job_uri,
+        aligned_batch_interval,
+    })
 }
 
 /// Run an aggregation job for a set of reports. Return the number of reports that were
 /// aggregated successfully.
+#[tracing::instrument(skip_all, fields(%task_id, %agg_job_id))]
 async fn run_agg_job<S: Sync, A: DapLeader<S>>(
     aggregator: &A,
     task_id: &TaskId,
     task_config: &DapTaskConfig,
+    agg_job_id: &MetaAggregationJobId,
     part_batch_sel: &PartialBatchSelector,
     agg_param: &DapAggregationParam,
     reports: Vec<Report>,
@@ -340,13 +737,12 @@ async fn run_agg_job<S: Sync, A: DapLeader<S>>(
     let taskprov = task_config.resolve_taskprove_advertisement()?;
 
     // Prepare AggregationJobInitReq.
-    let agg_job_id = MetaAggregationJobId::gen_for_version(task_config.version);
     let transition = task_config
         .produce_agg_job_init_req(
             aggregator,
             aggregator,
             task_id,
-            &agg_job_id,
+            agg_job_id,
             part_batch_sel,
             agg_param,
             reports,
@@ -384,6 +780,7 @@ async fn run_agg_job<S: Sync, A: DapLeader<S>>(
     };
 
     // Send AggregationJobInitReq and receive AggregationJobResp.
+    let prep_init_count = agg_job_init_req.prep_inits.len();
     let resp = leader_send_http_request(
         aggregator,
         task_id,
@@ -398,15 +795,49 @@ async fn run_agg_job<S: Sync, A: DapLeader<S>>(
                 .map_err(DapError::encoding)?,
             method,
             taskprov: taskprov.clone(),
+            idempotency_token: None,
+            vdaf_version: Some(VDAF_VERSION.into()),
+            collector_hpke_config_id: None,
         },
     )
     .await?;
-    let agg_job_resp = AggregationJobResp::get_decoded(&resp.payload)
-        .map_err(|e| DapAbort::from_codec_error(e, *task_id))?;
+    let agg_job_resp = if resp.media_type == DapMediaType::AggregationJobRespChunk {
+        fetch_agg_job_resp_chunks(
+            aggregator,
+            task_id,
+            task_config,
+            agg_job_id,
+            &url_path,
+            taskprov.clone(),
+            prep_init_count,
+            &resp.payload,
+        )
+        .await?
+    } else {
+        AggregationJobResp::get_decoded(&resp.payload)
+            .map_err(|e| DapAbort::from_codec_error(e, *task_id))?
+    };
+
+    // Store the Helper's response along with our state before committing the job, so that the job
+    // can be finished by `resume_agg_jobs()` if we restart in the meantime.
+    //
+    // draft02: The Leader's state includes its prep shares and can't be encoded.
+    let checkpointed = task_config.version != DapVersion::Draft02;
+    if checkpointed {
+        aggregator
+            .put_agg_job_checkpoint(AggregationJobCheckpoint {
+                task_id: *task_id,
+                agg_job_id: *agg_job_id,
+                agg_param: agg_param.get_encoded().map_err(DapError::encoding)?,
+                state: state.get_encoded().map_err(DapError::encoding)?,
+                agg_job_resp: agg_job_resp.get_encoded().map_err(DapError::encoding)?,
+            })
+            .await?;
+    }
 
     // Handle AggregationJobResp.
     let transition =
-        task_config.handle_agg_job_resp(task_id, &agg_job_id, state, agg_job_resp, metrics)?;
+        task_config.handle_agg_job_resp(task_id, agg_job_id, state, agg_job_resp, metrics)?;
     let agg_span = match transition {
         DapLeaderAggregationJobTransition::Uncommitted(uncommited, agg_job_cont_req) => {
             // Send AggregationJobContinueReq and receive AggregationJobResp.
@@ -426,6 +857,9 @@ async fn run_agg_job<S: Sync, A: DapLeader<S>>(
                         .map_err(DapError::encoding)?,
                     method: LeaderHttpRequestMethod::Post,
                     taskprov,
+                    idempotency_token: None,
+                    vdaf_version: None,
+                    collector_hpke_config_id: None,
                 },
             )
             .await?;
@@ -435,18 +869,36 @@ async fn run_agg_job<S: Sync, A: DapLeader<S>>(
             // Handle AggregationJobResp.
             task_config.handle_final_agg_job_resp(uncommited, agg_job_resp, metrics)?
         }
-        DapLeaderAggregationJobTransition::Finished(agg_span) => {
-            if agg_span.report_count() > 0 {
-                agg_span
-            } else {
-                return Ok(0);
-            }
-        }
+        DapLeaderAggregationJobTransition::Finished(agg_span) => agg_span,
         DapLeaderAggregationJobTransition::Continued(..) => {
             return Err(fatal_error!(err = "unexpected state transition (continue)"))
         }
     };
 
+    let out_shares_count =
+        commit_agg_span(aggregator, task_id, task_config, agg_param, agg_span).await?;
+    if checkpointed {
+        aggregator
+            .delete_agg_job_checkpoint(task_id, agg_job_id)
+            .await?;
+    }
+    Ok(out_shares_count)
+}
+
+/// Merge the output shares of an aggregation job into the aggregate shares of their buckets for
+/// the given aggregation parameter. Returns the number of reports aggregated.
+async fn commit_agg_span<S: Sync, A: DapLeader<S>>(
+    aggregator: &A,
+    task_id: &TaskId,
+    task_config: &DapTaskConfig,
+    agg_param: &DapAggregationParam,
+    agg_span: DapAggregateSpan<DapAggregateShare>,
+) -> Result<u64, DapError> {
+    let metrics = aggregator.metrics();
+    if agg_span.report_count() == 0 {
+        return Ok(0);
+    }
+
     let out_shares_count = agg_span.report_count() as u64;
 
     // At this point we're committed to aggregating the reports: if we do detect an error (a
@@ -454,13 +906,25 @@ async fn run_agg_job<S: Sync, A: DapLeader<S>>(
     // may end up with a batch mismatch. However, this should only happen if there are multiple
     // aggregation jobs in-flight that include the same report.
     let (replayed, collected) = aggregator
-        .try_put_agg_share_span(task_id, task_config, agg_span)
+        .try_put_agg_share_span(task_id, task_config, agg_param, agg_span)
         .await
         .into_iter()
-        .map(|(_bucket, (result, _report_metadata))| match result {
+        .map(|(_bucket, (result, report_metadata))| match result {
             Ok(()) => Ok((0, 0)),
-            Err(MergeAggShareError::AlreadyCollected) => Ok((0, 1)),
-            Err(MergeAggShareError::ReplaysDetected(replays)) => Ok((replays.len(), 0)),
+            Err(MergeAggShareError::AlreadyCollected) => {
+                metrics.report_rejection_inc_by(
+                    TransitionFailure::BatchCollected,
+                    report_metadata.len() as u64,
+                );
+                Ok((0, 1))
+            }
+            Err(MergeAggShareError::ReplaysDetected(replays)) => {
+                metrics.report_rejection_inc_by(
+                    TransitionFailure::ReportReplayed,
+                    replays.len() as u64,
+                );
+                Ok((replays.len(), 0))
+            }
             Err(MergeAggShareError::Other(e)) => Err(e),
         })
         .try_fold((0, 0), |(replayed, collected), rc| {
@@ -486,9 +950,149 @@ async fn run_agg_job<S: Sync, A: DapLeader<S>>(
     Ok(out_shares_count)
 }
 
+/// Finish the aggregation jobs that were interrupted after the Helper responded to them, e.g.,
+/// because the Leader restarted while committing them. Returns the number of reports aggregated.
+///
+/// A checkpoint that can't be resumed is logged and left in storage for inspection; it doesn't
+/// prevent the other jobs from being resumed.
+///
+/// This is meant to be called once on startup, before [`process`]. A job that is being committed
+/// concurrently would be committed twice, and its reports rejected as replays the second time.
+pub async fn resume_agg_jobs<S: Sync, A: DapLeader<S>>(aggregator: &A) -> Result<u64, DapError> {
+    let mut reports_aggregated = 0;
+    for checkpoint in aggregator.list_agg_job_checkpoints().await? {
+        let task_id = checkpoint.task_id;
+        let agg_job_id = checkpoint.agg_job_id;
+        match resume_agg_job(aggregator, checkpoint).await {
+            Ok(out_shares_count) => reports_aggregated += out_shares_count,
+            Err(e) => tracing::error!(
+                task_id = task_id.to_base64url(),
+                agg_job_id = agg_job_id.to_base64url(),
+                error = ?e,
+                "failed to resume aggregation job"
+            ),
+        }
+    }
+    Ok(reports_aggregated)
+}
+
+/// Finish the aggregation job stored in `checkpoint`. Returns the number of reports aggregated.
+async fn resume_agg_job<S: Sync, A: DapLeader<S>>(
+    aggregator: &A,
+    checkpoint: AggregationJobCheckpoint,
+) -> Result<u64, DapError> {
+    let AggregationJobCheckpoint {
+        task_id,
+        agg_job_id,
+        agg_param,
+        state,
+        agg_job_resp,
+    } = checkpoint;
+    let Some(task_config) = aggregator.get_task_config_for(&task_id).await? else {
+        tracing::warn!(
+            task_id = task_id.to_base64url(),
+            "dropping aggregation job checkpoint for unrecognized task"
+        );
+        aggregator
+            .delete_agg_job_checkpoint(&task_id, &agg_job_id)
+            .await?;
+        return Ok(0);
+    };
+    let task_config = task_config.as_ref();
+
+    let agg_param = DapAggregationParam::decode_for_task(&task_id, &task_config.vdaf, &agg_param)?;
+    let state = DapAggregationJobState::get_decoded_for_leader(&task_config.vdaf, &state)?;
+    let agg_job_resp = AggregationJobResp::get_decoded(&agg_job_resp)
+        .map_err(|e| DapAbort::from_codec_error(e, task_id))?;
+    let DapLeaderAggregationJobTransition::Finished(agg_span) = task_config.handle_agg_job_resp(
+        &task_id,
+        &agg_job_id,
+        state,
+        agg_job_resp,
+        aggregator.metrics(),
+    )?
+    else {
+        return Err(fatal_error!(
+            err = "unexpected state transition for checkpointed aggregation job"
+        ));
+    };
+
+    let out_shares_count =
+        commit_agg_span(aggregator, &task_id, task_config, &agg_param, agg_span).await?;
+    aggregator
+        .set_agg_job_status(&task_id, &agg_job_id, DapAggregationJobStatus::Completed)
+        .await?;
+    aggregator
+        .delete_agg_job_checkpoint(&task_id, &agg_job_id)
+        .await?;
+    Ok(out_shares_count)
+}
+
+/// Reassemble an aggregation job response that the Helper split into chunks, beginning with the
+/// encoded `first_chunk`. The response has at most one transition per report in the aggregation
+/// job, i.e., at most `max_transitions`.
+#[allow(clippy::too_many_arguments)]
+async fn fetch_agg_job_resp_chunks<S: Sync, A: DapLeader<S>>(
+    aggregator: &A,
+    task_id: &TaskId,
+    task_config: &DapTaskConfig,
+    agg_job_id: &MetaAggregationJobId,
+    url_path: &str,
+    taskprov: Option<String>,
+    max_transitions: usize,
+    first_chunk: &[u8],
+) -> Result<AggregationJobResp, DapError> {
+    let mut chunk = AggregationJobRespChunk::get_decoded(first_chunk)
+        .map_err(|e| DapAbort::from_codec_error(e, *task_id))?;
+    let mut transitions = Vec::with_capacity(max_transitions);
+    loop {
+        // Make sure the Helper can't keep us fetching chunks forever.
+        if transitions.len() + chunk.transitions.len() > max_transitions
+            || (chunk.transitions.is_empty() && chunk.continuation_token.is_some())
+        {
+            return Err(DapAbort::InvalidMessage {
+                detail: "aggregation job response chunks do not match the request".into(),
+                task_id: Some(*task_id),
+            }
+            .into());
+        }
+        transitions.append(&mut chunk.transitions);
+
+        let Some(continuation_token) = chunk.continuation_token else {
+            break;
+        };
+
+        let resp = leader_send_http_request(
+            aggregator,
+            task_id,
+            task_config,
+            LeaderHttpRequestOptions {
+                path: url_path,
+                req_media_type: DapMediaType::AggregationJobRespChunkReq,
+                resp_media_type: DapMediaType::AggregationJobRespChunk,
+                resource: agg_job_id.for_request_path(),
+                req_data: AggregationJobRespChunkReq { continuation_token }
+                    .get_encoded()
+                    .map_err(DapError::encoding)?,
+                method: LeaderHttpRequestMethod::Post,
+                taskprov: taskprov.clone(),
+                idempotency_token: None,
+                vdaf_version: None,
+                collector_hpke_config_id: None,
+            },
+        )
+        .await?;
+        chunk = AggregationJobRespChunk::get_decoded(&resp.payload)
+            .map_err(|e| DapAbort::from_codec_error(e, *task_id))?;
+    }
+
+    Ok(AggregationJobResp { transitions })
+}
+
 /// Handle a pending collection job. If the results are ready, then compute the aggregate
 /// results and store them to be retrieved by the Collector later. Returns the number of
 /// reports in the batch.
+#[tracing::instrument(skip_all, fields(%task_id, %coll_job_id))]
 async fn run_coll_job<S: Sync, A: DapLeader<S>>(
     aggregator: &A,
     task_id: &TaskId,
@@ -496,24 +1100,44 @@ async fn run_coll_job<S: Sync, A: DapLeader<S>>(
     coll_job_id: &CollectionJobId,
     batch_sel: &BatchSelector,
     agg_param: &DapAggregationParam,
+    collector_hpke_config_id: Option<u8>,
 ) -> Result<u64, DapError> {
     let metrics = aggregator.metrics();
 
+    // Don't request the Helper's aggregate share until the batch has been aggregated. Otherwise
+    // the request will fail. This only waits on aggregation jobs already queued for the batch, so
+    // the collection job is requeued a bounded number of times.
+    if !aggregator
+        .batch_ready_for_collection(task_id, batch_sel, agg_param)
+        .await?
+    {
+        return Ok(0);
+    }
+
     debug!("collecting id {coll_job_id}");
-    let leader_agg_share = aggregator.get_agg_share(task_id, batch_sel).await?;
+    let leader_agg_share = aggregator
+        .get_agg_share(task_id, batch_sel, agg_param)
+        .await?;
 
     let taskprov = task_config.resolve_taskprove_advertisement()?;
 
-    // Check the batch size. If not not ready, then return early.
-    //
-    // TODO Consider logging this error, as it should never happen.
+    // Check the batch size. No more reports will be aggregated into the batch for this collection
+    // job, so if the batch is too small (in particular, if it is empty), then the job fails.
     if !task_config.is_report_count_compatible(task_id, leader_agg_share.report_count)? {
-        return Ok(0);
+        return Err(DapAbort::InvalidBatchSize {
+            detail: format!(
+                "Report count ({}) is less than the minimum ({})",
+                leader_agg_share.report_count,
+                task_config.effective_min_batch_size()
+            ),
+            task_id: *task_id,
+        }
+        .into());
     }
 
     // Prepare the Leader's aggregate share.
     let leader_enc_agg_share = task_config.produce_leader_encrypted_agg_share(
-        &task_config.collector_hpke_config,
+        collector_hpke_config_id,
         task_id,
         batch_sel,
         agg_param,
@@ -553,6 +1177,11 @@ async fn run_coll_job<S: Sync, A: DapLeader<S>>(
                 .map_err(DapError::encoding)?,
             method: LeaderHttpRequestMethod::Post,
             taskprov,
+            // The collection job ID is stable across retries of the collection job, so the Helper
+            // can use it to recognize a retried request.
+            idempotency_token: Some(coll_job_id.to_base64url()),
+            vdaf_version: None,
+            collector_hpke_config_id,
         },
     )
     .await?;
@@ -590,63 +1219,166 @@ async fn run_coll_job<S: Sync, A: DapLeader<S>>(
 
     // Mark reports as collected.
     aggregator
-        .mark_collected(task_id, &agg_share_req.batch_sel)
+        .mark_collected(task_id, &agg_share_req.batch_sel, agg_param)
         .await?;
 
     metrics.report_inc_by("collected", agg_share_req.report_count);
     Ok(agg_share_req.report_count)
 }
 
+/// Maximum number of times [`process`] puts a collection job back in the work queue, either
+/// because its batch isn't ready yet or because collecting it failed transiently. Once the limit is
+/// reached, the job is left pending until it is re-driven with [`DapLeader::retry_collect_job`].
+pub const MAX_COLL_JOB_REQUEUES: u32 = 1000;
+
 /// Drain a number of items from the work queue and process them.
 ///
 /// Aggregation jobs are handled in parallel, subject to the restriction that all aggregation jobs
 /// pertaining to a task are completed before processing any collection job for the same task.
 ///
 /// Collection jobs are processed in order. If a collection job is still pending once processed, it
-/// is pushed to the back of the work queue.
+/// is pushed to the back of the work queue, at most [`MAX_COLL_JOB_REQUEUES`] times.
+///
+/// An aggregation job that fails with a retryable error is queued again, subject to the retry
+/// budget and backoff configured in [`DapGlobalConfig`](crate::DapGlobalConfig). Once the budget is
+/// exhausted, the error is returned.
 pub async fn process<S: Sync, A: DapLeader<S>>(
     aggregator: &A,
     host: &str,
     num_items: usize,
 ) -> Result<DapLeaderProcessTelemetry, DapError> {
     let mut telem = DapLeaderProcessTelemetry::default();
+    process_into(aggregator, host, num_items, &mut telem).await?;
+    Ok(telem)
+}
 
+/// Like [`process`], but record the telemetry in `telem`. The work done before an error is
+/// returned is recorded as well.
+pub async fn process_into<S: Sync, A: DapLeader<S>>(
+    aggregator: &A,
+    host: &str,
+    num_items: usize,
+    telem: &mut DapLeaderProcessTelemetry,
+) -> Result<(), DapError> {
     tracing::debug!("RUNNING read_work_stream");
 
+    // Each work item is acknowledged as soon as it has been processed, whether or not processing
+    // succeeded, so that neither finished work nor a bad item is handed out again. Work that should
+    // be retried is queued again as a new item. If processing is interrupted, then the remaining
+    // items are handed out again once their lease expires.
     let mut agg_jobs = HashMap::new();
-    let mut pending_coll_jobs = Vec::new();
-    for work_item in aggregator.dequeue_work(num_items).await? {
+    let mut first_err = None;
+    for (item_id, work_item) in aggregator.dequeue_work(num_items).await? {
         match work_item {
             WorkItem::AggregationJob {
                 task_id,
+                agg_job_id,
                 part_batch_sel,
                 agg_param,
                 reports,
+                retries_remaining,
+                not_before: _,
             } => {
                 telem.reports_processed += u64::try_from(reports.len()).unwrap();
                 let agg_jobs_per_task: &mut Vec<_> = agg_jobs.entry(task_id).or_default();
                 agg_jobs_per_task.push(async move {
-                    let task_config = aggregator
-                        .get_task_config_for(&task_id)
-                        .await?
-                        .ok_or(DapAbort::UnrecognizedTask)?;
-
-                    if reports.is_empty() {
-                        return Ok(0);
+                    let res: Result<u64, DapError> = async {
+                        let task_config = aggregator
+                            .get_task_config_for(&task_id)
+                            .await?
+                            .ok_or(DapAbort::UnrecognizedTask)?;
+
+                        if reports.is_empty() {
+                            aggregator
+                                .set_agg_job_status(
+                                    &task_id,
+                                    &agg_job_id,
+                                    DapAggregationJobStatus::Completed,
+                                )
+                                .await?;
+                            return Ok(0);
+                        }
+
+                        aggregator
+                            .set_agg_job_status(
+                                &task_id,
+                                &agg_job_id,
+                                DapAggregationJobStatus::InFlight,
+                            )
+                            .await?;
+
+                        tracing::debug!(
+                            "RUNNING run_agg_job FOR TID {task_id} AND {part_batch_sel:?} AND {host}"
+                        );
+                        let retry_reports = (retries_remaining > 0).then(|| reports.clone());
+                        let res = run_agg_job(
+                            aggregator,
+                            &task_id,
+                            task_config.as_ref(),
+                            &agg_job_id,
+                            &part_batch_sel,
+                            &agg_param,
+                            reports,
+                        )
+                        .await;
+
+                        // If the job failed transiently and its retry budget allows it, then
+                        // queue it to be run again after backing off.
+                        if let (Err(e), Some(reports)) = (&res, retry_reports) {
+                            if e.is_retryable() {
+                                let global_config = aggregator.get_global_config();
+                                let attempt = global_config
+                                    .agg_job_retry_budget
+                                    .saturating_sub(retries_remaining);
+                                let backoff = global_config
+                                    .agg_job_retry_backoff
+                                    .saturating_mul(2_u64.saturating_pow(attempt));
+                                tracing::warn!(
+                                    error = ?e,
+                                    retries_remaining,
+                                    backoff,
+                                    "aggregation job failed; retrying"
+                                );
+                                let retry_agg_job_id = agg_job_id.for_retry();
+                                if retry_agg_job_id != agg_job_id {
+                                    aggregator
+                                        .replace_agg_job_id(
+                                            &task_id,
+                                            &agg_job_id,
+                                            &retry_agg_job_id,
+                                        )
+                                        .await?;
+                                }
+                                aggregator
+                                    .enqueue_work(vec![WorkItem::AggregationJob {
+                                        task_id,
+                                        agg_job_id: retry_agg_job_id,
+                                        part_batch_sel,
+                                        agg_param,
+                                        reports,
+                                        retries_remaining: retries_remaining - 1,
+                                        not_before: Some(aggregator.get_current_time() + backoff),
+                                    }])
+                                    .await?;
+                                return Ok(0);
+                            }
+                        }
+
+                        let status = if res.is_ok() {
+                            DapAggregationJobStatus::Completed
+                        } else {
+                            DapAggregationJobStatus::Failed
+                        };
+                        aggregator
+                            .set_agg_job_status(&task_id, &agg_job_id, status)
+                            .await?;
+                        res
                     }
+                    .await;
 
-                    tracing::debug!(
-                        "RUNNING run_agg_job FOR TID {task_id} AND {part_batch_sel:?} AND {host}"
-                    );
-                    run_agg_job(
-                        aggregator,
-                        &task_id,
-                        task_config.as_ref(),
-                        &part_batch_sel,
-                        &agg_param,
-                        reports,
-                    )
-                    .await
+                    // Acknowledging the job also frees up the task's slot for the next one.
+                    aggregator.ack_work(vec![item_id]).await?;
+                    res
                 });
             }
             WorkItem::CollectionJob {
@@ -654,61 +1386,99 @@ pub async fn process<S: Sync, A: DapLeader<S>>(
                 coll_job_id,
                 batch_sel,
                 agg_param,
+                collector_hpke_config_id,
+                requeues,
             } => {
                 // Wait for all pending aggregation jobs for this task to complete before
                 // processing the next collection job. This is to prevent a race condition
                 // involving an aggregate share computed during a collection job and any output
                 // shares computed during an aggregation job.
                 if let Some(agg_jobs_per_task) = agg_jobs.get_mut(&task_id) {
-                    telem.reports_aggregated +=
-                        try_join_all(agg_jobs_per_task.drain(0..agg_jobs_per_task.len()))
-                            .await?
-                            .into_iter()
-                            .sum::<u64>();
+                    for res in join_all(agg_jobs_per_task.drain(..)).await {
+                        match res {
+                            Ok(reports_aggregated) => {
+                                telem.reports_aggregated += reports_aggregated;
+                            }
+                            Err(e) => {
+                                first_err.get_or_insert(e);
+                            }
+                        }
+                    }
                 }
 
-                let task_config = aggregator
-                    .get_task_config_for(&task_id)
-                    .await?
-                    .ok_or(DapAbort::UnrecognizedTask)?;
-
-                tracing::debug!("RUNNING run_collect_job FOR TID {task_id} AND {coll_job_id} AND {batch_sel:?} AND {agg_param:?} AND {host}");
-                let collected = run_coll_job(
-                    aggregator,
-                    &task_id,
-                    task_config.as_ref(),
-                    &coll_job_id,
-                    &batch_sel,
-                    &agg_param,
-                )
-                .await?;
-
-                if collected > 0 {
-                    telem.reports_collected += collected;
-                } else {
-                    pending_coll_jobs.push(WorkItem::CollectionJob {
-                        task_id,
-                        coll_job_id,
-                        batch_sel,
-                        agg_param,
-                    });
+                let res = async {
+                    let task_config = aggregator
+                        .get_task_config_for(&task_id)
+                        .await?
+                        .ok_or(DapAbort::UnrecognizedTask)?;
+
+                    tracing::debug!("RUNNING run_collect_job FOR TID {task_id} AND {coll_job_id} AND {batch_sel:?} AND {agg_param:?} AND {host}");
+                    run_coll_job(
+                        aggregator,
+                        &task_id,
+                        task_config.as_ref(),
+                        &coll_job_id,
+                        &batch_sel,
+                        &agg_param,
+                        collector_hpke_config_id,
+                    )
+                    .await
+                }
+                .await;
+
+                // If the batch isn't ready yet, or collecting it failed transiently, then put the
+                // collection job back in the queue to try again later.
+                let retry = match res {
+                    Ok(0) => true,
+                    Ok(collected) => {
+                        telem.reports_collected += collected;
+                        false
+                    }
+                    Err(e) => {
+                        let retry = e.is_retryable();
+                        first_err.get_or_insert(e);
+                        retry
+                    }
+                };
+                if retry && requeues >= MAX_COLL_JOB_REQUEUES {
+                    tracing::warn!(
+                        %task_id,
+                        %coll_job_id,
+                        requeues,
+                        "collection job was requeued too many times; leaving it pending"
+                    );
+                } else if retry {
+                    aggregator
+                        .enqueue_work(vec![WorkItem::CollectionJob {
+                            task_id,
+                            coll_job_id,
+                            batch_sel,
+                            agg_param,
+                            collector_hpke_config_id,
+                            requeues: requeues + 1,
+                        }])
+                        .await?;
                 }
+                aggregator.ack_work(vec![item_id]).await?;
             }
         }
     }
 
-    for (_task_id, mut agg_jobs_per_task) in agg_jobs {
-        telem.reports_aggregated +=
-            try_join_all(agg_jobs_per_task.drain(0..agg_jobs_per_task.len()))
-                .await?
-                .into_iter()
-                .sum::<u64>();
+    for (_task_id, agg_jobs_per_task) in agg_jobs {
+        for res in join_all(agg_jobs_per_task).await {
+            match res {
+                Ok(reports_aggregated) => telem.reports_aggregated += reports_aggregated,
+                Err(e) => {
+                    first_err.get_or_insert(e);
+                }
+            }
+        }
     }
 
-    // Put all pending collection jobs back in the queue.
-    aggregator.enqueue_work(pending_coll_jobs).await?;
-
-    Ok(telem)
+    match first_err {
+        Some(e) => Err(e),
+        None => Ok(()),
+    }
 }
 
 fn check_response_content_type(resp: &DapResponse, expected: DapMediaType) -> Result<(), DapError> {
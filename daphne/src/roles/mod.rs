@@ -10,7 +10,7 @@ pub mod leader;
 use crate::{
     constants::DapMediaType,
     messages::{Base64Encode, Query, ReportMetadata, TaskId, Time},
-    taskprov, DapAbort, DapError, DapQueryConfig, DapRequest, DapTaskConfig,
+    taskprov, DapAbort, DapAggregationParam, DapError, DapQueryConfig, DapRequest, DapTaskConfig,
 };
 use tracing::warn;
 
@@ -41,7 +41,20 @@ async fn check_batch<S: Sync>(
     // Check that the batch boundaries are valid.
     match (&task_config.query, query) {
         (DapQueryConfig::TimeInterval { .. }, Query::TimeInterval { batch_interval }) => {
-            if batch_interval.start % task_config.time_precision != 0
+            let batch_interval = if task_config.align_batch_intervals {
+                batch_interval
+                    .align_to(task_config)
+                    .ok_or_else(|| DapAbort::BatchInvalid {
+                        detail: format!(
+                            "The queried batch interval ({batch_interval:?}) cannot be aligned."
+                        ),
+                        task_id: *task_id,
+                    })?
+            } else {
+                batch_interval.clone()
+            };
+
+            if batch_interval.start != task_config.quantized_time_lower_bound(batch_interval.start)
                 || batch_interval.duration % task_config.time_precision != 0
                 || batch_interval.duration < task_config.time_precision
             {
@@ -83,10 +96,16 @@ async fn check_batch<S: Sync>(
         _ => return Err(DapAbort::query_mismatch(task_id, &task_config.query, query).into()),
     };
 
-    // Check that the batch does not overlap with any previously collected batch.
+    // Check that the batch does not overlap with any batch previously collected under the same
+    // aggregation parameter.
     if let Some(batch_sel) = query.clone().into_batch_sel() {
-        if agg.is_batch_overlapping(task_id, &batch_sel).await? {
-            return Err(DapAbort::batch_overlap(task_id, query).into());
+        let agg_param =
+            DapAggregationParam::decode_for_task(task_id, &task_config.vdaf, agg_param)?;
+        let overlapping = agg
+            .get_overlapping_buckets(task_id, &batch_sel, &agg_param)
+            .await?;
+        if !overlapping.is_empty() {
+            return Err(DapAbort::batch_overlap(task_id, query, &overlapping).into());
         }
     }
 
@@ -137,6 +156,8 @@ async fn resolve_taskprov<S: Sync>(
         return Ok(());
     };
 
+    task_config.validate(task_id, agg.get_global_config(), agg.get_current_time())?;
+
     // This is the opt-in / opt-out decision point.
     if let Some(reason) = agg.taskprov_opt_out_reason(&task_config)? {
         return Err(DapError::Abort(DapAbort::InvalidTask {
@@ -145,43 +166,72 @@ async fn resolve_taskprov<S: Sync>(
         }));
     }
 
+    if agg.get_global_config().taskprov_vdaf_self_test {
+        task_config.vdaf.self_test(task_id)?;
+    }
+
     agg.taskprov_put(req, task_config).await?;
     Ok(())
 }
 
 #[cfg(test)]
 mod test {
-    use super::{aggregator, helper, leader, DapAggregator, DapAuthorizedSender, DapLeader};
+    use super::{
+        aggregator, helper, leader, DapAggregator, DapAuthorizedSender, DapHelper, DapLeader,
+    };
     use crate::{
-        assert_metrics_include, async_test_versions,
+        assert_metrics_include, async_test_version, async_test_versions,
         auth::BearerToken,
         constants::DapMediaType,
-        hpke::{HpkeDecrypter, HpkeKemId, HpkeReceiverConfig},
+        error::{ConfigError, PeerError},
+        fatal_error,
+        hpke::{HpkeConfig, HpkeDecrypter, HpkeKemId, HpkeReceiverConfig},
         messages::{
-            AggregateShareReq, AggregationJobContinueReq, AggregationJobInitReq,
-            AggregationJobResp, Base64Encode, BatchId, BatchSelector, Collection, CollectionJobId,
-            CollectionReq, Extension, HpkeCiphertext, Interval, PartialBatchSelector, Query,
-            Report, ReportId, ReportMetadata, TaskId, Time, Transition, TransitionFailure,
-            TransitionVar,
+            encode_u32_bytes, AggregateShare, AggregateShareReq, AggregationJobContinueReq,
+            AggregationJobInitReq, AggregationJobResp, AggregationJobRespChunk,
+            AggregationJobRespChunkReq, Base64Encode, BatchId, BatchSelector, Collection,
+            CollectionJobId, CollectionReq, Duration, Extension, HpkeCiphertext, HpkeConfigList,
+            Interval, PartialBatchSelector, Query, Report, ReportId, ReportMetadata, TaskId, Time,
+            Transition, TransitionFailure, TransitionVar,
+        },
+        roles::{
+            aggregator::CollectionPreview,
+            leader::{
+                AggregationJobCheckpoint, UploadDedupCache, WorkItem, WorkItemId, WorkQueueStats,
+                WorkQueueTaskStats, WorkQueueWatermarks,
+            },
         },
-        roles::leader::WorkItem,
         test_versions,
-        testing::{AggStore, MockAggregator},
-        vdaf::{mastic::MasticWeight, MasticWeightConfig, Prio3Config, VdafConfig},
-        DapAbort, DapAggregateShare, DapAggregationJobState, DapAggregationParam, DapBatchBucket,
-        DapCollectionJob, DapError, DapGlobalConfig, DapLeaderAggregationJobTransition,
-        DapMeasurement, DapQueryConfig, DapRequest, DapResource, DapTaskConfig, DapTaskParameters,
-        DapVersion, MetaAggregationJobId,
+        testing::{
+            AggStore, Clock, MockAggregator, MockClock, MockLeaderMemory, SystemClock,
+            MAX_FINISHED_AGG_JOBS_PER_TASK,
+        },
+        vdaf::{
+            mastic::MasticWeight, MasticWeightConfig, Prio3Config, VdafAggregateShare, VdafConfig,
+            VDAF_VERSION,
+        },
+        DapAbort, DapAggregateResult, DapAggregateShare, DapAggregationJobState,
+        DapAggregationJobStatus, DapAggregationParam, DapBatchBucket, DapCollectionJob,
+        DapCollectionJobInfo, DapError, DapGlobalConfig, DapLeaderAggregationJobTransition,
+        DapLeaderProcessTelemetry, DapMeasurement, DapQueryConfig, DapRequest, DapResource,
+        DapRetainedAggShare, DapTaskConfig, DapTaskParameters, DapVersion, MetaAggregationJobId,
     };
     use assert_matches::assert_matches;
+    use futures::StreamExt;
     use matchit::Router;
     use prio::{
-        codec::{Decode, Encode, ParameterizedDecode, ParameterizedEncode},
+        codec::{encode_u32_items, Decode, Encode, ParameterizedDecode, ParameterizedEncode},
+        field::Field64,
         idpf::IdpfInput,
         vdaf::poplar1::Poplar1AggregationParam,
     };
     use rand::{thread_rng, Rng};
-    use std::{collections::HashMap, sync::Arc, time::SystemTime, vec};
+    use std::{
+        collections::{HashMap, HashSet},
+        sync::{Arc, Mutex},
+        time::SystemTime,
+        vec,
+    };
     use url::Url;
 
     fn empty_report_extensions_for_version(version: DapVersion) -> Option<Vec<Extension>> {
@@ -235,6 +285,9 @@ mod test {
         taskprov_vdaf_verify_key_init: [u8; 32],
         taskprov_leader_token: BearerToken,
         leader_registry: prometheus::Registry,
+        pub clock: Arc<dyn Clock>,
+        /// The Leader's report storage epoch duration and maximum future time skew, if any.
+        pub report_storage_bounds: Option<(Duration, Duration)>,
     }
 
     impl TestData {
@@ -255,6 +308,19 @@ mod test {
                 max_batch_interval_end: 259_200,
                 supported_hpke_kems: vec![HpkeKemId::X25519HkdfSha256],
                 allow_taskprov: true,
+                agg_share_idempotency_window: 3600,
+                revoked_hpke_config_ids: Vec::new(),
+                upload_dedup_cache_capacity: 0,
+                max_concurrent_agg_jobs_per_task: 0,
+                eager_agg_threshold: None,
+                max_report_shares_per_agg_job: None,
+                agg_job_resp_chunk_size: None,
+                work_item_visibility_timeout: None,
+                agg_job_retry_budget: 0,
+                agg_job_retry_backoff: 0,
+                report_debug_sample_rate: 0.0,
+                taskprov_vdaf_self_test: false,
+                allow_http_urls: true,
             };
 
             // Task Parameters that the Leader and Helper must agree on.
@@ -275,9 +341,12 @@ mod test {
                 DapTaskConfig {
                     version,
                     collector_hpke_config: collector_hpke_receiver_config.config.clone(),
+                    collector_hpke_config_allowlist: Vec::new(),
                     leader_url: leader_url.clone(),
                     helper_url: helper_url.clone(),
                     time_precision: Self::TASK_TIME_PRECISION,
+                    time_quantization_offset: 0,
+                    align_batch_intervals: false,
                     expiration: now + Self::TASK_TIME_PRECISION,
                     min_batch_size: 1,
                     query: DapQueryConfig::TimeInterval,
@@ -291,9 +360,12 @@ mod test {
                 DapTaskConfig {
                     version,
                     collector_hpke_config: collector_hpke_receiver_config.config.clone(),
+                    collector_hpke_config_allowlist: Vec::new(),
                     leader_url: leader_url.clone(),
                     helper_url: helper_url.clone(),
                     time_precision: Self::TASK_TIME_PRECISION,
+                    time_quantization_offset: 0,
+                    align_batch_intervals: false,
                     expiration: now + Self::TASK_TIME_PRECISION,
                     min_batch_size: 1,
                     query: DapQueryConfig::FixedSize {
@@ -309,9 +381,12 @@ mod test {
                 DapTaskConfig {
                     version,
                     collector_hpke_config: collector_hpke_receiver_config.config.clone(),
+                    collector_hpke_config_allowlist: Vec::new(),
                     leader_url: leader_url.clone(),
                     helper_url: helper_url.clone(),
                     time_precision: Self::TASK_TIME_PRECISION,
+                    time_quantization_offset: 0,
+                    align_batch_intervals: false,
                     expiration: now, // Expires this second
                     min_batch_size: 1,
                     query: DapQueryConfig::TimeInterval,
@@ -324,15 +399,19 @@ mod test {
             let mastic = VdafConfig::Mastic {
                 input_size: 1,
                 weight_config: MasticWeightConfig::Count,
+                fixed_agg_param: false,
             };
             tasks.insert(
                 heavy_hitters_task_id,
                 DapTaskConfig {
                     version,
                     collector_hpke_config: collector_hpke_receiver_config.config.clone(),
+                    collector_hpke_config_allowlist: Vec::new(),
                     leader_url,
                     helper_url,
                     time_precision: Self::TASK_TIME_PRECISION,
+                    time_quantization_offset: 0,
+                    align_batch_intervals: false,
                     expiration: now + Self::TASK_TIME_PRECISION,
                     min_batch_size: 10,
                     query: DapQueryConfig::TimeInterval,
@@ -384,6 +463,8 @@ mod test {
                 collector_hpke_receiver_config,
                 taskprov_vdaf_verify_key_init,
                 leader_registry,
+                clock: Arc::new(SystemClock),
+                report_storage_bounds: None,
             }
         }
 
@@ -398,9 +479,12 @@ mod test {
                 DapTaskConfig {
                     version,
                     collector_hpke_config: self.collector_hpke_receiver_config.config.clone(),
+                    collector_hpke_config_allowlist: Vec::new(),
                     leader_url,
                     helper_url,
                     time_precision: Self::TASK_TIME_PRECISION,
+                    time_quantization_offset: 0,
+                    align_batch_intervals: false,
                     expiration: self.now + Self::TASK_TIME_PRECISION,
                     min_batch_size: 1,
                     query: DapQueryConfig::TimeInterval,
@@ -413,36 +497,52 @@ mod test {
         }
 
         pub fn new_helper(&self) -> Arc<MockAggregator> {
-            Arc::new(MockAggregator::new_helper(
-                self.tasks.clone(),
-                self.global_config
-                    .gen_hpke_receiver_config_list(thread_rng().gen())
-                    .expect("failed to generate HPKE receiver config"),
-                self.global_config.clone(),
-                self.leader_token.clone(),
-                self.collector_hpke_receiver_config.config.clone(),
-                &self.helper_registry,
-                self.taskprov_vdaf_verify_key_init,
-                self.taskprov_leader_token.clone(),
-            ))
+            Arc::new(
+                MockAggregator::new_helper(
+                    self.tasks.clone(),
+                    self.global_config
+                        .gen_hpke_receiver_config_list(thread_rng().gen())
+                        .expect("failed to generate HPKE receiver config"),
+                    self.global_config.clone(),
+                    self.leader_token.clone(),
+                    self.collector_hpke_receiver_config.config.clone(),
+                    &self.helper_registry,
+                    self.taskprov_vdaf_verify_key_init,
+                    self.taskprov_leader_token.clone(),
+                )
+                .unwrap()
+                .with_clock(Arc::clone(&self.clock)),
+            )
         }
 
         pub fn with_leader(self, helper: Arc<MockAggregator>) -> Test {
-            let leader = Arc::new(MockAggregator::new_leader(
-                self.tasks,
-                self.global_config
-                    .gen_hpke_receiver_config_list(thread_rng().gen())
-                    .expect("failed to generate HPKE receiver config"),
-                self.global_config,
-                self.leader_token,
-                self.collector_token.clone(),
-                self.collector_hpke_receiver_config.config.clone(),
-                &self.leader_registry,
-                self.taskprov_vdaf_verify_key_init,
-                self.taskprov_leader_token,
-                self.taskprov_collector_token.clone(),
-                Arc::clone(&helper),
-            ));
+            let leader = Arc::new(
+                MockAggregator::new_leader(
+                    self.tasks,
+                    self.global_config
+                        .gen_hpke_receiver_config_list(thread_rng().gen())
+                        .expect("failed to generate HPKE receiver config"),
+                    self.global_config,
+                    self.leader_token,
+                    self.collector_token.clone(),
+                    self.collector_hpke_receiver_config.config.clone(),
+                    &self.leader_registry,
+                    self.taskprov_vdaf_verify_key_init,
+                    self.taskprov_leader_token,
+                    self.taskprov_collector_token.clone(),
+                    Arc::clone(&helper),
+                )
+                .unwrap()
+                .with_clock(self.clock),
+            );
+            let leader = match self.report_storage_bounds {
+                Some((epoch_duration, max_future_time_skew)) => Arc::new(
+                    Arc::into_inner(leader)
+                        .unwrap()
+                        .with_report_storage_bounds(epoch_duration, max_future_time_skew),
+                ),
+                None => leader,
+            };
 
             Test {
                 now: self.now,
@@ -655,6 +755,16 @@ mod test {
             &self,
             task_id: &TaskId,
             measurement: DapMeasurement,
+        ) -> Report {
+            self.gen_test_report_for_measurement_at(task_id, measurement, self.now)
+                .await
+        }
+
+        pub async fn gen_test_report_for_measurement_at(
+            &self,
+            task_id: &TaskId,
+            measurement: DapMeasurement,
+            time: Time,
         ) -> Report {
             let task_config = self.leader.unchecked_get_task_config(task_id).await;
 
@@ -678,7 +788,7 @@ mod test {
                 .vdaf
                 .produce_report(
                     &hpke_config_list,
-                    self.now,
+                    time,
                     task_id,
                     measurement,
                     task_config.version,
@@ -842,6 +952,35 @@ mod test {
 
     async_test_versions! { handle_agg_job_init_req_unauthorized_request }
 
+    async fn handle_agg_job_init_req_vdaf_version_mismatch(version: DapVersion) {
+        let t = Test::new(version);
+        let report = t.gen_test_report(&t.time_interval_task_id).await;
+        let (_, mut req) = t
+            .gen_test_agg_job_init_req(
+                &t.time_interval_task_id,
+                version,
+                DapAggregationParam::Empty,
+                vec![report],
+            )
+            .await;
+
+        // Expect failure due to the Leader running an incompatible VDAF implementation.
+        req.vdaf_version = Some("prio/0.0".into());
+        assert_matches!(
+            helper::handle_agg_job_req(&*t.helper, &req).await,
+            Err(DapError::Abort(DapAbort::VdafVersionMismatch { task_id, .. }))
+                if task_id == t.time_interval_task_id
+        );
+        assert_eq!(t.helper.audit_log.invocations(), 0);
+
+        // Expect success if the versions match.
+        req.vdaf_version = Some(VDAF_VERSION.into());
+        helper::handle_agg_job_req(&*t.helper, &req).await.unwrap();
+        assert_eq!(t.helper.audit_log.invocations(), 1);
+    }
+
+    async_test_versions! { handle_agg_job_init_req_vdaf_version_mismatch }
+
     async fn handle_hpke_config_req_unrecognized_task(version: DapVersion) {
         let t = Test::new(version);
         let mut rng = thread_rng();
@@ -885,6 +1024,50 @@ mod test {
 
     async_test_versions! { handle_hpke_config_req_missing_task_id }
 
+    async fn handle_hpke_config_req(version: DapVersion) {
+        let t = Test::new(version);
+        let task_id = t.time_interval_task_id;
+        let req = DapRequest {
+            version,
+            media_type: DapMediaType::HpkeConfigList,
+            task_id: Some(task_id),
+            resource: DapResource::Undefined,
+            payload: Vec::new(),
+            ..Default::default()
+        };
+
+        let resp = aggregator::handle_hpke_config_req(&*t.leader, &req, Some(task_id))
+            .await
+            .unwrap();
+        let want = t
+            .leader
+            .get_hpke_config_for(version, Some(&task_id))
+            .await
+            .unwrap()
+            .as_ref()
+            .clone();
+        assert_eq!(resp.media_type, DapMediaType::HpkeConfigList);
+        match version {
+            DapVersion::Draft02 => {
+                assert_eq!(HpkeConfig::get_decoded(&resp.payload).unwrap(), want);
+            }
+            DapVersion::DraftLatest => {
+                assert_eq!(
+                    resp.media_type.as_str_for_version(version),
+                    Some("application/dap-hpke-config-list")
+                );
+                assert_eq!(
+                    HpkeConfigList::get_decoded(&resp.payload).unwrap(),
+                    HpkeConfigList {
+                        hpke_configs: vec![want],
+                    }
+                );
+            }
+        }
+    }
+
+    async_test_versions! { handle_hpke_config_req }
+
     async fn handle_agg_job_cont_req_unauthorized_request(version: DapVersion) {
         let t = Test::new(version);
         let agg_job_id = MetaAggregationJobId::gen_for_version(version);
@@ -1003,6 +1186,66 @@ mod test {
 
     async_test_versions! { handle_agg_share_req_invalid_batch_sel }
 
+    // Test that the Helper enforces the same batch interval bounds as the Leader does for
+    // collection jobs.
+    async fn handle_agg_share_req_fail_invalid_batch_interval(version: DapVersion) {
+        let t = Test::new(version);
+        let task_id = &t.time_interval_task_id;
+        let task_config = t.leader.unchecked_get_task_config(task_id).await;
+        let agg_share_req = |batch_interval| AggregateShareReq {
+            draft02_task_id: task_id.for_request_payload(&version),
+            batch_sel: BatchSelector::TimeInterval { batch_interval },
+            agg_param: Vec::default(),
+            report_count: 0,
+            checksum: [0; 32],
+        };
+
+        // Leader: Send an AggregateShareReq with a very large batch interval.
+        let req = t
+            .leader_authorized_req(
+                task_id,
+                &task_config,
+                None,
+                DapMediaType::AggregateShareReq,
+                agg_share_req(Interval {
+                    start: task_config.quantized_time_lower_bound(t.now),
+                    duration: t.helper.global_config.max_batch_duration
+                        + task_config.time_precision,
+                }),
+            )
+            .await;
+        assert_matches!(
+            helper::handle_agg_share_req(&*t.helper, &req)
+                .await
+                .unwrap_err(),
+            DapError::Abort(DapAbort::BadRequest(s)) => assert_eq!(s, "batch interval too large")
+        );
+
+        // Leader: Send an AggregateShareReq with a batch interval in the past.
+        let req = t
+            .leader_authorized_req(
+                task_id,
+                &task_config,
+                None,
+                DapMediaType::AggregateShareReq,
+                agg_share_req(Interval {
+                    start: task_config.quantized_time_lower_bound(t.now)
+                        - t.helper.global_config.min_batch_interval_start
+                        - task_config.time_precision,
+                    duration: task_config.time_precision * 2,
+                }),
+            )
+            .await;
+        assert_matches!(
+            helper::handle_agg_share_req(&*t.helper, &req)
+                .await
+                .unwrap_err(),
+            DapError::Abort(DapAbort::BadRequest(s)) => assert_eq!(s, "batch interval too far into past")
+        );
+    }
+
+    async_test_versions! { handle_agg_share_req_fail_invalid_batch_interval }
+
     async fn handle_coll_job_req_unauthorized_request(version: DapVersion) {
         let mut rng = thread_rng();
         let t = Test::new(version);
@@ -1073,58 +1316,88 @@ mod test {
 
     async_test_versions! { handle_agg_job_req_failure_hpke_decrypt_error }
 
-    async fn handle_agg_job_req_transition_continue(version: DapVersion) {
+    async fn handle_agg_job_req_failure_hpke_decrypt_error_counted(version: DapVersion) {
         let t = Test::new(version);
         let task_id = &t.time_interval_task_id;
 
-        let report = t.gen_test_report(task_id).await;
+        let mut report = t.gen_test_report(task_id).await;
+        report.encrypted_input_shares[1].payload[0] ^= 0xff; // Cause decryption to fail
+        let config_id = report.encrypted_input_shares[1].config_id;
+
         let (_, req) = t
             .gen_test_agg_job_init_req(task_id, version, DapAggregationParam::Empty, vec![report])
             .await;
+        helper::handle_agg_job_req(&*t.helper, &req).await.unwrap();
 
-        // Get AggregationJobResp and then extract the transition data from inside.
-        let agg_job_resp = AggregationJobResp::get_decoded(
-            &helper::handle_agg_job_req(&*t.helper, &req)
-                .await
-                .unwrap()
-                .payload,
-        )
-        .unwrap();
-        let transition = &agg_job_resp.transitions[0];
-
-        // Expect success due to valid ciphertext.
-        assert_matches!(transition.var, TransitionVar::Continued(_));
+        assert_metrics_include!(t.helper_registry, {
+            (format!(r#"hpke_decrypt_failure_counter{{config_id="{config_id}",env="test_helper",host="helper.org"}}"#)): 1,
+        });
     }
 
-    async_test_versions! { handle_agg_job_req_transition_continue }
-
-    async fn handle_agg_job_req_failure_report_replayed(version: DapVersion) {
-        let t = Test::new(version);
+    async_test_versions! { handle_agg_job_req_failure_hpke_decrypt_error_counted }
+
+    async fn handle_agg_job_req_failure_hpke_config_revoked(version: DapVersion) {
+        let mut data = TestData::new(version);
+        let revoked_hpke_receiver_config =
+            HpkeReceiverConfig::gen(1, HpkeKemId::X25519HkdfSha256).unwrap();
+        let hpke_receiver_config = HpkeReceiverConfig::gen(2, HpkeKemId::X25519HkdfSha256).unwrap();
+        data.global_config.revoked_hpke_config_ids = vec![revoked_hpke_receiver_config.config.id];
+        let helper = Arc::new(
+            MockAggregator::new_helper(
+                data.tasks.clone(),
+                [
+                    revoked_hpke_receiver_config.clone(),
+                    hpke_receiver_config.clone(),
+                ],
+                data.global_config.clone(),
+                data.leader_token.clone(),
+                data.collector_hpke_receiver_config.config.clone(),
+                &data.helper_registry,
+                data.taskprov_vdaf_verify_key_init,
+                data.taskprov_leader_token.clone(),
+            )
+            .unwrap(),
+        );
+        let t = data.with_leader(helper);
         let task_id = &t.time_interval_task_id;
+        let task_config = t.leader.unchecked_get_task_config(task_id).await;
 
-        let report = t.gen_test_report(task_id).await;
-        let (leader_state, req) = t
-            .gen_test_agg_job_init_req(
+        // The revoked HPKE config is not advertised.
+        assert_eq!(
+            t.helper
+                .get_hpke_config_for(version, Some(task_id))
+                .await
+                .unwrap()
+                .id,
+            hpke_receiver_config.config.id
+        );
+        assert!(!t
+            .helper
+            .can_hpke_decrypt(task_id, revoked_hpke_receiver_config.config.id)
+            .await
+            .unwrap());
+
+        // A report encrypted under the revoked HPKE config is rejected.
+        let report = task_config
+            .vdaf
+            .produce_report(
+                &[
+                    t.leader
+                        .get_hpke_config_for(version, Some(task_id))
+                        .await
+                        .unwrap()
+                        .clone(),
+                    revoked_hpke_receiver_config.config,
+                ],
+                t.now,
                 task_id,
+                DapMeasurement::U64(1),
                 version,
-                DapAggregationParam::Empty,
-                vec![report.clone()],
             )
+            .unwrap();
+        let (_, req) = t
+            .gen_test_agg_job_init_req(task_id, version, DapAggregationParam::Empty, vec![report])
             .await;
-
-        // Add dummy data to report store backend. This is done in a new scope so that the lock on the
-        // report store is released before running the test.
-        {
-            let mut guard = t
-                .helper
-                .report_store
-                .lock()
-                .expect("report_store: failed to lock");
-            let report_store = guard.entry(*task_id).or_default();
-            report_store.insert(report.report_metadata.id);
-        }
-
-        // Get AggregationJobResp and then extract the transition data from inside.
         let agg_job_resp = AggregationJobResp::get_decoded(
             &helper::handle_agg_job_req(&*t.helper, &req)
                 .await
@@ -1132,720 +1405,4631 @@ mod test {
                 .payload,
         )
         .unwrap();
-        let transitions = if version == DapVersion::Draft02 {
-            // in version 2 replays are only detected later.
-            let agg_job_id =
-                AggregationJobInitReq::get_decoded_with_param(&DapVersion::Draft02, &req.payload)
-                    .unwrap()
-                    .draft02_agg_job_id
-                    .unwrap();
-            let task_config = t.leader.unchecked_get_task_config(task_id).await;
-            let transition = task_config
-                .handle_agg_job_resp(
-                    task_id,
-                    &MetaAggregationJobId::Draft02(agg_job_id),
-                    leader_state,
-                    agg_job_resp,
-                    t.leader.metrics(),
-                )
-                .unwrap();
-            let DapLeaderAggregationJobTransition::Uncommitted(
-                _,
-                AggregationJobContinueReq { transitions, .. },
-            ) = transition
-            else {
-                panic!("expected uncommitted transition, was {transition:?}");
-            };
-            let req = t
-                .gen_test_agg_job_cont_req(
-                    task_id,
-                    &MetaAggregationJobId::Draft02(agg_job_id),
-                    transitions,
-                    version,
-                )
-                .await;
-            AggregationJobResp::get_decoded(
-                &helper::handle_agg_job_req(&*t.helper, &req)
-                    .await
-                    .unwrap()
-                    .payload,
-            )
-            .unwrap()
-            .transitions
-        } else {
-            agg_job_resp.transitions
-        };
+        assert_matches!(
+            agg_job_resp.transitions[0].var,
+            TransitionVar::Failed(TransitionFailure::HpkeUnknownConfigId)
+        );
+    }
 
-        // Expect failure due to report store marked as collected.
+    async_test_versions! { handle_agg_job_req_failure_hpke_config_revoked }
+
+    #[test]
+    fn new_helper_fails_on_duplicate_hpke_config_id() {
+        let data = TestData::new(DapVersion::DraftLatest);
+        let hpke_receiver_config = HpkeReceiverConfig::gen(1, HpkeKemId::X25519HkdfSha256).unwrap();
+        let other_hpke_receiver_config =
+            HpkeReceiverConfig::gen(1, HpkeKemId::P256HkdfSha256).unwrap();
+        let Err(err) = MockAggregator::new_helper(
+            data.tasks.clone(),
+            [hpke_receiver_config.clone(), other_hpke_receiver_config],
+            data.global_config.clone(),
+            data.leader_token.clone(),
+            data.collector_hpke_receiver_config.config.clone(),
+            &data.helper_registry,
+            data.taskprov_vdaf_verify_key_init,
+            data.taskprov_leader_token.clone(),
+        ) else {
+            panic!("expected construction to fail");
+        };
         assert_matches!(
-            transitions[0].var,
-            TransitionVar::Failed(TransitionFailure::ReportReplayed)
+            err,
+            DapError::Config(ConfigError::DuplicateHpkeConfigId { config_id: 1 })
         );
 
-        assert_metrics_include!(t.helper_registry, {
-            r#"report_counter{env="test_helper",host="helper.org",status="rejected_report_replayed"}"#: 1,
-            r#"inbound_request_counter{env="test_helper",host="helper.org",type="aggregate"}"#: if version == DapVersion::Draft02 { 2 } else { 1 },
-            r#"aggregation_job_counter{env="test_helper",host="helper.org",status="started"}"#: 1,
-        });
+        // Rotating in a config with an ID that is already in use fails as well.
+        let helper = data.new_helper();
+        let config_id = helper.hpke_receiver_config_list.lock().unwrap()[0]
+            .receiver
+            .config
+            .id;
+        assert_matches!(
+            helper.rotate_hpke_receiver_config(
+                HpkeReceiverConfig::gen(config_id, HpkeKemId::X25519HkdfSha256).unwrap()
+            ),
+            Err(DapError::Config(ConfigError::DuplicateHpkeConfigId { .. }))
+        );
     }
 
-    async_test_versions! { handle_agg_job_req_failure_report_replayed }
-
-    async fn handle_agg_job_req_failure_batch_collected(version: DapVersion) {
-        let t = Test::new(version);
+    async fn handle_agg_job_req_hpke_config_rotated(version: DapVersion) {
+        let mut data = TestData::new(version);
+        let clock = Arc::new(MockClock::new(data.now));
+        data.clock = clock.clone();
+        let helper = data.new_helper();
+        let t = data.with_leader(helper);
         let task_id = &t.time_interval_task_id;
-        let task_config = t.helper.unchecked_get_task_config(task_id).await;
 
+        // A Client encrypts its report under the config advertised before the rotation.
+        let old_hpke_config = t
+            .helper
+            .get_hpke_config_for(version, Some(task_id))
+            .await
+            .unwrap();
         let report = t.gen_test_report(task_id).await;
+
+        clock.advance(3600);
+        let new_hpke_config_id = {
+            let hpke_receiver_config_list = t.helper.hpke_receiver_config_list.lock().unwrap();
+            (0..=u8::MAX)
+                .find(|id| {
+                    hpke_receiver_config_list
+                        .iter()
+                        .all(|config| config.receiver.config.id != *id)
+                })
+                .unwrap()
+        };
+        let new_hpke_receiver_config =
+            HpkeReceiverConfig::gen(new_hpke_config_id, HpkeKemId::X25519HkdfSha256).unwrap();
+        t.helper
+            .rotate_hpke_receiver_config(new_hpke_receiver_config.clone())
+            .unwrap();
+        t.helper.retire_hpke_receiver_config(old_hpke_config.id);
+
+        // The newest config is advertised, but the old one can still be used for decryption.
+        assert_eq!(
+            t.helper
+                .get_hpke_config_for(version, Some(task_id))
+                .await
+                .unwrap(),
+            new_hpke_receiver_config.config
+        );
+        assert!(t
+            .helper
+            .can_hpke_decrypt(task_id, old_hpke_config.id)
+            .await
+            .unwrap());
+
         let (_, req) = t
             .gen_test_agg_job_init_req(task_id, version, DapAggregationParam::Empty, vec![report])
             .await;
+        let agg_job_resp = AggregationJobResp::get_decoded(
+            &helper::handle_agg_job_req(&*t.helper, &req)
+                .await
+                .unwrap()
+                .payload,
+        )
+        .unwrap();
+        assert_matches!(agg_job_resp.transitions[0].var, TransitionVar::Continued(_));
+    }
 
-        // Add mock data to the aggreagte store backend. This is done in its own scope so that the lock
-        // is released before running the test. Otherwise the test will deadlock.
-        {
-            let mut guard = t
-                .helper
-                .agg_store
-                .lock()
-                .expect("agg_store: failed to lock");
-            let agg_store = guard.entry(*task_id).or_default();
+    async_test_versions! { handle_agg_job_req_hpke_config_rotated }
+
+    // Test that a Helper that splits its aggregation job response into chunks sends the same
+    // transitions as one that doesn't.
+    async fn handle_agg_job_req_chunked_resp(version: DapVersion) {
+        const NUM_REPORTS: usize = 5;
+        const CHUNK_SIZE: usize = 2;
+        let mut data = TestData::new(version);
+        let unchunked_helper = data.new_helper();
+        data.helper_registry = prometheus::Registry::new();
+        data.global_config.agg_job_resp_chunk_size = Some(CHUNK_SIZE);
+        let helper = data.new_helper();
+        let t = data.with_leader(helper);
+        let task_id = &t.time_interval_task_id;
+        let task_config = t.leader.unchecked_get_task_config(task_id).await;
 
-            agg_store.insert(
-                DapBatchBucket::TimeInterval {
-                    batch_window: task_config.quantized_time_lower_bound(t.now),
-                },
-                AggStore {
-                    agg_share: DapAggregateShare::default(),
-                    collected: true,
-                },
-            );
+        // Both Helpers need to be able to decrypt the reports.
+        *unchunked_helper.hpke_receiver_config_list.lock().unwrap() =
+            t.helper.hpke_receiver_config_list.lock().unwrap().clone();
+
+        let mut reports = Vec::with_capacity(NUM_REPORTS);
+        for _ in 0..NUM_REPORTS {
+            reports.push(t.gen_test_report(task_id).await);
         }
+        let (_, req) = t
+            .gen_test_agg_job_init_req(task_id, version, DapAggregationParam::Empty, reports)
+            .await;
+        let DapResource::AggregationJob(agg_job_id) = req.resource else {
+            panic!("unexpected resource: {:?}", req.resource);
+        };
+        let agg_job_id = MetaAggregationJobId::from(&agg_job_id);
 
-        // Get AggregationJobResp and then extract the transition data from inside.
-        let agg_job_resp = AggregationJobResp::get_decoded(
-            &helper::handle_agg_job_req(&*t.helper, &req)
+        let resp = helper::handle_agg_job_req(&*unchunked_helper, &req)
+            .await
+            .unwrap();
+        assert_eq!(resp.media_type, DapMediaType::AggregationJobResp);
+        let want = AggregationJobResp::get_decoded(&resp.payload).unwrap();
+        assert_eq!(want.transitions.len(), NUM_REPORTS);
+
+        let mut resp = helper::handle_agg_job_req(&*t.helper, &req).await.unwrap();
+        let mut got = Vec::new();
+        loop {
+            assert_eq!(resp.media_type, DapMediaType::AggregationJobRespChunk);
+            let chunk = AggregationJobRespChunk::get_decoded(&resp.payload).unwrap();
+            assert!(chunk.transitions.len() <= CHUNK_SIZE);
+            got.extend(chunk.transitions);
+
+            let Some(continuation_token) = chunk.continuation_token else {
+                break;
+            };
+            let chunk_req = t
+                .leader_authorized_req(
+                    task_id,
+                    &task_config,
+                    Some(&agg_job_id),
+                    DapMediaType::AggregationJobRespChunkReq,
+                    AggregationJobRespChunkReq { continuation_token },
+                )
+                .await;
+            resp = helper::handle_agg_job_req(&*t.helper, &chunk_req)
                 .await
-                .unwrap()
-                .payload,
-        )
-        .unwrap();
-        let transition = &agg_job_resp.transitions[0];
+                .unwrap();
+        }
+        assert_eq!(got, want.transitions);
+    }
 
-        assert_eq!(t.helper.audit_log.invocations(), 1);
+    async_test_version! { handle_agg_job_req_chunked_resp, DraftLatest }
 
-        // Expect failure due to report store marked as collected.
-        assert_matches!(
-            transition.var,
-            TransitionVar::Failed(TransitionFailure::BatchCollected)
-        );
+    /// Captures the output of a `tracing_subscriber::fmt` subscriber.
+    #[derive(Clone, Default)]
+    struct CapturedLogs(Arc<Mutex<Vec<u8>>>);
 
-        assert_metrics_include!(t.helper_registry, {
-            r#"report_counter{env="test_helper",host="helper.org",status="rejected_batch_collected"}"#: 1,
-            r#"inbound_request_counter{env="test_helper",host="helper.org",type="aggregate"}"#: 1,
-            r#"aggregation_job_counter{env="test_helper",host="helper.org",status="started"}"#: 1,
-        });
+    impl std::io::Write for CapturedLogs {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
     }
 
-    async_test_versions! { handle_agg_job_req_failure_batch_collected }
+    impl<'a> tracing_subscriber::fmt::MakeWriter<'a> for CapturedLogs {
+        type Writer = Self;
 
-    #[tokio::test]
-    async fn handle_agg_job_req_abort_helper_state_overwritten_draft02() {
-        let t = Test::new(DapVersion::Draft02);
-        let task_id = &t.time_interval_task_id;
+        fn make_writer(&'a self) -> Self::Writer {
+            self.clone()
+        }
+    }
+
+    // Test that logs emitted while handling an aggregation job carry the task and aggregation job
+    // IDs.
+    async fn handle_agg_job_req_logs_include_span_fields(version: DapVersion) {
+        let logs = CapturedLogs::default();
+        let _guard = tracing::subscriber::set_default(
+            tracing_subscriber::fmt()
+                .with_writer(logs.clone())
+                .with_max_level(tracing::Level::DEBUG)
+                .with_ansi(false)
+                .finish(),
+        );
 
+        let t = Test::new(version);
+        let task_id = &t.time_interval_task_id;
         let report = t.gen_test_report(task_id).await;
         let (_, req) = t
-            .gen_test_agg_job_init_req(
-                task_id,
-                DapVersion::Draft02,
-                DapAggregationParam::Empty,
-                vec![report],
-            )
+            .gen_test_agg_job_init_req(task_id, version, DapAggregationParam::Empty, vec![report])
             .await;
+        let agg_job_id = match (version, &req.resource) {
+            (DapVersion::Draft02, DapResource::Undefined) => MetaAggregationJobId::from(
+                AggregationJobInitReq::get_decoded_with_param(&version, &req.payload)
+                    .unwrap()
+                    .draft02_agg_job_id
+                    .unwrap(),
+            ),
+            (DapVersion::DraftLatest, DapResource::AggregationJob(agg_job_id)) => {
+                MetaAggregationJobId::from(*agg_job_id)
+            }
+            (_, resource) => panic!("unexpected resource: {resource:?}"),
+        };
 
-        // Send aggregate request.
-        let _ = helper::handle_agg_job_req(&*t.helper, &req).await;
-
-        assert_eq!(t.helper.audit_log.invocations(), 1);
+        helper::handle_agg_job_req(&*t.helper, &req).await.unwrap();
 
-        // Send another aggregate request.
-        let err = helper::handle_agg_job_req(&*t.helper, &req)
-            .await
-            .unwrap_err();
+        let logs = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+        let line = logs
+            .lines()
+            .find(|line| line.contains("initializing aggregation job"))
+            .unwrap_or_else(|| panic!("log line not found in {logs:?}"));
+        assert!(line.contains(&format!("task_id={task_id}")), "{line}");
+        assert!(line.contains(&format!("agg_job_id={agg_job_id}")), "{line}");
+    }
 
-        assert_eq!(t.helper.audit_log.invocations(), 1);
+    async_test_versions! { handle_agg_job_req_logs_include_span_fields }
 
-        // Expect failure due to overwriting existing helper state.
-        assert_matches!(err, DapError::Abort(DapAbort::BadRequest(e)) =>
-            assert_eq!(e, "unexpected message for aggregation job (already exists)")
+    /// Initialize an aggregation job with a few reports at the given debug sample rate. Returns
+    /// the IDs of the reports and the number of "sampled report share" log lines that mention
+    /// each of them.
+    async fn sampled_report_log_lines(
+        version: DapVersion,
+        report_debug_sample_rate: f64,
+    ) -> Vec<(ReportId, usize)> {
+        let logs = CapturedLogs::default();
+        let _guard = tracing::subscriber::set_default(
+            tracing_subscriber::fmt()
+                .with_writer(logs.clone())
+                .with_max_level(tracing::Level::INFO)
+                .with_ansi(false)
+                .finish(),
         );
-    }
 
-    async fn handle_agg_job_req_fail_send_cont_req(version: DapVersion) {
-        let t = Test::new(version);
-        let agg_job_id = MetaAggregationJobId::gen_for_version(version);
-        let req = t
-            .gen_test_agg_job_cont_req(
-                &t.time_interval_task_id,
-                &agg_job_id,
-                Vec::default(),
-                version,
-            )
+        let mut data = TestData::new(version);
+        data.global_config.report_debug_sample_rate = report_debug_sample_rate;
+        let helper = data.new_helper();
+        let t = data.with_leader(helper);
+        let task_id = &t.time_interval_task_id;
+        let mut reports = Vec::new();
+        for _ in 0..3 {
+            reports.push(t.gen_test_report(task_id).await);
+        }
+        let report_ids = reports
+            .iter()
+            .map(|report| report.report_metadata.id)
+            .collect::<Vec<_>>();
+        let (_, req) = t
+            .gen_test_agg_job_init_req(task_id, version, DapAggregationParam::Empty, reports)
             .await;
+        helper::handle_agg_job_req(&*t.helper, &req).await.unwrap();
+
+        let logs = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+        let sampled = logs
+            .lines()
+            .filter(|line| line.contains("sampled report share"))
+            .collect::<Vec<_>>();
+        report_ids
+            .into_iter()
+            .map(|report_id| {
+                let count = sampled
+                    .iter()
+                    .filter(|line| line.contains(&format!("report_id={report_id}")))
+                    .count();
+                (report_id, count)
+            })
+            .collect()
+    }
 
-        assert_eq!(t.helper.audit_log.invocations(), 0);
+    async fn report_debug_sample_rate_all(version: DapVersion) {
+        for (report_id, count) in sampled_report_log_lines(version, 1.0).await {
+            assert_eq!(count, 1, "report {report_id} not logged exactly once");
+        }
+    }
 
-        // Send aggregate continue request to helper.
-        let err = helper::handle_agg_job_req(&*t.helper, &req)
-            .await
-            .unwrap_err();
+    async_test_versions! { report_debug_sample_rate_all }
 
-        // Expect failure due to sending continue request before initialization request.
-        assert_matches!(
-            err,
-            DapError::Abort(DapAbort::UnrecognizedAggregationJob { .. })
-        );
+    async fn report_debug_sample_rate_none(version: DapVersion) {
+        for (report_id, count) in sampled_report_log_lines(version, 0.0).await {
+            assert_eq!(count, 0, "report {report_id} logged");
+        }
     }
 
-    async_test_versions! { handle_agg_job_req_fail_send_cont_req }
+    async_test_versions! { report_debug_sample_rate_none }
 
-    async fn handle_upload_req_fail_send_invalid_report(version: DapVersion) {
-        let t = Test::new(version);
+    async fn purge_expired_helper_state(version: DapVersion) {
+        let mut data = TestData::new(version);
+        let clock = Arc::new(MockClock::new(data.now));
+        data.clock = clock.clone();
+        let helper = data.new_helper();
+        let t = data.with_leader(helper);
         let task_id = &t.time_interval_task_id;
-        let task_config = t.leader.unchecked_get_task_config(task_id).await;
+        let task_config = t.helper.unchecked_get_task_config(task_id).await;
 
-        // Construct a report payload with an invalid task ID.
-        let report_invalid_task_id = t.gen_test_report(task_id).await;
-        let req = DapRequest {
-            version: task_config.version,
-            media_type: DapMediaType::Report,
-            task_id: Some(TaskId([0; 32])),
-            resource: DapResource::Undefined,
-            payload: report_invalid_task_id
-                .get_encoded_with_param(&task_config.version)
-                .unwrap(),
-            ..Default::default()
-        };
+        let report = t.gen_test_report(task_id).await;
+        let (helper_state, _) = t
+            .gen_test_agg_job_init_req(task_id, version, DapAggregationParam::Empty, vec![report])
+            .await;
+        let agg_job_id = MetaAggregationJobId::gen_for_version(version);
+        assert!(t
+            .helper
+            .put_helper_state_if_not_exists(task_id, agg_job_id, &helper_state)
+            .await
+            .unwrap());
 
-        // Expect failure due to invalid task ID in report.
-        assert_matches!(
-            leader::handle_upload_req(&*t.leader, &req).await,
-            Err(DapError::Abort(DapAbort::UnrecognizedTask))
-        );
+        // State stored at or after the cutoff is kept while the task is live.
+        t.helper
+            .purge_expired_helper_state(task_id, t.now)
+            .await
+            .unwrap();
+        assert!(t
+            .helper
+            .get_helper_state(task_id, agg_job_id)
+            .await
+            .unwrap()
+            .is_some());
+
+        // Once the task expires, all of its state is purged.
+        clock.advance(task_config.expiration - t.now);
+        t.helper
+            .purge_expired_helper_state(task_id, t.now)
+            .await
+            .unwrap();
+        assert!(t
+            .helper
+            .get_helper_state(task_id, agg_job_id)
+            .await
+            .unwrap()
+            .is_none());
     }
 
-    async_test_versions! { handle_upload_req_fail_send_invalid_report }
+    async_test_versions! { purge_expired_helper_state }
 
-    // Test that the Leader rejects reports past the expiration date.
-    async fn handle_upload_req_task_expired(version: DapVersion) {
-        let t = Test::new(version);
-        let task_id = &t.expired_task_id;
-        let task_config = t.leader.unchecked_get_task_config(task_id).await;
+    async fn purge_helper_state_older_than_cutoff(version: DapVersion) {
+        let mut data = TestData::new(version);
+        let clock = Arc::new(MockClock::new(data.now));
+        data.clock = clock.clone();
+        let helper = data.new_helper();
+        let t = data.with_leader(helper);
+        let task_id = &t.time_interval_task_id;
+        let task_config = t.helper.unchecked_get_task_config(task_id).await;
 
         let report = t.gen_test_report(task_id).await;
-        let req = DapRequest {
-            version: task_config.version,
-            media_type: DapMediaType::Report,
-            task_id: Some(*task_id),
-            resource: DapResource::Undefined,
-            payload: report.get_encoded_with_param(&version).unwrap(),
-            ..Default::default()
-        };
+        let (helper_state, _) = t
+            .gen_test_agg_job_init_req(task_id, version, DapAggregationParam::Empty, vec![report])
+            .await;
+        let agg_job_id = MetaAggregationJobId::gen_for_version(version);
+        assert!(t
+            .helper
+            .put_helper_state_if_not_exists(task_id, agg_job_id, &helper_state)
+            .await
+            .unwrap());
 
-        assert_matches!(
-            leader::handle_upload_req(&*t.leader, &req)
-                .await
-                .unwrap_err(),
-            DapError::Abort(DapAbort::ReportTooLate)
-        );
+        // State stored before the cutoff is purged even though the task is still live.
+        clock.advance(1);
+        assert!(t.helper.get_current_time() < task_config.expiration);
+        t.helper
+            .purge_expired_helper_state(task_id, t.now + 1)
+            .await
+            .unwrap();
+        assert!(t
+            .helper
+            .get_helper_state(task_id, agg_job_id)
+            .await
+            .unwrap()
+            .is_none());
     }
 
-    async_test_versions! { handle_upload_req_task_expired }
+    async_test_versions! { purge_helper_state_older_than_cutoff }
 
-    async fn dequeue_work_empty(version: DapVersion) {
+    async fn handle_agg_job_req_transition_continue(version: DapVersion) {
         let t = Test::new(version);
         let task_id = &t.time_interval_task_id;
-        let task_config = t.leader.unchecked_get_task_config(task_id).await;
-
-        for _ in 0..10 {
-            let report = t.gen_test_report(task_id).await;
-            let req = t.gen_test_upload_req(report.clone(), task_id).await;
-            leader::handle_upload_req(&*t.leader, &req).await.unwrap();
-        }
-
-        let query = task_config.query_for_current_batch_window(t.now);
-        let req = t.gen_test_coll_job_req(query, task_id).await;
-        leader::handle_coll_job_req(&*t.leader, &req).await.unwrap();
 
-        // Get the next work item. This should be an aggregation job for the reports that were
-        // uploaded.
-        let mut work_items = t.leader.dequeue_work(1).await.unwrap();
-        assert_eq!(work_items.len(), 1);
-        let WorkItem::AggregationJob {
-            task_id: returned_task_id,
-            part_batch_sel: _,
-            agg_param: _,
-            reports,
-        } = work_items.pop().unwrap()
-        else {
-            panic!("unexpected work item type");
-        };
-        assert_eq!(reports.len(), 10);
-        assert_eq!(&returned_task_id, task_id);
+        let report = t.gen_test_report(task_id).await;
+        let (_, req) = t
+            .gen_test_agg_job_init_req(task_id, version, DapAggregationParam::Empty, vec![report])
+            .await;
 
-        // Get the next work item. This should be the collection job.
-        let mut work_items = t.leader.dequeue_work(1).await.unwrap();
-        assert_eq!(work_items.len(), 1);
-        let WorkItem::CollectionJob {
-            task_id: returned_task_id,
-            coll_job_id: _,
-            batch_sel: _,
-            agg_param: _,
-        } = work_items.pop().unwrap()
-        else {
-            panic!("unexpected work item type");
-        };
-        assert_eq!(&returned_task_id, task_id);
+        // Get AggregationJobResp and then extract the transition data from inside.
+        let agg_job_resp = AggregationJobResp::get_decoded(
+            &helper::handle_agg_job_req(&*t.helper, &req)
+                .await
+                .unwrap()
+                .payload,
+        )
+        .unwrap();
+        let transition = &agg_job_resp.transitions[0];
 
-        // Get the next work item. Expect the return value to be empty because there is no more
-        // work to process.
-        assert_eq!(t.leader.dequeue_work(1).await.unwrap().len(), 0);
+        // Expect success due to valid ciphertext.
+        assert_matches!(transition.var, TransitionVar::Continued(_));
     }
 
-    async_test_versions! { dequeue_work_empty }
+    async_test_versions! { handle_agg_job_req_transition_continue }
 
-    async fn poll_collect_job_test_results(version: DapVersion) {
+    async fn handle_agg_job_req_failure_report_replayed(version: DapVersion) {
         let t = Test::new(version);
         let task_id = &t.time_interval_task_id;
-        let task_config = t.leader.unchecked_get_task_config(task_id).await;
-
-        // Collector: Create a CollectReq.
-        let version = task_config.version;
-        let req = t.collector_authorized_req(
-            task_id,
-            &task_config,
-            DapMediaType::CollectReq,
-            CollectionReq {
-                draft02_task_id: task_id.for_request_payload(&version),
-                query: task_config.query_for_current_batch_window(t.now),
-                agg_param: Vec::default(),
-            },
-        );
+
+        let report = t.gen_test_report(task_id).await;
+        let (leader_state, req) = t
+            .gen_test_agg_job_init_req(
+                task_id,
+                version,
+                DapAggregationParam::Empty,
+                vec![report.clone()],
+            )
+            .await;
+
+        // Add dummy data to report store backend. This is done in a new scope so that the lock on the
+        // report store is released before running the test.
+        {
+            let mut guard = t
+                .helper
+                .report_store
+                .lock()
+                .expect("report_store: failed to lock");
+            let report_store = guard
+                .entry((*task_id, DapAggregationParam::Empty))
+                .or_default();
+            report_store.insert(report.report_metadata.id);
+        }
+
+        // Get AggregationJobResp and then extract the transition data from inside.
+        let agg_job_resp = AggregationJobResp::get_decoded(
+            &helper::handle_agg_job_req(&*t.helper, &req)
+                .await
+                .unwrap()
+                .payload,
+        )
+        .unwrap();
+        let transitions = if version == DapVersion::Draft02 {
+            // in version 2 replays are only detected later.
+            let agg_job_id =
+                AggregationJobInitReq::get_decoded_with_param(&DapVersion::Draft02, &req.payload)
+                    .unwrap()
+                    .draft02_agg_job_id
+                    .unwrap();
+            let task_config = t.leader.unchecked_get_task_config(task_id).await;
+            let transition = task_config
+                .handle_agg_job_resp(
+                    task_id,
+                    &MetaAggregationJobId::Draft02(agg_job_id),
+                    leader_state,
+                    agg_job_resp,
+                    t.leader.metrics(),
+                )
+                .unwrap();
+            let DapLeaderAggregationJobTransition::Uncommitted(
+                _,
+                AggregationJobContinueReq { transitions, .. },
+            ) = transition
+            else {
+                panic!("expected uncommitted transition, was {transition:?}");
+            };
+            let req = t
+                .gen_test_agg_job_cont_req(
+                    task_id,
+                    &MetaAggregationJobId::Draft02(agg_job_id),
+                    transitions,
+                    version,
+                )
+                .await;
+            AggregationJobResp::get_decoded(
+                &helper::handle_agg_job_req(&*t.helper, &req)
+                    .await
+                    .unwrap()
+                    .payload,
+            )
+            .unwrap()
+            .transitions
+        } else {
+            agg_job_resp.transitions
+        };
+
+        // Expect failure due to report store marked as collected.
+        assert_matches!(
+            transitions[0].var,
+            TransitionVar::Failed(TransitionFailure::ReportReplayed)
+        );
+
+        assert_metrics_include!(t.helper_registry, {
+            r#"report_counter{env="test_helper",host="helper.org",status="rejected_report_replayed"}"#: 1,
+            r#"inbound_request_counter{env="test_helper",host="helper.org",type="aggregate"}"#: if version == DapVersion::Draft02 { 2 } else { 1 },
+            r#"aggregation_job_counter{env="test_helper",host="helper.org",status="started"}"#: 1,
+        });
+    }
+
+    async_test_versions! { handle_agg_job_req_failure_report_replayed }
+
+    async fn handle_agg_job_req_failure_batch_collected(version: DapVersion) {
+        let t = Test::new(version);
+        let task_id = &t.time_interval_task_id;
+        let task_config = t.helper.unchecked_get_task_config(task_id).await;
+
+        let report = t.gen_test_report(task_id).await;
+        let (_, req) = t
+            .gen_test_agg_job_init_req(task_id, version, DapAggregationParam::Empty, vec![report])
+            .await;
+
+        // Add mock data to the aggreagte store backend. This is done in its own scope so that the lock
+        // is released before running the test. Otherwise the test will deadlock.
+        {
+            let mut guard = t
+                .helper
+                .agg_store
+                .lock()
+                .expect("agg_store: failed to lock");
+            let agg_store = guard.entry(*task_id).or_default();
+
+            agg_store.insert(
+                (
+                    DapBatchBucket::TimeInterval {
+                        batch_window: task_config.quantized_time_lower_bound(t.now),
+                    },
+                    DapAggregationParam::Empty,
+                ),
+                AggStore {
+                    agg_share: DapAggregateShare::default(),
+                    report_ids: Vec::new(),
+                    collected: true,
+                },
+            );
+        }
+
+        // Get AggregationJobResp and then extract the transition data from inside.
+        let agg_job_resp = AggregationJobResp::get_decoded(
+            &helper::handle_agg_job_req(&*t.helper, &req)
+                .await
+                .unwrap()
+                .payload,
+        )
+        .unwrap();
+        let transition = &agg_job_resp.transitions[0];
+
+        assert_eq!(t.helper.audit_log.invocations(), 1);
+
+        // Expect failure due to report store marked as collected.
+        assert_matches!(
+            transition.var,
+            TransitionVar::Failed(TransitionFailure::BatchCollected)
+        );
+
+        assert_metrics_include!(t.helper_registry, {
+            r#"report_counter{env="test_helper",host="helper.org",status="rejected_batch_collected"}"#: 1,
+            r#"inbound_request_counter{env="test_helper",host="helper.org",type="aggregate"}"#: 1,
+            r#"aggregation_job_counter{env="test_helper",host="helper.org",status="started"}"#: 1,
+        });
+    }
+
+    async_test_versions! { handle_agg_job_req_failure_batch_collected }
+
+    #[tokio::test]
+    async fn handle_agg_job_req_report_rejection_metrics() {
+        let version = DapVersion::DraftLatest;
+        let t = Test::new(version);
+        let task_id = &t.time_interval_task_id;
+        let task_config = t.helper.unchecked_get_task_config(task_id).await;
+
+        // The first report is a replay.
+        let report = t.gen_test_report(task_id).await;
+        t.helper
+            .report_store
+            .lock()
+            .expect("report_store: failed to lock")
+            .entry((*task_id, DapAggregationParam::Empty))
+            .or_default()
+            .insert(report.report_metadata.id);
+        let (_, req) = t
+            .gen_test_agg_job_init_req(task_id, version, DapAggregationParam::Empty, vec![report])
+            .await;
+        helper::handle_agg_job_req(&*t.helper, &req).await.unwrap();
+
+        // The second report pertains to a batch that was already collected.
+        t.helper
+            .agg_store
+            .lock()
+            .expect("agg_store: failed to lock")
+            .entry(*task_id)
+            .or_default()
+            .insert(
+                (
+                    DapBatchBucket::TimeInterval {
+                        batch_window: task_config.quantized_time_lower_bound(t.now),
+                    },
+                    DapAggregationParam::Empty,
+                ),
+                AggStore {
+                    agg_share: DapAggregateShare::default(),
+                    report_ids: Vec::new(),
+                    collected: true,
+                },
+            );
+        let report = t.gen_test_report(task_id).await;
+        let (_, req) = t
+            .gen_test_agg_job_init_req(task_id, version, DapAggregationParam::Empty, vec![report])
+            .await;
+        helper::handle_agg_job_req(&*t.helper, &req).await.unwrap();
+
+        assert_metrics_include!(t.helper_registry, {
+            r#"report_rejection_total{env="test_helper",host="helper.org",reason="report_replayed"}"#: 1,
+            r#"report_rejection_total{env="test_helper",host="helper.org",reason="batch_collected"}"#: 1,
+        });
+    }
+
+    async fn handle_agg_job_req_failure_fixed_size_batch_collected(version: DapVersion) {
+        let t = Test::new(version);
+        let task_id = &t.fixed_size_task_id;
+
+        let mut reports = Vec::new();
+        for _ in 0..3 {
+            reports.push(t.gen_test_report(task_id).await);
+        }
+        let (_, req) = t
+            .gen_test_agg_job_init_req(task_id, version, DapAggregationParam::Empty, reports)
+            .await;
+        let PartialBatchSelector::FixedSizeByBatchId { batch_id } =
+            AggregationJobInitReq::get_decoded_with_param(&version, &req.payload)
+                .unwrap()
+                .part_batch_sel
+        else {
+            panic!("expected fixed-size partial batch selector");
+        };
+
+        // Collect the batch before the aggregation job is run.
+        t.helper
+            .agg_store
+            .lock()
+            .expect("agg_store: failed to lock")
+            .entry(*task_id)
+            .or_default()
+            .insert(
+                (
+                    DapBatchBucket::FixedSize { batch_id },
+                    DapAggregationParam::Empty,
+                ),
+                AggStore {
+                    agg_share: DapAggregateShare::default(),
+                    report_ids: Vec::new(),
+                    collected: false,
+                },
+            );
+        t.helper
+            .mark_collected(
+                task_id,
+                &BatchSelector::FixedSizeByBatchId { batch_id },
+                &DapAggregationParam::Empty,
+            )
+            .await
+            .unwrap();
+
+        let agg_job_resp = AggregationJobResp::get_decoded(
+            &helper::handle_agg_job_req(&*t.helper, &req)
+                .await
+                .unwrap()
+                .payload,
+        )
+        .unwrap();
+
+        // Expect every report in the job to be rejected.
+        assert_eq!(agg_job_resp.transitions.len(), 3);
+        for transition in agg_job_resp.transitions {
+            assert_matches!(
+                transition.var,
+                TransitionVar::Failed(TransitionFailure::BatchCollected)
+            );
+        }
+    }
+
+    async_test_versions! { handle_agg_job_req_failure_fixed_size_batch_collected }
+
+    #[tokio::test]
+    async fn handle_agg_job_req_abort_helper_state_overwritten_draft02() {
+        let t = Test::new(DapVersion::Draft02);
+        let task_id = &t.time_interval_task_id;
+
+        let report = t.gen_test_report(task_id).await;
+        let (_, req) = t
+            .gen_test_agg_job_init_req(
+                task_id,
+                DapVersion::Draft02,
+                DapAggregationParam::Empty,
+                vec![report],
+            )
+            .await;
+
+        // Send aggregate request.
+        let _ = helper::handle_agg_job_req(&*t.helper, &req).await;
+
+        assert_eq!(t.helper.audit_log.invocations(), 1);
+
+        // Send another aggregate request.
+        let err = helper::handle_agg_job_req(&*t.helper, &req)
+            .await
+            .unwrap_err();
+
+        assert_eq!(t.helper.audit_log.invocations(), 1);
+
+        // Expect failure due to overwriting existing helper state.
+        assert_matches!(err, DapError::Abort(DapAbort::BadRequest(e)) =>
+            assert_eq!(e, "unexpected message for aggregation job (already exists)")
+        );
+    }
+
+    async fn handle_agg_job_req_fail_send_cont_req(version: DapVersion) {
+        let t = Test::new(version);
+        let agg_job_id = MetaAggregationJobId::gen_for_version(version);
+        let req = t
+            .gen_test_agg_job_cont_req(
+                &t.time_interval_task_id,
+                &agg_job_id,
+                Vec::default(),
+                version,
+            )
+            .await;
+
+        assert_eq!(t.helper.audit_log.invocations(), 0);
+
+        // Send aggregate continue request to helper.
+        let err = helper::handle_agg_job_req(&*t.helper, &req)
+            .await
+            .unwrap_err();
+
+        // Expect failure due to sending continue request before initialization request.
+        let DapError::Abort(abort) = err else {
+            panic!("unexpected error: {err:?}");
+        };
+        assert_eq!(abort.status_code(), 400);
+        assert_matches!(
+            abort,
+            DapAbort::UnrecognizedAggregationJob {
+                task_id,
+                agg_job_id_base64url,
+            } => {
+                assert_eq!(task_id, t.time_interval_task_id);
+                assert_eq!(agg_job_id_base64url, agg_job_id.to_base64url());
+            }
+        );
+    }
+
+    async_test_versions! { handle_agg_job_req_fail_send_cont_req }
+
+    async fn handle_upload_req_fail_send_invalid_report(version: DapVersion) {
+        let t = Test::new(version);
+        let task_id = &t.time_interval_task_id;
+        let task_config = t.leader.unchecked_get_task_config(task_id).await;
+
+        // Construct a report payload with an invalid task ID.
+        let report_invalid_task_id = t.gen_test_report(task_id).await;
+        let req = DapRequest {
+            version: task_config.version,
+            media_type: DapMediaType::Report,
+            task_id: Some(TaskId([0; 32])),
+            resource: DapResource::Undefined,
+            payload: report_invalid_task_id
+                .get_encoded_with_param(&task_config.version)
+                .unwrap(),
+            ..Default::default()
+        };
+
+        // Expect failure due to invalid task ID in report.
+        assert_matches!(
+            leader::handle_upload_req(&*t.leader, &req).await,
+            Err(DapError::Abort(DapAbort::UnrecognizedTask))
+        );
+    }
+
+    async_test_versions! { handle_upload_req_fail_send_invalid_report }
+
+    async fn handle_upload_req_fail_task_id_mismatch(version: DapVersion) {
+        let t = Test::new(version);
+
+        // The report is for one task, but is uploaded for another known task.
+        let report = t.gen_test_report(&t.time_interval_task_id).await;
+        assert_eq!(report.draft02_task_id, Some(t.time_interval_task_id));
+        let req = DapRequest {
+            version,
+            media_type: DapMediaType::Report,
+            task_id: Some(t.fixed_size_task_id),
+            resource: DapResource::Undefined,
+            payload: report.get_encoded_with_param(&version).unwrap(),
+            ..Default::default()
+        };
+
+        assert_matches!(
+            leader::handle_upload_req(&*t.leader, &req).await,
+            Err(DapError::Abort(DapAbort::UnrecognizedTask))
+        );
+    }
+
+    // The task ID is only encoded in the report in draft02.
+    async_test_version! { handle_upload_req_fail_task_id_mismatch, Draft02 }
+
+    async fn handle_upload_req_fail_wrong_number_of_input_shares(version: DapVersion) {
+        let t = Test::new(version);
+        let task_id = &t.time_interval_task_id;
+        let report = t.gen_test_report(task_id).await;
+
+        for num_shares in [0, 3] {
+            let encrypted_input_shares = (0..num_shares)
+                .map(|i| report.encrypted_input_shares[i % 2].clone())
+                .collect::<Vec<_>>();
+
+            // Encode the report by hand, since `Report` can only hold two input shares.
+            let mut payload = Vec::new();
+            if let Some(draft02_task_id) = &report.draft02_task_id {
+                draft02_task_id.encode(&mut payload).unwrap();
+            }
+            report
+                .report_metadata
+                .encode_with_param(&version, &mut payload)
+                .unwrap();
+            encode_u32_bytes(&mut payload, &report.public_share).unwrap();
+            match version {
+                DapVersion::Draft02 => {
+                    encode_u32_items(&mut payload, &(), &encrypted_input_shares).unwrap();
+                }
+                DapVersion::DraftLatest => {
+                    for encrypted_input_share in &encrypted_input_shares {
+                        encrypted_input_share.encode(&mut payload).unwrap();
+                    }
+                }
+            }
+
+            let req = DapRequest {
+                version,
+                media_type: DapMediaType::Report,
+                task_id: Some(*task_id),
+                resource: DapResource::Undefined,
+                payload,
+                ..Default::default()
+            };
+            assert_matches!(
+                leader::handle_upload_req(&*t.leader, &req).await,
+                Err(DapError::Abort(DapAbort::InvalidMessage { detail, .. }))
+                    if version != DapVersion::Draft02
+                        || detail.ends_with(&format!("got {num_shares}"))
+            );
+        }
+    }
+
+    async_test_versions! { handle_upload_req_fail_wrong_number_of_input_shares }
+
+    // Test that the Leader tells the Client to re-fetch its HPKE config if the report is encrypted
+    // under a config the Leader doesn't recognize.
+    async fn handle_upload_req_fail_outdated_hpke_config(version: DapVersion) {
+        let t = Test::new(version);
+        let task_id = &t.time_interval_task_id;
+
+        let mut report = t.gen_test_report(task_id).await;
+        report.encrypted_input_shares[0].config_id = {
+            let hpke_receiver_config_list = t.leader.hpke_receiver_config_list.lock().unwrap();
+            (0..=u8::MAX)
+                .find(|id| {
+                    hpke_receiver_config_list
+                        .iter()
+                        .all(|config| config.receiver.config.id != *id)
+                })
+                .unwrap()
+        };
+        let req = t.gen_test_upload_req(report, task_id).await;
+
+        let err = leader::handle_upload_req(&*t.leader, &req)
+            .await
+            .unwrap_err();
+        let DapError::Abort(abort) = err else {
+            panic!("unexpected error: {err:?}");
+        };
+        assert_matches!(abort, DapAbort::OutdatedConfig { .. });
+        assert_eq!(abort.status_code(), 400);
+        assert_eq!(
+            abort.into_problem_details().typ.as_deref(),
+            Some("urn:ietf:params:ppm:dap:error:outdatedConfig")
+        );
+    }
+
+    async_test_versions! { handle_upload_req_fail_outdated_hpke_config }
+
+    // Test that the Leader rejects reports past the expiration date.
+    async fn handle_upload_req_task_expired(version: DapVersion) {
+        let t = Test::new(version);
+        let task_id = &t.expired_task_id;
+        let task_config = t.leader.unchecked_get_task_config(task_id).await;
+
+        let report = t.gen_test_report(task_id).await;
+        let req = DapRequest {
+            version: task_config.version,
+            media_type: DapMediaType::Report,
+            task_id: Some(*task_id),
+            resource: DapResource::Undefined,
+            payload: report.get_encoded_with_param(&version).unwrap(),
+            ..Default::default()
+        };
+
+        assert_matches!(
+            leader::handle_upload_req(&*t.leader, &req)
+                .await
+                .unwrap_err(),
+            DapError::Abort(DapAbort::ReportTooLate)
+        );
+    }
+
+    async_test_versions! { handle_upload_req_task_expired }
+
+    // Test that the Leader rejects reports once the current time passes the task's expiration,
+    // even if the report's timestamp is within the task's lifetime.
+    async fn handle_upload_req_task_expired_by_clock(version: DapVersion) {
+        let mut data = TestData::new(version);
+        let clock = Arc::new(MockClock::new(data.now));
+        data.clock = clock.clone();
+        let helper = data.new_helper();
+        let t = data.with_leader(helper);
+        let task_id = &t.time_interval_task_id;
+        let task_config = t.leader.unchecked_get_task_config(task_id).await;
+
+        let report = t.gen_test_report(task_id).await;
+        let req = t.gen_test_upload_req(report, task_id).await;
+        leader::handle_upload_req(&*t.leader, &req).await.unwrap();
+
+        clock.advance(task_config.expiration - t.now);
+
+        let report = t.gen_test_report(task_id).await;
+        let req = t.gen_test_upload_req(report, task_id).await;
+        assert_matches!(
+            leader::handle_upload_req(&*t.leader, &req)
+                .await
+                .unwrap_err(),
+            DapError::Abort(DapAbort::ReportTooLate)
+        );
+    }
+
+    async_test_versions! { handle_upload_req_task_expired_by_clock }
+
+    // Test that the Leader distinguishes reports that are too far in the future from reports that
+    // are too far in the past.
+    async fn handle_upload_req_report_time_out_of_range(version: DapVersion) {
+        let mut data = TestData::new(version);
+        data.report_storage_bounds = Some((300, 60));
+        let helper = data.new_helper();
+        let t = data.with_leader(helper);
+        let task_id = &t.time_interval_task_id;
+
+        // A report at the edge of the range is accepted.
+        let report = t
+            .gen_test_report_for_measurement_at(task_id, DapMeasurement::U64(1), t.now + 60)
+            .await;
+        leader::handle_upload_req(&*t.leader, &t.gen_test_upload_req(report, task_id).await)
+            .await
+            .unwrap();
+
+        let report = t
+            .gen_test_report_for_measurement_at(task_id, DapMeasurement::U64(1), t.now + 600)
+            .await;
+        let err =
+            leader::handle_upload_req(&*t.leader, &t.gen_test_upload_req(report, task_id).await)
+                .await
+                .unwrap_err();
+        let DapError::Abort(abort) = err else {
+            panic!("unexpected error: {err:?}");
+        };
+        assert_matches!(abort, DapAbort::ReportTooEarly);
+        assert_eq!(
+            abort.into_problem_details().typ.as_deref(),
+            Some("urn:ietf:params:ppm:dap:error:reportTooEarly")
+        );
+
+        let report = t
+            .gen_test_report_for_measurement_at(task_id, DapMeasurement::U64(1), t.now - 600)
+            .await;
+        let err =
+            leader::handle_upload_req(&*t.leader, &t.gen_test_upload_req(report, task_id).await)
+                .await
+                .unwrap_err();
+        let DapError::Abort(abort) = err else {
+            panic!("unexpected error: {err:?}");
+        };
+        assert_matches!(abort, DapAbort::ReportRejected { .. });
+        assert_eq!(
+            abort.into_problem_details().typ.as_deref(),
+            Some("urn:ietf:params:ppm:dap:error:reportRejected")
+        );
+    }
+
+    async_test_versions! { handle_upload_req_report_time_out_of_range }
+
+    // Test that the Leader refuses uploads while its work queue is backed up and accepts them
+    // again once the queue drains.
+    async fn put_report_sheds_load_when_work_queue_backed_up(version: DapVersion) {
+        let t = Test::new(version);
+        let task_id = &t.time_interval_task_id;
+        let task_config = t.leader.unchecked_get_task_config(task_id).await;
+        let watermarks = WorkQueueWatermarks {
+            high: 3,
+            low: 2,
+            retry_after: 30,
+        };
+
+        // Aggregate each report eagerly so that every upload adds an item to the work queue.
+        let global_config = DapGlobalConfig {
+            eager_agg_threshold: Some(1),
+            ..t.leader.global_config.clone()
+        };
+        let mut leader_state = MockLeaderMemory::with_report_sharding([0; 32], 1);
+        let put_report = |leader_state: &mut MockLeaderMemory, report| {
+            leader_state.put_report(
+                task_id,
+                &task_config,
+                report,
+                &global_config,
+                Some(watermarks),
+            )
+        };
+
+        for _ in 0..=watermarks.high {
+            put_report(&mut leader_state, t.gen_test_report(task_id).await).unwrap();
+        }
+
+        let err = put_report(&mut leader_state, t.gen_test_report(task_id).await).unwrap_err();
+        assert!(err.is_retryable());
+        let DapError::Abort(abort) = err else {
+            panic!("unexpected error: {err:?}");
+        };
+        assert_eq!(abort.retry_after(), Some(30));
+        assert_eq!(abort.status_code(), 503);
+
+        // Uploads are refused until the queue drains below the low watermark.
+        leader_state.dequeue_work(1, 0, t.now, 60).unwrap();
+        assert_matches!(
+            put_report(&mut leader_state, t.gen_test_report(task_id).await),
+            Err(DapError::Abort(DapAbort::ServiceUnavailable { .. }))
+        );
+        leader_state.dequeue_work(2, 0, t.now, 60).unwrap();
+        put_report(&mut leader_state, t.gen_test_report(task_id).await).unwrap();
+    }
+
+    async_test_versions! { put_report_sheds_load_when_work_queue_backed_up }
+
+    async fn handle_upload_req_dedup_retries(version: DapVersion) {
+        let mut data = TestData::new(version);
+        data.global_config.upload_dedup_cache_capacity = 100;
+        let helper = data.new_helper();
+        let t = data.with_leader(helper);
+        let task_id = &t.time_interval_task_id;
+        let task_config = t.leader.unchecked_get_task_config(task_id).await;
+
+        // The Client retries the same upload several times. Each attempt succeeds.
+        let report = t.gen_test_report(task_id).await;
+        let req = t.gen_test_upload_req(report, task_id).await;
+        for _ in 0..5 {
+            leader::handle_upload_req(&*t.leader, &req).await.unwrap();
+        }
+
+        // Only one copy of the report was stored.
+        let query = task_config.query_for_current_batch_window(t.now);
+        let req = t.gen_test_coll_job_req(query, task_id).await;
+        leader::handle_coll_job_req(&*t.leader, &req).await.unwrap();
+        let mut work_items = t.leader.dequeue_work(1).await.unwrap();
+        let WorkItem::AggregationJob { reports, .. } = work_items.pop().unwrap().1 else {
+            panic!("unexpected work item type");
+        };
+        assert_eq!(reports.len(), 1);
+    }
+
+    async_test_versions! { handle_upload_req_dedup_retries }
+
+    #[test]
+    fn upload_dedup_cache_evicts_least_recently_used() {
+        let cache = UploadDedupCache::new(2);
+        let task_id = TaskId([1; 32]);
+        let [r1, r2, r3] = [1, 2, 3].map(|i| ReportId([i; 16]));
+
+        cache.insert(&task_id, &r1);
+        cache.insert(&task_id, &r2);
+
+        // Using r1 makes r2 the least recently used report.
+        assert!(cache.contains(&task_id, &r1));
+        cache.insert(&task_id, &r3);
+        assert!(cache.contains(&task_id, &r1));
+        assert!(!cache.contains(&task_id, &r2));
+        assert!(cache.contains(&task_id, &r3));
+
+        // Reports are cached per task.
+        assert!(!cache.contains(&TaskId([2; 32]), &r1));
+    }
+
+    async fn dequeue_work_empty(version: DapVersion) {
+        let t = Test::new(version);
+        let task_id = &t.time_interval_task_id;
+        let task_config = t.leader.unchecked_get_task_config(task_id).await;
+
+        for _ in 0..10 {
+            let report = t.gen_test_report(task_id).await;
+            let req = t.gen_test_upload_req(report.clone(), task_id).await;
+            leader::handle_upload_req(&*t.leader, &req).await.unwrap();
+        }
+
+        let query = task_config.query_for_current_batch_window(t.now);
+        let req = t.gen_test_coll_job_req(query, task_id).await;
+        leader::handle_coll_job_req(&*t.leader, &req).await.unwrap();
+
+        // Get the next work item. This should be an aggregation job for the reports that were
+        // uploaded.
+        let mut work_items = t.leader.dequeue_work(1).await.unwrap();
+        assert_eq!(work_items.len(), 1);
+        let WorkItem::AggregationJob {
+            task_id: returned_task_id,
+            agg_job_id: _,
+            part_batch_sel: _,
+            agg_param: _,
+            reports,
+            retries_remaining: _,
+            not_before: _,
+        } = work_items.pop().unwrap().1
+        else {
+            panic!("unexpected work item type");
+        };
+        assert_eq!(reports.len(), 10);
+        assert_eq!(&returned_task_id, task_id);
+
+        // Get the next work item. This should be the collection job.
+        let mut work_items = t.leader.dequeue_work(1).await.unwrap();
+        assert_eq!(work_items.len(), 1);
+        let WorkItem::CollectionJob {
+            task_id: returned_task_id,
+            coll_job_id: _,
+            batch_sel: _,
+            agg_param: _,
+            collector_hpke_config_id: _,
+            requeues: _,
+        } = work_items.pop().unwrap().1
+        else {
+            panic!("unexpected work item type");
+        };
+        assert_eq!(&returned_task_id, task_id);
+
+        // Get the next work item. Expect the return value to be empty because there is no more
+        // work to process.
+        assert_eq!(t.leader.dequeue_work(1).await.unwrap().len(), 0);
+    }
+
+    async_test_versions! { dequeue_work_empty }
+
+    async fn dequeue_work_concurrent_agg_jobs_per_task_capped(version: DapVersion) {
+        let mut data = TestData::new(version);
+        data.global_config.max_concurrent_agg_jobs_per_task = 2;
+        let helper = data.new_helper();
+        let t = data.with_leader(helper);
+        let task_id = &t.time_interval_task_id;
+
+        let agg_job_ids = (0..5)
+            .map(|_| MetaAggregationJobId::gen_for_version(version))
+            .collect::<Vec<_>>();
+        t.leader
+            .enqueue_work(
+                agg_job_ids
+                    .iter()
+                    .map(|agg_job_id| WorkItem::AggregationJob {
+                        task_id: *task_id,
+                        agg_job_id: *agg_job_id,
+                        part_batch_sel: PartialBatchSelector::TimeInterval,
+                        agg_param: DapAggregationParam::Empty,
+                        reports: Vec::new(),
+                        retries_remaining: 0,
+                        not_before: None,
+                    })
+                    .collect(),
+            )
+            .await
+            .unwrap();
+
+        // Only as many jobs as the cap allows are handed out, and in queue order.
+        let dequeued_agg_job_ids = |work_items: &[(WorkItemId, WorkItem)]| {
+            work_items
+                .iter()
+                .map(|(_item_id, work_item)| match work_item {
+                    WorkItem::AggregationJob { agg_job_id, .. } => *agg_job_id,
+                    WorkItem::CollectionJob { .. } => panic!("unexpected work item type"),
+                })
+                .collect::<Vec<_>>()
+        };
+        let mut leased = t.leader.dequeue_work(100).await.unwrap();
+        assert_eq!(dequeued_agg_job_ids(&leased), agg_job_ids[..2]);
+        assert_eq!(t.leader.dequeue_work(100).await.unwrap().len(), 0);
+
+        // Acknowledging a job frees up a slot for the next one. Acknowledging it again doesn't
+        // free up another.
+        let (item_id, _work_item) = leased.remove(0);
+        t.leader.ack_work(vec![item_id]).await.unwrap();
+        t.leader.ack_work(vec![item_id]).await.unwrap();
+        let work_items = t.leader.dequeue_work(100).await.unwrap();
+        assert_eq!(dequeued_agg_job_ids(&work_items), agg_job_ids[2..3]);
+        assert_eq!(t.leader.dequeue_work(100).await.unwrap().len(), 0);
+        leased.extend(work_items);
+
+        // The limit is per task: work for other tasks is not held back.
+        t.leader
+            .enqueue_work(vec![WorkItem::AggregationJob {
+                task_id: t.fixed_size_task_id,
+                agg_job_id: MetaAggregationJobId::gen_for_version(version),
+                part_batch_sel: PartialBatchSelector::TimeInterval,
+                agg_param: DapAggregationParam::Empty,
+                reports: Vec::new(),
+                retries_remaining: 0,
+                not_before: None,
+            }])
+            .await
+            .unwrap();
+        let work_items = t.leader.dequeue_work(100).await.unwrap();
+        assert_eq!(work_items.len(), 1);
+        assert_eq!(work_items[0].1.task_id(), &t.fixed_size_task_id);
+        leased.extend(work_items);
+
+        // Once the leased jobs are acknowledged, processing drains the queue.
+        t.leader
+            .ack_work(leased.into_iter().map(|(item_id, _)| item_id).collect())
+            .await
+            .unwrap();
+        for _ in 0..2 {
+            leader::process(&*t.leader, "leader.com", 100)
+                .await
+                .unwrap();
+        }
+        assert_eq!(t.leader.dequeue_work(100).await.unwrap().len(), 0);
+        for agg_job_id in &agg_job_ids[3..] {
+            assert_eq!(
+                t.leader.agg_job_status(task_id, agg_job_id).await.unwrap(),
+                Some(DapAggregationJobStatus::Completed)
+            );
+        }
+    }
+
+    async_test_versions! { dequeue_work_concurrent_agg_jobs_per_task_capped }
+
+    async fn process_failed_agg_job_frees_slot(version: DapVersion) {
+        let mut data = TestData::new(version);
+        data.global_config.max_concurrent_agg_jobs_per_task = 1;
+        let helper = data.new_helper();
+        let t = data.with_leader(helper);
+
+        // Aggregation jobs for a task the Leader doesn't know about always fail.
+        let task_id = TaskId([0xff; 32]);
+        t.leader
+            .enqueue_work(
+                (0..2)
+                    .map(|_| WorkItem::AggregationJob {
+                        task_id,
+                        agg_job_id: MetaAggregationJobId::gen_for_version(version),
+                        part_batch_sel: PartialBatchSelector::TimeInterval,
+                        agg_param: DapAggregationParam::Empty,
+                        reports: Vec::new(),
+                        retries_remaining: 0,
+                        not_before: None,
+                    })
+                    .collect(),
+            )
+            .await
+            .unwrap();
+
+        // Only one job is run at a time. The failed job gives up its slot, so the next one can be
+        // run right away.
+        for _ in 0..2 {
+            assert!(leader::process(&*t.leader, "leader.com", 100)
+                .await
+                .is_err());
+        }
+        assert_eq!(t.leader.dequeue_work(100).await.unwrap().len(), 0);
+    }
+
+    async_test_versions! { process_failed_agg_job_frees_slot }
+
+    async fn dequeue_work_redelivers_expired_lease(version: DapVersion) {
+        let mut data = TestData::new(version);
+        let clock = Arc::new(MockClock::new(data.now));
+        data.clock = clock.clone();
+        data.global_config.work_item_visibility_timeout = Some(60);
+        let helper = data.new_helper();
+        let t = data.with_leader(helper);
+        let task_id = &t.time_interval_task_id;
+
+        let agg_job_id = MetaAggregationJobId::gen_for_version(version);
+        t.leader
+            .enqueue_work(vec![WorkItem::AggregationJob {
+                task_id: *task_id,
+                agg_job_id,
+                part_batch_sel: PartialBatchSelector::TimeInterval,
+                agg_param: DapAggregationParam::Empty,
+                reports: Vec::new(),
+                retries_remaining: 0,
+                not_before: None,
+            }])
+            .await
+            .unwrap();
+
+        // The item is hidden while it is leased.
+        let mut work_items = t.leader.dequeue_work(100).await.unwrap();
+        assert_eq!(work_items.len(), 1);
+        let (first_item_id, _work_item) = work_items.pop().unwrap();
+        clock.advance(59);
+        assert_eq!(t.leader.dequeue_work(100).await.unwrap().len(), 0);
+
+        // The item wasn't acknowledged before the lease expired, so it is handed out again. The job
+        // keeps its ID, so that the Helper recognizes it if it was already sent. draft02 Helpers
+        // reject a repeated job, so a draft02 job is given a new ID instead.
+        clock.advance(1);
+        let mut work_items = t.leader.dequeue_work(100).await.unwrap();
+        assert_eq!(work_items.len(), 1);
+        let (second_item_id, work_item) = work_items.pop().unwrap();
+        assert_ne!(first_item_id, second_item_id);
+        let WorkItem::AggregationJob {
+            agg_job_id: redelivered_agg_job_id,
+            ..
+        } = work_item
+        else {
+            panic!("unexpected work item type");
+        };
+        match version {
+            DapVersion::Draft02 => {
+                assert_ne!(redelivered_agg_job_id, agg_job_id);
+                assert_eq!(
+                    t.leader.agg_job_status(task_id, &agg_job_id).await.unwrap(),
+                    Some(DapAggregationJobStatus::Failed)
+                );
+            }
+            DapVersion::DraftLatest => assert_eq!(redelivered_agg_job_id, agg_job_id),
+        }
+        assert_eq!(
+            t.leader
+                .agg_job_status(task_id, &redelivered_agg_job_id)
+                .await
+                .unwrap(),
+            Some(DapAggregationJobStatus::Queued)
+        );
+
+        // Acknowledging the stale lease has no effect on the redelivered item.
+        t.leader.ack_work(vec![first_item_id]).await.unwrap();
+        clock.advance(60);
+        assert_eq!(t.leader.dequeue_work(100).await.unwrap().len(), 1);
+    }
+
+    async_test_versions! { dequeue_work_redelivers_expired_lease }
+
+    async fn dequeue_work_ack_prevents_redelivery(version: DapVersion) {
+        let mut data = TestData::new(version);
+        let clock = Arc::new(MockClock::new(data.now));
+        data.clock = clock.clone();
+        data.global_config.work_item_visibility_timeout = Some(60);
+        let helper = data.new_helper();
+        let t = data.with_leader(helper);
+        let task_id = &t.time_interval_task_id;
+        let task_config = t.leader.unchecked_get_task_config(task_id).await;
+
+        let report = t.gen_test_report(task_id).await;
+        let req = t.gen_test_upload_req(report, task_id).await;
+        leader::handle_upload_req(&*t.leader, &req).await.unwrap();
+        let query = task_config.query_for_current_batch_window(t.now);
+        let req = t.gen_test_coll_job_req(query, task_id).await;
+        leader::handle_coll_job_req(&*t.leader, &req).await.unwrap();
+
+        // Processing acknowledges the work it dequeued.
+        let telem = leader::process(&*t.leader, "leader.com", 100)
+            .await
+            .unwrap();
+        assert_eq!(telem.reports_collected, 1);
+
+        clock.advance(60);
+        assert_eq!(t.leader.dequeue_work(100).await.unwrap().len(), 0);
+    }
+
+    async_test_versions! { dequeue_work_ack_prevents_redelivery }
+
+    async fn process_acks_failed_work_item(version: DapVersion) {
+        let mut data = TestData::new(version);
+        let clock = Arc::new(MockClock::new(data.now));
+        data.clock = clock.clone();
+        data.global_config.work_item_visibility_timeout = Some(60);
+        let helper = data.new_helper();
+        let t = data.with_leader(helper);
+        let task_id = &t.time_interval_task_id;
+        let task_config = t.leader.unchecked_get_task_config(task_id).await;
+
+        let report = t.gen_test_report(task_id).await;
+        let req = t.gen_test_upload_req(report, task_id).await;
+        leader::handle_upload_req(&*t.leader, &req).await.unwrap();
+        let query = task_config.query_for_current_batch_window(t.now);
+        let req = t.gen_test_coll_job_req(query, task_id).await;
+        leader::handle_coll_job_req(&*t.leader, &req).await.unwrap();
+
+        // An aggregation job for a task the Leader doesn't know about can never succeed.
+        t.leader
+            .enqueue_work(vec![WorkItem::AggregationJob {
+                task_id: TaskId([0xff; 32]),
+                agg_job_id: MetaAggregationJobId::gen_for_version(version),
+                part_batch_sel: PartialBatchSelector::TimeInterval,
+                agg_param: DapAggregationParam::Empty,
+                reports: Vec::new(),
+                retries_remaining: 0,
+                not_before: None,
+            }])
+            .await
+            .unwrap();
+
+        // The error is surfaced, but doesn't prevent the rest of the work from completing. The
+        // work that was done is still recorded.
+        let mut telem = DapLeaderProcessTelemetry::default();
+        assert!(
+            leader::process_into(&*t.leader, "leader.com", 100, &mut telem)
+                .await
+                .is_err()
+        );
+        assert_eq!(telem.reports_aggregated, 1);
+        assert_eq!(telem.reports_collected, 1);
+        let [(_coll_job_id, coll_job_info)] =
+            <[_; 1]>::try_from(t.leader.list_collection_jobs(task_id).await.unwrap()).unwrap();
+        assert_matches!(
+            coll_job_info.status,
+            DapCollectionJob::Done(collection) if collection.report_count == 1
+        );
+
+        // The failed item was acknowledged, so it isn't handed out again once its lease expires.
+        clock.advance(60);
+        assert_eq!(t.leader.dequeue_work(100).await.unwrap().len(), 0);
+    }
+
+    async_test_versions! { process_acks_failed_work_item }
+
+    async fn process_caps_coll_job_requeues(version: DapVersion) {
+        let t = Test::new(version);
+        let task_id = &t.time_interval_task_id;
+        let task_config = t.leader.unchecked_get_task_config(task_id).await;
+
+        let report = t.gen_test_report(task_id).await;
+        let req = t.gen_test_upload_req(report, task_id).await;
+        leader::handle_upload_req(&*t.leader, &req).await.unwrap();
+        let query = task_config.query_for_current_batch_window(t.now);
+        let req = t.gen_test_coll_job_req(query, task_id).await;
+        leader::handle_coll_job_req(&*t.leader, &req).await.unwrap();
+
+        // Lease the aggregation job without ever finishing it, so that the batch is never ready.
+        let [(_agg_job_item_id, WorkItem::AggregationJob { .. })] =
+            <[_; 1]>::try_from(t.leader.dequeue_work(1).await.unwrap()).unwrap()
+        else {
+            panic!("expected the aggregation job to be dequeued first");
+        };
+
+        for _ in 0..=leader::MAX_COLL_JOB_REQUEUES {
+            assert_eq!(t.leader.work_queue_stats().await.unwrap().coll_jobs, 1);
+            leader::process(&*t.leader, "leader.com", 1).await.unwrap();
+        }
+
+        // The collection job is no longer requeued, but it can still be re-driven.
+        assert_eq!(t.leader.work_queue_stats().await.unwrap().coll_jobs, 0);
+        let [(coll_job_id, coll_job_info)] =
+            <[_; 1]>::try_from(t.leader.list_collection_jobs(task_id).await.unwrap()).unwrap();
+        assert_eq!(coll_job_info.status, DapCollectionJob::Pending);
+        t.leader
+            .retry_collect_job(task_id, &coll_job_id)
+            .await
+            .unwrap();
+        assert_eq!(t.leader.work_queue_stats().await.unwrap().coll_jobs, 1);
+    }
+
+    async_test_versions! { process_caps_coll_job_requeues }
+
+    async fn dequeue_work_default_visibility_timeout(version: DapVersion) {
+        let mut data = TestData::new(version);
+        let clock = Arc::new(MockClock::new(data.now));
+        data.clock = clock.clone();
+        data.global_config.work_item_visibility_timeout = None;
+        let helper = data.new_helper();
+        let t = data.with_leader(helper);
+        let task_id = &t.time_interval_task_id;
+
+        t.leader
+            .enqueue_work(vec![WorkItem::AggregationJob {
+                task_id: *task_id,
+                agg_job_id: MetaAggregationJobId::gen_for_version(version),
+                part_batch_sel: PartialBatchSelector::TimeInterval,
+                agg_param: DapAggregationParam::Empty,
+                reports: Vec::new(),
+                retries_remaining: 0,
+                not_before: None,
+            }])
+            .await
+            .unwrap();
+        assert_eq!(t.leader.dequeue_work(100).await.unwrap().len(), 1);
+
+        // Without a configured timeout, the lease still expires after the default.
+        clock.advance(t.leader.get_global_config().work_item_visibility_timeout() - 1);
+        assert_eq!(t.leader.dequeue_work(100).await.unwrap().len(), 0);
+        clock.advance(1);
+        assert_eq!(t.leader.dequeue_work(100).await.unwrap().len(), 1);
+    }
+
+    async_test_versions! { dequeue_work_default_visibility_timeout }
+
+    async fn process_retries_agg_job_within_budget(version: DapVersion) {
+        let mut data = TestData::new(version);
+        let clock = Arc::new(MockClock::new(data.now));
+        data.clock = clock.clone();
+        data.global_config.agg_job_retry_budget = 2;
+        data.global_config.agg_job_retry_backoff = 10;
+        // The lease on a failed job expires before its retry is due, so the job would be run
+        // twice if it weren't acknowledged when it is queued again.
+        data.global_config.work_item_visibility_timeout = Some(1);
+        let helper = data.new_helper();
+        let t = data.with_leader(helper);
+        let task_id = &t.time_interval_task_id;
+        let task_config = t.leader.unchecked_get_task_config(task_id).await;
+
+        let report = t.gen_test_report(task_id).await;
+        let req = t.gen_test_upload_req(report, task_id).await;
+        leader::handle_upload_req(&*t.leader, &req).await.unwrap();
+        let query = task_config.query_for_current_batch_window(t.now);
+        let req = t.gen_test_coll_job_req(query, task_id).await;
+        leader::handle_coll_job_req(&*t.leader, &req).await.unwrap();
+
+        // The Helper can't be reached the first two times the aggregation job is run.
+        t.leader.fail_next_peer_requests(2);
+        let telem = leader::process(&*t.leader, "leader.com", 100)
+            .await
+            .unwrap();
+        assert_eq!(telem.reports_aggregated, 0);
+        assert_eq!(telem.reports_collected, 0);
+
+        // The retry waits for the backoff to elapse.
+        let telem = leader::process(&*t.leader, "leader.com", 100)
+            .await
+            .unwrap();
+        assert_eq!(telem.reports_processed, 0);
+
+        // The backoff doubles for the second retry.
+        clock.advance(10);
+        let telem = leader::process(&*t.leader, "leader.com", 100)
+            .await
+            .unwrap();
+        assert_eq!(telem.reports_processed, 1);
+        assert_eq!(telem.reports_collected, 0);
+        clock.advance(19);
+        let telem = leader::process(&*t.leader, "leader.com", 100)
+            .await
+            .unwrap();
+        assert_eq!(telem.reports_processed, 0);
+
+        clock.advance(1);
+        let telem = leader::process(&*t.leader, "leader.com", 100)
+            .await
+            .unwrap();
+        assert_eq!(telem.reports_aggregated, 1);
+        assert_eq!(telem.reports_collected, 1);
+    }
+
+    async_test_versions! { process_retries_agg_job_within_budget }
+
+    async fn process_fails_agg_job_once_retry_budget_exhausted(version: DapVersion) {
+        let mut data = TestData::new(version);
+        data.global_config.agg_job_retry_budget = 1;
+        let helper = data.new_helper();
+        let t = data.with_leader(helper);
+        let task_id = &t.time_interval_task_id;
+        let task_config = t.leader.unchecked_get_task_config(task_id).await;
+
+        let report = t.gen_test_report(task_id).await;
+        let req = t.gen_test_upload_req(report, task_id).await;
+        leader::handle_upload_req(&*t.leader, &req).await.unwrap();
+        let query = task_config.query_for_current_batch_window(t.now);
+        let req = t.gen_test_coll_job_req(query, task_id).await;
+        leader::handle_coll_job_req(&*t.leader, &req).await.unwrap();
+
+        t.leader.fail_next_peer_requests(2);
+        leader::process(&*t.leader, "leader.com", 100)
+            .await
+            .unwrap();
+        assert_matches!(
+            leader::process(&*t.leader, "leader.com", 100).await,
+            Err(DapError::Peer(PeerError::Unreachable(..)))
+        );
+    }
+
+    async_test_versions! { process_fails_agg_job_once_retry_budget_exhausted }
+
+    async fn dequeue_work_skips_agg_job_backing_off(version: DapVersion) {
+        let mut data = TestData::new(version);
+        let clock = Arc::new(MockClock::new(data.now));
+        data.clock = clock.clone();
+        let helper = data.new_helper();
+        let t = data.with_leader(helper);
+        let task_id = &t.time_interval_task_id;
+
+        let agg_job = |not_before| WorkItem::AggregationJob {
+            task_id: *task_id,
+            agg_job_id: MetaAggregationJobId::gen_for_version(version),
+            part_batch_sel: PartialBatchSelector::TimeInterval,
+            agg_param: DapAggregationParam::Empty,
+            reports: Vec::new(),
+            retries_remaining: 0,
+            not_before,
+        };
+        let backing_off = agg_job(Some(t.now + 10));
+        let ready = agg_job(None);
+        t.leader
+            .enqueue_work(vec![backing_off.clone(), ready.clone()])
+            .await
+            .unwrap();
+
+        // Only the job that is backing off is held back, not the work queued after it.
+        let dequeue = || async {
+            t.leader
+                .dequeue_work(100)
+                .await
+                .unwrap()
+                .into_iter()
+                .map(|(_item_id, work_item)| work_item)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(dequeue().await, vec![ready]);
+        clock.advance(10);
+        assert_eq!(dequeue().await, vec![backing_off]);
+    }
+
+    async_test_versions! { dequeue_work_skips_agg_job_backing_off }
+
+    #[tokio::test]
+    async fn handle_agg_job_req_repeated() {
+        let version = DapVersion::DraftLatest;
+        let t = Test::new(version);
+        let task_id = &t.time_interval_task_id;
+
+        let reports = vec![
+            t.gen_test_report(task_id).await,
+            t.gen_test_report(task_id).await,
+        ];
+        let (_, req) = t
+            .gen_test_agg_job_init_req(task_id, version, DapAggregationParam::Empty, reports)
+            .await;
+        let resp = helper::handle_agg_job_req(&*t.helper, &req).await.unwrap();
+
+        // The Leader retries the job, e.g., because the response was lost in transit. The Helper
+        // responds as it did the first time rather than rejecting the reports as replays.
+        let retry_resp = helper::handle_agg_job_req(&*t.helper, &req).await.unwrap();
+        assert_eq!(retry_resp.payload, resp.payload);
+        let agg_job_resp = AggregationJobResp::get_decoded(&resp.payload).unwrap();
+        assert!(agg_job_resp
+            .transitions
+            .iter()
+            .all(|transition| !matches!(transition.var, TransitionVar::Failed(..))));
+        assert_eq!(t.helper.audit_log.invocations(), 1);
+    }
+
+    #[test]
+    fn is_retryable() {
+        assert!(DapError::from(PeerError::Unreachable("connection reset".into())).is_retryable());
+        assert!(DapError::from(PeerError::ServerError { status: 502 }).is_retryable());
+        assert!(DapError::from(DapAbort::ServiceUnavailable { retry_after: 30 }).is_retryable());
+
+        // Internal errors and aborts sent by the peer would recur.
+        assert!(!fatal_error!(err = "something went wrong").is_retryable());
+        assert!(!DapError::from(DapAbort::UnrecognizedTask).is_retryable());
+    }
+
+    async fn work_item_serde_roundtrip(version: DapVersion) {
+        let t = Test::new(version);
+        let task_id = &t.time_interval_task_id;
+        let task_config = t.leader.unchecked_get_task_config(task_id).await;
+        let agg_param = DapAggregationParam::Mastic(
+            Poplar1AggregationParam::try_from_prefixes(vec![
+                IdpfInput::from_bytes(&[0]),
+                IdpfInput::from_bytes(&[1]),
+            ])
+            .unwrap(),
+        );
+
+        let work_queue = vec![
+            WorkItem::AggregationJob {
+                task_id: *task_id,
+                agg_job_id: MetaAggregationJobId::gen_for_version(version),
+                part_batch_sel: PartialBatchSelector::TimeInterval,
+                agg_param: agg_param.clone(),
+                reports: vec![
+                    t.gen_test_report(task_id).await,
+                    t.gen_test_report(task_id).await,
+                ],
+                retries_remaining: 2,
+                not_before: Some(t.now),
+            },
+            WorkItem::AggregationJob {
+                task_id: t.fixed_size_task_id,
+                agg_job_id: MetaAggregationJobId::gen_for_version(version),
+                part_batch_sel: PartialBatchSelector::FixedSizeByBatchId {
+                    batch_id: BatchId(thread_rng().gen()),
+                },
+                agg_param: DapAggregationParam::Empty,
+                reports: Vec::new(),
+                retries_remaining: 0,
+                not_before: None,
+            },
+            WorkItem::CollectionJob {
+                task_id: *task_id,
+                coll_job_id: CollectionJobId(thread_rng().gen()),
+                batch_sel: task_config
+                    .query_for_current_batch_window(t.now)
+                    .into_batch_sel()
+                    .unwrap(),
+                agg_param,
+                collector_hpke_config_id: None,
+                requeues: 0,
+            },
+        ];
+
+        let serialized = serde_json::to_string(&work_queue).unwrap();
+        let deserialized: Vec<WorkItem> = serde_json::from_str(&serialized).unwrap();
+        assert_eq!(deserialized, work_queue);
+    }
+
+    async_test_versions! { work_item_serde_roundtrip }
+
+    async fn handle_upload_req_eager_aggregation(version: DapVersion) {
+        let mut data = TestData::new(version);
+        data.global_config.eager_agg_threshold = Some(3);
+        let helper = data.new_helper();
+        let t = data.with_leader(helper);
+        let task_id = &t.time_interval_task_id;
+
+        // Below the threshold, reports are held until a collection job arrives.
+        for _ in 0..2 {
+            let report = t.gen_test_report(task_id).await;
+            let req = t.gen_test_upload_req(report, task_id).await;
+            leader::handle_upload_req(&*t.leader, &req).await.unwrap();
+        }
+        assert_eq!(t.leader.dequeue_work(100).await.unwrap().len(), 0);
+
+        // Once the bucket reaches the threshold, it is queued for aggregation right away.
+        let report = t.gen_test_report(task_id).await;
+        let req = t.gen_test_upload_req(report, task_id).await;
+        leader::handle_upload_req(&*t.leader, &req).await.unwrap();
+        let mut work_items = t.leader.dequeue_work(100).await.unwrap();
+        assert_eq!(work_items.len(), 1);
+        let WorkItem::AggregationJob {
+            part_batch_sel,
+            reports,
+            ..
+        } = work_items.pop().unwrap().1
+        else {
+            panic!("unexpected work item type");
+        };
+        assert_eq!(part_batch_sel, PartialBatchSelector::TimeInterval);
+        assert_eq!(reports.len(), 3);
+    }
+
+    async_test_versions! { handle_upload_req_eager_aggregation }
+
+    async fn handle_upload_req_sharded_aggregation(version: DapVersion) {
+        const NUM_REPORTS: usize = 20;
+        let mut data = TestData::new(version);
+        data.global_config.eager_agg_threshold = Some(NUM_REPORTS.try_into().unwrap());
+        let helper = data.new_helper();
+        let t = data.with_leader(helper);
+        *t.leader.leader_state_store.lock().unwrap() =
+            MockLeaderMemory::with_report_sharding([7; 32], 4);
+        let task_id = &t.time_interval_task_id;
+
+        let mut report_ids = HashSet::new();
+        for _ in 0..NUM_REPORTS {
+            let report = t.gen_test_report(task_id).await;
+            report_ids.insert(report.report_metadata.id);
+            let req = t.gen_test_upload_req(report, task_id).await;
+            leader::handle_upload_req(&*t.leader, &req).await.unwrap();
+        }
+
+        // Each shard of the bucket is aggregated in its own job.
+        let work_items = t.leader.dequeue_work(100).await.unwrap();
+        assert!(work_items.len() > 1 && work_items.len() <= 4);
+        for (_item_id, work_item) in work_items {
+            let WorkItem::AggregationJob { reports, .. } = work_item else {
+                panic!("unexpected work item type");
+            };
+            let shard = reports[0].report_metadata.id.shard(&[7; 32], 4);
+            for report in reports {
+                assert_eq!(report.report_metadata.id.shard(&[7; 32], 4), shard);
+                assert!(report_ids.remove(&report.report_metadata.id));
+            }
+        }
+        assert!(report_ids.is_empty());
+    }
+
+    async_test_versions! { handle_upload_req_sharded_aggregation }
+
+    async fn max_report_shares_per_agg_job_leader_splits_job(version: DapVersion) {
+        const NUM_REPORTS: u64 = 5;
+        let mut data = TestData::new(version);
+        data.global_config.max_report_shares_per_agg_job = Some(2);
+        let helper = data.new_helper();
+        let t = data.with_leader(helper);
+        let task_id = &t.time_interval_task_id;
+        let task_config = t.leader.unchecked_get_task_config(task_id).await;
+
+        for _ in 0..NUM_REPORTS {
+            let report = t.gen_test_report(task_id).await;
+            leader::handle_upload_req(&*t.leader, &t.gen_test_upload_req(report, task_id).await)
+                .await
+                .unwrap();
+        }
+        let query = task_config.query_for_current_batch_window(t.now);
+        leader::handle_coll_job_req(&*t.leader, &t.gen_test_coll_job_req(query, task_id).await)
+            .await
+            .unwrap();
+        leader::process(&*t.leader, "leader.com", 100)
+            .await
+            .unwrap();
+
+        // The reports are split across three aggregation jobs, all of which are collected.
+        let [(_coll_job_id, coll_job_info)] =
+            <[_; 1]>::try_from(t.leader.list_collection_jobs(task_id).await.unwrap()).unwrap();
+        assert_matches!(
+            coll_job_info.status,
+            DapCollectionJob::Done(collection) if collection.report_count == NUM_REPORTS
+        );
+        assert_metrics_include!(t.helper_registry, {
+            r#"aggregation_job_counter{env="test_helper",host="helper.org",status="started"}"#: 3,
+            r#"aggregation_job_counter{env="test_helper",host="helper.org",status="completed"}"#: 3,
+        });
+    }
+
+    async_test_versions! { max_report_shares_per_agg_job_leader_splits_job }
+
+    async fn max_report_shares_per_agg_job_helper_rejects_job(version: DapVersion) {
+        let mut data = TestData::new(version);
+        data.global_config.max_report_shares_per_agg_job = Some(1);
+        let helper = data.new_helper();
+        let t = data.with_leader(helper);
+        let task_id = &t.time_interval_task_id;
+
+        let reports = vec![
+            t.gen_test_report(task_id).await,
+            t.gen_test_report(task_id).await,
+        ];
+        let (_, req) = t
+            .gen_test_agg_job_init_req(task_id, version, DapAggregationParam::Empty, reports)
+            .await;
+        assert_matches!(
+            helper::handle_agg_job_req(&*t.helper, &req)
+                .await
+                .unwrap_err(),
+            DapError::Abort(DapAbort::BadRequest(..))
+        );
+    }
+
+    async_test_versions! { max_report_shares_per_agg_job_helper_rejects_job }
+
+    #[tokio::test]
+    async fn handle_agg_job_req_agg_param_mismatch() {
+        let version = DapVersion::DraftLatest;
+        let t = Test::new(version);
+        let task_id = &t.heavy_hitters_task_id;
+        let agg_param_for_prefixes = |prefixes: [u8; 2]| {
+            DapAggregationParam::Mastic(
+                Poplar1AggregationParam::try_from_prefixes(
+                    prefixes
+                        .iter()
+                        .map(|prefix| IdpfInput::from_bytes(&[*prefix]))
+                        .collect(),
+                )
+                .unwrap(),
+            )
+        };
+
+        // Have the Helper aggregate each batch under a single aggregation parameter.
+        {
+            let mut tasks = t.helper.tasks.lock().unwrap();
+            let VdafConfig::Mastic {
+                fixed_agg_param, ..
+            } = &mut tasks.get_mut(task_id).unwrap().vdaf
+            else {
+                panic!("unexpected VDAF");
+            };
+            *fixed_agg_param = true;
+        }
+
+        let mut agg_job_init_reqs = Vec::new();
+        for (i, agg_param) in [
+            agg_param_for_prefixes([0, 1]),
+            agg_param_for_prefixes([2, 3]),
+            agg_param_for_prefixes([0, 1]),
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            let report = t
+                .gen_test_report_for_measurement(
+                    task_id,
+                    DapMeasurement::Mastic {
+                        input: vec![u8::try_from(i).unwrap()],
+                        weight: MasticWeight::Bool(true),
+                    },
+                )
+                .await;
+            let (_, req) = t
+                .gen_test_agg_job_init_req(task_id, version, agg_param, vec![report])
+                .await;
+            agg_job_init_reqs.push(req);
+        }
+
+        // The first job fixes the aggregation parameter for the bucket, so the second job, whose
+        // report falls into the same bucket, is rejected. A job that uses the same parameter as
+        // the first is accepted.
+        helper::handle_agg_job_req(&*t.helper, &agg_job_init_reqs[0])
+            .await
+            .unwrap();
+        assert_matches!(
+            helper::handle_agg_job_req(&*t.helper, &agg_job_init_reqs[1])
+                .await
+                .unwrap_err(),
+            DapError::Abort(DapAbort::AggregationParameterMismatch { .. })
+        );
+        helper::handle_agg_job_req(&*t.helper, &agg_job_init_reqs[2])
+            .await
+            .unwrap();
+    }
+
+    async fn poll_collect_job_test_results(version: DapVersion) {
+        let t = Test::new(version);
+        let task_id = &t.time_interval_task_id;
+        let task_config = t.leader.unchecked_get_task_config(task_id).await;
+
+        // Collector: Create a CollectReq.
+        let version = task_config.version;
+        let req = t.collector_authorized_req(
+            task_id,
+            &task_config,
+            DapMediaType::CollectReq,
+            CollectionReq {
+                draft02_task_id: task_id.for_request_payload(&version),
+                query: task_config.query_for_current_batch_window(t.now),
+                agg_param: Vec::default(),
+            },
+        );
+
+        // Leader: Handle the CollectReq received from Collector.
+        leader::handle_coll_job_req(&*t.leader, &req).await.unwrap();
+
+        // Expect DapCollectionJob::Unknown due to invalid collect ID.
+        assert_eq!(
+            t.leader
+                .poll_collect_job(task_id, &CollectionJobId::default())
+                .await
+                .unwrap(),
+            DapCollectionJob::Unknown
+        );
+
+        // Leader: Get pending collect job to obtain collect_id
+        let WorkItem::CollectionJob {
+            task_id: _,
+            coll_job_id,
+            batch_sel: _,
+            agg_param: _,
+            collector_hpke_config_id: _,
+            requeues: _,
+        } = t.leader.dequeue_work(1).await.unwrap().pop().unwrap().1
+        else {
+            panic!("unexpected work item type")
+        };
+        let collection = Collection {
+            part_batch_sel: PartialBatchSelector::TimeInterval,
+            report_count: 0,
+            draft_latest_interval: if version == DapVersion::Draft02 {
+                None
+            } else {
+                Some(Interval {
+                    start: 0,
+                    duration: 2_000_000_000,
+                })
+            },
+            encrypted_agg_shares: [
+                HpkeCiphertext {
+                    config_id: Default::default(),
+                    enc: Default::default(),
+                    payload: Default::default(),
+                },
+                HpkeCiphertext {
+                    config_id: Default::default(),
+                    enc: Default::default(),
+                    payload: Default::default(),
+                },
+            ],
+        };
+
+        // Expect DapCollectionJob::Pending due to pending collect job.
+        assert_eq!(
+            t.leader
+                .poll_collect_job(task_id, &coll_job_id)
+                .await
+                .unwrap(),
+            DapCollectionJob::Pending
+        );
+
+        // Leader: Complete the collect job by storing CollectResp in LeaderStore.processed.
+        t.leader
+            .finish_collect_job(task_id, &coll_job_id, &collection)
+            .await
+            .unwrap();
+
+        // Expect DapCollectionJob::Done due to processed collect job.
+        assert_matches!(
+            t.leader
+                .poll_collect_job(task_id, &coll_job_id)
+                .await
+                .unwrap(),
+            DapCollectionJob::Done(..)
+        );
+    }
+
+    async_test_versions! { poll_collect_job_test_results }
+
+    async fn handle_coll_job_req_non_empty_agg_param(version: DapVersion) {
+        let t = Test::new(version);
+        let task_id = &t.time_interval_task_id;
+        let task_config = t.leader.unchecked_get_task_config(task_id).await;
+        assert_matches!(task_config.vdaf, VdafConfig::Prio3(..));
+
+        // Prio3 takes no aggregation parameter, so a Collector can't supply one.
+        let req = t.collector_authorized_req(
+            task_id,
+            &task_config,
+            DapMediaType::CollectReq,
+            CollectionReq {
+                draft02_task_id: task_id.for_request_payload(&version),
+                query: task_config.query_for_current_batch_window(t.now),
+                agg_param: b"not empty".to_vec(),
+            },
+        );
+        assert_matches!(
+            leader::handle_coll_job_req(&*t.leader, &req).await,
+            Err(DapError::Abort(DapAbort::InvalidMessage { detail, .. })) => {
+                assert!(detail.contains("empty aggregation parameter"), "{detail}");
+            }
+        );
+
+        // No collection job was created.
+        assert!(t
+            .leader
+            .list_collection_jobs(task_id)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    async_test_versions! { handle_coll_job_req_non_empty_agg_param }
+
+    async fn list_collection_jobs(version: DapVersion) {
+        let t = Test::new(version);
+        let task_id = &t.time_interval_task_id;
+        let task_config = t.leader.unchecked_get_task_config(task_id).await;
+        let query_for_window = |start| Query::TimeInterval {
+            batch_interval: Interval {
+                start,
+                duration: task_config.time_precision,
+            },
+        };
+        let current_window = task_config.quantized_time_lower_bound(t.now);
+
+        // Store a report in the current batch window.
+        let report = t.gen_test_report(task_id).await;
+        t.leader.put_report(&report, task_id).await.unwrap();
+
+        // Create a collection job that queues aggregation of the current batch window, a
+        // collection job for an empty batch window, and a collection job that completes.
+        let mut coll_job_ids = [(); 3].map(|()| CollectionJobId(thread_rng().gen()));
+        coll_job_ids.sort_by_key(|coll_job_id| coll_job_id.0);
+        let [pending_queued_id, pending_empty_id, done_id] = coll_job_ids;
+        for (coll_job_id, window) in [
+            (pending_queued_id, current_window),
+            (
+                pending_empty_id,
+                current_window - task_config.time_precision,
+            ),
+            (done_id, current_window - 2 * task_config.time_precision),
+        ] {
+            t.leader
+                .init_collect_job(
+                    task_id,
+                    &Some(coll_job_id),
+                    query_for_window(window),
+                    DapAggregationParam::Empty,
+                    None,
+                )
+                .await
+                .unwrap();
+        }
+
+        let collection = Collection {
+            part_batch_sel: PartialBatchSelector::TimeInterval,
+            report_count: 0,
+            draft_latest_interval: None,
+            encrypted_agg_shares: [
+                HpkeCiphertext {
+                    config_id: Default::default(),
+                    enc: Default::default(),
+                    payload: Default::default(),
+                },
+                HpkeCiphertext {
+                    config_id: Default::default(),
+                    enc: Default::default(),
+                    payload: Default::default(),
+                },
+            ],
+        };
+        t.leader
+            .finish_collect_job(task_id, &done_id, &collection)
+            .await
+            .unwrap();
+
+        let mut coll_jobs = t.leader.list_collection_jobs(task_id).await.unwrap();
+        coll_jobs.sort_by_key(|(coll_job_id, _)| coll_job_id.0);
+        assert_eq!(
+            coll_jobs,
+            vec![
+                (
+                    pending_queued_id,
+                    DapCollectionJobInfo {
+                        status: DapCollectionJob::Pending,
+                        queued_buckets: Some(1),
+                        batch_time_range: None,
+                    }
+                ),
+                (
+                    pending_empty_id,
+                    DapCollectionJobInfo {
+                        status: DapCollectionJob::Pending,
+                        queued_buckets: Some(0),
+                        batch_time_range: None,
+                    }
+                ),
+                (
+                    done_id,
+                    DapCollectionJobInfo {
+                        status: DapCollectionJob::Done(collection),
+                        queued_buckets: None,
+                        batch_time_range: None,
+                    }
+                ),
+            ]
+        );
+
+        // Once the aggregation job is dequeued, no buckets remain queued.
+        t.leader.dequeue_work(100).await.unwrap();
+        let coll_jobs = t.leader.list_collection_jobs(task_id).await.unwrap();
+        assert!(coll_jobs
+            .iter()
+            .all(|(_, info)| info.queued_buckets.unwrap_or_default() == 0));
+    }
+
+    async_test_versions! { list_collection_jobs }
+
+    async fn agg_job_status(version: DapVersion) {
+        let t = Test::new(version);
+        let task_id = &t.time_interval_task_id;
+        let task_config = t.leader.unchecked_get_task_config(task_id).await;
+
+        let report = t.gen_test_report(task_id).await;
+        let req = t.gen_test_upload_req(report, task_id).await;
+        leader::handle_upload_req(&*t.leader, &req).await.unwrap();
+
+        let query = task_config.query_for_current_batch_window(t.now);
+        let req = t.gen_test_coll_job_req(query, task_id).await;
+        leader::handle_coll_job_req(&*t.leader, &req).await.unwrap();
+
+        // Peek at the aggregation job queued for the collection job.
+        let (item_ids, work_items): (Vec<_>, Vec<_>) = t
+            .leader
+            .dequeue_work(100)
+            .await
+            .unwrap()
+            .into_iter()
+            .unzip();
+        let agg_job_id = work_items
+            .iter()
+            .find_map(|work_item| match work_item {
+                WorkItem::AggregationJob { agg_job_id, .. } => Some(*agg_job_id),
+                WorkItem::CollectionJob { .. } => None,
+            })
+            .unwrap();
+        t.leader.enqueue_work(work_items).await.unwrap();
+        t.leader.ack_work(item_ids).await.unwrap();
+        assert_eq!(
+            t.leader.agg_job_status(task_id, &agg_job_id).await.unwrap(),
+            Some(DapAggregationJobStatus::Queued)
+        );
+
+        leader::process(&*t.leader, "leader.com", 100)
+            .await
+            .unwrap();
+        assert_eq!(
+            t.leader.agg_job_status(task_id, &agg_job_id).await.unwrap(),
+            Some(DapAggregationJobStatus::Completed)
+        );
+
+        // An aggregation job that was never queued is unknown.
+        assert_eq!(
+            t.leader
+                .agg_job_status(task_id, &MetaAggregationJobId::gen_for_version(version))
+                .await
+                .unwrap(),
+            None
+        );
+    }
+
+    async_test_versions! { agg_job_status }
+
+    async fn agg_job_status_evicts_finished_jobs(version: DapVersion) {
+        let t = Test::new(version);
+        let task_id = &t.time_interval_task_id;
+
+        let queued_agg_job_id = MetaAggregationJobId::gen_for_version(version);
+        t.leader
+            .set_agg_job_status(task_id, &queued_agg_job_id, DapAggregationJobStatus::Queued)
+            .await
+            .unwrap();
+        let finished_agg_job_ids = (0..=MAX_FINISHED_AGG_JOBS_PER_TASK)
+            .map(|_| MetaAggregationJobId::gen_for_version(version))
+            .collect::<Vec<_>>();
+        for agg_job_id in &finished_agg_job_ids {
+            t.leader
+                .set_agg_job_status(task_id, agg_job_id, DapAggregationJobStatus::Completed)
+                .await
+                .unwrap();
+        }
+
+        // Only the job that finished first is forgotten.
+        assert_eq!(
+            t.leader
+                .agg_job_status(task_id, &finished_agg_job_ids[0])
+                .await
+                .unwrap(),
+            None
+        );
+        assert_eq!(
+            t.leader
+                .agg_job_status(task_id, &finished_agg_job_ids[1])
+                .await
+                .unwrap(),
+            Some(DapAggregationJobStatus::Completed)
+        );
+        assert_eq!(
+            t.leader
+                .agg_job_status(task_id, &queued_agg_job_id)
+                .await
+                .unwrap(),
+            Some(DapAggregationJobStatus::Queued)
+        );
+    }
+
+    async_test_versions! { agg_job_status_evicts_finished_jobs }
+
+    async fn work_queue_stats(version: DapVersion) {
+        let t = Test::new(version);
+        let mut rng = thread_rng();
+        let agg_job = |task_id| WorkItem::AggregationJob {
+            task_id,
+            agg_job_id: MetaAggregationJobId::gen_for_version(version),
+            part_batch_sel: PartialBatchSelector::TimeInterval,
+            agg_param: DapAggregationParam::Empty,
+            reports: Vec::new(),
+            retries_remaining: 0,
+            not_before: None,
+        };
+        let coll_job = WorkItem::CollectionJob {
+            task_id: t.fixed_size_task_id,
+            coll_job_id: CollectionJobId(rng.gen()),
+            batch_sel: BatchSelector::FixedSizeByBatchId {
+                batch_id: BatchId(rng.gen()),
+            },
+            agg_param: DapAggregationParam::Empty,
+            collector_hpke_config_id: None,
+            requeues: 0,
+        };
+
+        assert_eq!(
+            t.leader.work_queue_stats().await.unwrap(),
+            WorkQueueStats::default()
+        );
+
+        t.leader
+            .enqueue_work(vec![
+                agg_job(t.time_interval_task_id),
+                agg_job(t.time_interval_task_id),
+                coll_job,
+            ])
+            .await
+            .unwrap();
+        let stats = t.leader.work_queue_stats().await.unwrap();
+        assert_eq!(stats.len, 3);
+        assert_eq!(stats.agg_jobs, 2);
+        assert_eq!(stats.coll_jobs, 1);
+        assert_eq!(
+            stats.per_task[&t.time_interval_task_id],
+            WorkQueueTaskStats {
+                agg_jobs: 2,
+                coll_jobs: 0
+            }
+        );
+        assert_eq!(
+            stats.per_task[&t.fixed_size_task_id],
+            WorkQueueTaskStats {
+                agg_jobs: 0,
+                coll_jobs: 1
+            }
+        );
+
+        // Leased items are no longer counted.
+        let leased = t.leader.dequeue_work(1).await.unwrap();
+        assert_eq!(leased.len(), 1);
+        assert_eq!(t.leader.work_queue_stats().await.unwrap().len, 2);
+    }
+
+    async_test_versions! { work_queue_stats }
+
+    async fn replace_task_config_extends_expiration(version: DapVersion) {
+        let t = Test::new(version);
+        let task_id = t.time_interval_task_id;
+        let mut task_config = t.helper.unchecked_get_task_config(&task_id).await;
+        task_config.expiration += 3600;
+
+        t.helper
+            .replace_task_config_if_compatible(&task_id, task_config.clone())
+            .await
+            .unwrap();
+        assert_eq!(
+            t.helper
+                .unchecked_get_task_config(&task_id)
+                .await
+                .expiration,
+            task_config.expiration
+        );
+    }
+
+    async_test_versions! { replace_task_config_extends_expiration }
+
+    async fn replace_task_config_rejects_vdaf_change(version: DapVersion) {
+        let t = Test::new(version);
+        let task_id = t.time_interval_task_id;
+        let current = t.helper.unchecked_get_task_config(&task_id).await;
+        let mut task_config = current.clone();
+        task_config.vdaf = VdafConfig::Prio2 { dimension: 10 };
+
+        assert_matches!(
+            t.helper
+                .replace_task_config_if_compatible(&task_id, task_config)
+                .await,
+            Err(DapError::Abort(DapAbort::TaskprovMismatch { .. }))
+        );
+
+        // The current config is kept.
+        assert_eq!(
+            t.helper.unchecked_get_task_config(&task_id).await.vdaf,
+            current.vdaf
+        );
+    }
+
+    async_test_versions! { replace_task_config_rejects_vdaf_change }
+
+    async fn bucket_for_report_same_window(version: DapVersion) {
+        let t = Test::new(version);
+        let task_config = t
+            .leader
+            .unchecked_get_task_config(&t.time_interval_task_id)
+            .await;
+        let window_start = task_config.quantized_time_lower_bound(t.now);
+        let first = t
+            .gen_test_report_for_measurement_at(
+                &t.time_interval_task_id,
+                DapMeasurement::U64(1),
+                window_start,
+            )
+            .await;
+        let last = t
+            .gen_test_report_for_measurement_at(
+                &t.time_interval_task_id,
+                DapMeasurement::U64(1),
+                window_start + task_config.time_precision - 1,
+            )
+            .await;
+
+        let bucket = task_config.bucket_for_report(&first);
+        assert_eq!(
+            bucket,
+            Some(DapBatchBucket::TimeInterval {
+                batch_window: window_start
+            })
+        );
+        assert_eq!(task_config.bucket_for_report(&last), bucket);
+
+        // For fixed-size tasks, the bucket depends on the Leader's batch assignment.
+        let task_config = t
+            .leader
+            .unchecked_get_task_config(&t.fixed_size_task_id)
+            .await;
+        assert_eq!(task_config.bucket_for_report(&first), None);
+    }
+
+    async_test_versions! { bucket_for_report_same_window }
+
+    async fn get_agg_share_reproducible(version: DapVersion) {
+        let t = Test::new(version);
+        let task_id = &t.time_interval_task_id;
+        let task_config = t.helper.unchecked_get_task_config(task_id).await;
+        let start = task_config.quantized_time_lower_bound(t.now);
+        let batch_window = |i: u64| start + i * task_config.time_precision;
+
+        // Store an aggregate share for each of a number of batch windows, latest first, so that the
+        // order in which they are stored differs from the order of the batch interval.
+        {
+            let mut guard = t
+                .helper
+                .agg_store
+                .lock()
+                .expect("agg_store: failed to lock");
+            let agg_store = guard.entry(*task_id).or_default();
+            for i in (0..16).rev() {
+                agg_store.insert(
+                    (
+                        DapBatchBucket::TimeInterval {
+                            batch_window: batch_window(i),
+                        },
+                        DapAggregationParam::Empty,
+                    ),
+                    AggStore {
+                        agg_share: DapAggregateShare {
+                            report_count: 1,
+                            min_time: batch_window(i),
+                            max_time: batch_window(i),
+                            checksum: [u8::try_from(i).unwrap(); 32],
+                            data: Some(VdafAggregateShare::Field64(
+                                prio::vdaf::AggregateShare::from(vec![Field64::from(i)]),
+                            )),
+                        },
+                        report_ids: Vec::new(),
+                        collected: false,
+                    },
+                );
+            }
+        }
+
+        let batch_sel = BatchSelector::TimeInterval {
+            batch_interval: Interval {
+                start,
+                duration: 16 * task_config.time_precision,
+            },
+        };
+        let get_agg_share = || {
+            t.helper
+                .get_agg_share(task_id, &batch_sel, &DapAggregationParam::Empty)
+        };
+        let first = get_agg_share().await.unwrap();
+        let second = get_agg_share().await.unwrap();
+        assert_eq!(first.report_count, 16);
+        assert_eq!(
+            first.data.as_ref().unwrap().get_encoded().unwrap(),
+            second.data.as_ref().unwrap().get_encoded().unwrap()
+        );
+        assert_eq!(
+            serde_json::to_vec(&first).unwrap(),
+            serde_json::to_vec(&second).unwrap()
+        );
+
+        // The buckets are visited in order: once the later ones have been collected, the overlap
+        // is always reported for the earliest of them.
+        {
+            let mut guard = t
+                .helper
+                .agg_store
+                .lock()
+                .expect("agg_store: failed to lock");
+            for ((bucket, _agg_param), inner_agg_store) in guard.get_mut(task_id).unwrap() {
+                if *bucket
+                    >= (DapBatchBucket::TimeInterval {
+                        batch_window: batch_window(4),
+                    })
+                {
+                    inner_agg_store.collected = true;
+                }
+            }
+        }
+        for _ in 0..2 {
+            assert_matches!(
+                get_agg_share().await,
+                Err(DapError::Abort(DapAbort::BatchOverlap { detail, .. }))
+                    if detail.ends_with(&format!("Previously collected: batch_window({})", batch_window(4)))
+            );
+        }
+    }
+
+    async_test_versions! { get_agg_share_reproducible }
+
+    async fn get_agg_share_before_aggregation(version: DapVersion) {
+        let t = Test::new(version);
+        let task_id = &t.time_interval_task_id;
+        let task_config = t.helper.unchecked_get_task_config(task_id).await;
+        let batch_sel = BatchSelector::TimeInterval {
+            batch_interval: Interval {
+                start: task_config.quantized_time_lower_bound(t.now),
+                duration: task_config.time_precision,
+            },
+        };
+
+        // Nothing has been aggregated for the batch, so there is no aggregate share to collect.
+        assert_matches!(
+            t.helper
+                .get_agg_share(task_id, &batch_sel, &DapAggregationParam::Empty)
+                .await,
+            Err(DapError::Abort(DapAbort::InvalidBatchSize { .. }))
+        );
+    }
+
+    async_test_versions! { get_agg_share_before_aggregation }
+
+    async fn batch_span_for_sel_end_exclusive(version: DapVersion) {
+        let t = Test::new(version);
+        let task_config = t
+            .leader
+            .unchecked_get_task_config(&t.time_interval_task_id)
+            .await;
+        let batch_interval = Interval {
+            start: task_config.quantized_time_lower_bound(t.now),
+            duration: 2 * task_config.time_precision,
+        };
+        let span = task_config
+            .batch_span_for_sel(&BatchSelector::TimeInterval {
+                batch_interval: batch_interval.clone(),
+            })
+            .unwrap();
+        let bucket_for_time = |time| DapBatchBucket::TimeInterval {
+            batch_window: task_config.quantized_time_lower_bound(time),
+        };
+
+        // A report at the start of the interval is included.
+        assert!(span.contains(&bucket_for_time(batch_interval.start)));
+
+        // A report at the last second of the interval is included.
+        assert!(span.contains(&bucket_for_time(batch_interval.end() - 1)));
+
+        // A report at the end of the interval is excluded.
+        assert!(!span.contains(&bucket_for_time(batch_interval.end())));
+        assert_eq!(span.len(), 2);
+    }
+
+    async_test_versions! { batch_span_for_sel_end_exclusive }
+
+    async fn batch_span_iter_for_sel_matches_batch_span_for_sel(version: DapVersion) {
+        let t = Test::new(version);
+        let task_config = t
+            .leader
+            .unchecked_get_task_config(&t.time_interval_task_id)
+            .await;
+
+        // An interval that isn't aligned to the time precision overlaps one more batch window than
+        // its duration covers.
+        let start = task_config.quantized_time_lower_bound(t.now);
+        let batch_sel = BatchSelector::TimeInterval {
+            batch_interval: Interval {
+                start: start + 1,
+                duration: 5 * task_config.time_precision,
+            },
+        };
+        let buckets = task_config
+            .batch_span_iter_for_sel(&batch_sel)
+            .unwrap()
+            .collect::<Vec<_>>();
+        assert_eq!(
+            buckets,
+            (0..6)
+                .map(|i| DapBatchBucket::TimeInterval {
+                    batch_window: start + i * task_config.time_precision
+                })
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            buckets.into_iter().collect::<HashSet<_>>(),
+            task_config.batch_span_for_sel(&batch_sel).unwrap()
+        );
+
+        let task_config = t
+            .leader
+            .unchecked_get_task_config(&t.fixed_size_task_id)
+            .await;
+        let batch_sel = BatchSelector::FixedSizeByBatchId {
+            batch_id: BatchId(thread_rng().gen()),
+        };
+        assert_eq!(
+            task_config
+                .batch_span_iter_for_sel(&batch_sel)
+                .unwrap()
+                .collect::<HashSet<_>>(),
+            task_config.batch_span_for_sel(&batch_sel).unwrap()
+        );
+    }
+
+    async_test_versions! { batch_span_iter_for_sel_matches_batch_span_for_sel }
+
+    async fn quantized_time_zero_offset(version: DapVersion) {
+        let t = Test::new(version);
+        let task_config = t
+            .leader
+            .unchecked_get_task_config(&t.time_interval_task_id)
+            .await;
+        assert_eq!(task_config.time_quantization_offset, 0);
+        let time_precision = task_config.time_precision;
+
+        // Batch windows are aligned to the UNIX epoch.
+        let time = 1_000 * time_precision + 17;
+        assert_eq!(
+            task_config.quantized_time_lower_bound(time),
+            1_000 * time_precision
+        );
+        assert_eq!(
+            task_config.quantized_time_upper_bound(time),
+            1_001 * time_precision
+        );
+        assert_eq!(
+            task_config.quantized_time_lower_bound(1_000 * time_precision),
+            1_000 * time_precision
+        );
+    }
+
+    async_test_versions! { quantized_time_zero_offset }
+
+    async fn quantized_time_nonzero_offset(version: DapVersion) {
+        let t = Test::new(version);
+        let mut task_config = t
+            .leader
+            .unchecked_get_task_config(&t.time_interval_task_id)
+            .await;
+        let time_precision = task_config.time_precision;
+        let offset = time_precision / 3;
+        task_config.time_quantization_offset = offset;
+        let window_start = 1_000 * time_precision + offset;
+
+        // Reports bucket into windows that start at the offset rather than at a multiple of the
+        // time precision.
+        for time in [
+            window_start,
+            window_start + 1,
+            window_start + time_precision - 1,
+        ] {
+            assert_eq!(task_config.quantized_time_lower_bound(time), window_start);
+            assert_eq!(
+                task_config.quantized_time_upper_bound(time),
+                window_start + time_precision
+            );
+        }
+        assert_eq!(
+            task_config.quantized_time_lower_bound(window_start - 1),
+            window_start - time_precision
+        );
+
+        // Times before the first offset window don't underflow.
+        assert_eq!(task_config.quantized_time_lower_bound(offset - 1), 0);
+        assert_eq!(task_config.quantized_time_lower_bound(0), 0);
+
+        // Offsets of a whole time precision or more wrap around.
+        task_config.time_quantization_offset = offset + 2 * time_precision;
+        assert_eq!(
+            task_config.quantized_time_lower_bound(window_start + 1),
+            window_start
+        );
+        task_config.time_quantization_offset = offset;
+
+        // The batch span walks offset-aligned windows.
+        let span = task_config
+            .batch_span_for_sel(&BatchSelector::TimeInterval {
+                batch_interval: Interval {
+                    start: window_start,
+                    duration: 2 * time_precision,
+                },
+            })
+            .unwrap();
+        assert_eq!(
+            span,
+            HashSet::from([
+                DapBatchBucket::TimeInterval {
+                    batch_window: window_start
+                },
+                DapBatchBucket::TimeInterval {
+                    batch_window: window_start + time_precision
+                },
+            ])
+        );
+    }
+
+    async_test_versions! { quantized_time_nonzero_offset }
+
+    async fn handle_coll_job_req_fail_invalid_batch_interval(version: DapVersion) {
+        let t = Test::new(version);
+        let task_id = &t.time_interval_task_id;
+        let task_config = t.leader.unchecked_get_task_config(task_id).await;
+
+        // Collector: Create a CollectReq with a very large batch interval.
+        let req = t.collector_authorized_req(
+            task_id,
+            &task_config,
+            DapMediaType::CollectReq,
+            CollectionReq {
+                draft02_task_id: task_id.for_request_payload(&version),
+                query: Query::TimeInterval {
+                    batch_interval: Interval {
+                        start: task_config.quantized_time_lower_bound(t.now),
+                        duration: t.leader.global_config.max_batch_duration
+                            + task_config.time_precision,
+                    },
+                },
+                agg_param: Vec::default(),
+            },
+        );
+
+        // Leader: Handle the CollectReq received from Collector.
+        let err = leader::handle_coll_job_req(&*t.leader, &req)
+            .await
+            .unwrap_err();
+
+        // Fails because the requested batch interval is too large.
+        assert_matches!(err, DapError::Abort(DapAbort::BadRequest(s)) => assert_eq!(s, "batch interval too large".to_string()));
+
+        // Collector: Create a CollectReq with a batch interval in the past.
+        let req = t.collector_authorized_req(
+            task_id,
+            &task_config,
+            DapMediaType::CollectReq,
+            CollectionReq {
+                draft02_task_id: task_id.for_request_payload(&version),
+                query: Query::TimeInterval {
+                    batch_interval: Interval {
+                        start: task_config.quantized_time_lower_bound(t.now)
+                            - t.leader.global_config.min_batch_interval_start
+                            - task_config.time_precision,
+                        duration: task_config.time_precision * 2,
+                    },
+                },
+                agg_param: Vec::default(),
+            },
+        );
+
+        // Leader: Handle the CollectReq received from Collector.
+        let err = leader::handle_coll_job_req(&*t.leader, &req)
+            .await
+            .unwrap_err();
+
+        // Fails because the requested batch interval is too far into the past.
+        assert_matches!(err, DapError::Abort(DapAbort::BadRequest(s)) => assert_eq!(s, "batch interval too far into past".to_string()));
+
+        // Collector: Create a CollectReq with a batch interval in the future.
+        let req = t.collector_authorized_req(
+            task_id,
+            &task_config,
+            DapMediaType::CollectReq,
+            CollectionReq {
+                draft02_task_id: task_id.for_request_payload(&version),
+                query: Query::TimeInterval {
+                    batch_interval: Interval {
+                        start: task_config.quantized_time_lower_bound(t.now)
+                            + t.leader.global_config.max_batch_interval_end
+                            - task_config.time_precision,
+                        duration: task_config.time_precision * 2,
+                    },
+                },
+                agg_param: Vec::default(),
+            },
+        );
+
+        // Leader: Handle the CollectReq received from Collector.
+        let err = leader::handle_coll_job_req(&*t.leader, &req)
+            .await
+            .unwrap_err();
+
+        // Fails because the requested batch interval is too far into the future.
+        assert_matches!(err, DapError::Abort(DapAbort::BadRequest(s)) => assert_eq!(s, "batch interval too far into future".to_string()));
+    }
+
+    async_test_versions! { handle_coll_job_req_fail_invalid_batch_interval }
+
+    async fn handle_coll_job_req_align_batch_interval(version: DapVersion) {
+        let mut data = TestData::new(version);
+        let task_id = data.time_interval_task_id;
+        let time_precision = data.tasks[&task_id].time_precision;
+        let start = data.tasks[&task_id].quantized_time_lower_bound(data.now) - time_precision;
+        let misaligned = Interval {
+            start: start + 1,
+            duration: time_precision,
+        };
+        let aligned = Interval {
+            start,
+            duration: 2 * time_precision,
+        };
+        assert_eq!(
+            misaligned.align_to(&data.tasks[&task_id]),
+            Some(aligned.clone())
+        );
+        assert_eq!(
+            aligned.align_to(&data.tasks[&task_id]),
+            Some(aligned.clone())
+        );
+        assert_eq!(
+            Interval { start, duration: 0 }.align_to(&data.tasks[&task_id]),
+            Some(Interval {
+                start,
+                duration: time_precision
+            })
+        );
+        assert_eq!(
+            Interval {
+                start: u64::MAX - 1,
+                duration: 1,
+            }
+            .align_to(&data.tasks[&task_id]),
+            None
+        );
+        assert_eq!(
+            Interval {
+                start: u64::MAX,
+                duration: 1,
+            }
+            .align_to(&data.tasks[&task_id]),
+            None
+        );
+
+        data.tasks.get_mut(&task_id).unwrap().align_batch_intervals = true;
+        let collector_hpke_receiver_config = data.collector_hpke_receiver_config.clone();
+        let helper = data.new_helper();
+        let t = data.with_leader(helper);
+        let task_id = &task_id;
+        let task_config = t.leader.unchecked_get_task_config(task_id).await;
+
+        let report = t.gen_test_report(task_id).await;
+        leader::handle_upload_req(&*t.leader, &t.gen_test_upload_req(report, task_id).await)
+            .await
+            .unwrap();
+
+        let req = t
+            .gen_test_coll_job_req(
+                Query::TimeInterval {
+                    batch_interval: misaligned.clone(),
+                },
+                task_id,
+            )
+            .await;
+        let created = leader::handle_coll_job_req(&*t.leader, &req).await.unwrap();
+        assert_eq!(created.aligned_batch_interval, Some(aligned));
+        leader::process(&*t.leader, "leader.com", 100)
+            .await
+            .unwrap();
+
+        // The report in the aligned interval is collected, but the aggregate shares are bound to
+        // the interval the Collector requested.
+        let [(_coll_job_id, coll_job_info)] =
+            <[_; 1]>::try_from(t.leader.list_collection_jobs(task_id).await.unwrap()).unwrap();
+        let DapCollectionJob::Done(collection) = coll_job_info.status else {
+            panic!("collection job is not done");
+        };
+        let agg_res = task_config
+            .vdaf
+            .consume_encrypted_agg_shares(
+                &collector_hpke_receiver_config,
+                task_id,
+                &BatchSelector::TimeInterval {
+                    batch_interval: misaligned,
+                },
+                collection.report_count,
+                &DapAggregationParam::Empty,
+                collection.encrypted_agg_shares.to_vec(),
+                version,
+            )
+            .await
+            .unwrap();
+        assert_eq!(agg_res, DapAggregateResult::U64(1));
+    }
+
+    async_test_versions! { handle_coll_job_req_align_batch_interval }
+
+    async fn handle_coll_job_req_misaligned_batch_interval_strict(version: DapVersion) {
+        let t = Test::new(version);
+        let task_id = &t.time_interval_task_id;
+        let task_config = t.leader.unchecked_get_task_config(task_id).await;
+        assert!(!task_config.align_batch_intervals);
+
+        let req = t
+            .gen_test_coll_job_req(
+                Query::TimeInterval {
+                    batch_interval: Interval {
+                        start: task_config.quantized_time_lower_bound(t.now) + 1,
+                        duration: task_config.time_precision,
+                    },
+                },
+                task_id,
+            )
+            .await;
+        assert_matches!(
+            leader::handle_coll_job_req(&*t.leader, &req)
+                .await
+                .unwrap_err(),
+            DapError::Abort(DapAbort::BatchInvalid { .. })
+        );
+    }
+
+    async_test_versions! { handle_coll_job_req_misaligned_batch_interval_strict }
+
+    async fn handle_coll_job_req_succeed_max_batch_interval(version: DapVersion) {
+        let t = Test::new(version);
+        let task_id = &t.time_interval_task_id;
+        let task_config = t.leader.unchecked_get_task_config(task_id).await;
+
+        // Collector: Create a CollectReq with a very large batch interval.
+        let req = t.collector_authorized_req(
+            task_id,
+            &task_config,
+            DapMediaType::CollectReq,
+            CollectionReq {
+                draft02_task_id: task_id.for_request_payload(&version),
+                query: Query::TimeInterval {
+                    batch_interval: Interval {
+                        start: task_config.quantized_time_lower_bound(t.now)
+                            - t.leader.global_config.max_batch_duration / 2,
+                        duration: t.leader.global_config.max_batch_duration,
+                    },
+                },
+                agg_param: Vec::default(),
+            },
+        );
+
+        // Leader: Handle the CollectReq received from Collector.
+        let _collect_uri = leader::handle_coll_job_req(&*t.leader, &req).await.unwrap();
+    }
+
+    async_test_versions! { handle_coll_job_req_succeed_max_batch_interval }
+
+    async fn handle_coll_job_req_fail_overlapping_batch_interval(version: DapVersion) {
+        let t = Test::new(version);
+        let task_id = &t.time_interval_task_id;
+        let task_config = t.leader.unchecked_get_task_config(task_id).await;
+
+        let report = t.gen_test_report(task_id).await;
+        let req = t.gen_test_upload_req(report.clone(), task_id).await;
+        leader::handle_upload_req(&*t.leader, &req).await.unwrap();
+
+        let query = task_config.query_for_current_batch_window(t.now);
+        let req = t.gen_test_coll_job_req(query.clone(), task_id).await;
+        leader::handle_coll_job_req(&*t.leader, &req).await.unwrap();
+
+        leader::process(&*t.leader, "leader.com", 100)
+            .await
+            .unwrap();
+
+        // Request a new collection job for the same batch. Expect failure due to overlapping
+        // batch.
+        let req = t.gen_test_coll_job_req(query, task_id).await;
+        assert_matches!(
+            leader::handle_coll_job_req(&*t.leader, &req)
+                .await
+                .unwrap_err(),
+            DapError::Abort(DapAbort::BatchOverlap { .. })
+        );
+    }
+
+    async_test_versions! { handle_coll_job_req_fail_overlapping_batch_interval }
+
+    async fn handle_coll_job_req_fail_overlapping_batch_names_batch(version: DapVersion) {
+        let t = Test::new(version);
+        let task_id = &t.fixed_size_task_id;
+
+        let report = t.gen_test_report(task_id).await;
+        leader::handle_upload_req(&*t.leader, &t.gen_test_upload_req(report, task_id).await)
+            .await
+            .unwrap();
+
+        let batch_id = t.leader.current_batch(task_id).await.unwrap();
+        let req = t
+            .gen_test_coll_job_req(Query::FixedSizeByBatchId { batch_id }, task_id)
+            .await;
+        leader::handle_coll_job_req(&*t.leader, &req).await.unwrap();
+        leader::process(&*t.leader, "leader.com", 100)
+            .await
+            .unwrap();
+
+        // Request a new collection job for the same batch. Expect the rejection to name the batch
+        // that was already collected.
+        let req = t
+            .gen_test_coll_job_req(Query::FixedSizeByBatchId { batch_id }, task_id)
+            .await;
+        assert_matches!(
+            leader::handle_coll_job_req(&*t.leader, &req)
+                .await
+                .unwrap_err(),
+            DapError::Abort(DapAbort::BatchOverlap { detail, .. })
+                if detail.ends_with(&format!("Previously collected: batch_id({})", batch_id.to_base64url()))
+        );
+    }
+
+    async_test_versions! { handle_coll_job_req_fail_overlapping_batch_names_batch }
+
+    async fn handle_coll_job_req_fail_unrecongized_batch(version: DapVersion) {
+        let t = Test::new(version);
+        let task_id = &t.fixed_size_task_id;
+
+        let req = t
+            .gen_test_coll_job_req(
+                Query::FixedSizeByBatchId {
+                    batch_id: BatchId(thread_rng().gen()),
+                },
+                task_id,
+            )
+            .await;
+
+        // Expect failure due to unrecognized batch
+        assert_matches!(
+            leader::handle_coll_job_req(&*t.leader, &req)
+                .await
+                .unwrap_err(),
+            DapError::Abort(DapAbort::BatchInvalid { .. })
+        );
+    }
+
+    async_test_versions! { handle_coll_job_req_fail_unrecongized_batch }
+
+    async fn handle_coll_job_req_fixed_size_by_batch_id_and_current_batch(version: DapVersion) {
+        let mut data = TestData::new(version);
+        let task_id = data.fixed_size_task_id;
+        data.tasks.get_mut(&task_id).unwrap().min_batch_size = 2;
+        let helper = data.new_helper();
+        let t = data.with_leader(helper);
+        let task_id = &task_id;
+
+        // Fill the first batch and start filling the second.
+        for _ in 0..3 {
+            let report = t.gen_test_report(task_id).await;
+            leader::handle_upload_req(&*t.leader, &t.gen_test_upload_req(report, task_id).await)
+                .await
+                .unwrap();
+        }
+
+        // Collect the first batch by its ID.
+        let batch_id = t.leader.current_batch(task_id).await.unwrap();
+        let req = t
+            .gen_test_coll_job_req(Query::FixedSizeByBatchId { batch_id }, task_id)
+            .await;
+        leader::handle_coll_job_req(&*t.leader, &req).await.unwrap();
+        leader::process(&*t.leader, "leader.com", 100)
+            .await
+            .unwrap();
+        let [(_coll_job_id, coll_job_info)] =
+            <[_; 1]>::try_from(t.leader.list_collection_jobs(task_id).await.unwrap()).unwrap();
+        assert_matches!(
+            coll_job_info.status,
+            DapCollectionJob::Done(collection) if collection.report_count == 2
+        );
+
+        // The batch can't be collected twice.
+        let req = t
+            .gen_test_coll_job_req(Query::FixedSizeByBatchId { batch_id }, task_id)
+            .await;
+        assert_matches!(
+            leader::handle_coll_job_req(&*t.leader, &req)
+                .await
+                .unwrap_err(),
+            DapError::Abort(DapAbort::BatchOverlap { .. })
+        );
+
+        // The current batch is still being filled, so it can't be collected yet.
+        let filling_batch_id = t.leader.current_batch(task_id).await.unwrap();
+        assert_ne!(filling_batch_id, batch_id);
+        if version != DapVersion::Draft02 {
+            assert_matches!(
+                leader::handle_coll_job_req(
+                    &*t.leader,
+                    &t.gen_test_coll_job_req(Query::FixedSizeCurrentBatch, task_id)
+                        .await,
+                )
+                .await
+                .unwrap_err(),
+                DapError::Abort(DapAbort::InvalidBatchSize { .. })
+            );
+        }
+
+        // Once the current batch is full, it can be collected.
+        let report = t.gen_test_report(task_id).await;
+        leader::handle_upload_req(&*t.leader, &t.gen_test_upload_req(report, task_id).await)
+            .await
+            .unwrap();
+        let query = match version {
+            DapVersion::Draft02 => Query::FixedSizeByBatchId {
+                batch_id: t.leader.current_batch(task_id).await.unwrap(),
+            },
+            DapVersion::DraftLatest => Query::FixedSizeCurrentBatch,
+        };
+        leader::handle_coll_job_req(&*t.leader, &t.gen_test_coll_job_req(query, task_id).await)
+            .await
+            .unwrap();
+        leader::process(&*t.leader, "leader.com", 100)
+            .await
+            .unwrap();
+        let coll_jobs = t.leader.list_collection_jobs(task_id).await.unwrap();
+        assert_eq!(coll_jobs.len(), 2);
+        assert!(coll_jobs
+            .iter()
+            .all(|(_coll_job_id, coll_job_info)| matches!(
+                &coll_job_info.status,
+                DapCollectionJob::Done(collection) if collection.report_count == 2
+            )));
+    }
+
+    async_test_versions! { handle_coll_job_req_fixed_size_by_batch_id_and_current_batch }
+
+    async fn reserve_current_batch(version: DapVersion) {
+        let mut data = TestData::new(version);
+        let task_id = data.fixed_size_task_id;
+        {
+            let task_config = data.tasks.get_mut(&task_id).unwrap();
+            task_config.min_batch_size = 2;
+            task_config.query = DapQueryConfig::FixedSize {
+                max_batch_size: Some(4),
+            };
+        }
+        let helper = data.new_helper();
+        let t = data.with_leader(helper);
+        let (t, task_id) = (&t, &task_id);
+
+        let upload = |n| async move {
+            for _ in 0..n {
+                let report = t.gen_test_report(task_id).await;
+                leader::handle_upload_req(
+                    &*t.leader,
+                    &t.gen_test_upload_req(report, task_id).await,
+                )
+                .await
+                .unwrap();
+            }
+        };
+
+        // Reserve a batch that is large enough to collect but could hold more reports.
+        upload(2).await;
+        let first = t.leader.reserve_current_batch(task_id).await.unwrap();
+        assert_matches!(
+            t.leader.reserve_current_batch(task_id).await.unwrap_err(),
+            DapError::Abort(DapAbort::BadRequest(..))
+        );
+
+        // New reports are assigned to a new batch rather than the reserved one.
+        upload(2).await;
+        let second = t.leader.reserve_current_batch(task_id).await.unwrap();
+        assert_ne!(first, second);
+
+        // The reserved batch contains only the reports assigned to it before it was reserved.
+        let req = t
+            .gen_test_coll_job_req(Query::FixedSizeByBatchId { batch_id: first }, task_id)
+            .await;
+        leader::handle_coll_job_req(&*t.leader, &req).await.unwrap();
+        leader::process(&*t.leader, "leader.com", 100)
+            .await
+            .unwrap();
+        let [(_coll_job_id, coll_job_info)] =
+            <[_; 1]>::try_from(t.leader.list_collection_jobs(task_id).await.unwrap()).unwrap();
+        assert_matches!(
+            coll_job_info.status,
+            DapCollectionJob::Done(collection) if collection.report_count == 2
+        );
+    }
+
+    async_test_versions! { reserve_current_batch }
+
+    // Test that the current batch is only reserved by a collection request that creates a job.
+    #[tokio::test]
+    async fn handle_coll_job_req_current_batch_reserved_with_job() {
+        let t = Test::new(DapVersion::DraftLatest);
+        let task_id = &t.fixed_size_task_id;
+
+        // Fill two batches.
+        for _ in 0..4 {
+            let report = t.gen_test_report(task_id).await;
+            leader::handle_upload_req(&*t.leader, &t.gen_test_upload_req(report, task_id).await)
+                .await
+                .unwrap();
+        }
+
+        let req = t
+            .gen_test_coll_job_req(Query::FixedSizeCurrentBatch, task_id)
+            .await;
+        let first = leader::handle_coll_job_req(&*t.leader, &req).await.unwrap();
+        let next_batch_id = t.leader.current_batch(task_id).await.unwrap();
+
+        // A retried request returns the existing job without reserving another batch.
+        let second = leader::handle_coll_job_req(&*t.leader, &req).await.unwrap();
+        assert_eq!(first.uri, second.uri);
+        assert_eq!(
+            t.leader.current_batch(task_id).await.unwrap(),
+            next_batch_id
+        );
+
+        // Reusing the job ID for the current batch is a conflict and leaves the batch in the
+        // queue.
+        let mut conflicting_req = t
+            .gen_test_coll_job_req(
+                Query::FixedSizeByBatchId {
+                    batch_id: next_batch_id,
+                },
+                task_id,
+            )
+            .await;
+        conflicting_req.resource = DapResource::CollectionJob(*req.collection_job_id().unwrap());
+        assert_matches!(
+            leader::handle_coll_job_req(&*t.leader, &conflicting_req)
+                .await
+                .unwrap_err(),
+            DapError::Abort(DapAbort::Conflict(..))
+        );
+        assert_eq!(
+            t.leader.current_batch(task_id).await.unwrap(),
+            next_batch_id
+        );
+    }
+
+    async fn batch_time_range_fixed_size(version: DapVersion) {
+        let mut data = TestData::new(version);
+        let task_id = data.fixed_size_task_id;
+        data.tasks.get_mut(&task_id).unwrap().min_batch_size = 2;
+        let helper = data.new_helper();
+        let t = data.with_leader(helper);
+        let task_id = &task_id;
+
+        for time in [t.now - 10, t.now - 300] {
+            let report = t
+                .gen_test_report_for_measurement_at(task_id, DapMeasurement::U64(1), time)
+                .await;
+            leader::handle_upload_req(&*t.leader, &t.gen_test_upload_req(report, task_id).await)
+                .await
+                .unwrap();
+        }
+
+        // The range spans the earliest and latest report.
+        let batch_id = t.leader.current_batch(task_id).await.unwrap();
+        let batch_time_range = Interval {
+            start: t.now - 300,
+            duration: 291,
+        };
+        assert_eq!(
+            t.leader.batch_time_range(task_id, &batch_id).await.unwrap(),
+            Some(batch_time_range.clone())
+        );
+        assert_eq!(
+            t.leader
+                .batch_time_range(task_id, &BatchId([0; 32]))
+                .await
+                .unwrap(),
+            None
+        );
+
+        // The range is included in the collection job's metadata.
+        let req = t
+            .gen_test_coll_job_req(Query::FixedSizeByBatchId { batch_id }, task_id)
+            .await;
+        leader::handle_coll_job_req(&*t.leader, &req).await.unwrap();
+        leader::process(&*t.leader, "leader.com", 100)
+            .await
+            .unwrap();
+        let [(_coll_job_id, coll_job_info)] =
+            <[_; 1]>::try_from(t.leader.list_collection_jobs(task_id).await.unwrap()).unwrap();
+        assert_matches!(
+            coll_job_info.status,
+            DapCollectionJob::Done(collection) if collection.report_count == 2
+        );
+        assert_eq!(coll_job_info.batch_time_range, Some(batch_time_range));
+
+        // Once collected, the range is no longer tracked for the batch itself.
+        assert_eq!(
+            t.leader.batch_time_range(task_id, &batch_id).await.unwrap(),
+            None
+        );
+    }
+
+    async_test_versions! { batch_time_range_fixed_size }
+
+    // Test a successful collect request submission.
+    // This checks that the Leader reponds with the collect ID with the ID associated to the request.
+    async fn handle_coll_job_req_success(version: DapVersion) {
+        let t = Test::new(version);
+        let task_id = &t.time_interval_task_id;
+        let task_config = t.leader.unchecked_get_task_config(task_id).await;
+
+        // Collector: Create a CollectReq.
+        let collector_collect_req = CollectionReq {
+            draft02_task_id: task_id.for_request_payload(&version),
+            query: task_config.query_for_current_batch_window(t.now),
+            agg_param: Vec::default(),
+        };
+        let req = t.collector_authorized_req(
+            task_id,
+            &task_config,
+            DapMediaType::CollectReq,
+            collector_collect_req.clone(),
+        );
 
         // Leader: Handle the CollectReq received from Collector.
+        let url = leader::handle_coll_job_req(&*t.leader, &req)
+            .await
+            .unwrap()
+            .uri;
+        let WorkItem::CollectionJob {
+            task_id: _,
+            coll_job_id: leader_collect_id,
+            batch_sel: leader_batch_sel,
+            agg_param: leader_agg_param,
+            collector_hpke_config_id: _,
+            requeues: _,
+        } = t.leader.dequeue_work(1).await.unwrap().pop().unwrap().1
+        else {
+            panic!("unexpected work item type");
+        };
+
+        // Check that the CollectReq sent by Collector is the same that is received by Leader.
+        assert_eq!(collector_collect_req.query, leader_batch_sel.into());
+        assert_eq!(
+            collector_collect_req.agg_param,
+            leader_agg_param.get_encoded().unwrap()
+        );
+
+        // Check that the collect_id included in the URI is the same with the one received
+        // by Leader.
+        let path = url.path().to_string();
+        let mut router = Router::new();
+        router
+            .insert("/:version/collect/task/:task_id/req/:collect_id", true)
+            .unwrap();
+        let url_match = router.at(&path).unwrap();
+        let collector_collect_id = url_match.params.get("collect_id").unwrap();
+        assert_eq!(
+            collector_collect_id.to_string(),
+            leader_collect_id.to_base64url()
+        );
+    }
+
+    async_test_versions! { handle_coll_job_req_success }
+
+    // Test that repeating a collection job initialization with the same parameters is treated as
+    // a retry and returns the existing job's URI.
+    async fn init_collect_job_idempotent_retry(version: DapVersion) {
+        let t = Test::new(version);
+        let task_id = &t.time_interval_task_id;
+        let task_config = t.leader.unchecked_get_task_config(task_id).await;
+
+        let report = t.gen_test_report(task_id).await;
+        leader::handle_upload_req(&*t.leader, &t.gen_test_upload_req(report, task_id).await)
+            .await
+            .unwrap();
+
+        let coll_job_id = Some(CollectionJobId(thread_rng().gen()));
+        let query = task_config.query_for_current_batch_window(t.now);
+        let first = t
+            .leader
+            .init_collect_job(
+                task_id,
+                &coll_job_id,
+                query.clone(),
+                DapAggregationParam::Empty,
+                None,
+            )
+            .await
+            .unwrap();
+        let work = t.leader.dequeue_work(100).await.unwrap();
+
+        let second = t
+            .leader
+            .init_collect_job(
+                task_id,
+                &coll_job_id,
+                query,
+                DapAggregationParam::Empty,
+                None,
+            )
+            .await
+            .unwrap();
+        assert_eq!(first, second);
+
+        // The retry doesn't queue any more work.
+        assert!(!work.is_empty());
+        assert!(t.leader.dequeue_work(100).await.unwrap().is_empty());
+    }
+
+    async_test_versions! { init_collect_job_idempotent_retry }
+
+    // Test that reusing a collection job ID with different parameters is rejected as a conflict.
+    async fn init_collect_job_conflicting_reuse(version: DapVersion) {
+        let t = Test::new(version);
+        let task_id = &t.time_interval_task_id;
+        let task_config = t.leader.unchecked_get_task_config(task_id).await;
+
+        let coll_job_id = Some(CollectionJobId(thread_rng().gen()));
+        t.leader
+            .init_collect_job(
+                task_id,
+                &coll_job_id,
+                task_config.query_for_current_batch_window(t.now),
+                DapAggregationParam::Empty,
+                None,
+            )
+            .await
+            .unwrap();
+
+        let err = t
+            .leader
+            .init_collect_job(
+                task_id,
+                &coll_job_id,
+                task_config.query_for_current_batch_window(t.now - task_config.time_precision),
+                DapAggregationParam::Empty,
+                None,
+            )
+            .await
+            .unwrap_err();
+        assert_matches!(err, DapError::Abort(DapAbort::Conflict(..)));
+    }
+
+    async_test_versions! { init_collect_job_conflicting_reuse }
+
+    // Test that the Collector can retry a collection request after the job it created has
+    // collected the batch.
+    #[tokio::test]
+    async fn handle_coll_job_req_retry_after_collection() {
+        let t = Test::new(DapVersion::DraftLatest);
+        let task_id = &t.time_interval_task_id;
+        let task_config = t.leader.unchecked_get_task_config(task_id).await;
+
+        let report = t.gen_test_report(task_id).await;
+        leader::handle_upload_req(&*t.leader, &t.gen_test_upload_req(report, task_id).await)
+            .await
+            .unwrap();
+        let query = task_config.query_for_current_batch_window(t.now);
+        let req = t.gen_test_coll_job_req(query.clone(), task_id).await;
+        let first = leader::handle_coll_job_req(&*t.leader, &req).await.unwrap();
+        leader::process(&*t.leader, "leader.com", 100)
+            .await
+            .unwrap();
+
+        // The batch overlaps with the one collected by the job, but the request is a retry.
+        let second = leader::handle_coll_job_req(&*t.leader, &req).await.unwrap();
+        assert_eq!(first.uri, second.uri);
+        assert!(t.leader.dequeue_work(100).await.unwrap().is_empty());
+
+        // A new collection job for the same batch is rejected.
+        assert_matches!(
+            leader::handle_coll_job_req(&*t.leader, &t.gen_test_coll_job_req(query, task_id).await)
+                .await
+                .unwrap_err(),
+            DapError::Abort(DapAbort::BatchOverlap { .. })
+        );
+
+        // Reusing the job's ID for a different batch is a conflict.
+        let mut conflicting_req = t
+            .gen_test_coll_job_req(
+                task_config.query_for_current_batch_window(t.now - task_config.time_precision),
+                task_id,
+            )
+            .await;
+        conflicting_req.resource = DapResource::CollectionJob(*req.collection_job_id().unwrap());
+        assert_matches!(
+            leader::handle_coll_job_req(&*t.leader, &conflicting_req)
+                .await
+                .unwrap_err(),
+            DapError::Abort(DapAbort::Conflict(..))
+        );
+    }
+
+    // Test that the Leader handles queries from the Collector properly.
+    async fn handle_coll_job_req_invalid_query(version: DapVersion) {
+        let mut rng = thread_rng();
+        let t = Test::new(version);
+
+        // Leader expects "time_interval" query, but Collector sent "fixed_size".
+        let task_config = t
+            .leader
+            .unchecked_get_task_config(&t.time_interval_task_id)
+            .await;
+        let req = t.collector_authorized_req(
+            &t.time_interval_task_id,
+            &task_config,
+            DapMediaType::CollectReq,
+            CollectionReq {
+                draft02_task_id: t.time_interval_task_id.for_request_payload(&version),
+                query: Query::FixedSizeByBatchId {
+                    batch_id: BatchId(rng.gen()),
+                },
+                agg_param: Vec::default(),
+            },
+        );
+        assert_matches!(
+            leader::handle_coll_job_req(&*t.leader, &req)
+                .await
+                .unwrap_err(),
+            DapError::Abort(DapAbort::QueryMismatch { .. })
+        );
+
+        // Collector indicates unrecognized batch ID.
+        let task_config = t
+            .leader
+            .unchecked_get_task_config(&t.fixed_size_task_id)
+            .await;
+        let req = t.collector_authorized_req(
+            &t.fixed_size_task_id,
+            &task_config,
+            DapMediaType::CollectReq,
+            CollectionReq {
+                draft02_task_id: t.fixed_size_task_id.for_request_payload(&version),
+                query: Query::FixedSizeByBatchId {
+                    batch_id: BatchId(rng.gen()), // Unrecognized batch ID
+                },
+                agg_param: Vec::default(),
+            },
+        );
+        assert_matches!(
+            leader::handle_coll_job_req(&*t.leader, &req)
+                .await
+                .unwrap_err(),
+            DapError::Abort(DapAbort::BatchInvalid { .. })
+        );
+    }
+
+    async_test_versions! { handle_coll_job_req_invalid_query }
+
+    async fn handle_upload_req(version: DapVersion) {
+        let t = Test::new(version);
+        let task_id = &t.time_interval_task_id;
+
+        let report = t.gen_test_report(task_id).await;
+        let req = t.gen_test_upload_req(report, task_id).await;
+
+        leader::handle_upload_req(&*t.leader, &req)
+            .await
+            .expect("upload failed unexpectedly");
+    }
+
+    async_test_versions! { handle_upload_req }
+
+    async fn memory_usage_grows_with_reports(version: DapVersion) {
+        let t = Test::new(version);
+        let task_id = &t.time_interval_task_id;
+        let task_config = t.leader.unchecked_get_task_config(task_id).await;
+        let leader_before = t.leader.memory_usage();
+        let helper_before = t.helper.memory_usage();
+
+        for _ in 0..3 {
+            let report = t.gen_test_report(task_id).await;
+            let req = t.gen_test_upload_req(report, task_id).await;
+            leader::handle_upload_req(&*t.leader, &req).await.unwrap();
+        }
+        // The reports are aggregated once the batch is collected.
+        let query = task_config.query_for_current_batch_window(t.now);
+        let req = t.gen_test_coll_job_req(query, task_id).await;
+        leader::handle_coll_job_req(&*t.leader, &req).await.unwrap();
+        leader::process(&*t.leader, "leader.com", 100)
+            .await
+            .unwrap();
+
+        let leader_after = t.leader.memory_usage();
+        let helper_after = t.helper.memory_usage();
+        assert!(leader_after.report_store > leader_before.report_store);
+        assert!(leader_after.agg_store > leader_before.agg_store);
+        assert!(leader_after.total > leader_before.total);
+        assert!(helper_after.report_store > helper_before.report_store);
+        assert!(helper_after.agg_store > helper_before.agg_store);
+        assert_eq!(leader_after.tasks, leader_before.tasks);
+
+        let breakdown = serde_json::to_value(leader_after).unwrap();
+        for component in ["tasks", "report_store", "agg_store", "helper_state_store"] {
+            assert!(breakdown.get(component).is_some(), "missing {component}");
+        }
+    }
+
+    async_test_versions! { memory_usage_grows_with_reports }
+
+    async fn handle_upload_req_pending_report_count(version: DapVersion) {
+        let t = Test::new(version);
+        let task_id = &t.time_interval_task_id;
+        let task_config = t.leader.unchecked_get_task_config(task_id).await;
+        let pending_counts = || {
+            let leader_state = t.leader.leader_state_store.lock().unwrap();
+            (
+                leader_state.pending_report_count(),
+                leader_state.pending_bucket_count(),
+            )
+        };
+
+        for _ in 0..3 {
+            let report = t.gen_test_report(task_id).await;
+            let req = t.gen_test_upload_req(report, task_id).await;
+            leader::handle_upload_req(&*t.leader, &req).await.unwrap();
+        }
+        assert_eq!(pending_counts(), (3, 1));
+
+        // Once a collection job is initialized, the reports are queued for aggregation.
+        let query = task_config.query_for_current_batch_window(t.now);
+        leader::handle_coll_job_req(&*t.leader, &t.gen_test_coll_job_req(query, task_id).await)
+            .await
+            .unwrap();
+        assert_eq!(pending_counts(), (0, 0));
+    }
+
+    async_test_versions! { handle_upload_req_pending_report_count }
+
+    async fn e2e_time_interval(version: DapVersion) {
+        let t = Test::new(version);
+        let task_id = &t.time_interval_task_id;
+        let task_config = t
+            .leader
+            .unchecked_get_task_config(&t.fixed_size_task_id)
+            .await;
+
+        // Client: Send upload request to Leader.
+        let report = t.gen_test_report(task_id).await;
+        leader::handle_upload_req(&*t.leader, &t.gen_test_upload_req(report, task_id).await)
+            .await
+            .unwrap();
+
+        // Collector: Request result from the Leader.
+        let query = task_config.query_for_current_batch_window(t.now);
+        leader::handle_coll_job_req(&*t.leader, &t.gen_test_coll_job_req(query, task_id).await)
+            .await
+            .unwrap();
+
+        leader::process(&*t.leader, "leader.com", 100)
+            .await
+            .unwrap();
+
+        let agg_job_req_count = match version {
+            DapVersion::Draft02 => 2,
+            DapVersion::DraftLatest => 1,
+        };
+
+        assert_metrics_include!(t.helper_registry, {
+            r#"inbound_request_counter{env="test_helper",host="helper.org",type="aggregate"}"#: agg_job_req_count,
+            r#"inbound_request_counter{env="test_helper",host="helper.org",type="collect"}"#: 1,
+            r#"report_counter{env="test_helper",host="helper.org",status="aggregated"}"#: 1,
+            r#"report_counter{env="test_helper",host="helper.org",status="collected"}"#: 1,
+            r#"aggregation_job_counter{env="test_helper",host="helper.org",status="started"}"#: 1,
+            r#"aggregation_job_counter{env="test_helper",host="helper.org",status="completed"}"#: 1,
+        });
+        assert_metrics_include!(t.leader_registry, {
+            r#"report_counter{env="test_leader",host="leader.com",status="aggregated"}"#: 1,
+            r#"report_counter{env="test_leader",host="leader.com",status="collected"}"#: 1,
+        });
+    }
+
+    async_test_versions! { e2e_time_interval }
+
+    async fn e2e_rotated_collector_hpke_config(version: DapVersion) {
+        let mut data = TestData::new(version);
+        let task_id = data.time_interval_task_id;
+        let rotated_config_id = data
+            .collector_hpke_receiver_config
+            .config
+            .id
+            .wrapping_add(1);
+        let rotated_collector_hpke_receiver_config =
+            HpkeReceiverConfig::gen(rotated_config_id, HpkeKemId::X25519HkdfSha256).unwrap();
+        data.tasks
+            .get_mut(&task_id)
+            .unwrap()
+            .collector_hpke_config_allowlist
+            .push(rotated_collector_hpke_receiver_config.config.clone());
+        let helper = data.new_helper();
+        let t = data.with_leader(helper);
+        let task_config = t.leader.unchecked_get_task_config(&task_id).await;
+
+        let report = t.gen_test_report(&task_id).await;
+        leader::handle_upload_req(&*t.leader, &t.gen_test_upload_req(report, &task_id).await)
+            .await
+            .unwrap();
+
+        // The Collector asks for the aggregate shares to be encrypted under its new config.
+        let query = task_config.query_for_current_batch_window(t.now);
+        let mut req = t.gen_test_coll_job_req(query.clone(), &task_id).await;
+        req.collector_hpke_config_id = Some(rotated_config_id);
         leader::handle_coll_job_req(&*t.leader, &req).await.unwrap();
+        leader::process(&*t.leader, "leader.com", 100)
+            .await
+            .unwrap();
+
+        let [(_coll_job_id, coll_job_info)] =
+            <[_; 1]>::try_from(t.leader.list_collection_jobs(&task_id).await.unwrap()).unwrap();
+        let DapCollectionJob::Done(collection) = coll_job_info.status else {
+            panic!("collection job is not done");
+        };
+        for encrypted_agg_share in &collection.encrypted_agg_shares {
+            assert_eq!(encrypted_agg_share.config_id, rotated_config_id);
+        }
+
+        let Query::TimeInterval { batch_interval } = query else {
+            panic!("unexpected query type");
+        };
+        let agg_res = task_config
+            .vdaf
+            .consume_encrypted_agg_shares(
+                &rotated_collector_hpke_receiver_config,
+                &task_id,
+                &BatchSelector::TimeInterval { batch_interval },
+                collection.report_count,
+                &DapAggregationParam::Empty,
+                collection.encrypted_agg_shares.to_vec(),
+                version,
+            )
+            .await
+            .unwrap();
+        assert_eq!(agg_res, DapAggregateResult::U64(1));
+    }
+
+    async_test_versions! { e2e_rotated_collector_hpke_config }
+
+    async fn coll_job_req_unknown_collector_hpke_config(version: DapVersion) {
+        let t = Test::new(version);
+        let task_id = &t.time_interval_task_id;
+        let task_config = t.leader.unchecked_get_task_config(task_id).await;
+
+        // A config that isn't accepted for the task is rejected before the collection job is
+        // created.
+        let query = task_config.query_for_current_batch_window(t.now);
+        let mut req = t.gen_test_coll_job_req(query, task_id).await;
+        req.collector_hpke_config_id = Some(task_config.collector_hpke_config.id.wrapping_add(1));
+        assert_matches!(
+            leader::handle_coll_job_req(&*t.leader, &req)
+                .await
+                .unwrap_err(),
+            DapError::Abort(DapAbort::OutdatedConfig { .. })
+        );
+        assert!(t
+            .leader
+            .list_collection_jobs(task_id)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    async_test_versions! { coll_job_req_unknown_collector_hpke_config }
+
+    async fn e2e_chunked_agg_job_resp(version: DapVersion) {
+        const NUM_REPORTS: u64 = 5;
+        let mut data = TestData::new(version);
+        data.global_config.agg_job_resp_chunk_size = Some(2);
+        let helper = data.new_helper();
+        let t = data.with_leader(helper);
+        let task_id = &t.time_interval_task_id;
+        let task_config = t.leader.unchecked_get_task_config(task_id).await;
+
+        for _ in 0..NUM_REPORTS {
+            let report = t.gen_test_report(task_id).await;
+            leader::handle_upload_req(&*t.leader, &t.gen_test_upload_req(report, task_id).await)
+                .await
+                .unwrap();
+        }
+
+        let query = task_config.query_for_current_batch_window(t.now);
+        leader::handle_coll_job_req(&*t.leader, &t.gen_test_coll_job_req(query, task_id).await)
+            .await
+            .unwrap();
+
+        leader::process(&*t.leader, "leader.com", 100)
+            .await
+            .unwrap();
+
+        assert_metrics_include!(t.helper_registry, {
+            r#"report_counter{env="test_helper",host="helper.org",status="aggregated"}"#: NUM_REPORTS,
+            r#"report_counter{env="test_helper",host="helper.org",status="collected"}"#: NUM_REPORTS,
+        });
+        assert_metrics_include!(t.leader_registry, {
+            r#"report_counter{env="test_leader",host="leader.com",status="aggregated"}"#: NUM_REPORTS,
+            r#"report_counter{env="test_leader",host="leader.com",status="collected"}"#: NUM_REPORTS,
+        });
+
+        // The Helper deletes the response once the Leader has fetched all of it.
+        assert!(t.helper.agg_job_resp_store.lock().unwrap().is_empty());
+    }
+
+    async_test_versions! { e2e_chunked_agg_job_resp }
+
+    async fn batch_ready_for_collection(version: DapVersion) {
+        let t = Test::new(version);
+        let task_id = &t.time_interval_task_id;
+        let task_config = t.leader.unchecked_get_task_config(task_id).await;
+        let query = task_config.query_for_current_batch_window(t.now);
+        let Query::TimeInterval { batch_interval } = query.clone() else {
+            panic!("unexpected query: {query:?}");
+        };
+        let batch_sel = BatchSelector::TimeInterval {
+            batch_interval: batch_interval.clone(),
+        };
+
+        // Nothing is waiting to be aggregated.
+        assert!(t
+            .leader
+            .batch_ready_for_collection(task_id, &batch_sel, &DapAggregationParam::Empty)
+            .await
+            .unwrap());
+
+        for _ in 0..task_config.min_batch_size {
+            let report = t.gen_test_report(task_id).await;
+            leader::handle_upload_req(&*t.leader, &t.gen_test_upload_req(report, task_id).await)
+                .await
+                .unwrap();
+        }
+        leader::handle_coll_job_req(&*t.leader, &t.gen_test_coll_job_req(query, task_id).await)
+            .await
+            .unwrap();
+
+        // The reports have been assigned to an aggregation job that hasn't run yet.
+        assert!(!t
+            .leader
+            .batch_ready_for_collection(task_id, &batch_sel, &DapAggregationParam::Empty)
+            .await
+            .unwrap());
+
+        // A batch that doesn't contain any of the reports doesn't wait on the aggregation job.
+        let other_batch_sel = BatchSelector::TimeInterval {
+            batch_interval: Interval {
+                start: batch_interval.end(),
+                duration: batch_interval.duration,
+            },
+        };
+        assert!(t
+            .leader
+            .batch_ready_for_collection(task_id, &other_batch_sel, &DapAggregationParam::Empty)
+            .await
+            .unwrap());
+
+        leader::process(&*t.leader, "leader.com", 100)
+            .await
+            .unwrap();
+
+        // The batch remains ready once it has been collected.
+        assert!(t
+            .leader
+            .batch_ready_for_collection(task_id, &batch_sel, &DapAggregationParam::Empty)
+            .await
+            .unwrap());
+        assert_metrics_include!(t.leader_registry, {
+            r#"report_counter{env="test_leader",host="leader.com",status="collected"}"#: task_config.min_batch_size,
+        });
+    }
+
+    async_test_versions! { batch_ready_for_collection }
+
+    async fn process_empty_batch_fails(version: DapVersion) {
+        let t = Test::new(version);
+        let task_id = &t.time_interval_task_id;
+        let task_config = t.leader.unchecked_get_task_config(task_id).await;
+        let query = task_config.query_for_current_batch_window(t.now);
+
+        // Collect a batch into which no reports were uploaded.
+        leader::handle_coll_job_req(&*t.leader, &t.gen_test_coll_job_req(query, task_id).await)
+            .await
+            .unwrap();
+
+        assert_matches!(
+            leader::process(&*t.leader, "leader.com", 100).await,
+            Err(DapError::Abort(DapAbort::InvalidBatchSize { .. }))
+        );
+
+        // The collection job is not requeued.
+        assert!(t.leader.dequeue_work(100).await.unwrap().is_empty());
+    }
+
+    async_test_versions! { process_empty_batch_fails }
+
+    async fn preview_collection(version: DapVersion) {
+        let t = Test::new(version);
+        let task_id = &t.time_interval_task_id;
+        let task_config = t.leader.unchecked_get_task_config(task_id).await;
+        let query = task_config.query_for_current_batch_window(t.now);
+        let Query::TimeInterval { batch_interval } = query.clone() else {
+            panic!("unexpected query: {query:?}");
+        };
+        let batch_sel = BatchSelector::TimeInterval { batch_interval };
+        let bucket_count = task_config.batch_span_for_sel(&batch_sel).unwrap().len();
+
+        // Nothing has been aggregated yet.
+        assert_eq!(
+            t.leader
+                .preview_collection(task_id, &batch_sel)
+                .await
+                .unwrap(),
+            CollectionPreview {
+                bucket_count,
+                report_count: 0,
+                any_collected: false,
+            }
+        );
+
+        for _ in 0..task_config.min_batch_size {
+            let report = t.gen_test_report(task_id).await;
+            leader::handle_upload_req(&*t.leader, &t.gen_test_upload_req(report, task_id).await)
+                .await
+                .unwrap();
+        }
+        leader::handle_coll_job_req(&*t.leader, &t.gen_test_coll_job_req(query, task_id).await)
+            .await
+            .unwrap();
+        leader::process(&*t.leader, "leader.com", 100)
+            .await
+            .unwrap();
+
+        let [(_coll_job_id, coll_job_info)] =
+            <[_; 1]>::try_from(t.leader.list_collection_jobs(task_id).await.unwrap()).unwrap();
+        let DapCollectionJob::Done(collection) = coll_job_info.status else {
+            panic!("collection job did not complete");
+        };
+
+        // Both Aggregators agree with the collection on the number of reports.
+        let want = CollectionPreview {
+            bucket_count,
+            report_count: collection.report_count,
+            any_collected: true,
+        };
+        assert_eq!(
+            t.leader
+                .preview_collection(task_id, &batch_sel)
+                .await
+                .unwrap(),
+            want
+        );
+        assert_eq!(
+            t.helper
+                .preview_collection(task_id, &batch_sel)
+                .await
+                .unwrap(),
+            want
+        );
+    }
+
+    async_test_versions! { preview_collection }
+
+    async fn batch_report_ids(version: DapVersion) {
+        const NUM_REPORTS: u64 = 3;
+        let mut data = TestData::new(version);
+        data.global_config.eager_agg_threshold = Some(NUM_REPORTS);
+        let helper = data.new_helper();
+        let t = data.with_leader(helper);
+        let task_id = &t.time_interval_task_id;
+        let task_config = t.leader.unchecked_get_task_config(task_id).await;
+        let query = task_config.query_for_current_batch_window(t.now);
+        let batch_sel = query.clone().into_batch_sel().unwrap();
+
+        let mut report_ids = HashSet::new();
+        for _ in 0..NUM_REPORTS {
+            let report = t.gen_test_report(task_id).await;
+            report_ids.insert(report.report_metadata.id);
+            leader::handle_upload_req(&*t.leader, &t.gen_test_upload_req(report, task_id).await)
+                .await
+                .unwrap();
+        }
+
+        // The reports are aggregated eagerly, but the IDs aren't revealed until the batch is
+        // collected.
+        leader::process(&*t.leader, "leader.com", 100)
+            .await
+            .unwrap();
+        for agg in [&t.leader, &t.helper] {
+            assert_matches!(
+                agg.batch_report_ids(task_id, &batch_sel).await.unwrap_err(),
+                DapError::Abort(DapAbort::BatchInvalid { .. })
+            );
+        }
+
+        leader::handle_coll_job_req(&*t.leader, &t.gen_test_coll_job_req(query, task_id).await)
+            .await
+            .unwrap();
+        leader::process(&*t.leader, "leader.com", 100)
+            .await
+            .unwrap();
+        for agg in [&t.leader, &t.helper] {
+            let got = agg.batch_report_ids(task_id, &batch_sel).await.unwrap();
+            assert_eq!(got.len(), report_ids.len());
+            assert_eq!(got.into_iter().collect::<HashSet<_>>(), report_ids);
+        }
+    }
+
+    async_test_versions! { batch_report_ids }
+
+    async fn retry_collect_job(version: DapVersion) {
+        let t = Test::new(version);
+        let task_id = &t.time_interval_task_id;
+        let task_config = t.leader.unchecked_get_task_config(task_id).await;
+
+        let report = t.gen_test_report(task_id).await;
+        leader::handle_upload_req(&*t.leader, &t.gen_test_upload_req(report, task_id).await)
+            .await
+            .unwrap();
+        let query = task_config.query_for_current_batch_window(t.now);
+        leader::handle_coll_job_req(&*t.leader, &t.gen_test_coll_job_req(query, task_id).await)
+            .await
+            .unwrap();
 
-        // Expect DapCollectionJob::Unknown due to invalid collect ID.
-        assert_eq!(
+        // Run the aggregation job, then fail the collection job by making the task temporarily
+        // unavailable to the Leader.
+        leader::process(&*t.leader, "leader.com", 1).await.unwrap();
+        t.leader.tasks.lock().unwrap().remove(task_id);
+        assert!(leader::process(&*t.leader, "leader.com", 100)
+            .await
+            .is_err());
+        t.leader
+            .tasks
+            .lock()
+            .unwrap()
+            .insert(*task_id, task_config.clone());
+
+        // The collection job is stuck in the pending state.
+        let [(coll_job_id, coll_job_info)] =
+            <[_; 1]>::try_from(t.leader.list_collection_jobs(task_id).await.unwrap()).unwrap();
+        assert_matches!(coll_job_info.status, DapCollectionJob::Pending);
+        assert_eq!(coll_job_info.queued_buckets, Some(0));
+
+        // Retrying queues the collection job again, but not the aggregation job, which already
+        // completed. Retrying twice doesn't queue the collection job twice.
+        for _ in 0..2 {
             t.leader
-                .poll_collect_job(task_id, &CollectionJobId::default())
+                .retry_collect_job(task_id, &coll_job_id)
+                .await
+                .unwrap();
+        }
+        let [(_, coll_job_info)] =
+            <[_; 1]>::try_from(t.leader.list_collection_jobs(task_id).await.unwrap()).unwrap();
+        assert_eq!(coll_job_info.queued_buckets, Some(0));
+
+        leader::process(&*t.leader, "leader.com", 100)
+            .await
+            .unwrap();
+        assert_matches!(
+            t.leader
+                .poll_collect_job(task_id, &coll_job_id)
                 .await
                 .unwrap(),
-            DapCollectionJob::Unknown
+            DapCollectionJob::Done(collection) if collection.report_count == 1
         );
+        assert_metrics_include!(t.helper_registry, {
+            r#"report_counter{env="test_helper",host="helper.org",status="aggregated"}"#: 1,
+            r#"report_counter{env="test_helper",host="helper.org",status="collected"}"#: 1,
+        });
 
-        // Leader: Get pending collect job to obtain collect_id
-        let WorkItem::CollectionJob {
-            task_id: _,
-            coll_job_id,
-            batch_sel: _,
-            agg_param: _,
-        } = t.leader.dequeue_work(1).await.unwrap().pop().unwrap()
+        // Retrying a collection job that is done is a no-op.
+        t.leader
+            .retry_collect_job(task_id, &coll_job_id)
+            .await
+            .unwrap();
+        assert!(t.leader.dequeue_work(100).await.unwrap().is_empty());
+    }
+
+    async_test_versions! { retry_collect_job }
+
+    #[tokio::test]
+    async fn retry_collect_job_after_helper_committed() {
+        let version = DapVersion::DraftLatest;
+        let t = Test::new(version);
+        let task_id = &t.time_interval_task_id;
+        let task_config = t.leader.unchecked_get_task_config(task_id).await;
+
+        let report = t.gen_test_report(task_id).await;
+        leader::handle_upload_req(&*t.leader, &t.gen_test_upload_req(report, task_id).await)
+            .await
+            .unwrap();
+        let query = task_config.query_for_current_batch_window(t.now);
+        leader::handle_coll_job_req(&*t.leader, &t.gen_test_coll_job_req(query, task_id).await)
+            .await
+            .unwrap();
+        let [(coll_job_id, _)] =
+            <[_; 1]>::try_from(t.leader.list_collection_jobs(task_id).await.unwrap()).unwrap();
+
+        // Simulate a Leader that failed after the Helper committed the aggregation job.
+        let [(item_id, work_item)] =
+            <[_; 1]>::try_from(t.leader.dequeue_work(1).await.unwrap()).unwrap();
+        let WorkItem::AggregationJob {
+            agg_job_id,
+            part_batch_sel,
+            agg_param,
+            reports,
+            ..
+        } = work_item
         else {
-            panic!("unexpected work item type")
+            panic!("unexpected work item type");
         };
-        let collection = Collection {
-            part_batch_sel: PartialBatchSelector::TimeInterval,
-            report_count: 0,
-            draft_latest_interval: if version == DapVersion::Draft02 {
-                None
-            } else {
-                Some(Interval {
-                    start: 0,
-                    duration: 2_000_000_000,
-                })
-            },
-            encrypted_agg_shares: [
-                HpkeCiphertext {
-                    config_id: Default::default(),
-                    enc: Default::default(),
-                    payload: Default::default(),
-                },
-                HpkeCiphertext {
-                    config_id: Default::default(),
-                    enc: Default::default(),
-                    payload: Default::default(),
-                },
-            ],
+        let DapLeaderAggregationJobTransition::Continued(_state, agg_job_init_req) = task_config
+            .produce_agg_job_init_req(
+                t.leader.as_ref(),
+                t.leader.as_ref(),
+                task_id,
+                &agg_job_id,
+                &part_batch_sel,
+                &agg_param,
+                reports,
+                &t.leader.metrics,
+            )
+            .await
+            .unwrap()
+        else {
+            panic!("unexpected transition");
         };
+        let req = t
+            .leader_authorized_req(
+                task_id,
+                &task_config,
+                Some(&agg_job_id),
+                DapMediaType::AggregationJobInitReq,
+                agg_job_init_req,
+            )
+            .await;
+        helper::handle_agg_job_req(&*t.helper, &req).await.unwrap();
+        t.leader
+            .set_agg_job_status(task_id, &agg_job_id, DapAggregationJobStatus::Failed)
+            .await
+            .unwrap();
+        t.leader.ack_work(vec![item_id]).await.unwrap();
 
-        // Expect DapCollectionJob::Pending due to pending collect job.
+        // The aggregation job is retried under the same ID, so the Helper replays its response
+        // rather than rejecting the reports it already aggregated.
+        t.leader
+            .retry_collect_job(task_id, &coll_job_id)
+            .await
+            .unwrap();
+        leader::process(&*t.leader, "leader.com", 100)
+            .await
+            .unwrap();
         assert_eq!(
+            t.leader.agg_job_status(task_id, &agg_job_id).await.unwrap(),
+            Some(DapAggregationJobStatus::Completed)
+        );
+        assert_matches!(
             t.leader
                 .poll_collect_job(task_id, &coll_job_id)
                 .await
                 .unwrap(),
-            DapCollectionJob::Pending
+            DapCollectionJob::Done(collection) if collection.report_count == 1
         );
+    }
 
-        // Leader: Complete the collect job by storing CollectResp in LeaderStore.processed.
+    #[tokio::test]
+    async fn retry_collect_job_after_agg_job_retry() {
+        let version = DapVersion::Draft02;
+        let mut data = TestData::new(version);
+        data.global_config.agg_job_retry_budget = 1;
+        data.global_config.agg_job_retry_backoff = 0;
+        let helper = data.new_helper();
+        let t = data.with_leader(helper);
+        let task_id = &t.time_interval_task_id;
+        let task_config = t.leader.unchecked_get_task_config(task_id).await;
+
+        let report = t.gen_test_report(task_id).await;
+        leader::handle_upload_req(&*t.leader, &t.gen_test_upload_req(report, task_id).await)
+            .await
+            .unwrap();
+        let query = task_config.query_for_current_batch_window(t.now);
+        leader::handle_coll_job_req(&*t.leader, &t.gen_test_coll_job_req(query, task_id).await)
+            .await
+            .unwrap();
+        let [(coll_job_id, _)] =
+            <[_; 1]>::try_from(t.leader.list_collection_jobs(task_id).await.unwrap()).unwrap();
+
+        // The aggregation job fails once and is queued again under a new ID.
+        t.leader.fail_next_peer_requests(1);
+        leader::process(&*t.leader, "leader.com", 1).await.unwrap();
+        assert_eq!(t.leader.work_queue_stats().await.unwrap().agg_jobs, 1);
+
+        // The collection job depends on the retry, which is still queued, so retrying the
+        // collection job doesn't queue the aggregation job again.
         t.leader
-            .finish_collect_job(task_id, &coll_job_id, &collection)
+            .retry_collect_job(task_id, &coll_job_id)
             .await
             .unwrap();
+        assert_eq!(t.leader.work_queue_stats().await.unwrap().agg_jobs, 1);
 
-        // Expect DapCollectionJob::Done due to processed collect job.
+        leader::process(&*t.leader, "leader.com", 100)
+            .await
+            .unwrap();
         assert_matches!(
             t.leader
                 .poll_collect_job(task_id, &coll_job_id)
                 .await
                 .unwrap(),
-            DapCollectionJob::Done(..)
+            DapCollectionJob::Done(collection) if collection.report_count == 1
         );
+        assert!(t.leader.dequeue_work(100).await.unwrap().is_empty());
     }
 
-    async_test_versions! { poll_collect_job_test_results }
-
-    async fn handle_coll_job_req_fail_invalid_batch_interval(version: DapVersion) {
-        let t = Test::new(version);
+    async fn retry_collect_job_while_leased(version: DapVersion) {
+        let mut data = TestData::new(version);
+        let clock = Arc::new(MockClock::new(data.now));
+        data.clock = clock.clone();
+        data.global_config.work_item_visibility_timeout = Some(60);
+        let helper = data.new_helper();
+        let t = data.with_leader(helper);
         let task_id = &t.time_interval_task_id;
         let task_config = t.leader.unchecked_get_task_config(task_id).await;
 
-        // Collector: Create a CollectReq with a very large batch interval.
-        let req = t.collector_authorized_req(
-            task_id,
-            &task_config,
-            DapMediaType::CollectReq,
-            CollectionReq {
-                draft02_task_id: task_id.for_request_payload(&version),
-                query: Query::TimeInterval {
-                    batch_interval: Interval {
-                        start: task_config.quantized_time_lower_bound(t.now),
-                        duration: t.leader.global_config.max_batch_duration
-                            + task_config.time_precision,
-                    },
-                },
-                agg_param: Vec::default(),
-            },
-        );
-
-        // Leader: Handle the CollectReq received from Collector.
-        let err = leader::handle_coll_job_req(&*t.leader, &req)
+        let report = t.gen_test_report(task_id).await;
+        leader::handle_upload_req(&*t.leader, &t.gen_test_upload_req(report, task_id).await)
             .await
-            .unwrap_err();
-
-        // Fails because the requested batch interval is too large.
-        assert_matches!(err, DapError::Abort(DapAbort::BadRequest(s)) => assert_eq!(s, "batch interval too large".to_string()));
-
-        // Collector: Create a CollectReq with a batch interval in the past.
-        let req = t.collector_authorized_req(
-            task_id,
-            &task_config,
-            DapMediaType::CollectReq,
-            CollectionReq {
-                draft02_task_id: task_id.for_request_payload(&version),
-                query: Query::TimeInterval {
-                    batch_interval: Interval {
-                        start: task_config.quantized_time_lower_bound(t.now)
-                            - t.leader.global_config.min_batch_interval_start
-                            - task_config.time_precision,
-                        duration: task_config.time_precision * 2,
-                    },
-                },
-                agg_param: Vec::default(),
-            },
-        );
-
-        // Leader: Handle the CollectReq received from Collector.
-        let err = leader::handle_coll_job_req(&*t.leader, &req)
+            .unwrap();
+        let query = task_config.query_for_current_batch_window(t.now);
+        leader::handle_coll_job_req(&*t.leader, &t.gen_test_coll_job_req(query, task_id).await)
             .await
-            .unwrap_err();
-
-        // Fails because the requested batch interval is too far into the past.
-        assert_matches!(err, DapError::Abort(DapAbort::BadRequest(s)) => assert_eq!(s, "batch interval too far into past".to_string()));
+            .unwrap();
+        let [(coll_job_id, _)] =
+            <[_; 1]>::try_from(t.leader.list_collection_jobs(task_id).await.unwrap()).unwrap();
 
-        // Collector: Create a CollectReq with a batch interval in the future.
-        let req = t.collector_authorized_req(
-            task_id,
-            &task_config,
-            DapMediaType::CollectReq,
-            CollectionReq {
-                draft02_task_id: task_id.for_request_payload(&version),
-                query: Query::TimeInterval {
-                    batch_interval: Interval {
-                        start: task_config.quantized_time_lower_bound(t.now)
-                            + t.leader.global_config.max_batch_interval_end
-                            - task_config.time_precision,
-                        duration: task_config.time_precision * 2,
-                    },
-                },
-                agg_param: Vec::default(),
-            },
-        );
+        // Lease the aggregation job and the collection job without running them.
+        assert_eq!(t.leader.dequeue_work(100).await.unwrap().len(), 2);
 
-        // Leader: Handle the CollectReq received from Collector.
-        let err = leader::handle_coll_job_req(&*t.leader, &req)
+        // Work that is leased is not queued again.
+        t.leader
+            .retry_collect_job(task_id, &coll_job_id)
             .await
-            .unwrap_err();
-
-        // Fails because the requested batch interval is too far into the future.
-        assert_matches!(err, DapError::Abort(DapAbort::BadRequest(s)) => assert_eq!(s, "batch interval too far into future".to_string()));
-    }
-
-    async_test_versions! { handle_coll_job_req_fail_invalid_batch_interval }
-
-    async fn handle_coll_job_req_succeed_max_batch_interval(version: DapVersion) {
-        let t = Test::new(version);
-        let task_id = &t.time_interval_task_id;
-        let task_config = t.leader.unchecked_get_task_config(task_id).await;
+            .unwrap();
+        assert!(t.leader.dequeue_work(100).await.unwrap().is_empty());
 
-        // Collector: Create a CollectReq with a very large batch interval.
-        let req = t.collector_authorized_req(
-            task_id,
-            &task_config,
-            DapMediaType::CollectReq,
-            CollectionReq {
-                draft02_task_id: task_id.for_request_payload(&version),
-                query: Query::TimeInterval {
-                    batch_interval: Interval {
-                        start: task_config.quantized_time_lower_bound(t.now)
-                            - t.leader.global_config.max_batch_duration / 2,
-                        duration: t.leader.global_config.max_batch_duration,
-                    },
-                },
-                agg_param: Vec::default(),
-            },
+        // Once the leases expire, the work is run exactly once.
+        clock.advance(60);
+        for _ in 0..2 {
+            leader::process(&*t.leader, "leader.com", 1).await.unwrap();
+        }
+        assert_matches!(
+            t.leader
+                .poll_collect_job(task_id, &coll_job_id)
+                .await
+                .unwrap(),
+            DapCollectionJob::Done(collection) if collection.report_count == 1
         );
-
-        // Leader: Handle the CollectReq received from Collector.
-        let _collect_uri = leader::handle_coll_job_req(&*t.leader, &req).await.unwrap();
+        assert!(t.leader.dequeue_work(100).await.unwrap().is_empty());
+        assert_metrics_include!(t.helper_registry, {
+            r#"report_counter{env="test_helper",host="helper.org",status="aggregated"}"#: 1,
+            r#"report_counter{env="test_helper",host="helper.org",status="collected"}"#: 1,
+        });
     }
 
-    async_test_versions! { handle_coll_job_req_succeed_max_batch_interval }
+    async_test_versions! { retry_collect_job_while_leased }
+
+    #[tokio::test]
+    async fn process_cancelled() {
+        // None of the aggregator's locks may be held (or poisoned) once a future that uses it is
+        // dropped.
+        fn assert_locks_available(agg: &MockAggregator) {
+            assert!(agg.tasks.try_lock().is_ok());
+            assert!(agg.hpke_receiver_config_list.try_lock().is_ok());
+            assert!(agg.report_store.try_lock().is_ok());
+            assert!(agg.leader_state_store.try_lock().is_ok());
+            assert!(agg.helper_state_store.try_lock().is_ok());
+            assert!(agg.agg_job_resp_store.try_lock().is_ok());
+            assert!(agg.agg_store.try_lock().is_ok());
+            assert!(agg.retained_agg_share_store.try_lock().is_ok());
+        }
 
-    async fn handle_coll_job_req_fail_overlapping_batch_interval(version: DapVersion) {
-        let t = Test::new(version);
+        let mut data = TestData::new(DapVersion::DraftLatest);
+        let clock = Arc::new(MockClock::new(data.now));
+        data.clock = clock.clone();
+        data.global_config.work_item_visibility_timeout = Some(60);
+        let helper = data.new_helper();
+        let t = data.with_leader(helper);
         let task_id = &t.time_interval_task_id;
         let task_config = t.leader.unchecked_get_task_config(task_id).await;
 
         let report = t.gen_test_report(task_id).await;
-        let req = t.gen_test_upload_req(report.clone(), task_id).await;
-        leader::handle_upload_req(&*t.leader, &req).await.unwrap();
-
+        leader::handle_upload_req(&*t.leader, &t.gen_test_upload_req(report, task_id).await)
+            .await
+            .unwrap();
         let query = task_config.query_for_current_batch_window(t.now);
-        let req = t.gen_test_coll_job_req(query, task_id).await;
-        leader::handle_coll_job_req(&*t.leader, &req).await.unwrap();
+        leader::handle_coll_job_req(&*t.leader, &t.gen_test_coll_job_req(query, task_id).await)
+            .await
+            .unwrap();
 
+        // Park the Leader while it sends the aggregation job to the Helper, i.e., after it has
+        // dequeued the job and prepared its reports.
+        let mut parked = t.leader.park_peer_requests();
+        let leader = Arc::clone(&t.leader);
+        let handle = tokio::spawn(async move {
+            leader::process(&*leader, "leader.com", 1).await.unwrap();
+        });
+        parked.next().await.unwrap();
+        assert!(!handle.is_finished());
+        assert_locks_available(&t.leader);
+        assert_locks_available(&t.helper);
+
+        // Cancel the Leader while it is parked.
+        handle.abort();
+        assert!(handle.await.unwrap_err().is_cancelled());
+        assert_locks_available(&t.leader);
+        assert_locks_available(&t.helper);
+        t.leader.unpark_peer_requests();
+
+        // Subsequent operations are unaffected. The cancelled job is handed out again once its
+        // lease expires.
+        clock.advance(60);
         leader::process(&*t.leader, "leader.com", 100)
             .await
             .unwrap();
-
-        // Repeat the request. Expect failure due to overlapping batch.
-        assert_matches!(
-            leader::handle_coll_job_req(&*t.leader, &req)
-                .await
-                .unwrap_err(),
-            DapError::Abort(DapAbort::BatchOverlap { .. })
-        );
-    }
-
-    async_test_versions! { handle_coll_job_req_fail_overlapping_batch_interval }
-
-    async fn handle_coll_job_req_fail_unrecongized_batch(version: DapVersion) {
-        let t = Test::new(version);
-        let task_id = &t.fixed_size_task_id;
-
-        let req = t
-            .gen_test_coll_job_req(
-                Query::FixedSizeByBatchId {
-                    batch_id: BatchId(thread_rng().gen()),
-                },
-                task_id,
-            )
-            .await;
-
-        // Expect failure due to unrecognized batch
+        let [(_coll_job_id, coll_job_info)] =
+            <[_; 1]>::try_from(t.leader.list_collection_jobs(task_id).await.unwrap()).unwrap();
         assert_matches!(
-            leader::handle_coll_job_req(&*t.leader, &req)
-                .await
-                .unwrap_err(),
-            DapError::Abort(DapAbort::BatchInvalid { .. })
+            coll_job_info.status,
+            DapCollectionJob::Done(collection) if collection.report_count == 1
         );
     }
 
-    async_test_versions! { handle_coll_job_req_fail_unrecongized_batch }
-
-    // Test a successful collect request submission.
-    // This checks that the Leader reponds with the collect ID with the ID associated to the request.
-    async fn handle_coll_job_req_success(version: DapVersion) {
+    #[tokio::test]
+    async fn resume_agg_jobs_after_restart() {
+        let version = DapVersion::DraftLatest;
         let t = Test::new(version);
         let task_id = &t.time_interval_task_id;
         let task_config = t.leader.unchecked_get_task_config(task_id).await;
 
-        // Collector: Create a CollectReq.
-        let collector_collect_req = CollectionReq {
-            draft02_task_id: task_id.for_request_payload(&version),
-            query: task_config.query_for_current_batch_window(t.now),
-            agg_param: Vec::default(),
+        // Simulate a Leader that restarted after the Helper responded to an aggregation job, but
+        // before the job was committed.
+        let report = t.gen_test_report(task_id).await;
+        let (state, req) = t
+            .gen_test_agg_job_init_req(task_id, version, DapAggregationParam::Empty, vec![report])
+            .await;
+        let DapResource::AggregationJob(agg_job_id) = req.resource else {
+            panic!("unexpected resource");
+        };
+        let agg_job_id = MetaAggregationJobId::DraftLatest(agg_job_id);
+        let agg_job_resp = helper::handle_agg_job_req(&*t.helper, &req)
+            .await
+            .unwrap()
+            .payload;
+        let checkpoint = AggregationJobCheckpoint {
+            task_id: *task_id,
+            agg_job_id,
+            agg_param: Vec::new(),
+            state: state.get_encoded().unwrap(),
+            agg_job_resp,
         };
-        let req = t.collector_authorized_req(
-            task_id,
-            &task_config,
-            DapMediaType::CollectReq,
-            collector_collect_req.clone(),
-        );
 
-        // Leader: Handle the CollectReq received from Collector.
-        let url = leader::handle_coll_job_req(&*t.leader, &req).await.unwrap();
-        let WorkItem::CollectionJob {
-            task_id: _,
-            coll_job_id: leader_collect_id,
-            batch_sel: leader_batch_sel,
-            agg_param: leader_agg_param,
-        } = t.leader.dequeue_work(1).await.unwrap().pop().unwrap()
+        // Compute the aggregate share the uninterrupted job would have produced.
+        let DapLeaderAggregationJobTransition::Finished(agg_span) = task_config
+            .handle_agg_job_resp(
+                task_id,
+                &agg_job_id,
+                state,
+                AggregationJobResp::get_decoded(&checkpoint.agg_job_resp).unwrap(),
+                t.leader.metrics(),
+            )
+            .unwrap()
         else {
-            panic!("unexpected work item type");
+            panic!("unexpected transition");
         };
+        let expected = agg_span.collapsed();
 
-        // Check that the CollectReq sent by Collector is the same that is received by Leader.
-        assert_eq!(collector_collect_req.query, leader_batch_sel.into());
+        t.leader.put_agg_job_checkpoint(checkpoint).await.unwrap();
+
+        // A checkpoint that can't be decoded doesn't prevent the other jobs from being resumed.
+        let corrupt_checkpoint = AggregationJobCheckpoint {
+            task_id: *task_id,
+            agg_job_id: MetaAggregationJobId::gen_for_version(version),
+            agg_param: Vec::new(),
+            state: vec![0xff],
+            agg_job_resp: Vec::new(),
+        };
+        t.leader
+            .put_agg_job_checkpoint(corrupt_checkpoint.clone())
+            .await
+            .unwrap();
+
+        // The job is finished on startup. The corrupt checkpoint is kept for inspection.
+        assert_eq!(leader::resume_agg_jobs(&*t.leader).await.unwrap(), 1);
         assert_eq!(
-            collector_collect_req.agg_param,
-            leader_agg_param.get_encoded().unwrap()
+            t.leader.list_agg_job_checkpoints().await.unwrap(),
+            vec![corrupt_checkpoint]
         );
-
-        // Check that the collect_id included in the URI is the same with the one received
-        // by Leader.
-        let path = url.path().to_string();
-        let mut router = Router::new();
-        router
-            .insert("/:version/collect/task/:task_id/req/:collect_id", true)
+        let Query::TimeInterval { batch_interval } =
+            task_config.query_for_current_batch_window(t.now)
+        else {
+            unreachable!()
+        };
+        let agg_share = t
+            .leader
+            .get_agg_share(
+                task_id,
+                &BatchSelector::TimeInterval { batch_interval },
+                &DapAggregationParam::Empty,
+            )
+            .await
             .unwrap();
-        let url_match = router.at(&path).unwrap();
-        let collector_collect_id = url_match.params.get("collect_id").unwrap();
+        assert_eq!(agg_share.report_count, 1);
+        assert_eq!(agg_share.checksum, expected.checksum);
         assert_eq!(
-            collector_collect_id.to_string(),
-            leader_collect_id.to_base64url()
+            agg_share.data.unwrap().get_encoded().unwrap(),
+            expected.data.unwrap().get_encoded().unwrap()
         );
-    }
 
-    async_test_versions! { handle_coll_job_req_success }
+        // Resuming again is a no-op.
+        assert_eq!(leader::resume_agg_jobs(&*t.leader).await.unwrap(), 0);
+    }
 
-    // Test that the Leader handles queries from the Collector properly.
-    async fn handle_coll_job_req_invalid_query(version: DapVersion) {
-        let mut rng = thread_rng();
-        let t = Test::new(version);
+    #[tokio::test]
+    async fn resume_agg_jobs_drops_leased_work() {
+        let version = DapVersion::DraftLatest;
+        let mut data = TestData::new(version);
+        let clock = Arc::new(MockClock::new(data.now));
+        data.clock = clock.clone();
+        data.global_config.work_item_visibility_timeout = Some(60);
+        let helper = data.new_helper();
+        let t = data.with_leader(helper);
+        let task_id = &t.time_interval_task_id;
 
-        // Leader expects "time_interval" query, but Collector sent "fixed_size".
-        let task_config = t
-            .leader
-            .unchecked_get_task_config(&t.time_interval_task_id)
+        // Simulate a Leader that restarted after the Helper responded to an aggregation job, but
+        // before the job was committed or its work item acknowledged.
+        let report = t.gen_test_report(task_id).await;
+        let (state, req) = t
+            .gen_test_agg_job_init_req(
+                task_id,
+                version,
+                DapAggregationParam::Empty,
+                vec![report.clone()],
+            )
             .await;
-        let req = t.collector_authorized_req(
-            &t.time_interval_task_id,
-            &task_config,
-            DapMediaType::CollectReq,
-            CollectionReq {
-                draft02_task_id: t.time_interval_task_id.for_request_payload(&version),
-                query: Query::FixedSizeByBatchId {
-                    batch_id: BatchId(rng.gen()),
-                },
-                agg_param: Vec::default(),
-            },
-        );
-        assert_matches!(
-            leader::handle_coll_job_req(&*t.leader, &req)
-                .await
-                .unwrap_err(),
-            DapError::Abort(DapAbort::QueryMismatch { .. })
-        );
+        let DapResource::AggregationJob(agg_job_id) = req.resource else {
+            panic!("unexpected resource");
+        };
+        let agg_job_id = MetaAggregationJobId::DraftLatest(agg_job_id);
+        t.leader
+            .enqueue_work(vec![WorkItem::AggregationJob {
+                task_id: *task_id,
+                agg_job_id,
+                part_batch_sel: PartialBatchSelector::TimeInterval,
+                agg_param: DapAggregationParam::Empty,
+                reports: vec![report],
+                retries_remaining: 0,
+                not_before: None,
+            }])
+            .await
+            .unwrap();
+        assert_eq!(t.leader.dequeue_work(100).await.unwrap().len(), 1);
+        let agg_job_resp = helper::handle_agg_job_req(&*t.helper, &req)
+            .await
+            .unwrap()
+            .payload;
+        t.leader
+            .put_agg_job_checkpoint(AggregationJobCheckpoint {
+                task_id: *task_id,
+                agg_job_id,
+                agg_param: Vec::new(),
+                state: state.get_encoded().unwrap(),
+                agg_job_resp,
+            })
+            .await
+            .unwrap();
+        assert_eq!(leader::resume_agg_jobs(&*t.leader).await.unwrap(), 1);
 
-        // Collector indicates unrecognized batch ID.
-        let task_config = t
-            .leader
-            .unchecked_get_task_config(&t.fixed_size_task_id)
-            .await;
-        let req = t.collector_authorized_req(
-            &t.fixed_size_task_id,
-            &task_config,
-            DapMediaType::CollectReq,
-            CollectionReq {
-                draft02_task_id: t.fixed_size_task_id.for_request_payload(&version),
-                query: Query::FixedSizeByBatchId {
-                    batch_id: BatchId(rng.gen()), // Unrecognized batch ID
-                },
-                agg_param: Vec::default(),
-            },
-        );
-        assert_matches!(
-            leader::handle_coll_job_req(&*t.leader, &req)
-                .await
-                .unwrap_err(),
-            DapError::Abort(DapAbort::BatchInvalid { .. })
+        // The job was committed on startup, so it isn't handed out again once its lease expires.
+        clock.advance(60);
+        assert_eq!(t.leader.dequeue_work(100).await.unwrap().len(), 0);
+        assert_eq!(
+            t.leader.agg_job_status(task_id, &agg_job_id).await.unwrap(),
+            Some(DapAggregationJobStatus::Completed)
         );
     }
 
-    async_test_versions! { handle_coll_job_req_invalid_query }
-
-    async fn handle_upload_req(version: DapVersion) {
+    async fn process_removes_agg_job_checkpoints(version: DapVersion) {
         let t = Test::new(version);
         let task_id = &t.time_interval_task_id;
+        let task_config = t.leader.unchecked_get_task_config(task_id).await;
 
         let report = t.gen_test_report(task_id).await;
         let req = t.gen_test_upload_req(report, task_id).await;
+        leader::handle_upload_req(&*t.leader, &req).await.unwrap();
+        let query = task_config.query_for_current_batch_window(t.now);
+        let req = t.gen_test_coll_job_req(query, task_id).await;
+        leader::handle_coll_job_req(&*t.leader, &req).await.unwrap();
 
-        leader::handle_upload_req(&*t.leader, &req)
+        leader::process(&*t.leader, "leader.com", 100)
             .await
-            .expect("upload failed unexpectedly");
+            .unwrap();
+        assert_eq!(t.leader.list_agg_job_checkpoints().await.unwrap(), vec![]);
     }
 
-    async_test_versions! { handle_upload_req }
+    async_test_versions! { process_removes_agg_job_checkpoints }
 
-    async fn e2e_time_interval(version: DapVersion) {
+    async fn handle_agg_share_req_idempotency_token(version: DapVersion) {
         let t = Test::new(version);
         let task_id = &t.time_interval_task_id;
-        let task_config = t
-            .leader
-            .unchecked_get_task_config(&t.fixed_size_task_id)
-            .await;
+        let task_config = t.leader.unchecked_get_task_config(task_id).await;
 
-        // Client: Send upload request to Leader.
+        // Run a collection job to completion. The Leader sends the aggregate share request with
+        // the collection job ID as its idempotency token.
         let report = t.gen_test_report(task_id).await;
         leader::handle_upload_req(&*t.leader, &t.gen_test_upload_req(report, task_id).await)
             .await
             .unwrap();
-
-        // Collector: Request result from the Leader.
         let query = task_config.query_for_current_batch_window(t.now);
-        leader::handle_coll_job_req(&*t.leader, &t.gen_test_coll_job_req(query, task_id).await)
+        leader::handle_coll_job_req(
+            &*t.leader,
+            &t.gen_test_coll_job_req(query.clone(), task_id).await,
+        )
+        .await
+        .unwrap();
+        leader::process(&*t.leader, "leader.com", 100)
             .await
             .unwrap();
 
-        leader::process(&*t.leader, "leader.com", 100)
+        let [(coll_job_id, coll_job_info)] =
+            <[_; 1]>::try_from(t.leader.list_collection_jobs(task_id).await.unwrap()).unwrap();
+        let DapCollectionJob::Done(collection) = coll_job_info.status else {
+            panic!("collection job did not complete");
+        };
+
+        let mut req = t
+            .leader_authorized_req(
+                task_id,
+                &task_config,
+                None,
+                DapMediaType::AggregateShareReq,
+                AggregateShareReq {
+                    draft02_task_id: task_id.for_request_payload(&version),
+                    batch_sel: query.into_batch_sel().unwrap(),
+                    agg_param: Vec::default(),
+                    report_count: 1,
+                    checksum: [0; 32],
+                },
+            )
+            .await;
+
+        // Retrying the request with the same idempotency token returns the same aggregate share.
+        req.idempotency_token = Some(coll_job_id.to_base64url());
+        let resp = helper::handle_agg_share_req(&*t.helper, &req)
             .await
             .unwrap();
+        let agg_share_resp = AggregateShare::get_decoded(&resp.payload).unwrap();
+        assert_eq!(
+            agg_share_resp.encrypted_agg_share,
+            collection.encrypted_agg_shares[1]
+        );
 
-        let agg_job_req_count = match version {
-            DapVersion::Draft02 => 2,
-            DapVersion::DraftLatest => 1,
+        // A request with a new idempotency token would collect the batch again.
+        req.idempotency_token = Some(CollectionJobId(thread_rng().gen()).to_base64url());
+        assert_matches!(
+            helper::handle_agg_share_req(&*t.helper, &req)
+                .await
+                .unwrap_err(),
+            DapError::Abort(DapAbort::BatchOverlap { .. })
+        );
+    }
+
+    async_test_versions! { handle_agg_share_req_idempotency_token }
+
+    async fn retained_agg_share_keyed_by_agg_param(version: DapVersion) {
+        let mut data = TestData::new(version);
+        let clock = Arc::new(MockClock::new(data.now));
+        data.clock = clock.clone();
+        let helper = data.new_helper();
+        let t = data.with_leader(helper);
+        let task_id = &t.heavy_hitters_task_id;
+        let agg_param = |prefix: u8| {
+            DapAggregationParam::Mastic(
+                Poplar1AggregationParam::try_from_prefixes(vec![IdpfInput::from_bytes(&[prefix])])
+                    .unwrap(),
+            )
         };
+        let retained = DapRetainedAggShare {
+            batch_sel: BatchSelector::TimeInterval {
+                batch_interval: Interval {
+                    start: t.now,
+                    duration: 3600,
+                },
+            },
+            encrypted_agg_share: HpkeCiphertext {
+                config_id: 0,
+                enc: Vec::new(),
+                payload: Vec::new(),
+            },
+            expiration: t.now + 60,
+        };
+
+        t.helper
+            .put_retained_agg_share(task_id, "token", &agg_param(0), &retained)
+            .await
+            .unwrap();
+        assert_eq!(
+            t.helper
+                .get_retained_agg_share(task_id, "token", &agg_param(0))
+                .await
+                .unwrap(),
+            Some(retained.clone())
+        );
+
+        // A request with the same idempotency token but a different aggregation parameter doesn't
+        // get the retained aggregate share.
+        assert_eq!(
+            t.helper
+                .get_retained_agg_share(task_id, "token", &agg_param(1))
+                .await
+                .unwrap(),
+            None
+        );
 
-        assert_metrics_include!(t.helper_registry, {
-            r#"inbound_request_counter{env="test_helper",host="helper.org",type="aggregate"}"#: agg_job_req_count,
-            r#"inbound_request_counter{env="test_helper",host="helper.org",type="collect"}"#: 1,
-            r#"report_counter{env="test_helper",host="helper.org",status="aggregated"}"#: 1,
-            r#"report_counter{env="test_helper",host="helper.org",status="collected"}"#: 1,
-            r#"aggregation_job_counter{env="test_helper",host="helper.org",status="started"}"#: 1,
-            r#"aggregation_job_counter{env="test_helper",host="helper.org",status="completed"}"#: 1,
-        });
-        assert_metrics_include!(t.leader_registry, {
-            r#"report_counter{env="test_leader",host="leader.com",status="aggregated"}"#: 1,
-            r#"report_counter{env="test_leader",host="leader.com",status="collected"}"#: 1,
-        });
+        // The aggregate share is evicted once it expires.
+        clock.advance(60);
+        assert_eq!(
+            t.helper
+                .get_retained_agg_share(task_id, "token", &agg_param(0))
+                .await
+                .unwrap(),
+            None
+        );
+        assert!(t.helper.retained_agg_share_store.lock().unwrap().is_empty());
     }
 
-    async_test_versions! { e2e_time_interval }
+    async_test_versions! { retained_agg_share_keyed_by_agg_param }
 
     async fn e2e_fixed_size(version: DapVersion) {
         let t = Test::new(version);
@@ -1896,6 +6080,211 @@ mod test {
 
     async_test_versions! { e2e_fixed_size }
 
+    fn task_config_validate(version: DapVersion) {
+        // Endpoints that don't use https are rejected unless the deployment opts in.
+        let mut global_config = serde_json::to_value(TestData::new(version).global_config).unwrap();
+        global_config
+            .as_object_mut()
+            .unwrap()
+            .remove("allow_http_urls");
+        let mut global_config: DapGlobalConfig = serde_json::from_value(global_config).unwrap();
+        assert!(!global_config.allow_http_urls);
+        let now = 1_700_000_000;
+        let task_id = TaskId([1; 32]);
+        let collector_hpke_config = HpkeReceiverConfig::gen(23, HpkeKemId::X25519HkdfSha256)
+            .unwrap()
+            .config;
+        let (task_config, ..) = DapTaskParameters {
+            version,
+            ..Default::default()
+        }
+        .to_config_with_taskprov(b"cool task".to_vec(), now, &[0; 32], &collector_hpke_config)
+        .unwrap();
+        let validate = |task_config: &DapTaskConfig, global_config: &DapGlobalConfig| {
+            task_config.validate(&task_id, global_config, now)
+        };
+        validate(&task_config, &global_config).unwrap();
+
+        let mut expired = task_config.clone();
+        expired.expiration = now;
+        assert_matches!(
+            validate(&expired, &global_config),
+            Err(DapAbort::InvalidTask { .. })
+        );
+
+        let mut empty_batches = task_config.clone();
+        empty_batches.min_batch_size = 0;
+        assert_matches!(
+            validate(&empty_batches, &global_config),
+            Err(DapAbort::InvalidTask { .. })
+        );
+
+        let mut no_time_precision = task_config.clone();
+        no_time_precision.time_precision = 0;
+        assert_matches!(
+            validate(&no_time_precision, &global_config),
+            Err(DapAbort::InvalidTask { .. })
+        );
+
+        // The maximum batch duration is 100 hours, which isn't a whole number of 7-hour windows.
+        let mut uneven_time_precision = task_config.clone();
+        uneven_time_precision.time_precision = 7 * 3600;
+        assert_matches!(
+            validate(&uneven_time_precision, &global_config),
+            Err(DapAbort::InvalidTask { .. })
+        );
+
+        let mut small_max_batch_size = task_config.clone();
+        small_max_batch_size.query = DapQueryConfig::FixedSize {
+            max_batch_size: Some(task_config.min_batch_size - 1),
+        };
+        assert_matches!(
+            validate(&small_max_batch_size, &global_config),
+            Err(DapAbort::InvalidTask { .. })
+        );
+
+        let mut insecure_helper = task_config.clone();
+        insecure_helper.helper_url = "http://helper.example.com/".parse().unwrap();
+        assert_matches!(
+            validate(&insecure_helper, &global_config),
+            Err(DapAbort::InvalidTask { .. })
+        );
+        global_config.allow_http_urls = true;
+        validate(&insecure_helper, &global_config).unwrap();
+
+        // Only http is allowed in addition to https.
+        let mut unknown_scheme = task_config.clone();
+        unknown_scheme.leader_url = "ftp://leader.example.com/".parse().unwrap();
+        assert_matches!(
+            validate(&unknown_scheme, &global_config),
+            Err(DapAbort::InvalidTask { .. })
+        );
+    }
+
+    test_versions! { task_config_validate }
+
+    async fn handle_upload_req_taskprov_expired_task(version: DapVersion) {
+        let t = Test::new(version);
+
+        // Advertise a task that expired before the current time.
+        let (task_config, task_id, taskprov_advertisement, taskprov_report_extension_payload) =
+            DapTaskParameters {
+                version,
+                ..Default::default()
+            }
+            .to_config_with_taskprov(
+                b"cool task".to_vec(),
+                t.now - 86400 * 30,
+                &t.leader.taskprov_vdaf_verify_key_init,
+                &t.leader.collector_hpke_config,
+            )
+            .unwrap();
+        assert!(task_config.expiration < t.now);
+
+        let hpke_config_list = [
+            t.leader
+                .get_hpke_config_for(version, Some(&task_id))
+                .await
+                .unwrap()
+                .as_ref()
+                .clone(),
+            t.helper
+                .get_hpke_config_for(version, Some(&task_id))
+                .await
+                .unwrap()
+                .as_ref()
+                .clone(),
+        ];
+        let report = task_config
+            .vdaf
+            .produce_report_with_extensions(
+                &hpke_config_list,
+                t.now,
+                &task_id,
+                DapMeasurement::U32Vec(vec![1; 10]),
+                vec![Extension::Taskprov {
+                    draft02_payload: taskprov_report_extension_payload,
+                }],
+                version,
+            )
+            .unwrap();
+        let req = DapRequest {
+            version,
+            media_type: DapMediaType::Report,
+            task_id: Some(task_id),
+            resource: DapResource::Undefined,
+            payload: report.get_encoded_with_param(&version).unwrap(),
+            taskprov: taskprov_advertisement,
+            ..Default::default()
+        };
+
+        assert_matches!(
+            leader::handle_upload_req(&*t.leader, &req).await,
+            Err(DapError::Abort(DapAbort::InvalidTask { .. }))
+        );
+        assert!(t
+            .leader
+            .get_task_config_for(&task_id)
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    async_test_versions! { handle_upload_req_taskprov_expired_task }
+
+    async fn resolve_taskprov_vdaf_self_test(self_test: bool) -> Result<(), DapError> {
+        let version = DapVersion::DraftLatest;
+        let mut data = TestData::new(version);
+        data.global_config.taskprov_vdaf_self_test = self_test;
+        let helper = data.new_helper();
+        let t = data.with_leader(helper);
+
+        // Prio2 with dimension zero fails the self-test.
+        let (_task_config, task_id, taskprov_advertisement, _taskprov_report_extension_payload) =
+            DapTaskParameters {
+                version,
+                vdaf: VdafConfig::Prio2 { dimension: 0 },
+                ..Default::default()
+            }
+            .to_config_with_taskprov(
+                b"cool task".to_vec(),
+                t.now,
+                &t.leader.taskprov_vdaf_verify_key_init,
+                &t.leader.collector_hpke_config,
+            )
+            .unwrap();
+        let req = DapRequest {
+            version,
+            media_type: DapMediaType::Report,
+            task_id: Some(task_id),
+            resource: DapResource::Undefined,
+            taskprov: taskprov_advertisement,
+            ..Default::default()
+        };
+
+        super::resolve_taskprov(&*t.leader, &task_id, &req, None).await?;
+        assert!(t
+            .leader
+            .get_task_config_for(&task_id)
+            .await
+            .unwrap()
+            .is_some());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn resolve_taskprov_skips_vdaf_self_test_by_default() {
+        resolve_taskprov_vdaf_self_test(false).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn resolve_taskprov_rejects_vdaf_failing_self_test() {
+        assert_matches!(
+            resolve_taskprov_vdaf_self_test(true).await,
+            Err(DapError::Abort(DapAbort::InvalidTask { detail, .. })) if detail.starts_with("self-test failed")
+        );
+    }
+
     async fn e2e_taskprov(
         version: DapVersion,
         vdaf_config: VdafConfig,
@@ -2234,4 +6623,239 @@ mod test {
             r#"report_counter{env="test_leader",host="leader.com",status="collected"}"#: 10,
         });
     }
+
+    #[tokio::test]
+    async fn heavy_hitters_collect_batch_under_distinct_agg_params() {
+        let version = DapVersion::DraftLatest;
+        let data = TestData::new(version);
+        let collector_hpke_receiver_config = data.collector_hpke_receiver_config.clone();
+        let helper = data.new_helper();
+        let t = data.with_leader(helper);
+        let task_id = &t.heavy_hitters_task_id;
+        let task_config = t.leader.unchecked_get_task_config(task_id).await;
+        let agg_param_for_prefixes = |prefixes: [u8; 3]| {
+            DapAggregationParam::Mastic(
+                Poplar1AggregationParam::try_from_prefixes(
+                    prefixes
+                        .iter()
+                        .map(|prefix| IdpfInput::from_bytes(&[*prefix]))
+                        .collect(),
+                )
+                .unwrap(),
+            )
+        };
+        let first_agg_param = agg_param_for_prefixes([0, 1, 7]);
+        let second_agg_param = agg_param_for_prefixes([2, 3, 200]);
+
+        let mut reports = Vec::new();
+        for i in 0..10 {
+            let report = t
+                .gen_test_report_for_measurement(
+                    task_id,
+                    DapMeasurement::Mastic {
+                        input: vec![i],
+                        weight: MasticWeight::Bool(true),
+                    },
+                )
+                .await;
+            leader::handle_upload_req(
+                &*t.leader,
+                &t.gen_test_upload_req(report.clone(), task_id).await,
+            )
+            .await
+            .unwrap();
+            reports.push(report);
+        }
+
+        // Collect the batch under the first aggregation parameter.
+        let query = task_config.query_for_current_batch_window(t.now);
+        let batch_sel = query.clone().into_batch_sel().unwrap();
+        leader::handle_coll_job_req(
+            &*t.leader,
+            &t.gen_test_coll_job_req_for_agg_param(query, first_agg_param.clone(), task_id)
+                .await,
+        )
+        .await
+        .unwrap();
+        leader::process(&*t.leader, "leader.com", 100)
+            .await
+            .unwrap();
+        assert!(!t
+            .leader
+            .get_overlapping_buckets(task_id, &batch_sel, &first_agg_param)
+            .await
+            .unwrap()
+            .is_empty());
+        assert!(t
+            .leader
+            .get_overlapping_buckets(task_id, &batch_sel, &second_agg_param)
+            .await
+            .unwrap()
+            .is_empty());
+
+        // Aggregate the same reports under the second aggregation parameter. They are not replays,
+        // since they haven't been aggregated under this parameter yet.
+        let (leader_state, req) = t
+            .gen_test_agg_job_init_req(task_id, version, second_agg_param.clone(), reports)
+            .await;
+        let DapResource::AggregationJob(agg_job_id) = req.resource else {
+            panic!("unexpected resource");
+        };
+        let agg_job_resp = AggregationJobResp::get_decoded(
+            &helper::handle_agg_job_req(&*t.helper, &req)
+                .await
+                .unwrap()
+                .payload,
+        )
+        .unwrap();
+        let DapLeaderAggregationJobTransition::Finished(agg_span) = task_config
+            .handle_agg_job_resp(
+                task_id,
+                &MetaAggregationJobId::DraftLatest(agg_job_id),
+                leader_state,
+                agg_job_resp,
+                t.leader.metrics(),
+            )
+            .unwrap()
+        else {
+            panic!("unexpected transition");
+        };
+        for (_bucket, (result, _reports)) in t
+            .leader
+            .try_put_agg_share_span(task_id, &task_config, &second_agg_param, agg_span)
+            .await
+        {
+            result.unwrap();
+        }
+
+        let leader_agg_share = t
+            .leader
+            .get_agg_share(task_id, &batch_sel, &second_agg_param)
+            .await
+            .unwrap();
+        assert_eq!(leader_agg_share.report_count, 10);
+        let agg_share_req = |agg_param: &DapAggregationParam| {
+            t.leader_authorized_req(
+                task_id,
+                &task_config,
+                None,
+                DapMediaType::AggregateShareReq,
+                AggregateShareReq {
+                    draft02_task_id: task_id.for_request_payload(&version),
+                    batch_sel: batch_sel.clone(),
+                    agg_param: agg_param.get_encoded().unwrap(),
+                    report_count: leader_agg_share.report_count,
+                    checksum: leader_agg_share.checksum,
+                },
+            )
+        };
+
+        // Collecting the same batch under a different aggregation parameter succeeds.
+        let resp =
+            helper::handle_agg_share_req(&*t.helper, &agg_share_req(&second_agg_param).await)
+                .await
+                .unwrap();
+        let helper_encrypted_agg_share = AggregateShare::get_decoded(&resp.payload)
+            .unwrap()
+            .encrypted_agg_share;
+        let leader_encrypted_agg_share = task_config
+            .produce_leader_encrypted_agg_share(
+                None,
+                task_id,
+                &batch_sel,
+                &second_agg_param,
+                &leader_agg_share,
+                version,
+            )
+            .unwrap();
+        let agg_res = task_config
+            .vdaf
+            .consume_encrypted_agg_shares(
+                &collector_hpke_receiver_config,
+                task_id,
+                &batch_sel,
+                leader_agg_share.report_count,
+                &second_agg_param,
+                vec![leader_encrypted_agg_share, helper_encrypted_agg_share],
+                version,
+            )
+            .await
+            .unwrap();
+        assert_eq!(agg_res, DapAggregateResult::U64Vec(vec![1, 1, 0]));
+
+        // Collecting the batch again under either aggregation parameter is rejected.
+        for agg_param in [&first_agg_param, &second_agg_param] {
+            assert_matches!(
+                helper::handle_agg_share_req(&*t.helper, &agg_share_req(agg_param).await)
+                    .await
+                    .unwrap_err(),
+                DapError::Abort(DapAbort::BatchOverlap { .. })
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn heavy_hitters_collection_requires_vdaf_min_batch_size() {
+        // Collect a batch of `num_reports` reports from a task whose configured minimum batch size
+        // is below the VDAF's own minimum and return the status of the collection job.
+        async fn collect(num_reports: u8) -> Result<DapCollectionJob, DapError> {
+            let mut data = TestData::new(DapVersion::DraftLatest);
+            let task_id = data.heavy_hitters_task_id;
+            let task_config = data.tasks.get_mut(&task_id).unwrap();
+            task_config.min_batch_size = 1;
+            assert_eq!(task_config.effective_min_batch_size(), 2);
+            let helper = data.new_helper();
+            let t = data.with_leader(helper);
+            let task_config = t.leader.unchecked_get_task_config(&task_id).await;
+
+            for i in 0..num_reports {
+                let report = t
+                    .gen_test_report_for_measurement(
+                        &task_id,
+                        DapMeasurement::Mastic {
+                            input: vec![i],
+                            weight: MasticWeight::Bool(true),
+                        },
+                    )
+                    .await;
+                leader::handle_upload_req(
+                    &*t.leader,
+                    &t.gen_test_upload_req(report, &task_id).await,
+                )
+                .await
+                .unwrap();
+            }
+
+            let query = task_config.query_for_current_batch_window(t.now);
+            let agg_param = DapAggregationParam::Mastic(
+                Poplar1AggregationParam::try_from_prefixes(vec![
+                    IdpfInput::from_bytes(&[0]),
+                    IdpfInput::from_bytes(&[1]),
+                ])
+                .unwrap(),
+            );
+            leader::handle_coll_job_req(
+                &*t.leader,
+                &t.gen_test_coll_job_req_for_agg_param(query, agg_param, &task_id)
+                    .await,
+            )
+            .await
+            .unwrap();
+            leader::process(&*t.leader, "leader.com", 100).await?;
+
+            let [(_coll_job_id, coll_job_info)] =
+                <[_; 1]>::try_from(t.leader.list_collection_jobs(&task_id).await.unwrap()).unwrap();
+            Ok(coll_job_info.status)
+        }
+
+        // A single report satisfies the task's configured minimum, but not the VDAF's.
+        assert_matches!(
+            collect(1).await,
+            Err(DapError::Abort(DapAbort::InvalidBatchSize { .. }))
+        );
+        assert_matches!(
+            collect(2).await,
+            Ok(DapCollectionJob::Done(collection)) if collection.report_count == 2
+        );
+    }
 }
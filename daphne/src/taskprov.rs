@@ -71,25 +71,6 @@ impl VdafConfig {
     }
 }
 
-/// Compute the VDAF verify key for `task_id` and the specified VDAF type using the
-/// pre-shared secret `verify_key_init`.
-///
-/// This is a convenience function to call `compute_vdaf_verify_prk`() and
-/// `compute_vdaf_verify_key_from_prk`(). Callers reusing the same PRK frequently
-/// should consider computing the prk once and then calling `compute_vdaf_verify_key_from_prk`()
-/// directly.
-fn compute_vdaf_verify_key(
-    version: DapVersion,
-    verify_key_init: &[u8; 32],
-    task_id: &TaskId,
-    vdaf_config: &VdafConfig,
-) -> VdafVerifyKey {
-    vdaf_config.expand_into_taskprov_verify_key(
-        &extract_prk_from_verify_key_init(version, verify_key_init),
-        task_id,
-    )
-}
-
 /// Opt out due to invalid configuration.
 //
 // TODO taskprov spec: Decide if this should be a different error type.
@@ -277,6 +258,22 @@ impl VdafConfig {
 }
 
 impl DapTaskConfig {
+    /// Derive the VDAF verify key for a task configured by the taskprov extension from the
+    /// pre-shared secret `verify_key_init`. The key is the output of HKDF-SHA256 with the SHA-256
+    /// hash of "dap-taskprov" as the salt, `verify_key_init` as the input keying material and
+    /// `task_id` as the info. Its length is determined by the VDAF.
+    pub fn derive_taskprov_verify_key(
+        verify_key_init: &[u8; 32],
+        task_id: &TaskId,
+        version: DapVersion,
+        vdaf_config: &VdafConfig,
+    ) -> VdafVerifyKey {
+        vdaf_config.expand_into_taskprov_verify_key(
+            &extract_prk_from_verify_key_init(version, verify_key_init),
+            task_id,
+        )
+    }
+
     pub fn try_from_taskprov(
         version: DapVersion,
         task_id: &TaskId,
@@ -308,18 +305,21 @@ impl DapTaskConfig {
 
         let vdaf = VdafConfig::try_from_taskprov(task_id, version, task_config.vdaf_config.var)?;
         let vdaf_verify_key =
-            compute_vdaf_verify_key(version, vdaf_verify_key_init, task_id, &vdaf);
+            Self::derive_taskprov_verify_key(vdaf_verify_key_init, task_id, version, &vdaf);
         Ok(DapTaskConfig {
             version,
             leader_url: url_from_bytes(task_id, &task_config.leader_url.bytes)?,
             helper_url: url_from_bytes(task_id, &task_config.helper_url.bytes)?,
             time_precision: task_config.query_config.time_precision,
+            time_quantization_offset: 0,
+            align_batch_intervals: false,
             expiration: task_config.task_expiration,
             min_batch_size: task_config.query_config.min_batch_size.into(),
             query: DapQueryConfig::try_from_taskprov(task_id, task_config.query_config.var)?,
             vdaf,
             vdaf_verify_key,
             collector_hpke_config: collector_hpke_config.clone(),
+            collector_hpke_config_allowlist: Vec::new(),
             method: DapTaskConfigMethod::Taskprov {
                 info: Some(task_config.task_info),
             },
@@ -394,6 +394,12 @@ impl TryFrom<&DapTaskConfig> for messages::taskprov::TaskConfig {
             return Err(fatal_error!(err = "task was not configured by taskprov"));
         };
 
+        if task_config.time_quantization_offset != 0 {
+            return Err(fatal_error!(
+                err = "time quantization offset is not supported for taskprov"
+            ));
+        }
+
         Ok(Self {
             task_info: task_info.clone(),
             leader_url: messages::taskprov::UrlBytes {
@@ -437,7 +443,7 @@ impl ReportMetadata {
 mod test {
     use prio::codec::ParameterizedEncode;
 
-    use super::{compute_task_id, compute_vdaf_verify_key, resolve_advertised_task_config};
+    use super::{compute_task_id, resolve_advertised_task_config};
     use crate::{
         auth::BearerToken,
         constants::DapMediaType,
@@ -445,7 +451,7 @@ mod test {
         hpke::{HpkeKemId, HpkeReceiverConfig},
         messages::{self, encode_base64url, Extension, ReportId, ReportMetadata, TaskId},
         test_versions,
-        vdaf::{VdafConfig, VdafVerifyKey},
+        vdaf::{Prio3Config, VdafConfig, VdafVerifyKey},
         DapRequest, DapResource, DapTaskConfig, DapVersion,
     };
 
@@ -488,16 +494,21 @@ mod test {
         )
         .unwrap();
 
+        assert_eq!(task_config.time_quantization_offset, 0);
         assert_eq!(
             messages::taskprov::TaskConfig::try_from(&task_config).unwrap(),
             taskprov_config
         );
+
+        // The time quantization offset can't be conveyed to the peer.
+        let mut task_config = task_config;
+        task_config.time_quantization_offset = 600;
+        assert!(messages::taskprov::TaskConfig::try_from(&task_config).is_err());
     }
 
     test_versions! { try_from_taskprov }
 
-    #[test]
-    fn check_vdaf_key_computation() {
+    fn check_vdaf_key_computation(version: DapVersion) {
         let task_id = TaskId([
             0xb4, 0x76, 0x9b, 0xb0, 0x63, 0xa8, 0xb3, 0x31, 0x2a, 0xf7, 0x42, 0x97, 0xf3, 0x0f,
             0xdb, 0xf8, 0xe0, 0xb7, 0x1c, 0x2e, 0xb2, 0x48, 0x1f, 0x59, 0x1d, 0x1d, 0x7d, 0xe6,
@@ -508,22 +519,37 @@ mod test {
             0x0f, 0x32, 0xd7, 0xe1, 0xbc, 0x6c, 0x75, 0x10, 0x05, 0x60, 0x7b, 0x81, 0xda, 0xc3,
             0xa7, 0xda, 0x76, 0x1d,
         ];
-        let vk = compute_vdaf_verify_key(
-            DapVersion::Draft02,
-            &verify_key_init,
-            &task_id,
-            &VdafConfig::Prio2 { dimension: 10 },
-        );
         let expected: [u8; 32] = [
             251, 209, 125, 181, 57, 15, 148, 158, 227, 45, 38, 52, 220, 73, 159, 91, 145, 40, 123,
             204, 49, 124, 7, 97, 221, 4, 232, 53, 194, 171, 19, 51,
         ];
+
+        let vk = DapTaskConfig::derive_taskprov_verify_key(
+            &verify_key_init,
+            &task_id,
+            version,
+            &VdafConfig::Prio2 { dimension: 10 },
+        );
         match &vk {
             VdafVerifyKey::L32(bytes) => assert_eq!(*bytes, expected),
             VdafVerifyKey::L16(..) => unreachable!(),
         }
+
+        // A shorter key is a prefix of the HKDF output.
+        let vk = DapTaskConfig::derive_taskprov_verify_key(
+            &verify_key_init,
+            &task_id,
+            version,
+            &VdafConfig::Prio3(Prio3Config::Count),
+        );
+        match &vk {
+            VdafVerifyKey::L16(bytes) => assert_eq!(bytes[..], expected[..16]),
+            VdafVerifyKey::L32(..) => unreachable!(),
+        }
     }
 
+    test_versions! { check_vdaf_key_computation }
+
     // Ensure that the task config is computed the same way whether it was advertised in the request
     // header or the report metadata.
     #[test]
@@ -669,6 +695,10 @@ mod test {
                 payload: Vec::default(),          // ignored by test
                 sender_auth: None,                // ignored by test
                 taskprov: Some(taskprov_task_config_base64url),
+                idempotency_token: None,
+                vdaf_version: None,
+                collection_format: Default::default(),
+                collector_hpke_config_id: None,
             };
 
             (req, task_id)
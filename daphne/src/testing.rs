@@ -7,38 +7,50 @@ use crate::{
     audit_log::{AggregationJobAuditAction, AuditLog},
     auth::{BearerToken, BearerTokenProvider},
     constants::DapMediaType,
+    error::PeerError,
     fatal_error,
-    hpke::{HpkeConfig, HpkeDecrypter, HpkeKemId, HpkeReceiverConfig},
+    hpke::{
+        validate_hpke_receiver_config_list, HpkeConfig, HpkeDecrypter, HpkeKemId,
+        HpkeReceiverConfig,
+    },
     messages::{
         AggregationJobContinueReq, AggregationJobInitReq, AggregationJobResp, Base64Encode,
-        BatchId, BatchSelector, Collection, CollectionJobId, HpkeCiphertext, Interval,
-        PartialBatchSelector, Report, ReportId, TaskId, Time, TransitionFailure,
+        BatchId, BatchSelector, Collection, CollectionJobId, Duration, HpkeCiphertext, Interval,
+        PartialBatchSelector, PrepareInit, Query, Report, ReportId, ReportMetadata, ReportShare,
+        TaskId, Time, TransitionFailure,
     },
     metrics::{prometheus::DaphnePromMetrics, DaphneMetrics},
     protocol::aggregator::{
         EarlyReportState, EarlyReportStateConsumed, EarlyReportStateInitialized,
     },
     roles::{
-        aggregator::MergeAggShareError, helper, leader::WorkItem, DapAggregator,
-        DapAuthorizedSender, DapHelper, DapLeader, DapReportInitializer,
+        aggregator::{CollectionPreview, MergeAggShareError},
+        helper,
+        leader::{
+            AggregationJobCheckpoint, UploadDedupCache, WorkItem, WorkItemId, WorkQueueStats,
+            WorkQueueWatermarks,
+        },
+        DapAggregator, DapAuthorizedSender, DapHelper, DapLeader, DapReportInitializer,
     },
     DapAbort, DapAggregateResult, DapAggregateShare, DapAggregateSpan, DapAggregationJobState,
-    DapAggregationJobUncommitted, DapAggregationParam, DapBatchBucket, DapCollectionJob, DapError,
-    DapGlobalConfig, DapHelperAggregationJobTransition, DapLeaderAggregationJobTransition,
-    DapMeasurement, DapQueryConfig, DapRequest, DapResponse, DapTaskConfig, DapVersion,
+    DapAggregationJobStatus, DapAggregationJobUncommitted, DapAggregationParam, DapBatchBucket,
+    DapCollectionJob, DapCollectionJobInfo, DapError, DapGlobalConfig,
+    DapHelperAggregationJobTransition, DapLeaderAggregationJobTransition, DapMeasurement,
+    DapQueryConfig, DapRequest, DapResponse, DapRetainedAggShare, DapTaskConfig, DapVersion,
     MetaAggregationJobId, VdafConfig,
 };
 use async_trait::async_trait;
 use deepsize::DeepSizeOf;
-use prio::codec::Encode;
+use futures::channel::mpsc;
+use prio::codec::{Decode, Encode};
 use rand::{thread_rng, Rng};
 use serde::{Deserialize, Serialize};
 use std::{
-    collections::{HashMap, HashSet, VecDeque},
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
     hash::Hash,
-    ops::DerefMut,
+    ops::{DerefMut, RangeInclusive},
     sync::{
-        atomic::{AtomicU32, Ordering},
+        atomic::{AtomicU32, AtomicU64, AtomicUsize, Ordering},
         Arc, Mutex,
     },
     time::SystemTime,
@@ -62,6 +74,7 @@ pub struct AggregationJobTest {
     pub(crate) now: Time,
 
     // operational parameters
+    pub(crate) global_config: DapGlobalConfig,
     #[allow(dead_code)]
     pub(crate) leader_registry: prometheus::Registry,
     #[allow(dead_code)]
@@ -165,14 +178,37 @@ impl AggregationJobTest {
                 leader_url: Url::parse("http://leader.com").unwrap(),
                 helper_url: Url::parse("https://helper.org").unwrap(),
                 time_precision: 500,
+                time_quantization_offset: 0,
+                align_batch_intervals: false,
                 expiration: now + 500,
                 min_batch_size: 10,
                 query: DapQueryConfig::TimeInterval,
                 vdaf: *vdaf,
                 vdaf_verify_key,
                 collector_hpke_config,
+                collector_hpke_config_allowlist: Vec::new(),
                 method: Default::default(),
             },
+            global_config: DapGlobalConfig {
+                max_batch_duration: 360_000,
+                min_batch_interval_start: 259_200,
+                max_batch_interval_end: 259_200,
+                supported_hpke_kems: vec![kem_id],
+                allow_taskprov: false,
+                agg_share_idempotency_window: 3600,
+                revoked_hpke_config_ids: Vec::new(),
+                upload_dedup_cache_capacity: 0,
+                max_concurrent_agg_jobs_per_task: 0,
+                eager_agg_threshold: None,
+                max_report_shares_per_agg_job: None,
+                agg_job_resp_chunk_size: None,
+                work_item_visibility_timeout: None,
+                agg_job_retry_budget: 0,
+                agg_job_retry_backoff: 0,
+                report_debug_sample_rate: 0.0,
+                taskprov_vdaf_self_test: false,
+                allow_http_urls: true,
+            },
             leader_registry,
             helper_registry,
             leader_metrics,
@@ -182,6 +218,26 @@ impl AggregationJobTest {
         }
     }
 
+    /// The task ID.
+    pub fn task_id(&self) -> &TaskId {
+        &self.task_id
+    }
+
+    /// The task configuration.
+    pub fn task_config(&self) -> &DapTaskConfig {
+        &self.task_config
+    }
+
+    /// The Helper's HPKE receiver config, to which the reports are encrypted.
+    pub fn helper_hpke_receiver_config(&self) -> &HpkeReceiverConfig {
+        &self.helper_hpke_receiver_config
+    }
+
+    /// The ID of the aggregation job produced by [`Self::produce_agg_job_init_req`].
+    pub fn agg_job_id(&self) -> &MetaAggregationJobId {
+        &self.agg_job_id
+    }
+
     /// For each measurement, generate a report for the given task.
     ///
     /// Panics if a measurement is incompatible with the given VDAF.
@@ -205,6 +261,30 @@ impl AggregationJobTest {
         reports
     }
 
+    /// Like [`Self::produce_reports`], except each report is generated with the given report ID.
+    /// This is useful for deliberately producing reports with colliding IDs.
+    ///
+    /// Panics if a measurement is incompatible with the given VDAF.
+    pub fn produce_reports_with_ids(&self, pairs: Vec<(ReportId, DapMeasurement)>) -> Vec<Report> {
+        pairs
+            .into_iter()
+            .map(|(report_id, measurement)| {
+                self.task_config
+                    .vdaf
+                    .produce_report_with_id(
+                        &self.client_hpke_config_list,
+                        self.now,
+                        &self.task_id,
+                        &report_id,
+                        measurement,
+                        Vec::new(),
+                        self.task_config.version,
+                    )
+                    .unwrap()
+            })
+            .collect()
+    }
+
     /// Leader: Produce `AggregationJobInitReq`.
     ///
     /// Panics if the Leader aborts.
@@ -247,6 +327,9 @@ impl AggregationJobTest {
                         self,
                         &self.task_id,
                         agg_job_init_req,
+                        &self.helper_metrics,
+                        &self.global_config,
+                        |_processed, _total| (),
                     )
                     .await
                     .unwrap(),
@@ -351,7 +434,7 @@ impl AggregationJobTest {
     ) -> HpkeCiphertext {
         self.task_config
             .produce_leader_encrypted_agg_share(
-                &self.task_config.collector_hpke_config,
+                None,
                 &self.task_id,
                 batch_selector,
                 agg_param,
@@ -370,7 +453,7 @@ impl AggregationJobTest {
     ) -> HpkeCiphertext {
         self.task_config
             .produce_helper_encrypted_agg_share(
-                &self.task_config.collector_hpke_config,
+                None,
                 &self.task_id,
                 batch_selector,
                 agg_param,
@@ -403,12 +486,58 @@ impl AggregationJobTest {
             .unwrap()
     }
 
+    /// Run the aggregation flow to completion, starting from the Leader's initial request. Returns
+    /// the Leader's and Helper's aggregate spans.
+    async fn run_agg_job(
+        &self,
+        leader_state: DapAggregationJobState,
+        agg_job_init_req: AggregationJobInitReq,
+    ) -> (
+        DapAggregateSpan<DapAggregateShare>,
+        DapAggregateSpan<DapAggregateShare>,
+    ) {
+        match self.handle_agg_job_init_req(agg_job_init_req).await {
+            DapHelperAggregationJobTransition::Continued(helper_state, agg_job_resp) => {
+                let got = DapAggregationJobState::get_decoded(
+                    &self.task_config.vdaf,
+                    &helper_state.get_encoded().unwrap(),
+                )
+                .expect("failed to decode helper state");
+                assert_eq!(
+                    got.get_encoded().unwrap(),
+                    helper_state.get_encoded().unwrap()
+                );
+
+                let DapLeaderAggregationJobTransition::Uncommitted(uncommitted, agg_cont) =
+                    self.handle_agg_job_resp(leader_state, agg_job_resp)
+                else {
+                    panic!("unexpected transition");
+                };
+                let (helper_agg_span, transitions) =
+                    self.handle_agg_job_cont_req(&helper_state, &agg_cont);
+                let leader_agg_span = self.handle_final_agg_job_resp(uncommitted, transitions);
+                (leader_agg_span, helper_agg_span)
+            }
+            DapHelperAggregationJobTransition::Finished(helper_agg_span, agg_job_resp) => {
+                let DapLeaderAggregationJobTransition::Finished(leader_agg_span) =
+                    self.handle_agg_job_resp(leader_state, agg_job_resp)
+                else {
+                    panic!("unexpected transition");
+                };
+                (leader_agg_span, helper_agg_span)
+            }
+        }
+    }
+
     /// Generate a set of reports, aggregate them, and unshard the result.
+    ///
+    /// Returns an abort if the batch can't be collected, e.g., because there are no reports to
+    /// aggregate.
     pub async fn roundtrip(
         &mut self,
         agg_param: DapAggregationParam,
         measurements: Vec<DapMeasurement>,
-    ) -> DapAggregateResult {
+    ) -> Result<DapAggregateResult, DapAbort> {
         let batch_selector = BatchSelector::TimeInterval {
             batch_interval: Interval {
                 start: self.now,
@@ -420,42 +549,17 @@ impl AggregationJobTest {
         let reports = self.produce_reports(measurements);
 
         // Aggregators: Preparation
-        let DapLeaderAggregationJobTransition::Continued(leader_state, agg_job_init_req) =
-            self.produce_agg_job_init_req(&agg_param, reports).await
-        else {
-            panic!("unexpected transition");
-        };
-
         let (leader_agg_span, helper_agg_span) =
-            match self.handle_agg_job_init_req(agg_job_init_req).await {
-                DapHelperAggregationJobTransition::Continued(helper_state, agg_job_resp) => {
-                    let got = DapAggregationJobState::get_decoded(
-                        &self.task_config.vdaf,
-                        &helper_state.get_encoded().unwrap(),
-                    )
-                    .expect("failed to decode helper state");
-                    assert_eq!(
-                        got.get_encoded().unwrap(),
-                        helper_state.get_encoded().unwrap()
-                    );
-
-                    let DapLeaderAggregationJobTransition::Uncommitted(uncommitted, agg_cont) =
-                        self.handle_agg_job_resp(leader_state, agg_job_resp)
-                    else {
-                        panic!("unexpected transition");
-                    };
-                    let (helper_agg_span, transitions) =
-                        self.handle_agg_job_cont_req(&helper_state, &agg_cont);
-                    let leader_agg_span = self.handle_final_agg_job_resp(uncommitted, transitions);
-                    (leader_agg_span, helper_agg_span)
+            match self.produce_agg_job_init_req(&agg_param, reports).await {
+                DapLeaderAggregationJobTransition::Continued(leader_state, agg_job_init_req) => {
+                    self.run_agg_job(leader_state, agg_job_init_req).await
                 }
-                DapHelperAggregationJobTransition::Finished(helper_agg_span, agg_job_resp) => {
-                    let DapLeaderAggregationJobTransition::Finished(leader_agg_span) =
-                        self.handle_agg_job_resp(leader_state, agg_job_resp)
-                    else {
-                        panic!("unexpected transition");
-                    };
-                    (leader_agg_span, helper_agg_span)
+                // There are no reports to aggregate, so the Leader has nothing to send.
+                DapLeaderAggregationJobTransition::Finished(leader_agg_span) => {
+                    (leader_agg_span, DapAggregateSpan::default())
+                }
+                DapLeaderAggregationJobTransition::Uncommitted(..) => {
+                    panic!("unexpected transition")
                 }
             };
 
@@ -463,8 +567,18 @@ impl AggregationJobTest {
 
         // Leader: Aggregation
         let leader_agg_share = leader_agg_span.collapsed();
-        let leader_encrypted_agg_share =
-            self.produce_leader_encrypted_agg_share(&batch_selector, &agg_param, &leader_agg_share);
+        let leader_encrypted_agg_share = match self.task_config.produce_leader_encrypted_agg_share(
+            None,
+            &self.task_id,
+            &batch_selector,
+            &agg_param,
+            &leader_agg_share,
+            self.task_config.version,
+        ) {
+            Ok(leader_encrypted_agg_share) => leader_encrypted_agg_share,
+            Err(DapError::Abort(e)) => return Err(e),
+            Err(e) => panic!("unexpected error: {e}"),
+        };
 
         // Helper: Aggregation
         let helper_encrypted_agg_share = self.produce_helper_encrypted_agg_share(
@@ -474,13 +588,14 @@ impl AggregationJobTest {
         );
 
         // Collector: Unshard
-        self.consume_encrypted_agg_shares(
-            &batch_selector,
-            report_count,
-            &agg_param,
-            vec![leader_encrypted_agg_share, helper_encrypted_agg_share],
-        )
-        .await
+        Ok(self
+            .consume_encrypted_agg_shares(
+                &batch_selector,
+                report_count,
+                &agg_param,
+                vec![leader_encrypted_agg_share, helper_encrypted_agg_share],
+            )
+            .await)
     }
 }
 
@@ -556,6 +671,37 @@ impl From<DapBatchBucket> for PartialBatchSelector {
     }
 }
 
+/// Generate an aggregation job initialization request with `num_reports` report shares of dummy
+/// data. This is meant for exercising the handling of large requests; the report shares can't be
+/// prepared.
+pub fn gen_dummy_agg_job_init_req(num_reports: u32) -> AggregationJobInitReq {
+    let mut rng = thread_rng();
+    AggregationJobInitReq {
+        draft02_task_id: None,
+        draft02_agg_job_id: None,
+        agg_param: Vec::new(),
+        part_batch_sel: PartialBatchSelector::TimeInterval,
+        prep_inits: (0..num_reports)
+            .map(|i| PrepareInit {
+                report_share: ReportShare {
+                    report_metadata: ReportMetadata {
+                        id: ReportId(rng.gen()),
+                        time: 1_637_364_244 + u64::from(i),
+                        draft02_extensions: None,
+                    },
+                    public_share: b"public share".to_vec(),
+                    encrypted_input_share: HpkeCiphertext {
+                        config_id: 23,
+                        enc: vec![1; 32],
+                        payload: vec![2; 64],
+                    },
+                },
+                draft_latest_payload: Some(b"prep share".to_vec()),
+            })
+            .collect(),
+    }
+}
+
 #[derive(Default)]
 #[cfg_attr(any(test, feature = "test-utils"), derive(deepsize::DeepSizeOf))]
 pub struct MockAuditLog(AtomicU32);
@@ -580,38 +726,236 @@ impl AuditLog for MockAuditLog {
     }
 }
 
+/// A source of the current time.
+pub trait Clock: Send + Sync {
+    /// Return the current time, in seconds since the UNIX epoch.
+    fn now(&self) -> Time;
+}
+
+/// The system clock.
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Time {
+        SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    }
+}
+
+/// A clock that only moves when advanced by the test.
+pub struct MockClock(AtomicU64);
+
+impl MockClock {
+    pub fn new(now: Time) -> Self {
+        Self(AtomicU64::new(now))
+    }
+
+    /// Move the clock forward by `duration` seconds.
+    pub fn advance(&self, duration: Duration) {
+        self.0.fetch_add(duration, Ordering::Relaxed);
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> Time {
+        self.0.load(Ordering::Relaxed)
+    }
+}
+
 #[derive(Default)]
 pub struct MockLeaderMemory {
     work_queue: VecDeque<WorkItem>,
+    leased_work: BTreeMap<WorkItemId, (Time, WorkItem)>, // Work leased until the given time
+    next_work_item_id: u64,
     per_task: HashMap<TaskId, MockLeaderMemoryPerTask>,
+    report_shard_key: [u8; 32],
+    report_shard_count: u64,
+    /// Whether uploads are being refused because the work queue is backed up. See
+    /// [`Self::put_report`].
+    shedding_uploads: bool,
+}
+
+/// Split the reports of a shard into aggregation jobs of at most `max_report_shares` reports each.
+fn split_agg_job(reports: VecDeque<Report>, max_report_shares: Option<usize>) -> Vec<Vec<Report>> {
+    let mut reports = Vec::from(reports);
+    let mut agg_jobs = Vec::new();
+    if let Some(max_report_shares) = max_report_shares.filter(|max| *max > 0) {
+        while reports.len() > max_report_shares {
+            let rest = reports.split_off(max_report_shares);
+            agg_jobs.push(reports);
+            reports = rest;
+        }
+    }
+    agg_jobs.push(reports);
+    agg_jobs
 }
 
 impl MockLeaderMemory {
+    /// Create a store that partitions the pending reports of each bucket into
+    /// `report_shard_count` shards (see [`ReportId::shard`]). Each shard is aggregated in its own
+    /// aggregation job, which spreads the work across jobs.
+    pub fn with_report_sharding(report_shard_key: [u8; 32], report_shard_count: u64) -> Self {
+        Self {
+            report_shard_key,
+            report_shard_count,
+            ..Default::default()
+        }
+    }
+
     pub fn delete_all(&mut self) {
         self.work_queue.clear();
+        self.leased_work.clear();
         self.per_task.clear();
     }
 
+    /// Store a report. If `eager_agg_threshold` is set in `global_config`, then a time-interval
+    /// bucket that reaches the threshold is queued for aggregation right away. The queued
+    /// aggregation jobs may be retried `agg_job_retry_budget` times and carry at most
+    /// `max_report_shares_per_agg_job` reports each.
+    ///
+    /// If `work_queue_watermarks` is set, then the report is refused with a
+    /// [`DapAbort::ServiceUnavailable`] while the work queue is backed up.
     pub fn put_report(
         &mut self,
         task_id: &TaskId,
         task_config: &DapTaskConfig,
         report: Report,
+        global_config: &DapGlobalConfig,
+        work_queue_watermarks: Option<WorkQueueWatermarks>,
     ) -> Result<(), DapError> {
+        if let Some(watermarks) = work_queue_watermarks {
+            if self.work_queue.len() > watermarks.high {
+                self.shedding_uploads = true;
+            } else if self.work_queue.len() < watermarks.low {
+                self.shedding_uploads = false;
+            }
+            if self.shedding_uploads {
+                return Err(DapAbort::ServiceUnavailable {
+                    retry_after: watermarks.retry_after,
+                }
+                .into());
+            }
+        }
+
+        let shard = report
+            .report_metadata
+            .id
+            .shard(&self.report_shard_key, self.report_shard_count);
         let per_task = self.per_task.entry(*task_id).or_default();
         let bucket = per_task.assign_report_to_bucket(task_config, &report);
 
-        // Store the report until a collection job is initialized for it. Note that, in a
-        // production Leader, it will usually be desirable to start aggregating reports immediately
-        // (if allowed by the VDAF).
-        per_task
-            .pending_reports
-            .entry(bucket)
-            .or_default()
-            .push_back(report);
+        // Store the report until a collection job is initialized for it, unless eager
+        // aggregation is enabled.
+        let shards = per_task.pending_reports.entry(bucket.clone()).or_default();
+        shards.entry(shard).or_default().push_back(report);
+
+        let Some(threshold) = global_config.eager_agg_threshold else {
+            return Ok(());
+        };
+        let threshold = if threshold == 0 {
+            task_config.effective_min_batch_size()
+        } else {
+            threshold
+        };
+        // Eager aggregation only makes sense if the VDAF has no aggregation parameter, since
+        // otherwise the parameter is only known once the Collector asks for it.
+        if !matches!(bucket, DapBatchBucket::TimeInterval { .. })
+            || matches!(task_config.vdaf, VdafConfig::Mastic { .. })
+            || u64::try_from(shards.values().map(VecDeque::len).sum::<usize>()).unwrap() < threshold
+        {
+            return Ok(());
+        }
+
+        let shards = per_task.pending_reports.remove(&bucket).unwrap();
+        for reports in shards
+            .into_values()
+            .flat_map(|reports| split_agg_job(reports, global_config.max_report_shares_per_agg_job))
+        {
+            self.push_work(WorkItem::AggregationJob {
+                task_id: *task_id,
+                agg_job_id: MetaAggregationJobId::gen_for_version(task_config.version),
+                part_batch_sel: PartialBatchSelector::TimeInterval,
+                agg_param: DapAggregationParam::Empty,
+                reports,
+                retries_remaining: global_config.agg_job_retry_budget,
+                not_before: None,
+            });
+        }
         Ok(())
     }
 
+    /// Return the number of reports, across all tasks, that are waiting for a collection job (or
+    /// the eager aggregation threshold) to be queued for aggregation.
+    pub fn pending_report_count(&self) -> usize {
+        self.per_task
+            .values()
+            .flat_map(|per_task| per_task.pending_reports.values())
+            .flat_map(BTreeMap::values)
+            .map(VecDeque::len)
+            .sum()
+    }
+
+    /// Whether any reports in the given buckets belong to an aggregation job under the given
+    /// aggregation parameter that is queued or in flight. Reports uploaded after the collection job
+    /// was initialized are still pending and don't count, since they aren't queued for aggregation
+    /// until the next collection job.
+    pub fn has_unaggregated_reports(
+        &self,
+        task_id: &TaskId,
+        task_config: &DapTaskConfig,
+        buckets: &HashSet<DapBatchBucket>,
+        agg_param: &DapAggregationParam,
+    ) -> bool {
+        self.work_queue
+            .iter()
+            .chain(
+                self.leased_work
+                    .values()
+                    .map(|(_expiration, work_item)| work_item),
+            )
+            .any(|work_item| match work_item {
+                WorkItem::AggregationJob {
+                    task_id: agg_job_task_id,
+                    part_batch_sel,
+                    agg_param: agg_job_agg_param,
+                    reports,
+                    ..
+                } if agg_job_task_id == task_id && agg_job_agg_param == agg_param => {
+                    reports.iter().any(|report| {
+                        let bucket = match part_batch_sel {
+                            PartialBatchSelector::TimeInterval => DapBatchBucket::TimeInterval {
+                                batch_window: task_config
+                                    .quantized_time_lower_bound(report.report_metadata.time),
+                            },
+                            PartialBatchSelector::FixedSizeByBatchId { batch_id } => {
+                                DapBatchBucket::FixedSize {
+                                    batch_id: *batch_id,
+                                }
+                            }
+                        };
+                        buckets.contains(&bucket)
+                    })
+                }
+                _ => false,
+            })
+    }
+
+    /// Return the number of buckets, across all tasks, that have pending reports.
+    pub fn pending_bucket_count(&self) -> usize {
+        self.per_task
+            .values()
+            .map(|per_task| per_task.pending_reports.len())
+            .sum()
+    }
+
+    /// Return the smallest interval containing the timestamps of the reports assigned to the
+    /// fixed-size batch.
+    pub fn batch_time_range(&self, task_id: &TaskId, batch_id: &BatchId) -> Option<Interval> {
+        self.per_task.get(task_id)?.batch_time_range(batch_id)
+    }
+
     pub fn current_batch(
         &self,
         task_id: &TaskId,
@@ -634,32 +978,307 @@ impl MockLeaderMemory {
             .ok_or_else(|| DapError::Abort(DapAbort::BadRequest("empty batch queue".into())))
     }
 
+    /// Return the current batch and remove it from the batch queue, so that it's neither returned
+    /// by a subsequent call nor assigned any more reports. The batch is only reserved once it has
+    /// reached the task's minimum batch size.
+    pub fn reserve_current_batch(
+        &mut self,
+        task_id: &TaskId,
+        task_config: &DapTaskConfig,
+    ) -> std::result::Result<BatchId, DapError> {
+        let batch_id = self.current_batch(task_id, task_config)?;
+        let Some(per_task) = self.per_task.get_mut(task_id) else {
+            return Err(DapError::Abort(DapAbort::UnrecognizedTask));
+        };
+        if per_task
+            .batch_queue
+            .front()
+            .is_some_and(|(_batch_id, report_count)| {
+                *report_count < task_config.effective_min_batch_size()
+            })
+        {
+            return Err(DapError::Abort(DapAbort::InvalidBatchSize {
+                detail: "The current batch has not yet reached the minimum batch size.".into(),
+                task_id: *task_id,
+            }));
+        }
+        per_task.batch_queue.pop_front();
+        per_task.reserved_batches.insert(batch_id);
+        Ok(batch_id)
+    }
+
+    /// Summarize the contents of the work queue. Leased items are not counted.
+    pub fn work_queue_stats(&self) -> WorkQueueStats {
+        let mut stats = WorkQueueStats::default();
+        for work_item in &self.work_queue {
+            stats.add(work_item);
+        }
+        stats
+    }
+
     pub fn enqueue_work(&mut self, work_items: Vec<WorkItem>) -> Result<(), DapError> {
-        self.work_queue.extend(work_items);
+        for work_item in work_items {
+            self.push_work(work_item);
+        }
+        Ok(())
+    }
+
+    /// Append a work item to the queue, marking it as queued if it's an aggregation job.
+    fn push_work(&mut self, work_item: WorkItem) {
+        if let WorkItem::AggregationJob {
+            task_id,
+            agg_job_id,
+            ..
+        } = &work_item
+        {
+            self.per_task
+                .entry(*task_id)
+                .or_default()
+                .agg_job_status
+                .set(*agg_job_id, DapAggregationJobStatus::Queued);
+        }
+        self.work_queue.push_back(work_item);
+    }
+
+    pub fn agg_job_status(
+        &self,
+        task_id: &TaskId,
+        agg_job_id: &MetaAggregationJobId,
+    ) -> Result<Option<DapAggregationJobStatus>, DapError> {
+        Ok(self
+            .per_task
+            .get(task_id)
+            .and_then(|per_task| per_task.agg_job_status.get(agg_job_id))
+            .copied())
+    }
+
+    pub fn set_agg_job_status(
+        &mut self,
+        task_id: &TaskId,
+        agg_job_id: &MetaAggregationJobId,
+        status: DapAggregationJobStatus,
+    ) -> Result<(), DapError> {
+        self.per_task
+            .entry(*task_id)
+            .or_default()
+            .agg_job_status
+            .set(*agg_job_id, status);
+        Ok(())
+    }
+
+    /// The in-memory footprint of the reports that are waiting to be aggregated, in bytes.
+    pub fn pending_reports_size(&self) -> usize {
+        self.per_task
+            .values()
+            .map(|per_task| per_task.pending_reports.deep_size_of())
+            .sum()
+    }
+
+    pub fn put_agg_job_checkpoint(
+        &mut self,
+        checkpoint: AggregationJobCheckpoint,
+    ) -> Result<(), DapError> {
+        self.per_task
+            .entry(checkpoint.task_id)
+            .or_default()
+            .agg_job_checkpoints
+            .insert(checkpoint.agg_job_id, checkpoint);
+        Ok(())
+    }
+
+    pub fn delete_agg_job_checkpoint(
+        &mut self,
+        task_id: &TaskId,
+        agg_job_id: &MetaAggregationJobId,
+    ) -> Result<(), DapError> {
+        if let Some(per_task) = self.per_task.get_mut(task_id) {
+            per_task.agg_job_checkpoints.remove(agg_job_id);
+        }
         Ok(())
     }
 
-    pub fn dequeue_work(&mut self, num_items: usize) -> Result<Vec<WorkItem>, DapError> {
+    pub fn list_agg_job_checkpoints(&self) -> Result<Vec<AggregationJobCheckpoint>, DapError> {
+        Ok(self
+            .per_task
+            .values()
+            .flat_map(|per_task| per_task.agg_job_checkpoints.values().cloned())
+            .collect())
+    }
+
+    /// Lease at most `num_items` items from the work queue. The items are held until they are
+    /// acknowledged with [`Self::ack_work`]; if `visibility_timeout` is set and the items haven't
+    /// been acknowledged by then, [`Self::reclaim_expired_leases`] puts them back in the queue.
+    ///
+    /// If `max_agg_jobs_in_flight` is non-zero, then aggregation jobs for a task that already has
+    /// that many jobs in flight are left in the queue, along with any work queued after them for
+    /// the same task. An aggregation job that is backing off before a retry is left in the queue
+    /// on its own, without holding up the rest of the task's work.
+    pub fn dequeue_work(
+        &mut self,
+        num_items: usize,
+        max_agg_jobs_in_flight: usize,
+        now: Time,
+        visibility_timeout: Duration,
+    ) -> Result<Vec<(WorkItemId, WorkItem)>, DapError> {
         let mut work_items = Vec::with_capacity(num_items);
 
         // Drain the work queue for each task, in an arbitrary order. Note that a production
         // Leader would likely need to handle tasks in some priority order, e.g., drain the
         // oldest tasks first.
-        let n = std::cmp::min(self.work_queue.len(), num_items);
-        work_items.extend(self.work_queue.drain(..n));
+        let mut deferred = VecDeque::new();
+        let mut blocked_tasks = HashSet::new();
+        while work_items.len() < num_items {
+            let Some(work_item) = self.work_queue.pop_front() else {
+                break;
+            };
+            let task_id = *work_item.task_id();
+
+            // Work for a task must be processed in order, so once one item is deferred, so is
+            // everything after it.
+            if blocked_tasks.contains(&task_id) {
+                deferred.push_back(work_item);
+                continue;
+            }
+
+            if let WorkItem::AggregationJob { not_before, .. } = &work_item {
+                // A job that is backing off is skipped. A collection job queued after it doesn't
+                // need to wait, since the job counts as unaggregated until it completes.
+                if not_before.is_some_and(|not_before| not_before > now) {
+                    deferred.push_back(work_item);
+                    continue;
+                }
+                let per_task = self.per_task.entry(task_id).or_default();
+                if max_agg_jobs_in_flight > 0
+                    && per_task.agg_jobs_in_flight >= max_agg_jobs_in_flight
+                {
+                    blocked_tasks.insert(task_id);
+                    deferred.push_back(work_item);
+                    continue;
+                }
+                per_task.agg_jobs_in_flight += 1;
+            }
+
+            let item_id = WorkItemId(self.next_work_item_id);
+            self.next_work_item_id += 1;
+            self.leased_work
+                .insert(item_id, (now + visibility_timeout, work_item.clone()));
+            work_items.push((item_id, work_item));
+        }
+
+        // Put deferred work back at the front of the queue in its original order.
+        while let Some(work_item) = deferred.pop_back() {
+            self.work_queue.push_front(work_item);
+        }
         Ok(work_items)
     }
 
+    /// Delete leased work items that have been processed. Acknowledging an aggregation job frees
+    /// up a slot for the task's next job. Items whose lease already expired have been put back in
+    /// the queue and are not affected.
+    pub fn ack_work(&mut self, item_ids: &[WorkItemId]) -> Result<(), DapError> {
+        for item_id in item_ids {
+            if let Some((_expiration, work_item)) = self.leased_work.remove(item_id) {
+                self.release_lease(&work_item);
+            }
+        }
+        Ok(())
+    }
+
+    /// Put work items whose lease has expired back at the front of the queue in the order in which
+    /// they were dequeued.
+    ///
+    /// The process that leased a reclaimed aggregation job may have already sent it to the Helper,
+    /// so the job is redelivered under [`MetaAggregationJobId::for_retry`]. If that's a new ID, then
+    /// the old job is marked as failed. A job that was completed in the meantime, e.g., by
+    /// [`resume_agg_jobs`](crate::roles::leader::resume_agg_jobs), is dropped instead, as running
+    /// it again would commit its reports twice.
+    pub fn reclaim_expired_leases(&mut self, now: Time) -> Result<(), DapError> {
+        let expired = self
+            .leased_work
+            .iter()
+            .filter(|(_item_id, (expiration, _work_item))| *expiration <= now)
+            .map(|(item_id, _)| *item_id)
+            .collect::<Vec<_>>();
+
+        for item_id in expired.into_iter().rev() {
+            let Some((_expiration, mut work_item)) = self.leased_work.remove(&item_id) else {
+                continue;
+            };
+            self.release_lease(&work_item);
+            if let WorkItem::AggregationJob {
+                task_id,
+                agg_job_id,
+                ..
+            } = &mut work_item
+            {
+                let per_task = self.per_task.entry(*task_id).or_default();
+                if matches!(
+                    per_task.agg_job_status.get(agg_job_id),
+                    Some(DapAggregationJobStatus::Completed)
+                ) {
+                    continue;
+                }
+                let retry_agg_job_id = agg_job_id.for_retry();
+                if retry_agg_job_id != *agg_job_id {
+                    self.replace_agg_job_id(task_id, agg_job_id, &retry_agg_job_id)?;
+                    *agg_job_id = retry_agg_job_id;
+                }
+                self.set_agg_job_status(task_id, agg_job_id, DapAggregationJobStatus::Queued)?;
+            }
+            self.work_queue.push_front(work_item);
+        }
+        Ok(())
+    }
+
+    /// Mark an aggregation job as failed because it is retried under a new ID. The collection jobs
+    /// that queued the job re-queue the retry instead if they are retried themselves.
+    pub fn replace_agg_job_id(
+        &mut self,
+        task_id: &TaskId,
+        agg_job_id: &MetaAggregationJobId,
+        retry_agg_job_id: &MetaAggregationJobId,
+    ) -> Result<(), DapError> {
+        let per_task = self.per_task.entry(*task_id).or_default();
+        per_task
+            .agg_job_status
+            .set(*agg_job_id, DapAggregationJobStatus::Failed);
+        for work_item in per_task.coll_job_work.values_mut().flatten() {
+            if let WorkItem::AggregationJob {
+                agg_job_id: queued_agg_job_id,
+                ..
+            } = work_item
+            {
+                if queued_agg_job_id == agg_job_id {
+                    *queued_agg_job_id = *retry_agg_job_id;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Account for a lease that ended, either because the item was acknowledged or because the
+    /// lease expired. An aggregation job no longer counts towards the task's jobs in flight.
+    fn release_lease(&mut self, work_item: &WorkItem) {
+        if let WorkItem::AggregationJob { task_id, .. } = work_item {
+            let per_task = self.per_task.entry(*task_id).or_default();
+            per_task.agg_jobs_in_flight = per_task.agg_jobs_in_flight.saturating_sub(1);
+        }
+    }
+
+    /// Initialize a collection job and queue aggregation jobs for the pending reports in its
+    /// batch. The aggregation jobs may be retried `agg_job_retry_budget` times and carry at most
+    /// `max_report_shares_per_agg_job` reports each (see [`DapGlobalConfig`]).
+    #[allow(clippy::too_many_arguments)]
     pub fn init_collect_job(
         &mut self,
         task_id: &TaskId,
         task_config: &DapTaskConfig,
         coll_job_id: &Option<CollectionJobId>,
-        batch_sel: BatchSelector,
+        query: Query,
         agg_param: DapAggregationParam,
+        collector_hpke_config_id: Option<u8>,
+        global_config: &DapGlobalConfig,
     ) -> Result<Url, DapError> {
-        let per_task = self.per_task.entry(*task_id).or_default();
-
         // Construct the collection URI for this collection job.
         let coll_job_id = (*coll_job_id).unwrap_or(CollectionJobId(thread_rng().gen()));
         let coll_job_uri = task_config
@@ -671,28 +1290,68 @@ impl MockLeaderMemory {
             ))
             .map_err(|e| fatal_error!(err = ?e))?;
 
-        // Store the collection job in the pending state.
-        if per_task.coll_jobs.get(&coll_job_id).is_some() {
-            return Err(DapError::Abort(DapAbort::BadRequest(format!(
-                "tried to overwrite collection job {}",
+        // A repeated request for an existing collection job is treated as a retry, as long as it
+        // has the same parameters.
+        let per_task = self.per_task.entry(*task_id).or_default();
+        if per_task.coll_jobs.contains_key(&coll_job_id) {
+            if per_task.coll_job_params.get(&coll_job_id)
+                == Some(&(query, agg_param, collector_hpke_config_id))
+            {
+                return Ok(coll_job_uri);
+            }
+            return Err(DapError::Abort(DapAbort::Conflict(format!(
+                "collection job {} already exists with different parameters",
                 coll_job_id.to_base64url()
             ))));
         }
 
+        let batch_sel = match query {
+            Query::TimeInterval { ref batch_interval } => BatchSelector::TimeInterval {
+                batch_interval: batch_interval.clone(),
+            },
+            Query::FixedSizeByBatchId { batch_id } => {
+                BatchSelector::FixedSizeByBatchId { batch_id }
+            }
+            Query::FixedSizeCurrentBatch => BatchSelector::FixedSizeByBatchId {
+                batch_id: self.reserve_current_batch(task_id, task_config)?,
+            },
+        };
+
+        // Store the collection job in the pending state.
+        let per_task = self.per_task.entry(*task_id).or_default();
+        per_task.coll_job_params.insert(
+            coll_job_id,
+            (query, agg_param.clone(), collector_hpke_config_id),
+        );
+
         per_task
             .coll_jobs
             .insert(coll_job_id, DapCollectionJob::Pending);
 
-        // Fill the work queue. Queue an aggregation job for each bucket of pending reports
-        // incident to the collection job.
+        // Fill the work queue. Queue an aggregation job for each shard of each bucket of pending
+        // reports incident to the collection job.
+        let mut work = Vec::new();
+        let mut queued_buckets = HashSet::new();
         for bucket in task_config.batch_span_for_sel(&batch_sel)? {
-            if let Some(reports) = per_task.pending_reports.remove(&bucket) {
-                self.work_queue.push_back(WorkItem::AggregationJob {
-                    task_id: *task_id,
-                    part_batch_sel: batch_sel.clone().into(),
-                    agg_param: agg_param.clone(),
-                    reports: reports.into(),
-                });
+            if let Some(shards) = per_task.pending_reports.remove(&bucket) {
+                for reports in shards.into_values().flat_map(|reports| {
+                    split_agg_job(reports, global_config.max_report_shares_per_agg_job)
+                }) {
+                    let agg_job_id = MetaAggregationJobId::gen_for_version(task_config.version);
+                    work.push(WorkItem::AggregationJob {
+                        task_id: *task_id,
+                        agg_job_id,
+                        part_batch_sel: batch_sel.clone().into(),
+                        agg_param: agg_param.clone(),
+                        reports,
+                        retries_remaining: global_config.agg_job_retry_budget,
+                        not_before: None,
+                    });
+                    per_task
+                        .agg_job_status
+                        .set(agg_job_id, DapAggregationJobStatus::Queued);
+                }
+                queued_buckets.insert(bucket.clone());
             }
 
             // The batch will be collected, so remove it from the batch queue.
@@ -703,118 +1362,409 @@ impl MockLeaderMemory {
             }
         }
 
+        per_task
+            .coll_job_buckets
+            .insert(coll_job_id, queued_buckets);
+
         // Queue processing of the collection job.
-        self.work_queue.push_back(WorkItem::CollectionJob {
+        work.push(WorkItem::CollectionJob {
             task_id: *task_id,
             coll_job_id,
             batch_sel,
             agg_param,
+            collector_hpke_config_id,
+            requeues: 0,
         });
 
-        Ok(coll_job_uri)
-    }
+        // Keep a copy of the work so that it can be re-queued if processing fails.
+        self.work_queue.extend(work.iter().cloned());
+        per_task.coll_job_work.insert(coll_job_id, work);
 
-    pub fn poll_collect_job(
-        &self,
-        task_id: &TaskId,
-        coll_job_id: &CollectionJobId,
-    ) -> Result<DapCollectionJob, DapError> {
-        if let Some(per_task) = self.per_task.get(task_id) {
-            Ok(per_task
-                .coll_jobs
-                .get(coll_job_id)
-                .cloned()
-                .unwrap_or(DapCollectionJob::Unknown))
-        } else {
-            Err(DapError::Abort(DapAbort::UnrecognizedTask))
-        }
+        Ok(coll_job_uri)
     }
 
-    pub fn finish_collect_job(
+    pub fn retry_collect_job(
         &mut self,
         task_id: &TaskId,
         coll_job_id: &CollectionJobId,
-        collection: &Collection,
     ) -> Result<(), DapError> {
         let Some(per_task) = self.per_task.get_mut(task_id) else {
-            return Err(fatal_error!(err = "collect job not found for task_id", %task_id));
+            return Err(DapError::Abort(DapAbort::UnrecognizedTask));
         };
 
-        let Some(coll_job) = per_task.coll_jobs.get_mut(coll_job_id) else {
-            return Err(fatal_error!(err = "collect job not found for collect_id", %task_id))?;
+        match per_task.coll_jobs.get(coll_job_id) {
+            Some(DapCollectionJob::Pending) => (),
+            Some(DapCollectionJob::Done(_)) => return Ok(()),
+            Some(DapCollectionJob::Unknown) | None => {
+                return Err(DapError::Abort(DapAbort::BadRequest(format!(
+                    "tried to retry unknown collection job {}",
+                    coll_job_id.to_base64url()
+                ))))
+            }
+        }
+
+        let Some(work) = per_task.coll_job_work.get_mut(coll_job_id) else {
+            return Err(fatal_error!(
+                err = "work not found for pending collection job"
+            ));
+        };
+
+        // Work that is queued or leased will be run without being retried. A leased item that
+        // doesn't get acknowledged is put back in the queue once its lease expires.
+        let pending_work = self
+            .work_queue
+            .iter()
+            .chain(
+                self.leased_work
+                    .values()
+                    .map(|(_expiration, work_item)| work_item),
+            )
+            .collect::<Vec<_>>();
+        let pending_agg_job_ids = pending_work
+            .iter()
+            .filter_map(|work_item| match work_item {
+                WorkItem::AggregationJob { agg_job_id, .. } => Some(*agg_job_id),
+                WorkItem::CollectionJob { .. } => None,
+            })
+            .collect::<HashSet<_>>();
+        let coll_job_leased = self.leased_work.values().any(|(_expiration, work_item)| {
+            matches!(work_item, WorkItem::CollectionJob { coll_job_id: leased_id, .. }
+                if leased_id == coll_job_id)
+        });
+
+        // Re-queue the aggregation jobs that neither completed nor are still pending.
+        for work_item in work.iter_mut() {
+            let WorkItem::AggregationJob { agg_job_id, .. } = work_item else {
+                continue;
+            };
+            if pending_agg_job_ids.contains(agg_job_id)
+                || per_task.agg_job_status.get(agg_job_id)
+                    == Some(&DapAggregationJobStatus::Completed)
+            {
+                continue;
+            }
+
+            // The Helper may have committed the job even though the Leader didn't, so the job is
+            // retried under the same ID where the protocol allows it.
+            *agg_job_id = agg_job_id.for_retry();
+            per_task
+                .agg_job_status
+                .set(*agg_job_id, DapAggregationJobStatus::Queued);
+            self.work_queue.push_back(work_item.clone());
+        }
+
+        // Move the collection job behind the aggregation jobs it depends on, unless it is
+        // already being run.
+        if coll_job_leased {
+            return Ok(());
+        }
+        self.work_queue.retain(|work_item| {
+            !matches!(work_item, WorkItem::CollectionJob { coll_job_id: queued_id, .. }
+                if queued_id == coll_job_id)
+        });
+        if let Some(coll_job) = work
+            .iter()
+            .find(|work_item| matches!(work_item, WorkItem::CollectionJob { .. }))
+        {
+            self.work_queue.push_back(coll_job.clone());
+        }
+
+        Ok(())
+    }
+
+    pub fn poll_collect_job(
+        &self,
+        task_id: &TaskId,
+        coll_job_id: &CollectionJobId,
+    ) -> Result<DapCollectionJob, DapError> {
+        // A task for which nothing has been stored yet has no collection jobs.
+        Ok(self
+            .per_task
+            .get(task_id)
+            .and_then(|per_task| per_task.coll_jobs.get(coll_job_id))
+            .cloned()
+            .unwrap_or(DapCollectionJob::Unknown))
+    }
+
+    pub fn list_collection_jobs(
+        &self,
+        task_id: &TaskId,
+        task_config: &DapTaskConfig,
+    ) -> Result<Vec<(CollectionJobId, DapCollectionJobInfo)>, DapError> {
+        let Some(per_task) = self.per_task.get(task_id) else {
+            return Ok(Vec::new());
+        };
+
+        // Find the buckets of this task for which an aggregation job is still queued.
+        let queued_buckets = self
+            .work_queue
+            .iter()
+            .filter_map(|work_item| match work_item {
+                WorkItem::AggregationJob {
+                    task_id: queued_task_id,
+                    part_batch_sel,
+                    reports,
+                    ..
+                } if queued_task_id == task_id => {
+                    reports.first().map(|report| match part_batch_sel {
+                        PartialBatchSelector::TimeInterval => DapBatchBucket::TimeInterval {
+                            batch_window: task_config
+                                .quantized_time_lower_bound(report.report_metadata.time),
+                        },
+                        PartialBatchSelector::FixedSizeByBatchId { batch_id } => {
+                            DapBatchBucket::FixedSize {
+                                batch_id: *batch_id,
+                            }
+                        }
+                    })
+                }
+                _ => None,
+            })
+            .collect::<HashSet<_>>();
+
+        Ok(per_task
+            .coll_jobs
+            .iter()
+            .map(|(coll_job_id, status)| {
+                let queued_buckets = matches!(status, DapCollectionJob::Pending).then(|| {
+                    per_task
+                        .coll_job_buckets
+                        .get(coll_job_id)
+                        .map_or(0, |buckets| {
+                            queued_buckets
+                                .iter()
+                                .filter(|bucket| buckets.contains(bucket))
+                                .count()
+                        })
+                });
+
+                // The batch is recorded in the collection once the job is done and in the queued
+                // work until then.
+                let batch_id = match status {
+                    DapCollectionJob::Done(collection) => match &collection.part_batch_sel {
+                        PartialBatchSelector::FixedSizeByBatchId { batch_id } => Some(batch_id),
+                        PartialBatchSelector::TimeInterval => None,
+                    },
+                    DapCollectionJob::Pending | DapCollectionJob::Unknown => per_task
+                        .coll_job_work
+                        .get(coll_job_id)
+                        .into_iter()
+                        .flatten()
+                        .find_map(|work_item| match work_item {
+                            WorkItem::CollectionJob {
+                                batch_sel: BatchSelector::FixedSizeByBatchId { batch_id },
+                                ..
+                            } => Some(batch_id),
+                            _ => None,
+                        }),
+                };
+                let batch_time_range = per_task
+                    .coll_job_time_ranges
+                    .get(coll_job_id)
+                    .cloned()
+                    .or_else(|| batch_id.and_then(|batch_id| per_task.batch_time_range(batch_id)));
+                (
+                    *coll_job_id,
+                    DapCollectionJobInfo {
+                        status: status.clone(),
+                        queued_buckets,
+                        batch_time_range,
+                    },
+                )
+            })
+            .collect())
+    }
+
+    pub fn finish_collect_job(
+        &mut self,
+        task_id: &TaskId,
+        coll_job_id: &CollectionJobId,
+        collection: &Collection,
+    ) -> Result<(), DapError> {
+        let Some(per_task) = self.per_task.get_mut(task_id) else {
+            return Err(fatal_error!(err = "collect job not found for task_id", %task_id));
+        };
+
+        let Some(coll_job) = per_task.coll_jobs.get_mut(coll_job_id) else {
+            return Err(fatal_error!(err = "collect job not found for collect_id", %task_id))?;
         };
 
         match coll_job {
             DapCollectionJob::Pending => {
                 // Mark collection job as complete.
                 *coll_job = DapCollectionJob::Done(collection.clone());
-                Ok(())
+                per_task.coll_job_buckets.remove(coll_job_id);
+                per_task.coll_job_work.remove(coll_job_id);
+            }
+            DapCollectionJob::Done(_) => {
+                return Err(fatal_error!(
+                    err = "tried to overwrite completed collection job"
+                ))
+            }
+            DapCollectionJob::Unknown => {
+                return Err(fatal_error!(
+                    err = "tried to overwrite collection job in unkonwn state"
+                ))
+            }
+        }
+
+        // The batch won't receive any more reports, so its time range is kept only for the
+        // collection job.
+        if let PartialBatchSelector::FixedSizeByBatchId { batch_id } = &collection.part_batch_sel {
+            if let Some(batch_time_range) = per_task.batch_time_range(batch_id) {
+                per_task
+                    .coll_job_time_ranges
+                    .insert(*coll_job_id, batch_time_range);
+            }
+            per_task.batch_time_ranges.remove(batch_id);
+        }
+        Ok(())
+    }
+}
+
+/// The number of finished aggregation jobs per task whose status is remembered. Once exceeded, the
+/// status of the job that finished first is forgotten.
+pub(crate) const MAX_FINISHED_AGG_JOBS_PER_TASK: usize = 1024;
+
+/// The status of each aggregation job of a task.
+#[derive(Default)]
+struct AggJobStatusStore {
+    status: HashMap<MetaAggregationJobId, DapAggregationJobStatus>,
+    finished: VecDeque<MetaAggregationJobId>, // In the order they finished, for eviction
+}
+
+impl AggJobStatusStore {
+    fn is_finished(status: Option<&DapAggregationJobStatus>) -> bool {
+        matches!(
+            status,
+            Some(DapAggregationJobStatus::Completed | DapAggregationJobStatus::Failed)
+        )
+    }
+
+    fn get(&self, agg_job_id: &MetaAggregationJobId) -> Option<&DapAggregationJobStatus> {
+        self.status.get(agg_job_id)
+    }
+
+    fn set(&mut self, agg_job_id: MetaAggregationJobId, status: DapAggregationJobStatus) {
+        self.status.insert(agg_job_id, status);
+        if !Self::is_finished(Some(&status)) {
+            return;
+        }
+        self.finished.push_back(agg_job_id);
+        while self.finished.len() > MAX_FINISHED_AGG_JOBS_PER_TASK {
+            let evicted = self.finished.pop_front().unwrap();
+            // The job may have been re-queued since it finished.
+            if Self::is_finished(self.status.get(&evicted)) {
+                self.status.remove(&evicted);
             }
-            DapCollectionJob::Done(_) => Err(fatal_error!(
-                err = "tried to overwrite completed collection job"
-            )),
-            DapCollectionJob::Unknown => Err(fatal_error!(
-                err = "tried to overwrite collection job in unkonwn state"
-            )),
         }
     }
 }
 
 #[derive(Default)]
 struct MockLeaderMemoryPerTask {
-    pending_reports: HashMap<DapBatchBucket, VecDeque<Report>>,
+    pending_reports: HashMap<DapBatchBucket, BTreeMap<u32, VecDeque<Report>>>, // Reports by shard
     coll_jobs: HashMap<CollectionJobId, DapCollectionJob>,
-    batch_queue: VecDeque<(BatchId, u64)>, // Batch ID, batch size
+    coll_job_params: HashMap<CollectionJobId, (Query, DapAggregationParam, Option<u8>)>, // For retries
+    coll_job_buckets: HashMap<CollectionJobId, HashSet<DapBatchBucket>>, // Buckets queued per job
+    coll_job_work: HashMap<CollectionJobId, Vec<WorkItem>>, // Work queued per job, for retries
+    batch_queue: VecDeque<(BatchId, u64)>,                  // Batch ID, batch size
+    reserved_batches: HashSet<BatchId>, // Batches removed from the queue for collection
+    agg_job_status: AggJobStatusStore,
+    agg_job_checkpoints: HashMap<MetaAggregationJobId, AggregationJobCheckpoint>,
+    agg_jobs_in_flight: usize, // Aggregation jobs dequeued but not yet completed
+    batch_time_ranges: HashMap<BatchId, (Time, Time)>, // Min and max report time per batch
+    coll_job_time_ranges: HashMap<CollectionJobId, Interval>, // Time range of collected batches
 }
 
 impl MockLeaderMemoryPerTask {
+    fn batch_time_range(&self, batch_id: &BatchId) -> Option<Interval> {
+        let (min_time, max_time) = self.batch_time_ranges.get(batch_id)?;
+        Some(Interval {
+            start: *min_time,
+            duration: max_time - min_time + 1,
+        })
+    }
+
     fn assign_report_to_bucket(
         &mut self,
         task_config: &DapTaskConfig,
         report: &Report,
     ) -> DapBatchBucket {
-        let mut rng = thread_rng();
-        match task_config.query {
-            // For fixed-size queries, the bucket corresponds to a single batch.
-            DapQueryConfig::FixedSize { .. } => {
-                // Assign the report to the first unsaturated batch.
-                for (batch_id, report_count) in &mut self.batch_queue {
-                    if *report_count < task_config.min_batch_size {
-                        *report_count += 1;
-                        return DapBatchBucket::FixedSize {
-                            batch_id: *batch_id,
-                        };
-                    }
-                }
+        // For time-interval queries, the bucket is determined by the report's timestamp.
+        if let Some(bucket) = task_config.bucket_for_report(report) {
+            return bucket;
+        }
 
-                // No unsaturated batch exists, so create a new batch.
-                let batch_id = BatchId(rng.gen());
-                self.batch_queue.push_back((batch_id, 1));
-                DapBatchBucket::FixedSize { batch_id }
-            }
+        // For fixed-size queries, the bucket corresponds to a single batch. Assign the report to
+        // the first unsaturated batch. If no unsaturated batch exists, then create a new batch.
+        let batch_id = if let Some((batch_id, report_count)) =
+            self.batch_queue
+                .iter_mut()
+                .find(|(_batch_id, report_count)| {
+                    *report_count < task_config.effective_min_batch_size()
+                }) {
+            *report_count += 1;
+            *batch_id
+        } else {
+            let batch_id = BatchId(thread_rng().gen());
+            self.batch_queue.push_back((batch_id, 1));
+            batch_id
+        };
 
-            // For time-interval queries, the bucket is the batch window computed by truncating the
-            // report timestamp.
-            DapQueryConfig::TimeInterval => DapBatchBucket::TimeInterval {
-                batch_window: task_config.quantized_time_lower_bound(report.report_metadata.time),
-            },
-        }
+        let time = report.report_metadata.time;
+        self.batch_time_ranges
+            .entry(batch_id)
+            .and_modify(|(min_time, max_time)| {
+                *min_time = (*min_time).min(time);
+                *max_time = (*max_time).max(time);
+            })
+            .or_insert((time, time));
+        DapBatchBucket::FixedSize { batch_id }
     }
 }
 
+/// An HPKE receiver config held by [`MockAggregator`], along with the metadata used to rotate it.
+#[derive(Clone)]
+#[cfg_attr(any(test, feature = "test-utils"), derive(deepsize::DeepSizeOf))]
+pub struct MockHpkeReceiverConfig {
+    pub receiver: HpkeReceiverConfig,
+
+    /// Whether the config may be advertised to Clients. Retired configs are no longer advertised,
+    /// but reports encrypted under them can still be decrypted.
+    pub active: bool,
+
+    /// When the config was added.
+    pub created_at: Time,
+}
+
 pub struct MockAggregator {
     pub global_config: DapGlobalConfig,
     pub(crate) tasks: Arc<Mutex<HashMap<TaskId, DapTaskConfig>>>,
-    pub hpke_receiver_config_list: Vec<HpkeReceiverConfig>,
+    pub hpke_receiver_config_list: Arc<Mutex<Vec<MockHpkeReceiverConfig>>>,
     pub leader_token: BearerToken,
     pub collector_token: Option<BearerToken>, // Not set by Helper
-    pub(crate) report_store: Arc<Mutex<HashMap<TaskId, HashSet<ReportId>>>>,
+    pub(crate) report_store: Arc<Mutex<ReportStore>>,
     pub(crate) leader_state_store: Arc<Mutex<MockLeaderMemory>>,
-    pub(crate) helper_state_store: Arc<Mutex<HashMap<HelperStateInfo, DapAggregationJobState>>>,
-    pub(crate) agg_store: Arc<Mutex<HashMap<TaskId, HashMap<DapBatchBucket, AggStore>>>>,
+    /// Helper state, along with the time at which it was stored.
+    pub(crate) helper_state_store:
+        Arc<Mutex<HashMap<HelperStateInfo, (DapAggregationJobState, Time)>>>,
+    /// Encoded aggregation job responses that are sent to the Leader in chunks.
+    pub(crate) agg_job_resp_store: Arc<Mutex<HashMap<HelperStateInfo, Vec<u8>>>>,
+    pub(crate) agg_store: Arc<Mutex<HashMap<TaskId, AggStorePerTask>>>,
+    /// Aggregation parameter of the first aggregation job for each bucket. Only recorded for VDAFs
+    /// that aggregate each batch under a single aggregation parameter.
+    pub(crate) bucket_agg_param_store:
+        Arc<Mutex<HashMap<(TaskId, DapBatchBucket), DapAggregationParam>>>,
+    pub(crate) retained_agg_share_store: Arc<Mutex<RetainedAggShareStore>>,
     pub collector_hpke_config: HpkeConfig,
     pub metrics: DaphnePromMetrics,
     pub(crate) audit_log: MockAuditLog,
+    pub(crate) clock: Arc<dyn Clock>,
+    pub(crate) upload_dedup_cache: UploadDedupCache,
+    /// The report storage epoch duration and maximum future time skew, if set. See
+    /// [`MockAggregator::with_report_storage_bounds`].
+    pub(crate) report_storage_bounds: Option<(Duration, Duration)>,
 
     // taskprov
     pub taskprov_vdaf_verify_key_init: [u8; 32],
@@ -824,6 +1774,14 @@ pub struct MockAggregator {
     // Leader: Reference to peer. Used to simulate HTTP requests from Leader to Helper, i.e.,
     // implement `DapLeader::send_http_post()` for `MockAggregator`. Not set by the Helper.
     pub peer: Option<Arc<MockAggregator>>,
+
+    // Leader: Number of upcoming requests to the peer that fail without reaching it. See
+    // `MockAggregator::fail_next_peer_requests()`.
+    pub(crate) peer_failures: AtomicUsize,
+
+    // Leader: If set, requests to the peer never complete and each one is reported on this
+    // channel instead. See `MockAggregator::park_peer_requests()`.
+    pub(crate) peer_parking: Mutex<Option<mpsc::UnboundedSender<()>>>,
 }
 
 impl DeepSizeOf for MockAggregator {
@@ -837,7 +1795,10 @@ impl DeepSizeOf for MockAggregator {
                 + self.collector_token.deep_size_of_children(context)
                 + self.report_store.deep_size_of_children(context)
                 + self.helper_state_store.deep_size_of_children(context)
+                + self.agg_job_resp_store.deep_size_of_children(context)
                 + self.agg_store.deep_size_of_children(context)
+                + self.bucket_agg_param_store.deep_size_of_children(context)
+                + self.retained_agg_share_store.deep_size_of_children(context)
                 + self.collector_hpke_config.deep_size_of_children(context)
                 // + self.metrics.deep_size_of_children(context)
                 // + self.audit_log.deep_size_of_children(context)
@@ -850,7 +1811,20 @@ impl DeepSizeOf for MockAggregator {
     }
 }
 
+/// The in-memory footprint of an aggregator in bytes, broken down by component. This is meant for
+/// watching memory growth under sustained load. Test servers report it at `/internal/debug/memory`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct MockMemoryUsage {
+    pub tasks: usize,
+    pub report_store: usize,
+    pub agg_store: usize,
+    pub helper_state_store: usize,
+    /// The footprint of the entire aggregator, including the components above.
+    pub total: usize,
+}
+
 impl MockAggregator {
+    /// Create a Helper. Returns an error if two of the HPKE receiver configs have the same ID.
     #[allow(clippy::too_many_arguments)]
     pub fn new_helper(
         tasks: impl IntoIterator<Item = (TaskId, DapTaskConfig)>,
@@ -861,27 +1835,41 @@ impl MockAggregator {
         registry: &prometheus::Registry,
         taskprov_vdaf_verify_key_init: [u8; 32],
         taskprov_leader_token: BearerToken,
-    ) -> Self {
-        Self {
+    ) -> Result<Self, DapError> {
+        let clock = Arc::new(SystemClock);
+        Ok(Self {
             global_config,
             tasks: Arc::new(Mutex::new(tasks.into_iter().collect())),
-            hpke_receiver_config_list: hpke_receiver_config_list.into_iter().collect(),
+            hpke_receiver_config_list: Self::hpke_receiver_config_list_at(
+                hpke_receiver_config_list,
+                clock.now(),
+            )?,
             leader_token,
             collector_token: None,
             report_store: Default::default(),
             leader_state_store: Default::default(),
             helper_state_store: Default::default(),
+            agg_job_resp_store: Default::default(),
             agg_store: Default::default(),
+            bucket_agg_param_store: Default::default(),
+            retained_agg_share_store: Default::default(),
             collector_hpke_config,
             metrics: DaphnePromMetrics::register(registry).unwrap(),
             audit_log: MockAuditLog::default(),
+            clock,
+            upload_dedup_cache: UploadDedupCache::new(0),
+            report_storage_bounds: None,
             taskprov_vdaf_verify_key_init,
             taskprov_leader_token,
             taskprov_collector_token: None,
             peer: None,
-        }
+            peer_failures: AtomicUsize::new(0),
+            peer_parking: Mutex::new(None),
+        })
     }
 
+    /// Create a Leader whose peer is `peer`. Returns an error if two of the HPKE receiver configs
+    /// have the same ID.
     #[allow(clippy::too_many_arguments)]
     pub fn new_leader(
         tasks: impl IntoIterator<Item = (TaskId, DapTaskConfig)>,
@@ -895,27 +1883,170 @@ impl MockAggregator {
         taskprov_leader_token: BearerToken,
         taskprov_collector_token: impl Into<Option<BearerToken>>,
         peer: impl Into<Option<Arc<Self>>>,
-    ) -> Self {
-        Self {
+    ) -> Result<Self, DapError> {
+        let upload_dedup_cache = UploadDedupCache::new(global_config.upload_dedup_cache_capacity);
+        let clock = Arc::new(SystemClock);
+        Ok(Self {
             global_config,
             tasks: Arc::new(Mutex::new(tasks.into_iter().collect())),
-            hpke_receiver_config_list: hpke_receiver_config_list.into_iter().collect(),
+            hpke_receiver_config_list: Self::hpke_receiver_config_list_at(
+                hpke_receiver_config_list,
+                clock.now(),
+            )?,
             leader_token,
             collector_token: collector_token.into(),
             report_store: Default::default(),
             leader_state_store: Default::default(),
             helper_state_store: Default::default(),
+            agg_job_resp_store: Default::default(),
             agg_store: Default::default(),
+            bucket_agg_param_store: Default::default(),
+            retained_agg_share_store: Default::default(),
             collector_hpke_config,
             metrics: DaphnePromMetrics::register(registry).unwrap(),
             audit_log: MockAuditLog::default(),
+            clock,
+            upload_dedup_cache,
+            report_storage_bounds: None,
             taskprov_vdaf_verify_key_init,
             taskprov_leader_token,
             taskprov_collector_token: taskprov_collector_token.into(),
             peer: peer.into(),
+            peer_failures: AtomicUsize::new(0),
+            peer_parking: Mutex::new(None),
+        })
+    }
+
+    /// Leader: Make the next `count` requests to the peer fail as if the peer could not be
+    /// reached.
+    pub fn fail_next_peer_requests(&self, count: usize) {
+        self.peer_failures.store(count, Ordering::Relaxed);
+    }
+
+    /// Consume one of the failures set by [`Self::fail_next_peer_requests`], if any remain.
+    fn inject_peer_failure(&self) -> Result<(), DapError> {
+        if self
+            .peer_failures
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1))
+            .is_ok()
+        {
+            return Err(PeerError::Unreachable("simulated failure".into()).into());
+        }
+        Ok(())
+    }
+
+    /// Leader: Make requests to the peer hang until the future sending them is dropped. The
+    /// returned stream yields once for every request that is parked. Dropping it doesn't unpark
+    /// requests; use [`Self::unpark_peer_requests`] for that.
+    pub fn park_peer_requests(&self) -> mpsc::UnboundedReceiver<()> {
+        let (sender, receiver) = mpsc::unbounded();
+        *self.peer_parking.lock().expect("peer_parking: lock failed") = Some(sender);
+        receiver
+    }
+
+    /// Leader: Undo [`Self::park_peer_requests`].
+    pub fn unpark_peer_requests(&self) {
+        *self.peer_parking.lock().expect("peer_parking: lock failed") = None;
+    }
+
+    /// Hang forever if requests to the peer are parked. See [`Self::park_peer_requests`].
+    async fn park_peer_request(&self) {
+        let parking = self
+            .peer_parking
+            .lock()
+            .expect("peer_parking: lock failed")
+            .clone();
+        if let Some(parking) = parking {
+            let _ = parking.unbounded_send(());
+            std::future::pending::<()>().await;
+        }
+    }
+
+    /// Measure the aggregator's memory usage. See [`MockMemoryUsage`].
+    pub fn memory_usage(&self) -> MockMemoryUsage {
+        MockMemoryUsage {
+            tasks: self.tasks.deep_size_of(),
+            report_store: self.report_store.deep_size_of(),
+            agg_store: self.agg_store.deep_size_of(),
+            helper_state_store: self.helper_state_store.deep_size_of(),
+            total: self.deep_size_of(),
         }
     }
 
+    /// Use `clock` as the source of the current time instead of the system clock.
+    #[must_use]
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> Self {
+        self.clock = clock;
+        self
+    }
+
+    /// Only accept reports whose timestamp is at most `epoch_duration` seconds in the past and at
+    /// most `max_future_time_skew` seconds in the future.
+    #[must_use]
+    pub fn with_report_storage_bounds(
+        mut self,
+        epoch_duration: Duration,
+        max_future_time_skew: Duration,
+    ) -> Self {
+        self.report_storage_bounds = Some((epoch_duration, max_future_time_skew));
+        self
+    }
+
+    fn hpke_receiver_config_list_at(
+        hpke_receiver_config_list: impl IntoIterator<Item = HpkeReceiverConfig>,
+        created_at: Time,
+    ) -> Result<Arc<Mutex<Vec<MockHpkeReceiverConfig>>>, DapError> {
+        let hpke_receiver_config_list = hpke_receiver_config_list.into_iter().collect::<Vec<_>>();
+        validate_hpke_receiver_config_list(&hpke_receiver_config_list)?;
+        Ok(Arc::new(Mutex::new(
+            hpke_receiver_config_list
+                .into_iter()
+                .map(|receiver| MockHpkeReceiverConfig {
+                    receiver,
+                    active: true,
+                    created_at,
+                })
+                .collect(),
+        )))
+    }
+
+    /// Add an HPKE receiver config. As it is the newest config, it is advertised to Clients from
+    /// now on; reports encrypted under the previous configs can still be decrypted. Returns an
+    /// error if the ID of the config is already in use.
+    pub fn rotate_hpke_receiver_config(
+        &self,
+        receiver: HpkeReceiverConfig,
+    ) -> Result<(), DapError> {
+        let created_at = self.get_current_time();
+        let mut hpke_receiver_config_list = self
+            .hpke_receiver_config_list
+            .lock()
+            .expect("hpke_receiver_config_list: failed to lock");
+        validate_hpke_receiver_config_list(
+            hpke_receiver_config_list
+                .iter()
+                .map(|config| &config.receiver)
+                .chain([&receiver]),
+        )?;
+        hpke_receiver_config_list.push(MockHpkeReceiverConfig {
+            receiver,
+            active: true,
+            created_at,
+        });
+        Ok(())
+    }
+
+    /// Stop advertising the HPKE receiver config with the given ID. Reports encrypted under it can
+    /// still be decrypted until it is removed from the list.
+    pub fn retire_hpke_receiver_config(&self, hpke_config_id: u8) {
+        self.hpke_receiver_config_list
+            .lock()
+            .expect("hpke_receiver_config_list: failed to lock")
+            .iter_mut()
+            .filter(|config| config.receiver.config.id == hpke_config_id)
+            .for_each(|config| config.active = false);
+    }
+
     fn is_leader(&self) -> bool {
         self.peer.is_some()
     }
@@ -926,22 +2057,33 @@ impl MockAggregator {
         &self,
         task_id: &TaskId,
         bucket: &DapBatchBucket,
+        agg_param: &DapAggregationParam,
     ) -> Option<TransitionFailure> {
         // Check AggStateStore to see whether the report is part of a batch that has already
-        // been collected.
+        // been collected under the same aggregation parameter.
         let mut guard = self.agg_store.lock().expect("agg_store: failed to lock");
         let agg_store = guard.entry(*task_id).or_default();
-        if matches!(agg_store.get(bucket), Some(inner_agg_store) if inner_agg_store.collected) {
+        if matches!(
+            agg_store.get(&(bucket.clone(), agg_param.clone())),
+            Some(inner_agg_store) if inner_agg_store.collected
+        ) {
             return Some(TransitionFailure::BatchCollected);
         }
 
         None
     }
 
-    fn get_hpke_receiver_config_for(&self, hpke_config_id: u8) -> Option<&HpkeReceiverConfig> {
+    fn get_hpke_receiver_config_for(&self, hpke_config_id: u8) -> Option<HpkeReceiverConfig> {
+        if self.global_config.is_hpke_config_revoked(hpke_config_id) {
+            return None;
+        }
+
         self.hpke_receiver_config_list
+            .lock()
+            .expect("hpke_receiver_config_list: failed to lock")
             .iter()
-            .find(|&hpke_receiver_config| hpke_config_id == hpke_receiver_config.config.id)
+            .find(|config| hpke_config_id == config.receiver.config.id)
+            .map(|config| config.receiver.clone())
     }
 
     pub(crate) async fn unchecked_get_task_config(&self, task_id: &TaskId) -> DapTaskConfig {
@@ -987,14 +2129,18 @@ impl BearerTokenProvider for MockAggregator {
 
 #[async_trait]
 impl HpkeDecrypter for MockAggregator {
-    type WrappedHpkeConfig<'a> = &'a HpkeConfig;
+    type WrappedHpkeConfig<'a> = HpkeConfig;
 
     async fn get_hpke_config_for<'s>(
         &'s self,
         _version: DapVersion,
         task_id: Option<&TaskId>,
     ) -> Result<Self::WrappedHpkeConfig<'s>, DapError> {
-        if self.hpke_receiver_config_list.is_empty() {
+        let hpke_receiver_config_list = self
+            .hpke_receiver_config_list
+            .lock()
+            .expect("hpke_receiver_config_list: failed to lock");
+        if hpke_receiver_config_list.is_empty() {
             return Err(fatal_error!(err = "empty HPKE receiver config list"));
         }
 
@@ -1007,8 +2153,22 @@ impl HpkeDecrypter for MockAggregator {
             return Err(DapError::Abort(DapAbort::MissingTaskId));
         }
 
-        // Always advertise the first HPKE config in the list.
-        Ok(&self.hpke_receiver_config_list[0].config)
+        // Advertise the newest active HPKE config that has not been revoked. Of configs created at
+        // the same time, the first one in the list is advertised.
+        hpke_receiver_config_list
+            .iter()
+            .rev()
+            .filter(|config| {
+                config.active
+                    && !self
+                        .global_config
+                        .is_hpke_config_revoked(config.receiver.config.id)
+            })
+            .max_by_key(|config| config.created_at)
+            .map(|config| config.receiver.config.clone())
+            .ok_or_else(|| {
+                fatal_error!(err = "all HPKE receiver configs have been retired or revoked")
+            })
     }
 
     async fn can_hpke_decrypt(&self, _task_id: &TaskId, config_id: u8) -> Result<bool, DapError> {
@@ -1022,12 +2182,20 @@ impl HpkeDecrypter for MockAggregator {
         aad: &[u8],
         ciphertext: &HpkeCiphertext,
     ) -> Result<Vec<u8>, DapError> {
-        if let Some(hpke_receiver_config) = self.get_hpke_receiver_config_for(ciphertext.config_id)
+        let res = if let Some(hpke_receiver_config) =
+            self.get_hpke_receiver_config_for(ciphertext.config_id)
         {
-            Ok(hpke_receiver_config.decrypt(info, aad, &ciphertext.enc, &ciphertext.payload)?)
+            hpke_receiver_config.decrypt(info, aad, &ciphertext.enc, &ciphertext.payload)
         } else {
             Err(DapError::Transition(TransitionFailure::HpkeUnknownConfigId))
+        };
+
+        if let Err(DapError::Transition(failure)) = &res {
+            let config_id = ciphertext.config_id.to_string();
+            tracing::warn!(hpke_config_id = config_id, %failure, "failed to decrypt input share");
+            self.metrics.hpke_decrypt_failure_inc(&config_id);
         }
+        res
     }
 }
 
@@ -1068,7 +2236,7 @@ impl DapReportInitializer for MockAggregator {
             for (id, _) in report_ids_and_time {
                 // Check whether Report has been collected or replayed.
                 if let Some(transition_failure) =
-                    self.check_report_has_been_collected(task_id, bucket)
+                    self.check_report_has_been_collected(task_id, bucket, agg_param)
                 {
                     early_fails.insert(*id, transition_failure);
                 };
@@ -1140,6 +2308,19 @@ impl DapAggregator<BearerToken> for MockAggregator {
         Ok(())
     }
 
+    async fn replace_task_config_if_compatible(
+        &self,
+        task_id: &TaskId,
+        task_config: DapTaskConfig,
+    ) -> Result<(), DapError> {
+        let mut tasks = self.tasks.lock().expect("tasks: lock failed");
+        if let Some(current) = tasks.get(task_id) {
+            current.check_compatible_update(task_id, &task_config)?;
+        }
+        tasks.insert(*task_id, task_config);
+        Ok(())
+    }
+
     async fn get_task_config_for<'req>(
         &'req self,
         task_id: &'req TaskId,
@@ -1149,17 +2330,23 @@ impl DapAggregator<BearerToken> for MockAggregator {
     }
 
     fn get_current_time(&self) -> Time {
-        SystemTime::now()
-            .duration_since(SystemTime::UNIX_EPOCH)
-            .unwrap()
-            .as_secs()
+        self.clock.now()
     }
 
-    async fn is_batch_overlapping(
+    fn valid_report_time_range(&self) -> RangeInclusive<Time> {
+        let Some((epoch_duration, max_future_time_skew)) = self.report_storage_bounds else {
+            return Time::MIN..=Time::MAX;
+        };
+        let now = self.get_current_time();
+        now.saturating_sub(epoch_duration)..=now.saturating_add(max_future_time_skew)
+    }
+
+    async fn get_overlapping_buckets(
         &self,
         task_id: &TaskId,
         batch_sel: &BatchSelector,
-    ) -> Result<bool, DapError> {
+        agg_param: &DapAggregationParam,
+    ) -> Result<Vec<DapBatchBucket>, DapError> {
         let task_config = self
             .get_task_config_for(task_id)
             .await?
@@ -1167,18 +2354,21 @@ impl DapAggregator<BearerToken> for MockAggregator {
 
         let agg_store = self.agg_store.lock().map_err(|e| fatal_error!(err = ?e))?;
         let Some(agg_store_per_task) = agg_store.get(task_id) else {
-            return Ok(false);
+            return Ok(Vec::new());
         };
 
-        for bucket in task_config.batch_span_for_sel(batch_sel)? {
-            if let Some(inner_agg_store) = agg_store_per_task.get(&bucket) {
+        let mut overlapping = Vec::new();
+        for bucket in task_config.batch_span_iter_for_sel(batch_sel)? {
+            if let Some(inner_agg_store) =
+                agg_store_per_task.get(&(bucket.clone(), agg_param.clone()))
+            {
                 if inner_agg_store.collected {
-                    return Ok(true);
+                    overlapping.push(bucket);
                 }
             }
         }
 
-        Ok(false)
+        Ok(overlapping)
     }
 
     async fn batch_exists(&self, task_id: &TaskId, batch_id: &BatchId) -> Result<bool, DapError> {
@@ -1188,10 +2378,11 @@ impl DapAggregator<BearerToken> for MockAggregator {
 
         let aggregated = {
             let agg_store_guard = self.agg_store.lock().map_err(|e| fatal_error!(err = ?e))?;
-            agg_store_guard
-                .get(task_id)
-                .map(|agg_store| agg_store.get(&bucket))
-                .is_some()
+            agg_store_guard.get(task_id).is_some_and(|agg_store| {
+                agg_store
+                    .keys()
+                    .any(|(aggregated_bucket, _agg_param)| *aggregated_bucket == bucket)
+            })
         };
 
         let uploaded = {
@@ -1203,29 +2394,81 @@ impl DapAggregator<BearerToken> for MockAggregator {
                 && leader_state
                     .per_task
                     .get(task_id)
-                    .map(|leader_state| {
+                    .is_some_and(|leader_state| {
                         leader_state
                             .batch_queue
                             .iter()
                             .any(|(queued_batch_id, _)| queued_batch_id == batch_id)
+                            || leader_state.reserved_batches.contains(batch_id)
                     })
-                    .is_some()
         };
 
         Ok(aggregated || uploaded)
     }
 
+    async fn batch_ready_for_collection(
+        &self,
+        task_id: &TaskId,
+        batch_sel: &BatchSelector,
+        agg_param: &DapAggregationParam,
+    ) -> Result<bool, DapError> {
+        let task_config = self
+            .get_task_config_for(task_id)
+            .await?
+            .ok_or(DapError::Abort(DapAbort::UnrecognizedTask))?;
+        let span = task_config.batch_span_for_sel(batch_sel)?;
+
+        Ok(!self
+            .leader_state_store
+            .lock()
+            .map_err(|e| fatal_error!(err = ?e))?
+            .has_unaggregated_reports(task_id, &task_config, &span, agg_param))
+    }
+
+    async fn preview_collection(
+        &self,
+        task_id: &TaskId,
+        batch_sel: &BatchSelector,
+    ) -> Result<CollectionPreview, DapError> {
+        let task_config = self
+            .get_task_config_for(task_id)
+            .await?
+            .ok_or(DapError::Abort(DapAbort::UnrecognizedTask))?;
+        let span = task_config.batch_span_for_sel(batch_sel)?;
+
+        let agg_store = self.agg_store.lock().map_err(|e| fatal_error!(err = ?e))?;
+        let mut preview = CollectionPreview {
+            bucket_count: span.len(),
+            ..Default::default()
+        };
+        if let Some(agg_store_per_task) = agg_store.get(task_id) {
+            for inner_agg_store in agg_store_per_task
+                .iter()
+                .filter(|((bucket, _agg_param), _)| span.contains(bucket))
+                .map(|(_, inner_agg_store)| inner_agg_store)
+            {
+                preview.report_count += inner_agg_store.agg_share.report_count;
+                preview.any_collected |= inner_agg_store.collected;
+            }
+        }
+
+        Ok(preview)
+    }
+
     async fn try_put_agg_share_span(
         &self,
         task_id: &TaskId,
         _task_config: &DapTaskConfig,
+        agg_param: &DapAggregationParam,
         agg_agg_span: DapAggregateSpan<DapAggregateShare>,
     ) -> DapAggregateSpan<Result<(), MergeAggShareError>> {
         let mut report_store_guard = self
             .report_store
             .lock()
             .expect("report_store: failed to lock");
-        let report_store = report_store_guard.entry(*task_id).or_default();
+        let report_store = report_store_guard
+            .entry((*task_id, agg_param.clone()))
+            .or_default();
         let mut agg_store_guard = self.agg_store.lock().expect("agg_store: failed to lock");
         let agg_store = agg_store_guard.entry(*task_id).or_default();
 
@@ -1241,7 +2484,9 @@ impl DapAggregator<BearerToken> for MockAggregator {
                 let result = if replayed.is_empty() {
                     report_store.extend(report_metadatas.iter().map(|(id, _)| *id));
                     // Add to aggregate share.
-                    let agg_share = agg_store.entry(bucket.clone()).or_default();
+                    let agg_share = agg_store
+                        .entry((bucket.clone(), agg_param.clone()))
+                        .or_default();
                     if agg_share.collected {
                         Err(MergeAggShareError::AlreadyCollected)
                     } else {
@@ -1249,6 +2494,11 @@ impl DapAggregator<BearerToken> for MockAggregator {
                             .agg_share
                             .merge(agg_share_delta.clone())
                             .map_err(MergeAggShareError::Other)
+                            .map(|()| {
+                                agg_share
+                                    .report_ids
+                                    .extend(report_metadatas.iter().map(|(id, _)| *id));
+                            })
                     }
                 } else {
                     Err(MergeAggShareError::ReplaysDetected(replayed))
@@ -1262,6 +2512,7 @@ impl DapAggregator<BearerToken> for MockAggregator {
         &self,
         task_id: &TaskId,
         batch_sel: &BatchSelector,
+        agg_param: &DapAggregationParam,
     ) -> Result<DapAggregateShare, DapError> {
         let task_config = self
             .get_task_config_for(task_id)
@@ -1271,17 +2522,32 @@ impl DapAggregator<BearerToken> for MockAggregator {
         let mut guard = self.agg_store.lock().expect("agg_store: failed to lock");
         let agg_store = guard.entry(*task_id).or_default();
 
-        // Fetch aggregate shares.
+        // Fetch aggregate shares. The buckets of the batch span are visited in order, not in the
+        // order of `agg_store`, so the result is reproducible.
         let mut agg_share = DapAggregateShare::default();
-        for bucket in task_config.batch_span_for_sel(batch_sel)? {
-            if let Some(inner_agg_store) = agg_store.get(&bucket) {
+        let mut aggregated = false;
+        for bucket in task_config.batch_span_iter_for_sel(batch_sel)? {
+            if let Some(inner_agg_store) = agg_store.get(&(bucket.clone(), agg_param.clone())) {
                 if inner_agg_store.collected {
-                    return Err(DapError::Abort(DapAbort::batch_overlap(task_id, batch_sel)));
+                    return Err(DapError::Abort(DapAbort::batch_overlap(
+                        task_id,
+                        batch_sel,
+                        &[bucket],
+                    )));
                 }
                 agg_share.merge(inner_agg_store.agg_share.clone())?;
+                aggregated = true;
             }
         }
 
+        // Don't mistake a batch that hasn't been aggregated yet for an empty one.
+        if !aggregated {
+            return Err(DapError::Abort(DapAbort::InvalidBatchSize {
+                detail: "batch has not been aggregated yet".into(),
+                task_id: *task_id,
+            }));
+        }
+
         Ok(agg_share)
     }
 
@@ -1289,13 +2555,14 @@ impl DapAggregator<BearerToken> for MockAggregator {
         &self,
         task_id: &TaskId,
         batch_sel: &BatchSelector,
+        agg_param: &DapAggregationParam,
     ) -> Result<(), DapError> {
         let task_config = self.unchecked_get_task_config(task_id).await;
         let mut guard = self.agg_store.lock().expect("agg_store: failed to lock");
         let agg_store = guard.entry(*task_id).or_default();
 
-        for bucket in task_config.batch_span_for_sel(batch_sel)? {
-            if let Some(inner_agg_store) = agg_store.get_mut(&bucket) {
+        for bucket in task_config.batch_span_iter_for_sel(batch_sel)? {
+            if let Some(inner_agg_store) = agg_store.get_mut(&(bucket, agg_param.clone())) {
                 inner_agg_store.collected = true;
             }
         }
@@ -1303,6 +2570,44 @@ impl DapAggregator<BearerToken> for MockAggregator {
         Ok(())
     }
 
+    async fn batch_report_ids(
+        &self,
+        task_id: &TaskId,
+        batch_sel: &BatchSelector,
+    ) -> Result<Vec<ReportId>, DapError> {
+        let task_config = self.unchecked_get_task_config(task_id).await;
+        let agg_store = self.agg_store.lock().map_err(|e| fatal_error!(err = ?e))?;
+        let Some(agg_store_per_task) = agg_store.get(task_id) else {
+            return Ok(Vec::new());
+        };
+
+        // The same report may be aggregated under several aggregation parameters, but is only
+        // listed once.
+        let mut report_ids = Vec::new();
+        let mut seen = HashSet::new();
+        for bucket in task_config.batch_span_iter_for_sel(batch_sel)? {
+            for ((aggregated_bucket, _agg_param), inner_agg_store) in agg_store_per_task {
+                if *aggregated_bucket != bucket {
+                    continue;
+                }
+                if !inner_agg_store.report_ids.is_empty() && !inner_agg_store.collected {
+                    return Err(DapError::Abort(DapAbort::BatchInvalid {
+                        detail: format!("The queried batch has not been collected ({bucket})."),
+                        task_id: *task_id,
+                    }));
+                }
+                report_ids.extend(
+                    inner_agg_store
+                        .report_ids
+                        .iter()
+                        .filter(|report_id| seen.insert(**report_id)),
+                );
+            }
+        }
+
+        Ok(report_ids)
+    }
+
     fn metrics(&self) -> &dyn DaphneMetrics {
         &self.metrics
     }
@@ -1331,6 +2636,7 @@ impl DapHelper<BearerToken> for MockAggregator {
             task_id: *task_id,
             agg_job_id_owned: agg_job_id.into(),
         };
+        let now = self.get_current_time();
 
         let mut helper_state_store = self
             .helper_state_store
@@ -1343,7 +2649,7 @@ impl DapHelper<BearerToken> for MockAggregator {
 
         // NOTE: This code is only correct for VDAFs with exactly one round of preparation.
         // For VDAFs with more rounds, the helper state blob will need to be updated here.
-        helper_state_store.insert(helper_state_info, helper_state.clone());
+        helper_state_store.insert(helper_state_info, (helper_state.clone(), now));
 
         Ok(true)
     }
@@ -1368,12 +2674,157 @@ impl DapHelper<BearerToken> for MockAggregator {
 
         // NOTE: This code is only correct for VDAFs with exactly one round of preparation.
         // For VDAFs with more rounds, the helper state blob will need to be updated here.
-        Ok(helper_state_store.get(&helper_state_info).cloned())
+        Ok(helper_state_store
+            .get(&helper_state_info)
+            .map(|(helper_state, _created_at)| helper_state.clone()))
+    }
+
+    async fn purge_expired_helper_state(
+        &self,
+        task_id: &TaskId,
+        older_than: Time,
+    ) -> Result<(), DapError> {
+        let now = self.get_current_time();
+        let task_expired = self
+            .get_task_config_for(task_id)
+            .await?
+            .is_none_or(|task_config| task_config.expiration <= now);
+
+        self.helper_state_store
+            .lock()
+            .map_err(|e| fatal_error!(err = ?e))?
+            .retain(|info, (_, created_at)| {
+                info.task_id != *task_id || (!task_expired && *created_at >= older_than)
+            });
+        Ok(())
+    }
+
+    async fn check_and_set_bucket_agg_param(
+        &self,
+        task_id: &TaskId,
+        buckets: &HashSet<DapBatchBucket>,
+        agg_param: &DapAggregationParam,
+    ) -> Result<Vec<DapBatchBucket>, DapError> {
+        let mut bucket_agg_param_store = self
+            .bucket_agg_param_store
+            .lock()
+            .map_err(|e| fatal_error!(err = ?e))?;
+
+        let mut mismatched = Vec::new();
+        for bucket in buckets {
+            let bucket_agg_param = bucket_agg_param_store
+                .entry((*task_id, bucket.clone()))
+                .or_insert_with(|| agg_param.clone());
+            if bucket_agg_param != agg_param {
+                mismatched.push(bucket.clone());
+            }
+        }
+
+        Ok(mismatched)
+    }
+
+    async fn put_agg_job_resp(
+        &self,
+        task_id: &TaskId,
+        agg_job_id: &MetaAggregationJobId,
+        agg_job_resp: &AggregationJobResp,
+    ) -> Result<(), DapError> {
+        let helper_state_info = HelperStateInfo {
+            task_id: *task_id,
+            agg_job_id_owned: *agg_job_id,
+        };
+        self.agg_job_resp_store
+            .lock()
+            .map_err(|e| fatal_error!(err = ?e))?
+            .insert(
+                helper_state_info,
+                agg_job_resp.get_encoded().map_err(DapError::encoding)?,
+            );
+        Ok(())
+    }
+
+    async fn get_agg_job_resp(
+        &self,
+        task_id: &TaskId,
+        agg_job_id: &MetaAggregationJobId,
+    ) -> Result<Option<AggregationJobResp>, DapError> {
+        let helper_state_info = HelperStateInfo {
+            task_id: *task_id,
+            agg_job_id_owned: *agg_job_id,
+        };
+        self.agg_job_resp_store
+            .lock()
+            .map_err(|e| fatal_error!(err = ?e))?
+            .get(&helper_state_info)
+            .map(|bytes| AggregationJobResp::get_decoded(bytes))
+            .transpose()
+            .map_err(|e| fatal_error!(err = ?e))
+    }
+
+    async fn delete_agg_job_resp(
+        &self,
+        task_id: &TaskId,
+        agg_job_id: &MetaAggregationJobId,
+    ) -> Result<(), DapError> {
+        let helper_state_info = HelperStateInfo {
+            task_id: *task_id,
+            agg_job_id_owned: *agg_job_id,
+        };
+        self.agg_job_resp_store
+            .lock()
+            .map_err(|e| fatal_error!(err = ?e))?
+            .remove(&helper_state_info);
+        Ok(())
+    }
+
+    async fn put_retained_agg_share(
+        &self,
+        task_id: &TaskId,
+        idempotency_token: &str,
+        agg_param: &DapAggregationParam,
+        retained_agg_share: &DapRetainedAggShare,
+    ) -> Result<(), DapError> {
+        let now = self.get_current_time();
+        let mut retained_agg_share_store = self
+            .retained_agg_share_store
+            .lock()
+            .map_err(|e| fatal_error!(err = ?e))?;
+
+        // Drop aggregate shares that no longer need to be retained.
+        retained_agg_share_store.retain(|_, retained| retained.expiration > now);
+        retained_agg_share_store.insert(
+            (*task_id, idempotency_token.to_string(), agg_param.clone()),
+            retained_agg_share.clone(),
+        );
+        Ok(())
+    }
+
+    async fn get_retained_agg_share(
+        &self,
+        task_id: &TaskId,
+        idempotency_token: &str,
+        agg_param: &DapAggregationParam,
+    ) -> Result<Option<DapRetainedAggShare>, DapError> {
+        let now = self.get_current_time();
+        let mut retained_agg_share_store = self
+            .retained_agg_share_store
+            .lock()
+            .map_err(|e| fatal_error!(err = ?e))?;
+
+        // Drop aggregate shares that no longer need to be retained.
+        retained_agg_share_store.retain(|_, retained| retained.expiration > now);
+        Ok(retained_agg_share_store
+            .get(&(*task_id, idempotency_token.to_string(), agg_param.clone()))
+            .cloned())
     }
 }
 
 #[async_trait]
 impl DapLeader<BearerToken> for MockAggregator {
+    fn upload_dedup_cache(&self) -> Option<&UploadDedupCache> {
+        Some(&self.upload_dedup_cache)
+    }
+
     async fn put_report(&self, report: &Report, task_id: &TaskId) -> Result<(), DapError> {
         let task_config = self
             .get_task_config_for(task_id)
@@ -1383,7 +2834,13 @@ impl DapLeader<BearerToken> for MockAggregator {
         self.leader_state_store
             .lock()
             .map_err(|e| fatal_error!(err = ?e))?
-            .put_report(task_id, &task_config, report.clone())
+            .put_report(
+                task_id,
+                &task_config,
+                report.clone(),
+                &self.global_config,
+                None,
+            )
     }
 
     async fn current_batch(&self, task_id: &TaskId) -> std::result::Result<BatchId, DapError> {
@@ -1398,11 +2855,44 @@ impl DapLeader<BearerToken> for MockAggregator {
             .current_batch(task_id, &task_config)
     }
 
-    async fn dequeue_work(&self, num_items: usize) -> Result<Vec<WorkItem>, DapError> {
+    async fn reserve_current_batch(
+        &self,
+        task_id: &TaskId,
+    ) -> std::result::Result<BatchId, DapError> {
+        let task_config = self
+            .get_task_config_for(task_id)
+            .await?
+            .ok_or_else(|| fatal_error!(err = "task not found"))?;
+
+        self.leader_state_store
+            .lock()
+            .map_err(|e| fatal_error!(err = ?e))?
+            .reserve_current_batch(task_id, &task_config)
+    }
+
+    async fn dequeue_work(
+        &self,
+        num_items: usize,
+    ) -> Result<Vec<(WorkItemId, WorkItem)>, DapError> {
+        let now = self.get_current_time();
+        let mut leader_state = self
+            .leader_state_store
+            .lock()
+            .map_err(|e| fatal_error!(err = ?e))?;
+        leader_state.reclaim_expired_leases(now)?;
+        leader_state.dequeue_work(
+            num_items,
+            self.global_config.max_concurrent_agg_jobs_per_task,
+            now,
+            self.global_config.work_item_visibility_timeout(),
+        )
+    }
+
+    async fn ack_work(&self, item_ids: Vec<WorkItemId>) -> Result<(), DapError> {
         self.leader_state_store
             .lock()
             .map_err(|e| fatal_error!(err = ?e))?
-            .dequeue_work(num_items)
+            .ack_work(&item_ids)
     }
 
     async fn enqueue_work(&self, work_items: Vec<WorkItem>) -> Result<(), DapError> {
@@ -1412,18 +2902,90 @@ impl DapLeader<BearerToken> for MockAggregator {
             .map_err(|e| fatal_error!(err = ?e))?;
 
         for work_item in work_items {
-            leader_state.work_queue.push_back(work_item);
+            leader_state.push_work(work_item);
         }
         Ok(())
     }
 
+    async fn work_queue_stats(&self) -> Result<WorkQueueStats, DapError> {
+        Ok(self
+            .leader_state_store
+            .lock()
+            .map_err(|e| fatal_error!(err = ?e))?
+            .work_queue_stats())
+    }
+
+    async fn agg_job_status(
+        &self,
+        task_id: &TaskId,
+        agg_job_id: &MetaAggregationJobId,
+    ) -> Result<Option<DapAggregationJobStatus>, DapError> {
+        self.leader_state_store
+            .lock()
+            .map_err(|e| fatal_error!(err = ?e))?
+            .agg_job_status(task_id, agg_job_id)
+    }
+
+    async fn set_agg_job_status(
+        &self,
+        task_id: &TaskId,
+        agg_job_id: &MetaAggregationJobId,
+        status: DapAggregationJobStatus,
+    ) -> Result<(), DapError> {
+        self.leader_state_store
+            .lock()
+            .map_err(|e| fatal_error!(err = ?e))?
+            .set_agg_job_status(task_id, agg_job_id, status)
+    }
+
+    async fn replace_agg_job_id(
+        &self,
+        task_id: &TaskId,
+        agg_job_id: &MetaAggregationJobId,
+        retry_agg_job_id: &MetaAggregationJobId,
+    ) -> Result<(), DapError> {
+        self.leader_state_store
+            .lock()
+            .map_err(|e| fatal_error!(err = ?e))?
+            .replace_agg_job_id(task_id, agg_job_id, retry_agg_job_id)
+    }
+
+    async fn put_agg_job_checkpoint(
+        &self,
+        checkpoint: AggregationJobCheckpoint,
+    ) -> Result<(), DapError> {
+        self.leader_state_store
+            .lock()
+            .map_err(|e| fatal_error!(err = ?e))?
+            .put_agg_job_checkpoint(checkpoint)
+    }
+
+    async fn delete_agg_job_checkpoint(
+        &self,
+        task_id: &TaskId,
+        agg_job_id: &MetaAggregationJobId,
+    ) -> Result<(), DapError> {
+        self.leader_state_store
+            .lock()
+            .map_err(|e| fatal_error!(err = ?e))?
+            .delete_agg_job_checkpoint(task_id, agg_job_id)
+    }
+
+    async fn list_agg_job_checkpoints(&self) -> Result<Vec<AggregationJobCheckpoint>, DapError> {
+        self.leader_state_store
+            .lock()
+            .map_err(|e| fatal_error!(err = ?e))?
+            .list_agg_job_checkpoints()
+    }
+
     // Called after receiving a CollectReq from Collector.
     async fn init_collect_job(
         &self,
         task_id: &TaskId,
         coll_job_id: &Option<CollectionJobId>,
-        batch_sel: BatchSelector,
+        query: Query,
         agg_param: DapAggregationParam,
+        collector_hpke_config_id: Option<u8>,
     ) -> Result<Url, DapError> {
         let task_config = self
             .get_task_config_for(task_id)
@@ -1433,7 +2995,27 @@ impl DapLeader<BearerToken> for MockAggregator {
         self.leader_state_store
             .lock()
             .map_err(|e| fatal_error!(err = ?e))?
-            .init_collect_job(task_id, &task_config, coll_job_id, batch_sel, agg_param)
+            .init_collect_job(
+                task_id,
+                &task_config,
+                coll_job_id,
+                query,
+                agg_param,
+                collector_hpke_config_id,
+                &self.global_config,
+            )
+    }
+
+    async fn batch_time_range(
+        &self,
+        task_id: &TaskId,
+        batch_id: &BatchId,
+    ) -> Result<Option<Interval>, DapError> {
+        Ok(self
+            .leader_state_store
+            .lock()
+            .map_err(|e| fatal_error!(err = ?e))?
+            .batch_time_range(task_id, batch_id))
     }
 
     async fn poll_collect_job(
@@ -1447,6 +3029,32 @@ impl DapLeader<BearerToken> for MockAggregator {
             .poll_collect_job(task_id, coll_job_id)
     }
 
+    async fn list_collection_jobs(
+        &self,
+        task_id: &TaskId,
+    ) -> Result<Vec<(CollectionJobId, DapCollectionJobInfo)>, DapError> {
+        let task_config = self
+            .get_task_config_for(task_id)
+            .await?
+            .ok_or_else(|| fatal_error!(err = "task not found"))?;
+
+        self.leader_state_store
+            .lock()
+            .map_err(|e| fatal_error!(err = ?e))?
+            .list_collection_jobs(task_id, &task_config)
+    }
+
+    async fn retry_collect_job(
+        &self,
+        task_id: &TaskId,
+        coll_job_id: &CollectionJobId,
+    ) -> Result<(), DapError> {
+        self.leader_state_store
+            .lock()
+            .map_err(|e| fatal_error!(err = ?e))?
+            .retry_collect_job(task_id, coll_job_id)
+    }
+
     async fn finish_collect_job(
         &self,
         task_id: &TaskId,
@@ -1464,15 +3072,17 @@ impl DapLeader<BearerToken> for MockAggregator {
         req: DapRequest<BearerToken>,
         _url: Url,
     ) -> Result<DapResponse, DapError> {
+        self.inject_peer_failure()?;
+        self.park_peer_request().await;
         match req.media_type {
-            DapMediaType::AggregationJobInitReq | DapMediaType::AggregationJobContinueReq => {
-                Ok(helper::handle_agg_job_req(
-                    &**self.peer.as_ref().expect("peer not configured"),
-                    &req,
-                )
-                .await
-                .expect("peer aborted unexpectedly"))
-            }
+            DapMediaType::AggregationJobInitReq
+            | DapMediaType::AggregationJobContinueReq
+            | DapMediaType::AggregationJobRespChunkReq => Ok(helper::handle_agg_job_req(
+                &**self.peer.as_ref().expect("peer not configured"),
+                &req,
+            )
+            .await
+            .expect("peer aborted unexpectedly")),
             DapMediaType::AggregateShareReq => Ok(helper::handle_agg_share_req(
                 &**self.peer.as_ref().expect("peer not configured"),
                 &req,
@@ -1488,6 +3098,8 @@ impl DapLeader<BearerToken> for MockAggregator {
         req: DapRequest<BearerToken>,
         _url: Url,
     ) -> Result<DapResponse, DapError> {
+        self.inject_peer_failure()?;
+        self.park_peer_request().await;
         if req.media_type == DapMediaType::AggregationJobInitReq {
             Ok(helper::handle_agg_job_req(
                 &**self.peer.as_ref().expect("peer not configured"),
@@ -1509,13 +3121,26 @@ pub struct HelperStateInfo {
     agg_job_id_owned: MetaAggregationJobId,
 }
 
-/// `AggStore` keeps track of the following:
+/// The IDs of the reports aggregated for each task under each aggregation parameter.
+pub(crate) type ReportStore = HashMap<(TaskId, DapAggregationParam), HashSet<ReportId>>;
+
+/// The aggregate shares retained by the Helper for each task, idempotency token, and aggregation
+/// parameter.
+pub(crate) type RetainedAggShareStore =
+    HashMap<(TaskId, String, DapAggregationParam), DapRetainedAggShare>;
+
+/// The aggregate shares of a task, kept separately for each bucket and aggregation parameter.
+pub(crate) type AggStorePerTask = HashMap<(DapBatchBucket, DapAggregationParam), AggStore>;
+
+/// `AggStore` keeps track of the following for a bucket and aggregation parameter:
 /// * Aggregate share
+/// * The IDs of the reports aggregated into the aggregate share
 /// * Whether this aggregate share has been collected
 #[derive(Default)]
 #[cfg_attr(any(test, feature = "test-utils"), derive(deepsize::DeepSizeOf))]
 pub struct AggStore {
     pub(crate) agg_share: DapAggregateShare,
+    pub(crate) report_ids: Vec<ReportId>,
     pub(crate) collected: bool,
 }
 
@@ -208,6 +208,23 @@ pub(crate) fn mastic_unshard<M: IntoIterator<Item = Vec<u8>>>(
     agg_param: &DapAggregationParam,
     agg_share_bytes: M,
 ) -> Result<DapAggregateResult, VdafError> {
+    match mastic_merge_agg_shares(weight_config, agg_param, agg_share_bytes)? {
+        VdafAggregateShare::Field64(agg) => Ok(DapAggregateResult::U64Vec(
+            agg.as_ref().iter().copied().map(u64::from).collect(),
+        )),
+        _ => Err(VdafError::Dap(fatal_error!(
+            err = "mastic: unexpected agg share type"
+        ))),
+    }
+}
+
+/// Merge the encoded aggregate shares into a single aggregate share. The aggregate result is this
+/// aggregate share with each field element converted to an integer.
+pub(crate) fn mastic_merge_agg_shares<M: IntoIterator<Item = Vec<u8>>>(
+    weight_config: MasticWeightConfig,
+    agg_param: &DapAggregationParam,
+    agg_share_bytes: M,
+) -> Result<VdafAggregateShare, VdafError> {
     match (weight_config, agg_param) {
         (MasticWeightConfig::Count, DapAggregationParam::Mastic(agg_param)) => {
             let agg: Vec<Field64> = agg_share_bytes
@@ -226,9 +243,7 @@ pub(crate) fn mastic_unshard<M: IntoIterator<Item = Vec<u8>>>(
                     ))
                 })??;
 
-            Ok(DapAggregateResult::U64Vec(
-                agg.into_iter().map(u64::from).collect(),
-            ))
+            Ok(VdafAggregateShare::Field64(AggregateShare::from(agg)))
         }
         _ => Err(VdafError::Dap(fatal_error!(
             err = "mastic: unexpected agg param type"
@@ -252,6 +267,7 @@ mod test {
             &VdafConfig::Mastic {
                 input_size: 4,
                 weight_config: MasticWeightConfig::Count,
+                fixed_agg_param: false,
             },
             HpkeKemId::X25519HkdfSha256,
             DapVersion::DraftLatest,
@@ -288,7 +304,8 @@ mod test {
                     },
                 ],
             )
-            .await;
+            .await
+            .unwrap();
 
         assert_eq!(got, DapAggregateResult::U64Vec(vec![1, 2]));
     }
@@ -10,9 +10,20 @@ pub(crate) mod prio2;
 pub(crate) mod prio3;
 
 use crate::{
-    error::DapAbort,
-    vdaf::{prio2::prio2_decode_prep_state, prio3::prio3_decode_prep_state},
-    DapError,
+    error::{DapAbort, MeasurementError},
+    fatal_error,
+    messages::{Base64Encode, ReportId, TaskId},
+    vdaf::{
+        prio2::{
+            prio2_decode_prep_state, prio2_prep_finish, prio2_prep_finish_from_shares,
+            prio2_prep_init, prio2_unshard,
+        },
+        prio3::{
+            prio3_decode_prep_state, prio3_prep_finish, prio3_prep_finish_from_shares,
+            prio3_prep_init, prio3_unshard,
+        },
+    },
+    DapAggregateResult, DapAggregateShare, DapError, DapMeasurement,
 };
 #[cfg(any(test, feature = "test-utils"))]
 use prio::field::FieldElement;
@@ -33,6 +44,12 @@ use std::io::Read;
 #[cfg(any(test, feature = "test-utils"))]
 pub use self::mastic::MasticWeightConfig;
 
+/// Identifies the implementation of the VDAFs used by this crate. The Leader sends it to the Helper
+/// in the "dap-vdaf-version" header of each aggregation job initialization request so that the
+/// Aggregators don't silently compute garbage when they run incompatible implementations. This
+/// must be bumped whenever the `prio` dependency is upgraded to a version with breaking changes.
+pub const VDAF_VERSION: &str = "prio/0.16";
+
 #[derive(Debug, thiserror::Error)]
 pub(crate) enum VdafError {
     #[error("{0}")]
@@ -59,6 +76,12 @@ pub enum VdafConfig {
 
         /// The type of each weight.
         weight_config: MasticWeightConfig,
+
+        /// Whether each batch is aggregated under a single aggregation parameter, as when the
+        /// Collector uses Mastic to compute metrics for a fixed set of attributes. Otherwise, as
+        /// for heavy hitters, a batch may be aggregated once for each round of collection.
+        #[serde(default)]
+        fixed_agg_param: bool,
     },
 }
 
@@ -79,11 +102,53 @@ impl std::fmt::Display for VdafConfig {
             VdafConfig::Mastic {
                 input_size,
                 weight_config,
+                ..
             } => write!(f, "Mastic({input_size}, {weight_config})"),
         }
     }
 }
 
+/// Names of the types of VDAF that are supported, as returned by [`VdafConfig::type_name`].
+const SUPPORTED_TYPE_NAMES: &[&str] = &[
+    "prio3_count",
+    "prio3_sum",
+    "prio3_histogram",
+    "prio3_sum_vec",
+    "prio3_sum_vec_field64_multiproof_hmac_sha256_aes128",
+    "prio2",
+];
+
+impl VdafConfig {
+    /// Name of the type of VDAF, e.g., `"prio3_count"` or `"prio2"`. A Prio3 type is named after
+    /// both its [`VdafConfig`] and [`Prio3Config`] variants. Returns `None` for VDAFs that are only
+    /// available for testing.
+    pub fn type_name(&self) -> Option<&'static str> {
+        match self {
+            Self::Prio3(prio3_config) => Some(match prio3_config {
+                Prio3Config::Count => "prio3_count",
+                Prio3Config::Sum { .. } => "prio3_sum",
+                Prio3Config::Histogram { .. } => "prio3_histogram",
+                Prio3Config::SumVec { .. } => "prio3_sum_vec",
+                Prio3Config::SumVecField64MultiproofHmacSha256Aes128 { .. } => {
+                    "prio3_sum_vec_field64_multiproof_hmac_sha256_aes128"
+                }
+            }),
+            Self::Prio2 { .. } => Some("prio2"),
+            #[cfg(any(test, feature = "test-utils"))]
+            Self::Mastic { .. } => None,
+        }
+    }
+
+    /// Names of the types of VDAF that are supported. Each is the [`Self::type_name`] of some
+    /// [`VdafConfig`].
+    pub fn type_names() -> Vec<String> {
+        SUPPORTED_TYPE_NAMES
+            .iter()
+            .map(ToString::to_string)
+            .collect()
+    }
+}
+
 /// Supported data types for prio3.
 #[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, PartialOrd, Ord, Hash, Serialize)]
 #[serde(rename_all = "snake_case")]
@@ -225,7 +290,7 @@ impl Encode for VdafPrepState {
             Self::Prio3Field128(state) => state.encode(bytes),
             Self::Prio2(state) => state.encode(bytes),
             #[cfg(any(test, feature = "test-utils"))]
-            Self::Mastic { .. } => todo!("mastic: decoding of prep state is not implemented"),
+            Self::Mastic { out_share } => prio::codec::encode_u32_items(bytes, &(), out_share),
         }
     }
 }
@@ -246,7 +311,9 @@ impl<'a> ParameterizedDecode<(&'a VdafConfig, bool /* is_leader */)> for VdafPre
                     .map_err(|e| CodecError::Other(Box::new(e)))?)
             }
             #[cfg(any(test, feature = "test-utils"))]
-            VdafConfig::Mastic { .. } => todo!("mastic: decoding of prep state is not implemented"),
+            VdafConfig::Mastic { .. } => Ok(Self::Mastic {
+                out_share: prio::codec::decode_u32_items(&(), bytes)?,
+            }),
         }
     }
 }
@@ -354,6 +421,18 @@ impl Encode for VdafAggregateShare {
 }
 
 impl VdafConfig {
+    /// The minimum number of reports in a batch needed for the VDAF to protect the privacy of the
+    /// Clients, regardless of the task's configured minimum batch size.
+    pub fn min_batch_size(&self) -> u64 {
+        match self {
+            Self::Prio3(..) | Self::Prio2 { .. } => 0,
+            // The aggregate of a single report would reveal which of the candidate prefixes the
+            // Client's input matches.
+            #[cfg(any(test, feature = "test-utils"))]
+            Self::Mastic { .. } => 2,
+        }
+    }
+
     pub(crate) fn uninitialized_verify_key(&self) -> VdafVerifyKey {
         match self {
             Self::Prio3(Prio3Config::SumVecField64MultiproofHmacSha256Aes128 { .. })
@@ -404,6 +483,276 @@ impl VdafConfig {
             Self::Mastic { .. } => true,
         }
     }
+
+    /// Whether every aggregation job for a batch must use the same aggregation parameter. VDAFs
+    /// without an aggregation parameter trivially satisfy this, so it is never checked for them.
+    pub fn is_agg_param_fixed_per_batch(&self) -> bool {
+        match self {
+            Self::Prio3(..) | Self::Prio2 { .. } => false,
+            #[cfg(any(test, feature = "test-utils"))]
+            Self::Mastic {
+                fixed_agg_param, ..
+            } => *fixed_agg_param,
+        }
+    }
+
+    /// Check that the measurement can be sharded under this VDAF, i.e., that it has the expected
+    /// type and is in range.
+    pub fn validate_measurement(
+        &self,
+        measurement: &DapMeasurement,
+    ) -> Result<(), MeasurementError> {
+        let valid = match (self, measurement) {
+            (Self::Prio3(Prio3Config::Count), DapMeasurement::U64(measurement)) => {
+                *measurement <= 1
+            }
+            (Self::Prio3(Prio3Config::Sum { bits }), DapMeasurement::U64(measurement)) => {
+                fits_in_bits(u128::from(*measurement), *bits)
+            }
+            (Self::Prio3(Prio3Config::Histogram { length, .. }), DapMeasurement::U64(index)) => {
+                usize::try_from(*index).is_ok_and(|index| index < *length)
+            }
+            (
+                Self::Prio3(Prio3Config::SumVec { bits, length, .. }),
+                DapMeasurement::U128Vec(measurement),
+            ) => {
+                measurement.len() == *length
+                    && measurement.iter().all(|value| fits_in_bits(*value, *bits))
+            }
+            (
+                Self::Prio3(Prio3Config::SumVecField64MultiproofHmacSha256Aes128 {
+                    bits,
+                    length,
+                    ..
+                }),
+                DapMeasurement::U64Vec(measurement),
+            ) => {
+                measurement.len() == *length
+                    && measurement
+                        .iter()
+                        .all(|value| fits_in_bits(u128::from(*value), *bits))
+            }
+            (Self::Prio2 { dimension }, DapMeasurement::U32Vec(measurement)) => {
+                measurement.len() == *dimension && measurement.iter().all(|bit| *bit <= 1)
+            }
+            #[cfg(any(test, feature = "test-utils"))]
+            (Self::Mastic { input_size, .. }, DapMeasurement::Mastic { input, .. }) => {
+                input.len() == *input_size
+            }
+            _ => return Err(MeasurementError::UnexpectedType),
+        };
+
+        if valid {
+            Ok(())
+        } else {
+            Err(MeasurementError::OutOfRange)
+        }
+    }
+}
+
+impl VdafConfig {
+    /// Maximum measurement length for which [`Self::self_test`] instantiates the VDAF.
+    pub const SELF_TEST_MAX_LENGTH: usize = 1 << 12;
+
+    /// Check that the VDAF is usable by running it end-to-end on a pair of fixed measurements and
+    /// comparing the unsharded result to the expected aggregate. This is meant to be run when a
+    /// task is configured so that invalid VDAF parameters, e.g., a histogram with no buckets, are
+    /// caught before any reports are uploaded. A failure is reported as an invalid task.
+    ///
+    /// The VDAF parameters may come from an untrusted peer (e.g., via taskprov), so the test is
+    /// skipped, with a warning, for VDAFs whose measurement length exceeds
+    /// [`Self::SELF_TEST_MAX_LENGTH`].
+    pub fn self_test(&self, task_id: &TaskId) -> Result<(), DapAbort> {
+        let invalid_task = |detail| DapAbort::InvalidTask {
+            detail: format!("self-test failed for VDAF {self}: {detail}"),
+            task_id: *task_id,
+        };
+        let length = match self {
+            Self::Prio3(
+                Prio3Config::Histogram { length, .. }
+                | Prio3Config::SumVec { length, .. }
+                | Prio3Config::SumVecField64MultiproofHmacSha256Aes128 { length, .. },
+            ) => *length,
+            Self::Prio2 { dimension } => *dimension,
+            _ => 0,
+        };
+        if length > Self::SELF_TEST_MAX_LENGTH {
+            tracing::warn!(
+                task_id = task_id.to_base64url(),
+                vdaf = %self,
+                length,
+                max_length = Self::SELF_TEST_MAX_LENGTH,
+                "skipping VDAF self-test for oversized measurement",
+            );
+            return Ok(());
+        }
+        let (measurements, expected) = match self {
+            Self::Prio3(Prio3Config::Count) => (
+                vec![DapMeasurement::U64(1), DapMeasurement::U64(0)],
+                DapAggregateResult::U64(1),
+            ),
+            Self::Prio3(Prio3Config::Sum { .. }) => (
+                vec![DapMeasurement::U64(1), DapMeasurement::U64(0)],
+                DapAggregateResult::U128(1),
+            ),
+            Self::Prio3(Prio3Config::Histogram { length, .. }) => {
+                let mut expected = vec![0; *length];
+                if let Some(last) = expected.last_mut() {
+                    *last = 2;
+                }
+                (
+                    vec![
+                        DapMeasurement::U64(length.saturating_sub(1) as u64),
+                        DapMeasurement::U64(length.saturating_sub(1) as u64),
+                    ],
+                    DapAggregateResult::U128Vec(expected),
+                )
+            }
+            Self::Prio3(Prio3Config::SumVec { length, .. }) => (
+                vec![
+                    DapMeasurement::U128Vec(vec![1; *length]),
+                    DapMeasurement::U128Vec(vec![0; *length]),
+                ],
+                DapAggregateResult::U128Vec(vec![1; *length]),
+            ),
+            Self::Prio3(Prio3Config::SumVecField64MultiproofHmacSha256Aes128 {
+                length, ..
+            }) => (
+                vec![
+                    DapMeasurement::U64Vec(vec![1; *length]),
+                    DapMeasurement::U64Vec(vec![0; *length]),
+                ],
+                DapAggregateResult::U64Vec(vec![1; *length]),
+            ),
+            // Prio2 panics, rather than returning an error, if the dimension is zero.
+            Self::Prio2 { dimension: 0 } => {
+                return Err(invalid_task("dimension cannot be zero".into()))
+            }
+            Self::Prio2 { dimension } => (
+                vec![
+                    DapMeasurement::U32Vec(vec![1; *dimension]),
+                    DapMeasurement::U32Vec(vec![0; *dimension]),
+                ],
+                DapAggregateResult::U32Vec(vec![1; *dimension]),
+            ),
+            // Mastic can't be run without an aggregation parameter chosen by the Collector.
+            #[cfg(any(test, feature = "test-utils"))]
+            Self::Mastic { .. } => return Ok(()),
+        };
+
+        let got = self
+            .self_test_aggregate(measurements)
+            .map_err(|e| invalid_task(e.to_string()))?;
+        if got != expected {
+            return Err(invalid_task(format!("got {got:?}, expected {expected:?}")));
+        }
+        Ok(())
+    }
+
+    /// Shard each measurement, prepare the input shares as the Leader and Helper would, aggregate
+    /// the output shares, and unshard the aggregate shares.
+    fn self_test_aggregate(
+        &self,
+        measurements: Vec<DapMeasurement>,
+    ) -> Result<DapAggregateResult, DapError> {
+        let verify_key = self.gen_verify_key();
+        let num_measurements = measurements.len();
+        let mut leader_agg_share = DapAggregateShare::default();
+        let mut helper_agg_share = DapAggregateShare::default();
+        for measurement in measurements {
+            let report_id = ReportId(thread_rng().gen());
+            let nonce = &report_id.0;
+            let (public_share, input_shares) = self.produce_input_shares(measurement, nonce)?;
+            let (leader_out_share, helper_out_share) = match self {
+                Self::Prio3(config) => {
+                    let (leader_state, leader_share) = prio3_prep_init(
+                        config,
+                        &verify_key,
+                        0,
+                        nonce,
+                        &public_share,
+                        &input_shares[0],
+                    )?;
+                    let (helper_state, helper_share) = prio3_prep_init(
+                        config,
+                        &verify_key,
+                        1,
+                        nonce,
+                        &public_share,
+                        &input_shares[1],
+                    )?;
+                    let (helper_out_share, prep_msg) = prio3_prep_finish_from_shares(
+                        config,
+                        1,
+                        helper_state,
+                        helper_share,
+                        &leader_share.get_encoded().map_err(VdafError::Codec)?,
+                    )?;
+                    let leader_out_share = prio3_prep_finish(config, leader_state, &prep_msg)?;
+                    (leader_out_share, helper_out_share)
+                }
+                Self::Prio2 { dimension } => {
+                    let (leader_state, leader_share) = prio2_prep_init(
+                        *dimension,
+                        &verify_key,
+                        0,
+                        nonce,
+                        &public_share,
+                        &input_shares[0],
+                    )?;
+                    let (helper_state, helper_share) = prio2_prep_init(
+                        *dimension,
+                        &verify_key,
+                        1,
+                        nonce,
+                        &public_share,
+                        &input_shares[1],
+                    )?;
+                    let (helper_out_share, prep_msg) = prio2_prep_finish_from_shares(
+                        *dimension,
+                        helper_state,
+                        helper_share,
+                        &leader_share.get_encoded().map_err(VdafError::Codec)?,
+                    )?;
+                    let leader_out_share = prio2_prep_finish(*dimension, leader_state, &prep_msg)?;
+                    (leader_out_share, helper_out_share)
+                }
+                #[cfg(any(test, feature = "test-utils"))]
+                Self::Mastic { .. } => {
+                    return Err(fatal_error!(err = "self-test is not supported for Mastic"))
+                }
+            };
+            leader_agg_share.add_out_share(&report_id, 0, leader_out_share)?;
+            helper_agg_share.add_out_share(&report_id, 0, helper_out_share)?;
+        }
+
+        let agg_shares = [leader_agg_share, helper_agg_share]
+            .into_iter()
+            .map(|agg_share| {
+                agg_share
+                    .data
+                    .ok_or_else(|| fatal_error!(err = "empty aggregate share"))?
+                    .get_encoded()
+                    .map_err(|e| fatal_error!(err = ?e))
+            })
+            .collect::<Result<Vec<_>, DapError>>()?;
+        match self {
+            Self::Prio3(config) => Ok(prio3_unshard(config, num_measurements, agg_shares)?),
+            Self::Prio2 { dimension } => {
+                Ok(prio2_unshard(*dimension, num_measurements, agg_shares)?)
+            }
+            #[cfg(any(test, feature = "test-utils"))]
+            Self::Mastic { .. } => Err(fatal_error!(err = "self-test is not supported for Mastic")),
+        }
+    }
+}
+
+/// Check that `value` is in range `[0, 2^bits)`.
+fn fits_in_bits(value: u128, bits: usize) -> bool {
+    u32::try_from(bits)
+        .ok()
+        .and_then(|bits| 1_u128.checked_shl(bits))
+        .is_none_or(|bound| value < bound)
 }
 
 #[cfg(any(test, feature = "test-utils"))]
@@ -421,3 +770,71 @@ pub(crate) fn decode_field_vec<F: FieldElement>(
     }
     Ok(v)
 }
+
+#[cfg(test)]
+mod test {
+    use super::{MasticWeightConfig, Prio3Config, VdafConfig};
+
+    #[test]
+    fn type_names() {
+        // One config of each type. Add new types here as well as to `VdafConfig::type_name`.
+        let vdaf_configs = [
+            VdafConfig::Prio3(Prio3Config::Count),
+            VdafConfig::Prio3(Prio3Config::Sum { bits: 8 }),
+            VdafConfig::Prio3(Prio3Config::Histogram {
+                length: 4,
+                chunk_length: 2,
+            }),
+            VdafConfig::Prio3(Prio3Config::SumVec {
+                bits: 1,
+                length: 4,
+                chunk_length: 2,
+            }),
+            VdafConfig::Prio3(Prio3Config::SumVecField64MultiproofHmacSha256Aes128 {
+                bits: 1,
+                length: 4,
+                chunk_length: 2,
+                num_proofs: 2,
+            }),
+            VdafConfig::Prio2 { dimension: 4 },
+            VdafConfig::Mastic {
+                input_size: 1,
+                weight_config: MasticWeightConfig::Count,
+                fixed_agg_param: false,
+            },
+        ];
+
+        // Each name is made of the serialized variant names.
+        for vdaf_config in &vdaf_configs {
+            let Some(type_name) = vdaf_config.type_name() else {
+                continue;
+            };
+            // Unit variants are serialized as a string, the others as a single-key object.
+            let tag = |v: &serde_json::Value| match v {
+                serde_json::Value::String(tag) => (tag.clone(), serde_json::Value::Null),
+                serde_json::Value::Object(o) => {
+                    let (tag, inner) = o.iter().next().unwrap();
+                    (tag.clone(), inner.clone())
+                }
+                _ => panic!("unexpected serialization {v}"),
+            };
+            let (vdaf_tag, inner) = tag(&serde_json::to_value(vdaf_config).unwrap());
+            let tags = if vdaf_tag == "prio3" {
+                vec![vdaf_tag, tag(&inner).0]
+            } else {
+                vec![vdaf_tag]
+            };
+            assert_eq!(tags.join("_"), type_name);
+        }
+
+        let mut type_names = vdaf_configs
+            .iter()
+            .filter_map(VdafConfig::type_name)
+            .collect::<Vec<_>>();
+        type_names.sort_unstable();
+        let mut supported = VdafConfig::type_names();
+        supported.sort_unstable();
+        assert_eq!(type_names, supported);
+        assert!(!supported.iter().any(|t| t.starts_with("mastic")));
+    }
+}
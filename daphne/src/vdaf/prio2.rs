@@ -13,7 +13,8 @@ use prio::{
     field::FieldPrio2,
     vdaf::{
         prio2::{Prio2, Prio2PrepareShare, Prio2PrepareState},
-        AggregateShare, Aggregator, Client, Collector, PrepareTransition, Share, Vdaf,
+        Aggregatable, AggregateShare, Aggregator, Client, Collector, PrepareTransition, Share,
+        Vdaf,
     },
 };
 use std::io::Cursor;
@@ -156,6 +157,26 @@ pub(crate) fn prio2_unshard<M: IntoIterator<Item = Vec<u8>>>(
     Ok(DapAggregateResult::U32Vec(agg_res))
 }
 
+/// Merge the encoded aggregate shares into a single aggregate share. The aggregate result is this
+/// aggregate share with each field element converted to an integer.
+pub(crate) fn prio2_merge_agg_shares<M: IntoIterator<Item = Vec<u8>>>(
+    dimension: usize,
+    encoded_agg_shares: M,
+) -> Result<VdafAggregateShare, VdafError> {
+    let vdaf = Prio2::new(dimension).map_err(|e| VdafError::Dap(fatal_error!(err = ?e)))?;
+    let mut agg: Option<AggregateShare<FieldPrio2>> = None;
+    for encoded in encoded_agg_shares {
+        let agg_share = AggregateShare::get_decoded_with_param(&(&vdaf, &()), encoded.as_ref())?;
+        match agg.as_mut() {
+            Some(agg) => agg.merge(&agg_share)?,
+            None => agg = Some(agg_share),
+        }
+    }
+    let agg =
+        agg.ok_or_else(|| VdafError::Dap(fatal_error!(err = "unexpected number of agg shares")))?;
+    Ok(VdafAggregateShare::FieldPrio2(agg))
+}
+
 #[cfg(test)]
 mod test {
     use crate::{
@@ -180,7 +201,8 @@ mod test {
                     DapMeasurement::U32Vec(vec![0, 0, 1, 0, 1]),
                 ],
             )
-            .await;
+            .await
+            .unwrap();
         assert_eq!(got, DapAggregateResult::U32Vec(vec![3, 3, 1, 0, 5]));
     }
 
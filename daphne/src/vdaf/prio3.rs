@@ -11,11 +11,12 @@ use crate::{
     VdafPrepState,
 };
 use prio::{
-    codec::{Encode, ParameterizedDecode},
-    field::Field64,
+    codec::{CodecError, Encode, ParameterizedDecode},
+    field::{Field128, Field64, FieldElement},
     flp::{
         gadgets::{Mul, ParallelSum},
-        types::SumVec,
+        types::{Histogram, SumVec},
+        Type,
     },
     vdaf::{
         prio3::{
@@ -23,7 +24,8 @@ use prio::{
             Prio3PublicShare,
         },
         xof::XofHmacSha256Aes128,
-        AggregateShare, Aggregator, Client, Collector, OutputShare, PrepareTransition, Vdaf,
+        Aggregatable, AggregateShare, Aggregator, Client, Collector, OutputShare,
+        PrepareTransition, Vdaf,
     },
 };
 use std::io::Cursor;
@@ -180,6 +182,12 @@ pub(crate) fn prio3_prep_init(
         ) => {
             let vdaf = Prio3::new_histogram(2, *length, *chunk_length)
                 .map_err(|e| VdafError::Dap(fatal_error!(err = ?e)))?;
+            let typ = Histogram::<Field128, ParallelSum<Field128, Mul<Field128>>>::new(
+                *length,
+                *chunk_length,
+            )
+            .map_err(|e| VdafError::Dap(fatal_error!(err = ?e)))?;
+            check_input_share_len::<_, 16>("Prio3Histogram", &typ, 1, agg_id, input_share_data)?;
             let (state, share) = prep_init(
                 vdaf,
                 verify_key,
@@ -219,6 +227,13 @@ pub(crate) fn prio3_prep_init(
         ) => {
             let vdaf = Prio3::new_sum_vec(2, *bits, *length, *chunk_length)
                 .map_err(|e| VdafError::Dap(fatal_error!(err = ?e)))?;
+            let typ = SumVec::<Field128, ParallelSum<Field128, Mul<Field128>>>::new(
+                *bits,
+                *length,
+                *chunk_length,
+            )
+            .map_err(|e| VdafError::Dap(fatal_error!(err = ?e)))?;
+            check_input_share_len::<_, 16>("Prio3SumVec", &typ, 1, agg_id, input_share_data)?;
             let (state, share) = prep_init(
                 vdaf,
                 verify_key,
@@ -267,6 +282,41 @@ pub(crate) fn prio3_prep_init(
         }
     };
 
+    /// Check that the input share has the length implied by the measurement shape of the task's
+    /// VDAF config. A Client that shards its measurement with the wrong length (e.g., the wrong
+    /// number of histogram buckets) produces a Leader share that can't be decoded.
+    fn check_input_share_len<T: Type, const SEED_SIZE: usize>(
+        vdaf_name: &str,
+        typ: &T,
+        num_proofs: usize,
+        agg_id: usize,
+        input_share_data: &[u8],
+    ) -> Result<(), VdafError> {
+        let joint_rand_blind_len = if typ.joint_rand_len() > 0 {
+            SEED_SIZE
+        } else {
+            0
+        };
+        let expected_len = if agg_id == 0 {
+            (typ.input_len() + typ.proof_len() * num_proofs) * T::Field::ENCODED_SIZE
+                + joint_rand_blind_len
+        } else {
+            2 * SEED_SIZE + joint_rand_blind_len
+        };
+
+        if input_share_data.len() != expected_len {
+            tracing::warn!(
+                vdaf = vdaf_name,
+                agg_id,
+                expected_len,
+                actual_len = input_share_data.len(),
+                "input share length does not match the VDAF config",
+            );
+            return Err(VdafError::Codec(CodecError::UnexpectedValue));
+        }
+        Ok(())
+    }
+
     type Prio3Prepared<T, const SEED_SIZE: usize> = (
         Prio3PrepareState<<T as prio::flp::Type>::Field, SEED_SIZE>,
         Prio3PrepareShare<<T as prio::flp::Type>::Field, SEED_SIZE>,
@@ -652,19 +702,95 @@ pub(crate) fn prio3_unshard<M: IntoIterator<Item = Vec<u8>>>(
     }
 }
 
+/// Merge the encoded aggregate shares into a single aggregate share. For each of the supported
+/// Prio3 types, the aggregate result is this aggregate share with each field element converted to
+/// an integer, so the result can be emitted without being collected into a [`DapAggregateResult`].
+pub(crate) fn prio3_merge_agg_shares<M: IntoIterator<Item = Vec<u8>>>(
+    config: &Prio3Config,
+    agg_shares: M,
+) -> Result<VdafAggregateShare, VdafError> {
+    return match &config {
+        Prio3Config::Count => {
+            let vdaf = Prio3::new_count(2).map_err(|e| VdafError::Dap(fatal_error!(err = ?e)))?;
+            Ok(VdafAggregateShare::Field64(merge(&vdaf, agg_shares)?))
+        }
+        Prio3Config::Histogram {
+            length,
+            chunk_length,
+        } => {
+            let vdaf = Prio3::new_histogram(2, *length, *chunk_length)
+                .map_err(|e| VdafError::Dap(fatal_error!(err = ?e)))?;
+            Ok(VdafAggregateShare::Field128(merge(&vdaf, agg_shares)?))
+        }
+        Prio3Config::Sum { bits } => {
+            let vdaf =
+                Prio3::new_sum(2, *bits).map_err(|e| VdafError::Dap(fatal_error!(err = ?e)))?;
+            Ok(VdafAggregateShare::Field128(merge(&vdaf, agg_shares)?))
+        }
+        Prio3Config::SumVec {
+            bits,
+            length,
+            chunk_length,
+        } => {
+            let vdaf = Prio3::new_sum_vec(2, *bits, *length, *chunk_length)
+                .map_err(|e| VdafError::Dap(fatal_error!(err = ?e)))?;
+            Ok(VdafAggregateShare::Field128(merge(&vdaf, agg_shares)?))
+        }
+        Prio3Config::SumVecField64MultiproofHmacSha256Aes128 {
+            bits,
+            length,
+            chunk_length,
+            num_proofs,
+        } => {
+            let vdaf = new_prio3_sum_vec_field64_multiproof_hmac_sha256_aes128(
+                *bits,
+                *length,
+                *chunk_length,
+                *num_proofs,
+            )?;
+            Ok(VdafAggregateShare::Field64(merge(&vdaf, agg_shares)?))
+        }
+    };
+
+    fn merge<T, P, M, const SEED_SIZE: usize>(
+        vdaf: &Prio3<T, P, SEED_SIZE>,
+        agg_shares: M,
+    ) -> Result<AggregateShare<T::Field>, VdafError>
+    where
+        T: prio::flp::Type,
+        P: prio::vdaf::xof::Xof<SEED_SIZE>,
+        M: IntoIterator<Item = Vec<u8>>,
+    {
+        let mut agg: Option<AggregateShare<T::Field>> = None;
+        for data in agg_shares {
+            let agg_share = AggregateShare::get_decoded_with_param(&(vdaf, &()), data.as_ref())?;
+            match agg.as_mut() {
+                Some(agg) => agg.merge(&agg_share)?,
+                None => agg = Some(agg_share),
+            }
+        }
+        agg.ok_or_else(|| VdafError::Dap(fatal_error!(err = "unexpected number of agg shares")))
+    }
+}
+
 #[cfg(test)]
 mod test {
 
     use prio::vdaf::prio3_test::check_test_vec;
 
+    use assert_matches::assert_matches;
+
     use crate::{
         async_test_versions,
+        error::{DapAbort, MeasurementError},
         hpke::HpkeKemId,
+        messages::TaskId,
+        test_versions,
         testing::AggregationJobTest,
         vdaf::{
             prio3::new_prio3_sum_vec_field64_multiproof_hmac_sha256_aes128, Prio3Config, VdafConfig,
         },
-        DapAggregateResult, DapAggregationParam, DapMeasurement, DapVersion,
+        DapAggregateResult, DapAggregationParam, DapError, DapMeasurement, DapVersion,
     };
 
     async fn roundtrip_count(version: DapVersion) {
@@ -684,12 +810,27 @@ mod test {
                     DapMeasurement::U64(0),
                 ],
             )
-            .await;
+            .await
+            .unwrap();
         assert_eq!(got, DapAggregateResult::U64(3));
     }
 
     async_test_versions! { roundtrip_count }
 
+    async fn roundtrip_no_measurements(version: DapVersion) {
+        let mut t = AggregationJobTest::new(
+            &VdafConfig::Prio3(Prio3Config::Count),
+            HpkeKemId::X25519HkdfSha256,
+            version,
+        );
+        assert_matches!(
+            t.roundtrip(DapAggregationParam::Empty, Vec::new()).await,
+            Err(DapAbort::InvalidBatchSize { .. })
+        );
+    }
+
+    async_test_versions! { roundtrip_no_measurements }
+
     async fn roundtrip_sum(version: DapVersion) {
         let mut t = AggregationJobTest::new(
             &VdafConfig::Prio3(Prio3Config::Sum { bits: 23 }),
@@ -707,7 +848,8 @@ mod test {
                     DapMeasurement::U64(0),
                 ],
             )
-            .await;
+            .await
+            .unwrap();
         assert_eq!(got, DapAggregateResult::U128(1342));
     }
 
@@ -732,7 +874,8 @@ mod test {
                     DapMeasurement::U128Vec(vec![1, 1]),
                 ],
             )
-            .await;
+            .await
+            .unwrap();
         assert_eq!(got, DapAggregateResult::U128Vec(vec![1338, 1338]));
     }
 
@@ -758,12 +901,91 @@ mod test {
                     DapMeasurement::U64(2),
                 ],
             )
-            .await;
+            .await
+            .unwrap();
         assert_eq!(got, DapAggregateResult::U128Vec(vec![1, 1, 3]));
     }
 
     async_test_versions! { roundtrip_histogram }
 
+    fn produce_report_validates_measurement(version: DapVersion) {
+        let histogram = VdafConfig::Prio3(Prio3Config::Histogram {
+            length: 3,
+            chunk_length: 1,
+        });
+        let sum_vec = VdafConfig::Prio3(Prio3Config::SumVec {
+            bits: 1,
+            length: 2,
+            chunk_length: 1,
+        });
+        for (i, (vdaf_config, measurement, expected)) in [
+            (
+                VdafConfig::Prio3(Prio3Config::Count),
+                DapMeasurement::U64(1),
+                Ok(()),
+            ),
+            (
+                VdafConfig::Prio3(Prio3Config::Count),
+                DapMeasurement::U64(2),
+                Err(MeasurementError::OutOfRange),
+            ),
+            (
+                VdafConfig::Prio3(Prio3Config::Count),
+                DapMeasurement::U64Vec(vec![1]),
+                Err(MeasurementError::UnexpectedType),
+            ),
+            (
+                VdafConfig::Prio3(Prio3Config::Sum { bits: 8 }),
+                DapMeasurement::U64(255),
+                Ok(()),
+            ),
+            (
+                VdafConfig::Prio3(Prio3Config::Sum { bits: 8 }),
+                DapMeasurement::U64(256),
+                Err(MeasurementError::OutOfRange),
+            ),
+            (histogram, DapMeasurement::U64(2), Ok(())),
+            (
+                histogram,
+                DapMeasurement::U64(3),
+                Err(MeasurementError::OutOfRange),
+            ),
+            (sum_vec, DapMeasurement::U128Vec(vec![1, 0]), Ok(())),
+            (
+                sum_vec,
+                DapMeasurement::U128Vec(vec![1, 0, 1]),
+                Err(MeasurementError::OutOfRange),
+            ),
+        ]
+        .into_iter()
+        .enumerate()
+        {
+            let t = AggregationJobTest::new(&vdaf_config, HpkeKemId::X25519HkdfSha256, version);
+            assert_eq!(
+                vdaf_config.validate_measurement(&measurement),
+                expected,
+                "case {i}"
+            );
+            let produced = vdaf_config.produce_report(
+                &t.client_hpke_config_list,
+                t.now,
+                &t.task_id,
+                measurement,
+                version,
+            );
+            match expected {
+                Ok(()) => assert!(produced.is_ok(), "case {i}"),
+                Err(e) => assert_matches!(
+                    produced,
+                    Err(DapError::Measurement(got)) if got == e,
+                    "case {i}"
+                ),
+            }
+        }
+    }
+
+    test_versions! { produce_report_validates_measurement }
+
     async fn roundtrip_sum_vec_field64_multiproof_hmac_sha256_aes128(version: DapVersion) {
         let mut t = AggregationJobTest::new(
             &VdafConfig::Prio3(Prio3Config::SumVecField64MultiproofHmacSha256Aes128 {
@@ -784,7 +1006,8 @@ mod test {
                     DapMeasurement::U64Vec(vec![1, 1]),
                 ],
             )
-            .await;
+            .await
+            .unwrap();
         assert_eq!(got, DapAggregateResult::U64Vec(vec![1338, 1338]));
     }
 
@@ -813,4 +1036,35 @@ mod test {
             });
         }
     }
+
+    #[test]
+    fn self_test_sum() {
+        VdafConfig::Prio3(Prio3Config::Sum { bits: 8 })
+            .self_test(&TaskId([1; 32]))
+            .unwrap();
+    }
+
+    #[test]
+    fn self_test_histogram_without_buckets() {
+        assert_matches!(
+            VdafConfig::Prio3(Prio3Config::Histogram {
+                length: 0,
+                chunk_length: 1,
+            })
+            .self_test(&TaskId([1; 32])),
+            Err(DapAbort::InvalidTask { .. })
+        );
+    }
+
+    #[test]
+    fn self_test_skips_oversized_vdaf() {
+        // A length this large would exhaust memory if the VDAF were instantiated.
+        VdafConfig::Prio3(Prio3Config::SumVec {
+            bits: 1,
+            length: usize::MAX,
+            chunk_length: 1,
+        })
+        .self_test(&TaskId([1; 32]))
+        .unwrap();
+    }
 }
@@ -4,7 +4,7 @@
 use std::path::PathBuf;
 
 use clap::Parser;
-use daphne_server::{router, App, StorageProxyConfig};
+use daphne_server::{router, App, StorageProxy, StorageProxyConfig};
 use daphne_service_utils::{
     config::DaphneServiceConfig, metrics::DaphnePromServiceMetrics, DapRole,
 };
@@ -97,7 +97,14 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Sync + Send>> {
 
     let role = config.service.role;
     // Configure the application
-    let app = App::new(config.storage_proxy, daphne_service_metrics, config.service)?;
+    let app = App::new(
+        StorageProxy::new(config.storage_proxy)?,
+        daphne_service_metrics,
+        config.service,
+    )?;
+
+    // keep a handle for shutting down gracefully, since the router takes ownership of the app
+    let shutdown = app.shutdown_handle();
 
     // create the router that will handle the protocol's http requests
     let router = router::new(role, app);
@@ -113,6 +120,14 @@ async fn main() -> Result<(), Box<dyn std::error::Error + Sync + Send>> {
         config.port,
     ))
     .serve(router.into_make_service())
+    .with_graceful_shutdown(async move {
+        // on ctrl-c, refuse new work but keep serving requests until in-flight work has drained
+        tokio::signal::ctrl_c()
+            .await
+            .expect("failed to listen for ctrl-c");
+        shutdown.trigger();
+        shutdown.wait_for_drain().await;
+    })
     .await?;
 
     Ok(())
@@ -1,29 +1,43 @@
 // Copyright (c) 2024 Cloudflare, Inc. All rights reserved.
 // SPDX-License-Identifier: BSD-3-Clause
 
-use std::sync::Arc;
+use std::{
+    collections::{HashMap, HashSet},
+    sync::Arc,
+};
 
-use daphne::{auth::BearerToken, testing::MockLeaderMemory, DapError};
-use daphne_service_utils::{config::DaphneServiceConfig, metrics::DaphneServiceMetrics};
+use daphne::{
+    auth::BearerToken, fatal_error, messages::TaskId, roles::leader::UploadDedupCache,
+    testing::MockLeaderMemory, DapError,
+};
+use daphne_service_utils::{
+    compression, config::DaphneServiceConfig, metrics::DaphneServiceMetrics,
+};
 use futures::lock::Mutex;
 use serde::{Deserialize, Serialize};
+use storage_backend::StorageBackend;
 use storage_proxy_connection::{kv, Do, Kv};
-use tokio::sync::RwLock;
+use tokio::sync::{OwnedMutexGuard, RwLock};
 use url::Url;
 
 mod roles;
 pub mod router;
+mod shutdown;
+pub mod storage_backend;
 mod storage_proxy_connection;
+#[cfg(test)]
+mod test_utils;
+
+pub use shutdown::{InFlight, Shutdown};
+pub use storage_proxy_connection::StorageProxy;
 
 /// Entrypoint to the server implementation. This struct implements
 /// [`DapLeader`](daphne::roles::DapLeader) and [`DapHelper`](daphne::roles::DapHelper) and can be
 /// passed to the router.
 ///
-/// It depends on a cloudflare worker to do it's storage using durable objects.
-///
 /// It can be constructed from:
-/// - a `url` that points to a cloudflare worker which serves as proxy for the storage
-/// implementation.
+/// - a [`StorageBackend`]. In production this is a [`StorageProxy`], which points to a cloudflare
+/// worker that serves as proxy for the storage implementation.
 /// - an implementation of [`DaphneServiceMetrics`].
 /// - a [`DaphneServiceConfig`].
 ///
@@ -31,12 +45,13 @@ mod storage_proxy_connection;
 /// ```
 /// use url::Url;
 /// use daphne::{DapGlobalConfig, hpke::HpkeKemId, DapVersion};
-/// use daphne_server::{App, router, StorageProxyConfig};
+/// use daphne_server::{App, router, StorageProxy, StorageProxyConfig};
 /// use daphne_service_utils::{config::DaphneServiceConfig, DapRole, metrics::DaphnePromServiceMetrics};
 ///
 /// let storage_proxy_settings = StorageProxyConfig {
 ///     url: Url::parse("http://example.com").unwrap(),
 ///     auth_token: "some-token".into(),
+///     tls_client_identity: None,
 /// };
 /// let registry = prometheus::Registry::new();
 /// let daphne_service_metrics = DaphnePromServiceMetrics::register(&registry).unwrap();
@@ -46,6 +61,19 @@ mod storage_proxy_connection;
 ///     max_batch_interval_end: 259_200,
 ///     supported_hpke_kems: vec![HpkeKemId::X25519HkdfSha256],
 ///     allow_taskprov: true,
+///     agg_share_idempotency_window: 3600,
+///     revoked_hpke_config_ids: Vec::new(),
+///     upload_dedup_cache_capacity: 0,
+///     max_concurrent_agg_jobs_per_task: 0,
+///     eager_agg_threshold: None,
+///     max_report_shares_per_agg_job: None,
+///     agg_job_resp_chunk_size: None,
+///     work_item_visibility_timeout: None,
+///     agg_job_retry_budget: 0,
+///     agg_job_retry_backoff: 0,
+///     report_debug_sample_rate: 0.0,
+///     taskprov_vdaf_self_test: false,
+///     allow_http_urls: false,
 /// };
 /// let service_config = DaphneServiceConfig {
 ///     env: "some-machine-identifier".into(),
@@ -58,8 +86,18 @@ mod storage_proxy_connection;
 ///     default_version: DapVersion::DraftLatest,
 ///     report_storage_epoch_duration: 300,
 ///     report_storage_max_future_time_skew: 300,
+///     max_request_body_bytes: Some(1 << 20),
+///     max_request_body_bytes_per_media_type: Default::default(),
+///     hpke_config_max_age: Some(3600),
+///     work_queue_watermarks: None,
+///     vdaf_worker_threads: None,
+///     compression: None,
 /// };
-/// let app = App::new(storage_proxy_settings, daphne_service_metrics, service_config)?;
+/// let app = App::new(
+///     StorageProxy::new(storage_proxy_settings).unwrap(),
+///     daphne_service_metrics,
+///     service_config,
+/// )?;
 ///
 /// let router = router::new(DapRole::Helper, app);
 ///
@@ -68,7 +106,7 @@ mod storage_proxy_connection;
 /// # Ok::<(), daphne::DapError>(())
 /// ```
 pub struct App {
-    storage_proxy_config: StorageProxyConfig,
+    storage: Box<dyn StorageBackend>,
     http: reqwest::Client,
     cache: RwLock<kv::Cache>,
     metrics: Box<dyn DaphneServiceMetrics>,
@@ -78,6 +116,32 @@ pub struct App {
     /// colleciton requests. Note that in a production Leader, it is necessary to store this state
     /// across requsets.
     test_leader_state: Arc<Mutex<MockLeaderMemory>>,
+
+    /// Leader: Recently uploaded reports, used to skip redundant writes for retried uploads.
+    upload_dedup_cache: UploadDedupCache,
+
+    /// Graceful shutdown state. See [`App::shutdown_handle`].
+    shutdown: Arc<Shutdown>,
+
+    /// Leader: Notified whenever a collection job finishes, waking up long-polling Collectors.
+    collection_job_finished: tokio::sync::Notify,
+
+    /// Thread pool for CPU-bound VDAF work, if one is configured. See
+    /// [`DaphneServiceConfig::vdaf_worker_threads`].
+    vdaf_pool: Option<Arc<rayon::ThreadPool>>,
+
+    /// Leader: Origins of the peers that accept gzip-compressed request bodies, as advertised by
+    /// the `Accept-Encoding` header of their responses. Request bodies are only compressed when
+    /// sent to one of these.
+    peers_accepting_gzip: Mutex<HashSet<String>>,
+
+    /// Locks that serialize the writes of each task's config. See [`App::lock_task_config`].
+    task_config_locks: Mutex<HashMap<TaskId, Arc<tokio::sync::Mutex<()>>>>,
+
+    /// Leader: Whether the aggregation jobs interrupted by a restart have been resumed. See
+    /// [`App::resume_agg_jobs_once`].
+    #[cfg(any(test, feature = "test-utils"))]
+    agg_jobs_resumed: Mutex<bool>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -85,41 +149,142 @@ pub struct StorageProxyConfig {
     pub url: Url,
     #[serde(with = "transparent_auth_token")]
     pub auth_token: BearerToken,
+    /// Client certificate to present to the storage proxy, if it requires mutual TLS. The bearer
+    /// token is sent either way.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tls_client_identity: Option<TlsClientIdentity>,
+}
+
+/// A PEM encoded certificate chain and the matching private key, used to authenticate to the
+/// storage proxy over mutual TLS. The private key must be in PKCS#8, RSA or SEC1 format.
+#[derive(Serialize, Deserialize)]
+pub struct TlsClientIdentity {
+    pub cert_pem: String,
+    pub key_pem: String,
+}
+
+impl std::fmt::Debug for TlsClientIdentity {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TlsClientIdentity")
+            .field("cert_pem", &self.cert_pem)
+            .field("key_pem", &"<redacted>")
+            .finish()
+    }
 }
 
 impl router::DaphneService for App {
     fn server_metrics(&self) -> &dyn DaphneServiceMetrics {
         &*self.metrics
     }
+
+    fn hpke_config_max_age(&self) -> Option<daphne::messages::Duration> {
+        self.service_config.hpke_config_max_age
+    }
+
+    fn capabilities(&self) -> daphne_service_utils::config::Capabilities {
+        self.service_config.capabilities()
+    }
+
+    fn collection_job_finished(&self) -> Option<&tokio::sync::Notify> {
+        Some(&self.collection_job_finished)
+    }
 }
 
 impl App {
     /// Create a new configured app. See [`App`] for details.
-    pub fn new<M>(
-        storage_proxy_config: StorageProxyConfig,
+    pub fn new<B, M>(
+        storage: B,
         daphne_service_metrics: M,
         service_config: DaphneServiceConfig,
     ) -> Result<Self, DapError>
     where
+        B: StorageBackend + 'static,
         M: DaphneServiceMetrics + 'static,
     {
+        let upload_dedup_cache =
+            UploadDedupCache::new(service_config.global.upload_dedup_cache_capacity);
+        let test_leader_state = MockLeaderMemory::with_report_sharding(
+            service_config.report_shard_key,
+            service_config.report_shard_count,
+        );
+        let vdaf_pool = service_config
+            .vdaf_worker_threads
+            .map(|num_threads| {
+                rayon::ThreadPoolBuilder::new()
+                    .num_threads(num_threads)
+                    .thread_name(|i| format!("vdaf-worker-{i}"))
+                    .build()
+                    .map(Arc::new)
+                    .map_err(|e| fatal_error!(err = ?e, "failed to build VDAF worker pool"))
+            })
+            .transpose()?;
         Ok(Self {
-            storage_proxy_config,
+            storage: Box::new(storage),
             http: reqwest::Client::new(),
             cache: Default::default(),
             metrics: Box::new(daphne_service_metrics),
             service_config,
-            test_leader_state: Default::default(),
+            test_leader_state: Arc::new(Mutex::new(test_leader_state)),
+            upload_dedup_cache,
+            shutdown: Default::default(),
+            collection_job_finished: Default::default(),
+            vdaf_pool,
+            peers_accepting_gzip: Default::default(),
+            task_config_locks: Default::default(),
+            #[cfg(any(test, feature = "test-utils"))]
+            agg_jobs_resumed: Default::default(),
         })
     }
 
+    /// Return a handle for shutting down the app gracefully. Once [`Shutdown::trigger`] is
+    /// called, new uploads and aggregation jobs are refused with "503 Service Unavailable", while
+    /// work already in flight is allowed to complete. [`Shutdown::wait_for_drain`] resolves once
+    /// it has.
+    pub fn shutdown_handle(&self) -> Arc<Shutdown> {
+        self.shutdown.clone()
+    }
+
     pub(crate) fn durable(&self) -> Do<'_> {
-        Do::new(&self.storage_proxy_config, &self.http)
+        Do::new(&*self.storage)
     }
 
     pub(crate) fn kv(&self) -> Kv<'_> {
-        Kv::new(&self.storage_proxy_config, &self.http, &self.cache)
+        Kv::new(&*self.storage, &self.cache)
     }
+
+    /// Serialize the writes of a task's config within this process. The guard is held from
+    /// reading the current config until the new one is stored, so that a write that depends on the
+    /// current config can't be interleaved with another write.
+    pub(crate) async fn lock_task_config(&self, task_id: &TaskId) -> OwnedMutexGuard<()> {
+        let lock = {
+            let mut locks = self.task_config_locks.lock().await;
+            // Forget the locks that are neither held nor waited for.
+            locks.retain(|_task_id, lock| Arc::strong_count(lock) > 1);
+            locks.entry(*task_id).or_default().clone()
+        };
+        lock.lock_owned().await
+    }
+}
+
+/// Bodies at least this long are compressed on a blocking thread rather than on the async runtime.
+const BLOCKING_COMPRESSION_MIN_BYTES: usize = 1 << 16;
+
+/// Compress a request or response body. See [`compression::compress`].
+async fn compress_body(body: Vec<u8>) -> Result<Vec<u8>, tokio::task::JoinError> {
+    if body.len() < BLOCKING_COMPRESSION_MIN_BYTES {
+        return Ok(compression::compress(&body));
+    }
+    tokio::task::spawn_blocking(move || compression::compress(&body)).await
+}
+
+/// Decompress a request or response body. See [`compression::decompress`]. The size of the output
+/// isn't known in advance, so this is always done on a blocking thread.
+async fn decompress_body(
+    body: Vec<u8>,
+    max_decompressed_bytes: usize,
+) -> Result<Result<Vec<u8>, compression::DecompressError>, tokio::task::JoinError> {
+    tokio::task::spawn_blocking(move || compression::decompress(&body, max_decompressed_bytes))
+        .await
 }
 
 mod transparent_auth_token {
@@ -142,7 +307,7 @@ mod transparent_auth_token {
     //! TODO(mendes): Once the `dap_prototype` is removed we can make the change to remove this.
 
     use daphne::auth::BearerToken;
-    use serde::{Deserialize, Deserializer, Serializer};
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
 
     pub fn serialize<S>(value: &BearerToken, serializer: S) -> Result<S::Ok, S::Error>
     where
@@ -156,6 +321,81 @@ mod transparent_auth_token {
         D: Deserializer<'de>,
     {
         let s = String::deserialize(deserializer)?;
+        if s.is_empty() {
+            // An empty bearer token is never valid, so this can only be a misconfiguration.
+            return Err(D::Error::custom("auth_token must not be empty"));
+        }
         Ok(BearerToken::from(s))
     }
 }
+
+#[cfg(test)]
+mod test {
+    use serde_json::json;
+
+    use super::StorageProxyConfig;
+
+    fn storage_proxy_config_from_yaml(
+        yaml: &str,
+    ) -> Result<StorageProxyConfig, config::ConfigError> {
+        config::Config::builder()
+            .add_source(config::File::from_str(yaml, config::FileFormat::Yaml))
+            .build()?
+            .try_deserialize()
+    }
+
+    #[test]
+    fn storage_proxy_config_json_round_trip() {
+        let config: StorageProxyConfig = serde_json::from_value(json!({
+            "url": "http://localhost:4000",
+            "auth_token": "the-token",
+        }))
+        .unwrap();
+        assert_eq!(config.url.as_str(), "http://localhost:4000/");
+        assert_eq!(config.auth_token.as_str(), "the-token");
+
+        // The URL is serialized with a trailing slash and the token as a plain string.
+        let json = serde_json::to_value(&config).unwrap();
+        assert_eq!(
+            json,
+            json!({
+                "url": "http://localhost:4000/",
+                "auth_token": "the-token",
+            })
+        );
+
+        let round_tripped: StorageProxyConfig = serde_json::from_value(json).unwrap();
+        assert_eq!(round_tripped.url, config.url);
+        assert_eq!(round_tripped.auth_token.as_str(), "the-token");
+    }
+
+    #[test]
+    fn storage_proxy_config_yaml_round_trip() {
+        let config =
+            storage_proxy_config_from_yaml("url: http://localhost:4000\nauth_token: 'the-token'\n")
+                .unwrap();
+        assert_eq!(config.url.as_str(), "http://localhost:4000/");
+        assert_eq!(config.auth_token.as_str(), "the-token");
+
+        let round_tripped: StorageProxyConfig = config::Config::try_from(&config)
+            .unwrap()
+            .try_deserialize()
+            .unwrap();
+        assert_eq!(round_tripped.url, config.url);
+        assert_eq!(round_tripped.auth_token.as_str(), "the-token");
+    }
+
+    #[test]
+    fn storage_proxy_config_rejects_empty_auth_token() {
+        let err = serde_json::from_value::<StorageProxyConfig>(json!({
+            "url": "http://localhost:4000",
+            "auth_token": "",
+        }))
+        .unwrap_err();
+        assert!(err.to_string().contains("auth_token must not be empty"));
+
+        let err = storage_proxy_config_from_yaml("url: http://localhost:4000\nauth_token: ''\n")
+            .unwrap_err();
+        assert!(err.to_string().contains("auth_token must not be empty"));
+    }
+}
@@ -1,7 +1,7 @@
 // Copyright (c) 2024 Cloudflare, Inc. All rights reserved.
 // SPDX-License-Identifier: BSD-3-Clause
 
-use std::{borrow::Cow, time::SystemTime};
+use std::{borrow::Cow, collections::HashSet, ops::RangeInclusive, time::SystemTime};
 
 use axum::async_trait;
 use daphne::{
@@ -11,11 +11,14 @@ use daphne::{
     fatal_error,
     hpke::{HpkeConfig, HpkeDecrypter},
     messages::{
-        BatchId, BatchSelector, HpkeCiphertext, PartialBatchSelector, TaskId, Time,
+        BatchId, BatchSelector, HpkeCiphertext, PartialBatchSelector, ReportId, TaskId, Time,
         TransitionFailure,
     },
     metrics::DaphneMetrics,
-    roles::{aggregator::MergeAggShareError, DapAggregator, DapReportInitializer},
+    roles::{
+        aggregator::{CollectionPreview, MergeAggShareError},
+        DapAggregator, DapReportInitializer,
+    },
     DapAggregateShare, DapAggregateSpan, DapAggregationParam, DapBatchBucket, DapError,
     DapGlobalConfig, DapRequest, DapSender, DapTaskConfig, DapVersion, EarlyReportState,
     EarlyReportStateConsumed, EarlyReportStateInitialized,
@@ -35,6 +38,7 @@ impl DapAggregator<DaphneAuth> for crate::App {
         &self,
         task_id: &TaskId,
         task_config: &DapTaskConfig,
+        agg_param: &DapAggregationParam,
         agg_share_span: DapAggregateSpan<DapAggregateShare>,
     ) -> DapAggregateSpan<Result<(), MergeAggShareError>> {
         let task_id_hex = task_id.to_hex();
@@ -45,7 +49,7 @@ impl DapAggregator<DaphneAuth> for crate::App {
                 let result = durable
                     .request(
                         bindings::AggregateStore::Merge,
-                        (task_config.version, &task_id_hex, &bucket),
+                        (task_config.version, &task_id_hex, agg_param, &bucket),
                     )
                     .encode_bincode(AggregateStoreMergeReq {
                         contained_reports: report_metadatas.iter().map(|(id, _)| *id).collect(),
@@ -75,6 +79,7 @@ impl DapAggregator<DaphneAuth> for crate::App {
         &self,
         task_id: &TaskId,
         batch_sel: &BatchSelector,
+        agg_param: &DapAggregationParam,
     ) -> Result<DapAggregateShare, DapError> {
         let task_config = self
             .get_task_config_for(task_id)
@@ -83,12 +88,17 @@ impl DapAggregator<DaphneAuth> for crate::App {
 
         let durable = self.durable();
         let mut requests = Vec::new();
-        for bucket in task_config.as_ref().batch_span_for_sel(batch_sel)? {
+        for bucket in task_config.as_ref().batch_span_iter_for_sel(batch_sel)? {
             requests.push(
                 durable
                     .request(
                         bindings::AggregateStore::Get,
-                        (task_config.as_ref().version, &task_id.to_hex(), &bucket),
+                        (
+                            task_config.as_ref().version,
+                            &task_id.to_hex(),
+                            agg_param,
+                            &bucket,
+                        ),
                     )
                     .send(),
             );
@@ -108,6 +118,7 @@ impl DapAggregator<DaphneAuth> for crate::App {
         &self,
         task_id: &TaskId,
         batch_sel: &BatchSelector,
+        agg_param: &DapAggregationParam,
     ) -> Result<(), DapError> {
         let task_config = self
             .get_task_config_for(task_id)
@@ -116,12 +127,17 @@ impl DapAggregator<DaphneAuth> for crate::App {
 
         let durable = self.durable();
         let mut requests = Vec::new();
-        for bucket in task_config.as_ref().batch_span_for_sel(batch_sel)? {
+        for bucket in task_config.as_ref().batch_span_iter_for_sel(batch_sel)? {
             requests.push(
                 durable
                     .request(
                         bindings::AggregateStore::MarkCollected,
-                        (task_config.as_ref().version, &task_id.to_hex(), &bucket),
+                        (
+                            task_config.as_ref().version,
+                            &task_id.to_hex(),
+                            agg_param,
+                            &bucket,
+                        ),
                     )
                     .send::<()>(),
             );
@@ -235,10 +251,13 @@ impl DapAggregator<DaphneAuth> for crate::App {
 
     fn taskprov_opt_out_reason(
         &self,
-        _task_config: &DapTaskConfig,
+        task_config: &DapTaskConfig,
     ) -> Result<Option<String>, DapError> {
-        // For now we always opt-in.
-        Ok(None)
+        Ok(self
+            .service_config
+            .taskprov
+            .as_ref()
+            .and_then(|taskprov| taskprov.policy.opt_out_reason(task_config)))
     }
 
     async fn taskprov_put(
@@ -249,6 +268,12 @@ impl DapAggregator<DaphneAuth> for crate::App {
         let task_id = req.task_id().map_err(DapError::Abort)?;
 
         if self.service_config.role.is_leader() || req.taskprov.is_none() {
+            let _guard = self.lock_task_config(task_id).await;
+            // Drop the cached config first, so that if storing the new one fails we reload the
+            // config from storage rather than keep serving the old one.
+            self.kv()
+                .invalidate::<kv::prefix::TaskConfig>(task_id)
+                .await;
             self.kv()
                 .put::<kv::prefix::TaskConfig>(task_id, task_config)
                 .await
@@ -261,6 +286,31 @@ impl DapAggregator<DaphneAuth> for crate::App {
         Ok(())
     }
 
+    async fn replace_task_config_if_compatible(
+        &self,
+        task_id: &TaskId,
+        task_config: DapTaskConfig,
+    ) -> Result<(), DapError> {
+        // Hold the task's lock until the new config is stored, so that the config that is
+        // replaced is the one the new config was checked against.
+        let _guard = self.lock_task_config(task_id).await;
+        if let Some(current) = self
+            .kv()
+            .get::<kv::prefix::TaskConfig>(task_id)
+            .await
+            .map_err(|e| fatal_error!(err = ?e))?
+        {
+            current.check_compatible_update(task_id, &task_config)?;
+        }
+
+        // Overwriting the stored config also replaces the cached one, so there is no point at
+        // which neither config is served.
+        self.kv()
+            .put::<kv::prefix::TaskConfig>(task_id, task_config)
+            .await
+            .map_err(|e| fatal_error!(err = ?e))
+    }
+
     async fn get_task_config_for<'req>(
         &'req self,
         task_id: &'req TaskId,
@@ -278,11 +328,19 @@ impl DapAggregator<DaphneAuth> for crate::App {
             .as_secs()
     }
 
-    async fn is_batch_overlapping(
+    fn valid_report_time_range(&self) -> RangeInclusive<Time> {
+        let now = self.get_current_time();
+        let start = now.saturating_sub(self.service_config.report_storage_epoch_duration);
+        let end = now.saturating_add(self.service_config.report_storage_max_future_time_skew);
+        start..=end
+    }
+
+    async fn get_overlapping_buckets(
         &self,
         task_id: &TaskId,
         batch_sel: &BatchSelector,
-    ) -> Result<bool, DapError> {
+        agg_param: &DapAggregationParam,
+    ) -> Result<Vec<DapBatchBucket>, DapError> {
         let task_config = self
             .get_task_config_for(task_id)
             .await?
@@ -290,15 +348,24 @@ impl DapAggregator<DaphneAuth> for crate::App {
 
         // Check whether the request overlaps with previous requests. This is done by
         // checking the AggregateStore and seeing whether it requests for aggregate
-        // shares that have already been marked collected.
+        // shares that have already been marked collected under the same aggregation parameter.
         let durable = self.durable();
+        let buckets = task_config
+            .as_ref()
+            .batch_span_iter_for_sel(batch_sel)?
+            .collect::<Vec<_>>();
         let mut requests = Vec::new();
-        for bucket in task_config.as_ref().batch_span_for_sel(batch_sel)? {
+        for bucket in &buckets {
             requests.push(
                 durable
                     .request(
                         bindings::AggregateStore::CheckCollected,
-                        (task_config.as_ref().version, &task_id.to_hex(), &bucket),
+                        (
+                            task_config.as_ref().version,
+                            &task_id.to_hex(),
+                            agg_param,
+                            bucket,
+                        ),
                     )
                     .send(),
             );
@@ -308,13 +375,137 @@ impl DapAggregator<DaphneAuth> for crate::App {
             .await
             .map_err(|e| fatal_error!(err = ?e))?;
 
-        for collected in responses {
-            if collected {
-                return Ok(true);
+        Ok(buckets
+            .into_iter()
+            .zip(responses)
+            .filter_map(|(bucket, collected)| collected.then_some(bucket))
+            .collect())
+    }
+
+    async fn batch_ready_for_collection(
+        &self,
+        task_id: &TaskId,
+        batch_sel: &BatchSelector,
+        agg_param: &DapAggregationParam,
+    ) -> Result<bool, DapError> {
+        let task_config = self
+            .get_task_config_for(task_id)
+            .await?
+            .ok_or(DapError::Abort(DapAbort::UnrecognizedTask))?;
+        let span = task_config.as_ref().batch_span_for_sel(batch_sel)?;
+
+        Ok(!self
+            .test_leader_state
+            .lock()
+            .await
+            .has_unaggregated_reports(task_id, task_config.as_ref(), &span, agg_param))
+    }
+
+    async fn preview_collection(
+        &self,
+        task_id: &TaskId,
+        batch_sel: &BatchSelector,
+    ) -> Result<CollectionPreview, DapError> {
+        let task_config = self
+            .get_task_config_for(task_id)
+            .await?
+            .ok_or(DapError::Abort(DapAbort::UnrecognizedTask))?;
+        let span = task_config.as_ref().batch_span_for_sel(batch_sel)?;
+
+        // VDAFs with an aggregation parameter are only supported in tests, so only the aggregate
+        // shares of VDAFs without one are considered.
+        let agg_param = &DapAggregationParam::Empty;
+        let durable = self.durable();
+        let task_id_hex = task_id.to_hex();
+        let version = task_config.as_ref().version;
+        let agg_shares: Vec<DapAggregateShare> = try_join_all(span.iter().map(|bucket| {
+            durable
+                .request(
+                    bindings::AggregateStore::Get,
+                    (version, &task_id_hex, agg_param, bucket),
+                )
+                .send()
+        }))
+        .await
+        .map_err(|e| fatal_error!(err = ?e))?;
+        let collected: Vec<bool> = try_join_all(span.iter().map(|bucket| {
+            durable
+                .request(
+                    bindings::AggregateStore::CheckCollected,
+                    (version, &task_id_hex, agg_param, bucket),
+                )
+                .send()
+        }))
+        .await
+        .map_err(|e| fatal_error!(err = ?e))?;
+
+        Ok(CollectionPreview {
+            bucket_count: span.len(),
+            report_count: agg_shares
+                .iter()
+                .map(|agg_share| agg_share.report_count)
+                .sum(),
+            any_collected: collected.into_iter().any(|collected| collected),
+        })
+    }
+
+    async fn batch_report_ids(
+        &self,
+        task_id: &TaskId,
+        batch_sel: &BatchSelector,
+    ) -> Result<Vec<ReportId>, DapError> {
+        let task_config = self
+            .get_task_config_for(task_id)
+            .await?
+            .ok_or(DapError::Abort(DapAbort::UnrecognizedTask))?;
+
+        // VDAFs with an aggregation parameter are only supported in tests, so only the reports
+        // aggregated by VDAFs without one are considered.
+        let agg_param = &DapAggregationParam::Empty;
+        let durable = self.durable();
+        let task_id_hex = task_id.to_hex();
+        let version = task_config.as_ref().version;
+        let mut buckets = Vec::new();
+        let mut report_id_requests = Vec::new();
+        let mut collected_requests = Vec::new();
+        for bucket in task_config.as_ref().batch_span_iter_for_sel(batch_sel)? {
+            report_id_requests.push(
+                durable
+                    .request(
+                        bindings::AggregateStore::GetMerged,
+                        (version, &task_id_hex, agg_param, &bucket),
+                    )
+                    .send(),
+            );
+            collected_requests.push(
+                durable
+                    .request(
+                        bindings::AggregateStore::CheckCollected,
+                        (version, &task_id_hex, agg_param, &bucket),
+                    )
+                    .send(),
+            );
+            buckets.push(bucket);
+        }
+        let report_ids: Vec<HashSet<ReportId>> = try_join_all(report_id_requests)
+            .await
+            .map_err(|e| fatal_error!(err = ?e))?;
+        let collected: Vec<bool> = try_join_all(collected_requests)
+            .await
+            .map_err(|e| fatal_error!(err = ?e))?;
+
+        for (bucket, (report_ids, collected)) in
+            buckets.iter().zip(report_ids.iter().zip(collected))
+        {
+            if !report_ids.is_empty() && !collected {
+                return Err(DapError::Abort(DapAbort::BatchInvalid {
+                    detail: format!("The queried batch has not been collected ({bucket})."),
+                    task_id: *task_id,
+                }));
             }
         }
 
-        Ok(false)
+        Ok(report_ids.into_iter().flatten().collect())
     }
 
     async fn batch_exists(&self, task_id: &TaskId, batch_id: &BatchId) -> Result<bool, DapError> {
@@ -330,6 +521,8 @@ impl DapAggregator<DaphneAuth> for crate::App {
                 (
                     task_config.as_ref().version,
                     &task_id.to_hex(),
+                    // VDAFs with an aggregation parameter are only supported in tests.
+                    &DapAggregationParam::Empty,
                     &DapBatchBucket::FixedSize {
                         batch_id: *batch_id,
                     },
@@ -368,7 +561,7 @@ impl DapReportInitializer for crate::App {
     ) -> Result<Vec<EarlyReportStateInitialized>, DapError> {
         let valid_report_range = self.valid_report_time_range();
 
-        tokio::task::spawn_blocking({
+        self.run_vdaf_work({
             let vdaf_config = task_config.vdaf;
             let vdaf_verify_key = task_config.vdaf_verify_key.clone();
             let agg_param = agg_param.clone();
@@ -377,12 +570,12 @@ impl DapReportInitializer for crate::App {
                     .into_par_iter()
                     .map(|consumed_report| {
                         let metadata = consumed_report.metadata();
-                        let initialized = if metadata.time < valid_report_range.start {
+                        let initialized = if metadata.time < *valid_report_range.start() {
                             // If the report time is before the first valid timestamp, we drop it
                             // because it's too late.
                             consumed_report
                                 .into_initialized_rejected_due_to(TransitionFailure::ReportDropped)
-                        } else if valid_report_range.end < metadata.time {
+                        } else if *valid_report_range.end() < metadata.time {
                             // If the report time is too far in the future of the maximum allowed
                             // time skew so we reject it.
                             consumed_report
@@ -401,8 +594,25 @@ impl DapReportInitializer for crate::App {
                     .collect::<Result<Vec<EarlyReportStateInitialized>, _>>()
             }
         })
+        .await?
+    }
+}
+
+impl crate::App {
+    /// Run CPU-bound VDAF work off of the async runtime, on the VDAF worker pool if one is
+    /// configured.
+    pub(crate) async fn run_vdaf_work<F, T>(&self, work: F) -> Result<T, DapError>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let vdaf_pool = self.vdaf_pool.clone();
+        tokio::task::spawn_blocking(move || match vdaf_pool {
+            Some(vdaf_pool) => vdaf_pool.install(work),
+            None => work(),
+        })
         .await
-        .map_err(|e| fatal_error!(err = ?e, "initialzing reports panicked"))?
+        .map_err(|e| fatal_error!(err = ?e, "VDAF work panicked"))
     }
 }
 
@@ -417,16 +627,18 @@ impl HpkeDecrypter for crate::App {
         version: DapVersion,
         _task_id: Option<&TaskId>,
     ) -> Result<Self::WrappedHpkeConfig<'s>, DapError> {
+        let global_config = &self.service_config.global;
         self.kv()
             .get_mapped::<kv::prefix::HpkeReceiverConfigSet, _, _>(&version, |config_list| {
-                // Assume the first HPKE config in the receiver list has the highest preference.
+                // Assume the first HPKE config in the receiver list that has not been revoked has
+                // the highest preference.
                 //
                 // NOTE draft02 compatibility: The spec allows us to return multiple configs, but
                 // draft02 does not. In order to keep things imple we preserve the semantics of the old
                 // version for now.
                 config_list
                     .iter()
-                    .next()
+                    .find(|receiver| !global_config.is_hpke_config_revoked(receiver.config.id))
                     .map(|receiver| receiver.config.clone())
             })
             .await
@@ -435,6 +647,10 @@ impl HpkeDecrypter for crate::App {
     }
 
     async fn can_hpke_decrypt(&self, task_id: &TaskId, config_id: u8) -> Result<bool, DapError> {
+        if self.service_config.global.is_hpke_config_revoked(config_id) {
+            return Ok(false);
+        }
+
         let version = self
             .get_task_config_for(task_id)
             .await
@@ -462,24 +678,44 @@ impl HpkeDecrypter for crate::App {
         aad: &[u8],
         ciphertext: &HpkeCiphertext,
     ) -> Result<Vec<u8>, DapError> {
-        let version = self
-            .get_task_config_for(task_id)
-            .await?
-            .as_ref()
-            .ok_or(DapAbort::UnrecognizedTask)?
-            .version;
-        self.kv()
-            .get_mapped::<kv::prefix::HpkeReceiverConfigSet, _, _>(&version, |config_list| {
-                config_list
-                    .iter()
-                    .find(|receiver| receiver.config.id == ciphertext.config_id)
-                    .map(|receiver| {
-                        receiver.decrypt(info, aad, &ciphertext.enc, &ciphertext.payload)
-                    })
-            })
-            .await
-            .map_err(|e| fatal_error!(err = ?e))?
-            .ok_or(DapError::Transition(TransitionFailure::HpkeUnknownConfigId))?
+        let res: Result<Vec<u8>, DapError> = async {
+            if self
+                .service_config
+                .global
+                .is_hpke_config_revoked(ciphertext.config_id)
+            {
+                return Err(DapError::Transition(TransitionFailure::HpkeUnknownConfigId));
+            }
+
+            let version = self
+                .get_task_config_for(task_id)
+                .await?
+                .as_ref()
+                .ok_or(DapAbort::UnrecognizedTask)?
+                .version;
+            self.kv()
+                .get_mapped::<kv::prefix::HpkeReceiverConfigSet, _, _>(&version, |config_list| {
+                    config_list
+                        .iter()
+                        .find(|receiver| receiver.config.id == ciphertext.config_id)
+                        .map(|receiver| {
+                            receiver.decrypt(info, aad, &ciphertext.enc, &ciphertext.payload)
+                        })
+                })
+                .await
+                .map_err(|e| fatal_error!(err = ?e))?
+                .ok_or(DapError::Transition(TransitionFailure::HpkeUnknownConfigId))?
+        }
+        .await;
+
+        if let Err(DapError::Transition(failure)) = &res {
+            // Config IDs are only unique within an environment, so they are prefixed with the
+            // environment in logs and metrics to disambiguate them. This is never sent on the wire.
+            let config_id = format!("{}:{}", self.service_config.env, ciphertext.config_id);
+            tracing::warn!(hpke_config_id = config_id, %failure, "failed to decrypt input share");
+            self.metrics.daphne().hpke_decrypt_failure_inc(&config_id);
+        }
+        res
     }
 }
 
@@ -537,3 +773,318 @@ impl BearerTokenProvider for crate::App {
             .map_err(|e| fatal_error!(err = ?e))
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            mpsc, Arc, Mutex,
+        },
+        time::Duration,
+    };
+
+    use axum::{
+        body::Body,
+        http::{header::CONTENT_TYPE, Request, StatusCode},
+    };
+    use daphne::{
+        auth::BearerToken,
+        error::DapAbort,
+        hpke::{HpkeKemId, HpkeReceiverConfig},
+        messages::{
+            AggregationJobInitReq, AggregationJobResp, Base64Encode, TransitionFailure,
+            TransitionVar,
+        },
+        roles::DapAggregator,
+        testing::AggregationJobTest,
+        vdaf::{Prio3Config, VdafConfig},
+        DapAggregationParam, DapError, DapLeaderAggregationJobTransition, DapMeasurement,
+        DapVersion,
+    };
+    use daphne_service_utils::DapRole;
+    use prio::codec::{ParameterizedDecode, ParameterizedEncode};
+    use rayon::prelude::{IntoParallelIterator, ParallelIterator};
+    use tower::ServiceExt;
+
+    use crate::{
+        router,
+        storage_proxy_connection::kv,
+        test_utils::{test_app, test_app_with_registry, test_service_config},
+        App,
+    };
+
+    #[tokio::test]
+    async fn vdaf_work_runs_on_configured_pool() {
+        let mut service_config = test_service_config(DapRole::Helper);
+        service_config.vdaf_worker_threads = Some(2);
+        let app = test_app(service_config);
+
+        let (num_threads, thread_name) = app
+            .run_vdaf_work(|| {
+                (
+                    rayon::current_num_threads(),
+                    std::thread::current().name().map(ToOwned::to_owned),
+                )
+            })
+            .await
+            .unwrap();
+        assert_eq!(num_threads, 2);
+        assert!(thread_name.unwrap().starts_with("vdaf-worker-"));
+    }
+
+    // Saturate the VDAF worker pool and check that a lightweight request is still served. The test runtime has a single thread, so if VDAF work
+    // ran on it, the request couldn't be served until the work finished.
+    #[tokio::test]
+    async fn vdaf_work_does_not_starve_requests() {
+        const VDAF_WORKER_THREADS: usize = 2;
+        let mut service_config = test_service_config(DapRole::Helper);
+        service_config.vdaf_worker_threads = Some(VDAF_WORKER_THREADS);
+        let app = Arc::new(test_app(service_config.clone()));
+
+        let receiver = HpkeReceiverConfig::gen(1, HpkeKemId::X25519HkdfSha256).unwrap();
+        let other_app = test_app(service_config);
+        other_app
+            .kv()
+            .put::<kv::prefix::HpkeReceiverConfigSet>(&DapVersion::DraftLatest, vec![receiver])
+            .await
+            .unwrap();
+        let router = router::new(DapRole::Helper, other_app);
+
+        // Queue one more piece of work than there are workers. None of it finishes until it's
+        // released.
+        let started = Arc::new(AtomicUsize::new(0));
+        let (release_tx, release_rx) = mpsc::channel::<()>();
+        let release_rx = Arc::new(Mutex::new(release_rx));
+        let work = (0..=VDAF_WORKER_THREADS)
+            .map(|_| {
+                let app = Arc::clone(&app);
+                let started = Arc::clone(&started);
+                let release_rx = Arc::clone(&release_rx);
+                tokio::spawn(async move {
+                    app.run_vdaf_work(move || {
+                        started.fetch_add(1, Ordering::SeqCst);
+                        release_rx.lock().unwrap().recv().unwrap();
+                        (0..100_000_u64).into_par_iter().map(|x| x % 7).sum::<u64>()
+                    })
+                    .await
+                })
+            })
+            .collect::<Vec<_>>();
+
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while started.load(Ordering::SeqCst) < VDAF_WORKER_THREADS {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("VDAF work didn't start");
+
+        let resp = tokio::time::timeout(
+            Duration::from_secs(5),
+            router.oneshot(
+                Request::builder()
+                    .uri("/v09/hpke_config")
+                    .body(Body::empty())
+                    .unwrap(),
+            ),
+        )
+        .await
+        .expect("request was starved by VDAF work")
+        .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        for _ in 0..=VDAF_WORKER_THREADS {
+            release_tx.send(()).unwrap();
+        }
+        for work in work {
+            let sum = work.await.unwrap().unwrap();
+            assert_eq!(sum, (0..100_000_u64).map(|x| x % 7).sum::<u64>());
+        }
+        assert_eq!(started.load(Ordering::SeqCst), VDAF_WORKER_THREADS + 1);
+    }
+
+    /// Configure the task of `t` on the Helper, with "leader-token" as the Leader's bearer token.
+    async fn put_task(app: &App, t: &AggregationJobTest) {
+        app.kv()
+            .put::<kv::prefix::TaskConfig>(t.task_id(), t.task_config().clone())
+            .await
+            .unwrap();
+        app.kv()
+            .put::<kv::prefix::HpkeReceiverConfigSet>(
+                &t.task_config().version,
+                vec![t.helper_hpke_receiver_config().clone()],
+            )
+            .await
+            .unwrap();
+        app.kv()
+            .put::<kv::prefix::LeaderBearerToken>(t.task_id(), BearerToken::from("leader-token"))
+            .await
+            .unwrap();
+    }
+
+    /// Send `agg_job_init_req` to the Helper and return its response.
+    async fn send_agg_job_init_req(
+        router: axum::Router,
+        t: &AggregationJobTest,
+        agg_job_init_req: &AggregationJobInitReq,
+    ) -> AggregationJobResp {
+        let version = t.task_config().version;
+        let resp = router
+            .oneshot(
+                Request::builder()
+                    .method("PUT")
+                    .uri(format!(
+                        "/v09/tasks/{}/aggregation_jobs/{}",
+                        t.task_id().to_base64url(),
+                        t.agg_job_id().to_base64url(),
+                    ))
+                    .header(CONTENT_TYPE, "application/dap-aggregation-job-init-req")
+                    .header("DAP-Auth-Token", "leader-token")
+                    .body(Body::from(
+                        agg_job_init_req.get_encoded_with_param(&version).unwrap(),
+                    ))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        AggregationJobResp::get_decoded_with_param(&version, &body).unwrap()
+    }
+
+    async fn produce_agg_job_init_req(
+        t: &AggregationJobTest,
+        measurements: Vec<DapMeasurement>,
+    ) -> AggregationJobInitReq {
+        let reports = t.produce_reports(measurements);
+        let DapLeaderAggregationJobTransition::Continued(_, agg_job_init_req) = t
+            .produce_agg_job_init_req(&DapAggregationParam::Empty, reports)
+            .await
+        else {
+            panic!("unexpected transition");
+        };
+        agg_job_init_req
+    }
+
+    // Drive an aggregation job initialization request through the Helper's router with the VDAF
+    // worker pool configured, so that the reports are initialized on the pool.
+    #[tokio::test]
+    async fn agg_job_init_on_vdaf_worker_pool() {
+        let mut service_config = test_service_config(DapRole::Helper);
+        service_config.vdaf_worker_threads = Some(2);
+        let app = test_app(service_config);
+
+        let t = AggregationJobTest::new(
+            &VdafConfig::Prio3(Prio3Config::Count),
+            HpkeKemId::X25519HkdfSha256,
+            DapVersion::DraftLatest,
+        );
+        put_task(&app, &t).await;
+        let router = router::new(DapRole::Helper, app);
+
+        let agg_job_init_req = produce_agg_job_init_req(&t, vec![DapMeasurement::U64(1); 10]).await;
+        let agg_job_resp = send_agg_job_init_req(router, &t, &agg_job_init_req).await;
+        assert_eq!(agg_job_resp.transitions.len(), 10);
+        for transition in agg_job_resp.transitions {
+            assert!(
+                matches!(transition.var, TransitionVar::Continued(..)),
+                "{:?}",
+                transition.var
+            );
+        }
+    }
+
+    // HPKE config IDs are only unique within an environment, so decryption failures are counted
+    // under the config ID prefixed with the environment.
+    #[tokio::test]
+    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+    async fn hpke_decrypt_failure_counted_by_env() {
+        let service_config = test_service_config(DapRole::Helper);
+        let env = service_config.env.clone();
+        let registry = prometheus::Registry::new();
+        let app = test_app_with_registry(service_config, &registry);
+
+        let t = AggregationJobTest::new(
+            &VdafConfig::Prio3(Prio3Config::Count),
+            HpkeKemId::X25519HkdfSha256,
+            DapVersion::DraftLatest,
+        );
+        put_task(&app, &t).await;
+        let router = router::new(DapRole::Helper, app);
+
+        let mut agg_job_init_req = produce_agg_job_init_req(&t, vec![DapMeasurement::U64(1)]).await;
+        let encrypted_input_share = &mut agg_job_init_req.prep_inits[0]
+            .report_share
+            .encrypted_input_share;
+        encrypted_input_share.payload[0] ^= 0xff; // Cause decryption to fail
+        let config_id = encrypted_input_share.config_id;
+
+        let agg_job_resp = send_agg_job_init_req(router, &t, &agg_job_init_req).await;
+        assert_eq!(
+            agg_job_resp.transitions[0].var,
+            TransitionVar::Failed(TransitionFailure::HpkeDecryptError)
+        );
+
+        let counter = registry
+            .gather()
+            .into_iter()
+            .find(|family| family.get_name() == "hpke_decrypt_failure_counter")
+            .unwrap();
+        let [metric] = counter.get_metric() else {
+            panic!("expected a single config ID");
+        };
+        assert_eq!(
+            metric.get_label()[0].get_value(),
+            format!("{env}:{config_id}")
+        );
+        assert_eq!(metric.get_counter().get_value() as u64, 1);
+    }
+
+    // Writes of a task's config are serialized, so that a replacement is checked against the
+    // config it replaces.
+    #[tokio::test]
+    async fn replace_task_config_serializes_writes() {
+        let app = Arc::new(test_app(test_service_config(DapRole::Helper)));
+        let t = AggregationJobTest::new(
+            &VdafConfig::Prio3(Prio3Config::Count),
+            HpkeKemId::X25519HkdfSha256,
+            DapVersion::DraftLatest,
+        );
+        put_task(&app, &t).await;
+        let task_id = *t.task_id();
+        let expiration = t.task_config().expiration;
+        let replace = |extension| {
+            let app = Arc::clone(&app);
+            let mut task_config = t.task_config().clone();
+            task_config.expiration = expiration + extension;
+            async move {
+                app.replace_task_config_if_compatible(&task_id, task_config)
+                    .await
+            }
+        };
+
+        // A replacement waits for the write that is in progress.
+        let guard = app.lock_task_config(&task_id).await;
+        let first = tokio::spawn(replace(2));
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert!(!first.is_finished());
+        drop(guard);
+        first.await.unwrap().unwrap();
+
+        // The next replacement is checked against the new config, which expires later.
+        assert!(matches!(
+            replace(1).await,
+            Err(DapError::Abort(DapAbort::TaskprovMismatch { .. }))
+        ));
+        assert_eq!(
+            app.get_task_config_for(&task_id)
+                .await
+                .unwrap()
+                .unwrap()
+                .expiration,
+            expiration + 2
+        );
+    }
+}
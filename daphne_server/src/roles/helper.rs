@@ -1,16 +1,21 @@
 // Copyright (c) 2024 Cloudflare, Inc. All rights reserved.
 // SPDX-License-Identifier: BSD-3-Clause
 
+use std::collections::HashSet;
+
 use axum::async_trait;
 use daphne::{
     error::DapAbort,
     fatal_error,
-    messages::TaskId,
+    messages::{AggregationJobResp, TaskId, Time},
     roles::{DapAggregator, DapHelper},
-    DapAggregationJobState, DapError, MetaAggregationJobId,
+    DapAggregationJobState, DapAggregationParam, DapBatchBucket, DapError, DapRetainedAggShare,
+    MetaAggregationJobId,
 };
 use daphne_service_utils::{auth::DaphneAuth, durable_requests::bindings};
-use prio::codec::Encode;
+use prio::codec::{Decode, Encode};
+
+use crate::storage_proxy_connection::kv;
 
 #[async_trait]
 impl DapHelper<DaphneAuth> for crate::App {
@@ -77,4 +82,169 @@ impl DapHelper<DaphneAuth> for crate::App {
             None => Ok(None),
         }
     }
+
+    async fn purge_expired_helper_state(
+        &self,
+        _task_id: &TaskId,
+        _older_than: Time,
+    ) -> Result<(), DapError> {
+        // Nothing to do: the helper state durable objects schedule their own deletion with an
+        // alarm when they are written.
+        Ok(())
+    }
+
+    async fn check_and_set_bucket_agg_param(
+        &self,
+        task_id: &TaskId,
+        buckets: &HashSet<DapBatchBucket>,
+        agg_param: &DapAggregationParam,
+    ) -> Result<Vec<DapBatchBucket>, DapError> {
+        let agg_param_hex = hex::encode(agg_param.get_encoded().map_err(DapError::encoding)?);
+        let kv = self.kv();
+        let mut mismatched = Vec::new();
+        for bucket in buckets {
+            let key = bucket_agg_param_key(task_id, bucket);
+            if kv
+                .put_if_not_exists::<kv::prefix::BucketAggParam>(&key, agg_param_hex.clone())
+                .await
+                .map_err(|e| fatal_error!(err = ?e))?
+                .is_none()
+            {
+                continue;
+            }
+
+            let bucket_agg_param_hex = kv
+                .get::<kv::prefix::BucketAggParam>(&key)
+                .await
+                .map_err(|e| fatal_error!(err = ?e))?;
+            if bucket_agg_param_hex.as_ref() != Some(&agg_param_hex) {
+                mismatched.push(bucket.clone());
+            }
+        }
+        Ok(mismatched)
+    }
+
+    async fn put_agg_job_resp(
+        &self,
+        task_id: &TaskId,
+        agg_job_id: &MetaAggregationJobId,
+        agg_job_resp: &AggregationJobResp,
+    ) -> Result<(), DapError> {
+        let task_config = self
+            .get_task_config_for(task_id)
+            .await?
+            .ok_or(DapError::Abort(DapAbort::UnrecognizedTask))?;
+        let agg_job_resp_hex = hex::encode(agg_job_resp.get_encoded().map_err(DapError::encoding)?);
+        self.durable()
+            .with_retry()
+            .request(
+                bindings::HelperState::PutAggJobResp,
+                (task_config.as_ref().version, task_id, agg_job_id),
+            )
+            .encode_bincode(agg_job_resp_hex)
+            .send()
+            .await
+            .map_err(|e| fatal_error!(err = ?e))
+    }
+
+    async fn get_agg_job_resp(
+        &self,
+        task_id: &TaskId,
+        agg_job_id: &MetaAggregationJobId,
+    ) -> Result<Option<AggregationJobResp>, DapError> {
+        let task_config = self
+            .get_task_config_for(task_id)
+            .await?
+            .ok_or(DapError::Abort(DapAbort::UnrecognizedTask))?;
+        let Some(agg_job_resp_hex) = self
+            .durable()
+            .with_retry()
+            .request(
+                bindings::HelperState::GetAggJobResp,
+                (task_config.as_ref().version, task_id, agg_job_id),
+            )
+            .send::<Option<String>>()
+            .await
+            .map_err(|e| fatal_error!(err = ?e))?
+        else {
+            return Ok(None);
+        };
+        let data =
+            hex::decode(agg_job_resp_hex).map_err(|e| DapAbort::from_hex_error(e, *task_id))?;
+        Ok(Some(
+            AggregationJobResp::get_decoded(&data).map_err(|e| fatal_error!(err = ?e))?,
+        ))
+    }
+
+    async fn delete_agg_job_resp(
+        &self,
+        task_id: &TaskId,
+        agg_job_id: &MetaAggregationJobId,
+    ) -> Result<(), DapError> {
+        let task_config = self
+            .get_task_config_for(task_id)
+            .await?
+            .ok_or(DapError::Abort(DapAbort::UnrecognizedTask))?;
+        self.durable()
+            .with_retry()
+            .request(
+                bindings::HelperState::DeleteAggJobResp,
+                (task_config.as_ref().version, task_id, agg_job_id),
+            )
+            .send()
+            .await
+            .map_err(|e| fatal_error!(err = ?e))
+    }
+
+    async fn put_retained_agg_share(
+        &self,
+        task_id: &TaskId,
+        idempotency_token: &str,
+        agg_param: &DapAggregationParam,
+        retained_agg_share: &DapRetainedAggShare,
+    ) -> Result<(), DapError> {
+        self.kv()
+            .put_with_expiration::<kv::prefix::RetainedAggShare>(
+                &retained_agg_share_key(task_id, idempotency_token, agg_param)?,
+                retained_agg_share.clone(),
+                retained_agg_share.expiration,
+            )
+            .await
+            .map_err(|e| fatal_error!(err = ?e))
+    }
+
+    async fn get_retained_agg_share(
+        &self,
+        task_id: &TaskId,
+        idempotency_token: &str,
+        agg_param: &DapAggregationParam,
+    ) -> Result<Option<DapRetainedAggShare>, DapError> {
+        self.kv()
+            .get::<kv::prefix::RetainedAggShare>(&retained_agg_share_key(
+                task_id,
+                idempotency_token,
+                agg_param,
+            )?)
+            .await
+            .map_err(|e| fatal_error!(err = ?e))
+    }
+}
+
+/// The idempotency token is chosen by the Leader, so it is hex-encoded to make it safe for use in
+/// the storage key, as is the aggregation parameter.
+fn retained_agg_share_key(
+    task_id: &TaskId,
+    idempotency_token: &str,
+    agg_param: &DapAggregationParam,
+) -> Result<String, DapError> {
+    Ok(format!(
+        "{}/{}/{}",
+        task_id.to_hex(),
+        hex::encode(idempotency_token),
+        hex::encode(agg_param.get_encoded().map_err(DapError::encoding)?)
+    ))
+}
+
+fn bucket_agg_param_key(task_id: &TaskId, bucket: &DapBatchBucket) -> String {
+    format!("{}/{bucket}", task_id.to_hex())
 }
@@ -9,13 +9,22 @@ use axum::{async_trait, http::Method};
 use daphne::{
     auth::BearerTokenProvider,
     constants::DapMediaType,
-    error::DapAbort,
+    error::{DapAbort, PeerError},
     fatal_error,
-    messages::{BatchId, BatchSelector, Collection, CollectionJobId, Report, TaskId},
-    roles::{leader::WorkItem, DapAggregator, DapAuthorizedSender, DapLeader},
-    DapAggregationParam, DapCollectionJob, DapError, DapRequest, DapResponse, DapTaskConfig,
+    messages::{BatchId, Collection, CollectionJobId, Interval, Query, Report, TaskId},
+    roles::{
+        leader::{
+            AggregationJobCheckpoint, UploadDedupCache, WorkItem, WorkItemId, WorkQueueStats,
+        },
+        DapAggregator, DapAuthorizedSender, DapLeader,
+    },
+    testing::MockLeaderMemory,
+    DapAggregationJobStatus, DapAggregationParam, DapCollectionJob, DapCollectionJobInfo, DapError,
+    DapRequest, DapResponse, DapTaskConfig, MetaAggregationJobId,
+};
+use daphne_service_utils::{
+    auth::DaphneAuth, compression, durable_requests::bindings::LeaderAggJobCheckpointStore,
 };
-use daphne_service_utils::auth::DaphneAuth;
 use tracing::{error, info};
 use url::Url;
 
@@ -45,19 +54,41 @@ impl DapAuthorizedSender<DaphneAuth> for crate::App {
 
 #[async_trait]
 impl DapLeader<DaphneAuth> for crate::App {
+    fn upload_dedup_cache(&self) -> Option<&UploadDedupCache> {
+        Some(&self.upload_dedup_cache)
+    }
+
     async fn put_report(&self, report: &Report, task_id: &TaskId) -> Result<(), DapError> {
         let task_config = self
             .get_task_config_for(task_id)
             .await?
             .ok_or(DapAbort::UnrecognizedTask)?;
 
+        let mut leader_state = self.test_leader_state.lock().await;
+        leader_state.put_report(
+            task_id,
+            &task_config,
+            report.clone(),
+            &self.service_config.global,
+            self.service_config.work_queue_watermarks,
+        )?;
+        self.update_pending_gauges(&leader_state);
+        Ok(())
+    }
+
+    async fn current_batch(&self, task_id: &TaskId) -> Result<BatchId, DapError> {
+        let task_config = self
+            .get_task_config_for(task_id)
+            .await?
+            .ok_or(DapError::Abort(DapAbort::UnrecognizedTask))?;
+
         self.test_leader_state
             .lock()
             .await
-            .put_report(task_id, &task_config, report.clone())
+            .current_batch(task_id, &task_config)
     }
 
-    async fn current_batch(&self, task_id: &TaskId) -> Result<BatchId, DapError> {
+    async fn reserve_current_batch(&self, task_id: &TaskId) -> Result<BatchId, DapError> {
         let task_config = self
             .get_task_config_for(task_id)
             .await?
@@ -66,28 +97,46 @@ impl DapLeader<DaphneAuth> for crate::App {
         self.test_leader_state
             .lock()
             .await
-            .current_batch(task_id, &task_config)
+            .reserve_current_batch(task_id, &task_config)
     }
 
     async fn init_collect_job(
         &self,
         task_id: &TaskId,
         coll_job_id: &Option<CollectionJobId>,
-        batch_sel: BatchSelector,
+        query: Query,
         agg_param: DapAggregationParam,
+        collector_hpke_config_id: Option<u8>,
     ) -> Result<url::Url, DapError> {
         let task_config = self
             .get_task_config_for(task_id)
             .await?
             .ok_or(DapAbort::UnrecognizedTask)?;
 
-        self.test_leader_state.lock().await.init_collect_job(
+        let mut leader_state = self.test_leader_state.lock().await;
+        let coll_job_uri = leader_state.init_collect_job(
             task_id,
             &task_config,
             coll_job_id,
-            batch_sel,
+            query,
             agg_param,
-        )
+            collector_hpke_config_id,
+            &self.service_config.global,
+        )?;
+        self.update_pending_gauges(&leader_state);
+        Ok(coll_job_uri)
+    }
+
+    async fn batch_time_range(
+        &self,
+        task_id: &TaskId,
+        batch_id: &BatchId,
+    ) -> Result<Option<Interval>, DapError> {
+        Ok(self
+            .test_leader_state
+            .lock()
+            .await
+            .batch_time_range(task_id, batch_id))
     }
 
     async fn poll_collect_job(
@@ -101,6 +150,32 @@ impl DapLeader<DaphneAuth> for crate::App {
             .poll_collect_job(task_id, coll_job_id)
     }
 
+    async fn list_collection_jobs(
+        &self,
+        task_id: &TaskId,
+    ) -> Result<Vec<(CollectionJobId, DapCollectionJobInfo)>, DapError> {
+        let task_config = self
+            .get_task_config_for(task_id)
+            .await?
+            .ok_or(DapAbort::UnrecognizedTask)?;
+
+        self.test_leader_state
+            .lock()
+            .await
+            .list_collection_jobs(task_id, &task_config)
+    }
+
+    async fn retry_collect_job(
+        &self,
+        task_id: &TaskId,
+        coll_job_id: &CollectionJobId,
+    ) -> Result<(), DapError> {
+        self.test_leader_state
+            .lock()
+            .await
+            .retry_collect_job(task_id, coll_job_id)
+    }
+
     async fn finish_collect_job(
         &self,
         task_id: &TaskId,
@@ -110,17 +185,128 @@ impl DapLeader<DaphneAuth> for crate::App {
         self.test_leader_state
             .lock()
             .await
-            .finish_collect_job(task_id, coll_job_id, collection)
+            .finish_collect_job(task_id, coll_job_id, collection)?;
+        self.collection_job_finished.notify_waiters();
+        Ok(())
+    }
+
+    async fn agg_job_status(
+        &self,
+        task_id: &TaskId,
+        agg_job_id: &MetaAggregationJobId,
+    ) -> Result<Option<DapAggregationJobStatus>, DapError> {
+        self.test_leader_state
+            .lock()
+            .await
+            .agg_job_status(task_id, agg_job_id)
     }
 
-    async fn dequeue_work(&self, num_items: usize) -> Result<Vec<WorkItem>, DapError> {
-        self.test_leader_state.lock().await.dequeue_work(num_items)
+    async fn set_agg_job_status(
+        &self,
+        task_id: &TaskId,
+        agg_job_id: &MetaAggregationJobId,
+        status: DapAggregationJobStatus,
+    ) -> Result<(), DapError> {
+        self.test_leader_state
+            .lock()
+            .await
+            .set_agg_job_status(task_id, agg_job_id, status)
+    }
+
+    async fn replace_agg_job_id(
+        &self,
+        task_id: &TaskId,
+        agg_job_id: &MetaAggregationJobId,
+        retry_agg_job_id: &MetaAggregationJobId,
+    ) -> Result<(), DapError> {
+        self.test_leader_state.lock().await.replace_agg_job_id(
+            task_id,
+            agg_job_id,
+            retry_agg_job_id,
+        )
+    }
+
+    async fn put_agg_job_checkpoint(
+        &self,
+        checkpoint: AggregationJobCheckpoint,
+    ) -> Result<(), DapError> {
+        self.durable()
+            .with_retry()
+            .request(
+                LeaderAggJobCheckpointStore::Put,
+                LeaderAggJobCheckpointStore::shard(&checkpoint.agg_job_id),
+            )
+            .encode_bincode(checkpoint)
+            .send()
+            .await
+            .map_err(|e| fatal_error!(err = ?e))
+    }
+
+    async fn delete_agg_job_checkpoint(
+        &self,
+        task_id: &TaskId,
+        agg_job_id: &MetaAggregationJobId,
+    ) -> Result<(), DapError> {
+        self.durable()
+            .with_retry()
+            .request(
+                LeaderAggJobCheckpointStore::Delete,
+                LeaderAggJobCheckpointStore::shard(agg_job_id),
+            )
+            .encode_bincode((task_id, agg_job_id))
+            .send()
+            .await
+            .map_err(|e| fatal_error!(err = ?e))
+    }
+
+    async fn list_agg_job_checkpoints(&self) -> Result<Vec<AggregationJobCheckpoint>, DapError> {
+        let durable = self.durable().with_retry();
+        let shards = futures::future::try_join_all(
+            (0..LeaderAggJobCheckpointStore::SHARD_COUNT).map(|shard| {
+                durable
+                    .request(LeaderAggJobCheckpointStore::List, shard)
+                    .send::<Vec<AggregationJobCheckpoint>>()
+            }),
+        )
+        .await
+        .map_err(|e| fatal_error!(err = ?e))?;
+        Ok(shards.into_iter().flatten().collect())
+    }
+
+    async fn dequeue_work(
+        &self,
+        num_items: usize,
+    ) -> Result<Vec<(WorkItemId, WorkItem)>, DapError> {
+        // Don't start new work during shutdown. Work that was already dequeued runs to completion.
+        if self.shutdown.is_triggered() {
+            return Ok(Vec::new());
+        }
+
+        let now = self.get_current_time();
+        let mut leader_state = self.test_leader_state.lock().await;
+        leader_state.reclaim_expired_leases(now)?;
+        let work_items = leader_state.dequeue_work(
+            num_items,
+            self.service_config.global.max_concurrent_agg_jobs_per_task,
+            now,
+            self.service_config.global.work_item_visibility_timeout(),
+        )?;
+        self.update_pending_gauges(&leader_state);
+        Ok(work_items)
+    }
+
+    async fn ack_work(&self, item_ids: Vec<WorkItemId>) -> Result<(), DapError> {
+        self.test_leader_state.lock().await.ack_work(&item_ids)
     }
 
     async fn enqueue_work(&self, items: Vec<WorkItem>) -> Result<(), DapError> {
         self.test_leader_state.lock().await.enqueue_work(items)
     }
 
+    async fn work_queue_stats(&self) -> Result<WorkQueueStats, DapError> {
+        Ok(self.test_leader_state.lock().await.work_queue_stats())
+    }
+
     async fn send_http_post(
         &self,
         req: DapRequest<DaphneAuth>,
@@ -139,6 +325,49 @@ impl DapLeader<DaphneAuth> for crate::App {
 }
 
 impl crate::App {
+    /// Finish the aggregation jobs that were interrupted by a restart of the Leader (see
+    /// [`resume_agg_jobs`](daphne::roles::leader::resume_agg_jobs)). This is only done by the first call, which must happen before any
+    /// work is processed; subsequent calls return zero. Returns the number of reports aggregated.
+    #[cfg(any(test, feature = "test-utils"))]
+    pub(crate) async fn resume_agg_jobs_once(&self) -> Result<u64, DapError> {
+        let mut resumed = self.agg_jobs_resumed.lock().await;
+        if *resumed {
+            return Ok(0);
+        }
+        // Jobs that can't be resumed are logged by `resume_agg_jobs()`. Don't try again if listing
+        // the checkpoints fails, as that would keep the Leader from processing any work.
+        *resumed = true;
+        daphne::roles::leader::resume_agg_jobs(self).await
+    }
+
+    /// Report the queue depth to the pending-reports gauges.
+    fn update_pending_gauges(&self, leader_state: &MockLeaderMemory) {
+        self.metrics
+            .leader_pending_reports_set(leader_state.pending_report_count());
+        self.metrics
+            .leader_pending_buckets_set(leader_state.pending_bucket_count());
+    }
+
+    /// Whether the peer at `url` has advertised that it accepts gzip-compressed request bodies.
+    async fn peer_accepts_gzip(&self, url: &Url) -> bool {
+        self.peers_accepting_gzip
+            .lock()
+            .await
+            .contains(&url.origin().ascii_serialization())
+    }
+
+    /// Remember whether the peer at `url` accepts gzip-compressed request bodies, as advertised by
+    /// the `Accept-Encoding` header of one of its responses.
+    async fn note_peer_accept_encoding(&self, url: &Url, accept_encoding: Option<&str>) {
+        let origin = url.origin().ascii_serialization();
+        let mut peers = self.peers_accepting_gzip.lock().await;
+        if accept_encoding.is_some_and(compression::accepts_gzip) {
+            peers.insert(origin);
+        } else {
+            peers.remove(&origin);
+        }
+    }
+
     async fn send_http(
         &self,
         req: DapRequest<DaphneAuth>,
@@ -185,19 +414,74 @@ impl crate::App {
             );
         }
 
+        if let Some(idempotency_token) = req.idempotency_token.as_deref() {
+            headers.insert(
+                HeaderName::from_static("dap-idempotency-token"),
+                HeaderValue::from_str(idempotency_token).map_err(
+                    |e| fatal_error!(err = ?e, "failed to construct dap-idempotency-token header"),
+                )?,
+            );
+        }
+
+        if let Some(vdaf_version) = req.vdaf_version.as_deref() {
+            headers.insert(
+                HeaderName::from_static("dap-vdaf-version"),
+                HeaderValue::from_str(vdaf_version).map_err(
+                    |e| fatal_error!(err = ?e, "failed to construct dap-vdaf-version header"),
+                )?,
+            );
+        }
+
+        if let Some(collector_hpke_config_id) = req.collector_hpke_config_id {
+            headers.insert(
+                HeaderName::from_static("dap-collector-hpke-config-id"),
+                HeaderValue::from(u16::from(collector_hpke_config_id)),
+            );
+        }
+
+        let compression_config = self.service_config.compression;
+        let mut payload = req.payload;
+        if let Some(config) = compression_config {
+            headers.insert(
+                header::ACCEPT_ENCODING,
+                HeaderValue::from_static(compression::GZIP),
+            );
+            // A peer that doesn't accept compressed bodies would reject the request, so only
+            // compress once the peer has said that it does.
+            if config.should_compress(payload.len()) && self.peer_accepts_gzip(&url).await {
+                payload = crate::compress_body(payload)
+                    .await
+                    .map_err(|e| fatal_error!(err = ?e, "failed to compress request body"))?;
+                headers.insert(
+                    header::CONTENT_ENCODING,
+                    HeaderValue::from_static(compression::GZIP),
+                );
+            }
+        }
+
         let req_builder = self
             .http
             .request(method, url.clone())
-            .body(req.payload)
+            .body(payload)
             .headers(headers);
 
         let start = Instant::now();
-        let reqwest_resp = req_builder
-            .send()
-            .await
-            .map_err(|e| fatal_error!(err = ?e))?;
+        let reqwest_resp = req_builder.send().await.map_err(|e| {
+            error!("{url}: request failed: {e:?}");
+            PeerError::Unreachable(e.to_string())
+        })?;
         info!("request to {} completed in {:?}", url, start.elapsed());
         let status = reqwest_resp.status();
+        if compression_config.is_some() {
+            self.note_peer_accept_encoding(
+                &url,
+                reqwest_resp
+                    .headers()
+                    .get(header::ACCEPT_ENCODING)
+                    .and_then(|v| v.to_str().ok()),
+            )
+            .await;
+        }
 
         const INT_ERR_PEER_ABORT: &str = "request aborted by peer";
         const INT_ERR_PEER_RESP_MISSING_MEDIA_TYPE: &str = "peer response is missing media type";
@@ -212,14 +496,34 @@ impl crate::App {
                 .find_map(|h| DapMediaType::from_str_for_version(req.version, Some(h)))
                 .ok_or_else(|| fatal_error!(err = INT_ERR_PEER_RESP_MISSING_MEDIA_TYPE))?;
 
-            let payload = reqwest_resp
-                .bytes()
-                .await
-                .map_err(|e| fatal_error!(err = ?e))?
-                .to_vec();
+            let content_encoding = reqwest_resp
+                .headers()
+                .get(header::CONTENT_ENCODING)
+                .map(|v| v.to_str().unwrap_or_default().to_string());
+            let payload = reqwest_resp.bytes().await.map_err(|e| {
+                error!("{url}: failed to read response: {e:?}");
+                PeerError::Unreachable(e.to_string())
+            })?;
+            let payload = match (content_encoding, compression_config) {
+                (None, _) => payload.to_vec(),
+                (Some(encoding), _) if compression::is_identity(&encoding) => payload.to_vec(),
+                (Some(encoding), Some(config)) if compression::is_gzip(&encoding) => {
+                    crate::decompress_body(payload.to_vec(), config.max_decompressed_body_bytes)
+                        .await
+                        .map_err(|e| fatal_error!(err = ?e, "failed to decompress peer response"))?
+                        .map_err(|e| fatal_error!(err = ?e, "failed to decompress peer response"))?
+                }
+                (Some(encoding), _) => {
+                    return Err(fatal_error!(
+                        err = "peer response has unsupported content-encoding",
+                        %encoding
+                    ))
+                }
+            };
 
             Ok(DapResponse {
                 version: req.version,
+                status: status.as_u16(),
                 payload,
                 media_type,
             })
@@ -240,7 +544,156 @@ impl crate::App {
                     }
                 }
             }
+            if status.is_server_error() {
+                return Err(PeerError::ServerError {
+                    status: status.as_u16(),
+                }
+                .into());
+            }
             Err(fatal_error!(err = INT_ERR_PEER_ABORT))
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use daphne::{
+        hpke::{HpkeKemId, HpkeReceiverConfig},
+        messages::{
+            HpkeCiphertext, Interval, Query, Report, ReportId, ReportMetadata, TaskId, Time,
+        },
+        roles::{leader::AggregationJobCheckpoint, DapAggregator, DapLeader},
+        DapAggregationParam, DapTaskParameters, DapVersion, MetaAggregationJobId,
+    };
+    use daphne_service_utils::{metrics::DaphnePromServiceMetrics, DapRole};
+    use prometheus::Registry;
+
+    use crate::{
+        storage_backend::InMemoryStorageBackend, storage_proxy_connection::kv,
+        test_utils::test_service_config, App,
+    };
+
+    fn test_app_with_storage(storage: InMemoryStorageBackend) -> App {
+        test_app_with_registry(storage, &Registry::new())
+    }
+
+    fn test_app_with_registry(storage: InMemoryStorageBackend, registry: &Registry) -> App {
+        App::new(
+            storage,
+            DaphnePromServiceMetrics::register(registry).unwrap(),
+            test_service_config(DapRole::Leader),
+        )
+        .unwrap()
+    }
+
+    /// Return the value of the integer gauge called `name` in the registry.
+    #[allow(clippy::cast_possible_truncation)]
+    fn gauge(registry: &Registry, name: &str) -> i64 {
+        let family = registry
+            .gather()
+            .into_iter()
+            .find(|family| family.get_name() == name)
+            .unwrap_or_else(|| panic!("{name} is not registered"));
+        family.get_metric()[0].get_gauge().get_value() as i64
+    }
+
+    #[tokio::test]
+    async fn agg_job_checkpoint_survives_restart() {
+        let storage = InMemoryStorageBackend::default();
+        let checkpoint = AggregationJobCheckpoint {
+            task_id: TaskId([1; 32]),
+            agg_job_id: MetaAggregationJobId::gen_for_version(DapVersion::DraftLatest),
+            agg_param: Vec::new(),
+            state: vec![1, 2, 3],
+            agg_job_resp: vec![4, 5, 6],
+        };
+
+        // The Leader stores a checkpoint, then goes away before committing the job.
+        test_app_with_storage(storage.clone())
+            .put_agg_job_checkpoint(checkpoint.clone())
+            .await
+            .unwrap();
+
+        // The checkpoint is still there when the Leader comes back up.
+        let app = test_app_with_storage(storage);
+        assert_eq!(
+            app.list_agg_job_checkpoints().await.unwrap(),
+            vec![checkpoint]
+        );
+
+        // Resuming consumes it. (The task isn't configured, so the job is dropped.)
+        assert_eq!(app.resume_agg_jobs_once().await.unwrap(), 0);
+        assert_eq!(app.list_agg_job_checkpoints().await.unwrap(), Vec::new());
+    }
+
+    #[tokio::test]
+    async fn pending_gauges_track_queue_depth() {
+        let registry = Registry::new();
+        let app = test_app_with_registry(InMemoryStorageBackend::default(), &registry);
+        let now = app.get_current_time();
+        let collector_hpke_config = HpkeReceiverConfig::gen(1, HpkeKemId::X25519HkdfSha256)
+            .unwrap()
+            .config;
+        let (task_config, task_id, _, _) = DapTaskParameters::default()
+            .to_config_with_taskprov(b"gauges".to_vec(), now, &[0; 32], &collector_hpke_config)
+            .unwrap();
+        app.kv()
+            .put::<kv::prefix::TaskConfig>(&task_id, task_config.clone())
+            .await
+            .unwrap();
+
+        let report = |id: u8, time: Time| Report {
+            draft02_task_id: None,
+            report_metadata: ReportMetadata {
+                id: ReportId([id; 16]),
+                time,
+                draft02_extensions: None,
+            },
+            public_share: Vec::new(),
+            encrypted_input_shares: [
+                HpkeCiphertext {
+                    config_id: 0,
+                    enc: Vec::new(),
+                    payload: Vec::new(),
+                },
+                HpkeCiphertext {
+                    config_id: 0,
+                    enc: Vec::new(),
+                    payload: Vec::new(),
+                },
+            ],
+        };
+        let batch_start = task_config.quantized_time_lower_bound(now);
+        for id in 0..3 {
+            app.put_report(&report(id, batch_start), &task_id)
+                .await
+                .unwrap();
+        }
+        app.put_report(
+            &report(3, batch_start - task_config.time_precision),
+            &task_id,
+        )
+        .await
+        .unwrap();
+        assert_eq!(gauge(&registry, "leader_pending_reports"), 4);
+        assert_eq!(gauge(&registry, "leader_pending_buckets"), 2);
+
+        // Collecting the newer bucket queues its reports for aggregation.
+        app.init_collect_job(
+            &task_id,
+            &None,
+            Query::TimeInterval {
+                batch_interval: Interval {
+                    start: batch_start,
+                    duration: task_config.time_precision,
+                },
+            },
+            DapAggregationParam::Empty,
+            None,
+        )
+        .await
+        .unwrap();
+        assert_eq!(gauge(&registry, "leader_pending_reports"), 1);
+        assert_eq!(gauge(&registry, "leader_pending_buckets"), 1);
+    }
+}
@@ -1,26 +1,10 @@
 // Copyright (c) 2024 Cloudflare, Inc. All rights reserved.
 // SPDX-License-Identifier: BSD-3-Clause
 
-use std::{ops::Range, time::SystemTime};
-
 mod aggregator;
 mod helper;
 mod leader;
 
-impl crate::App {
-    pub(crate) fn valid_report_time_range(&self) -> Range<u64> {
-        let now = SystemTime::now()
-            .duration_since(SystemTime::UNIX_EPOCH)
-            .expect("now should always be after unix epoch")
-            .as_secs();
-
-        let start = now.saturating_sub(self.service_config.report_storage_epoch_duration);
-        let end = now.saturating_add(self.service_config.report_storage_max_future_time_skew);
-
-        start..end
-    }
-}
-
 #[cfg(feature = "test-utils")]
 mod test_utils {
     use daphne::{
@@ -28,41 +12,64 @@ mod test_utils {
         fatal_error,
         hpke::{HpkeConfig, HpkeReceiverConfig},
         messages::decode_base64url_vec,
+        roles::DapAggregator,
+        testing::MockMemoryUsage,
         vdaf::{Prio3Config, VdafConfig},
         DapError, DapQueryConfig, DapTaskConfig, DapVersion,
     };
     use daphne_service_utils::{
+        durable_requests::{
+            bindings::{self, DurableMethod},
+            KV_PATH_PREFIX,
+        },
         test_route_types::{InternalTestAddTask, InternalTestEndpointForTask},
         DapRole,
     };
     use prio::codec::Decode;
 
-    use crate::storage_proxy_connection::{kv, DAP_STORAGE_AUTH_TOKEN};
+    use crate::storage_proxy_connection::kv::{self, KvPrefix};
 
     impl crate::App {
         pub(crate) async fn internal_delete_all(&self) -> Result<(), DapError> {
             self.test_leader_state.lock().await.delete_all();
 
-            use daphne_service_utils::durable_requests::PURGE_STORAGE;
             *self.cache.write().await = Default::default();
 
-            self.http
-                .delete(self.storage_proxy_config.url.join(PURGE_STORAGE).unwrap())
-                .header(
-                    DAP_STORAGE_AUTH_TOKEN,
-                    self.storage_proxy_config
-                        .auth_token
-                        .to_standard_header_value(),
-                )
-                .send()
+            self.storage
+                .purge()
                 .await
-                .map_err(|e| fatal_error!(err = ?e))?
-                .error_for_status()
                 .map_err(|e| fatal_error!(err = ?e))?;
 
             Ok(())
         }
 
+        /// Measure the memory held by the server, broken down by component. This is only possible
+        /// if the server's storage is kept in memory.
+        pub(crate) async fn internal_memory_usage(&self) -> Result<MockMemoryUsage, DapError> {
+            let storage = self.storage.memory_usage().ok_or_else(|| {
+                fatal_error!(err = "memory usage is only available for in-memory storage")
+            })?;
+            let task_config_prefix =
+                format!("{KV_PATH_PREFIX}/{}/", kv::prefix::TaskConfig::PREFIX);
+            let durable = |binding: &str| storage.durable.get(binding).copied().unwrap_or_default();
+
+            let report_store = self.test_leader_state.lock().await.pending_reports_size();
+            Ok(MockMemoryUsage {
+                tasks: storage
+                    .kv
+                    .iter()
+                    .filter(|(key, _)| key.starts_with(&task_config_prefix))
+                    .map(|(_, size)| size)
+                    .sum(),
+                report_store,
+                agg_store: durable(bindings::AggregateStore::BINDING),
+                helper_state_store: durable(bindings::HelperState::BINDING),
+                total: storage.kv.values().sum::<usize>()
+                    + storage.durable.values().sum::<usize>()
+                    + report_store,
+            })
+        }
+
         pub(crate) fn internal_endpoint_for_task(
             &self,
             version: DapVersion,
@@ -111,6 +118,7 @@ mod test_utils {
                 }
                 _ => return Err(fatal_error!(err = "command failed: unrecognized VDAF")),
             };
+            vdaf.self_test(&cmd.task_id)?;
 
             // VDAF verification key.
             let vdaf_verify_key_data = decode_base64url_vec(cmd.vdaf_verify_key.as_bytes())
@@ -190,24 +198,33 @@ mod test_utils {
                 }
             };
 
-            if self
-                .kv()
-                .put_if_not_exists::<kv::prefix::TaskConfig>(
+            let task_config = DapTaskConfig {
+                version,
+                leader_url: cmd.leader,
+                helper_url: cmd.helper,
+                time_precision: cmd.time_precision,
+                time_quantization_offset: 0,
+                align_batch_intervals: false,
+                expiration: cmd.task_expiration,
+                min_batch_size: cmd.min_batch_size,
+                query,
+                vdaf,
+                vdaf_verify_key,
+                collector_hpke_config,
+                collector_hpke_config_allowlist: Vec::new(),
+                method: Default::default(),
+            };
+            task_config
+                .validate(
                     &cmd.task_id,
-                    DapTaskConfig {
-                        version,
-                        leader_url: cmd.leader,
-                        helper_url: cmd.helper,
-                        time_precision: cmd.time_precision,
-                        expiration: cmd.task_expiration,
-                        min_batch_size: cmd.min_batch_size,
-                        query,
-                        vdaf,
-                        vdaf_verify_key,
-                        collector_hpke_config,
-                        method: Default::default(),
-                    },
+                    &self.service_config.global,
+                    self.get_current_time(),
                 )
+                .map_err(DapError::Abort)?;
+
+            if self
+                .kv()
+                .put_if_not_exists::<kv::prefix::TaskConfig>(&cmd.task_id, task_config)
                 .await
                 .map_err(|e| fatal_error!(err = ?e))?
                 .is_some()
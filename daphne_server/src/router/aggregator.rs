@@ -6,6 +6,11 @@ use std::sync::Arc;
 use axum::{
     body::HttpBody,
     extract::{Query, State},
+    http::{
+        header::{CACHE_CONTROL, ETAG, IF_NONE_MATCH},
+        HeaderMap, HeaderValue, StatusCode,
+    },
+    response::{IntoResponse, Response},
     routing::get,
 };
 use daphne::{
@@ -36,19 +41,63 @@ struct QueryTaskId {
     task_id: Option<TaskId>,
 }
 
-#[tracing::instrument(skip(app, req), fields(version = ?req.version))]
+#[tracing::instrument(skip(app, headers, req), fields(version = ?req.version))]
 async fn hpke_config<A>(
     State(app): State<Arc<A>>,
     Query(QueryTaskId { task_id }): Query<QueryTaskId>,
+    headers: HeaderMap,
     DapRequestExtractor(req): DapRequestExtractor,
-) -> AxumDapResponse
+) -> Response
 where
     A: DapAggregator<DaphneAuth> + DaphneService,
 {
-    AxumDapResponse::from_result(
-        aggregator::handle_hpke_config_req(&*app, &req, task_id).await,
-        app.server_metrics(),
-    )
+    let resp = match aggregator::handle_hpke_config_req(&*app, &req, task_id).await {
+        Ok(resp) => resp,
+        Err(e) => return AxumDapResponse::new_error(e, app.server_metrics()).into_response(),
+    };
+
+    // The config only changes when keys are rotated, so let clients cache it and revalidate it
+    // cheaply.
+    let etag = HeaderValue::from_str(&format!(
+        "\"{}\"",
+        hex::encode(ring::digest::digest(&ring::digest::SHA256, &resp.payload))
+    ))
+    .expect("hex is a valid header value");
+    let mut cache_headers = HeaderMap::new();
+    if let Some(max_age) = app.hpke_config_max_age() {
+        cache_headers.insert(
+            CACHE_CONTROL,
+            HeaderValue::from_str(&format!("max-age={max_age}"))
+                .expect("integer is a valid header value"),
+        );
+    }
+
+    if if_none_match(&headers, &etag) {
+        cache_headers.insert(ETAG, etag);
+        return (StatusCode::NOT_MODIFIED, cache_headers).into_response();
+    }
+
+    let mut response = AxumDapResponse::new_success(resp, app.server_metrics()).into_response();
+    if response.status().is_success() {
+        cache_headers.insert(ETAG, etag);
+        response.headers_mut().extend(cache_headers);
+    }
+    response
+}
+
+/// Check whether any of the entity tags listed in the if-none-match header matches `etag`. Weak
+/// tags are compared by their value, as required for this header (RFC 9110, Section 13.1.2).
+fn if_none_match(headers: &HeaderMap, etag: &HeaderValue) -> bool {
+    let Ok(etag) = etag.to_str() else {
+        return false;
+    };
+    headers
+        .get_all(IF_NONE_MATCH)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .any(|tag| tag == "*" || tag.strip_prefix("W/").unwrap_or(tag) == etag)
 }
 
 #[cfg(test)]
@@ -56,15 +105,28 @@ mod test {
     use axum::{
         body::Body,
         extract::Query,
-        http::{Request, StatusCode},
+        http::{
+            header::{CACHE_CONTROL, ETAG, IF_NONE_MATCH},
+            Request, StatusCode,
+        },
         routing::get,
         Router,
     };
-    use daphne::messages::{Base64Encode, TaskId};
+    use daphne::{
+        hpke::{HpkeKemId, HpkeReceiverConfig},
+        messages::{Base64Encode, TaskId},
+        DapVersion,
+    };
+    use daphne_service_utils::DapRole;
     use rand::{thread_rng, Rng};
     use tower::ServiceExt;
 
     use super::QueryTaskId;
+    use crate::{
+        router,
+        storage_proxy_connection::kv,
+        test_utils::{test_app, test_service_config},
+    };
 
     #[tokio::test]
     async fn can_parse_task_id() {
@@ -107,4 +169,79 @@ mod test {
 
         assert_eq!(status, StatusCode::OK);
     }
+
+    async fn hpke_config_router(hpke_config_max_age: Option<u64>) -> Router {
+        let mut service_config = test_service_config(DapRole::Helper);
+        service_config.hpke_config_max_age = hpke_config_max_age;
+        let app = test_app(service_config);
+        let receiver = HpkeReceiverConfig::gen(1, HpkeKemId::X25519HkdfSha256).unwrap();
+        app.kv()
+            .put::<kv::prefix::HpkeReceiverConfigSet>(&DapVersion::DraftLatest, vec![receiver])
+            .await
+            .unwrap();
+        router::new(DapRole::Helper, app)
+    }
+
+    fn get_hpke_config(if_none_match: Option<&str>) -> Request<Body> {
+        let mut req = Request::builder().uri("/v09/hpke_config");
+        if let Some(if_none_match) = if_none_match {
+            req = req.header(IF_NONE_MATCH, if_none_match);
+        }
+        req.body(Body::empty()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn hpke_config_sets_caching_headers() {
+        let router = hpke_config_router(Some(3600)).await;
+
+        let resp = router.clone().oneshot(get_hpke_config(None)).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()[CACHE_CONTROL], "max-age=3600");
+        let etag = resp.headers()[ETAG].to_str().unwrap().to_string();
+        assert!(etag.starts_with('"') && etag.ends_with('"'));
+
+        // The config hasn't changed, so the ETag is stable.
+        let resp = router.oneshot(get_hpke_config(None)).await.unwrap();
+        assert_eq!(resp.headers()[ETAG], etag.as_str());
+    }
+
+    #[tokio::test]
+    async fn hpke_config_without_max_age() {
+        let router = hpke_config_router(None).await;
+
+        let resp = router.oneshot(get_hpke_config(None)).await.unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(resp.headers().get(CACHE_CONTROL).is_none());
+        assert!(resp.headers().get(ETAG).is_some());
+    }
+
+    #[tokio::test]
+    async fn hpke_config_if_none_match() {
+        let router = hpke_config_router(Some(3600)).await;
+        let resp = router.clone().oneshot(get_hpke_config(None)).await.unwrap();
+        let etag = resp.headers()[ETAG].to_str().unwrap().to_string();
+
+        for if_none_match in [
+            etag.clone(),
+            format!("W/{etag}"),
+            format!("\"some-other-tag\", {etag}"),
+            "*".to_string(),
+        ] {
+            let resp = router
+                .clone()
+                .oneshot(get_hpke_config(Some(&if_none_match)))
+                .await
+                .unwrap();
+            assert_eq!(resp.status(), StatusCode::NOT_MODIFIED, "{if_none_match}");
+            assert_eq!(resp.headers()[ETAG], etag.as_str());
+            assert_eq!(resp.headers()[CACHE_CONTROL], "max-age=3600");
+        }
+
+        // A stale tag yields the config.
+        let resp = router
+            .oneshot(get_hpke_config(Some("\"some-other-tag\"")))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
 }
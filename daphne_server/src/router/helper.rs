@@ -3,12 +3,14 @@
 
 use std::sync::Arc;
 
-use axum::{body::HttpBody, extract::State, routing::post};
-use daphne::{
-    constants::DapMediaType,
-    error::DapAbort,
-    roles::{helper, DapHelper},
+use axum::{
+    body::HttpBody,
+    extract::State,
+    response::{IntoResponse, Response},
+    routing::{get, post},
+    Json,
 };
+use daphne::roles::{helper, DapHelper};
 use daphne_service_utils::auth::DaphneAuth;
 
 use super::{AxumDapResponse, DapRequestExtractor, DaphneService};
@@ -30,6 +32,7 @@ where
             post(agg_job).put(agg_job),
         )
         .route("/:version/tasks/:task_id/aggregate_shares", post(agg_share))
+        .route("/capabilities", get(capabilities))
 }
 
 #[tracing::instrument(
@@ -48,15 +51,7 @@ where
     A: DapHelper<DaphneAuth> + DaphneService + Send + Sync,
 {
     AxumDapResponse::from_result(
-        match req.media_type {
-            DapMediaType::AggregationJobInitReq => {
-                helper::handle_agg_job_init_req(&*app, &req).await
-            }
-            DapMediaType::AggregationJobContinueReq => {
-                helper::handle_agg_job_cont_req(&*app, &req).await
-            }
-            m => Err(DapAbort::BadRequest(format!("unexpected media type: {m:?}")).into()),
-        },
+        helper::handle_agg_job_req(&*app, &req).await,
         app.server_metrics(),
     )
 }
@@ -81,3 +76,60 @@ where
         app.server_metrics(),
     )
 }
+
+/// Report what the Helper supports. This is unauthenticated, as it only reflects the deployment's
+/// configuration.
+async fn capabilities<A>(State(app): State<Arc<A>>) -> Response
+where
+    A: DaphneService + Send + Sync,
+{
+    Json(app.capabilities()).into_response()
+}
+
+#[cfg(test)]
+mod test {
+    use axum::{
+        body::Body,
+        http::{Request, StatusCode},
+    };
+    use daphne::hpke::HpkeKemId;
+    use daphne_service_utils::DapRole;
+    use tower::ServiceExt;
+
+    use crate::{
+        router,
+        test_utils::{test_app, test_service_config},
+    };
+
+    #[tokio::test]
+    async fn capabilities() {
+        let mut service_config = test_service_config(DapRole::Helper);
+        service_config.global.supported_hpke_kems =
+            vec![HpkeKemId::X25519HkdfSha256, HpkeKemId::P256HkdfSha256];
+        let router = router::new(DapRole::Helper, test_app(service_config));
+
+        let resp = router
+            .oneshot(
+                Request::builder()
+                    .uri("/capabilities")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        let capabilities: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(
+            capabilities["hpke_kem_ids"],
+            serde_json::json!([0x20, 0x10])
+        );
+        assert_eq!(
+            capabilities["query_types"],
+            serde_json::json!(["time_interval", "fixed_size"])
+        );
+        assert_eq!(capabilities["versions"], serde_json::json!(["v02", "v09"]));
+        assert!(capabilities["taskprov"].is_null());
+    }
+}
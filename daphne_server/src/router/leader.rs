@@ -1,27 +1,32 @@
 // Copyright (c) 2024 Cloudflare, Inc. All rights reserved.
 // SPDX-License-Identifier: BSD-3-Clause
 
-use std::sync::Arc;
+use std::{sync::Arc, time::Duration};
 
 use axum::{
     body::HttpBody,
-    extract::{Path, State},
-    http::{header, Request, StatusCode},
+    extract::{Path, Query, State},
+    http::{header, HeaderMap, HeaderName, HeaderValue, Request, StatusCode},
     middleware::{self, Next},
     response::{AppendHeaders, IntoResponse, Response},
     routing::{get, post, put},
+    Json,
 };
 use daphne::{
     constants::DapMediaType,
     error::DapAbort,
+    messages::{AggregationJobId, Base64Encode, Draft02AggregationJobId, TaskId},
     roles::leader::{self, DapLeader},
-    DapError, DapVersion,
+    DapCollectionJob, DapError, DapRequest, DapVersion, MetaAggregationJobId,
 };
 use daphne_service_utils::auth::DaphneAuth;
-use prio::codec::ParameterizedEncode;
 use serde::Deserialize;
+use tokio::time::Instant;
+use tracing::error;
 
-use super::{AxumDapResponse, DapRequestExtractor, DaphneService};
+use super::{
+    sender_auth_from_headers, AxumDapResponse, DapRequestExtractor, DaphneService, SkipCompression,
+};
 
 #[derive(Deserialize, Debug)]
 struct PathVersion {
@@ -68,6 +73,10 @@ where
             "/:version/tasks/:task_id/collection_jobs/:collect_job_id",
             put(get_collect_uri).post(collect),
         )
+        .route(
+            "/:version/tasks/:task_id/aggregation_jobs/:agg_job_id/status",
+            get(agg_job_status),
+        )
 }
 
 #[tracing::instrument(
@@ -104,15 +113,32 @@ async fn get_collect_uri<A>(
 where
     A: DapLeader<DaphneAuth> + DaphneService + Send + Sync,
 {
-    match (leader::handle_coll_job_req(&*app, &req).await, req.version) {
-        (Ok(uri), DapVersion::Draft02) => (
+    let created = match leader::handle_coll_job_req(&*app, &req).await {
+        Ok(created) => created,
+        Err(e) => return AxumDapResponse::new_error(e, app.server_metrics()).into_response(),
+    };
+    let mut response = match req.version {
+        DapVersion::Draft02 => (
             StatusCode::SEE_OTHER,
-            AppendHeaders([(header::LOCATION, uri.as_str())]),
+            AppendHeaders([(header::LOCATION, created.uri.as_str())]),
         )
             .into_response(),
-        (Ok(_), DapVersion::DraftLatest) => StatusCode::CREATED.into_response(),
-        (Err(e), _) => AxumDapResponse::new_error(e, app.server_metrics()).into_response(),
+        DapVersion::DraftLatest => StatusCode::CREATED.into_response(),
+    };
+
+    // Let the Collector know that a different batch interval than the one it requested is going
+    // to be collected. The value is the start and duration of the interval in seconds.
+    if let Some(batch_interval) = created.aligned_batch_interval {
+        response.headers_mut().insert(
+            HeaderName::from_static("dap-batch-interval"),
+            HeaderValue::try_from(format!(
+                "{}/{}",
+                batch_interval.start, batch_interval.duration
+            ))
+            .expect("integers are a valid header value"),
+        );
     }
+    response
 }
 
 #[tracing::instrument(
@@ -124,38 +150,47 @@ where
 )]
 async fn collect<A>(
     State(app): State<Arc<A>>,
+    Query(CollectPollParams { max_wait }): Query<CollectPollParams>,
     DapRequestExtractor(req): DapRequestExtractor,
 ) -> Response
 where
     A: DapLeader<DaphneAuth> + DaphneService + Send + Sync,
 {
-    let task_id = match req.task_id() {
-        Ok(id) => id,
-        Err(e) => return AxumDapResponse::new_error(e, app.server_metrics()).into_response(),
+    // The poll has no body, so the sender can't be inferred from its media type. Only the
+    // Collector may poll a collection job.
+    let req = DapRequest {
+        media_type: DapMediaType::CollectReq,
+        ..req
     };
-    let collect_id = match req.collection_job_id() {
-        Ok(id) => id,
-        Err(e) => return AxumDapResponse::new_error(e, app.server_metrics()).into_response(),
-    };
-    match app.poll_collect_job(task_id, collect_id).await {
-        Ok(daphne::DapCollectionJob::Done(collect_resp)) => AxumDapResponse::new_success(
-            daphne::DapResponse {
-                version: req.version,
-                media_type: DapMediaType::Collection,
-                payload: match collect_resp.get_encoded_with_param(&req.version) {
-                    Ok(payload) => payload,
-                    Err(e) => {
-                        return AxumDapResponse::new_error(
-                            DapError::encoding(e),
-                            app.server_metrics(),
-                        )
-                        .into_response()
-                    }
+    let status = poll_collect_job_with_deadline(&*app, &req, max_wait).await;
+    let mut resp = match status {
+        Ok(daphne::DapCollectionJob::Done(collect_resp)) => (
+            AppendHeaders([(
+                "dap-collection-format",
+                u8::from(req.collection_format).to_string(),
+            )]),
+            AxumDapResponse::new_success(
+                daphne::DapResponse {
+                    version: req.version,
+                    status: 200,
+                    media_type: DapMediaType::Collection,
+                    payload: match collect_resp
+                        .get_encoded_with_format(req.version, req.collection_format)
+                    {
+                        Ok(payload) => payload,
+                        Err(e) => {
+                            return AxumDapResponse::new_error(
+                                DapError::encoding(e),
+                                app.server_metrics(),
+                            )
+                            .into_response()
+                        }
+                    },
                 },
-            },
-            app.server_metrics(),
+                app.server_metrics(),
+            ),
         )
-        .into_response(),
+            .into_response(),
         Ok(daphne::DapCollectionJob::Pending) => StatusCode::ACCEPTED.into_response(),
         Ok(daphne::DapCollectionJob::Unknown) => AxumDapResponse::new_error(
             DapAbort::BadRequest("unknown collection job id".into()),
@@ -163,5 +198,513 @@ where
         )
         .into_response(),
         Err(e) => AxumDapResponse::new_error(e, app.server_metrics()).into_response(),
+    };
+    // Send the response to a long-poll as soon as it is ready.
+    if max_wait.is_some() {
+        resp.extensions_mut().insert(SkipCompression);
+    }
+    resp
+}
+
+/// The longest a Collector may long-poll a collection job for.
+const MAX_COLLECT_POLL_WAIT: Duration = Duration::from_secs(30);
+
+/// How often a long-poll re-checks the status of the collection job. Only pollers in the process
+/// that finished the job are notified, so this bounds how long a poller in another process keeps
+/// waiting after the job is done.
+const COLLECT_POLL_RECHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+#[derive(Deserialize, Debug)]
+struct CollectPollParams {
+    /// How long, in seconds, to wait for the collection job to finish before responding.
+    #[serde(default)]
+    max_wait: Option<u64>,
+}
+
+/// Check that the request was sent by the Collector of a task that is known.
+async fn authorize_collector<A>(app: &A, req: &DapRequest<DaphneAuth>) -> Result<(), DapError>
+where
+    A: DapLeader<DaphneAuth> + DaphneService + Send + Sync,
+{
+    let task_id = req.task_id()?;
+    let task_config = app
+        .get_task_config_for(task_id)
+        .await?
+        .ok_or(DapAbort::UnrecognizedTask)?;
+    if let Some(reason) = app.unauthorized_reason(task_config.as_ref(), req).await? {
+        error!("aborted unauthorized Collector request: {reason}");
+        return Err(DapAbort::UnauthorizedRequest {
+            detail: reason,
+            task_id: *task_id,
+        }
+        .into());
+    }
+    Ok(())
+}
+
+/// Poll a collection job on behalf of the Collector. If `max_wait` is set, then wait up to that
+/// many seconds (at most [`MAX_COLLECT_POLL_WAIT`]) for the job to finish before returning its
+/// status, so that the Collector doesn't need to poll as often. The request is authorized before
+/// waiting, so that only the Collector can hold a connection open.
+async fn poll_collect_job_with_deadline<A>(
+    app: &A,
+    req: &DapRequest<DaphneAuth>,
+    max_wait: Option<u64>,
+) -> Result<DapCollectionJob, DapError>
+where
+    A: DapLeader<DaphneAuth> + DaphneService + Send + Sync,
+{
+    let task_id = req.task_id()?;
+    let coll_job_id = req.collection_job_id()?;
+    authorize_collector(app, req).await?;
+
+    let (Some(max_wait), Some(finished)) = (max_wait, app.collection_job_finished()) else {
+        return app.poll_collect_job(task_id, coll_job_id).await;
+    };
+    let deadline = Instant::now() + Duration::from_secs(max_wait).min(MAX_COLLECT_POLL_WAIT);
+
+    loop {
+        // Register for the notification before checking the status, so that a job that finishes
+        // in between isn't missed.
+        let notified = finished.notified();
+        tokio::pin!(notified);
+        notified.as_mut().enable();
+
+        let status = app.poll_collect_job(task_id, coll_job_id).await?;
+        if !matches!(status, DapCollectionJob::Pending) || Instant::now() >= deadline {
+            return Ok(status);
+        }
+
+        // Whether notified or not, check the status again.
+        let _ = tokio::time::timeout_at(
+            deadline.min(Instant::now() + COLLECT_POLL_RECHECK_INTERVAL),
+            notified,
+        )
+        .await;
+    }
+}
+
+#[derive(Deserialize, Debug)]
+struct PathAggJob {
+    version: DapVersion,
+    #[serde(deserialize_with = "daphne::messages::base64url::deserialize")]
+    task_id: TaskId,
+    agg_job_id: String,
+}
+
+/// Report the status of an aggregation job to the Collector.
+#[tracing::instrument(skip(app, headers))]
+async fn agg_job_status<A>(
+    State(app): State<Arc<A>>,
+    Path(PathAggJob {
+        version,
+        task_id,
+        agg_job_id,
+    }): Path<PathAggJob>,
+    headers: HeaderMap,
+) -> Response
+where
+    A: DapLeader<DaphneAuth> + DaphneService + Send + Sync,
+{
+    // The request has no body, so the sender can't be inferred from its media type. Only the
+    // Collector may ask for the status of an aggregation job.
+    let req = DapRequest::builder(version)
+        .media_type(DapMediaType::CollectReq)
+        .task_id(Some(task_id))
+        .sender_auth(sender_auth_from_headers(&headers))
+        .build();
+    let authorized = match req {
+        Ok(req) => authorize_collector(&*app, &req).await,
+        Err(e) => Err(e.into()),
+    };
+    if let Err(e) = authorized {
+        return AxumDapResponse::new_error(e, app.server_metrics()).into_response();
+    }
+
+    let agg_job_id = match version {
+        DapVersion::Draft02 => {
+            Draft02AggregationJobId::try_from_base64url(agg_job_id).map(MetaAggregationJobId::from)
+        }
+        DapVersion::DraftLatest => {
+            AggregationJobId::try_from_base64url(agg_job_id).map(MetaAggregationJobId::from)
+        }
+    };
+    let Some(agg_job_id) = agg_job_id else {
+        return AxumDapResponse::new_error(
+            DapAbort::BadRequest("invalid aggregation job id".into()),
+            app.server_metrics(),
+        )
+        .into_response();
+    };
+    match app.agg_job_status(&task_id, &agg_job_id).await {
+        Ok(Some(status)) => (StatusCode::OK, Json(status)).into_response(),
+        Ok(None) => StatusCode::NOT_FOUND.into_response(),
+        Err(e) => AxumDapResponse::new_error(e, app.server_metrics()).into_response(),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        sync::Arc,
+        time::{Duration, SystemTime},
+    };
+
+    use axum::{
+        body::Body,
+        http::{header::CONTENT_TYPE, Request, StatusCode},
+        response::Response,
+    };
+    use daphne::{
+        auth::BearerToken,
+        constants::DapMediaType,
+        hpke::{HpkeKemId, HpkeReceiverConfig},
+        messages::{
+            Base64Encode, BatchSelector, Collection, CollectionJobId, CollectionReq,
+            HpkeCiphertext, Interval, PartialBatchSelector, Query, TaskId, Time,
+        },
+        roles::leader::{DapLeader, WorkItem},
+        DapAggregationJobStatus, DapAggregationParam, DapCollectionJob, DapTaskConfig,
+        DapTaskParameters, DapVersion, MetaAggregationJobId,
+    };
+    use daphne_service_utils::DapRole;
+    use prio::codec::ParameterizedEncode;
+    use rand::{thread_rng, Rng};
+    use tower::ServiceExt;
+
+    use super::add_leader_routes;
+    use crate::{
+        storage_proxy_connection::kv,
+        test_utils::{test_app, test_service_config},
+        App,
+    };
+
+    fn now() -> Time {
+        SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    }
+
+    /// Start a Leader with a single task, configured by `f`. The Collector's bearer token is
+    /// "collector-token".
+    async fn app_with_task(f: impl FnOnce(&mut DapTaskConfig)) -> (App, DapTaskConfig, TaskId) {
+        let app = test_app(test_service_config(DapRole::Leader));
+        let collector_hpke_config = HpkeReceiverConfig::gen(1, HpkeKemId::X25519HkdfSha256)
+            .unwrap()
+            .config;
+        let (mut task_config, task_id, _, _) = DapTaskParameters::default()
+            .to_config_with_taskprov(b"poll".to_vec(), now(), &[0; 32], &collector_hpke_config)
+            .unwrap();
+        f(&mut task_config);
+        app.kv()
+            .put::<kv::prefix::TaskConfig>(&task_id, task_config.clone())
+            .await
+            .unwrap();
+        app.kv()
+            .put::<kv::prefix::CollectorBearerToken>(
+                &task_id,
+                BearerToken::from("collector-token".to_string()),
+            )
+            .await
+            .unwrap();
+        (app, task_config, task_id)
+    }
+
+    /// Start a Leader with a single pending collection job.
+    async fn app_with_pending_coll_job() -> (Arc<App>, TaskId, CollectionJobId) {
+        let (app, task_config, task_id) = app_with_task(|_| ()).await;
+        let coll_job_id = CollectionJobId(thread_rng().gen());
+        let query = Query::TimeInterval {
+            batch_interval: Interval {
+                start: task_config.quantized_time_lower_bound(now()),
+                duration: task_config.time_precision,
+            },
+        };
+        app.test_leader_state
+            .lock()
+            .await
+            .init_collect_job(
+                &task_id,
+                &task_config,
+                &Some(coll_job_id),
+                query,
+                DapAggregationParam::Empty,
+                None,
+                &app.service_config.global,
+            )
+            .unwrap();
+        (Arc::new(app), task_id, coll_job_id)
+    }
+
+    /// Poll the collection job through the Leader's routes, waiting up to `max_wait` seconds.
+    async fn poll(
+        app: &Arc<App>,
+        task_id: &TaskId,
+        coll_job_id: &CollectionJobId,
+        max_wait: u64,
+        bearer_token: Option<&str>,
+    ) -> Response {
+        let router = add_leader_routes(axum::Router::new()).with_state(Arc::clone(app));
+        let mut req = Request::builder()
+            .method("POST")
+            .uri(format!(
+                "/v09/tasks/{}/collection_jobs/{}?max_wait={max_wait}",
+                task_id.to_base64url(),
+                coll_job_id.to_base64url(),
+            ))
+            .header(
+                CONTENT_TYPE,
+                DapMediaType::CollectReq
+                    .as_str_for_version(DapVersion::DraftLatest)
+                    .unwrap(),
+            );
+        if let Some(bearer_token) = bearer_token {
+            req = req.header("DAP-Auth-Token", bearer_token);
+        }
+        router
+            .oneshot(req.body(Body::empty()).unwrap())
+            .await
+            .unwrap()
+    }
+
+    /// Create a collection job for `batch_interval` through the Leader's routes.
+    async fn create_coll_job(app: App, task_id: &TaskId, batch_interval: Interval) -> Response {
+        let router = add_leader_routes(axum::Router::new()).with_state(Arc::new(app));
+        let coll_job_req = CollectionReq {
+            draft02_task_id: None,
+            query: Query::TimeInterval { batch_interval },
+            agg_param: Vec::new(),
+        };
+        let req = Request::builder()
+            .method("PUT")
+            .uri(format!(
+                "/v09/tasks/{}/collection_jobs/{}",
+                task_id.to_base64url(),
+                CollectionJobId(thread_rng().gen()).to_base64url(),
+            ))
+            .header(
+                CONTENT_TYPE,
+                DapMediaType::CollectReq
+                    .as_str_for_version(DapVersion::DraftLatest)
+                    .unwrap(),
+            )
+            .header("DAP-Auth-Token", "collector-token")
+            .body(Body::from(
+                coll_job_req
+                    .get_encoded_with_param(&DapVersion::DraftLatest)
+                    .unwrap(),
+            ))
+            .unwrap();
+        router.oneshot(req).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn coll_job_echoes_aligned_batch_interval() {
+        let (app, task_config, task_id) =
+            app_with_task(|task_config| task_config.align_batch_intervals = true).await;
+        let start = task_config.quantized_time_lower_bound(now()) - task_config.time_precision;
+
+        let resp = create_coll_job(
+            app,
+            &task_id,
+            Interval {
+                start: start + 1,
+                duration: task_config.time_precision,
+            },
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        assert_eq!(
+            resp.headers()["dap-batch-interval"],
+            format!("{start}/{}", 2 * task_config.time_precision)
+        );
+    }
+
+    #[tokio::test]
+    async fn coll_job_omits_batch_interval_if_aligned() {
+        let (app, task_config, task_id) =
+            app_with_task(|task_config| task_config.align_batch_intervals = true).await;
+
+        let resp = create_coll_job(
+            app,
+            &task_id,
+            Interval {
+                start: task_config.quantized_time_lower_bound(now()),
+                duration: task_config.time_precision,
+            },
+        )
+        .await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        assert!(!resp.headers().contains_key("dap-batch-interval"));
+    }
+
+    fn dummy_collection() -> Collection {
+        let ciphertext = HpkeCiphertext {
+            config_id: 1,
+            enc: Vec::new(),
+            payload: Vec::new(),
+        };
+        Collection {
+            part_batch_sel: PartialBatchSelector::TimeInterval,
+            report_count: 1,
+            draft_latest_interval: Some(Interval {
+                start: 0,
+                duration: 3600,
+            }),
+            encrypted_agg_shares: [ciphertext.clone(), ciphertext],
+        }
+    }
+
+    #[tokio::test]
+    async fn long_poll_returns_once_coll_job_finishes() {
+        let (app, task_id, coll_job_id) = app_with_pending_coll_job().await;
+
+        let poll = tokio::spawn({
+            let app = Arc::clone(&app);
+            async move { poll(&app, &task_id, &coll_job_id, 30, Some("collector-token")).await }
+        });
+
+        // The test runtime is single-threaded and in-memory storage never blocks, so by the time
+        // this task is scheduled again the poll is waiting for the job to finish.
+        tokio::task::yield_now().await;
+        assert!(!poll.is_finished());
+
+        app.finish_collect_job(&task_id, &coll_job_id, &dummy_collection())
+            .await
+            .unwrap();
+
+        // The poll returns well before the deadline, and before it would have re-checked the
+        // status on its own.
+        let resp = tokio::time::timeout(Duration::from_secs(1), poll)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn long_poll_returns_pending_after_deadline() {
+        let (app, task_id, coll_job_id) = app_with_pending_coll_job().await;
+
+        let resp = tokio::time::timeout(
+            Duration::from_secs(5),
+            poll(&app, &task_id, &coll_job_id, 1, Some("collector-token")),
+        )
+        .await
+        .unwrap();
+        assert_eq!(resp.status(), StatusCode::ACCEPTED);
+    }
+
+    #[tokio::test]
+    async fn long_poll_rejects_unauthorized_collector() {
+        let (app, task_id, coll_job_id) = app_with_pending_coll_job().await;
+
+        // The request is rejected right away rather than after the deadline.
+        for bearer_token in [None, Some("wrong-token")] {
+            let resp = tokio::time::timeout(
+                Duration::from_secs(1),
+                poll(&app, &task_id, &coll_job_id, 30, bearer_token),
+            )
+            .await
+            .unwrap();
+            assert_eq!(resp.status(), StatusCode::BAD_REQUEST, "{bearer_token:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn agg_job_status_requires_collector_auth() {
+        let (app, task_config, task_id) = app_with_task(|_| ()).await;
+        let agg_job_id = MetaAggregationJobId::gen_for_version(task_config.version);
+        app.set_agg_job_status(&task_id, &agg_job_id, DapAggregationJobStatus::Queued)
+            .await
+            .unwrap();
+        let router = add_leader_routes(axum::Router::new()).with_state(Arc::new(app));
+
+        for (bearer_token, expected_status) in [
+            (None, StatusCode::BAD_REQUEST),
+            (Some("wrong-token"), StatusCode::BAD_REQUEST),
+            (Some("collector-token"), StatusCode::OK),
+        ] {
+            let mut req = Request::builder().uri(format!(
+                "/v09/tasks/{}/aggregation_jobs/{}/status",
+                task_id.to_base64url(),
+                agg_job_id.to_base64url(),
+            ));
+            if let Some(bearer_token) = bearer_token {
+                req = req.header("DAP-Auth-Token", bearer_token);
+            }
+            let resp = router
+                .clone()
+                .oneshot(req.body(Body::empty()).unwrap())
+                .await
+                .unwrap();
+            assert_eq!(resp.status(), expected_status, "{bearer_token:?}");
+        }
+    }
+
+    // Test that once shutdown is triggered, the Leader stops dequeuing work, while a job that was
+    // dequeued before then runs to completion and holds up the drain until it does.
+    #[tokio::test]
+    async fn shutdown_lets_dequeued_work_finish() {
+        let (app, task_id, coll_job_id) = app_with_pending_coll_job().await;
+        let shutdown = app.shutdown_handle();
+
+        // Another job that is still queued when shutdown is triggered.
+        app.test_leader_state
+            .lock()
+            .await
+            .enqueue_work(vec![WorkItem::CollectionJob {
+                task_id: TaskId(thread_rng().gen()),
+                coll_job_id: CollectionJobId(thread_rng().gen()),
+                batch_sel: BatchSelector::TimeInterval {
+                    batch_interval: Interval {
+                        start: 0,
+                        duration: 3600,
+                    },
+                },
+                agg_param: DapAggregationParam::Empty,
+                collector_hpke_config_id: None,
+                requeues: 0,
+            }])
+            .unwrap();
+
+        // The pending collection job is dequeued by a request that is still being handled when
+        // shutdown is triggered.
+        let request = shutdown.track();
+        let work = app.dequeue_work(1).await.unwrap();
+        assert!(matches!(
+            &work[..],
+            [(_, WorkItem::CollectionJob { coll_job_id: id, .. })] if *id == coll_job_id
+        ));
+        shutdown.trigger();
+
+        // No more work is started.
+        assert!(app.dequeue_work(100).await.unwrap().is_empty());
+        assert_eq!(app.test_leader_state.lock().await.work_queue_stats().len, 1);
+
+        let drain = tokio::spawn({
+            let shutdown = Arc::clone(&shutdown);
+            async move { shutdown.wait_for_drain().await }
+        });
+
+        // The dequeued collection job still completes.
+        app.finish_collect_job(&task_id, &coll_job_id, &dummy_collection())
+            .await
+            .unwrap();
+        app.ack_work(work.into_iter().map(|(item_id, _)| item_id).collect())
+            .await
+            .unwrap();
+        let status = app.poll_collect_job(&task_id, &coll_job_id).await.unwrap();
+        assert!(matches!(status, DapCollectionJob::Done(..)));
+
+        // The server drains once the request that was handling the job is done.
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert!(!drain.is_finished());
+        drop(request);
+        tokio::time::timeout(Duration::from_secs(1), drain)
+            .await
+            .expect("shutdown did not drain")
+            .unwrap();
     }
 }
@@ -13,29 +13,39 @@ use axum::{
     async_trait,
     body::HttpBody,
     extract::{FromRequest, FromRequestParts, Path, State},
-    http::{header::CONTENT_TYPE, HeaderValue, StatusCode},
+    http::{
+        header::{
+            ACCEPT_ENCODING, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE, RETRY_AFTER, VARY,
+        },
+        HeaderMap, HeaderValue, StatusCode,
+    },
     middleware::Next,
     response::IntoResponse,
-    Json,
 };
 use daphne::{
     auth::BearerToken,
     constants::DapMediaType,
     error::DapAbort,
     fatal_error,
-    messages::{AggregationJobId, CollectionJobId, TaskId},
+    messages::{AggregationJobId, CollectionFormat, CollectionJobId, TaskId},
     DapError, DapRequest, DapResource, DapResponse, DapVersion,
 };
 use daphne_service_utils::{
     auth::{DaphneAuth, TlsClientAuth},
+    compression::{self, DecompressError},
+    config::{Capabilities, CompressionConfig},
     metrics::DaphneServiceMetrics,
     DapRole,
 };
 use http::Request;
+use hyper::body::Buf;
 use prio::codec::Decode;
 use serde::Deserialize;
 
-use crate::App;
+use crate::{
+    shutdown::{shutting_down, starts_new_work},
+    App,
+};
 
 type Router<A, B> = axum::Router<Arc<A>, B>;
 
@@ -43,6 +53,21 @@ type Router<A, B> = axum::Router<Arc<A>, B>;
 pub trait DaphneService {
     /// The service metrics
     fn server_metrics(&self) -> &dyn DaphneServiceMetrics;
+
+    /// How long, in seconds, clients may cache the Aggregator's HPKE config, if at all.
+    fn hpke_config_max_age(&self) -> Option<daphne::messages::Duration> {
+        None
+    }
+
+    /// Helper: What the deployment supports, as reported to peers by the capabilities endpoint.
+    fn capabilities(&self) -> Capabilities;
+
+    /// Leader: Notified whenever this process finishes a collection job. If set, then the
+    /// Collector may long-poll a collection job rather than poll it repeatedly. Pollers in other
+    /// processes aren't notified and only notice the job is done when they re-check its status.
+    fn collection_job_finished(&self) -> Option<&tokio::sync::Notify> {
+        None
+    }
 }
 
 pub fn new<B>(role: DapRole, aggregator: App) -> axum::Router<(), B>
@@ -85,76 +110,292 @@ where
         resp
     }
 
+    async fn track_in_flight<B>(
+        State(app): State<Arc<App>>,
+        req: Request<B>,
+        next: Next<B>,
+    ) -> axum::response::Response {
+        // Refuse new work during shutdown. Refused requests don't keep the app from draining, but
+        // accepted ones do until they have been handled.
+        if app.shutdown.is_triggered() && starts_new_work(req.headers()) {
+            return shutting_down();
+        }
+        let _in_flight = app.shutdown.track();
+        next.run(req).await
+    }
+
+    async fn limit_request_body<B>(
+        State(app): State<Arc<App>>,
+        mut req: Request<B>,
+        next: Next<B>,
+    ) -> axum::response::Response {
+        let content_type = req
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok());
+        if let Some(limit) = app.service_config.max_request_body_bytes_for(content_type) {
+            // Reject the request early if the client declares the body is too large. Otherwise
+            // the limit is enforced as the body is read.
+            let content_length = req
+                .headers()
+                .get(CONTENT_LENGTH)
+                .and_then(|v| v.to_str().ok()?.parse::<usize>().ok());
+            if content_length.is_some_and(|content_length| content_length > limit) {
+                return body_too_large(limit).into_response();
+            }
+            req.extensions_mut().insert(RequestBodyLimit(limit));
+        }
+        next.run(req).await
+    }
+
+    async fn compress_response<B>(
+        State(app): State<Arc<App>>,
+        mut req: Request<B>,
+        next: Next<B>,
+    ) -> axum::response::Response {
+        let Some(config) = app.service_config.compression else {
+            return next.run(req).await;
+        };
+
+        // Let the request body be decompressed by [`DapRequestExtractor`].
+        req.extensions_mut().insert(config);
+        let accepts_gzip = req
+            .headers()
+            .get(ACCEPT_ENCODING)
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(compression::accepts_gzip);
+
+        let mut resp = next.run(req).await;
+        // Let the peer know that it may compress request bodies (RFC 9110, Section 12.5.3).
+        resp.headers_mut()
+            .insert(ACCEPT_ENCODING, HeaderValue::from_static(compression::GZIP));
+        // Streaming responses don't have an exact size; they are passed through rather than
+        // buffered.
+        if !accepts_gzip
+            || resp.headers().contains_key(CONTENT_ENCODING)
+            || resp.extensions().get::<SkipCompression>().is_some()
+            || resp.body().size_hint().exact().is_none()
+        {
+            return resp;
+        }
+
+        let (mut parts, body) = resp.into_parts();
+        let body = match hyper::body::to_bytes(body).await {
+            Ok(body) => body,
+            Err(e) => {
+                tracing::error!(error = ?e, "failed to read response body");
+                return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+            }
+        };
+        if !config.should_compress(body.len()) {
+            return axum::response::Response::from_parts(
+                parts,
+                axum::body::boxed(axum::body::Full::from(body)),
+            );
+        }
+
+        let body = match crate::compress_body(body.to_vec()).await {
+            Ok(body) => body,
+            Err(e) => {
+                tracing::error!(error = ?e, "failed to compress response body");
+                return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+            }
+        };
+        parts.headers.remove(CONTENT_LENGTH);
+        parts.headers.insert(
+            CONTENT_ENCODING,
+            HeaderValue::from_static(compression::GZIP),
+        );
+        parts
+            .headers
+            .append(VARY, HeaderValue::from_static("accept-encoding"));
+        axum::response::Response::from_parts(parts, axum::body::boxed(axum::body::Full::from(body)))
+    }
+
     let app = Arc::new(aggregator);
-    router
-        .with_state(app.clone())
-        .layer(
-            tower::ServiceBuilder::new().layer(axum::middleware::from_fn_with_state(
+    router.with_state(app.clone()).layer(
+        tower::ServiceBuilder::new()
+            .layer(axum::middleware::from_fn_with_state(
                 app.clone(),
                 request_metrics,
+            ))
+            .layer(axum::middleware::from_fn_with_state(
+                app.clone(),
+                limit_request_body,
+            ))
+            .layer(axum::middleware::from_fn_with_state(
+                app.clone(),
+                track_in_flight,
+            ))
+            .layer(axum::middleware::from_fn_with_state(
+                app.clone(),
+                compress_response,
             )),
+    )
+}
+
+/// Added to a response to keep it from being compressed, e.g., because it answers a long-poll
+/// and should be sent as soon as it is ready.
+#[derive(Clone, Copy, Debug)]
+struct SkipCompression;
+
+/// The maximum size of the request body in bytes. This is added to the request extensions by the
+/// router and enforced by [`DapRequestExtractor`] as it reads the body.
+#[derive(Clone, Copy, Debug)]
+struct RequestBodyLimit(usize);
+
+fn body_too_large(limit: usize) -> (StatusCode, String) {
+    (
+        StatusCode::PAYLOAD_TOO_LARGE,
+        format!("request body exceeds the limit of {limit} bytes"),
+    )
+}
+
+/// Read the request body, failing if it is longer than `limit` bytes.
+async fn read_body<B>(body: B, limit: Option<usize>) -> Result<Vec<u8>, (StatusCode, String)>
+where
+    B: HttpBody,
+{
+    let failed = || {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "failed to get payload".to_string(),
         )
+    };
+
+    let Some(limit) = limit else {
+        // TODO(mendess): this is very eager, we could redesign DapResponse later to allow for
+        // streaming of data.
+        return hyper::body::to_bytes(body)
+            .await
+            .map(|payload| payload.to_vec())
+            .map_err(|_| failed());
+    };
+
+    let mut body = std::pin::pin!(body);
+    let mut payload = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let mut chunk = chunk.map_err(|_| failed())?;
+        if payload.len() + chunk.remaining() > limit {
+            return Err(body_too_large(limit));
+        }
+        payload.extend_from_slice(&chunk.copy_to_bytes(chunk.remaining()));
+    }
+    Ok(payload)
+}
+
+/// Decompress a request body sent with the given content coding. The decompressed body may be at
+/// most `limit` bytes long, the same as an uncompressed one.
+async fn decode_body(
+    payload: Vec<u8>,
+    encoding: &str,
+    config: Option<&CompressionConfig>,
+    limit: Option<usize>,
+) -> Result<Vec<u8>, (StatusCode, String)> {
+    if compression::is_identity(encoding) {
+        return Ok(payload);
+    }
+
+    // Compressed bodies are only accepted if compression is configured, as otherwise the size of
+    // the decompressed body would be unbounded.
+    let Some(config) = config.filter(|_| compression::is_gzip(encoding)) else {
+        return Err((
+            StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            format!("unsupported content-encoding: {encoding}"),
+        ));
+    };
+
+    let max_decompressed_bytes = limit.map_or(config.max_decompressed_body_bytes, |limit| {
+        limit.min(config.max_decompressed_body_bytes)
+    });
+    crate::decompress_body(payload, max_decompressed_bytes)
+        .await
+        .map_err(|e| (StatusCode::INTERNAL_SERVER_ERROR, e.to_string()))?
+        .map_err(|e| {
+            let status = match e {
+                DecompressError::TooLarge { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+                DecompressError::Invalid(..) => StatusCode::BAD_REQUEST,
+            };
+            (status, e.to_string())
+        })
 }
 
 struct AxumDapResponse(axum::response::Response);
 
 impl AxumDapResponse {
     pub fn new_success(response: DapResponse, metrics: &dyn DaphneServiceMetrics) -> Self {
+        match Self::try_from_dap_response(response) {
+            Ok(response) => response,
+            Err(e) => Self::new_error(e, metrics),
+        }
+    }
+
+    fn try_from_dap_response(response: DapResponse) -> Result<Self, DapError> {
         let Some(media_type) = response.media_type.as_str_for_version(response.version) else {
-            return AxumDapResponse::new_error(
-                fatal_error!(err = "failed to construct content-type",
-                    ?response.media_type,
-                    ?response.version
-                ),
-                metrics,
-            );
+            return Err(fatal_error!(err = "failed to construct content-type",
+                ?response.media_type,
+                ?response.version
+            ));
         };
 
-        let media_type = match HeaderValue::from_str(media_type) {
-            Ok(media_type) => media_type,
-            Err(e) => {
-                return AxumDapResponse::new_error(
-                    fatal_error!(err = ?e, "content-type contained invalid bytes {media_type:?}"),
-                    metrics,
-                )
-            }
-        };
+        let media_type = HeaderValue::from_str(media_type).map_err(
+            |e| fatal_error!(err = ?e, "content-type contained invalid bytes {media_type:?}"),
+        )?;
+
+        let status = StatusCode::from_u16(response.status)
+            .map_err(|e| fatal_error!(err = ?e, "invalid status code {}", response.status))?;
 
         let headers = [(CONTENT_TYPE, media_type)];
 
-        Self((StatusCode::OK, headers, response.payload).into_response())
+        Ok(Self((status, headers, response.payload).into_response()))
+    }
+
+    /// Respond to a request that could not be parsed with a problem document. Unlike
+    /// [`Self::new_error`], this is not counted by the service metrics, which are not available to
+    /// [`DapRequestExtractor`].
+    fn new_rejection(abort: DapAbort) -> Self {
+        tracing::error!(error = ?abort, "request rejected");
+        // The problem document doesn't depend on the DAP version.
+        let response = abort.into_problem_response(DapVersion::default());
+        Self::try_from_dap_response(response)
+            .unwrap_or_else(|_| Self(StatusCode::INTERNAL_SERVER_ERROR.into_response()))
     }
 
     pub fn new_error<E: Into<DapError>>(error: E, metrics: &dyn DaphneServiceMetrics) -> Self {
         // trigger abort if transition failures reach this point.
         let error = match error.into() {
-            DapError::Transition(failure) => DapAbort::report_rejected(failure),
-            DapError::Fatal(e) => Err(e),
-            DapError::Abort(abort) => Ok(abort),
+            DapError::Transition(failure) => match DapAbort::report_rejected(failure) {
+                Ok(abort) => DapError::Abort(abort),
+                Err(e) => DapError::Fatal(e),
+            },
+            error => error,
         };
-        let status = if let Err(_e) = &error {
+        if let DapError::Abort(error) = &error {
+            tracing::error!(?error, "request aborted due to protocol abort");
+        } else {
             // TODO(mendess) uncomment the line below
             // self.error_reporter.report_abort(&e);
-            StatusCode::INTERNAL_SERVER_ERROR
-        } else {
-            StatusCode::BAD_REQUEST
-        };
-        let problem_details = match error {
-            Ok(error) => {
-                tracing::error!(?error, "request aborted due to protocol abort");
-                error.into_problem_details()
-            }
-            Err(error) => {
-                tracing::error!(?error, "request aborted due to fatal error");
-                DapError::Fatal(error).into_problem_details()
-            }
-        };
+            tracing::error!(?error, "request aborted due to fatal error");
+        }
         // this to string is bounded by the
         // number of variants in the enum
-        metrics.abort_count_inc(&problem_details.title);
-        let headers = [(CONTENT_TYPE, "application/problem+json")];
+        metrics.abort_count_inc(error.title());
+
+        let retry_after = match &error {
+            DapError::Abort(abort) => abort.retry_after(),
+            _ => None,
+        };
 
-        Self((status, headers, Json(problem_details)).into_response())
+        // The problem document doesn't depend on the DAP version.
+        let response = error.into_problem_response(DapVersion::default());
+        let mut response = Self::new_success(response, metrics);
+        if let Some(retry_after) = retry_after {
+            response
+                .0
+                .headers_mut()
+                .insert(RETRY_AFTER, HeaderValue::from(retry_after));
+        }
+        response
     }
 
     pub fn from_result<E>(
@@ -181,6 +422,30 @@ impl IntoResponse for AxumDapResponse {
 #[derive(Debug)]
 struct DapRequestExtractor(pub DapRequest<DaphneAuth>);
 
+/// Extract the sender's credentials from the headers of the HTTP request. If no credentials were
+/// presented, then return `None`, leaving the sender unauthenticated so that the request is
+/// rejected if the endpoint requires authorization.
+fn sender_auth_from_headers(headers: &HeaderMap) -> Option<DaphneAuth> {
+    let extract_header_as_string = |header: &'static str| -> Option<String> {
+        headers.get(header)?.to_str().ok().map(ToString::to_string)
+    };
+
+    let sender_auth = DaphneAuth {
+        bearer_token: extract_header_as_string("DAP-Auth-Token").map(BearerToken::from),
+        cf_tls_client_auth: (|| {
+            // Whatever service ends up fronting this one and terminating mTLS must pass through
+            // these headers.
+            Some(TlsClientAuth {
+                verified: extract_header_as_string("X-Client-Cert-Verified")?,
+                issuer: extract_header_as_string("X-Client-Cert-Issuer-Dn-Rfc2253")?,
+                subject: extract_header_as_string("X-Client-Cert-Subject-Dn-Rfc2253")?,
+            })
+        })(),
+    };
+    (sender_auth.bearer_token.is_some() || sender_auth.cf_tls_client_auth.is_some())
+        .then_some(sender_auth)
+}
+
 #[async_trait]
 impl<S, B> FromRequest<S, B> for DapRequestExtractor
 where
@@ -188,7 +453,7 @@ where
     B: HttpBody + Send + 'static,
     <B as HttpBody>::Data: Send,
 {
-    type Rejection = (StatusCode, String);
+    type Rejection = AxumDapResponse;
 
     async fn from_request(req: Request<B>, state: &S) -> Result<Self, Self::Rejection> {
         #[derive(Debug, Deserialize)]
@@ -220,7 +485,7 @@ where
             collect_job_id,
         }) = Path::from_request_parts(&mut parts, state)
             .await
-            .map_err(|e| (StatusCode::BAD_REQUEST, e.to_string()))?;
+            .map_err(|e| AxumDapResponse::new_rejection(DapAbort::BadRequest(e.to_string())))?;
 
         let extract_header_as_string = |header: &'static str| -> Option<String> {
             parts
@@ -231,89 +496,99 @@ where
                 .map(ToString::to_string)
         };
 
-        let sender_auth = DaphneAuth {
-            bearer_token: extract_header_as_string("DAP-Auth-Token").map(BearerToken::from),
-            cf_tls_client_auth: (|| {
-                // Whatever service ends up fronting this one and terminating mTLS must pass through
-                // these headers.
-                Some(TlsClientAuth {
-                    verified: extract_header_as_string("X-Client-Cert-Verified")?,
-                    issuer: extract_header_as_string("X-Client-Cert-Issuer-Dn-Rfc2253")?,
-                    subject: extract_header_as_string("X-Client-Cert-Subject-Dn-Rfc2253")?,
-                })
-            })(),
-        };
-
-        let media_type = DapMediaType::from_str_for_version(
-            version,
-            parts
-                .headers
-                .get(CONTENT_TYPE)
-                .and_then(|v| v.to_str().ok()),
-        )
-        .ok_or_else(|| (StatusCode::BAD_REQUEST, "invalid media type".into()))?;
-
         let taskprov = extract_header_as_string("dap-taskprov");
+        let idempotency_token = extract_header_as_string("dap-idempotency-token");
+        let vdaf_version = extract_header_as_string("dap-vdaf-version");
+        let collection_format = CollectionFormat::negotiate(
+            extract_header_as_string("dap-collection-format").and_then(|v| v.parse().ok()),
+        );
+        let collector_hpke_config_id = extract_header_as_string("dap-collector-hpke-config-id")
+            .map(|v| v.parse::<u8>())
+            .transpose()
+            .map_err(|e| {
+                AxumDapResponse::new_rejection(DapAbort::BadRequest(format!(
+                    "invalid dap-collector-hpke-config-id header: {e}"
+                )))
+            })?;
 
-        // TODO(mendess): this is very eager, we could redesign DapResponse later to allow for
-        // streaming of data.
-        let payload = hyper::body::to_bytes(body).await;
+        let limit = parts
+            .extensions
+            .get::<RequestBodyLimit>()
+            .map(|limit| limit.0);
+        // Failing to read the body is a transport error rather than a DAP abort.
+        let payload = read_body(body, limit)
+            .await
+            .map_err(|e| AxumDapResponse(e.into_response()))?;
+        let payload = match extract_header_as_string("content-encoding") {
+            None => payload,
+            Some(encoding) => decode_body(
+                payload,
+                &encoding,
+                parts.extensions.get::<CompressionConfig>(),
+                limit,
+            )
+            .await
+            .map_err(|e| AxumDapResponse(e.into_response()))?,
+        };
 
-        let Ok(payload) = payload else {
-            return Err((
-                StatusCode::INTERNAL_SERVER_ERROR,
-                "failed to get payload".into(),
-            ));
+        // draft02: The task ID is usually encoded by the payload rather than the request path.
+        let task_id = match version {
+            DapVersion::Draft02 => {
+                task_id.or_else(|| TaskId::decode(&mut Cursor::new(payload.as_ref())).ok())
+            }
+            DapVersion::DraftLatest => task_id,
         };
 
-        let (task_id, resource) = match version {
+        let mut req = DapRequest::builder(version)
+            .content_type(
+                parts
+                    .headers
+                    .get(CONTENT_TYPE)
+                    .and_then(|v| v.to_str().ok()),
+            )
+            .task_id(task_id)
+            .payload(payload)
+            .sender_auth(sender_auth_from_headers(&parts.headers))
+            .taskprov(taskprov)
+            .idempotency_token(idempotency_token)
+            .vdaf_version(vdaf_version)
+            .collection_format(collection_format)
+            .collector_hpke_config_id(collector_hpke_config_id)
+            .build()
+            .map_err(AxumDapResponse::new_rejection)?;
+
+        req.resource = match version {
+            // If the collection job ID was found in the request path, then this must be a request
+            // for a collection job result from the Collector.
             DapVersion::Draft02 => {
-                let mut r = Cursor::new(payload.as_ref());
-                let task_id = task_id.or_else(|| TaskId::decode(&mut r).ok());
-
-                // If the collection job ID was found in the request path, then this must be a
-                // request for a collection job result from the Collector.
-                let resource =
-                    collect_job_id.map_or(DapResource::Undefined, DapResource::CollectionJob);
-                (task_id, resource)
+                collect_job_id.map_or(DapResource::Undefined, DapResource::CollectionJob)
             }
-            DapVersion::DraftLatest => {
-                let resource = match media_type {
-                    DapMediaType::AggregationJobInitReq
-                    | DapMediaType::AggregationJobContinueReq => {
-                        if let Some(agg_job_id) = agg_job_id {
-                            DapResource::AggregationJob(agg_job_id)
-                        } else {
-                            // Missing or invalid agg job ID. This should be handled as a bad
-                            // request (undefined resource) by the caller.
-                            DapResource::Undefined
-                        }
+            DapVersion::DraftLatest => match req.media_type {
+                DapMediaType::AggregationJobInitReq
+                | DapMediaType::AggregationJobContinueReq
+                | DapMediaType::AggregationJobRespChunkReq => {
+                    if let Some(agg_job_id) = agg_job_id {
+                        DapResource::AggregationJob(agg_job_id)
+                    } else {
+                        // Missing or invalid agg job ID. This should be handled as a bad
+                        // request (undefined resource) by the caller.
+                        DapResource::Undefined
                     }
-                    DapMediaType::CollectReq => {
-                        if let Some(collect_job_id) = collect_job_id {
-                            DapResource::CollectionJob(collect_job_id)
-                        } else {
-                            // Missing or invalid agg job ID. This should be handled as a bad
-                            // request (undefined resource) by the caller.
-                            DapResource::Undefined
-                        }
+                }
+                DapMediaType::CollectReq => {
+                    if let Some(collect_job_id) = collect_job_id {
+                        DapResource::CollectionJob(collect_job_id)
+                    } else {
+                        // Missing or invalid agg job ID. This should be handled as a bad
+                        // request (undefined resource) by the caller.
+                        DapResource::Undefined
                     }
-                    _ => DapResource::Undefined,
-                };
-
-                (task_id, resource)
-            }
+                }
+                _ => DapResource::Undefined,
+            },
         };
 
-        Ok(DapRequestExtractor(DapRequest {
-            version,
-            task_id,
-            resource,
-            payload: payload.to_vec(),
-            media_type,
-            sender_auth: Some(sender_auth),
-            taskprov,
-        }))
+        Ok(DapRequestExtractor(req))
     }
 }
 
@@ -324,33 +599,40 @@ mod test {
     use axum::{
         body::{Body, HttpBody},
         extract::State,
-        http::{header::CONTENT_TYPE, Request, StatusCode},
+        http::{
+            header::{ACCEPT_ENCODING, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE},
+            Request, StatusCode,
+        },
         response::IntoResponse,
-        routing::get,
-        Router,
+        routing::{get, post},
+        Extension, Router,
     };
     use daphne::{
-        messages::{AggregationJobId, Base64Encode, TaskId},
+        auth::BearerToken,
+        messages::{AggregationJobId, AggregationJobInitReq, Base64Encode, TaskId},
+        testing::gen_dummy_agg_job_init_req,
         DapRequest, DapResource, DapVersion,
     };
-    use daphne_service_utils::auth::DaphneAuth;
+    use daphne_service_utils::{auth::DaphneAuth, compression, config::CompressionConfig, DapRole};
     use futures::future::BoxFuture;
-    use prio::codec::Encode;
+    use prio::codec::{Encode, ParameterizedDecode, ParameterizedEncode};
     use rand::{thread_rng, Rng};
     use tokio::sync::mpsc::{self, Sender};
     use tower::ServiceExt;
 
-    use super::DapRequestExtractor;
+    use super::{DapRequestExtractor, RequestBodyLimit};
+    use crate::test_utils::{test_app, test_service_config};
 
-    /// Return a function that will parse a request using the [`DapRequestExtractor`] and return
-    /// the parsed request.
+    /// Return a router that parses requests using the [`DapRequestExtractor`] and sends the
+    /// parsed request to `tx`.
     ///
     /// The possible request URIs that are supported by this parser are:
     ///  - `/:version/parse-version`
     ///  - `/:version/:task_id/parse-task-id`
     ///  - `/:version/:agg_job_id/parse-agg-job-id`
+    ///  - `/:version/:task_id/:agg_job_id/parse-agg-job-id`
     ///  - `/:version/:collect_job_id/parse-collect-job-id`
-    fn test_router<B>() -> impl FnOnce(Request<B>) -> BoxFuture<'static, DapRequest<DaphneAuth>>
+    fn parsing_router<B>(tx: Sender<DapRequest<DaphneAuth>>) -> Router<(), B>
     where
         B: Send + Sync + 'static + HttpBody,
         B::Data: Send,
@@ -365,17 +647,31 @@ mod test {
             ch.send(req).await.unwrap();
         }
 
-        let (tx, mut rx) = mpsc::channel(1);
-
-        let router = Router::new()
+        Router::new()
             .route("/:version/parse-version", get(handler))
             .route("/:version/:task_id/parse-task-id", get(handler))
             .route("/:version/:agg_job_id/parse-agg-job-id", get(handler))
+            .route(
+                "/:version/:task_id/:agg_job_id/parse-agg-job-id",
+                get(handler),
+            )
             .route(
                 "/:version/:collect_job_id/parse-collect-job-id",
                 get(handler),
             )
-            .with_state(Arc::new(tx));
+            .with_state(Arc::new(tx))
+    }
+
+    /// Return a function that will parse a request using the [`DapRequestExtractor`] and return
+    /// the parsed request. See [`parsing_router`] for the supported request URIs.
+    fn test_router<B>() -> impl FnOnce(Request<B>) -> BoxFuture<'static, DapRequest<DaphneAuth>>
+    where
+        B: Send + Sync + 'static + HttpBody,
+        B::Data: Send,
+        B::Error: Send + Sync + std::error::Error,
+    {
+        let (tx, mut rx) = mpsc::channel(1);
+        let router = parsing_router(tx);
 
         move |req| {
             Box::pin(async move {
@@ -472,22 +768,359 @@ mod test {
 
     #[tokio::test]
     async fn parse_agg_job_id_latest_version() {
+        let (tx, _rx) = mpsc::channel(1);
+
+        let agg_job_id = AggregationJobId(thread_rng().gen());
+
+        // Aggregation job requests are authorized per task, so a request without a task ID is
+        // rejected even though its aggregation job ID is valid.
+        let resp = parsing_router(tx)
+            .oneshot(
+                Request::builder()
+                    .uri(format!(
+                        "/v09/{}/parse-agg-job-id",
+                        agg_job_id.to_base64url()
+                    ))
+                    .header(CONTENT_TYPE, "application/dap-aggregation-job-init-req")
+                    .header("DAP-Auth-Token", "leader_token")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn parse_agg_job_id_with_task_id_latest_version() {
         let test = test_router();
 
+        let task_id = TaskId(thread_rng().gen());
         let agg_job_id = AggregationJobId(thread_rng().gen());
 
         let req = test(
             Request::builder()
                 .uri(format!(
-                    "/v09/{}/parse-agg-job-id",
+                    "/v09/{}/{}/parse-agg-job-id",
+                    task_id.to_base64url(),
                     agg_job_id.to_base64url()
                 ))
                 .header(CONTENT_TYPE, "application/dap-aggregation-job-init-req")
+                .header("DAP-Auth-Token", "leader_token")
                 .body(Body::empty())
                 .unwrap(),
         )
         .await;
 
+        assert_eq!(req.task_id, Some(task_id));
         assert_eq!(req.resource, DapResource::AggregationJob(agg_job_id));
     }
+
+    #[tokio::test]
+    async fn parse_sender_auth() {
+        let task_id = TaskId(thread_rng().gen());
+        let request = || {
+            Request::builder()
+                .uri(format!("/v09/{}/parse-task-id", task_id.to_base64url()))
+                .header(CONTENT_TYPE, "application/dap-aggregate-share-req")
+        };
+
+        // A request without credentials is rejected since the endpoint requires authorization.
+        let (tx, _rx) = mpsc::channel(1);
+        let resp = parsing_router(tx)
+            .oneshot(request().body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+
+        let req = test_router()(
+            request()
+                .header("DAP-Auth-Token", "leader_token")
+                .body(Body::empty())
+                .unwrap(),
+        )
+        .await;
+        assert_eq!(
+            req.sender_auth.and_then(|auth| auth.bearer_token),
+            Some(BearerToken::from("leader_token"))
+        );
+    }
+
+    #[tokio::test]
+    async fn reject_with_problem_document() {
+        async fn handler(DapRequestExtractor(_req): DapRequestExtractor) {}
+
+        let router = Router::new().route("/:version/:task_id/parse-task-id", post(handler));
+
+        let task_id = TaskId(thread_rng().gen());
+        for (uri, content_type) in [
+            // Unrecognized media type.
+            (
+                format!("/v09/{}/parse-task-id", task_id.to_base64url()),
+                "application/dap-unknown",
+            ),
+            // Malformed task ID.
+            (
+                "/v09/not-a-task-id/parse-task-id".to_string(),
+                "application/dap-report",
+            ),
+            // Authenticated endpoint without credentials.
+            (
+                format!("/v09/{}/parse-task-id", task_id.to_base64url()),
+                "application/dap-aggregate-share-req",
+            ),
+        ] {
+            let resp = router
+                .clone()
+                .oneshot(
+                    Request::builder()
+                        .method("POST")
+                        .uri(&uri)
+                        .header(CONTENT_TYPE, content_type)
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(resp.status(), StatusCode::BAD_REQUEST, "{uri}");
+            assert_eq!(
+                resp.headers().get(CONTENT_TYPE).unwrap(),
+                "application/problem+json",
+                "{uri}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn reject_oversized_body() {
+        async fn handler(DapRequestExtractor(_req): DapRequestExtractor) {}
+
+        let router = Router::new()
+            .route("/:version/parse-version", post(handler))
+            .layer(Extension(RequestBodyLimit(16)));
+
+        for (len, want_status) in [(16, StatusCode::OK), (17, StatusCode::PAYLOAD_TOO_LARGE)] {
+            let resp = router
+                .clone()
+                .oneshot(
+                    Request::builder()
+                        .method("POST")
+                        .uri("/v09/parse-version")
+                        .body(Body::from(vec![0; len]))
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(resp.status(), want_status, "body length {len}");
+        }
+    }
+
+    // The body limit is configured in the service config and enforced by the router, both when the
+    // client declares the length of the body and when it doesn't.
+    #[tokio::test]
+    async fn reject_oversized_body_by_media_type() {
+        let mut service_config = test_service_config(DapRole::Helper);
+        service_config.max_request_body_bytes = Some(16);
+        service_config.max_request_body_bytes_per_media_type = [(
+            "application/dap-aggregation-job-init-req".to_string(),
+            1 << 10,
+        )]
+        .into();
+        let router = super::new(DapRole::Helper, test_app(service_config));
+        let task_id = TaskId(thread_rng().gen());
+        let agg_job_id = AggregationJobId(thread_rng().gen());
+
+        for (method, uri, content_type, limit) in [
+            (
+                "POST",
+                format!("/v09/tasks/{}/aggregate_shares", task_id.to_base64url()),
+                "application/dap-aggregate-share-req",
+                16,
+            ),
+            (
+                "PUT",
+                format!(
+                    "/v09/tasks/{}/aggregation_jobs/{}",
+                    task_id.to_base64url(),
+                    agg_job_id.to_base64url()
+                ),
+                "application/dap-aggregation-job-init-req",
+                1 << 10,
+            ),
+        ] {
+            for declare_length in [false, true] {
+                for (len, too_large) in [(limit, false), (limit + 1, true)] {
+                    let mut req = Request::builder()
+                        .method(method)
+                        .uri(&uri)
+                        .header(CONTENT_TYPE, content_type)
+                        .header("DAP-Auth-Token", "leader_token");
+                    if declare_length {
+                        req = req.header(CONTENT_LENGTH, len);
+                    }
+                    let resp = router
+                        .clone()
+                        .oneshot(req.body(Body::from(vec![0; len])).unwrap())
+                        .await
+                        .unwrap();
+                    // Bodies within the limit are decoded, which fails since they're garbage.
+                    let want_status = if too_large {
+                        StatusCode::PAYLOAD_TOO_LARGE
+                    } else {
+                        StatusCode::BAD_REQUEST
+                    };
+                    assert_eq!(
+                        resp.status(),
+                        want_status,
+                        "{content_type}: body length {len}, declared: {declare_length}"
+                    );
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn decompress_agg_job_init_req() {
+        async fn handler(DapRequestExtractor(req): DapRequestExtractor) -> Vec<u8> {
+            req.payload
+        }
+
+        let compression_config = CompressionConfig {
+            min_body_bytes: 0,
+            max_decompressed_body_bytes: 1 << 20,
+        };
+        let router = Router::new()
+            .route("/:version/parse-version", post(handler))
+            .layer(Extension(compression_config));
+
+        let agg_job_init_req = gen_dummy_agg_job_init_req(2000);
+        let body = agg_job_init_req
+            .get_encoded_with_param(&DapVersion::DraftLatest)
+            .unwrap();
+        let resp = router
+            .oneshot(
+                Request::builder()
+                    .method("POST")
+                    .uri("/v09/parse-version")
+                    .header(CONTENT_ENCODING, compression::GZIP)
+                    .body(Body::from(compression::compress(&body)))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let payload = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        assert_eq!(
+            AggregationJobInitReq::get_decoded_with_param(&DapVersion::DraftLatest, &payload)
+                .unwrap()
+                .prep_inits,
+            agg_job_init_req.prep_inits
+        );
+    }
+
+    #[tokio::test]
+    async fn reject_compressed_body() {
+        async fn handler(DapRequestExtractor(_req): DapRequestExtractor) {}
+
+        let compressed = compression::compress(&vec![0; 1 << 12]);
+        for (compression_config, body_limit, want_status) in [
+            // Compression isn't configured.
+            (None, None, StatusCode::UNSUPPORTED_MEDIA_TYPE),
+            // The body decompresses to more than the limit.
+            (
+                Some(CompressionConfig {
+                    min_body_bytes: 0,
+                    max_decompressed_body_bytes: 1 << 10,
+                }),
+                None,
+                StatusCode::PAYLOAD_TOO_LARGE,
+            ),
+            // The body decompresses to more than the request body limit.
+            (
+                Some(CompressionConfig {
+                    min_body_bytes: 0,
+                    max_decompressed_body_bytes: 1 << 20,
+                }),
+                Some(RequestBodyLimit(1 << 10)),
+                StatusCode::PAYLOAD_TOO_LARGE,
+            ),
+        ] {
+            let mut router = Router::new().route("/:version/parse-version", post(handler));
+            if let Some(compression_config) = compression_config {
+                router = router.layer(Extension(compression_config));
+            }
+            if let Some(body_limit) = body_limit {
+                router = router.layer(Extension(body_limit));
+            }
+
+            let resp = router
+                .oneshot(
+                    Request::builder()
+                        .method("POST")
+                        .uri("/v09/parse-version")
+                        .header(CONTENT_ENCODING, compression::GZIP)
+                        .body(Body::from(compressed.clone()))
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(resp.status(), want_status);
+        }
+    }
+
+    #[tokio::test]
+    async fn accept_identity_content_encoding() {
+        async fn handler(DapRequestExtractor(req): DapRequestExtractor) -> Vec<u8> {
+            req.payload
+        }
+
+        // The body is taken as is, even if compression isn't configured.
+        let router = Router::new().route("/:version/parse-version", post(handler));
+        let resp = router
+            .oneshot(
+                Request::builder()
+                    .method("POST")
+                    .uri("/v09/parse-version")
+                    .header(CONTENT_ENCODING, compression::IDENTITY)
+                    .body(Body::from(b"some body".to_vec()))
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(
+            hyper::body::to_bytes(resp.into_body()).await.unwrap(),
+            b"some body".as_slice()
+        );
+    }
+
+    #[tokio::test]
+    async fn compress_response() {
+        let mut service_config = test_service_config(DapRole::Helper);
+        service_config.compression = Some(CompressionConfig {
+            min_body_bytes: 0,
+            max_decompressed_body_bytes: 1 << 20,
+        });
+        let router = super::new(DapRole::Helper, test_app(service_config));
+
+        let resp = router
+            .oneshot(
+                Request::builder()
+                    .uri("/capabilities")
+                    .header(ACCEPT_ENCODING, "deflate, gzip")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers()[CONTENT_ENCODING], compression::GZIP);
+        assert_eq!(resp.headers()[ACCEPT_ENCODING], compression::GZIP);
+
+        let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+        let body = compression::decompress(&body, 1 << 20).unwrap();
+        let capabilities: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(capabilities["hpke_kem_ids"], serde_json::json!([0x20]));
+    }
 }
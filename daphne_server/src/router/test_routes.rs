@@ -15,7 +15,7 @@ use daphne::{
     hpke::HpkeReceiverConfig,
     messages::{Base64Encode, TaskId},
     roles::{leader, DapLeader},
-    DapVersion,
+    DapError, DapLeaderProcessTelemetry, DapVersion,
 };
 use daphne_service_utils::{
     test_route_types::{InternalTestAddTask, InternalTestEndpointForTask},
@@ -46,6 +46,7 @@ where
 
     router
         .route("/internal/delete_all", post(delete_all))
+        .route("/internal/debug/memory", get(memory_usage))
         .route("/internal/test/ready", post(StatusCode::OK))
         .route(
             "/internal/test/endpoint_for_task",
@@ -56,7 +57,7 @@ where
             post(endpoint_for_task),
         )
         // TODO: could be removed after we add the divviup api
-        .route("/internal/test/add_task", post(add_task))
+        .route("/internal/test/add_task", post(add_task_default))
         .route("/:version/internal/test/add_task", post(add_task))
         .route(
             "/:version/internal/test/add_hpke_config",
@@ -66,9 +67,18 @@ where
 
 #[tracing::instrument(skip(app))]
 async fn leader_process(State(app): State<Arc<App>>) -> Response {
-    match leader::process(&*app, "unspecified-daphne-worker-host", 100).await {
-        Ok(telem) => (StatusCode::OK, Json(telem)).into_response(),
-        Err(e) => AxumDapResponse::new_error(e, app.server_metrics()).into_response(),
+    let mut telem = DapLeaderProcessTelemetry::default();
+    let res = async {
+        // Finish any aggregation job that was interrupted by a restart before starting new work.
+        telem.reports_aggregated += app.resume_agg_jobs_once().await?;
+        leader::process_into(&*app, "unspecified-daphne-worker-host", 100, &mut telem).await
+    };
+    match res.await {
+        Ok(()) => (StatusCode::OK, Json(telem)).into_response(),
+        Err(e) => {
+            tracing::warn!(?telem, "processing failed partway");
+            AxumDapResponse::new_error(e, app.server_metrics()).into_response()
+        }
     }
 }
 
@@ -97,6 +107,14 @@ async fn delete_all(State(app): State<Arc<App>>) -> impl IntoResponse {
     }
 }
 
+#[tracing::instrument(skip(app))]
+async fn memory_usage(State(app): State<Arc<App>>) -> impl IntoResponse {
+    match app.internal_memory_usage().await {
+        Ok(usage) => (StatusCode::OK, Json(usage)).into_response(),
+        Err(e) => AxumDapResponse::new_error(e, &*app.metrics).into_response(),
+    }
+}
+
 async fn endpoint_for_task_default(
     state: State<Arc<App>>,
     cmd: Json<InternalTestEndpointForTask>,
@@ -121,6 +139,14 @@ async fn endpoint_for_task(
     }
 }
 
+async fn add_task_default(
+    state: State<Arc<App>>,
+    cmd: Json<InternalTestAddTask>,
+) -> impl IntoResponse {
+    let version = state.0.service_config.default_version;
+    add_task(state, Path(version), cmd).await
+}
+
 #[tracing::instrument(skip(app, cmd))]
 async fn add_task(
     State(app): State<Arc<App>>,
@@ -152,3 +178,220 @@ async fn add_hpke_config(
         Err(e) => AxumDapResponse::new_error(e, &*app.metrics).into_response(),
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::sync::Arc;
+
+    use axum::{
+        body::Body,
+        http::{header::CONTENT_TYPE, Request, StatusCode},
+    };
+    use daphne::{
+        hpke::{HpkeConfig, HpkeKemId, HpkeReceiverConfig},
+        messages::{
+            encode_base64url, Base64Encode, HpkeCiphertext, Report, ReportId, ReportMetadata,
+            TaskId, Time,
+        },
+        roles::{DapAggregator, DapLeader},
+        testing::MockMemoryUsage,
+    };
+    use daphne_service_utils::DapRole;
+    use prio::codec::Encode;
+    use rand::{thread_rng, Rng};
+    use tower::ServiceExt;
+
+    use super::add_test_routes;
+    use crate::test_utils::{test_app, test_service_config};
+
+    fn add_task_cmd(
+        task_id: &TaskId,
+        role: DapRole,
+        collector_hpke_config: &HpkeConfig,
+        task_expiration: Time,
+    ) -> Request<Body> {
+        add_task_cmd_with_vdaf(
+            task_id,
+            role,
+            collector_hpke_config,
+            task_expiration,
+            serde_json::json!({ "type": "Prio3Count" }),
+        )
+    }
+
+    fn add_task_cmd_with_vdaf(
+        task_id: &TaskId,
+        role: DapRole,
+        collector_hpke_config: &HpkeConfig,
+        task_expiration: Time,
+        vdaf: serde_json::Value,
+    ) -> Request<Body> {
+        let mut body = serde_json::json!({
+            "task_id": task_id.to_base64url(),
+            "leader": "http://leader.example.com/",
+            "helper": "http://helper.example.com/",
+            "vdaf": vdaf,
+            "leader_authentication_token": "leader-token",
+            "role": role,
+            "vdaf_verify_key": encode_base64url([1; 16]),
+            "query_type": 1,
+            "min_batch_size": 10,
+            "time_precision": 3600,
+            "collector_hpke_config": encode_base64url(collector_hpke_config.get_encoded()),
+            "task_expiration": task_expiration,
+        });
+        if role == DapRole::Leader {
+            body["collector_authentication_token"] = "collector-token".into();
+        }
+        Request::builder()
+            .method("POST")
+            .uri("/internal/test/add_task")
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(body.to_string()))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn add_task() {
+        let app = Arc::new(test_app(test_service_config(DapRole::Helper)));
+        let router = add_test_routes(axum::Router::new(), DapRole::Helper).with_state(app.clone());
+
+        let task_id = TaskId(thread_rng().gen());
+        let collector_hpke_config = HpkeReceiverConfig::gen(1, HpkeKemId::X25519HkdfSha256)
+            .unwrap()
+            .config;
+
+        assert!(app.get_task_config_for(&task_id).await.unwrap().is_none());
+        let resp = router
+            .oneshot(add_task_cmd(
+                &task_id,
+                DapRole::Helper,
+                &collector_hpke_config,
+                app.get_current_time() + 86400,
+            ))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let task_config = app.get_task_config_for(&task_id).await.unwrap().unwrap();
+        assert_eq!(task_config.min_batch_size, 10);
+        assert_eq!(task_config.collector_hpke_config, collector_hpke_config);
+    }
+
+    #[tokio::test]
+    async fn add_task_rejects_invalid_task() {
+        let app = Arc::new(test_app(test_service_config(DapRole::Helper)));
+        let router = add_test_routes(axum::Router::new(), DapRole::Helper).with_state(app.clone());
+
+        let task_id = TaskId(thread_rng().gen());
+        let collector_hpke_config = HpkeReceiverConfig::gen(1, HpkeKemId::X25519HkdfSha256)
+            .unwrap()
+            .config;
+
+        // The task has already expired.
+        let resp = router
+            .oneshot(add_task_cmd(
+                &task_id,
+                DapRole::Helper,
+                &collector_hpke_config,
+                app.get_current_time() - 1,
+            ))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        assert!(app.get_task_config_for(&task_id).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn add_task_rejects_vdaf_failing_self_test() {
+        let app = Arc::new(test_app(test_service_config(DapRole::Helper)));
+        let router = add_test_routes(axum::Router::new(), DapRole::Helper).with_state(app.clone());
+
+        let task_id = TaskId(thread_rng().gen());
+        let collector_hpke_config = HpkeReceiverConfig::gen(1, HpkeKemId::X25519HkdfSha256)
+            .unwrap()
+            .config;
+
+        // A histogram with no buckets.
+        let resp = router
+            .oneshot(add_task_cmd_with_vdaf(
+                &task_id,
+                DapRole::Helper,
+                &collector_hpke_config,
+                app.get_current_time() + 86400,
+                serde_json::json!({
+                    "type": "Prio3Histogram",
+                    "length": "0",
+                    "chunk_length": "1",
+                }),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        assert!(app.get_task_config_for(&task_id).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn memory_usage_grows_with_reports() {
+        async fn memory_usage(router: &axum::Router) -> MockMemoryUsage {
+            let resp = router
+                .clone()
+                .oneshot(
+                    Request::builder()
+                        .uri("/internal/debug/memory")
+                        .body(Body::empty())
+                        .unwrap(),
+                )
+                .await
+                .unwrap();
+            assert_eq!(resp.status(), StatusCode::OK);
+            serde_json::from_slice(&hyper::body::to_bytes(resp.into_body()).await.unwrap()).unwrap()
+        }
+
+        let app = Arc::new(test_app(test_service_config(DapRole::Leader)));
+        let router = add_test_routes(axum::Router::new(), DapRole::Leader).with_state(app.clone());
+
+        let task_id = TaskId(thread_rng().gen());
+        let collector_hpke_config = HpkeReceiverConfig::gen(1, HpkeKemId::X25519HkdfSha256)
+            .unwrap()
+            .config;
+        let now = app.get_current_time();
+        let resp = router
+            .clone()
+            .oneshot(add_task_cmd(
+                &task_id,
+                DapRole::Leader,
+                &collector_hpke_config,
+                now + 86400,
+            ))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let before = memory_usage(&router).await;
+        assert!(before.tasks > 0);
+        for _ in 0..3 {
+            let ciphertext = HpkeCiphertext {
+                config_id: 1,
+                enc: vec![0; 32],
+                payload: vec![0; 64],
+            };
+            let report = Report {
+                draft02_task_id: None,
+                report_metadata: ReportMetadata {
+                    id: ReportId(thread_rng().gen()),
+                    time: now,
+                    draft02_extensions: None,
+                },
+                public_share: Vec::new(),
+                encrypted_input_shares: [ciphertext.clone(), ciphertext],
+            };
+            app.put_report(&report, &task_id).await.unwrap();
+        }
+
+        let after = memory_usage(&router).await;
+        assert!(after.report_store > before.report_store);
+        assert!(after.total > before.total);
+        assert_eq!(after.tasks, before.tasks);
+    }
+}
@@ -0,0 +1,226 @@
+// Copyright (c) 2024 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! Graceful shutdown of [`App`](crate::App).
+//!
+//! Once shutdown is triggered, the router stops accepting requests that would start new work
+//! (uploads and, for the Helper, aggregation job initialization) and the Leader stops dequeuing
+//! work items. Requests that are already in flight, including those driving aggregation jobs that
+//! were dequeued before shutdown, are allowed to complete. The binary can await
+//! [`Shutdown::wait_for_drain`] before exiting.
+
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use axum::{
+    http::{header::CONTENT_TYPE, HeaderMap, StatusCode},
+    response::{IntoResponse, Response},
+};
+use daphne::{constants::DapMediaType, DapVersion};
+use tokio::sync::Notify;
+
+/// Shutdown handle for [`App`](crate::App), obtained from
+/// [`App::shutdown_handle`](crate::App::shutdown_handle).
+#[derive(Debug, Default)]
+pub struct Shutdown {
+    triggered: AtomicBool,
+    in_flight: AtomicUsize,
+    drained: Notify,
+}
+
+/// Marks a unit of work as in flight for as long as it is held. See [`Shutdown::track`].
+#[must_use]
+pub struct InFlight<'s>(&'s Shutdown);
+
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        if self.0.in_flight.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.0.drained.notify_waiters();
+        }
+    }
+}
+
+impl Shutdown {
+    /// Start shutting down. New work is refused from now on.
+    pub fn trigger(&self) {
+        self.triggered.store(true, Ordering::SeqCst);
+        self.drained.notify_waiters();
+    }
+
+    /// Whether shutdown has been triggered.
+    pub fn is_triggered(&self) -> bool {
+        self.triggered.load(Ordering::SeqCst)
+    }
+
+    /// Track a unit of work so that [`Self::wait_for_drain`] waits for it to complete.
+    pub fn track(&self) -> InFlight<'_> {
+        self.in_flight.fetch_add(1, Ordering::SeqCst);
+        InFlight(self)
+    }
+
+    /// Wait until shutdown has been triggered and all in-flight work has completed.
+    pub async fn wait_for_drain(&self) {
+        loop {
+            // Register for notifications before checking the condition so that a notification
+            // sent in between isn't missed.
+            let drained = self.drained.notified();
+            tokio::pin!(drained);
+            drained.as_mut().enable();
+
+            if self.is_triggered() && self.in_flight.load(Ordering::SeqCst) == 0 {
+                return;
+            }
+            drained.await;
+        }
+    }
+}
+
+/// Whether the request would start new work, i.e., whether it uploads a report or initializes an
+/// aggregation job.
+pub(crate) fn starts_new_work(headers: &HeaderMap) -> bool {
+    let Some(content_type) = headers.get(CONTENT_TYPE).and_then(|v| v.to_str().ok()) else {
+        return false;
+    };
+    [DapVersion::Draft02, DapVersion::DraftLatest]
+        .into_iter()
+        .any(|version| {
+            matches!(
+                DapMediaType::from_str_for_version(version, Some(content_type)),
+                Some(DapMediaType::Report | DapMediaType::AggregationJobInitReq)
+            )
+        })
+}
+
+/// The response to a request for new work received during shutdown.
+pub(crate) fn shutting_down() -> Response {
+    (StatusCode::SERVICE_UNAVAILABLE, "server is shutting down").into_response()
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        sync::{atomic::Ordering, Arc},
+        time::Duration,
+    };
+
+    use axum::{
+        body::Body,
+        http::{header::CONTENT_TYPE, Request, StatusCode},
+    };
+    use daphne::messages::{AggregationJobId, Base64Encode, TaskId};
+    use daphne_service_utils::DapRole;
+    use rand::{thread_rng, Rng};
+    use tower::ServiceExt;
+
+    use super::Shutdown;
+    use crate::{
+        router,
+        test_utils::{test_app, test_service_config},
+    };
+
+    #[tokio::test]
+    async fn wait_for_drain_waits_for_in_flight_work() {
+        let shutdown = Arc::new(Shutdown::default());
+        let in_flight = shutdown.track();
+        shutdown.trigger();
+
+        let drain = tokio::spawn({
+            let shutdown = shutdown.clone();
+            async move { shutdown.wait_for_drain().await }
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert!(!drain.is_finished());
+
+        drop(in_flight);
+        tokio::time::timeout(Duration::from_secs(1), drain)
+            .await
+            .expect("shutdown did not drain")
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn reject_uploads_while_draining() {
+        let app = test_app(test_service_config(DapRole::Leader));
+        let shutdown = app.shutdown_handle();
+        let router = router::new(DapRole::Leader, app);
+
+        // Work that was in flight when shutdown was triggered, e.g., an aggregation job.
+        let in_flight = shutdown.track();
+        shutdown.trigger();
+
+        let task_id = TaskId(thread_rng().gen());
+        let resp = router
+            .oneshot(
+                Request::builder()
+                    .method("PUT")
+                    .uri(format!("/v09/tasks/{}/reports", task_id.to_base64url()))
+                    .header(CONTENT_TYPE, "application/dap-report")
+                    .body(Body::empty())
+                    .unwrap(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+        // The in-flight work is allowed to complete, after which the server has drained.
+        let drain = tokio::spawn({
+            let shutdown = shutdown.clone();
+            async move { shutdown.wait_for_drain().await }
+        });
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert!(!drain.is_finished());
+        drop(in_flight);
+        tokio::time::timeout(Duration::from_secs(1), drain)
+            .await
+            .expect("shutdown did not drain")
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn only_accepted_requests_are_tracked() {
+        let app = test_app(test_service_config(DapRole::Helper));
+        let shutdown = app.shutdown_handle();
+        let router = router::new(DapRole::Helper, app);
+        shutdown.trigger();
+
+        // A request whose body never arrives stays in flight once it has been accepted.
+        let agg_job_req = |content_type: &str| {
+            Request::builder()
+                .method("POST")
+                .uri(format!(
+                    "/v09/tasks/{}/aggregation_jobs/{}",
+                    TaskId(thread_rng().gen()).to_base64url(),
+                    AggregationJobId(thread_rng().gen()).to_base64url(),
+                ))
+                .header(CONTENT_TYPE, content_type)
+                .body(Body::wrap_stream(futures::stream::pending::<
+                    Result<Vec<u8>, std::io::Error>,
+                >()))
+                .unwrap()
+        };
+
+        // Continuing an aggregation job is accepted and keeps the app from draining.
+        let agg_job_cont = tokio::spawn(
+            router
+                .clone()
+                .oneshot(agg_job_req("application/dap-aggregation-job-continue-req")),
+        );
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert_eq!(shutdown.in_flight.load(Ordering::SeqCst), 1);
+
+        // Initializing one is refused before its body is read and isn't tracked.
+        let resp = tokio::time::timeout(
+            Duration::from_secs(1),
+            router.oneshot(agg_job_req("application/dap-aggregation-job-init-req")),
+        )
+        .await
+        .expect("refused request was not answered")
+        .unwrap();
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(shutdown.in_flight.load(Ordering::SeqCst), 1);
+
+        agg_job_cont.abort();
+        tokio::time::timeout(Duration::from_secs(1), shutdown.wait_for_drain())
+            .await
+            .expect("shutdown did not drain");
+    }
+}
@@ -0,0 +1,405 @@
+// Copyright (c) 2024 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+use async_trait::async_trait;
+use axum::http::StatusCode;
+use daphne::{
+    messages::{ReportId, TaskId},
+    roles::leader::AggregationJobCheckpoint,
+    DapAggregateShare, MetaAggregationJobId,
+};
+use daphne_service_utils::durable_requests::{
+    bindings::{self, AggregateStoreMergeReq, AggregateStoreMergeResp, DurableMethod},
+    DurableRequest, ObjectIdFrom,
+};
+use serde::{de::DeserializeOwned, Serialize};
+
+use super::{Error, StorageBackend};
+
+/// A [`StorageBackend`] that keeps everything in memory. It implements the durable objects used
+/// by the server the same way the storage proxy's durable objects do, so that the Leader and
+/// Helper can be run without a live proxy.
+///
+/// Clones share the same storage, the same way two processes pointed at the same storage proxy do.
+#[derive(Clone, Default)]
+pub struct InMemoryStorageBackend {
+    state: Arc<Mutex<State>>,
+}
+
+#[derive(Default)]
+struct State {
+    agg_stores: BTreeMap<ObjectIdFrom, AggregateStore>,
+    helper_states: BTreeMap<ObjectIdFrom, String>,
+    agg_job_resps: BTreeMap<ObjectIdFrom, String>,
+    agg_job_checkpoints: BTreeMap<ObjectIdFrom, BTreeMap<String, AggregationJobCheckpoint>>,
+    kv: HashMap<String, Vec<u8>>,
+    kv_expirations: HashMap<String, u64>,
+}
+
+impl State {
+    /// Delete the KV value for `key` if it has expired.
+    fn evict_expired_kv(&mut self, key: &str) {
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        if self
+            .kv_expirations
+            .get(key)
+            .is_some_and(|expiration| *expiration <= now)
+        {
+            self.kv.remove(key);
+            self.kv_expirations.remove(key);
+        }
+    }
+}
+
+#[derive(Default)]
+struct AggregateStore {
+    agg_share: DapAggregateShare,
+    report_ids: HashSet<ReportId>,
+    collected: bool,
+}
+
+fn bad_request(body: String) -> Error {
+    Error::Http {
+        status: StatusCode::BAD_REQUEST,
+        body,
+    }
+}
+
+fn parse<T: DeserializeOwned>(body: &[u8]) -> Result<T, Error> {
+    bincode::deserialize(body).map_err(|e| bad_request(format!("failed to deserialize: {e}")))
+}
+
+fn respond<T: Serialize>(resp: &T) -> Result<Vec<u8>, Error> {
+    Ok(serde_json::to_vec(resp)?)
+}
+
+impl AggregateStore {
+    fn handle(&mut self, method: bindings::AggregateStore, body: &[u8]) -> Result<Vec<u8>, Error> {
+        match method {
+            bindings::AggregateStore::GetMerged => respond(&self.report_ids),
+            bindings::AggregateStore::Merge => {
+                let AggregateStoreMergeReq {
+                    contained_reports,
+                    agg_share_delta,
+                } = parse(body)?;
+
+                if self.collected {
+                    return respond(&AggregateStoreMergeResp::AlreadyCollected);
+                }
+
+                let repeat_ids = contained_reports
+                    .iter()
+                    .filter(|id| self.report_ids.contains(id))
+                    .copied()
+                    .collect::<HashSet<_>>();
+                if !repeat_ids.is_empty() {
+                    return respond(&AggregateStoreMergeResp::ReplaysDetected(repeat_ids));
+                }
+
+                self.agg_share
+                    .merge(agg_share_delta)
+                    .map_err(|e| bad_request(e.to_string()))?;
+                self.report_ids.extend(contained_reports);
+                respond(&AggregateStoreMergeResp::Ok)
+            }
+            bindings::AggregateStore::Get => respond(&self.agg_share),
+            bindings::AggregateStore::MarkCollected => {
+                self.collected = true;
+                respond(&())
+            }
+            bindings::AggregateStore::CheckCollected => respond(&self.collected),
+        }
+    }
+}
+
+#[async_trait]
+impl StorageBackend for InMemoryStorageBackend {
+    async fn durable_request(&self, uri: &str, request: Vec<u8>) -> Result<Vec<u8>, Error> {
+        let request = DurableRequest::try_from(&request)
+            .map_err(|e| bad_request(format!("failed to decode durable request: {e}")))?;
+        let mut state = self.state.lock().unwrap();
+
+        if request.binding == bindings::AggregateStore::BINDING {
+            if let Some(method) = bindings::AggregateStore::try_from_uri(uri) {
+                return state
+                    .agg_stores
+                    .entry(request.id.clone())
+                    .or_default()
+                    .handle(method, request.body());
+            }
+        } else if request.binding == bindings::HelperState::BINDING {
+            match bindings::HelperState::try_from_uri(uri) {
+                Some(bindings::HelperState::PutIfNotExists) => {
+                    let helper_state_hex: String = parse(request.body())?;
+                    let inserted = !state.helper_states.contains_key(&request.id);
+                    if inserted {
+                        state
+                            .helper_states
+                            .insert(request.id.clone(), helper_state_hex);
+                    }
+                    return respond(&inserted);
+                }
+                Some(bindings::HelperState::Get) => {
+                    return respond(&state.helper_states.get(&request.id));
+                }
+                Some(bindings::HelperState::PutAggJobResp) => {
+                    let agg_job_resp_hex: String = parse(request.body())?;
+                    state
+                        .agg_job_resps
+                        .insert(request.id.clone(), agg_job_resp_hex);
+                    return respond(&());
+                }
+                Some(bindings::HelperState::GetAggJobResp) => {
+                    return respond(&state.agg_job_resps.get(&request.id));
+                }
+                Some(bindings::HelperState::DeleteAggJobResp) => {
+                    state.agg_job_resps.remove(&request.id);
+                    return respond(&());
+                }
+                None => (),
+            }
+        } else if request.binding == bindings::LeaderAggJobCheckpointStore::BINDING {
+            use bindings::LeaderAggJobCheckpointStore as Method;
+            let checkpoints = state
+                .agg_job_checkpoints
+                .entry(request.id.clone())
+                .or_default();
+            match Method::try_from_uri(uri) {
+                Some(Method::Put) => {
+                    let checkpoint: AggregationJobCheckpoint = parse(request.body())?;
+                    checkpoints.insert(
+                        Method::key(&checkpoint.task_id, &checkpoint.agg_job_id),
+                        checkpoint,
+                    );
+                    return respond(&());
+                }
+                Some(Method::Delete) => {
+                    let (task_id, agg_job_id): (TaskId, MetaAggregationJobId) =
+                        parse(request.body())?;
+                    checkpoints.remove(&Method::key(&task_id, &agg_job_id));
+                    return respond(&());
+                }
+                Some(Method::List) => {
+                    return respond(&checkpoints.values().collect::<Vec<_>>());
+                }
+                None => (),
+            }
+        }
+
+        Err(bad_request(format!(
+            "unexpected durable request: binding={}; uri={uri}",
+            request.binding
+        )))
+    }
+
+    async fn kv_get(&self, key: &str) -> Result<Option<Vec<u8>>, Error> {
+        let mut state = self.state.lock().unwrap();
+        state.evict_expired_kv(key);
+        Ok(state.kv.get(key).cloned())
+    }
+
+    async fn kv_put(&self, key: &str, value: Vec<u8>) -> Result<(), Error> {
+        let mut state = self.state.lock().unwrap();
+        state.kv.insert(key.to_string(), value);
+        state.kv_expirations.remove(key);
+        Ok(())
+    }
+
+    async fn kv_put_with_expiration(
+        &self,
+        key: &str,
+        value: Vec<u8>,
+        expiration: u64,
+    ) -> Result<(), Error> {
+        let mut state = self.state.lock().unwrap();
+        state.kv.insert(key.to_string(), value);
+        state.kv_expirations.insert(key.to_string(), expiration);
+        Ok(())
+    }
+
+    async fn kv_put_if_not_exists(&self, key: &str, value: Vec<u8>) -> Result<bool, Error> {
+        let mut state = self.state.lock().unwrap();
+        state.evict_expired_kv(key);
+        if state.kv.contains_key(key) {
+            return Ok(false);
+        }
+        state.kv.insert(key.to_string(), value);
+        Ok(true)
+    }
+
+    #[cfg(feature = "test-utils")]
+    async fn purge(&self) -> Result<(), Error> {
+        *self.state.lock().unwrap() = State::default();
+        Ok(())
+    }
+
+    #[cfg(feature = "test-utils")]
+    fn memory_usage(&self) -> Option<super::StorageMemoryUsage> {
+        use deepsize::DeepSizeOf;
+
+        let state = self.state.lock().unwrap();
+        let agg_stores = state
+            .agg_stores
+            .values()
+            .map(|agg_store| {
+                agg_store.agg_share.deep_size_of() + agg_store.report_ids.deep_size_of()
+            })
+            .sum();
+        let helper_states = state
+            .helper_states
+            .values()
+            .chain(state.agg_job_resps.values())
+            .map(String::deep_size_of)
+            .sum();
+        Some(super::StorageMemoryUsage {
+            kv: state
+                .kv
+                .iter()
+                .map(|(key, value)| {
+                    (
+                        key.clone(),
+                        String::deep_size_of(key) + value.deep_size_of(),
+                    )
+                })
+                .collect(),
+            durable: [
+                (bindings::AggregateStore::BINDING, agg_stores),
+                (bindings::HelperState::BINDING, helper_states),
+            ]
+            .into_iter()
+            .collect(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::SystemTime;
+
+    use daphne::{
+        messages::{ReportId, TaskId},
+        DapAggregateShare, DapAggregationParam, DapBatchBucket, DapVersion, MetaAggregationJobId,
+    };
+    use daphne_service_utils::durable_requests::bindings::{
+        self, AggregateStoreMergeReq, AggregateStoreMergeResp,
+    };
+
+    use super::InMemoryStorageBackend;
+    use crate::{storage_backend::StorageBackend, storage_proxy_connection::Do};
+
+    #[tokio::test]
+    async fn kv_put_with_expiration() {
+        let backend = InMemoryStorageBackend::default();
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+
+        backend
+            .kv_put_with_expiration("live", b"value".to_vec(), now + 3600)
+            .await
+            .unwrap();
+        backend
+            .kv_put_with_expiration("expired", b"value".to_vec(), now - 1)
+            .await
+            .unwrap();
+
+        assert_eq!(
+            backend.kv_get("live").await.unwrap(),
+            Some(b"value".to_vec())
+        );
+        assert_eq!(backend.kv_get("expired").await.unwrap(), None);
+        assert!(backend
+            .kv_put_if_not_exists("expired", b"new value".to_vec())
+            .await
+            .unwrap());
+    }
+
+    #[tokio::test]
+    async fn aggregate_store_merge_detects_replays_and_collection() {
+        let backend = InMemoryStorageBackend::default();
+        let durable = Do::new(&backend);
+        let bucket = DapBatchBucket::TimeInterval { batch_window: 0 };
+        let params = (
+            DapVersion::DraftLatest,
+            "some-task-id-hex",
+            &DapAggregationParam::Empty,
+            &bucket,
+        );
+        let merge = |report_ids: Vec<ReportId>| {
+            durable
+                .request(bindings::AggregateStore::Merge, params)
+                .encode_bincode(AggregateStoreMergeReq {
+                    contained_reports: report_ids,
+                    agg_share_delta: DapAggregateShare::default(),
+                })
+                .send::<AggregateStoreMergeResp>()
+        };
+
+        assert!(matches!(
+            merge(vec![ReportId([1; 16])]).await.unwrap(),
+            AggregateStoreMergeResp::Ok
+        ));
+        assert!(matches!(
+            merge(vec![ReportId([1; 16]), ReportId([2; 16])]).await.unwrap(),
+            AggregateStoreMergeResp::ReplaysDetected(replays) if replays.len() == 1
+        ));
+
+        durable
+            .request(bindings::AggregateStore::MarkCollected, params)
+            .send::<()>()
+            .await
+            .unwrap();
+        assert!(durable
+            .request(bindings::AggregateStore::CheckCollected, params)
+            .send::<bool>()
+            .await
+            .unwrap());
+        assert!(matches!(
+            merge(vec![ReportId([3; 16])]).await.unwrap(),
+            AggregateStoreMergeResp::AlreadyCollected
+        ));
+    }
+
+    #[tokio::test]
+    async fn helper_state_put_if_not_exists() {
+        let backend = InMemoryStorageBackend::default();
+        let durable = Do::new(&backend);
+        let task_id = TaskId([1; 32]);
+        let agg_job_id = MetaAggregationJobId::gen_for_version(DapVersion::DraftLatest);
+        let params = (DapVersion::DraftLatest, &task_id, &agg_job_id);
+
+        let got: Option<String> = durable
+            .request(bindings::HelperState::Get, params)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(got, None);
+
+        for (helper_state_hex, want_inserted) in [("first", true), ("second", false)] {
+            let inserted: bool = durable
+                .request(bindings::HelperState::PutIfNotExists, params)
+                .encode_bincode(helper_state_hex.to_string())
+                .send()
+                .await
+                .unwrap();
+            assert_eq!(inserted, want_inserted);
+        }
+
+        let got: Option<String> = durable
+            .request(bindings::HelperState::Get, params)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(got.as_deref(), Some("first"));
+    }
+}
@@ -0,0 +1,84 @@
+// Copyright (c) 2024 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! The storage used by [`App`](crate::App).
+//!
+//! Storage is split into two kinds:
+//! - Durable objects, which hold state that needs strong consistency, such as aggregate shares
+//!   and the Helper's aggregation job state. Requests are addressed to a durable object method
+//!   (see [`bindings`](daphne_service_utils::durable_requests::bindings)) and carry an encoded
+//!   [`DurableRequest`](daphne_service_utils::durable_requests::DurableRequest).
+//! - KV, an eventually consistent key-value store for configuration such as task configs, HPKE
+//!   receiver configs and bearer tokens.
+//!
+//! In production both are provided by a storage proxy worker (see
+//! [`StorageProxy`](crate::StorageProxy)). [`InMemoryStorageBackend`] can be used in tests.
+
+#[cfg(any(test, feature = "test-utils"))]
+mod in_memory;
+
+#[cfg(feature = "test-utils")]
+use std::collections::HashMap;
+
+use async_trait::async_trait;
+use axum::http::StatusCode;
+
+#[cfg(any(test, feature = "test-utils"))]
+pub use in_memory::InMemoryStorageBackend;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("serialization error: {0}")]
+    Serde(#[from] serde_json::Error),
+    #[error("network error: {0}")]
+    Reqwest(#[from] reqwest::Error),
+    #[error("http error. request returned status code {status} with the body {body}")]
+    Http { status: StatusCode, body: String },
+}
+
+/// The storage operations [`App`](crate::App) depends on. Values are opaque to the backend: the
+/// caller is responsible for serializing them.
+#[async_trait]
+pub trait StorageBackend: Send + Sync {
+    /// Send an encoded durable object request to the method identified by `uri` and return the
+    /// JSON encoded response.
+    async fn durable_request(&self, uri: &str, request: Vec<u8>) -> Result<Vec<u8>, Error>;
+
+    /// Get the value stored in KV under `key`, if any.
+    async fn kv_get(&self, key: &str) -> Result<Option<Vec<u8>>, Error>;
+
+    /// Store a value in KV, overwriting the existing value if there is one.
+    async fn kv_put(&self, key: &str, value: Vec<u8>) -> Result<(), Error>;
+
+    /// Like [`Self::kv_put`], except that the value is deleted at time `expiration` (in seconds
+    /// since the UNIX epoch).
+    async fn kv_put_with_expiration(
+        &self,
+        key: &str,
+        value: Vec<u8>,
+        expiration: u64,
+    ) -> Result<(), Error>;
+
+    /// Store a value in KV if there is none for `key`. Returns `false` if a value already
+    /// existed, in which case it is left unchanged.
+    async fn kv_put_if_not_exists(&self, key: &str, value: Vec<u8>) -> Result<bool, Error>;
+
+    /// Wipe all storage. This is meant for tests only.
+    #[cfg(feature = "test-utils")]
+    async fn purge(&self) -> Result<(), Error>;
+
+    /// Measure the memory held by the backend. Returns `None` if the storage isn't held by this
+    /// process, e.g., because it is provided by the storage proxy.
+    #[cfg(feature = "test-utils")]
+    fn memory_usage(&self) -> Option<StorageMemoryUsage>;
+}
+
+/// The memory held by a [`StorageBackend`] in bytes.
+#[cfg(feature = "test-utils")]
+#[derive(Debug, Default)]
+pub struct StorageMemoryUsage {
+    /// The size of each KV entry, by key.
+    pub kv: HashMap<String, usize>,
+    /// The size of the durable objects, by binding.
+    pub durable: HashMap<&'static str, usize>,
+}
@@ -1,7 +1,7 @@
 // Copyright (c) 2024 Cloudflare, Inc. All rights reserved.
 // SPDX-License-Identifier: BSD-3-Clause
 
-use std::{any::Any, collections::HashMap};
+use std::{any::Any, collections::HashMap, time::Instant};
 
 use super::KvPrefix;
 
@@ -10,7 +10,27 @@ pub struct Cache {
     /// This map follows the same structure of KV queries.
     /// The first key (&'static str) is a KvPrefix::PREFIX
     /// The second key (String) is the key that is associated with this value
-    kv: HashMap<&'static str, HashMap<String, Box<dyn Any + Send + Sync + 'static>>>,
+    kv: HashMap<&'static str, HashMap<String, CacheEntry>>,
+
+    /// The version to assign to the next value put in the cache.
+    next_version: u64,
+}
+
+#[derive(Debug)]
+struct CacheEntry {
+    value: Box<dyn Any + Send + Sync + 'static>,
+    metadata: CacheMetadata,
+}
+
+/// Metadata about a cached value.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CacheMetadata {
+    /// When the value was put in the cache.
+    pub inserted_at: Instant,
+
+    /// Version tag of the value. Every put is assigned a new version, greater than that of any
+    /// value put before it.
+    pub version: u64,
 }
 
 pub enum GetResult<T> {
@@ -21,13 +41,27 @@ pub enum GetResult<T> {
 
 impl Cache {
     pub fn get<'s, P>(&'s self, key: &str) -> GetResult<&'s P::Value>
+    where
+        P: KvPrefix,
+    {
+        match self.get_with_metadata::<P>(key) {
+            GetResult::Found((t, _metadata)) => GetResult::Found(t),
+            GetResult::MismatchedType => GetResult::MismatchedType,
+            GetResult::NoFound => GetResult::NoFound,
+        }
+    }
+
+    pub fn get_with_metadata<'s, P>(&'s self, key: &str) -> GetResult<(&'s P::Value, CacheMetadata)>
     where
         P: KvPrefix,
     {
         match self.kv.get(P::PREFIX) {
-            Some(cache) => match cache.get(key).map(|t| t.downcast_ref::<P::Value>()) {
-                Some(Some(t)) => GetResult::Found(t),
-                Some(None) => GetResult::MismatchedType,
+            Some(cache) => match cache
+                .get(key)
+                .map(|entry| (entry.value.downcast_ref::<P::Value>(), entry.metadata))
+            {
+                Some((Some(t), metadata)) => GetResult::Found((t, metadata)),
+                Some((None, _)) => GetResult::MismatchedType,
                 None => GetResult::NoFound,
             },
             None => GetResult::NoFound,
@@ -38,10 +72,18 @@ impl Cache {
     where
         P: KvPrefix,
     {
-        self.kv
-            .entry(P::PREFIX)
-            .or_default()
-            .insert(key, Box::new(value));
+        let metadata = CacheMetadata {
+            inserted_at: Instant::now(),
+            version: self.next_version,
+        };
+        self.next_version += 1;
+        self.kv.entry(P::PREFIX).or_default().insert(
+            key,
+            CacheEntry {
+                value: Box::new(value),
+                metadata,
+            },
+        );
     }
 
     pub fn delete<P>(&mut self, key: &str) -> GetResult<P::Value>
@@ -49,7 +91,10 @@ impl Cache {
         P: KvPrefix,
     {
         match self.kv.get_mut(P::PREFIX) {
-            Some(cache) => match cache.remove(key).map(|t| t.downcast::<P::Value>().ok()) {
+            Some(cache) => match cache
+                .remove(key)
+                .map(|entry| entry.value.downcast::<P::Value>().ok())
+            {
                 Some(Some(t)) => GetResult::Found(*t),
                 Some(None) => GetResult::MismatchedType,
                 None => GetResult::NoFound,
@@ -3,24 +3,36 @@
 
 pub(super) mod cache;
 
-use std::{any::Any, fmt::Display};
+use std::{any::Any, fmt::Display, time::Duration};
 
-use axum::http::StatusCode;
 use daphne_service_utils::durable_requests::KV_PATH_PREFIX;
 use serde::{de::DeserializeOwned, Serialize};
 use tokio::sync::RwLock;
 
-use crate::StorageProxyConfig;
-
-use super::{status_http_1_0_to_reqwest_0_11, Error};
+use crate::storage_backend::{Error, StorageBackend};
 pub(crate) use cache::Cache;
 
 pub(crate) struct Kv<'h> {
-    config: &'h StorageProxyConfig,
-    http: &'h reqwest::Client,
+    backend: &'h dyn StorageBackend,
     cache: &'h RwLock<Cache>,
 }
 
+/// Where a value read from KV came from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Freshness {
+    /// The value was served from the cache.
+    Cached {
+        /// How long the value has been in the cache.
+        age: Duration,
+
+        /// The version tag the cache assigned to the value.
+        version: u64,
+    },
+
+    /// The value was fetched from storage.
+    Fetched,
+}
+
 pub trait KvPrefix {
     const PREFIX: &'static str;
 
@@ -29,7 +41,9 @@ pub trait KvPrefix {
 }
 
 pub mod prefix {
-    use daphne::{auth::BearerToken, messages::TaskId, DapTaskConfig, DapVersion};
+    use daphne::{
+        auth::BearerToken, messages::TaskId, DapRetainedAggShare, DapTaskConfig, DapVersion,
+    };
     use daphne_service_utils::config::HpkeRecieverConfigList;
 
     use super::KvPrefix;
@@ -65,19 +79,28 @@ pub mod prefix {
         type Key = TaskId;
         type Value = BearerToken;
     }
+
+    pub struct RetainedAggShare();
+    impl KvPrefix for RetainedAggShare {
+        const PREFIX: &'static str = "retained_agg_share/task";
+
+        type Key = String;
+        type Value = DapRetainedAggShare;
+    }
+
+    /// Hex-encoded aggregation parameter of the first aggregation job for a bucket.
+    pub struct BucketAggParam();
+    impl KvPrefix for BucketAggParam {
+        const PREFIX: &'static str = "bucket_agg_param/task";
+
+        type Key = String;
+        type Value = String;
+    }
 }
 
 impl<'h> Kv<'h> {
-    pub fn new(
-        config: &'h StorageProxyConfig,
-        client: &'h reqwest::Client,
-        cache: &'h RwLock<Cache>,
-    ) -> Self {
-        Self {
-            config,
-            http: client,
-            cache,
-        }
+    pub fn new(backend: &'h dyn StorageBackend, cache: &'h RwLock<Cache>) -> Self {
+        Self { backend, cache }
     }
 
     pub async fn get<P>(&self, key: &P::Key) -> Result<Option<P::Value>, Error>
@@ -106,23 +129,63 @@ impl<'h> Kv<'h> {
                 );
             }
         }
-        let resp = self
-            .http
-            .get(self.config.url.join(&key).unwrap())
-            .header(
-                super::DAP_STORAGE_AUTH_TOKEN,
-                self.config.auth_token.to_standard_header_value(),
-            )
-            .send()
-            .await?;
-        if resp.status() == status_http_1_0_to_reqwest_0_11(StatusCode::NOT_FOUND) {
-            Ok(None)
-        } else {
-            let resp = resp.error_for_status()?;
-            let t = resp.json().await?;
-            let r = mapper(&t);
-            self.cache.write().await.put::<P>(key, t);
-            Ok(r)
+        match self.fetch::<P>(&key).await? {
+            Some(t) => {
+                let r = mapper(&t);
+                self.cache.write().await.put::<P>(key, t);
+                Ok(r)
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Like [`Self::get`], but also report whether the value was served from the cache and, if
+    /// so, how old the cached value is.
+    pub async fn get_with_freshness<P>(
+        &self,
+        key: &P::Key,
+    ) -> Result<Option<(P::Value, Freshness)>, Error>
+    where
+        P: KvPrefix,
+        P::Value: Clone,
+    {
+        let key = Self::to_key::<P>(key);
+        tracing::debug!(key, "GET with freshness");
+        match self.cache.read().await.get_with_metadata::<P>(&key) {
+            cache::GetResult::NoFound => {}
+            cache::GetResult::Found((t, metadata)) => {
+                return Ok(Some((
+                    t.clone(),
+                    Freshness::Cached {
+                        age: metadata.inserted_at.elapsed(),
+                        version: metadata.version,
+                    },
+                )))
+            }
+            cache::GetResult::MismatchedType => {
+                tracing::warn!(
+                    "cache mismatched type, wanted {}",
+                    std::any::type_name::<P::Value>()
+                );
+            }
+        }
+        match self.fetch::<P>(&key).await? {
+            Some(t) => {
+                self.cache.write().await.put::<P>(key, t.clone());
+                Ok(Some((t, Freshness::Fetched)))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Fetch a value from storage, bypassing the cache.
+    async fn fetch<P>(&self, key: &str) -> Result<Option<P::Value>, Error>
+    where
+        P: KvPrefix,
+    {
+        match self.backend.kv_get(key).await? {
+            Some(value) => Ok(Some(serde_json::from_slice(&value)?)),
+            None => Ok(None),
         }
     }
 
@@ -132,16 +195,29 @@ impl<'h> Kv<'h> {
     {
         let key = Self::to_key::<P>(key);
         tracing::debug!(key, "PUT");
-        self.http
-            .post(self.config.url.join(&key).unwrap())
-            .header(
-                super::DAP_STORAGE_AUTH_TOKEN,
-                self.config.auth_token.to_standard_header_value(),
-            )
-            .body(serde_json::to_vec(&value).unwrap())
-            .send()
-            .await?
-            .error_for_status()?;
+        self.backend
+            .kv_put(&key, serde_json::to_vec(&value).unwrap())
+            .await?;
+        self.cache.write().await.put::<P>(key, value);
+        Ok(())
+    }
+
+    /// Like [`Self::put`], except that the value is deleted from KV at time `expiration` (in
+    /// seconds since the UNIX epoch).
+    pub async fn put_with_expiration<P>(
+        &self,
+        key: &P::Key,
+        value: P::Value,
+        expiration: u64,
+    ) -> Result<(), Error>
+    where
+        P: KvPrefix,
+    {
+        let key = Self::to_key::<P>(key);
+        tracing::debug!(key, expiration, "PUT with expiration");
+        self.backend
+            .kv_put_with_expiration(&key, serde_json::to_vec(&value).unwrap(), expiration)
+            .await?;
         self.cache.write().await.put::<P>(key, value);
         Ok(())
     }
@@ -160,23 +236,15 @@ impl<'h> Kv<'h> {
         let key = Self::to_key::<P>(key);
 
         tracing::debug!(key, "PUT if not exists");
-        let response = self
-            .http
-            .put(self.config.url.join(&key).unwrap())
-            .header(
-                super::DAP_STORAGE_AUTH_TOKEN,
-                self.config.auth_token.to_standard_header_value(),
-            )
-            .body(serde_json::to_vec(&value).unwrap())
-            .send()
-            .await?;
-
-        if response.status() == status_http_1_0_to_reqwest_0_11(StatusCode::CONFLICT) {
-            Ok(Some(value))
-        } else {
-            response.error_for_status()?;
+        if self
+            .backend
+            .kv_put_if_not_exists(&key, serde_json::to_vec(&value).unwrap())
+            .await?
+        {
             self.cache.write().await.put::<P>(key, value);
             Ok(None)
+        } else {
+            Ok(Some(value))
         }
     }
 
@@ -188,7 +256,120 @@ impl<'h> Kv<'h> {
         self.cache.write().await.put::<P>(key, value);
     }
 
+    /// Drop the cached value for `key`, if any, so that the next read fetches it from storage.
+    pub async fn invalidate<P>(&self, key: &P::Key)
+    where
+        P: KvPrefix,
+    {
+        let key = Self::to_key::<P>(key);
+        tracing::debug!(key, "INVALIDATE");
+        self.cache.write().await.delete::<P>(&key);
+    }
+
     fn to_key<P: KvPrefix>(key: &P::Key) -> String {
         format!("{KV_PATH_PREFIX}/{}/{key}", P::PREFIX)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use tokio::sync::RwLock;
+
+    use super::{Cache, Freshness, Kv, KvPrefix};
+    use crate::storage_backend::InMemoryStorageBackend;
+
+    struct TestPrefix();
+    impl KvPrefix for TestPrefix {
+        const PREFIX: &'static str = "test";
+
+        type Key = String;
+        type Value = String;
+    }
+
+    #[tokio::test]
+    async fn get_with_freshness_reports_cached_value() {
+        let backend = InMemoryStorageBackend::default();
+        let cache = RwLock::new(Cache::default());
+        let kv = Kv::new(&backend, &cache);
+        let key = "some key".to_string();
+
+        kv.only_cache_put::<TestPrefix>(&key, "first".into()).await;
+        let (value, freshness) = kv
+            .get_with_freshness::<TestPrefix>(&key)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(value, "first");
+        let Freshness::Cached {
+            age,
+            version: first_version,
+        } = freshness
+        else {
+            panic!("expected a cached value, got {freshness:?}");
+        };
+        assert!(age < Duration::from_secs(60));
+
+        // Replacing the value bumps its version.
+        kv.only_cache_put::<TestPrefix>(&key, "second".into()).await;
+        let (value, freshness) = kv
+            .get_with_freshness::<TestPrefix>(&key)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(value, "second");
+        let Freshness::Cached { version, .. } = freshness else {
+            panic!("expected a cached value, got {freshness:?}");
+        };
+        assert!(version > first_version);
+    }
+
+    #[tokio::test]
+    async fn invalidate_drops_cached_value() {
+        let backend = InMemoryStorageBackend::default();
+        let cache = RwLock::new(Cache::default());
+        let kv = Kv::new(&backend, &cache);
+        let key = "some key".to_string();
+        let other_key = "other key".to_string();
+
+        kv.only_cache_put::<TestPrefix>(&key, "value".into()).await;
+        kv.only_cache_put::<TestPrefix>(&other_key, "other value".into())
+            .await;
+        kv.invalidate::<TestPrefix>(&key).await;
+
+        let cache = cache.read().await;
+        assert!(matches!(
+            cache.get::<TestPrefix>(&Kv::to_key::<TestPrefix>(&key)),
+            super::cache::GetResult::NoFound
+        ));
+        assert!(matches!(
+            cache.get::<TestPrefix>(&Kv::to_key::<TestPrefix>(&other_key)),
+            super::cache::GetResult::Found(value) if value == "other value"
+        ));
+    }
+
+    #[tokio::test]
+    async fn put_if_not_exists_keeps_existing_value() {
+        let backend = InMemoryStorageBackend::default();
+        let cache = RwLock::new(Cache::default());
+        let kv = Kv::new(&backend, &cache);
+        let key = "some key".to_string();
+
+        let res = kv
+            .put_if_not_exists::<TestPrefix>(&key, "first".into())
+            .await
+            .unwrap();
+        assert_eq!(res, None);
+        let res = kv
+            .put_if_not_exists::<TestPrefix>(&key, "second".into())
+            .await
+            .unwrap();
+        assert_eq!(res.as_deref(), Some("second"));
+
+        // The stored value is unchanged, even after the cache is dropped.
+        kv.invalidate::<TestPrefix>(&key).await;
+        let value = kv.get::<TestPrefix>(&key).await.unwrap();
+        assert_eq!(value.as_deref(), Some("first"));
+    }
+}
@@ -9,40 +9,181 @@ pub(crate) mod kv;
 
 use std::fmt::Debug;
 
+use async_trait::async_trait;
 use axum::http::{Method, StatusCode};
 use daphne_service_utils::durable_requests::{
-    bindings::DurableMethod, DurableRequest, ObjectIdFrom, DO_PATH_PREFIX,
+    bindings::DurableMethod, DurableRequest, ObjectIdFrom, DO_PATH_PREFIX, KV_EXPIRATION_HEADER,
 };
 use serde::{de::DeserializeOwned, Serialize};
 
 pub(crate) use kv::Kv;
 
-use crate::StorageProxyConfig;
+use crate::{
+    storage_backend::{Error, StorageBackend},
+    StorageProxyConfig,
+};
 
 pub(crate) const DAP_STORAGE_AUTH_TOKEN: &str = "Authorization";
 
-#[derive(Debug, thiserror::Error)]
-pub(crate) enum Error {
-    #[error("serialization error: {0}")]
-    Serde(#[from] serde_json::Error),
-    #[error("network error: {0}")]
-    Reqwest(#[from] reqwest::Error),
-    #[error("http error. request returned status code {status} with the body {body}")]
-    Http { status: StatusCode, body: String },
+/// A [`StorageBackend`] backed by a storage proxy, a cloudflare worker that serves durable
+/// objects and KV over HTTP.
+pub struct StorageProxy {
+    config: StorageProxyConfig,
+    http: reqwest::Client,
+}
+
+impl StorageProxy {
+    /// Create a connection to the storage proxy. This fails if the configured TLS client identity
+    /// can't be parsed.
+    pub fn new(config: StorageProxyConfig) -> Result<Self, Error> {
+        let http = http_client_builder(&config)?.build()?;
+        Ok(Self { config, http })
+    }
+}
+
+fn http_client_builder(config: &StorageProxyConfig) -> Result<reqwest::ClientBuilder, Error> {
+    let mut builder = reqwest::Client::builder();
+    if let Some(identity) = &config.tls_client_identity {
+        let pem = format!("{}\n{}", identity.cert_pem, identity.key_pem);
+        builder = builder.identity(reqwest::Identity::from_pem(pem.as_bytes())?);
+    }
+    Ok(builder)
+}
+
+#[async_trait]
+impl StorageBackend for StorageProxy {
+    async fn durable_request(&self, uri: &str, request: Vec<u8>) -> Result<Vec<u8>, Error> {
+        let url = self
+            .config
+            .url
+            .join(&format!("{DO_PATH_PREFIX}{uri}"))
+            .unwrap();
+        let resp = self
+            .http
+            .post(url)
+            .body(request)
+            .header(
+                DAP_STORAGE_AUTH_TOKEN,
+                self.config.auth_token.to_standard_header_value(),
+            )
+            .send()
+            .await?;
+
+        if resp.status().is_success() {
+            Ok(resp.bytes().await?.to_vec())
+        } else {
+            Err(Error::Http {
+                status: status_reqwest_0_11_to_http_1_0(resp.status()),
+                body: resp.text().await?,
+            })
+        }
+    }
+
+    async fn kv_get(&self, key: &str) -> Result<Option<Vec<u8>>, Error> {
+        let resp = self
+            .http
+            .get(self.config.url.join(key).unwrap())
+            .header(
+                DAP_STORAGE_AUTH_TOKEN,
+                self.config.auth_token.to_standard_header_value(),
+            )
+            .send()
+            .await?;
+        if resp.status() == status_http_1_0_to_reqwest_0_11(StatusCode::NOT_FOUND) {
+            Ok(None)
+        } else {
+            let resp = resp.error_for_status()?;
+            Ok(Some(resp.bytes().await?.to_vec()))
+        }
+    }
+
+    async fn kv_put(&self, key: &str, value: Vec<u8>) -> Result<(), Error> {
+        self.http
+            .post(self.config.url.join(key).unwrap())
+            .header(
+                DAP_STORAGE_AUTH_TOKEN,
+                self.config.auth_token.to_standard_header_value(),
+            )
+            .body(value)
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    async fn kv_put_with_expiration(
+        &self,
+        key: &str,
+        value: Vec<u8>,
+        expiration: u64,
+    ) -> Result<(), Error> {
+        self.http
+            .post(self.config.url.join(key).unwrap())
+            .header(
+                DAP_STORAGE_AUTH_TOKEN,
+                self.config.auth_token.to_standard_header_value(),
+            )
+            .header(KV_EXPIRATION_HEADER, expiration)
+            .body(value)
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    async fn kv_put_if_not_exists(&self, key: &str, value: Vec<u8>) -> Result<bool, Error> {
+        let response = self
+            .http
+            .put(self.config.url.join(key).unwrap())
+            .header(
+                DAP_STORAGE_AUTH_TOKEN,
+                self.config.auth_token.to_standard_header_value(),
+            )
+            .body(value)
+            .send()
+            .await?;
+
+        if response.status() == status_http_1_0_to_reqwest_0_11(StatusCode::CONFLICT) {
+            Ok(false)
+        } else {
+            response.error_for_status()?;
+            Ok(true)
+        }
+    }
+
+    #[cfg(feature = "test-utils")]
+    async fn purge(&self) -> Result<(), Error> {
+        use daphne_service_utils::durable_requests::PURGE_STORAGE;
+
+        self.http
+            .delete(self.config.url.join(PURGE_STORAGE).unwrap())
+            .header(
+                DAP_STORAGE_AUTH_TOKEN,
+                self.config.auth_token.to_standard_header_value(),
+            )
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    #[cfg(feature = "test-utils")]
+    fn memory_usage(&self) -> Option<crate::storage_backend::StorageMemoryUsage> {
+        // The state is held by the storage proxy.
+        None
+    }
 }
 
 #[derive(Clone, Copy)]
 pub(crate) struct Do<'h> {
-    config: &'h StorageProxyConfig,
-    http: &'h reqwest::Client,
+    backend: &'h dyn StorageBackend,
     retry: bool,
 }
 
 impl<'h> Do<'h> {
-    pub fn new(config: &'h StorageProxyConfig, client: &'h reqwest::Client) -> Self {
+    pub fn new(backend: &'h dyn StorageBackend) -> Self {
         Self {
-            config,
-            http: client,
+            backend,
             retry: false,
         }
     }
@@ -71,32 +212,12 @@ impl<'d, B: DurableMethod + Debug, P: AsRef<[u8]>> RequestBuilder<'d, B, P> {
             path = ?self.path,
             "requesting DO",
         );
-        let url = self
-            .durable
-            .config
-            .url
-            .join(&format!("{DO_PATH_PREFIX}{}", self.path.to_uri()))
-            .unwrap();
         let resp = self
             .durable
-            .http
-            .post(url)
-            .body(self.request.into_bytes())
-            .header(
-                DAP_STORAGE_AUTH_TOKEN,
-                self.durable.config.auth_token.to_standard_header_value(),
-            )
-            .send()
+            .backend
+            .durable_request(self.path.to_uri(), self.request.into_bytes())
             .await?;
-
-        if resp.status().is_success() {
-            Ok(resp.json().await?)
-        } else {
-            Err(Error::Http {
-                status: status_reqwest_0_11_to_http_1_0(resp.status()),
-                body: resp.text().await?,
-            })
-        }
+        Ok(serde_json::from_slice(&resp)?)
     }
 }
 
@@ -184,3 +305,144 @@ pub fn status_http_1_0_to_reqwest_0_11(status: StatusCode) -> reqwest::StatusCod
 pub fn status_reqwest_0_11_to_http_1_0(status: reqwest::StatusCode) -> StatusCode {
     StatusCode::from_u16(status.as_u16()).unwrap()
 }
+
+#[cfg(test)]
+mod test {
+    use std::{io::Cursor, net::SocketAddr, sync::Arc};
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+        task::JoinHandle,
+    };
+    use tokio_rustls::{
+        rustls::{
+            server::AllowAnyAuthenticatedClient, Certificate, PrivateKey, RootCertStore,
+            ServerConfig,
+        },
+        TlsAcceptor,
+    };
+    use url::Url;
+
+    use super::{http_client_builder, StorageProxy};
+    use crate::{storage_backend::StorageBackend, StorageProxyConfig, TlsClientIdentity};
+
+    const CA_CERT: &str = include_str!("test_data/ca.pem");
+    const SERVER_CERT: &str = include_str!("test_data/server.pem");
+    const SERVER_KEY: &str = include_str!("test_data/server.key");
+    const CLIENT_CERT: &str = include_str!("test_data/client.pem");
+    const CLIENT_KEY: &str = include_str!("test_data/client.key");
+
+    fn parse_certs(pem: &str) -> Vec<Certificate> {
+        rustls_pemfile::certs(&mut Cursor::new(pem))
+            .unwrap()
+            .into_iter()
+            .map(Certificate)
+            .collect()
+    }
+
+    /// Start a stand-in for the storage proxy that requires a client certificate issued by the
+    /// test CA. It answers a single request with 404 and returns the certificate chain presented
+    /// by the client, or `None` if the handshake failed.
+    async fn mtls_server() -> (SocketAddr, JoinHandle<Option<Vec<Certificate>>>) {
+        let mut roots = RootCertStore::empty();
+        for cert in parse_certs(CA_CERT) {
+            roots.add(&cert).unwrap();
+        }
+        let key = PrivateKey(
+            rustls_pemfile::pkcs8_private_keys(&mut Cursor::new(SERVER_KEY))
+                .unwrap()
+                .remove(0),
+        );
+        let server_config = ServerConfig::builder()
+            .with_safe_defaults()
+            .with_client_cert_verifier(AllowAnyAuthenticatedClient::new(roots).boxed())
+            .with_single_cert(parse_certs(SERVER_CERT), key)
+            .unwrap();
+        let acceptor = TlsAcceptor::from(Arc::new(server_config));
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut stream = acceptor.accept(stream).await.ok()?;
+            let peer_certs = stream.get_ref().1.peer_certificates().map(<[_]>::to_vec);
+
+            // Read the request head before responding.
+            let mut request = Vec::new();
+            let mut buf = [0; 1024];
+            while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+                let n = stream.read(&mut buf).await.unwrap();
+                if n == 0 {
+                    break;
+                }
+                request.extend_from_slice(&buf[..n]);
+            }
+            stream
+                .write_all(
+                    b"HTTP/1.1 404 Not Found\r\ncontent-length: 0\r\nconnection: close\r\n\r\n",
+                )
+                .await
+                .unwrap();
+            stream.shutdown().await.unwrap();
+            peer_certs
+        });
+        (addr, server)
+    }
+
+    /// Connect to the server at `addr`, trusting the test CA in addition to the usual roots.
+    fn storage_proxy(
+        addr: SocketAddr,
+        tls_client_identity: Option<TlsClientIdentity>,
+    ) -> StorageProxy {
+        let config = StorageProxyConfig {
+            url: Url::parse(&format!("https://localhost:{}/", addr.port())).unwrap(),
+            auth_token: "the-token".into(),
+            tls_client_identity,
+        };
+        let http = http_client_builder(&config)
+            .unwrap()
+            .add_root_certificate(reqwest::Certificate::from_pem(CA_CERT.as_bytes()).unwrap())
+            .resolve("localhost", addr)
+            .build()
+            .unwrap();
+        StorageProxy { config, http }
+    }
+
+    #[tokio::test]
+    async fn client_cert_presented_when_configured() {
+        let (addr, server) = mtls_server().await;
+        let proxy = storage_proxy(
+            addr,
+            Some(TlsClientIdentity {
+                cert_pem: CLIENT_CERT.into(),
+                key_pem: CLIENT_KEY.into(),
+            }),
+        );
+
+        assert_eq!(proxy.kv_get("some/key").await.unwrap(), None);
+        assert_eq!(server.await.unwrap(), Some(parse_certs(CLIENT_CERT)));
+    }
+
+    #[tokio::test]
+    async fn no_client_cert_when_not_configured() {
+        let (addr, server) = mtls_server().await;
+        let proxy = storage_proxy(addr, None);
+
+        assert!(proxy.kv_get("some/key").await.is_err());
+        assert_eq!(server.await.unwrap(), None);
+    }
+
+    #[test]
+    fn invalid_client_identity() {
+        let config = StorageProxyConfig {
+            url: Url::parse("https://localhost/").unwrap(),
+            auth_token: "the-token".into(),
+            tls_client_identity: Some(TlsClientIdentity {
+                cert_pem: CLIENT_CERT.into(),
+                key_pem: String::new(),
+            }),
+        };
+        assert!(StorageProxy::new(config).is_err());
+    }
+}
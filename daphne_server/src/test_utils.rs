@@ -0,0 +1,70 @@
+// Copyright (c) 2024 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! Helpers for running [`App`] in unit tests.
+
+use daphne::{hpke::HpkeKemId, DapGlobalConfig, DapVersion};
+use daphne_service_utils::{
+    config::DaphneServiceConfig, metrics::DaphnePromServiceMetrics, DapRole,
+};
+
+use crate::{storage_backend::InMemoryStorageBackend, App};
+
+/// A service configuration suitable for tests.
+pub(crate) fn test_service_config(role: DapRole) -> DaphneServiceConfig {
+    DaphneServiceConfig {
+        env: "test".into(),
+        role,
+        global: DapGlobalConfig {
+            max_batch_duration: 360_000,
+            min_batch_interval_start: 259_200,
+            max_batch_interval_end: 259_200,
+            supported_hpke_kems: vec![HpkeKemId::X25519HkdfSha256],
+            allow_taskprov: false,
+            agg_share_idempotency_window: 3600,
+            revoked_hpke_config_ids: Vec::new(),
+            upload_dedup_cache_capacity: 0,
+            max_concurrent_agg_jobs_per_task: 0,
+            eager_agg_threshold: None,
+            max_report_shares_per_agg_job: None,
+            agg_job_resp_chunk_size: None,
+            work_item_visibility_timeout: None,
+            agg_job_retry_budget: 0,
+            agg_job_retry_backoff: 0,
+            report_debug_sample_rate: 0.0,
+            taskprov_vdaf_self_test: false,
+            allow_http_urls: true,
+        },
+        report_shard_key: [1; 32],
+        report_shard_count: 1,
+        base_url: None,
+        taskprov: None,
+        default_version: DapVersion::DraftLatest,
+        report_storage_epoch_duration: 300,
+        report_storage_max_future_time_skew: 300,
+        max_request_body_bytes: None,
+        max_request_body_bytes_per_media_type: Default::default(),
+        hpke_config_max_age: None,
+        work_queue_watermarks: None,
+        vdaf_worker_threads: None,
+        compression: None,
+    }
+}
+
+/// An [`App`] backed by in-memory storage.
+pub(crate) fn test_app(service_config: DaphneServiceConfig) -> App {
+    test_app_with_registry(service_config, &prometheus::Registry::new())
+}
+
+/// Like [`test_app`], except that the metrics are registered with `registry`.
+pub(crate) fn test_app_with_registry(
+    service_config: DaphneServiceConfig,
+    registry: &prometheus::Registry,
+) -> App {
+    App::new(
+        InMemoryStorageBackend::default(),
+        DaphnePromServiceMetrics::register(registry).unwrap(),
+        service_config,
+    )
+    .unwrap()
+}
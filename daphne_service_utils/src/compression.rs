@@ -0,0 +1,158 @@
+// Copyright (c) 2024 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+//! Compression of request and response bodies exchanged between the Aggregators. Aggregation job
+//! initialization requests carry thousands of report shares and are highly compressible, as are
+//! the corresponding responses.
+//!
+//! Bodies are compressed with the "gzip" content coding (RFC 9110, Section 8.4.1.3), which is
+//! negotiated via the `Content-Encoding` and `Accept-Encoding` headers.
+
+use std::{
+    fmt,
+    io::{Read, Write},
+};
+
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
+
+/// The content coding used to compress bodies.
+pub const GZIP: &str = "gzip";
+
+/// The content coding of bodies that aren't compressed.
+pub const IDENTITY: &str = "identity";
+
+/// Compress a body with the "gzip" content coding.
+pub fn compress(body: &[u8]) -> Vec<u8> {
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    // Writing to a `Vec` can't fail.
+    encoder
+        .write_all(body)
+        .and_then(|()| encoder.finish())
+        .expect("failed to compress body")
+}
+
+/// Decompress a body that was compressed with the "gzip" content coding. Decompression stops as
+/// soon as the output would exceed `max_decompressed_bytes`.
+pub fn decompress(body: &[u8], max_decompressed_bytes: usize) -> Result<Vec<u8>, DecompressError> {
+    // Read one byte more than the limit to tell whether the limit was exceeded.
+    let limit = u64::try_from(max_decompressed_bytes).unwrap_or(u64::MAX);
+    let mut decompressed = Vec::new();
+    GzDecoder::new(body)
+        .take(limit.saturating_add(1))
+        .read_to_end(&mut decompressed)
+        .map_err(|e| DecompressError::Invalid(e.to_string()))?;
+    if decompressed.len() > max_decompressed_bytes {
+        return Err(DecompressError::TooLarge {
+            limit: max_decompressed_bytes,
+        });
+    }
+    Ok(decompressed)
+}
+
+/// Whether a `Content-Encoding` names the "gzip" content coding. Per RFC 9110, "x-gzip" is an
+/// alias for it.
+pub fn is_gzip(content_encoding: &str) -> bool {
+    content_encoding.eq_ignore_ascii_case(GZIP) || content_encoding.eq_ignore_ascii_case("x-gzip")
+}
+
+/// Whether a `Content-Encoding` means the body isn't compressed.
+pub fn is_identity(content_encoding: &str) -> bool {
+    content_encoding.eq_ignore_ascii_case(IDENTITY)
+}
+
+/// Whether the value of an `Accept-Encoding` header allows the "gzip" content coding.
+pub fn accepts_gzip(accept_encoding: &str) -> bool {
+    accept_encoding.split(',').any(|coding| {
+        let mut params = coding.split(';').map(str::trim);
+        let name = params.next().unwrap_or_default();
+        if !is_gzip(name) && name != "*" {
+            return false;
+        }
+
+        // A weight of zero means the coding is not acceptable.
+        !params.any(|param| {
+            param
+                .strip_prefix("q=")
+                .and_then(|weight| weight.parse::<f64>().ok())
+                .is_some_and(|weight| weight <= 0.0)
+        })
+    })
+}
+
+/// An error that occurred while decompressing a body.
+#[derive(Debug, PartialEq, Eq)]
+pub enum DecompressError {
+    /// The body decompresses to more than the configured limit.
+    TooLarge { limit: usize },
+
+    /// The body is not valid "gzip" data.
+    Invalid(String),
+}
+
+impl fmt::Display for DecompressError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::TooLarge { limit } => {
+                write!(f, "decompressed body exceeds the limit of {limit} bytes")
+            }
+            Self::Invalid(status) => write!(f, "invalid compressed body: {status}"),
+        }
+    }
+}
+
+impl std::error::Error for DecompressError {}
+
+#[cfg(test)]
+mod test {
+    use daphne::{
+        messages::AggregationJobInitReq, testing::gen_dummy_agg_job_init_req, DapVersion,
+    };
+    use prio::codec::{ParameterizedDecode, ParameterizedEncode};
+
+    use super::{accepts_gzip, compress, decompress, DecompressError};
+
+    #[test]
+    fn round_trip_agg_job_init_req() {
+        let version = DapVersion::DraftLatest;
+        let agg_job_init_req = gen_dummy_agg_job_init_req(5000);
+        let body = agg_job_init_req.get_encoded_with_param(&version).unwrap();
+
+        let compressed = compress(&body);
+        assert!(compressed.len() < body.len() / 2);
+
+        let decompressed = decompress(&compressed, body.len()).unwrap();
+        assert_eq!(
+            AggregationJobInitReq::get_decoded_with_param(&version, &decompressed).unwrap(),
+            agg_job_init_req
+        );
+    }
+
+    #[test]
+    fn decompress_bomb() {
+        let compressed = compress(&vec![0; 1 << 20]);
+        assert_eq!(
+            decompress(&compressed, 1 << 10),
+            Err(DecompressError::TooLarge { limit: 1 << 10 })
+        );
+        assert_eq!(decompress(&compressed, 1 << 20).unwrap().len(), 1 << 20);
+    }
+
+    #[test]
+    fn decompress_invalid() {
+        assert!(matches!(
+            decompress(b"not gzip", 1 << 10),
+            Err(DecompressError::Invalid(..))
+        ));
+    }
+
+    #[test]
+    fn accept_encoding() {
+        assert!(accepts_gzip("gzip"));
+        assert!(accepts_gzip("deflate, GZIP;q=0.5"));
+        assert!(accepts_gzip("x-gzip"));
+        assert!(accepts_gzip("*"));
+        assert!(!accepts_gzip("deflate"));
+        assert!(!accepts_gzip("gzip;q=0"));
+        assert!(!accepts_gzip(""));
+    }
+}
@@ -1,9 +1,13 @@
 // Copyright (c) 2024 Cloudflare, Inc. All rights reserved.
 // SPDX-License-Identifier: BSD-3-Clause
 
+use std::collections::HashMap;
+
 use daphne::{
-    hpke::{HpkeConfig, HpkeReceiverConfig},
-    DapGlobalConfig, DapVersion,
+    hpke::{HpkeAeadId, HpkeConfig, HpkeKdfId, HpkeReceiverConfig},
+    messages::ReportId,
+    vdaf::VdafConfig,
+    DapGlobalConfig, DapQueryConfig, DapTaskConfig, DapVersion,
 };
 use serde::{Deserialize, Serialize};
 use url::Url;
@@ -28,6 +32,96 @@ pub struct TaskprovConfig {
     /// Leader: Method for authorizing Collector requests.
     #[serde(default, with = "from_raw_string")]
     pub collector_auth: Option<DaphneWorkerAuthMethod>,
+
+    /// Policy for deciding whether to opt in to a task advertised via taskprov.
+    #[serde(default)]
+    pub policy: TaskprovPolicy,
+}
+
+/// draft-wang-ppm-dap-taskprov: Policy for deciding whether to opt in to a task advertised via
+/// taskprov. By default, every task is opted in to.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct TaskprovPolicy {
+    /// DAP versions that taskprov tasks may use. If empty, then any version is allowed.
+    #[serde(default)]
+    pub allowed_versions: Vec<DapVersion>,
+
+    /// VDAFs that taskprov tasks may use. If empty, then any VDAF is allowed.
+    #[serde(default)]
+    pub allowed_vdafs: Vec<VdafConfig>,
+
+    /// Query types that taskprov tasks may use. If empty, then any query type is allowed.
+    #[serde(default)]
+    pub allowed_query_types: Vec<TaskprovQueryType>,
+
+    /// Smallest minimum batch size a taskprov task may have.
+    #[serde(default)]
+    pub min_batch_size_lower_bound: Option<u64>,
+
+    /// Largest minimum batch size a taskprov task may have.
+    #[serde(default)]
+    pub min_batch_size_upper_bound: Option<u64>,
+}
+
+/// Query types that may be allowed by a [`TaskprovPolicy`].
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TaskprovQueryType {
+    TimeInterval,
+    FixedSize,
+}
+
+impl From<&DapQueryConfig> for TaskprovQueryType {
+    fn from(query: &DapQueryConfig) -> Self {
+        match query {
+            DapQueryConfig::TimeInterval => Self::TimeInterval,
+            DapQueryConfig::FixedSize { .. } => Self::FixedSize,
+        }
+    }
+}
+
+impl TaskprovPolicy {
+    /// Decide whether to opt out of the task. If the return value is `Some(reason)`, then the task
+    /// violates the policy and `reason` conveys how.
+    pub fn opt_out_reason(&self, task_config: &DapTaskConfig) -> Option<String> {
+        if !self.allowed_versions.is_empty()
+            && !self.allowed_versions.contains(&task_config.version)
+        {
+            return Some(format!(
+                "DAP version {} is not allowed",
+                task_config.version
+            ));
+        }
+
+        if !self.allowed_vdafs.is_empty() && !self.allowed_vdafs.contains(&task_config.vdaf) {
+            return Some(format!("VDAF {} is not allowed", task_config.vdaf));
+        }
+
+        let query_type = TaskprovQueryType::from(&task_config.query);
+        if !self.allowed_query_types.is_empty() && !self.allowed_query_types.contains(&query_type) {
+            return Some(format!("query type {query_type:?} is not allowed"));
+        }
+
+        if let Some(lower_bound) = self.min_batch_size_lower_bound {
+            if task_config.min_batch_size < lower_bound {
+                return Some(format!(
+                    "minimum batch size {} is smaller than {lower_bound}",
+                    task_config.min_batch_size
+                ));
+            }
+        }
+
+        if let Some(upper_bound) = self.min_batch_size_upper_bound {
+            if task_config.min_batch_size > upper_bound {
+                return Some(format!(
+                    "minimum batch size {} is larger than {upper_bound}",
+                    task_config.min_batch_size
+                ));
+            }
+        }
+
+        None
+    }
 }
 
 pub type HpkeRecieverConfigList = Vec<HpkeReceiverConfig>;
@@ -69,13 +163,173 @@ pub struct DaphneServiceConfig {
     /// which an Aggregator guarantees storage of reports and/or report metadata.
     ///
     /// A report will be accepted if its timestamp is no more than the specified number of seconds
-    /// before the current time.
+    /// before the current time. Defaults to one week.
+    #[serde(default = "default_report_storage_epoch_duration")]
     pub report_storage_epoch_duration: daphne::messages::Duration,
 
     /// The report storage maximum future time skew. Reports with timestamps greater than the
-    /// current time plus this value will be rejected.
+    /// current time plus this value will be rejected. Defaults to five minutes.
     #[serde(default = "default_report_storage_max_future_time_skew")]
     pub report_storage_max_future_time_skew: daphne::messages::Duration,
+
+    /// Maximum size of a request body in bytes. Requests with a larger body are rejected with
+    /// status 413 (Payload Too Large) before any of the body is decoded. If not set, then request
+    /// bodies are not limited, except by `max_request_body_bytes_per_media_type`.
+    #[serde(default)]
+    pub max_request_body_bytes: Option<usize>,
+
+    /// Overrides of `max_request_body_bytes` for specific media types, keyed by the value of the
+    /// content-type header. This allows, for example, aggregation job initialization requests to
+    /// be much larger than collection requests.
+    #[serde(default)]
+    pub max_request_body_bytes_per_media_type: HashMap<String, usize>,
+
+    /// How long, in seconds, clients may cache the Aggregator's HPKE config. This is sent in the
+    /// `max-age` directive of the cache-control header and should not exceed the interval at which
+    /// HPKE keys are rotated. If not set, then the header is not sent.
+    #[serde(default)]
+    pub hpke_config_max_age: Option<daphne::messages::Duration>,
+
+    /// Leader: Shed load when the work queue is backed up by refusing uploads with status 503
+    /// (Service Unavailable) until the queue drains. If not set, then uploads are always accepted.
+    #[serde(default)]
+    pub work_queue_watermarks: Option<daphne::roles::leader::WorkQueueWatermarks>,
+
+    /// Number of threads in the pool used for CPU-bound VDAF work, such as preparing reports for
+    /// an aggregation job. This work is kept off of the async runtime so that other requests
+    /// aren't starved while it runs. If not set, then the work is spread across rayon's global
+    /// pool, which has one thread per CPU.
+    #[serde(default)]
+    pub vdaf_worker_threads: Option<usize>,
+
+    /// Compression of large request and response bodies exchanged between the Aggregators, such
+    /// as aggregation job initialization requests. If not set, then bodies are never compressed
+    /// and compressed bodies are rejected. Request bodies are only compressed once the peer has
+    /// advertised, via the `Accept-Encoding` header of a response, that it accepts them.
+    #[serde(default)]
+    pub compression: Option<CompressionConfig>,
+}
+
+/// Parameters for compressing request and response bodies. See [`crate::compression`].
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct CompressionConfig {
+    /// Bodies shorter than this many bytes are sent uncompressed, as they aren't worth the
+    /// overhead.
+    pub min_body_bytes: usize,
+
+    /// Maximum size of a decompressed body in bytes. Bodies that decompress to anything larger
+    /// are rejected so that a small body can't exhaust memory (a "decompression bomb").
+    pub max_decompressed_body_bytes: usize,
+}
+
+impl CompressionConfig {
+    /// Whether a body of the given length should be compressed.
+    pub fn should_compress(&self, body_len: usize) -> bool {
+        body_len >= self.min_body_bytes
+    }
+}
+
+impl DaphneServiceConfig {
+    /// Compute the report storage shard the report with the given ID is mapped to.
+    pub fn shard_for_report(&self, report_id: &ReportId) -> u32 {
+        report_id.shard(&self.report_shard_key, self.report_shard_count)
+    }
+
+    /// Return the maximum size of the body of a request with the given content type, if any.
+    pub fn max_request_body_bytes_for(&self, content_type: Option<&str>) -> Option<usize> {
+        content_type
+            .and_then(|content_type| self.max_request_body_bytes_per_media_type.get(content_type))
+            .copied()
+            .or(self.max_request_body_bytes)
+    }
+
+    /// Describe what this deployment supports. This is purely informational: it lets a peer
+    /// discover, for example, which VDAFs and query types a taskprov task may use before
+    /// advertising one.
+    pub fn capabilities(&self) -> Capabilities {
+        let taskprov_policy = self
+            .taskprov
+            .as_ref()
+            .filter(|_| self.global.allow_taskprov)
+            .map(|taskprov| &taskprov.policy);
+        let query_types = match taskprov_policy {
+            Some(policy) if !policy.allowed_query_types.is_empty() => {
+                policy.allowed_query_types.clone()
+            }
+            _ => vec![
+                TaskprovQueryType::TimeInterval,
+                TaskprovQueryType::FixedSize,
+            ],
+        };
+
+        Capabilities {
+            versions: vec![DapVersion::Draft02, DapVersion::DraftLatest],
+            vdaf_types: VdafConfig::type_names(),
+            query_types,
+            hpke_kem_ids: self
+                .global
+                .supported_hpke_kems
+                .iter()
+                .copied()
+                .map(u16::from)
+                .collect(),
+            hpke_kdf_ids: vec![HpkeKdfId::HkdfSha256.into()],
+            hpke_aead_ids: vec![HpkeAeadId::Aes128Gcm.into()],
+            max_batch_duration: self.global.max_batch_duration,
+            taskprov: taskprov_policy.map(|policy| TaskprovCapabilities {
+                allowed_vdafs: policy.allowed_vdafs.clone(),
+                min_batch_size_lower_bound: policy.min_batch_size_lower_bound,
+                min_batch_size_upper_bound: policy.min_batch_size_upper_bound,
+            }),
+        }
+    }
+}
+
+/// The capabilities of a deployment, as reported by [`DaphneServiceConfig::capabilities`].
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Capabilities {
+    /// DAP versions that are supported.
+    pub versions: Vec<DapVersion>,
+
+    /// Types of VDAF that are supported.
+    pub vdaf_types: Vec<String>,
+
+    /// Query types that are supported.
+    pub query_types: Vec<TaskprovQueryType>,
+
+    /// Codepoints of the HPKE KEMs for which HPKE configs are generated.
+    pub hpke_kem_ids: Vec<u16>,
+
+    /// Codepoints of the supported HPKE KDFs.
+    pub hpke_kdf_ids: Vec<u16>,
+
+    /// Codepoints of the supported HPKE AEADs.
+    pub hpke_aead_ids: Vec<u16>,
+
+    /// Maximum duration of a time-interval batch.
+    pub max_batch_duration: daphne::messages::Duration,
+
+    /// draft-wang-ppm-dap-taskprov: Restrictions on taskprov tasks. Not set if taskprov is
+    /// disabled.
+    pub taskprov: Option<TaskprovCapabilities>,
+}
+
+/// draft-wang-ppm-dap-taskprov: Restrictions on the tasks that are opted in to, derived from the
+/// [`TaskprovPolicy`].
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TaskprovCapabilities {
+    /// VDAFs that taskprov tasks may use. If empty, then any supported VDAF is allowed.
+    pub allowed_vdafs: Vec<VdafConfig>,
+
+    /// Smallest minimum batch size a taskprov task may have.
+    pub min_batch_size_lower_bound: Option<u64>,
+
+    /// Largest minimum batch size a taskprov task may have.
+    pub min_batch_size_upper_bound: Option<u64>,
+}
+
+fn default_report_storage_epoch_duration() -> daphne::messages::Duration {
+    604_800
 }
 
 fn default_report_storage_max_future_time_skew() -> daphne::messages::Duration {
@@ -126,3 +380,135 @@ mod from_raw_string {
         serde_json::from_str(&s).map_err(<D::Error as de::Error>::custom)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use daphne::{
+        hpke::{HpkeKemId, HpkeReceiverConfig},
+        vdaf::{Prio3Config, VdafConfig},
+        DapQueryConfig, DapTaskConfig, DapVersion,
+    };
+    use url::Url;
+
+    use super::{DaphneServiceConfig, TaskprovPolicy};
+
+    fn task_config(vdaf: VdafConfig, min_batch_size: u64) -> DapTaskConfig {
+        DapTaskConfig {
+            version: DapVersion::DraftLatest,
+            leader_url: Url::parse("https://leader.com/").unwrap(),
+            helper_url: Url::parse("https://helper.org/").unwrap(),
+            time_precision: 3600,
+            time_quantization_offset: 0,
+            align_batch_intervals: false,
+            min_batch_size,
+            query: DapQueryConfig::TimeInterval,
+            vdaf,
+            expiration: 0,
+            vdaf_verify_key: vdaf.gen_verify_key(),
+            collector_hpke_config: HpkeReceiverConfig::gen(23, HpkeKemId::X25519HkdfSha256)
+                .unwrap()
+                .config,
+            collector_hpke_config_allowlist: Vec::new(),
+            method: Default::default(),
+        }
+    }
+
+    #[test]
+    fn taskprov_policy_disallowed_vdaf() {
+        let policy = TaskprovPolicy {
+            allowed_vdafs: vec![VdafConfig::Prio3(Prio3Config::Count)],
+            ..Default::default()
+        };
+
+        assert_eq!(
+            policy.opt_out_reason(&task_config(VdafConfig::Prio3(Prio3Config::Count), 10)),
+            None
+        );
+        assert!(policy
+            .opt_out_reason(&task_config(
+                VdafConfig::Prio3(Prio3Config::Sum { bits: 8 }),
+                10
+            ))
+            .is_some());
+    }
+
+    #[test]
+    fn taskprov_policy_disallowed_version() {
+        let policy = TaskprovPolicy {
+            allowed_versions: vec![DapVersion::DraftLatest],
+            ..Default::default()
+        };
+        let vdaf = VdafConfig::Prio3(Prio3Config::Count);
+
+        assert_eq!(policy.opt_out_reason(&task_config(vdaf, 10)), None);
+        assert_eq!(
+            policy.opt_out_reason(&DapTaskConfig {
+                version: DapVersion::Draft02,
+                ..task_config(vdaf, 10)
+            }),
+            Some("DAP version v02 is not allowed".into())
+        );
+    }
+
+    #[test]
+    fn taskprov_policy_batch_size_out_of_bounds() {
+        let policy = TaskprovPolicy {
+            min_batch_size_lower_bound: Some(10),
+            min_batch_size_upper_bound: Some(1000),
+            ..Default::default()
+        };
+        let vdaf = VdafConfig::Prio3(Prio3Config::Count);
+
+        assert_eq!(policy.opt_out_reason(&task_config(vdaf, 10)), None);
+        assert_eq!(policy.opt_out_reason(&task_config(vdaf, 1000)), None);
+        assert!(policy.opt_out_reason(&task_config(vdaf, 9)).is_some());
+        assert!(policy.opt_out_reason(&task_config(vdaf, 1001)).is_some());
+    }
+
+    /// Deserialize a minimal service configuration, with the fields in `overrides` added.
+    fn service_config(
+        default_version: DapVersion,
+        overrides: serde_json::Value,
+    ) -> DaphneServiceConfig {
+        let mut config = serde_json::json!({
+            "env": "test",
+            "role": "leader",
+            "report_shard_key": "0000000000000000000000000000000000000000000000000000000000000000",
+            "report_shard_count": 1,
+            "default_version": default_version,
+            "max_batch_duration": 360_000,
+            "min_batch_interval_start": 259_200,
+            "max_batch_interval_end": 259_200,
+            "supported_hpke_kems": ["x25519_hkdf_sha256"],
+        });
+        config
+            .as_object_mut()
+            .unwrap()
+            .extend(overrides.as_object().unwrap().clone());
+        serde_json::from_value(config).unwrap()
+    }
+
+    #[test]
+    fn report_storage_defaults() {
+        for version in [DapVersion::Draft02, DapVersion::DraftLatest] {
+            let config = service_config(version, serde_json::json!({}));
+
+            assert_eq!(config.report_storage_epoch_duration, 604_800);
+            assert_eq!(config.report_storage_max_future_time_skew, 300);
+        }
+    }
+
+    #[test]
+    fn report_storage_explicit_values_override_defaults() {
+        let config = service_config(
+            DapVersion::DraftLatest,
+            serde_json::json!({
+                "report_storage_epoch_duration": 300,
+                "report_storage_max_future_time_skew": 60,
+            }),
+        );
+
+        assert_eq!(config.report_storage_epoch_duration, 300);
+        assert_eq!(config.report_storage_max_future_time_skew, 60);
+    }
+}
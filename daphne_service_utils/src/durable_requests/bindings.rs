@@ -10,8 +10,9 @@ use std::collections::HashSet;
 
 use daphne::{
     messages::{ReportId, TaskId},
-    DapAggregateShare, DapBatchBucket, DapVersion, MetaAggregationJobId,
+    DapAggregateShare, DapAggregationParam, DapBatchBucket, DapVersion, MetaAggregationJobId,
 };
+use prio::codec::Encode;
 use serde::{Deserialize, Serialize};
 
 use super::ObjectIdFrom;
@@ -96,7 +97,22 @@ define_do_binding! {
         CheckCollected = "/internal/do/aggregate_store/check_collected",
     }
 
-    fn name((version, task_id_hex, bucket): (DapVersion, &'n str, &'n DapBatchBucket)) -> ObjectIdFrom {
+    fn name((version, task_id_hex, agg_param, bucket): (DapVersion, &'n str, &'n DapAggregationParam, &'n DapBatchBucket)) -> ObjectIdFrom {
+        // Aggregate shares are kept separately for each aggregation parameter. Objects for VDAFs
+        // without one keep the name they had before aggregation parameters were distinguished.
+        fn durable_name_agg_param(agg_param: &DapAggregationParam) -> String {
+            if *agg_param == DapAggregationParam::Empty {
+                return String::new();
+            }
+            format!(
+                "agg_param/{}/",
+                hex::encode(
+                    agg_param
+                        .get_encoded()
+                        .expect("failed to encode aggregation parameter")
+                )
+            )
+        }
         fn durable_name_bucket(bucket: &DapBatchBucket) -> String {
             match bucket {
                 DapBatchBucket::TimeInterval { batch_window } => {
@@ -108,8 +124,9 @@ define_do_binding! {
             }
         }
         ObjectIdFrom::Name(format!(
-            "{}/{}",
+            "{}/{}{}",
             durable_name_task(version, task_id_hex),
+            durable_name_agg_param(agg_param),
             durable_name_bucket(bucket),
         ))
     }
@@ -153,6 +170,9 @@ define_do_binding! {
     enum HelperState {
         PutIfNotExists = "/internal/do/helper_state/put_if_not_exists",
         Get = "/internal/do/helper_state/get",
+        PutAggJobResp = "/internal/do/helper_state/put_agg_job_resp",
+        GetAggJobResp = "/internal/do/helper_state/get_agg_job_resp",
+        DeleteAggJobResp = "/internal/do/helper_state/delete_agg_job_resp",
     }
 
     fn name((version, task_id, agg_job_id): (DapVersion, &'n TaskId, &'n MetaAggregationJobId)) -> ObjectIdFrom {
@@ -165,3 +185,45 @@ define_do_binding! {
     }
 
 }
+
+define_do_binding! {
+    const BINDING = "DAP_LEADER_AGG_JOB_CHECKPOINT_STORE";
+    enum LeaderAggJobCheckpointStore {
+        Put = "/internal/do/leader_agg_job_checkpoint_store/put",
+        Delete = "/internal/do/leader_agg_job_checkpoint_store/delete",
+        List = "/internal/do/leader_agg_job_checkpoint_store/list",
+    }
+
+    fn name(shard: u8) -> ObjectIdFrom {
+        ObjectIdFrom::Name(format!("{}/shard/{shard}", Self::NAME_STR))
+    }
+}
+
+impl LeaderAggJobCheckpointStore {
+    pub const NAME_STR: &'static str = "leader_agg_job_checkpoint_store";
+
+    /// The number of instances the checkpoints are spread over.
+    pub const SHARD_COUNT: u8 = 16;
+
+    /// The prefix of the keys under which checkpoints are stored.
+    pub const KEY_PREFIX: &'static str = "checkpoint/";
+
+    /// The shard that stores the checkpoint of an aggregation job.
+    pub fn shard(agg_job_id: &MetaAggregationJobId) -> u8 {
+        let first_byte = match agg_job_id {
+            MetaAggregationJobId::Draft02(agg_job_id) => agg_job_id.0[0],
+            MetaAggregationJobId::DraftLatest(agg_job_id) => agg_job_id.0[0],
+        };
+        first_byte % Self::SHARD_COUNT
+    }
+
+    /// The key under which the checkpoint of an aggregation job is stored.
+    pub fn key(task_id: &TaskId, agg_job_id: &MetaAggregationJobId) -> String {
+        format!(
+            "{}{}/{}",
+            Self::KEY_PREFIX,
+            task_id.to_hex(),
+            agg_job_id.to_hex()
+        )
+    }
+}
@@ -68,6 +68,9 @@ use crate::durable_request_capnp;
 pub const KV_PATH_PREFIX: &str = "/v1/kv";
 /// The base of a request path that points to a durable object.
 pub const DO_PATH_PREFIX: &str = "/v1/do";
+/// Header of a request to put a key in KV that sets the time (in seconds since the UNIX epoch) at
+/// which the key expires.
+pub const KV_EXPIRATION_HEADER: &str = "dap-storage-kv-expiration";
 #[cfg(feature = "test-utils")]
 /// The path of the purge request, which wipes all storage. This is meant for tests only.
 pub const PURGE_STORAGE: &str = "/v1/purge";
@@ -283,7 +286,7 @@ where
 
 #[cfg(test)]
 mod test {
-    use daphne::{DapBatchBucket, DapVersion};
+    use daphne::{DapAggregationParam, DapBatchBucket, DapVersion};
 
     use crate::durable_requests::bindings::AggregateStore;
 
@@ -296,6 +299,7 @@ mod test {
             (
                 DapVersion::Draft02,
                 "some-task-id-hex",
+                &DapAggregationParam::Empty,
                 &DapBatchBucket::TimeInterval { batch_window: 0 },
             ),
         );
@@ -313,6 +317,7 @@ mod test {
             (
                 DapVersion::Draft02,
                 "some-task-id-hex",
+                &DapAggregationParam::Empty,
                 &DapBatchBucket::TimeInterval { batch_window: 0 },
             ),
         );
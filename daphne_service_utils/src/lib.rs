@@ -6,6 +6,7 @@ use std::str::FromStr;
 use serde::{Deserialize, Serialize};
 
 pub mod auth;
+pub mod compression;
 pub mod config;
 pub mod durable_requests;
 pub mod metrics;
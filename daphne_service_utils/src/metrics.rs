@@ -9,6 +9,12 @@ pub trait DaphneServiceMetrics: DaphneMetrics {
     fn abort_count_inc(&self, label: &str);
     fn count_http_status_code(&self, status_code: u16);
     fn daphne(&self) -> &dyn DaphneMetrics;
+
+    /// Leader: Set the number of reports awaiting aggregation, across all tasks.
+    fn leader_pending_reports_set(&self, val: usize);
+
+    /// Leader: Set the number of buckets with reports awaiting aggregation, across all tasks.
+    fn leader_pending_buckets_set(&self, val: usize);
 }
 
 #[cfg(any(feature = "prometheus", feature = "test-utils", test))]
@@ -16,16 +22,24 @@ mod prometheus {
     use super::DaphneServiceMetrics;
     use daphne::{
         fatal_error,
+        messages::TransitionFailure,
         metrics::{prometheus::DaphnePromMetrics, DaphneMetrics},
         DapError,
     };
-    use prometheus::{register_int_counter_vec_with_registry, IntCounterVec, Registry};
+    use prometheus::{
+        register_int_counter_vec_with_registry, register_int_gauge_with_registry, IntCounterVec,
+        IntGauge, Registry,
+    };
 
     impl DaphneMetrics for DaphnePromServiceMetrics {
         fn report_inc_by(&self, status: &str, val: u64) {
             self.daphne.report_inc_by(status, val);
         }
 
+        fn report_rejection_inc_by(&self, failure: TransitionFailure, val: u64) {
+            self.daphne.report_rejection_inc_by(failure, val);
+        }
+
         fn inbound_req_inc(&self, request_type: daphne::metrics::DaphneRequestType) {
             self.daphne.inbound_req_inc(request_type);
         }
@@ -45,6 +59,10 @@ mod prometheus {
         fn agg_job_put_span_retry_inc(&self) {
             self.daphne.agg_job_put_span_retry_inc();
         }
+
+        fn hpke_decrypt_failure_inc(&self, config_id: &str) {
+            self.daphne.hpke_decrypt_failure_inc(config_id);
+        }
     }
 
     impl DaphneServiceMetrics for DaphnePromServiceMetrics {
@@ -61,6 +79,16 @@ mod prometheus {
         fn daphne(&self) -> &dyn DaphneMetrics {
             self
         }
+
+        fn leader_pending_reports_set(&self, val: usize) {
+            self.leader_pending_reports_gauge
+                .set(i64::try_from(val).unwrap_or(i64::MAX));
+        }
+
+        fn leader_pending_buckets_set(&self, val: usize) {
+            self.leader_pending_buckets_gauge
+                .set(i64::try_from(val).unwrap_or(i64::MAX));
+        }
     }
 
     #[derive(Clone)]
@@ -73,6 +101,12 @@ mod prometheus {
 
         /// DAP aborts.
         dap_abort_counter: IntCounterVec,
+
+        /// Leader: Reports awaiting aggregation.
+        leader_pending_reports_gauge: IntGauge,
+
+        /// Leader: Buckets with reports awaiting aggregation.
+        leader_pending_buckets_gauge: IntGauge,
     }
 
     impl DaphnePromServiceMetrics {
@@ -93,12 +127,28 @@ mod prometheus {
             )
             .map_err(|e| fatal_error!(err = ?e, "failed to register dap_abort"))?;
 
+            let leader_pending_reports_gauge = register_int_gauge_with_registry!(
+                "leader_pending_reports",
+                "Number of reports awaiting aggregation.",
+                registry
+            )
+            .map_err(|e| fatal_error!(err = ?e, "failed to register leader_pending_reports"))?;
+
+            let leader_pending_buckets_gauge = register_int_gauge_with_registry!(
+                "leader_pending_buckets",
+                "Number of buckets with reports awaiting aggregation.",
+                registry
+            )
+            .map_err(|e| fatal_error!(err = ?e, "failed to register leader_pending_buckets"))?;
+
             let daphne = DaphnePromMetrics::register(registry)?;
 
             Ok(Self {
                 daphne,
                 http_status_code_counter,
                 dap_abort_counter,
+                leader_pending_reports_gauge,
+                leader_pending_buckets_gauge,
             })
         }
     }
@@ -4,7 +4,7 @@
 use std::{collections::HashSet, io::Cursor, mem::size_of, ops::ControlFlow};
 
 use crate::{
-    durable::{create_span_from_request, state_get_or_default},
+    durable::{create_span_from_request, state_get_or_default, DurableStorage},
     initialize_tracing, int_err,
 };
 use daphne::{
@@ -23,11 +23,11 @@ use prio::{
     field::FieldElement,
     vdaf::AggregateShare,
 };
-use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use serde::{Deserialize, Serialize};
 use tracing::Instrument;
 use worker::{
-    async_trait, durable_object, js_sys, wasm_bindgen, wasm_bindgen::JsValue, wasm_bindgen_futures,
-    worker_sys, Env, Error, Request, Response, Result, State,
+    async_trait, durable_object, js_sys, wasm_bindgen, wasm_bindgen_futures, worker_sys, Env,
+    Error, Request, Response, Result, State,
 };
 
 use super::{req_parse, DapDurableObject, DaphneWorkerDurableConfig, GarbageCollectable};
@@ -67,10 +67,6 @@ const MAX_AGG_SHARE_CHUNK_KEY_COUNT: usize = 4;
 /// Minimum number of chunks needed to store `10_000` report ids.
 const MAX_REPORT_ID_CHUNK_KEY_COUNT: usize = 2;
 
-/// The maximum chunk size as documented in
-/// [the public docs](https://developers.cloudflare.com/durable-objects/platform/limits/)
-const MAX_CHUNK_SIZE: usize = 128_000;
-
 /// Key used to store metadata under.
 const METADATA_KEY: &str = "meta";
 
@@ -131,17 +127,6 @@ impl DapAggregateShareMetadata {
     }
 }
 
-fn js_map_to_chunks<T: DeserializeOwned>(keys: &[String], map: js_sys::Map) -> Vec<T> {
-    keys.iter()
-        .map(|k| JsValue::from_str(k))
-        .filter(|k| map.has(k))
-        .map(|k| map.get(&k))
-        .flat_map(|js_v| {
-            serde_wasm_bindgen::from_value::<Vec<T>>(js_v).expect("expect an array of bytes")
-        })
-        .collect()
-}
-
 impl AggregateStore {
     fn agg_share_shard_keys() -> Vec<String> {
         (0..MAX_AGG_SHARE_CHUNK_KEY_COUNT)
@@ -154,131 +139,142 @@ impl AggregateStore {
             .map(|n| format!("aggregated_report_ids_{n:03}"))
             .collect()
     }
+}
 
-    async fn get_agg_share(&self, keys: &[String]) -> Result<DapAggregateShare> {
-        let all_keys = keys
-            .iter()
-            .map(String::as_str)
-            .chain([METADATA_KEY])
-            .collect::<Vec<_>>();
-        let values = self.state.storage().get_multiple(all_keys).await?;
-
-        if values.size() == 0 {
-            return Ok(DapAggregateShare::default());
-        }
-
-        let meta_key = JsValue::from_str(METADATA_KEY);
-        let meta =
-            serde_wasm_bindgen::from_value::<DapAggregateShareMetadata>(values.get(&meta_key))
-                .unwrap_or_else(|e| {
-                    tracing::error!("failed to deser DapAggregateShareMeta: {e:?}");
-                    panic!("{e}")
-                });
-
-        let chunks = js_map_to_chunks(keys, values);
-
-        Ok(if chunks.is_empty() {
-            meta.into_agg_share_without_data()
-        } else {
-            let kind = meta.kind.expect("if there is data there should be a type");
-
-            fn from_slice<F: FieldElement>(chunks: &[u8]) -> Result<AggregateShare<F>> {
-                let mut share = Vec::new();
-                let mut bytes = Cursor::new(chunks);
-                let l = u64::try_from(chunks.len()).unwrap();
-                while bytes.position() < l {
-                    let x = F::decode(&mut bytes).map_err(|e| {
-                        worker::Error::Internal(
-                            serde_wasm_bindgen::to_value(&format!(
-                                "failed to decode aggregate share: {e}"
-                            ))
-                            .expect("string never fails to convert to JsValue"),
-                        )
-                    })?;
-                    share.push(x);
-                }
-                if bytes.position() < l {
-                    return Err(worker::Error::Internal(
-                        serde_wasm_bindgen::to_value(
-                            "failed to decode aggregate share: bytes remaining in buffer",
-                        )
+/// Load the aggregate share whose data is stored under `keys`.
+async fn load_agg_share(
+    storage: &impl DurableStorage,
+    keys: &[String],
+) -> Result<DapAggregateShare> {
+    let Some(meta) = storage
+        .get::<DapAggregateShareMetadata>(METADATA_KEY)
+        .await?
+    else {
+        return Ok(DapAggregateShare::default());
+    };
+
+    let chunks = storage.get_chunks(keys).await?;
+
+    Ok(if chunks.is_empty() {
+        meta.into_agg_share_without_data()
+    } else {
+        let kind = meta.kind.expect("if there is data there should be a type");
+
+        fn from_slice<F: FieldElement>(chunks: &[u8]) -> Result<AggregateShare<F>> {
+            let mut share = Vec::new();
+            let mut bytes = Cursor::new(chunks);
+            let l = u64::try_from(chunks.len()).unwrap();
+            while bytes.position() < l {
+                let x = F::decode(&mut bytes).map_err(|e| {
+                    worker::Error::Internal(
+                        serde_wasm_bindgen::to_value(&format!(
+                            "failed to decode aggregate share: {e}"
+                        ))
                         .expect("string never fails to convert to JsValue"),
-                    ));
-                }
-
-                Ok(AggregateShare::from(share))
+                    )
+                })?;
+                share.push(x);
+            }
+            if bytes.position() < l {
+                return Err(worker::Error::Internal(
+                    serde_wasm_bindgen::to_value(
+                        "failed to decode aggregate share: bytes remaining in buffer",
+                    )
+                    .expect("string never fails to convert to JsValue"),
+                ));
             }
 
-            let data = match kind {
-                VdafKind::Field64 => VdafAggregateShare::Field64(from_slice(&chunks)?),
-                VdafKind::Field128 => VdafAggregateShare::Field128(from_slice(&chunks)?),
-                VdafKind::FieldPrio2 => VdafAggregateShare::FieldPrio2(from_slice(&chunks)?),
-            };
+            Ok(AggregateShare::from(share))
+        }
 
-            meta.into_agg_share_with_data(data)
-        })
-    }
+        let data = match kind {
+            VdafKind::Field64 => VdafAggregateShare::Field64(from_slice(&chunks)?),
+            VdafKind::Field128 => VdafAggregateShare::Field128(from_slice(&chunks)?),
+            VdafKind::FieldPrio2 => VdafAggregateShare::FieldPrio2(from_slice(&chunks)?),
+        };
 
-    async fn load_aggregated_report_ids(&self) -> Result<HashSet<ReportId>> {
-        let chunks_map = self
-            .state
-            .storage()
-            .get_multiple(Self::aggregated_reports_keys())
-            .await?;
+        meta.into_agg_share_with_data(data)
+    })
+}
 
-        let bytes = js_map_to_chunks::<u8>(&Self::aggregated_reports_keys(), chunks_map);
+/// Load the IDs of the reports that have been aggregated so far.
+async fn load_aggregated_report_ids(storage: &impl DurableStorage) -> Result<HashSet<ReportId>> {
+    let bytes = storage
+        .get_chunks(&AggregateStore::aggregated_reports_keys())
+        .await?;
 
-        assert_eq!(bytes.len() % std::mem::size_of::<ReportId>(), 0);
-        let mut ids = HashSet::with_capacity(bytes.len() / size_of::<ReportId>());
-        for chunk in bytes.chunks_exact(size_of::<ReportId>()) {
-            ids.insert(ReportId::get_decoded(chunk).map_err(|_| Error::BadEncoding)?);
-        }
-        Ok(ids)
+    assert_eq!(bytes.len() % std::mem::size_of::<ReportId>(), 0);
+    let mut ids = HashSet::with_capacity(bytes.len() / size_of::<ReportId>());
+    for chunk in bytes.chunks_exact(size_of::<ReportId>()) {
+        ids.insert(ReportId::get_decoded(chunk).map_err(|_| Error::BadEncoding)?);
     }
+    Ok(ids)
 }
 
-fn shard_bytes_to_object(
-    keys: &[String],
-    bytes: Vec<u8>,
-    object_to_fill: &js_sys::Object,
-) -> Result<()> {
-    // stolen from
-    // https://doc.rust-lang.org/std/primitive.usize.html#method.div_ceil
-    // because it's nightly only
-    fn div_ceil(lhs: usize, rhs: usize) -> usize {
-        let d = lhs / rhs;
-        let r = lhs % rhs;
-        if r > 0 && rhs > 0 {
-            d + 1
-        } else {
-            d
-        }
-    }
+/// Encode the IDs of the aggregated reports for storage under
+/// [`AggregateStore::aggregated_reports_keys`].
+fn encode_aggregated_report_ids(report_ids: HashSet<ReportId>) -> Result<Vec<u8>> {
+    let mut as_bytes = Vec::with_capacity(report_ids.len() * size_of::<ReportId>());
+    report_ids.into_iter().try_for_each(|id| {
+        id.encode(&mut as_bytes)
+            .map_err(|e| Error::RustError(format!("failed to encode report ID: {e}")))
+    })?;
+    Ok(as_bytes)
+}
 
-    let num_chunks = div_ceil(bytes.len(), MAX_CHUNK_SIZE);
-    if num_chunks > keys.len() {
-        return Err(format!("too many chunks {num_chunks}. max is {}", keys.len()).into());
+/// Mark the given reports as aggregated. If any of the reports were already aggregated, then
+/// nothing is marked and the IDs of those reports are returned.
+fn mark_aggregated(
+    merged_report_ids: &mut HashSet<ReportId>,
+    report_ids: Vec<ReportId>,
+) -> HashSet<ReportId> {
+    let repeat_ids = report_ids
+        .iter()
+        .filter(|id| merged_report_ids.contains(id))
+        .copied()
+        .collect::<HashSet<_>>();
+    if repeat_ids.is_empty() {
+        merged_report_ids.extend(report_ids);
     }
+    repeat_ids
+}
 
-    let mut base_idx = 0;
-    for key in &keys[..num_chunks] {
-        let end = usize::min(base_idx + MAX_CHUNK_SIZE + 1, bytes.len());
-        let chunk = &bytes[base_idx..end];
-
-        // unwrap cannot fail because chunk len is bounded by MAX_CHUNK_SIZE which is smaller than
-        // u32::MAX
-        let value = js_sys::Uint8Array::new_with_length(u32::try_from(chunk.len()).unwrap());
-        value.copy_from(chunk);
-
-        js_sys::Reflect::set(
-            object_to_fill,
-            &JsValue::from_str(key.as_str()),
-            &value.into(),
-        )?;
+/// Merge an aggregate share into the stored one and mark the reports it contains as aggregated.
+/// If any of the reports were already aggregated, then nothing is stored and the IDs of those
+/// reports are returned.
+async fn merge(
+    storage: &impl DurableStorage,
+    contained_reports: Vec<ReportId>,
+    agg_share_delta: DapAggregateShare,
+) -> Result<HashSet<ReportId>> {
+    let mut merged_report_ids = load_aggregated_report_ids(storage).await?;
+    let repeat_ids = mark_aggregated(&mut merged_report_ids, contained_reports);
+    if !repeat_ids.is_empty() {
+        return Ok(repeat_ids);
+    }
 
-        base_idx = end;
+    let keys = AggregateStore::agg_share_shard_keys();
+    let mut agg_share = load_agg_share(storage, &keys).await?;
+    agg_share.merge(agg_share_delta).map_err(int_err)?;
+    let (meta, data) = DapAggregateShareMetadata::from_agg_share(agg_share);
+
+    // Only storage operations are awaited between these writes, so the runtime coalesces them
+    // into a single atomic write: the reports are marked as aggregated if and only if the
+    // aggregate share is updated.
+    storage
+        .put_chunks(
+            &AggregateStore::aggregated_reports_keys(),
+            encode_aggregated_report_ids(merged_report_ids)?,
+        )
+        .await?;
+    if let Some(data) = data {
+        let bytes = data
+            .get_encoded()
+            .map_err(|e| Error::RustError(format!("failed to encode agg share: {e}")))?;
+        storage.put_chunks(&keys, bytes).await?;
     }
-    Ok(())
+    storage.put(METADATA_KEY, &meta).await?;
+    Ok(HashSet::new())
 }
 
 #[durable_object]
@@ -322,7 +318,7 @@ impl AggregateStore {
 
         match bindings::AggregateStore::try_from_uri(&req.path()) {
             Some(bindings::AggregateStore::GetMerged) => {
-                Response::from_json(&self.load_aggregated_report_ids().await?)
+                Response::from_json(&load_aggregated_report_ids(&self.state).await?)
             }
             // Merge an aggregate share into the stored aggregate.
             //
@@ -335,63 +331,16 @@ impl AggregateStore {
                     agg_share_delta,
                 } = req_parse(&mut req).await?;
 
-                let chunks_map = js_sys::Object::default();
-
                 if self.is_collected().await? {
                     return Response::from_json(&AggregateStoreMergeResp::AlreadyCollected);
                 }
 
-                {
-                    // check for replays
-                    let mut merged_report_ids = self.load_aggregated_report_ids().await?;
-                    let repeat_ids = contained_reports
-                        .iter()
-                        .filter(|id| merged_report_ids.contains(id))
-                        .copied()
-                        .collect::<HashSet<_>>();
-                    if !repeat_ids.is_empty() {
-                        return Response::from_json(&AggregateStoreMergeResp::ReplaysDetected(
-                            repeat_ids,
-                        ));
-                    }
-                    merged_report_ids.extend(contained_reports);
-                    let mut as_bytes =
-                        Vec::with_capacity(merged_report_ids.len() * size_of::<ReportId>());
-                    merged_report_ids.into_iter().try_for_each(|id| {
-                        id.encode(&mut as_bytes).map_err(|e| {
-                            Error::RustError(format!("failed to encode report ID: {e}"))
-                        })
-                    })?;
-                    shard_bytes_to_object(&Self::aggregated_reports_keys(), as_bytes, &chunks_map)?;
-                };
-
-                let keys = Self::agg_share_shard_keys();
-                let mut agg_share = self.get_agg_share(&keys).await?;
-                agg_share.merge(agg_share_delta).map_err(int_err)?;
-
-                let (meta, data) = DapAggregateShareMetadata::from_agg_share(agg_share);
-
-                data.as_ref()
-                    .map(|data| {
-                        shard_bytes_to_object(
-                            &keys,
-                            data.get_encoded().map_err(|e| {
-                                Error::RustError(format!("failed to encode agg share: {e}"))
-                            })?,
-                            &chunks_map,
-                        )
-                    })
-                    .transpose()? // Option<Result> -> Result<Option> -> Option
-                    .unwrap_or_default();
-
-                js_sys::Reflect::set(
-                    &chunks_map,
-                    &JsValue::from_str(METADATA_KEY),
-                    &serde_wasm_bindgen::to_value(&meta)?,
-                )?;
-
-                self.state.storage().put_multiple_raw(chunks_map).await?;
-
+                let repeat_ids = merge(&self.state, contained_reports, agg_share_delta).await?;
+                if !repeat_ids.is_empty() {
+                    return Response::from_json(&AggregateStoreMergeResp::ReplaysDetected(
+                        repeat_ids,
+                    ));
+                }
                 Response::from_json(&AggregateStoreMergeResp::Ok)
             }
 
@@ -400,7 +349,7 @@ impl AggregateStore {
             // Idempotent
             // Output: `DapAggregateShare`
             Some(bindings::AggregateStore::Get) => {
-                let agg_share = self.get_agg_share(&Self::agg_share_shard_keys()).await?;
+                let agg_share = load_agg_share(&self.state, &Self::agg_share_shard_keys()).await?;
                 Response::from_json(&agg_share)
             }
 
@@ -457,3 +406,87 @@ impl GarbageCollectable for AggregateStore {
         &self.env
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashSet;
+
+    use daphne::{messages::ReportId, vdaf::VdafAggregateShare, DapAggregateShare};
+    use futures::executor::block_on;
+    use prio::{field::Field64, vdaf::AggregateShare};
+
+    use super::{load_agg_share, load_aggregated_report_ids, merge, AggregateStore};
+    use crate::durable::InMemoryStorage;
+
+    /// Mark reports as aggregated the way a merge request does, storing the result in `storage`.
+    fn mark(storage: &InMemoryStorage, report_ids: Vec<ReportId>) -> HashSet<ReportId> {
+        block_on(merge(storage, report_ids, DapAggregateShare::default())).unwrap()
+    }
+
+    #[test]
+    fn mark_aggregated_returns_replays() {
+        let storage = InMemoryStorage::default();
+
+        assert!(mark(&storage, vec![ReportId([1; 16]), ReportId([2; 16])]).is_empty());
+        assert_eq!(
+            mark(&storage, vec![ReportId([2; 16]), ReportId([3; 16])]),
+            HashSet::from([ReportId([2; 16])])
+        );
+
+        // Nothing is marked if a replay is detected.
+        assert!(mark(&storage, vec![ReportId([3; 16])]).is_empty());
+    }
+
+    #[test]
+    fn aggregated_report_ids_span_chunks() {
+        let storage = InMemoryStorage::default();
+        let report_ids = (0..10_000_u32)
+            .map(|i| {
+                let mut id = [0; 16];
+                id[..4].copy_from_slice(&i.to_be_bytes());
+                ReportId(id)
+            })
+            .collect::<Vec<_>>();
+
+        assert!(mark(&storage, report_ids.clone()).is_empty());
+        let loaded = block_on(load_aggregated_report_ids(&storage)).unwrap();
+        assert_eq!(loaded, report_ids.into_iter().collect());
+    }
+
+    #[test]
+    fn merge_accumulates_agg_share() {
+        let storage = InMemoryStorage::default();
+        let agg_share = |report_id: ReportId, time, val: u64| DapAggregateShare {
+            report_count: 1,
+            min_time: time,
+            max_time: time,
+            checksum: [report_id.0[0]; 32],
+            data: Some(VdafAggregateShare::Field64(AggregateShare::from(vec![
+                Field64::from(val),
+            ]))),
+        };
+
+        let first = ReportId([1; 16]);
+        let second = ReportId([2; 16]);
+        block_on(merge(&storage, vec![first], agg_share(first, 100, 3))).unwrap();
+        block_on(merge(&storage, vec![second], agg_share(second, 200, 4))).unwrap();
+
+        // A replayed report doesn't change the stored aggregate share.
+        let repeat_ids =
+            block_on(merge(&storage, vec![second], agg_share(second, 300, 5))).unwrap();
+        assert_eq!(repeat_ids, HashSet::from([second]));
+
+        let loaded = block_on(load_agg_share(
+            &storage,
+            &AggregateStore::agg_share_shard_keys(),
+        ))
+        .unwrap();
+        assert_eq!(loaded.report_count, 2);
+        assert_eq!((loaded.min_time, loaded.max_time), (100, 200));
+        assert_eq!(loaded.checksum, [1 ^ 2; 32]);
+        let Some(VdafAggregateShare::Field64(data)) = loaded.data else {
+            panic!("unexpected aggregate share data: {:?}", loaded.data);
+        };
+        assert_eq!(data, AggregateShare::from(vec![Field64::from(7)]));
+    }
+}
@@ -2,7 +2,7 @@
 // SPDX-License-Identifier: BSD-3-Clause
 
 use crate::{
-    durable::{create_span_from_request, state_get, state_set_if_not_exists},
+    durable::{create_span_from_request, state_get, state_set_if_not_exists, DurableStorage},
     initialize_tracing, int_err,
 };
 use daphne_service_utils::{
@@ -24,8 +24,14 @@ use super::{req_parse, Alarmed, DapDurableObject, DaphneWorkerDurableConfig};
 /// - `DURABLE_HELPER_STATE_PUT_IF_NOT_EXISTS`: Stores Helper's hex-encoded state unless the state
 ///    already exists. Returns a boolean indicating whether the operation succeeded.
 /// - `DURABLE_HELPER_STATE_GET`: Drains the Helper's hex-encoded state.
+/// - `HelperState::PutAggJobResp`: Stores the hex-encoded aggregation job response, which is sent
+///    again if the Leader retries the job and which the Leader may fetch in chunks.
+/// - `HelperState::GetAggJobResp`: Returns the hex-encoded aggregation job response, if any.
+/// - `HelperState::DeleteAggJobResp`: Deletes the aggregation job response.
 ///
-/// The state blob is stored in `helper_state`.
+/// The state blob is stored in `helper_state`. The aggregation job response may be too large for
+/// a single value, so it is split over the keys `agg_job_resp/{000..127}`, with the number of
+/// chunks stored in `agg_job_resp`.
 #[durable_object]
 pub struct HelperStateStore {
     state: State,
@@ -70,6 +76,54 @@ impl DurableObject for HelperStateStore {
     }
 }
 
+/// Maximum number of chunks an aggregation job response can span, i.e., about 16MB of data.
+const MAX_AGG_JOB_RESP_CHUNK_KEY_COUNT: usize = 128;
+
+fn agg_job_resp_chunk_keys() -> Vec<String> {
+    (0..MAX_AGG_JOB_RESP_CHUNK_KEY_COUNT)
+        .map(|n| format!("agg_job_resp/{n:03}"))
+        .collect()
+}
+
+/// Store the hex-encoded aggregation job response, replacing the previous one if any.
+async fn put_agg_job_resp(storage: &impl DurableStorage, agg_job_resp_hex: &str) -> Result<()> {
+    delete_agg_job_resp(storage).await?;
+    let chunk_count = storage
+        .put_chunks(
+            &agg_job_resp_chunk_keys(),
+            agg_job_resp_hex.as_bytes().to_vec(),
+        )
+        .await?;
+    storage.put("agg_job_resp", &chunk_count).await
+}
+
+/// Load the hex-encoded aggregation job response, if any.
+async fn get_agg_job_resp(storage: &impl DurableStorage) -> Result<Option<String>> {
+    let Some(chunk_count) = storage.get::<usize>("agg_job_resp").await? else {
+        return Ok(None);
+    };
+    let bytes = storage
+        .get_chunks(&agg_job_resp_chunk_keys()[..chunk_count])
+        .await?;
+    String::from_utf8(bytes).map(Some).map_err(|e| {
+        int_err(format!(
+            "HelperStateStore: invalid aggregation job response: {e}"
+        ))
+    })
+}
+
+/// Delete the aggregation job response, if any.
+async fn delete_agg_job_resp(storage: &impl DurableStorage) -> Result<()> {
+    let Some(chunk_count) = storage.get::<usize>("agg_job_resp").await? else {
+        return Ok(());
+    };
+    storage.delete("agg_job_resp").await?;
+    for chunk_key in &agg_job_resp_chunk_keys()[..chunk_count] {
+        storage.delete(chunk_key).await?;
+    }
+    Ok(())
+}
+
 impl HelperStateStore {
     async fn handle(&mut self, mut req: Request) -> Result<Response> {
         match bindings::HelperState::try_from_uri(&req.path()) {
@@ -96,6 +150,34 @@ impl HelperStateStore {
                 Response::from_json(&helper_state)
             }
 
+            // Store the aggregation job response that the Leader fetches in chunks.
+            //
+            // Idempotent
+            // Input: `agg_job_resp_hex: String` (hex-encoded response)
+            // Output: `()`
+            Some(bindings::HelperState::PutAggJobResp) => {
+                let agg_job_resp_hex: String = req_parse(&mut req).await?;
+                put_agg_job_resp(&self.state, &agg_job_resp_hex).await?;
+                Response::from_json(&())
+            }
+
+            // Get the aggregation job response.
+            //
+            // Idempotent
+            // Output: `Option<String>` (hex-encoded response)
+            Some(bindings::HelperState::GetAggJobResp) => {
+                Response::from_json(&get_agg_job_resp(&self.state).await?)
+            }
+
+            // Delete the aggregation job response once the Leader has fetched all of it.
+            //
+            // Idempotent
+            // Output: `()`
+            Some(bindings::HelperState::DeleteAggJobResp) => {
+                delete_agg_job_resp(&self.state).await?;
+                Response::from_json(&())
+            }
+
             _ => Err(int_err(format!(
                 "HelperStateStore: unexpected request: method={:?}; path={:?}",
                 req.method(),
@@ -126,3 +208,32 @@ impl Alarmed for HelperStateStore {
         &mut self.alarmed
     }
 }
+
+#[cfg(test)]
+mod test {
+    use futures::executor::block_on;
+
+    use super::{delete_agg_job_resp, get_agg_job_resp, put_agg_job_resp};
+    use crate::durable::{DurableStorage, InMemoryStorage};
+
+    #[test]
+    fn agg_job_resp_spans_chunks() {
+        let storage = InMemoryStorage::default();
+        let agg_job_resp_hex = "ab".repeat(200_000);
+
+        block_on(async {
+            put_agg_job_resp(&storage, &agg_job_resp_hex).await.unwrap();
+            assert_eq!(
+                get_agg_job_resp(&storage).await.unwrap(),
+                Some(agg_job_resp_hex)
+            );
+
+            delete_agg_job_resp(&storage).await.unwrap();
+            assert_eq!(get_agg_job_resp(&storage).await.unwrap(), None);
+            assert_eq!(
+                storage.get::<Vec<u8>>("agg_job_resp/000").await.unwrap(),
+                None
+            );
+        });
+    }
+}
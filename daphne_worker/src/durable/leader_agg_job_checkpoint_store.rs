@@ -0,0 +1,263 @@
+// Copyright (c) 2024 Cloudflare, Inc. All rights reserved.
+// SPDX-License-Identifier: BSD-3-Clause
+
+use crate::{
+    durable::{create_span_from_request, req_parse, DurableStorage},
+    initialize_tracing, int_err,
+};
+use daphne::{messages::TaskId, roles::leader::AggregationJobCheckpoint, MetaAggregationJobId};
+use daphne_service_utils::durable_requests::bindings::{
+    self, DurableMethod, LeaderAggJobCheckpointStore as Method,
+};
+use tracing::Instrument;
+use worker::{
+    async_trait, durable_object, js_sys, wasm_bindgen, wasm_bindgen_futures, worker_sys, Env,
+    Error, ListOptions, Request, Response, Result, State,
+};
+
+/// Durable Object (DO) for storing the checkpoints of the Leader's aggregation jobs, so that a job
+/// that was interrupted while being committed can be finished after the Leader restarts.
+///
+/// This object implements the following API endpoints:
+///
+/// - `LeaderAggJobCheckpointStore::Put`: Stores the checkpoint of an aggregation job, replacing the
+///    previous one for the same job if any.
+/// - `LeaderAggJobCheckpointStore::Delete`: Deletes the checkpoint of an aggregation job.
+/// - `LeaderAggJobCheckpointStore::List`: Returns all checkpoints stored in this instance.
+///
+/// Checkpoints are spread over `LeaderAggJobCheckpointStore::SHARD_COUNT` instances by
+/// aggregation job ID. A checkpoint is only kept while its job is being committed, so there are
+/// never more checkpoints than aggregation jobs in flight.
+///
+/// The schema for the data stored by this DO is as follows:
+///
+/// ```text
+/// [Checkpoint of an aggregation job]
+///     checkpoint/<task_id>/<agg_job_id>        -> number of chunks
+///     chunk/<task_id>/<agg_job_id>/{000..031}  -> slice of the encoded AggregationJobCheckpoint
+/// ```
+#[durable_object]
+pub struct LeaderAggJobCheckpointStore {
+    state: State,
+}
+
+/// Maximum number of chunks a checkpoint can span, i.e., about 4MB of data.
+const MAX_CHECKPOINT_CHUNK_KEY_COUNT: usize = 32;
+
+/// The keys under which the chunks of a checkpoint stored under `key` are stored.
+fn chunk_keys(key: &str) -> Vec<String> {
+    let key = key
+        .strip_prefix(Method::KEY_PREFIX)
+        .expect("checkpoint key has the expected prefix");
+    (0..MAX_CHECKPOINT_CHUNK_KEY_COUNT)
+        .map(|n| format!("chunk/{key}/{n:03}"))
+        .collect()
+}
+
+/// Store a checkpoint, replacing the previous checkpoint for the same aggregation job if any.
+async fn put_checkpoint(
+    storage: &impl DurableStorage,
+    checkpoint: &AggregationJobCheckpoint,
+) -> Result<()> {
+    let key = Method::key(&checkpoint.task_id, &checkpoint.agg_job_id);
+    let bytes = bincode::serialize(checkpoint)
+        .map_err(|e| Error::RustError(format!("failed to encode checkpoint: {e}")))?;
+    let chunk_count = storage.put_chunks(&chunk_keys(&key), bytes).await?;
+    storage.put(&key, &chunk_count).await
+}
+
+/// Load the checkpoint stored under `key`, if any.
+async fn get_checkpoint(
+    storage: &impl DurableStorage,
+    key: &str,
+) -> Result<Option<AggregationJobCheckpoint>> {
+    let Some(chunk_count) = storage.get::<usize>(key).await? else {
+        return Ok(None);
+    };
+    let bytes = storage.get_chunks(&chunk_keys(key)[..chunk_count]).await?;
+    bincode::deserialize(&bytes)
+        .map(Some)
+        .map_err(|e| Error::RustError(format!("failed to decode checkpoint: {e}")))
+}
+
+/// Load the checkpoints stored under `keys`. A checkpoint that can't be loaded is logged and
+/// skipped so that it doesn't keep the other jobs from being resumed.
+async fn get_checkpoints(
+    storage: &impl DurableStorage,
+    keys: &[String],
+) -> Vec<AggregationJobCheckpoint> {
+    let mut checkpoints = Vec::with_capacity(keys.len());
+    for key in keys {
+        match get_checkpoint(storage, key).await {
+            Ok(checkpoint) => checkpoints.extend(checkpoint),
+            Err(e) => tracing::warn!(%key, error = %e, "skipping unreadable checkpoint"),
+        }
+    }
+    checkpoints
+}
+
+/// Delete the checkpoint stored under `key`, if any.
+async fn delete_checkpoint(storage: &impl DurableStorage, key: &str) -> Result<()> {
+    let Some(chunk_count) = storage.get::<usize>(key).await? else {
+        return Ok(());
+    };
+    storage.delete(key).await?;
+    for chunk_key in &chunk_keys(key)[..chunk_count] {
+        storage.delete(chunk_key).await?;
+    }
+    Ok(())
+}
+
+#[durable_object]
+impl DurableObject for LeaderAggJobCheckpointStore {
+    fn new(state: State, env: Env) -> Self {
+        initialize_tracing(&env);
+        Self { state }
+    }
+
+    async fn fetch(&mut self, req: Request) -> Result<Response> {
+        let span = create_span_from_request(&req);
+        self.handle(req).instrument(span).await
+    }
+}
+
+impl LeaderAggJobCheckpointStore {
+    async fn handle(&mut self, mut req: Request) -> Result<Response> {
+        if let Some(bindings::GarbageCollector::DeleteAll) =
+            bindings::GarbageCollector::try_from_uri(&req.path())
+        {
+            self.state.storage().delete_all().await?;
+            return Response::from_json(&());
+        }
+
+        match Method::try_from_uri(&req.path()) {
+            // Store the checkpoint of an aggregation job.
+            //
+            // Idempotent
+            // Input: `checkpoint: AggregationJobCheckpoint`
+            // Output: `()`
+            Some(Method::Put) => {
+                let checkpoint: AggregationJobCheckpoint = req_parse(&mut req).await?;
+                put_checkpoint(&self.state, &checkpoint).await?;
+                Response::from_json(&())
+            }
+
+            // Delete the checkpoint of an aggregation job.
+            //
+            // Idempotent
+            // Input: `(task_id, agg_job_id): (TaskId, MetaAggregationJobId)`
+            // Output: `()`
+            Some(Method::Delete) => {
+                let (task_id, agg_job_id): (TaskId, MetaAggregationJobId) =
+                    req_parse(&mut req).await?;
+                delete_checkpoint(&self.state, &Method::key(&task_id, &agg_job_id)).await?;
+                Response::from_json(&())
+            }
+
+            // List the checkpoints stored in this instance.
+            //
+            // Idempotent
+            // Output: `Vec<AggregationJobCheckpoint>`
+            Some(Method::List) => {
+                let iter = self
+                    .state
+                    .storage()
+                    .list_with_options(ListOptions::new().prefix(Method::KEY_PREFIX))
+                    .await?
+                    .entries();
+                let mut keys = Vec::new();
+                let mut js_item = iter.next()?;
+                while !js_item.done() {
+                    let (key, _chunk_count): (String, usize) =
+                        serde_wasm_bindgen::from_value(js_item.value()).map_err(int_err)?;
+                    keys.push(key);
+                    js_item = iter.next()?;
+                }
+                Response::from_json(&get_checkpoints(&self.state, &keys).await)
+            }
+
+            _ => Err(int_err(format!(
+                "LeaderAggJobCheckpointStore: unexpected request: method={:?}; path={:?}",
+                req.method(),
+                req.path()
+            ))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use daphne::{
+        messages::TaskId, roles::leader::AggregationJobCheckpoint, DapVersion, MetaAggregationJobId,
+    };
+    use daphne_service_utils::durable_requests::bindings::LeaderAggJobCheckpointStore as Method;
+    use futures::executor::block_on;
+
+    use super::{chunk_keys, delete_checkpoint, get_checkpoint, get_checkpoints, put_checkpoint};
+    use crate::durable::{DurableStorage, InMemoryStorage};
+
+    #[test]
+    fn checkpoint_spans_chunks() {
+        let storage = InMemoryStorage::default();
+        let checkpoint = AggregationJobCheckpoint {
+            task_id: TaskId([1; 32]),
+            agg_job_id: MetaAggregationJobId::gen_for_version(DapVersion::DraftLatest),
+            agg_param: Vec::new(),
+            state: vec![7; 300_000],
+            agg_job_resp: vec![8; 1_000],
+        };
+        let key = Method::key(&checkpoint.task_id, &checkpoint.agg_job_id);
+
+        block_on(async {
+            put_checkpoint(&storage, &checkpoint).await.unwrap();
+            assert_eq!(
+                get_checkpoint(&storage, &key).await.unwrap(),
+                Some(checkpoint.clone())
+            );
+
+            // A smaller checkpoint replaces the larger one.
+            let smaller = AggregationJobCheckpoint {
+                state: vec![9; 10],
+                ..checkpoint
+            };
+            put_checkpoint(&storage, &smaller).await.unwrap();
+            assert_eq!(get_checkpoint(&storage, &key).await.unwrap(), Some(smaller));
+
+            delete_checkpoint(&storage, &key).await.unwrap();
+            assert_eq!(get_checkpoint(&storage, &key).await.unwrap(), None);
+        });
+    }
+    #[test]
+    fn get_checkpoints_skips_unreadable() {
+        let storage = InMemoryStorage::default();
+        let checkpoint = AggregationJobCheckpoint {
+            task_id: TaskId([1; 32]),
+            agg_job_id: MetaAggregationJobId::gen_for_version(DapVersion::DraftLatest),
+            agg_param: Vec::new(),
+            state: vec![7; 10],
+            agg_job_resp: vec![8; 10],
+        };
+        let key = Method::key(&checkpoint.task_id, &checkpoint.agg_job_id);
+        let bad_key = Method::key(
+            &TaskId([2; 32]),
+            &MetaAggregationJobId::gen_for_version(DapVersion::DraftLatest),
+        );
+
+        block_on(async {
+            put_checkpoint(&storage, &checkpoint).await.unwrap();
+
+            // A checkpoint whose encoding is truncated.
+            let chunk_count = storage
+                .put_chunks(&chunk_keys(&bad_key), vec![1, 2, 3])
+                .await
+                .unwrap();
+            storage.put(&bad_key, &chunk_count).await.unwrap();
+            assert!(get_checkpoint(&storage, &bad_key).await.is_err());
+
+            assert_eq!(
+                get_checkpoints(&storage, &[bad_key, key]).await,
+                vec![checkpoint]
+            );
+        });
+    }
+}
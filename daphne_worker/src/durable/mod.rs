@@ -4,6 +4,7 @@
 pub(crate) mod aggregate_store;
 pub(crate) mod garbage_collector;
 pub(crate) mod helper_state_store;
+pub(crate) mod leader_agg_job_checkpoint_store;
 
 use crate::{
     int_err, now,
@@ -20,12 +21,19 @@ use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use std::{cmp::min, time::Duration};
 use tracing::{info_span, trace, warn};
 use worker::{
-    async_trait, js_sys::Uint8Array, Delay, Env, Error, Headers, ListOptions, Method, Request,
-    RequestInit, Result, ScheduledTime, State, Stub,
+    async_trait,
+    js_sys::{self, Uint8Array},
+    wasm_bindgen::JsValue,
+    Delay, Env, Error, Headers, ListOptions, Method, Request, RequestInit, Result, ScheduledTime,
+    State, Stub,
 };
 
 const ERR_NO_VALUE: &str = "No such value in storage.";
 
+/// The maximum chunk size as documented in
+/// [the public docs](https://developers.cloudflare.com/durable-objects/platform/limits/)
+const MAX_CHUNK_SIZE: usize = 128_000;
+
 // The maximum number of keys to get at once in a list command.
 //
 // The DO API does not say that there is any limit on the number of keys it is willing to return
@@ -303,38 +311,197 @@ trait GarbageCollectable: DapDurableObject {
     }
 }
 
+/// The key/value operations on a DO instance's storage used by the DOs in this crate. This allows
+/// their logic to be exercised outside of the Workers runtime; see [`InMemoryStorage`].
+#[async_trait::async_trait(?Send)]
+pub(crate) trait DurableStorage {
+    /// Fetch the value associated with the given key, if it exists.
+    async fn get<T: DeserializeOwned>(&self, key: &str) -> Result<Option<T>>;
+
+    /// Set the value associated with the given key, overwriting the current value if any.
+    async fn put<T: Serialize>(&self, key: &str, val: &T) -> Result<()>;
+
+    /// Delete the given key. Return `true` if the key existed.
+    async fn delete(&self, key: &str) -> Result<bool>;
+
+    /// Fetch the byte arrays stored under the given keys and concatenate them in order. Keys that
+    /// don't exist are skipped.
+    async fn get_chunks(&self, keys: &[String]) -> Result<Vec<u8>>;
+
+    /// Split `bytes` into chunks that fit in a single value each and store them under the given
+    /// keys, in order. Returns the number of chunks written.
+    async fn put_chunks(&self, keys: &[String], bytes: Vec<u8>) -> Result<usize>;
+}
+
+#[async_trait::async_trait(?Send)]
+impl DurableStorage for State {
+    async fn get<T: DeserializeOwned>(&self, key: &str) -> Result<Option<T>> {
+        self.storage().get(key).await.map(Some).or_else(|e| {
+            if matches!(e, Error::JsError(ref s) if s == ERR_NO_VALUE) {
+                Ok(None)
+            } else {
+                Err(e)
+            }
+        })
+    }
+
+    async fn put<T: Serialize>(&self, key: &str, val: &T) -> Result<()> {
+        self.storage().put(key, val).await
+    }
+
+    async fn delete(&self, key: &str) -> Result<bool> {
+        self.storage().delete(key).await
+    }
+
+    async fn get_chunks(&self, keys: &[String]) -> Result<Vec<u8>> {
+        let map = self.storage().get_multiple(keys.to_vec()).await?;
+        Ok(js_map_to_chunks(keys, map))
+    }
+
+    async fn put_chunks(&self, keys: &[String], bytes: Vec<u8>) -> Result<usize> {
+        let chunks_map = js_sys::Object::default();
+        let chunk_count = shard_bytes(keys, &bytes)?.len();
+        shard_bytes_to_object(keys, bytes, &chunks_map)?;
+        self.storage().put_multiple_raw(chunks_map).await?;
+        Ok(chunk_count)
+    }
+}
+
+/// A [`DurableStorage`] that keeps everything in memory, for testing DO logic without the Workers
+/// runtime.
+#[cfg(test)]
+#[derive(Default)]
+pub(crate) struct InMemoryStorage {
+    kv: std::cell::RefCell<std::collections::HashMap<String, serde_json::Value>>,
+}
+
+#[cfg(test)]
+#[async_trait::async_trait(?Send)]
+impl DurableStorage for InMemoryStorage {
+    async fn get<T: DeserializeOwned>(&self, key: &str) -> Result<Option<T>> {
+        self.kv
+            .borrow()
+            .get(key)
+            .map(|val| serde_json::from_value(val.clone()))
+            .transpose()
+            .map_err(Error::from)
+    }
+
+    async fn put<T: Serialize>(&self, key: &str, val: &T) -> Result<()> {
+        let val = serde_json::to_value(val)?;
+        self.kv.borrow_mut().insert(key.to_string(), val);
+        Ok(())
+    }
+
+    async fn delete(&self, key: &str) -> Result<bool> {
+        Ok(self.kv.borrow_mut().remove(key).is_some())
+    }
+
+    async fn get_chunks(&self, keys: &[String]) -> Result<Vec<u8>> {
+        let mut bytes = Vec::new();
+        for key in keys {
+            if let Some(chunk) = self.get::<Vec<u8>>(key).await? {
+                bytes.extend(chunk);
+            }
+        }
+        Ok(bytes)
+    }
+
+    async fn put_chunks(&self, keys: &[String], bytes: Vec<u8>) -> Result<usize> {
+        let chunks = shard_bytes(keys, &bytes)?;
+        for (key, chunk) in &chunks {
+            self.put(key, chunk).await?;
+        }
+        Ok(chunks.len())
+    }
+}
+
+/// Concatenate the arrays stored under `keys` in a map returned by a `get_multiple()` call.
+pub(crate) fn js_map_to_chunks<T: DeserializeOwned>(keys: &[String], map: js_sys::Map) -> Vec<T> {
+    keys.iter()
+        .map(|k| JsValue::from_str(k))
+        .filter(|k| map.has(k))
+        .map(|k| map.get(&k))
+        .flat_map(|js_v| {
+            serde_wasm_bindgen::from_value::<Vec<T>>(js_v).expect("expect an array of bytes")
+        })
+        .collect()
+}
+
+/// Split `bytes` into chunks that fit in a single value each, paired with the keys under which
+/// they are stored.
+pub(crate) fn shard_bytes<'k, 'b>(
+    keys: &'k [String],
+    bytes: &'b [u8],
+) -> Result<Vec<(&'k String, &'b [u8])>> {
+    // stolen from
+    // https://doc.rust-lang.org/std/primitive.usize.html#method.div_ceil
+    // because it's nightly only
+    fn div_ceil(lhs: usize, rhs: usize) -> usize {
+        let d = lhs / rhs;
+        let r = lhs % rhs;
+        if r > 0 && rhs > 0 {
+            d + 1
+        } else {
+            d
+        }
+    }
+
+    let num_chunks = div_ceil(bytes.len(), MAX_CHUNK_SIZE);
+    if num_chunks > keys.len() {
+        return Err(format!("too many chunks {num_chunks}. max is {}", keys.len()).into());
+    }
+
+    let mut chunks = Vec::with_capacity(num_chunks);
+    let mut base_idx = 0;
+    for key in &keys[..num_chunks] {
+        let end = usize::min(base_idx + MAX_CHUNK_SIZE + 1, bytes.len());
+        chunks.push((key, &bytes[base_idx..end]));
+        base_idx = end;
+    }
+    Ok(chunks)
+}
+
+pub(crate) fn shard_bytes_to_object(
+    keys: &[String],
+    bytes: Vec<u8>,
+    object_to_fill: &js_sys::Object,
+) -> Result<()> {
+    for (key, chunk) in shard_bytes(keys, &bytes)? {
+        // unwrap cannot fail because chunk len is bounded by MAX_CHUNK_SIZE which is smaller than
+        // u32::MAX
+        let value = js_sys::Uint8Array::new_with_length(u32::try_from(chunk.len()).unwrap());
+        value.copy_from(chunk);
+
+        js_sys::Reflect::set(
+            object_to_fill,
+            &JsValue::from_str(key.as_str()),
+            &value.into(),
+        )?;
+    }
+    Ok(())
+}
+
 /// Fetch the value associated with the given key from durable storage. If the key/value pair does
 /// not exist, then return the default value.
-pub(crate) async fn state_get_or_default<T: Default + for<'a> Deserialize<'a>>(
-    state: &State,
+pub(crate) async fn state_get_or_default<T: Default + DeserializeOwned>(
+    state: &impl DurableStorage,
     key: &str,
 ) -> Result<T> {
-    state.storage().get(key).await.or_else(|e| {
-        if matches!(e, Error::JsError(ref s) if s == ERR_NO_VALUE) {
-            Ok(T::default())
-        } else {
-            Err(e)
-        }
-    })
+    Ok(state.get(key).await?.unwrap_or_default())
 }
 
-pub(crate) async fn state_get<T: for<'a> Deserialize<'a>>(
-    state: &State,
+pub(crate) async fn state_get<T: DeserializeOwned>(
+    state: &impl DurableStorage,
     key: &str,
 ) -> Result<Option<T>> {
-    state.storage().get(key).await.or_else(|e| {
-        if matches!(e, Error::JsError(ref s) if s == ERR_NO_VALUE) {
-            Ok(None)
-        } else {
-            Err(e)
-        }
-    })
+    state.get(key).await
 }
 
 /// Set a key/value pair unless the key already exists. If the key exists, then return the current
 /// value. Otherwise return nothing.
-pub(crate) async fn state_set_if_not_exists<T: for<'a> Deserialize<'a> + Serialize>(
-    state: &State,
+pub(crate) async fn state_set_if_not_exists<T: DeserializeOwned + Serialize>(
+    state: &impl DurableStorage,
     key: &str,
     val: &T,
 ) -> Result<Option<T>> {
@@ -343,7 +510,7 @@ pub(crate) async fn state_set_if_not_exists<T: for<'a> Deserialize<'a> + Seriali
         return Ok(curr_val);
     }
 
-    state.storage().put(key, val).await?;
+    state.put(key, val).await?;
     Ok(None)
 }
 
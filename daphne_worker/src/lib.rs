@@ -135,6 +135,19 @@
 //! where `<version>` is the DAP version, `<task_id>` is the task ID, and `<agg_job_id>` is the
 //! aggregation job ID.
 //!
+//! ## Aggregation Job Checkpoints (Leader-only)
+//!
+//! The `LeaderAggJobCheckpointStore` DO stores the checkpoint of each aggregation job the Leader
+//! is committing, so that the job can be finished if the Leader restarts in the meantime. The
+//! checkpoints are spread over a fixed number of instances by aggregation job ID. The naming
+//! scheme for instances of this DO is as follows:
+//!
+//! ```text
+//!     leader_agg_job_checkpoint_store/shard/<shard>
+//! ```
+//!
+//! where `<shard>` is derived from the aggregation job ID.
+//!
 //! # Environment Variables
 //!
 //! The runtime behavior of Daphne-Worker is controlled by the environment variables defined in the
@@ -17,7 +17,8 @@
 //! ## Putting a key
 //!
 //! Make a `POST` request with uri `{KV_PATH_BASE}/path/to/key`. The body of the request will be
-//! stored in kv as is, without any processing.
+//! stored in kv as is, without any processing. If the [`KV_EXPIRATION_HEADER`] is set, then the
+//! key expires at the given time.
 //!
 //! ## Putting a key if it doesn't exist
 //!
@@ -51,6 +52,7 @@
 //!     (
 //!         daphne::DapVersion::DraftLatest,
 //!         "some-task-id-in-hex",
+//!         &daphne::DapAggregationParam::Empty,
 //!         &daphne::DapBatchBucket::TimeInterval { batch_window: 50 }
 //!     ),
 //! );
@@ -73,7 +75,7 @@ use std::{sync::OnceLock, time::Duration};
 
 use daphne::auth::BearerToken;
 use daphne_service_utils::durable_requests::{
-    DurableRequest, ObjectIdFrom, DO_PATH_PREFIX, KV_PATH_PREFIX,
+    DurableRequest, ObjectIdFrom, DO_PATH_PREFIX, KV_EXPIRATION_HEADER, KV_PATH_PREFIX,
 };
 use tracing::warn;
 use url::Url;
@@ -145,7 +147,9 @@ pub async fn handle_request(
 #[cfg(feature = "test-utils")]
 /// Clear all storage. Only available to tests
 async fn storage_purge(env: Env) -> worker::Result<Response> {
-    use daphne_service_utils::durable_requests::bindings::{DurableMethod, GarbageCollector};
+    use daphne_service_utils::durable_requests::bindings::{
+        DurableMethod, GarbageCollector, LeaderAggJobCheckpointStore,
+    };
 
     let kv_delete = async {
         let kv = env.kv(KV_BINDING_DAP_CONFIG)?;
@@ -156,20 +160,33 @@ async fn storage_purge(env: Env) -> worker::Result<Response> {
         Ok(())
     };
 
-    let do_delete = async {
+    let env = &env;
+    let delete_all = |binding, name: String| async move {
         let req = Request::new_with_init(
             &format!("https://fake-host{}", GarbageCollector::DeleteAll.to_uri(),),
             RequestInit::new().with_method(worker::Method::Post),
         )?;
 
-        env.durable_object(GarbageCollector::BINDING)?
-            .id_from_name(GarbageCollector::NAME_STR)?
+        env.durable_object(binding)?
+            .id_from_name(&name)?
             .get_stub()?
             .fetch_with_request(req)
             .await
     };
 
-    futures::try_join!(kv_delete, do_delete)?;
+    // The checkpoint store isn't tracked by the garbage collector, as its instances are known.
+    let delete_checkpoints =
+        futures::future::try_join_all((0..LeaderAggJobCheckpointStore::SHARD_COUNT).map(|shard| {
+            delete_all(
+                LeaderAggJobCheckpointStore::BINDING,
+                LeaderAggJobCheckpointStore::name(shard).unwrap_from_name(),
+            )
+        }));
+    futures::try_join!(
+        kv_delete,
+        delete_all(GarbageCollector::BINDING, GarbageCollector::NAME_STR.into()),
+        delete_checkpoints,
+    )?;
     Response::empty()
 }
 
@@ -185,10 +202,16 @@ async fn handle_kv_request(mut req: Request, env: Env, key: &str) -> worker::Res
             }
         }
         worker::Method::Post => {
-            env.kv(KV_BINDING_DAP_CONFIG)?
-                .put_bytes(key, &req.bytes().await?)?
-                .execute()
-                .await?;
+            let mut put = env
+                .kv(KV_BINDING_DAP_CONFIG)?
+                .put_bytes(key, &req.bytes().await?)?;
+            if let Some(expiration) = req.headers().get(KV_EXPIRATION_HEADER)? {
+                let Ok(expiration) = expiration.parse() else {
+                    return Response::error("invalid expiration", 400);
+                };
+                put = put.expiration(expiration);
+            }
+            put.execute().await?;
 
             Response::empty()
         }
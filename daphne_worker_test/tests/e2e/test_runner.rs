@@ -104,11 +104,14 @@ impl TestRunner {
             helper_url: helper_url.clone(),
             expiration: now + 604_800, // one week from now
             time_precision: TIME_PRECISION,
+            time_quantization_offset: 0,
+            align_batch_intervals: false,
             min_batch_size: MIN_BATCH_SIZE,
             query: query_config.clone(),
             vdaf: *VDAF_CONFIG,
             vdaf_verify_key: VDAF_CONFIG.gen_verify_key(),
             collector_hpke_config: collector_hpke_receiver.config.clone(),
+            collector_hpke_config_allowlist: Vec::new(),
             method: Default::default(),
         };
 
@@ -119,6 +122,19 @@ impl TestRunner {
             max_batch_interval_end: 259_200,
             supported_hpke_kems: vec![HpkeKemId::X25519HkdfSha256],
             allow_taskprov: true,
+            agg_share_idempotency_window: 3600,
+            revoked_hpke_config_ids: Vec::new(),
+            upload_dedup_cache_capacity: 0,
+            max_concurrent_agg_jobs_per_task: 0,
+            eager_agg_threshold: None,
+            max_report_shares_per_agg_job: None,
+            agg_job_resp_chunk_size: None,
+            work_item_visibility_timeout: None,
+            agg_job_retry_budget: 0,
+            agg_job_retry_backoff: 0,
+            report_debug_sample_rate: 0.0,
+            taskprov_vdaf_self_test: false,
+            allow_http_urls: true,
         };
         let taskprov_vdaf_verify_key_init =
             hex::decode("b029a72fa327931a5cb643dcadcaafa098fcbfac07d990cb9e7c9a8675fafb18")
@@ -752,6 +768,10 @@ impl TestRunner {
             )
             .unwrap(),
         );
+        headers.insert(
+            reqwest::header::HeaderName::from_static("dap-auth-token"),
+            reqwest::header::HeaderValue::from_str(&self.collector_bearer_token).unwrap(),
+        );
         builder.headers(headers).send().await.unwrap()
     }
 